@@ -0,0 +1,406 @@
+//! Blocking client for synchronous code
+//!
+//! Apps without an async runtime (game engines with their own main loop,
+//! scripts, FFI callers) cannot `.await` a connection. `blocking::Client`
+//! owns a dedicated single-threaded runtime and a `client::Client`, and
+//! exposes the connection through synchronous methods that block the
+//! calling thread:
+//!
+//! - `connect` with `ClientConfig` (or `connect_with`, which also reports
+//!   events to `Hooks` for telemetry)
+//! - `send` of an `Envelope` as a frame
+//! - `request`, which sends an envelope with a new correlation id and waits
+//!   for the response with the same id
+//! - `recv_timeout`, which waits for the next envelope
+//!
+//! Envelopes that arrive while `request` waits for its response are kept,
+//! and returned by `recv_timeout` later in order. Feature flags pushed by
+//! the server are not returned, but kept in `flags`. Frames are compressed
+//! with the compression negotiated in the handshake, and heartbeat pings
+//! are answered while receiving.
+//!
+//! The methods must not be called from async code, since blocking on a
+//! runtime inside another runtime panics.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use cubby_connect_client::blocking::Client;
+//! use cubby_connect_client::config::ClientConfig;
+//! use cubby_connect_server_core::envelope::Envelope;
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let config = ClientConfig::builder().build()?;
+//!     let mut client = Client::connect("127.0.0.1:20202", &config)?;
+//!
+//!     let response = client.request(Envelope::new(b"ping".to_vec()), Duration::from_secs(5))?;
+//!     println!("{} bytes", response.msg.len());
+//!
+//!     while let Some(env) = client.recv_timeout(Duration::from_millis(100))? {
+//!         println!("pushed {:?}", env.headers.message_type);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use cubby_connect_server_core::codec::compress::{Compression, DeflateContext};
+use cubby_connect_server_core::envelope::Envelope;
+use cubby_connect_server_core::error::CubbyError;
+use cubby_connect_server_core::feature_flags::Flags;
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::Instant;
+
+use crate::client;
+use crate::config::ClientConfig;
+use crate::error::ConnectError;
+use crate::feature_flags;
+use crate::hooks::{ClientEvent, Hooks};
+
+/// size of the length before each frame
+const LENGTH_SIZE: usize = 4;
+
+/// frames smaller than this are not compressed
+const COMPRESS_THRESHOLD: usize = 1024;
+
+/// connection of a client that blocks the calling thread
+pub struct Client {
+    runtime: Runtime,
+    client: client::Client,
+
+    /// envelopes received while waiting for responses
+    inbox: VecDeque<Envelope<Vec<u8>>>,
+    next_id: u64,
+    deflate: DeflateContext,
+    flags: Flags,
+    hooks: Hooks,
+}
+
+impl Client {
+    /// connects to `addr` with `config`
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: &ClientConfig) -> Result<Self, ConnectError> {
+        Self::connect_with(addr, config, Hooks::new())
+    }
+
+    /// connects to `addr` with `config`, and reports events of the client
+    /// to `hooks`
+    pub fn connect_with<A: ToSocketAddrs>(
+        addr: A,
+        config: &ClientConfig,
+        hooks: Hooks,
+    ) -> Result<Self, ConnectError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let start = Instant::now();
+        let client = runtime.block_on(client::Client::connect(addrs.as_slice(), config))?;
+        hooks.emit(&ClientEvent::Connected {
+            latency: start.elapsed(),
+        });
+
+        Ok(Self {
+            runtime,
+            client,
+            inbox: VecDeque::new(),
+            next_id: 1,
+            deflate: DeflateContext::new(),
+            flags: Flags::new(),
+            hooks,
+        })
+    }
+
+    /// returns the codec negotiated in the handshake.
+    /// Returns `None` if the handshake is not done.
+    pub fn codec(&self) -> Option<&str> {
+        self.client.codec()
+    }
+
+    /// returns the compression negotiated in the handshake
+    pub fn compression(&self) -> Compression {
+        self.client.compression()
+    }
+
+    /// returns the feature flags that the server pushed last.
+    /// They are updated while receiving envelopes.
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    /// sends `env` as a frame
+    pub fn send(&mut self, env: &Envelope<Vec<u8>>) -> io::Result<()> {
+        let frame = self
+            .compression()
+            .compress(&env.encode(), COMPRESS_THRESHOLD)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let len = frame.len();
+        self.runtime
+            .block_on(self.client.send(frame))
+            .map_err(io_error)?;
+        self.hooks.emit(&ClientEvent::BytesSent(len + LENGTH_SIZE));
+        Ok(())
+    }
+
+    /// sends `env` with a new correlation id, and waits for the response
+    /// with the id for `timeout`
+    pub fn request(
+        &mut self,
+        mut env: Envelope<Vec<u8>>,
+        timeout: Duration,
+    ) -> io::Result<Envelope<Vec<u8>>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        env.headers.correlation_id = Some(id);
+
+        let start = Instant::now();
+        let result = self.send(&env).and_then(|_| loop {
+            let env = self
+                .receive_until(start + timeout)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no response in time"))?;
+            if env.headers.correlation_id == Some(id) {
+                return Ok(env);
+            }
+            self.inbox.push_back(env);
+        });
+        self.hooks.emit(&ClientEvent::Request {
+            rtt: start.elapsed(),
+            ok: result.is_ok(),
+        });
+        result
+    }
+
+    /// returns the next envelope, or `None` if none arrives in `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Envelope<Vec<u8>>>> {
+        if let Some(env) = self.inbox.pop_front() {
+            return Ok(Some(env));
+        }
+        let deadline = Instant::now() + timeout;
+        self.receive_until(deadline)
+    }
+
+    /// reads an envelope from the connection until `deadline`.
+    /// Reading is cancel safe, so a frame in the middle is kept.
+    fn receive_until(&mut self, deadline: Instant) -> io::Result<Option<Envelope<Vec<u8>>>> {
+        loop {
+            let client = &mut self.client;
+            let frame = match self
+                .runtime
+                .block_on(async { tokio::time::timeout_at(deadline, client.recv()).await })
+            {
+                Ok(frame) => frame.map_err(io_error)?,
+                Err(_) => return Ok(None),
+            };
+
+            let frame = frame
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"))?;
+            self.hooks
+                .emit(&ClientEvent::BytesReceived(frame.len() + LENGTH_SIZE));
+            let frame = match self.compression() {
+                Compression::None => frame,
+                _ => self
+                    .deflate
+                    .decompress(&frame)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            };
+            let env = Envelope::decode(&frame)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match feature_flags::decode(&env) {
+                Some(flags) => {
+                    self.flags = flags.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                }
+                None => return Ok(Some(env)),
+            }
+        }
+    }
+}
+
+fn io_error(e: CubbyError) -> io::Error {
+    match e {
+        CubbyError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use cubby_connect_server_core::codec::{Codecs, Protobuf};
+    use cubby_connect_server_core::config::Config;
+    use cubby_connect_server_core::context::Request;
+    use cubby_connect_server_core::feature_flags::FlagStore;
+    use cubby_connect_server_core::fn_handler::fn_handler;
+    use cubby_connect_server_core::handshake::accept_hello;
+    use cubby_connect_server_core::server::Server;
+    use cubby_connect_server_core::transport::{read_frame, write_frame};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn runtime() -> io::Result<Runtime> {
+        Builder::new_current_thread().enable_all().build()
+    }
+
+    /// returns a config of connections to servers without the handshake
+    fn plain() -> ClientConfig {
+        ClientConfig::builder().handshake(false).build().unwrap()
+    }
+
+    /// serves one connection: a push before each response, and the
+    /// response has the payload in upper case
+    fn server() -> io::Result<SocketAddr> {
+        let runtime = runtime()?;
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+
+        thread::spawn(move || {
+            runtime.block_on(async {
+                let (mut stream, _) = listener.accept().await?;
+                while let Some(frame) = read_frame(&mut stream).await? {
+                    let env = Envelope::decode(&frame).unwrap();
+                    let push = Envelope::new(b"push".to_vec());
+                    write_frame(&mut stream, &push.encode()).await?;
+                    let response = env.map(|msg| msg.to_ascii_uppercase());
+                    write_frame(&mut stream, &response.encode()).await?;
+                }
+                Ok::<_, io::Error>(())
+            })
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn request_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = Client::connect(server()?, &plain())?;
+        assert_eq!(client.codec(), None);
+
+        let timeout = Duration::from_secs(5);
+        let response = client.request(Envelope::new(b"hi".to_vec()), timeout)?;
+        assert_eq!(response.msg, b"HI");
+        assert_eq!(response.headers.correlation_id, Some(1));
+        let response = client.request(Envelope::new(b"yo".to_vec()), timeout)?;
+        assert_eq!(response.headers.correlation_id, Some(2));
+
+        // pushes that came before the responses are kept in order
+        let push = client.recv_timeout(timeout)?.unwrap();
+        assert_eq!(push.msg, b"push");
+        assert!(client.recv_timeout(timeout)?.is_some());
+        assert!(client.recv_timeout(Duration::from_millis(50))?.is_none());
+
+        // the response of a send comes after its push
+        client.send(&Envelope::new(b"ok".to_vec()))?;
+        assert_eq!(client.recv_timeout(timeout)?.unwrap().msg, b"push");
+        assert_eq!(client.recv_timeout(timeout)?.unwrap().msg, b"OK");
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_test() -> Result<(), Box<dyn std::error::Error>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ = events.clone();
+        let hooks = Hooks::new().on(move |event: &ClientEvent| {
+            let event = match event {
+                ClientEvent::Connected { .. } => String::from("connected"),
+                ClientEvent::Request { ok, .. } => format!("request {ok}"),
+                event => format!("{event:?}"),
+            };
+            events_.lock().unwrap().push(event);
+        });
+        let mut client = Client::connect_with(server()?, &plain(), hooks)?;
+
+        let mut env = Envelope::new(b"hi".to_vec());
+        client.request(env.clone(), Duration::from_secs(5))?;
+        env.headers.correlation_id = Some(1);
+        let size = env.encode().len() + LENGTH_SIZE;
+        let push = Envelope::new(b"push".to_vec()).encode().len() + LENGTH_SIZE;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                String::from("connected"),
+                format!("BytesSent({size})"),
+                format!("BytesReceived({push})"),
+                format!("BytesReceived({size})"),
+                String::from("request true"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_test() -> Result<(), Box<dyn std::error::Error>> {
+        let runtime = runtime()?;
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            runtime.block_on(async {
+                let (mut stream, _) = listener.accept().await?;
+                let codecs = Codecs::<String>::new().with(Protobuf);
+                accept_hello(&mut stream, &codecs, &[Compression::Zstd]).await?;
+
+                // echoes envelopes, which are compressed both ways
+                while let Some(frame) = read_frame(&mut stream).await? {
+                    write_frame(&mut stream, &frame).await?;
+                }
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+            })
+        });
+
+        let config = ClientConfig::builder()
+            .compressions(vec![Compression::Zstd])
+            .build()?;
+        let mut client = Client::connect(addr, &config)?;
+        assert_eq!(client.codec(), Some("protobuf"));
+        assert_eq!(client.compression(), Compression::Zstd);
+
+        let env = Envelope::new(b"x".repeat(COMPRESS_THRESHOLD));
+        let response = client.request(env, Duration::from_secs(5))?;
+        assert_eq!(response.msg, b"x".repeat(COMPRESS_THRESHOLD));
+        Ok(())
+    }
+
+    #[test]
+    fn flags_test() -> Result<(), Box<dyn std::error::Error>> {
+        // the server keeps running on its runtime while the client blocks
+        let runtime = Runtime::new()?;
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let store = FlagStore::with_flags([("dark_mode", "true")].into_iter().collect());
+        let server = runtime.block_on(
+            Server::from_config(config)
+                .handler(fn_handler(|req: Request<String>| async move {
+                    Ok::<_, Infallible>(req.msg)
+                }))
+                .flags(store.clone())
+                .run(),
+        )?;
+
+        let mut client = Client::connect(server.tcp_addr(), &ClientConfig::builder().build()?)?;
+        assert!(client.flags().is_empty());
+        assert!(client.recv_timeout(Duration::from_millis(100))?.is_none());
+        assert!(client.flags().is_enabled("dark_mode"));
+
+        store.set("dark_mode", "false");
+        assert!(client.recv_timeout(Duration::from_millis(100))?.is_none());
+        assert!(!client.flags().is_enabled("dark_mode"));
+        Ok(())
+    }
+
+    #[test]
+    fn closed_test() -> Result<(), Box<dyn std::error::Error>> {
+        let runtime = runtime()?;
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+        let addr = listener.local_addr()?;
+        let server = thread::spawn(move || runtime.block_on(listener.accept()).map(drop));
+
+        let mut client = Client::connect(addr, &plain())?;
+        server.join().unwrap()?;
+        let e = client.recv_timeout(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
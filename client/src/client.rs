@@ -0,0 +1,509 @@
+//! Connection to a server
+//!
+//! `Client::connect` opens a TCP connection (with TLS if configured) and
+//! does the handshake if `ClientConfig::handshake` is set. Then:
+//!
+//! - `send` passes a message to the outgoing chain, which ends with
+//!   `FrameSink` (`Client::sink`) writing frames to the server
+//! - `recv` reads the next frame and passes it to the incoming chain, which
+//!   usually ends with `Deliver` returning the message
+//!
+//! Heartbeat pings of the server are answered by `recv`, so it should be
+//! called regularly.
+//!
+//! Without a fixed address, `Client::discover` finds servers that announce
+//! themselves in LAN (see `discovery` of the server).
+//!
+//! Both chains are plain frames by default. They are replaced by handlers
+//! built with `apply!` from layers, like the handlers of the server.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_client::client::{Client, Deliver};
+//! use cubby_connect_client::codec::{DecodeLayer, EncodeLayer};
+//! use cubby_connect_client::config::ClientConfig;
+//! use cubby_connect_server_core::middleware::timeout::TimeoutLayer;
+//! use cubby_connect_server_core::apply;
+//! use std::time::Duration;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = ClientConfig::builder().build()?;
+//! let client = Client::connect("127.0.0.1:20202", &config).await?;
+//!
+//! let sink = client.sink();
+//! let mut client = client
+//!     .outgoing(apply!(
+//!         TimeoutLayer::new(Duration::from_secs(5)),
+//!         EncodeLayer::<String>::new()
+//!         to sink
+//!     ))
+//!     .incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+//!
+//! client.send(String::from("hello")).await?;
+//! while let Some(msg) = client.recv().await? {
+//!     println!("{msg}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cubby_connect_server_core::codec::compress::Compression;
+use cubby_connect_server_core::codec::framed::{FrameCodec, FramedRead};
+use cubby_connect_server_core::discovery::{Discovery, ServerInfo};
+use cubby_connect_server_core::error::CubbyError;
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::handshake::{connect_as, ClientInfo};
+use cubby_connect_server_core::heartbeat::answer;
+use cubby_connect_server_core::transport::{load_certs, load_key, write_frame, ALPN};
+use futures::future::{ok, BoxFuture, Ready};
+use futures::FutureExt;
+use rustls::pki_types::ServerName;
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+
+use crate::config::ClientConfig;
+use crate::error::ConnectError;
+
+/// stream of a connection, which can be encrypted or not
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+pub(crate) type BoxStream = Box<dyn Stream>;
+
+/// connection to a server, which sends messages through `S` and receives
+/// messages through `R`
+pub struct Client<S = FrameSink, R = Deliver> {
+    frames: FramedRead<ReadHalf<BoxStream>>,
+    sink: FrameSink,
+    outgoing: S,
+    incoming: R,
+
+    /// negotiated in the handshake
+    codec: Option<String>,
+    compression: Compression,
+}
+
+impl Client {
+    /// connects to `addr` with `config`
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+        config: &ClientConfig,
+    ) -> Result<Self, ConnectError> {
+        let (stream, codec, compression) = open(addr, config).await?;
+        let (reader, writer) = split(stream);
+        let sink = FrameSink {
+            writer: Arc::new(Mutex::new(writer)),
+        };
+        Ok(Self {
+            frames: FramedRead::new(reader, FrameCodec::default()),
+            sink: sink.clone(),
+            outgoing: sink,
+            incoming: Deliver,
+            codec,
+            compression,
+        })
+    }
+
+    /// listens for servers that announce themselves in LAN for `duration`,
+    /// and returns them. Connect to one with its `ServerInfo::addr`.
+    pub async fn discover(duration: Duration) -> io::Result<Vec<ServerInfo>> {
+        Discovery::default().discover(duration).await
+    }
+}
+
+impl<S, R> Client<S, R> {
+    /// returns the handler that writes frames to the server, which the
+    /// outgoing chain ends with
+    pub fn sink(&self) -> FrameSink {
+        self.sink.clone()
+    }
+
+    /// sends messages through `outgoing`, which should end with `sink`
+    pub fn outgoing<S2>(self, outgoing: S2) -> Client<S2, R> {
+        Client {
+            frames: self.frames,
+            sink: self.sink,
+            outgoing,
+            incoming: self.incoming,
+            codec: self.codec,
+            compression: self.compression,
+        }
+    }
+
+    /// receives frames through `incoming`
+    pub fn incoming<R2>(self, incoming: R2) -> Client<S, R2> {
+        Client {
+            frames: self.frames,
+            sink: self.sink,
+            outgoing: self.outgoing,
+            incoming,
+            codec: self.codec,
+            compression: self.compression,
+        }
+    }
+
+    /// returns the codec negotiated in the handshake.
+    /// Returns `None` if the handshake is not done.
+    pub fn codec(&self) -> Option<&str> {
+        self.codec.as_deref()
+    }
+
+    /// returns the compression negotiated in the handshake
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// sends `msg` through the outgoing chain
+    pub async fn send<T>(&self, msg: T) -> Result<S::Output, S::Error>
+    where
+        S: Handler<T>,
+    {
+        self.outgoing.call(msg).await
+    }
+
+    /// waits for the next frame, and returns the output of the incoming
+    /// chain for it. Heartbeat pings are answered instead.
+    /// Returns `None` if the server closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<R::Output>, R::Error>
+    where
+        R: Handler<Vec<u8>>,
+        R::Error: From<io::Error>,
+    {
+        loop {
+            let Some(frame) = self.frames.next().await? else {
+                return Ok(None);
+            };
+            match answer(&frame) {
+                Some(pong) => {
+                    let mut writer = self.sink.writer.lock().await;
+                    write_frame(&mut *writer, &pong).await?;
+                    writer.flush().await?;
+                }
+                None => return Ok(Some(self.incoming.call(frame.to_vec()).await?)),
+            }
+        }
+    }
+
+    /// returns true if the server closed the connection.
+    /// Frames that arrived without being received are dropped.
+    pub(crate) fn is_closed(&mut self) -> bool {
+        loop {
+            match self.frames.next().now_or_never() {
+                None => return false,
+                Some(Ok(Some(_))) => continue,
+                Some(Ok(None) | Err(_)) => return true,
+            }
+        }
+    }
+
+    /// closes the sending side of the connection.
+    /// Messages from the server can still be received until it closes.
+    pub async fn close(&self) -> io::Result<()> {
+        self.sink.writer.lock().await.shutdown().await
+    }
+}
+
+/// opens a connection to `addr` with `config`, and returns it with the
+/// codec and the compression negotiated in the handshake
+pub(crate) async fn open<A: ToSocketAddrs>(
+    addr: A,
+    config: &ClientConfig,
+) -> Result<(BoxStream, Option<String>, Compression), ConnectError> {
+    let stream = TcpStream::connect(addr).await?;
+    let _ = stream.set_nodelay(true);
+
+    let mut stream: BoxStream = match (&config.server_name, &config.ca_path) {
+        (Some(server_name), Some(ca_path)) => {
+            let connector = tls_connector(ca_path, config)?;
+            let server_name = ServerName::try_from(server_name.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Box::new(connector.connect(server_name, stream).await?)
+        }
+        _ => Box::new(stream),
+    };
+
+    if config.handshake {
+        let mut info = ClientInfo::current();
+        if let Some(version) = &config.app_version {
+            info = info.app_version(version);
+        }
+        let (codec, compression) =
+            connect_as(&mut stream, &config.codecs, &config.compressions, &info).await?;
+        Ok((stream, Some(codec), compression))
+    } else {
+        Ok((stream, None, Compression::None))
+    }
+}
+
+/// connector of TLS that trusts certificates in `ca_path`, and presents
+/// the certificate of `config` if it has one
+fn tls_connector(
+    ca_path: &std::path::Path,
+    config: &ClientConfig,
+) -> Result<TlsConnector, ConnectError> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert)?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots);
+    let mut tls = match (&config.cert_path, &config.key_path) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(TlsConnector::from(Arc::new(tls)))
+}
+
+/// `Handler` that writes frames to the server.
+/// Clones write to the same connection.
+#[derive(Clone)]
+pub struct FrameSink {
+    writer: Arc<Mutex<WriteHalf<BoxStream>>>,
+}
+
+impl Handler<Vec<u8>> for FrameSink {
+    type Output = ();
+    type Error = CubbyError;
+    type Future = BoxFuture<'static, Result<(), CubbyError>>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        let writer = self.writer.clone();
+        Box::pin(async move {
+            let mut writer = writer.lock().await;
+            write_frame(&mut *writer, &frame).await?;
+            writer.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+/// `Handler` that returns messages as they are, which the incoming chain
+/// ends with
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Deliver;
+
+impl<T> Handler<T> for Deliver {
+    type Output = T;
+    type Error = CubbyError;
+    type Future = Ready<Result<T, CubbyError>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use cubby_connect_server_core::apply;
+    use cubby_connect_server_core::codec::{Codecs, Protobuf};
+    use cubby_connect_server_core::config::Config;
+    use cubby_connect_server_core::connections::Connection;
+    use cubby_connect_server_core::context::{extract, Missing};
+    use cubby_connect_server_core::handshake::accept_hello;
+    use cubby_connect_server_core::heartbeat::Heartbeat;
+    use cubby_connect_server_core::server::Server;
+
+    use crate::codec::{CompressLayer, DecodeLayer, DecompressLayer, EncodeLayer};
+
+    use super::*;
+
+    async fn join(room: String, connection: Connection<Bytes>) -> Result<String, Missing> {
+        connection.tag(room.clone());
+        Ok(format!("joined {room}"))
+    }
+
+    // uses the multicast group of LAN, which many hosts (and CI) don't route
+    #[tokio::test]
+    #[ignore = "needs a multicast route to 239.255.20.20"]
+    async fn discover_test() -> Result<(), Box<dyn std::error::Error>> {
+        let announcer = tokio::spawn(async {
+            Discovery::default()
+                .announce("lan server", 20202, Duration::from_millis(50))
+                .await
+        });
+        let servers = Client::discover(Duration::from_millis(300)).await?;
+        announcer.abort();
+
+        let server = servers.iter().find(|server| server.name == "lan server");
+        assert_eq!(server.map(|server| server.addr.port()), Some(20202));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+
+        let client = Client::connect(server.tcp_addr(), &ClientConfig::builder().build()?).await?;
+        assert_eq!(client.codec(), Some("protobuf"));
+        let sink = client.sink();
+        let mut client = client
+            .outgoing(apply!(EncodeLayer::<String>::new() to sink))
+            .incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+
+        client.send(String::from("lobby")).await?;
+        assert_eq!(client.recv().await?, Some(String::from("joined lobby")));
+
+        server.broadcast_to("lobby", &String::from("hello"));
+        assert_eq!(client.recv().await?, Some(String::from("hello")));
+
+        server.shutdown().await?;
+        assert_eq!(client.recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn heartbeat_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .heartbeat_interval(Duration::from_millis(10))
+            .heartbeat_timeout(Duration::from_millis(10))
+            .heartbeat_misses(1)
+            .build()?;
+        let server = Server::from_config(config.clone())
+            .handler(extract(join))
+            .heartbeat(Heartbeat::from_config(&config))
+            .run()
+            .await?;
+
+        let client = Client::connect(server.tcp_addr(), &ClientConfig::builder().build()?).await?;
+        let mut client = client.incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+
+        // pings are answered while waiting
+        let broadcast = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            server.broadcast(&String::from("still here"))
+        };
+        let (received, broadcast) = tokio::join!(client.recv(), broadcast);
+        assert_eq!(broadcast, 1);
+        assert_eq!(received?, Some(String::from("still here")));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let codecs = Codecs::<String>::new().with(Protobuf);
+            let accepted = accept_hello(&mut stream, &codecs, &[Compression::Lz4]).await?;
+            assert_eq!(
+                accepted.client_info.unwrap().app_version.as_deref(),
+                Some("1.0.0")
+            );
+
+            // echoes frames as they are
+            let (mut reader, mut writer) = stream.into_split();
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+
+        let config = ClientConfig::builder()
+            .handshake(true)
+            .compressions(vec![Compression::Zstd, Compression::Lz4])
+            .app_version("1.0.0")
+            .build()?;
+        let client = Client::connect(addr, &config).await?;
+        assert_eq!(client.codec(), Some("protobuf"));
+        assert_eq!(client.compression(), Compression::Lz4);
+
+        let (sink, compression) = (client.sink(), client.compression());
+        let mut client = client
+            .outgoing(
+                apply!(EncodeLayer::<String>::new(), CompressLayer::new(compression, 0) to sink),
+            )
+            .incoming(apply!(
+                DecompressLayer::new(compression),
+                DecodeLayer::<String>::new()
+                to Deliver
+            ));
+
+        client.send("x".repeat(1000)).await?;
+        assert_eq!(client.recv().await?, Some("x".repeat(1000)));
+
+        client.close().await?;
+        assert_eq!(client.recv().await?, None);
+        server.await?.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_handshake_test() -> Result<(), Box<dyn std::error::Error>> {
+        async fn hello(
+            room: String,
+            info: ClientInfo,
+            connection: Connection<Bytes>,
+        ) -> Result<String, Missing> {
+            connection.tag(room);
+            Ok(info.app_version.unwrap_or_default())
+        }
+
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .compressions(vec![String::from("deflate")])
+            .compression_threshold(0)
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(hello))
+            .run()
+            .await?;
+
+        let config = ClientConfig::builder()
+            .compressions(vec![Compression::Deflate])
+            .app_version("1.4.2")
+            .build()?;
+        let client = Client::connect(server.tcp_addr(), &config).await?;
+        assert_eq!(client.codec(), Some("protobuf"));
+        assert_eq!(client.compression(), Compression::Deflate);
+
+        let (sink, compression) = (client.sink(), client.compression());
+        let mut client = client
+            .outgoing(apply!(
+                EncodeLayer::<String>::new(),
+                CompressLayer::new(compression, 0).context_takeover(true)
+                to sink
+            ))
+            .incoming(apply!(
+                DecompressLayer::new(compression),
+                DecodeLayer::<String>::new()
+                to Deliver
+            ));
+
+        // requests, responses and pushes are compressed
+        for _ in 0..2 {
+            client.send(String::from("lobby")).await?;
+            assert_eq!(client.recv().await?, Some(String::from("1.4.2")));
+        }
+        server.broadcast_to("lobby", &"x".repeat(1000));
+        assert_eq!(client.recv().await?, Some("x".repeat(1000)));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+//! Layers that encode, decode and compress messages
+//!
+//! A client sends messages through a chain that ends with `FrameSink`,
+//! which takes frames, and receives frames through a chain that usually
+//! ends with `Deliver`, which returns messages to `Client::recv`. These
+//! layers turn messages into frames and back:
+//!
+//! - `EncodeLayer`: encodes protobuf messages into frames
+//! - `DecodeLayer`: decodes frames into protobuf messages
+//! - `CompressLayer`: compresses frames with the negotiated compression
+//! - `DecompressLayer`: decompresses frames with the negotiated compression
+//!
+//! Handlers of `CompressLayer` and `DecompressLayer` keep the deflate
+//! stream of their direction when context takeover is used (see
+//! `compress::DeflateContext` of the server), so a handler should serve a
+//! single connection.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_client::codec::{CompressLayer, DecodeLayer, DecompressLayer, EncodeLayer};
+//! use cubby_connect_server_core::codec::compress::Compression;
+//! use cubby_connect_server_core::error::CubbyError;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn wire(frame: Vec<u8>) -> Result<Vec<u8>, CubbyError> {
+//!     Ok(frame)
+//! }
+//!
+//! async fn show(msg: String) -> Result<String, CubbyError> {
+//!     Ok(msg)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CubbyError> {
+//! let send = apply!(
+//!     EncodeLayer::<String>::new(),
+//!     CompressLayer::new(Compression::Lz4, 0)
+//!     to wire
+//! );
+//! let recv = apply!(
+//!     DecompressLayer::new(Compression::Lz4),
+//!     DecodeLayer::<String>::new()
+//!     to show
+//! );
+//!
+//! let frame = send.call(String::from("hello")).await?;
+//! assert_eq!(recv.call(frame).await?, "hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+
+use cubby_connect_server_core::codec::compress::{CompressError, Compression, DeflateContext};
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::layer::Layer;
+use futures::future::{err, ok, Either, Ready};
+use prost::{DecodeError, Message};
+
+/// `Layer` that builds `EncodeHandler` of messages of type `M`
+pub struct EncodeLayer<M> {
+    _marker: PhantomData<fn(M)>,
+}
+
+impl<M> EncodeLayer<M> {
+    pub fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<M> Default for EncodeLayer<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M, H> Layer<M, H> for EncodeLayer<M>
+where
+    M: Message,
+    H: Handler<Vec<u8>>,
+{
+    type Next = Vec<u8>;
+    type Error = H::Error;
+    type Handler = EncodeHandler<M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(EncodeHandler {
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that encodes messages of type `M` and calls the previous
+/// handler with the frames
+pub struct EncodeHandler<M, H> {
+    prev: H,
+    _marker: PhantomData<fn(M)>,
+}
+
+impl<M, H> Handler<M> for EncodeHandler<M, H>
+where
+    M: Message,
+    H: Handler<Vec<u8>>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = H::Future;
+
+    fn call(&self, msg: M) -> Self::Future {
+        self.prev.call(msg.encode_to_vec())
+    }
+}
+
+/// `Layer` that builds `DecodeHandler` of messages of type `M`
+pub struct DecodeLayer<M> {
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<M> DecodeLayer<M> {
+    pub fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<M> Default for DecodeLayer<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M, H> Layer<Vec<u8>, H> for DecodeLayer<M>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Error: From<DecodeError>,
+{
+    type Next = M;
+    type Error = H::Error;
+    type Handler = DecodeHandler<M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(DecodeHandler {
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that decodes frames into messages of type `M` and calls the
+/// previous handler with them
+pub struct DecodeHandler<M, H> {
+    prev: H,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<M, H> Handler<Vec<u8>> for DecodeHandler<M, H>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Error: From<DecodeError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<Ready<Result<H::Output, H::Error>>, H::Future>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        match M::decode(frame.as_slice()) {
+            Ok(msg) => Either::Right(self.prev.call(msg)),
+            Err(e) => Either::Left(err(e.into())),
+        }
+    }
+}
+
+/// `Layer` that builds `CompressHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct CompressLayer {
+    compression: Compression,
+    threshold: usize,
+    context_takeover: bool,
+}
+
+impl CompressLayer {
+    /// compresses frames that are not smaller than `threshold` bytes with
+    /// `compression` (usually `Client::compression`)
+    pub fn new(compression: Compression, threshold: usize) -> Self {
+        Self {
+            compression,
+            threshold,
+            context_takeover: false,
+        }
+    }
+
+    /// sets whether deflate retains its context across frames when
+    /// `compression` is `Compression::Deflate` (default: false). Retaining
+    /// compresses better, resetting keeps no deflate stream in memory.
+    /// Each stream of a multiplexed connection needs its own handler.
+    pub fn context_takeover(mut self, context_takeover: bool) -> Self {
+        self.context_takeover = context_takeover;
+        self
+    }
+}
+
+impl<H> Layer<Vec<u8>, H> for CompressLayer
+where
+    H: Handler<Vec<u8>>,
+    H::Error: From<CompressError>,
+{
+    type Next = Vec<u8>;
+    type Error = H::Error;
+    type Handler = CompressHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(CompressHandler {
+            compression: self.compression,
+            threshold: self.threshold,
+            context_takeover: self.context_takeover,
+            deflate: DeflateContext::new(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that compresses frames and calls the previous handler with
+/// them
+pub struct CompressHandler<H> {
+    compression: Compression,
+    threshold: usize,
+    context_takeover: bool,
+    deflate: DeflateContext,
+    prev: H,
+}
+
+impl<H> Handler<Vec<u8>> for CompressHandler<H>
+where
+    H: Handler<Vec<u8>>,
+    H::Error: From<CompressError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<Ready<Result<H::Output, H::Error>>, H::Future>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        let compressed = match self.compression {
+            Compression::Deflate => {
+                self.deflate
+                    .compress(&frame, self.threshold, self.context_takeover)
+            }
+            compression => compression.compress(&frame, self.threshold),
+        };
+        match compressed {
+            Ok(frame) => Either::Right(self.prev.call(frame)),
+            Err(e) => Either::Left(err(e.into())),
+        }
+    }
+}
+
+/// `Layer` that builds `DecompressHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct DecompressLayer {
+    compression: Compression,
+}
+
+impl DecompressLayer {
+    /// decompresses frames compressed with `compression` (usually
+    /// `Client::compression`)
+    pub fn new(compression: Compression) -> Self {
+        Self { compression }
+    }
+}
+
+impl<H> Layer<Vec<u8>, H> for DecompressLayer
+where
+    H: Handler<Vec<u8>>,
+    H::Error: From<CompressError>,
+{
+    type Next = Vec<u8>;
+    type Error = H::Error;
+    type Handler = DecompressHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(DecompressHandler {
+            compression: self.compression,
+            deflate: DeflateContext::new(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that decompresses frames and calls the previous handler with
+/// them.
+/// Frames are passed as they are with `Compression::None`.
+pub struct DecompressHandler<H> {
+    compression: Compression,
+    deflate: DeflateContext,
+    prev: H,
+}
+
+impl<H> Handler<Vec<u8>> for DecompressHandler<H>
+where
+    H: Handler<Vec<u8>>,
+    H::Error: From<CompressError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<Ready<Result<H::Output, H::Error>>, H::Future>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        if self.compression == Compression::None {
+            return Either::Right(self.prev.call(frame));
+        }
+        match self.deflate.decompress(&frame) {
+            Ok(frame) => Either::Right(self.prev.call(frame)),
+            Err(e) => Either::Left(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cubby_connect_server_core::error::CubbyError;
+    use cubby_connect_server_core::fn_handler::fn_handler;
+    use cubby_connect_server_core::layer::connect;
+
+    use super::*;
+
+    async fn len(frame: Vec<u8>) -> Result<usize, CubbyError> {
+        Ok(frame.len())
+    }
+
+    async fn echo(msg: String) -> Result<String, CubbyError> {
+        Ok(msg)
+    }
+
+    #[tokio::test]
+    async fn codec_test() -> Result<(), CubbyError> {
+        let encode = connect(EncodeLayer::<String>::new(), fn_handler(len)).await?;
+        assert_eq!(encode.call(String::from("hello")).await?, 7);
+
+        let decode = connect(DecodeLayer::<String>::new(), fn_handler(echo)).await?;
+        assert!(matches!(
+            decode.call(vec![0xff]).await,
+            Err(CubbyError::Decode(_))
+        ));
+
+        let compress =
+            connect(CompressLayer::new(Compression::Zstd, 1024), fn_handler(len)).await?;
+        assert_eq!(compress.call(vec![0; 10]).await?, 11);
+        assert!(compress.call(vec![0; 4096]).await? < 100);
+
+        let decompress = connect(DecompressLayer::new(Compression::Zstd), fn_handler(len)).await?;
+        assert!(matches!(
+            decompress.call(vec![9]).await,
+            Err(CubbyError::Compress(CompressError::UnknownFlag(9)))
+        ));
+        Ok(())
+    }
+
+    async fn wire(frame: Vec<u8>) -> Result<Vec<u8>, CubbyError> {
+        Ok(frame)
+    }
+
+    #[tokio::test]
+    async fn context_takeover_test() -> Result<(), CubbyError> {
+        let compress = CompressLayer::new(Compression::Deflate, 16).context_takeover(true);
+        let send = connect(compress, fn_handler(wire)).await?;
+        let recv = connect(DecompressLayer::new(Compression::Deflate), fn_handler(wire)).await?;
+
+        let frame = b"position of player 7 ".repeat(10);
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let compressed = send.call(frame.clone()).await?;
+            sizes.push(compressed.len());
+            assert_eq!(recv.call(compressed).await?, frame);
+        }
+        // the second frame refers to the first one
+        assert!(sizes[1] < sizes[0]);
+
+        let send = connect(compress.context_takeover(false), fn_handler(wire)).await?;
+        for _ in 0..2 {
+            let compressed = send.call(frame.clone()).await?;
+            assert!(compressed.len() > sizes[1]);
+            assert_eq!(recv.call(compressed).await?, frame);
+        }
+        Ok(())
+    }
+}
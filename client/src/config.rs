@@ -0,0 +1,82 @@
+//! Configuration of a client connection
+//!
+//! TLS is used when both `server_name` and `ca_path` are set, like the
+//! server uses it when both its key and certificate are set. With
+//! `cert_path` and `key_path` too, the client presents its certificate to
+//! servers that verify clients.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_client::config::ClientConfig;
+//! use cubby_connect_server_core::codec::compress::Compression;
+//!
+//! let config = ClientConfig::builder()
+//!     .server_name("play.cubby.dev")
+//!     .ca_path("ca.pem")
+//!     .compressions(vec![Compression::Zstd])
+//!     .app_version("1.4.2")
+//!     .build()
+//!     .unwrap();
+//! assert!(config.is_tls());
+//! ```
+
+use std::path::PathBuf;
+
+use cubby_connect_server_core::codec::compress::Compression;
+
+/// configuration of a connection to a server
+#[derive(Builder, Clone, Debug, Eq, PartialEq)]
+#[builder(derive(Debug, Eq, PartialEq))]
+pub struct ClientConfig {
+    /// name of the server in its certificate
+    /// if this value is `None`, there is no tls connection
+    #[builder(default = "None", setter(strip_option, into))]
+    pub server_name: Option<String>,
+
+    /// PEM file of certificates that the certificate of the server is
+    /// signed with
+    /// if this value is `None`, there is no tls connection
+    #[builder(default = "None", setter(strip_option, into))]
+    pub ca_path: Option<PathBuf>,
+
+    /// PEM file of the certificate chain presented to a server that
+    /// verifies clients (mutual TLS)
+    /// if this value or `key_path` is `None`, no certificate is presented
+    #[builder(default = "None", setter(strip_option, into))]
+    pub cert_path: Option<PathBuf>,
+
+    /// PEM file of the private key of `cert_path`
+    #[builder(default = "None", setter(strip_option, into))]
+    pub key_path: Option<PathBuf>,
+
+    /// whether to do the handshake (see `handshake` of the server) right
+    /// after connecting, which `Server` expects unless its
+    /// `Config::handshake` is off
+    #[builder(default = "true")]
+    pub handshake: bool,
+
+    /// codecs offered in the handshake, in order of preference
+    #[builder(default = "vec![String::from(\"protobuf\")]")]
+    pub codecs: Vec<String>,
+
+    /// compressions offered in the handshake, in order of preference
+    #[builder(default = "Vec::new()")]
+    pub compressions: Vec<Compression>,
+
+    /// version of the application told in the handshake
+    #[builder(default = "None", setter(strip_option, into))]
+    pub app_version: Option<String>,
+}
+
+impl ClientConfig {
+    /// returns default builder of `ClientConfigBuilder`
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+
+    /// returns true if the connection is encrypted
+    pub fn is_tls(&self) -> bool {
+        self.server_name.is_some() && self.ca_path.is_some()
+    }
+}
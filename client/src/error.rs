@@ -0,0 +1,72 @@
+//! Error when connecting to a server
+//!
+//! Messages are sent and received through handler chains, so errors after
+//! connecting are the errors of the chains (usually `CubbyError` of the
+//! server core).
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use cubby_connect_server_core::handshake::HandshakeError;
+use cubby_connect_server_core::transport::TransportError;
+
+/// error when connecting to a server
+#[derive(Debug)]
+pub enum ConnectError {
+    /// error from socket or files
+    Io(io::Error),
+
+    /// invalid certificate or failed tls handshake
+    Tls(rustls::Error),
+
+    /// the server rejected the handshake
+    Handshake(HandshakeError),
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Io(e) => write!(f, "io error: {e}"),
+            ConnectError::Tls(e) => write!(f, "tls error: {e}"),
+            ConnectError::Handshake(e) => write!(f, "handshake error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Io(e) => Some(e),
+            ConnectError::Tls(e) => Some(e),
+            ConnectError::Handshake(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Io(e)
+    }
+}
+
+impl From<rustls::Error> for ConnectError {
+    fn from(e: rustls::Error) -> Self {
+        ConnectError::Tls(e)
+    }
+}
+
+impl From<HandshakeError> for ConnectError {
+    fn from(e: HandshakeError) -> Self {
+        ConnectError::Handshake(e)
+    }
+}
+
+impl From<TransportError> for ConnectError {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Io(e) => ConnectError::Io(e),
+            TransportError::Tls(e) => ConnectError::Tls(e),
+            TransportError::NoTls => ConnectError::Tls(rustls::Error::General(e.to_string())),
+        }
+    }
+}
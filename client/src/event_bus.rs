@@ -0,0 +1,343 @@
+//! Typed subscriptions to messages on clients
+//!
+//! A client receives every message from the server on one inbound handler,
+//! but the modules of an app (chat, inventory, matchmaking) are interested
+//! in different messages. With `EventBus`, each module registers a callback
+//! for its message types with `on::<M>`, where `M` is a protobuf message of
+//! `router::MessageType`. `EventBus` is the inbound handler: it decodes the
+//! payload of each `Envelope` once by `Headers::message_type`, and calls
+//! every callback of the type. The output is the number of called
+//! callbacks, which is 0 for messages nobody is interested in.
+//!
+//! Callbacks can register and unregister callbacks themselves. Those
+//! changes apply to the next message.
+//!
+//! Two message types with the same `MESSAGE_TYPE` cannot both be
+//! registered: `on` fails with `TypeConflict` for the second one.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use cubby_connect_server_core::envelope::Envelope;
+//! use cubby_connect_client::event_bus::EventBus;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::router::MessageType;
+//! use prost::Message;
+//!
+//! #[derive(Clone, PartialEq, Message)]
+//! struct ChatMessage {
+//!     #[prost(string, tag = "1")]
+//!     text: String,
+//! }
+//!
+//! impl MessageType for ChatMessage {
+//!     const MESSAGE_TYPE: &'static str = "chat";
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let bus = EventBus::new();
+//!
+//! // registered by the chat module
+//! let chat_log = Arc::new(Mutex::new(Vec::new()));
+//! let chat_log_ = chat_log.clone();
+//! let subscription = bus.on(move |msg: &ChatMessage| {
+//!     chat_log_.lock().unwrap().push(msg.text.clone())
+//! })?;
+//!
+//! // the inbound handler of the connection
+//! let env = Envelope::typed(&ChatMessage { text: String::from("hi") });
+//! assert_eq!(bus.call(env.clone()).await?, 1);
+//! assert_eq!(*chat_log.lock().unwrap(), vec!["hi"]);
+//!
+//! bus.off(subscription);
+//! assert_eq!(bus.call(env).await?, 0);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use cubby_connect_server_core::envelope::Envelope;
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::router::MessageType;
+use futures::future::{ready, Ready};
+use prost::{DecodeError, Message};
+
+type Callback<M> = Arc<dyn Fn(&M) + Send + Sync>;
+
+/// delivery of a decoded message to a snapshot of callbacks
+type Delivery = Box<dyn FnOnce() -> usize>;
+
+/// callbacks of one message type
+trait Subscribers: Send {
+    /// decodes `payload`, and returns its delivery to current callbacks
+    fn decode(&self, payload: &[u8]) -> Result<Delivery, DecodeError>;
+
+    fn remove(&mut self, id: u64) -> bool;
+
+    fn is_empty(&self) -> bool;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+struct Callbacks<M>(Vec<(u64, Callback<M>)>);
+
+impl<M> Subscribers for Callbacks<M>
+where
+    M: Message + Default + 'static,
+{
+    fn decode(&self, payload: &[u8]) -> Result<Delivery, DecodeError> {
+        let msg = M::decode(payload)?;
+        let callbacks: Vec<_> = self.0.iter().map(|(_, f)| f.clone()).collect();
+        Ok(Box::new(move || {
+            callbacks.iter().for_each(|f| f(&msg));
+            callbacks.len()
+        }))
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let len = self.0.len();
+        self.0.retain(|(i, _)| *i != id);
+        self.0.len() != len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// error when a message type registers a `MESSAGE_TYPE` that another
+/// message type already has
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TypeConflict(pub &'static str);
+
+impl Display for TypeConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "message type `{}` is registered by another message",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TypeConflict {}
+
+/// registered callback, to unregister it with `EventBus::off`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Subscription {
+    message_type: &'static str,
+    id: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    next: u64,
+    types: HashMap<&'static str, Box<dyn Subscribers>>,
+}
+
+/// callbacks of message types.
+/// Clones share the callbacks.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl EventBus {
+    /// returns a bus without callbacks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// calls `f` with every message of type `M`.
+    /// Fails if another message type has the same `MESSAGE_TYPE`.
+    pub fn on<M, F>(&self, f: F) -> Result<Subscription, TypeConflict>
+    where
+        M: Message + Default + MessageType + 'static,
+        F: Fn(&M) + Send + Sync + 'static,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next;
+        inner.next += 1;
+
+        let subscribers = inner
+            .types
+            .entry(M::MESSAGE_TYPE)
+            .or_insert_with(|| Box::new(Callbacks::<M>(Vec::new())));
+        subscribers
+            .as_any_mut()
+            .downcast_mut::<Callbacks<M>>()
+            .ok_or(TypeConflict(M::MESSAGE_TYPE))?
+            .0
+            .push((id, Arc::new(f)));
+
+        Ok(Subscription {
+            message_type: M::MESSAGE_TYPE,
+            id,
+        })
+    }
+
+    /// unregisters the callback of `subscription`.
+    /// Returns false if it is already unregistered.
+    pub fn off(&self, subscription: Subscription) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let subscribers = match inner.types.get_mut(subscription.message_type) {
+            Some(subscribers) => subscribers,
+            None => return false,
+        };
+
+        let removed = subscribers.remove(subscription.id);
+        if subscribers.is_empty() {
+            inner.types.remove(subscription.message_type);
+        }
+        removed
+    }
+
+    /// returns true if a callback is registered for `message_type`
+    pub fn is_subscribed(&self, message_type: &str) -> bool {
+        self.inner.lock().unwrap().types.contains_key(message_type)
+    }
+
+    /// calls the callbacks of the type of `env`, and returns their number
+    pub fn dispatch(&self, env: &Envelope<Vec<u8>>) -> Result<usize, DecodeError> {
+        let delivery = {
+            let inner = self.inner.lock().unwrap();
+            let subscribers = env
+                .headers
+                .message_type
+                .as_deref()
+                .and_then(|t| inner.types.get(t));
+            match subscribers {
+                Some(subscribers) => subscribers.decode(&env.msg)?,
+                None => return Ok(0),
+            }
+        };
+        // without the lock, so callbacks can use the bus
+        Ok(delivery())
+    }
+}
+
+/// dispatches envelopes, the output is the number of called callbacks
+impl Handler<Envelope<Vec<u8>>> for EventBus {
+    type Output = usize;
+    type Error = DecodeError;
+    type Future = Ready<Result<usize, DecodeError>>;
+
+    fn call(&self, env: Envelope<Vec<u8>>) -> Self::Future {
+        ready(self.dispatch(&env))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Chat {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    impl MessageType for Chat {
+        const MESSAGE_TYPE: &'static str = "chat";
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Move {
+        #[prost(int32, tag = "1")]
+        x: i32,
+    }
+
+    impl MessageType for Move {
+        const MESSAGE_TYPE: &'static str = "move";
+    }
+
+    #[test]
+    fn dispatch_test() {
+        let bus = EventBus::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let seen_ = seen.clone();
+        let chat = bus
+            .on(move |msg: &Chat| seen_.lock().unwrap().push(msg.text.clone()))
+            .unwrap();
+        let seen_ = seen.clone();
+        bus.on(move |msg: &Chat| seen_.lock().unwrap().push(msg.text.to_uppercase()))
+            .unwrap();
+        let seen_ = seen.clone();
+        let moves = bus
+            .on(move |msg: &Move| seen_.lock().unwrap().push(msg.x.to_string()))
+            .unwrap();
+
+        let hi = Envelope::typed(&Chat {
+            text: String::from("hi"),
+        });
+        assert_eq!(bus.dispatch(&hi), Ok(2));
+        assert_eq!(bus.dispatch(&Envelope::typed(&Move { x: 3 })), Ok(1));
+        assert_eq!(*seen.lock().unwrap(), vec!["hi", "HI", "3"]);
+
+        // unknown types and messages without a type
+        let mut env = Envelope::new(vec![1, 2]);
+        assert_eq!(bus.dispatch(&env), Ok(0));
+        env.headers.message_type = Some(String::from("trade"));
+        assert_eq!(bus.dispatch(&env), Ok(0));
+        env.headers.message_type = Some(String::from("move"));
+        env.msg = vec![0xff];
+        assert!(bus.dispatch(&env).is_err());
+
+        assert!(bus.off(chat));
+        assert!(!bus.off(chat));
+        assert_eq!(bus.dispatch(&hi), Ok(1));
+        assert!(bus.off(moves));
+        assert!(!bus.is_subscribed("move"));
+        assert!(bus.is_subscribed("chat"));
+    }
+
+    #[test]
+    fn reentrant_test() {
+        // a callback that unregisters itself after the first message
+        let bus = EventBus::new();
+        let once = Arc::new(Mutex::new(None));
+        let bus_ = bus.clone();
+        let once_ = once.clone();
+        let subscription = bus
+            .on(move |_: &Chat| {
+                if let Some(subscription) = once_.lock().unwrap().take() {
+                    bus_.off(subscription);
+                }
+            })
+            .unwrap();
+        *once.lock().unwrap() = Some(subscription);
+
+        let hi = Envelope::typed(&Chat::default());
+        assert_eq!(bus.dispatch(&hi), Ok(1));
+        assert_eq!(bus.dispatch(&hi), Ok(0));
+    }
+
+    #[test]
+    fn conflict_test() {
+        #[derive(Clone, PartialEq, Message)]
+        struct OldChat {
+            #[prost(string, tag = "2")]
+            body: String,
+        }
+
+        impl MessageType for OldChat {
+            const MESSAGE_TYPE: &'static str = "chat";
+        }
+
+        let bus = EventBus::new();
+        bus.on(|_: &Chat| {}).unwrap();
+        assert_eq!(bus.on(|_: &OldChat| {}), Err(TypeConflict("chat")));
+        assert_eq!(bus.dispatch(&Envelope::typed(&Chat::default())), Ok(1));
+    }
+}
@@ -0,0 +1,62 @@
+//! Feature flags told by the server
+//!
+//! A server with `Server::flags` pushes every flag right after the
+//! handshake, and again whenever a flag changes, as an envelope of the type
+//! `FLAGS_TYPE`. `decode` tells it from other envelopes, and returns the
+//! flags, which replace the flags that the client had.
+//!
+//! `blocking::Client` keeps the flags that came last in `flags`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_client::feature_flags::decode;
+//! use cubby_connect_server_core::envelope::Envelope;
+//! use cubby_connect_server_core::feature_flags::Flags;
+//!
+//! let env = [("new_inventory", "true")].into_iter().collect::<Flags>().to_envelope();
+//! let flags = decode(&env).unwrap().unwrap();
+//! assert!(flags.is_enabled("new_inventory"));
+//!
+//! // other envelopes are not flags
+//! assert!(decode(&Envelope::new(b"hello".to_vec())).is_none());
+//! ```
+
+use std::collections::HashMap;
+
+use cubby_connect_server_core::envelope::Envelope;
+use cubby_connect_server_core::feature_flags::{Flags, FLAGS_TYPE};
+use prost::{DecodeError, Message};
+
+/// `Flags` of `protobuf/feature_flags.proto`
+#[derive(Clone, PartialEq, Message)]
+struct FlagsMessage {
+    #[prost(map = "string, string", tag = "1")]
+    flags: HashMap<String, String>,
+}
+
+/// decodes flags sent by the server.
+/// Returns `None` if `env` is not of `FLAGS_TYPE`.
+pub fn decode(env: &Envelope<Vec<u8>>) -> Option<Result<Flags, DecodeError>> {
+    if env.headers.message_type.as_deref() != Some(FLAGS_TYPE) {
+        return None;
+    }
+    Some(FlagsMessage::decode(env.msg.as_slice()).map(|msg| msg.flags.into_iter().collect()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_test() {
+        let flags: Flags = [("x", "on"), ("y", "3")].into_iter().collect();
+        assert_eq!(decode(&flags.to_envelope()), Some(Ok(flags)));
+        assert_eq!(decode(&Flags::new().to_envelope()), Some(Ok(Flags::new())));
+
+        let mut env = Envelope::new(vec![0xff]);
+        env.headers.message_type = Some(FLAGS_TYPE.to_string());
+        assert!(matches!(decode(&env), Some(Err(_))));
+        assert_eq!(decode(&Envelope::new(Vec::new())), None);
+    }
+}
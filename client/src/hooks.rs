@@ -0,0 +1,101 @@
+//! Instrumentation hooks of clients
+//!
+//! Mobile and desktop apps feed their own analytics (connect latency, round
+//! trip times, retries, data usage) instead of parsing logs. A client
+//! reports each of them as a `ClientEvent` to its `Hooks`, which call every
+//! registered callback with it. Callbacks run on the thread of the client,
+//! so they should be quick (e.g. add to a counter, or send to a channel).
+//!
+//! `blocking::Client` reports `Connected`, `Request`, `BytesSent` and
+//! `BytesReceived`, and `ReconnectingClient` reports `Retry` for every
+//! attempt to reconnect.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use cubby_connect_client::hooks::{ClientEvent, Hooks};
+//!
+//! let sent = Arc::new(AtomicUsize::new(0));
+//! let sent_ = sent.clone();
+//! let hooks = Hooks::new()
+//!     .on(move |event: &ClientEvent| {
+//!         if let ClientEvent::BytesSent(n) = event {
+//!             sent_.fetch_add(*n, Ordering::Relaxed);
+//!         }
+//!     })
+//!     .on(|event: &ClientEvent| println!("{event:?}"));
+//!
+//! // reported by a client
+//! hooks.emit(&ClientEvent::Connected { latency: Duration::from_millis(40) });
+//! hooks.emit(&ClientEvent::BytesSent(128));
+//! assert_eq!(sent.load(Ordering::Relaxed), 128);
+//! ```
+
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// measurement of a client
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientEvent {
+    /// a connection is established, including the handshake
+    Connected { latency: Duration },
+
+    /// a request got its response (`ok`), or failed
+    Request { rtt: Duration, ok: bool },
+
+    /// an operation is retried, `attempt` is 1 for the first retry
+    Retry { attempt: u32 },
+
+    /// bytes written to the connection, including framing
+    BytesSent(usize),
+
+    /// bytes read from the connection, including framing
+    BytesReceived(usize),
+}
+
+type Hook = Arc<dyn Fn(&ClientEvent) + Send + Sync>;
+
+/// callbacks of client events.
+/// Clones share the callbacks registered so far.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+}
+
+impl Hooks {
+    /// returns hooks without callbacks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// calls `f` with every event
+    pub fn on<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ClientEvent) + Send + Sync + 'static,
+    {
+        self.hooks.push(Arc::new(f));
+        self
+    }
+
+    /// calls every callback with `event`
+    pub fn emit(&self, event: &ClientEvent) {
+        self.hooks.iter().for_each(|hook| hook(event));
+    }
+
+    /// returns true if there is no callback
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("len", &self.hooks.len())
+            .finish()
+    }
+}
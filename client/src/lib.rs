@@ -0,0 +1,40 @@
+//! Connects a client to CubbyConnect servers with the same API as the
+//! server.
+//!
+//! Messages are sent and received through handler chains built from the
+//! `Handler` and `Layer` of `cubby-connect-server-core`, so middleware of
+//! the server (timeout, filter, inspect, tracing) and codecs work on both
+//! sides.
+//!
+//! - `blocking`: client for synchronous code, which blocks the thread
+//! - `client`: connection to a server with `send` and `recv`
+//! - `codec`: layers that encode, decode and compress messages
+//! - `config`: configuration of the connection
+//! - `error`: error when connecting
+//! - `event_bus`: typed subscriptions to received messages
+//! - `feature_flags`: feature flags told by the server
+//! - `hooks`: instrumentation hooks for analytics
+//! - `local_echo`: local echo of sent messages
+//! - `net_change`: detection of network changes
+//! - `pool`: pool of connections to many servers
+//! - `power_save`: power saving of mobile clients
+//! - `reconnect`: connection that reconnects when it breaks
+//! - `response_cache`: caching of responses
+
+#[macro_use]
+extern crate derive_builder;
+
+pub mod blocking;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod event_bus;
+pub mod feature_flags;
+pub mod hooks;
+pub mod local_echo;
+pub mod net_change;
+pub mod pool;
+pub mod power_save;
+pub mod reconnect;
+pub mod response_cache;
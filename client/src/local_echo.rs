@@ -0,0 +1,313 @@
+//! Local echo of sent messages on clients
+//!
+//! Chat and collaborative apps show a message as soon as the user sends it,
+//! instead of after a round trip to the server. `LocalEcho` delivers each
+//! sent message to local subscribers right away as `Echo::Pending`, and then
+//! reconciles it: `Echo::Confirmed` when the server acknowledges it, or
+//! `Echo::RolledBack` when the server rejects it (or it cannot be sent), so
+//! the subscriber removes the local copy.
+//!
+//! `LocalEchoLayer` goes in front of the handler that sends messages and
+//! waits for the acknowledgement: its success confirms the echo, and its
+//! error (or dropping the call) rolls the echo back. Acknowledgements that
+//! arrive separately can be given to `LocalEcho::confirm` and
+//! `LocalEcho::roll_back` instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_client::local_echo::{Echo, LocalEcho, LocalEchoLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! // sends the message, and waits for the acknowledgement of the server
+//! async fn send(msg: String) -> Result<(), String> {
+//!     if msg.contains("spam") {
+//!         Err(String::from("rejected"))
+//!     } else {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), String> {
+//! let echo = LocalEcho::new();
+//! let mut chat_view = echo.subscribe();
+//! let handler = apply!(LocalEchoLayer::new(echo.clone()) to send);
+//!
+//! handler.call(String::from("hello")).await?;
+//! let Some(Echo::Pending(id, msg)) = chat_view.recv().await else { panic!() };
+//! assert_eq!(msg, "hello");
+//! assert_eq!(chat_view.recv().await, Some(Echo::Confirmed(id)));
+//!
+//! assert!(handler.call(String::from("buy spam")).await.is_err());
+//! let Some(Echo::Pending(id, _)) = chat_view.recv().await else { panic!() };
+//! assert_eq!(chat_view.recv().await, Some(Echo::RolledBack(id)));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::layer::Layer;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// local id of an echoed message, increasing in the order of sends
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct EchoId(pub u64);
+
+/// event of an echoed message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Echo<T> {
+    /// sent, but not acknowledged yet
+    Pending(EchoId, T),
+
+    /// acknowledged by the server
+    Confirmed(EchoId),
+
+    /// rejected by the server or not sent, the local copy should be removed
+    RolledBack(EchoId),
+}
+
+struct Inner<T> {
+    next: u64,
+    pending: BTreeMap<EchoId, T>,
+    subscribers: Vec<UnboundedSender<Echo<T>>>,
+}
+
+impl<T: Clone> Inner<T> {
+    fn publish(&mut self, echo: Echo<T>) {
+        self.subscribers.retain(|tx| tx.send(echo.clone()).is_ok());
+    }
+
+    fn resolve(&mut self, id: EchoId, echo: Echo<T>) -> Option<T> {
+        let msg = self.pending.remove(&id)?;
+        self.publish(echo);
+        Some(msg)
+    }
+}
+
+/// sent messages of a client that are not acknowledged yet.
+/// Clones share the messages and subscribers.
+pub struct LocalEcho<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Clone for LocalEcho<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for LocalEcho<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next: 0,
+                pending: BTreeMap::new(),
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl<T: Clone> LocalEcho<T> {
+    /// returns a local echo without messages
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns events of messages echoed after this call.
+    /// Drop the receiver to unsubscribe.
+    pub fn subscribe(&self) -> UnboundedReceiver<Echo<T>> {
+        let (tx, rx) = unbounded_channel();
+        self.inner.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    /// delivers `msg` to subscribers as pending, and returns its id
+    pub fn echo(&self, msg: T) -> EchoId {
+        let mut inner = self.inner.lock().unwrap();
+        let id = EchoId(inner.next);
+        inner.next += 1;
+        inner.pending.insert(id, msg.clone());
+        inner.publish(Echo::Pending(id, msg));
+        id
+    }
+
+    /// confirms the pending message `id`, and returns it
+    pub fn confirm(&self, id: EchoId) -> Option<T> {
+        self.inner.lock().unwrap().resolve(id, Echo::Confirmed(id))
+    }
+
+    /// rolls back the pending message `id`, and returns it
+    pub fn roll_back(&self, id: EchoId) -> Option<T> {
+        self.inner.lock().unwrap().resolve(id, Echo::RolledBack(id))
+    }
+
+    /// returns pending messages in the order of sends, e.g. for subscribers
+    /// that start late
+    pub fn pending(&self) -> Vec<(EchoId, T)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .pending
+            .iter()
+            .map(|(id, msg)| (*id, msg.clone()))
+            .collect()
+    }
+}
+
+/// `Layer` that builds `LocalEchoHandler`
+pub struct LocalEchoLayer<T> {
+    echo: LocalEcho<T>,
+}
+
+impl<T> LocalEchoLayer<T> {
+    /// echoes messages of the next handler to `echo`
+    pub fn new(echo: LocalEcho<T>) -> Self {
+        Self { echo }
+    }
+}
+
+impl<T, H> Layer<T, H> for LocalEchoLayer<T>
+where
+    T: Clone + 'static,
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = LocalEchoHandler<T, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(LocalEchoHandler {
+            echo: self.echo.clone(),
+            prev,
+        })
+    }
+}
+
+/// rolls back the echo of a call that is dropped before it is done
+struct Unresolved<T: Clone> {
+    echo: LocalEcho<T>,
+    id: EchoId,
+}
+
+impl<T: Clone> Drop for Unresolved<T> {
+    fn drop(&mut self) {
+        self.echo.roll_back(self.id);
+    }
+}
+
+/// `Handler` that echoes messages before the previous handler sends them,
+/// and confirms or rolls them back by its result
+pub struct LocalEchoHandler<T, H> {
+    echo: LocalEcho<T>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for LocalEchoHandler<T, H>
+where
+    T: Clone + 'static,
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let unresolved = Unresolved {
+            echo: self.echo.clone(),
+            id: self.echo.echo(msg.clone()),
+        };
+        let future = self.prev.call(msg);
+
+        Box::pin(async move {
+            let result = future.await;
+            if result.is_ok() {
+                unresolved.echo.confirm(unresolved.id);
+            }
+            // rolled back by the drop if not confirmed
+            drop(unresolved);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use cubby_connect_server_core::fn_handler::fn_handler;
+    use cubby_connect_server_core::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn reconcile_test() {
+        let echo = LocalEcho::new();
+        let mut rx = echo.subscribe();
+        let a = echo.echo("a");
+        let b = echo.echo("b");
+        let c = echo.echo("c");
+        assert_eq!(echo.pending(), vec![(a, "a"), (b, "b"), (c, "c")]);
+
+        assert_eq!(echo.confirm(b), Some("b"));
+        assert_eq!(echo.roll_back(a), Some("a"));
+        // already resolved
+        assert_eq!(echo.confirm(a), None);
+        assert_eq!(echo.pending(), vec![(c, "c")]);
+
+        let events: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Echo::Pending(a, "a"),
+                Echo::Pending(b, "b"),
+                Echo::Pending(c, "c"),
+                Echo::Confirmed(b),
+                Echo::RolledBack(a),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_test() -> Result<(), ()> {
+        let echo = LocalEcho::new();
+        let mut rx = echo.subscribe();
+        let send = fn_handler(|i: i32| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if i < 0 {
+                Err(())
+            } else {
+                Ok(i)
+            }
+        });
+        let handler = connect(LocalEchoLayer::new(echo.clone()), send).await?;
+
+        // echoed before the acknowledgement
+        let call = handler.call(1);
+        assert_eq!(rx.try_recv(), Ok(Echo::Pending(EchoId(0), 1)));
+        assert_eq!(call.await, Ok(1));
+        assert_eq!(rx.try_recv(), Ok(Echo::Confirmed(EchoId(0))));
+
+        assert_eq!(handler.call(-1).await, Err(()));
+        assert_eq!(rx.try_recv(), Ok(Echo::Pending(EchoId(1), -1)));
+        assert_eq!(rx.try_recv(), Ok(Echo::RolledBack(EchoId(1))));
+
+        // cancelled calls are rolled back
+        let _ = tokio::time::timeout(Duration::from_millis(10), handler.call(2)).await;
+        assert_eq!(rx.try_recv(), Ok(Echo::Pending(EchoId(2), 2)));
+        assert_eq!(rx.try_recv(), Ok(Echo::RolledBack(EchoId(2))));
+        assert!(echo.pending().is_empty());
+        Ok(())
+    }
+}
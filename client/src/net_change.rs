@@ -0,0 +1,221 @@
+//! Detection of network changes on clients
+//!
+//! When a phone moves from Wi-Fi to cellular, its connection is dead, but a
+//! client only notices after heartbeats time out. The OS knows right away:
+//! an address of the old interface goes down and one of the new interface
+//! comes up. `NetworkMonitor` passes such changes to clients as
+//! `NetworkChanges`, so they can reconnect immediately.
+//!
+//! Changes come from the app (e.g. `ConnectivityManager` of Android through
+//! `NetworkMonitor::report`), or from the OS with `NetworkMonitor::watch`
+//! of the `net-watch` feature (netlink on Linux, System Configuration on
+//! macOS and iOS, IP Helper on Windows). A switch of interfaces makes
+//! several changes in a row, so changes are debounced and delivered
+//! together. Loopback addresses are ignored.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_client::net_change::{NetworkChange, NetworkMonitor};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let monitor = NetworkMonitor::new();
+//! let mut changes = monitor.subscribe();
+//!
+//! // reported by the app, or by `monitor.watch()` in a task
+//! monitor.report(NetworkChange::Down("192.168.0.10".parse().unwrap()));
+//! monitor.report(NetworkChange::Up("10.20.30.40".parse().unwrap()));
+//!
+//! let heartbeat_timeout = tokio::time::sleep(Duration::from_secs(30));
+//! tokio::select! {
+//!     Some(changes) = changes.next() => {
+//!         assert_eq!(changes.len(), 2);
+//!         // reconnect now
+//!     }
+//!     _ = heartbeat_timeout => {
+//!         // reconnect after the timeout
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::timeout;
+
+/// default time to wait for more changes
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// capacity of the channel of changes
+const CAPACITY: usize = 64;
+
+/// change of a local address
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NetworkChange {
+    Up(IpAddr),
+    Down(IpAddr),
+}
+
+impl NetworkChange {
+    /// returns the address that changed
+    pub fn addr(&self) -> IpAddr {
+        match self {
+            NetworkChange::Up(addr) | NetworkChange::Down(addr) => *addr,
+        }
+    }
+}
+
+/// source of network changes for clients.
+/// Clones report to the same subscribers.
+#[derive(Clone, Debug)]
+pub struct NetworkMonitor {
+    sender: broadcast::Sender<NetworkChange>,
+    debounce: Duration,
+}
+
+impl Default for NetworkMonitor {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            debounce: DEBOUNCE,
+        }
+    }
+}
+
+impl NetworkMonitor {
+    /// returns a monitor without changes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets how long subscribers wait for more changes after a change
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// reports a change to every subscriber
+    pub fn report(&self, change: NetworkChange) {
+        if !change.addr().is_loopback() {
+            // no subscriber is fine
+            let _ = self.sender.send(change);
+        }
+    }
+
+    /// returns changes reported after this call
+    pub fn subscribe(&self) -> NetworkChanges {
+        NetworkChanges {
+            receiver: self.sender.subscribe(),
+            debounce: self.debounce,
+        }
+    }
+
+    /// reports changes of addresses of the OS until an error.
+    ///
+    /// Addresses that exist when watching starts are not changes, and
+    /// the OS reports them first, so `Up`s in the first debounce period
+    /// are ignored.
+    #[cfg(feature = "net-watch")]
+    pub async fn watch(&self) -> std::io::Result<()> {
+        use futures::StreamExt;
+        use if_watch::tokio::IfWatcher;
+        use if_watch::IfEvent;
+        use tokio::time::Instant;
+
+        let mut watcher = IfWatcher::new()?;
+        let settled = Instant::now() + self.debounce;
+        while let Some(event) = watcher.next().await {
+            match event? {
+                IfEvent::Up(_) if Instant::now() < settled => {}
+                IfEvent::Up(net) => self.report(NetworkChange::Up(net.addr())),
+                IfEvent::Down(net) => self.report(NetworkChange::Down(net.addr())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// changes of a subscriber of `NetworkMonitor`
+#[derive(Debug)]
+pub struct NetworkChanges {
+    receiver: broadcast::Receiver<NetworkChange>,
+    debounce: Duration,
+}
+
+impl NetworkChanges {
+    /// waits for changes, and returns them when no more change comes in
+    /// the debounce period. Changes that were missed because the
+    /// subscriber was too slow are left out.
+    ///
+    /// Returns `None` when every `NetworkMonitor` is dropped.
+    pub async fn next(&mut self) -> Option<Vec<NetworkChange>> {
+        let mut changes = Vec::new();
+        loop {
+            match self.receiver.recv().await {
+                Ok(change) => {
+                    changes.push(change);
+                    break;
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+
+        while let Ok(received) = timeout(self.debounce, self.receiver.recv()).await {
+            match received {
+                Ok(change) => changes.push(change),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+        Some(changes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debounce_test() {
+        let monitor = NetworkMonitor::new().debounce(Duration::from_secs(1));
+        let mut changes = monitor.subscribe();
+
+        let reporter = monitor.clone();
+        tokio::spawn(async move {
+            reporter.report(NetworkChange::Down(addr("192.168.0.10")));
+            reporter.report(NetworkChange::Up(addr("127.0.0.1")));
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            reporter.report(NetworkChange::Up(addr("10.0.0.2")));
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            reporter.report(NetworkChange::Up(addr("fe80::1")));
+        });
+
+        let start = Instant::now();
+        assert_eq!(
+            changes.next().await.unwrap(),
+            vec![
+                NetworkChange::Down(addr("192.168.0.10")),
+                NetworkChange::Up(addr("10.0.0.2")),
+            ]
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+        assert_eq!(
+            changes.next().await.unwrap(),
+            vec![NetworkChange::Up(addr("fe80::1"))]
+        );
+
+        drop(monitor);
+        assert_eq!(changes.next().await, None);
+    }
+}
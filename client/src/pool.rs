@@ -0,0 +1,372 @@
+//! Pool of connections to servers
+//!
+//! Services that fan out to many servers (e.g. a lobby asking every game
+//! server for its rooms) reuse connections instead of connecting for each
+//! request. `ConnectionPool` keeps connections for each address, all made
+//! with the same `ClientConfig`, so they share the TLS settings and the
+//! negotiated codec:
+//!
+//! - `get` returns an idle connection to the address, or a new one
+//! - at most `max_size` connections are made to each address, and `get`
+//!   waits while all of them are in use
+//! - a connection returns to the pool when its `Pooled` is dropped, unless
+//!   it is `discard`ed (e.g. after an error)
+//! - connections idle for `idle_timeout` are closed by `get` and
+//!   `evict_idle`
+//! - idle connections that the server closed, or that fail the health
+//!   check, are closed instead of being handed out
+//!
+//! Frames that arrive on idle connections are dropped.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use cubby_connect_client::client::Client;
+//! use cubby_connect_client::config::ClientConfig;
+//! use cubby_connect_client::pool::ConnectionPool;
+//! use cubby_connect_server_core::error::CubbyError;
+//!
+//! async fn ping(mut client: Client) -> Result<Client, CubbyError> {
+//!     client.send(b"ping".to_vec()).await?;
+//!     match client.recv().await? {
+//!         Some(pong) if pong == b"pong" => Ok(client),
+//!         _ => Err(CubbyError::Missing("pong")),
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let pool = ConnectionPool::new(ClientConfig::builder().build()?)
+//!     .max_size(8)
+//!     .idle_timeout(Duration::from_secs(30))
+//!     .health_check(ping);
+//!
+//! for server in ["10.0.0.1:20202", "10.0.0.2:20202"] {
+//!     let mut client = pool.get(server).await?;
+//!     client.send(b"rooms".to_vec()).await?;
+//!     println!("{:?}", client.recv().await?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cubby_connect_server_core::error::CubbyError;
+use cubby_connect_server_core::handler::{Handler, IntoHandler};
+use futures::future::{ok, Ready};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::client::Client;
+use crate::config::ClientConfig;
+use crate::error::ConnectError;
+
+/// connections to one address
+struct Slot {
+    /// permits of connections in use
+    semaphore: Arc<Semaphore>,
+
+    /// idle connections with when they became idle
+    idle: Vec<(Client, Instant)>,
+}
+
+type Slots = Arc<Mutex<HashMap<String, Slot>>>;
+
+/// pool of connections to servers, which checks idle connections with `H`
+/// before handing them out.
+/// Clones share the same connections.
+pub struct ConnectionPool<H = NoCheck> {
+    config: ClientConfig,
+    max_size: usize,
+    idle_timeout: Duration,
+    health_check: Arc<H>,
+    slots: Slots,
+}
+
+impl<H> Clone for ConnectionPool<H> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            max_size: self.max_size,
+            idle_timeout: self.idle_timeout,
+            health_check: self.health_check.clone(),
+            slots: self.slots.clone(),
+        }
+    }
+}
+
+impl ConnectionPool {
+    /// makes connections with `config`.
+    /// By default, up to 16 connections are made to each address, and they
+    /// are closed after being idle for 5 minutes.
+    pub fn new(config: ClientConfig) -> Self {
+        Self {
+            config,
+            max_size: 16,
+            idle_timeout: Duration::from_secs(300),
+            health_check: Arc::new(NoCheck),
+            slots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<H> ConnectionPool<H> {
+    /// sets the largest number of connections to each address
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_size` is zero.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        assert!(max_size > 0, "max_size should be positive");
+        self.max_size = max_size;
+        self
+    }
+
+    /// closes connections that are idle for `idle_timeout`
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// checks idle connections with `health_check` before handing them
+    /// out. Connections it returns an error for are closed.
+    pub fn health_check<H2, C>(self, health_check: C) -> ConnectionPool<H2>
+    where
+        H2: Handler<Client, Output = Client>,
+        C: IntoHandler<H2, Client>,
+    {
+        ConnectionPool {
+            config: self.config,
+            max_size: self.max_size,
+            idle_timeout: self.idle_timeout,
+            health_check: Arc::new(health_check.into_handler()),
+            slots: self.slots,
+        }
+    }
+
+    /// returns the number of idle connections to `addr`
+    pub fn idle(&self, addr: &str) -> usize {
+        let slots = self.slots.lock().unwrap();
+        slots.get(addr).map_or(0, |slot| slot.idle.len())
+    }
+
+    /// closes connections that are idle for `idle_timeout`, or that the
+    /// server closed. Returns the number of closed connections.
+    pub fn evict_idle(&self) -> usize {
+        let mut slots = self.slots.lock().unwrap();
+        let mut evicted = 0;
+        for slot in slots.values_mut() {
+            let before = slot.idle.len();
+            slot.idle.retain_mut(|(client, since)| {
+                since.elapsed() < self.idle_timeout && !client.is_closed()
+            });
+            evicted += before - slot.idle.len();
+        }
+        evicted
+    }
+
+    /// returns a connection to `addr`, waiting while `max_size`
+    /// connections to it are in use
+    pub async fn get(&self, addr: &str) -> Result<Pooled, ConnectError>
+    where
+        H: Handler<Client, Output = Client>,
+    {
+        let semaphore = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(addr.to_owned())
+            .or_insert_with(|| Slot {
+                semaphore: Arc::new(Semaphore::new(self.max_size)),
+                idle: Vec::new(),
+            })
+            .semaphore
+            .clone();
+        // the semaphore is never closed
+        let permit = semaphore.acquire_owned().await.unwrap();
+
+        loop {
+            // the most recently used connection is the most likely alive
+            let idle = match self.slots.lock().unwrap().get_mut(addr) {
+                Some(slot) => slot.idle.pop(),
+                None => None,
+            };
+            let Some((mut client, since)) = idle else {
+                break;
+            };
+            if since.elapsed() >= self.idle_timeout || client.is_closed() {
+                continue;
+            }
+            if let Ok(client) = self.health_check.call(client).await {
+                return Ok(self.pooled(addr, client, permit));
+            }
+        }
+
+        let client = Client::connect(addr, &self.config).await?;
+        Ok(self.pooled(addr, client, permit))
+    }
+
+    fn pooled(&self, addr: &str, client: Client, permit: OwnedSemaphorePermit) -> Pooled {
+        Pooled {
+            client: Some(client),
+            addr: addr.to_owned(),
+            slots: self.slots.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+/// connection from `ConnectionPool`, which returns to the pool when
+/// dropped
+pub struct Pooled {
+    client: Option<Client>,
+    addr: String,
+    slots: Slots,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pooled {
+    /// closes the connection instead of returning it to the pool
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for Pooled {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        // only `discard` and `drop` take the client
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        // the connection is idle before the permit is released, so that
+        // waiting `get` takes it
+        if let Some(client) = self.client.take() {
+            if let Some(slot) = self.slots.lock().unwrap().get_mut(&self.addr) {
+                slot.idle.push((client, Instant::now()));
+            }
+        }
+    }
+}
+
+/// health check that passes every connection
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoCheck;
+
+impl Handler<Client> for NoCheck {
+    type Output = Client;
+    type Error = CubbyError;
+    type Future = Ready<Result<Client, CubbyError>>;
+
+    fn call(&self, client: Client) -> Self::Future {
+        ok(client)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use cubby_connect_server_core::config::Config;
+    use cubby_connect_server_core::context::{Missing, Request};
+    use cubby_connect_server_core::fn_handler::fn_handler;
+    use cubby_connect_server_core::server::{Server, ServerHandle};
+
+    use super::*;
+
+    async fn echo(request: Request<String>) -> Result<String, Missing> {
+        Ok(request.msg)
+    }
+
+    async fn serve() -> Result<(ServerHandle, String), Box<dyn std::error::Error>> {
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let server = Server::from_config(config)
+            .handler(fn_handler(echo))
+            .run()
+            .await?;
+        let addr = server.tcp_addr().to_string();
+        Ok((server, addr))
+    }
+
+    #[tokio::test]
+    async fn pool_test() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, addr) = serve().await?;
+        let pool = ConnectionPool::new(ClientConfig::builder().build()?).max_size(1);
+
+        let first = pool.get(&addr).await?;
+        // waits for the first connection
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            let addr = addr.clone();
+            async move { pool.get(&addr).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await??;
+        assert_eq!(pool.idle(&addr), 1);
+
+        let mut second = pool.get(&addr).await?;
+        assert_eq!(pool.idle(&addr), 0);
+        second.send(b"\x0a\x02hi".to_vec()).await?;
+        assert!(second.recv().await?.is_some());
+        second.discard();
+        assert_eq!(pool.idle(&addr), 0);
+
+        pool.get(&addr).await?;
+        assert_eq!(pool.idle(&addr), 1);
+        server.shutdown().await?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pool.evict_idle(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn eviction_test() -> Result<(), Box<dyn std::error::Error>> {
+        let (server, addr) = serve().await?;
+        let checked = Arc::new(AtomicUsize::new(0));
+        let pool = ConnectionPool::new(ClientConfig::builder().build()?)
+            .idle_timeout(Duration::from_millis(50))
+            .health_check(fn_handler({
+                let checked = checked.clone();
+                move |client: Client| {
+                    let healthy = checked.fetch_add(1, Ordering::Relaxed) > 0;
+                    async move {
+                        match healthy {
+                            true => Ok(client),
+                            false => Err(CubbyError::Missing("pong")),
+                        }
+                    }
+                }
+            }));
+
+        // the first check fails, so a new connection is made
+        pool.get(&addr).await?;
+        pool.get(&addr).await?;
+        assert_eq!(checked.load(Ordering::Relaxed), 1);
+        pool.get(&addr).await?;
+        assert_eq!(checked.load(Ordering::Relaxed), 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(pool.evict_idle(), 1);
+        assert_eq!(pool.idle(&addr), 0);
+        server.shutdown().await?;
+        Ok(())
+    }
+}
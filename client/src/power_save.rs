@@ -0,0 +1,298 @@
+//! Power saving of mobile clients
+//!
+//! Every send wakes up the radio of a phone, which stays on for seconds
+//! afterwards. While the app is in the background, a client should wake it
+//! up as rarely as possible. `PowerSaving` is the send queue of a client
+//! that knows whether the app is in the foreground (`AppState`, reported
+//! by the app):
+//!
+//! - in the foreground, everything is sent as soon as possible
+//! - in the background, `Urgency::Normal` messages are batched for
+//!   `batch_window`, `Urgency::Urgent` messages are sent right away
+//!   (together with the batched ones), and `Urgency::Background` messages
+//!   are paused until the app is in the foreground again
+//! - heartbeats are sent `heartbeat_multiplier` times less often
+//!
+//! The writer of the connection takes batches with `next_batch`, and sends
+//! the messages of a batch at once.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_client::power_save::{AppState, PowerSaving, Urgency};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let sends = PowerSaving::new().batch_window(Duration::from_millis(10));
+//!
+//! // the app went to the background
+//! sends.set_state(AppState::Background);
+//! assert_eq!(sends.heartbeat_interval(Duration::from_secs(15)), Duration::from_secs(60));
+//!
+//! sends.send("position", Urgency::Normal);
+//! sends.send("analytics", Urgency::Background);
+//! sends.send("chat", Urgency::Urgent);
+//! assert_eq!(sends.next_batch().await.0, vec!["position", "chat"]);
+//!
+//! sends.set_state(AppState::Foreground);
+//! assert_eq!(sends.next_batch().await.0, vec!["analytics"]);
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cubby_connect_server_core::batch::Batch;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+
+/// default factor of heartbeat intervals in the background
+pub const HEARTBEAT_MULTIPLIER: u32 = 4;
+
+/// default time to batch messages in the background
+pub const BATCH_WINDOW: Duration = Duration::from_secs(5);
+
+/// default largest batch
+pub const MAX_BATCH: usize = 64;
+
+/// state of the app, reported by the app
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum AppState {
+    #[default]
+    Foreground,
+    Background,
+}
+
+/// how soon a message should be sent
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Urgency {
+    /// sent right away in any state
+    Urgent,
+
+    /// batched in the background
+    #[default]
+    Normal,
+
+    /// paused in the background (e.g. analytics, prefetching)
+    Background,
+}
+
+struct Queue<T> {
+    state: AppState,
+
+    /// `Urgent` and `Normal` messages in order
+    sends: VecDeque<T>,
+
+    /// paused `Background` messages in order
+    paused: VecDeque<T>,
+
+    /// when the oldest message of `sends` was queued
+    since: Option<Instant>,
+
+    /// true if `sends` has an `Urgent` message
+    urgent: bool,
+}
+
+/// send queue of a client that batches and pauses in the background
+pub struct PowerSaving<T> {
+    heartbeat_multiplier: u32,
+    batch_window: Duration,
+    max_batch: usize,
+    queue: Arc<Mutex<Queue<T>>>,
+    notify: Arc<Notify>,
+}
+
+impl<T> Clone for PowerSaving<T> {
+    fn clone(&self) -> Self {
+        Self {
+            heartbeat_multiplier: self.heartbeat_multiplier,
+            batch_window: self.batch_window,
+            max_batch: self.max_batch,
+            queue: self.queue.clone(),
+            notify: self.notify.clone(),
+        }
+    }
+}
+
+impl<T> Default for PowerSaving<T> {
+    fn default() -> Self {
+        Self {
+            heartbeat_multiplier: HEARTBEAT_MULTIPLIER,
+            batch_window: BATCH_WINDOW,
+            max_batch: MAX_BATCH,
+            queue: Arc::new(Mutex::new(Queue {
+                state: AppState::Foreground,
+                sends: VecDeque::new(),
+                paused: VecDeque::new(),
+                since: None,
+                urgent: false,
+            })),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl<T> PowerSaving<T> {
+    /// returns an empty queue of an app in the foreground
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets the factor of heartbeat intervals in the background
+    pub fn heartbeat_multiplier(mut self, multiplier: u32) -> Self {
+        self.heartbeat_multiplier = multiplier.max(1);
+        self
+    }
+
+    /// sets how long messages are batched in the background
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = window;
+        self
+    }
+
+    /// sets the largest batch, which is sent without waiting for the window
+    pub fn max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch.max(1);
+        self
+    }
+
+    /// changes the state of the app
+    pub fn set_state(&self, state: AppState) {
+        self.queue.lock().unwrap().state = state;
+        self.notify.notify_one();
+    }
+
+    /// returns the state of the app
+    pub fn state(&self) -> AppState {
+        self.queue.lock().unwrap().state
+    }
+
+    /// returns the heartbeat interval of the current state for `base`,
+    /// which is the interval in the foreground
+    pub fn heartbeat_interval(&self, base: Duration) -> Duration {
+        match self.state() {
+            AppState::Foreground => base,
+            AppState::Background => base * self.heartbeat_multiplier,
+        }
+    }
+
+    /// queues `msg`
+    pub fn send(&self, msg: T, urgency: Urgency) {
+        let mut queue = self.queue.lock().unwrap();
+        match urgency {
+            Urgency::Background => queue.paused.push_back(msg),
+            _ => {
+                queue.sends.push_back(msg);
+                queue.since.get_or_insert_with(Instant::now);
+                queue.urgent |= urgency == Urgency::Urgent;
+            }
+        }
+        drop(queue);
+        self.notify.notify_one();
+    }
+
+    /// returns the number of queued messages, including paused ones
+    pub fn pending(&self) -> usize {
+        let queue = self.queue.lock().unwrap();
+        queue.sends.len() + queue.paused.len()
+    }
+
+    /// waits until messages should be sent, and takes them.
+    /// In the foreground, paused messages come after the others.
+    pub async fn next_batch(&self) -> Batch<T> {
+        loop {
+            let deadline = {
+                let mut queue = self.queue.lock().unwrap();
+                let queue = &mut *queue;
+                match queue.state {
+                    AppState::Foreground if !queue.sends.is_empty() || !queue.paused.is_empty() => {
+                        queue.since = None;
+                        queue.urgent = false;
+                        let mut batch: Vec<T> = queue.sends.drain(..).collect();
+                        batch.extend(queue.paused.drain(..));
+                        return Batch(batch);
+                    }
+                    AppState::Background if !queue.sends.is_empty() => {
+                        let deadline = queue.since.map(|since| since + self.batch_window);
+                        if queue.urgent
+                            || queue.sends.len() >= self.max_batch
+                            || deadline.is_some_and(|deadline| deadline <= Instant::now())
+                        {
+                            queue.since = None;
+                            queue.urgent = false;
+                            return Batch(queue.sends.drain(..).collect());
+                        }
+                        deadline
+                    }
+                    _ => None,
+                }
+            };
+
+            match deadline {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {}
+                        _ = sleep_until(deadline) => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn background_test() {
+        let sends = PowerSaving::new()
+            .batch_window(Duration::from_secs(5))
+            .max_batch(3);
+        sends.send(1, Urgency::Normal);
+        assert_eq!(sends.next_batch().await.0, vec![1]);
+
+        sends.set_state(AppState::Background);
+        let start = Instant::now();
+        sends.send(2, Urgency::Normal);
+        sends.send(3, Urgency::Background);
+        sends.send(4, Urgency::Normal);
+        assert_eq!(sends.next_batch().await.0, vec![2, 4]);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // a full batch is sent without waiting
+        for i in 5..8 {
+            sends.send(i, Urgency::Normal);
+        }
+        assert_eq!(sends.next_batch().await.0, vec![5, 6, 7]);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(sends.pending(), 1);
+
+        // waits for the state to change when only paused messages are left
+        let waiter = sends.clone();
+        let batch = tokio::spawn(async move { waiter.next_batch().await });
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        sends.set_state(AppState::Foreground);
+        assert_eq!(batch.await.unwrap().0, vec![3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn urgent_test() {
+        let sends = PowerSaving::new();
+        sends.set_state(AppState::Background);
+        assert_eq!(
+            sends.heartbeat_interval(Duration::from_secs(10)),
+            Duration::from_secs(40)
+        );
+
+        let waiter = sends.clone();
+        let batch = tokio::spawn(async move { waiter.next_batch().await });
+        sends.send("move", Urgency::Normal);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        sends.send("attack", Urgency::Urgent);
+        assert_eq!(batch.await.unwrap().0, vec!["move", "attack"]);
+    }
+}
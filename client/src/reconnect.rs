@@ -0,0 +1,847 @@
+//! Connection that reconnects when it breaks
+//!
+//! Mobile networks drop for a while (tunnels, elevators, switching from
+//! Wi-Fi to cellular). `ReconnectingClient` has the same `send` and `recv`
+//! as `Client`, but when the connection breaks, it connects again in the
+//! background instead of ending:
+//!
+//! - a broken connection is found when reading or writing fails
+//! - connecting is retried after `Backoff` delays, which grow exponentially
+//!   and are randomized (jitter) so clients don't reconnect all at once
+//! - frames sent while offline are queued (up to `Reconnect::queue`) and
+//!   written right after reconnecting, in order
+//! - every change is published as a `ConnectionState` to `state`
+//! - every attempt is reported as `ClientEvent::Retry` to `Reconnect::hooks`
+//! - heartbeat pings of the server are answered in the background
+//! - with `Reconnect::read_timeout`, a connection that reads nothing for a
+//!   while (a half-open connection, whose peer is gone without closing it)
+//!   is considered broken
+//!
+//! The first connection is not retried, so wrong addresses or certificates
+//! are returned from `connect`. Each reconnection does the handshake again
+//! with the same configuration, but offers only the codec negotiated first,
+//! because the outgoing chain encodes with it.
+//!
+//! The compression is negotiated again by each handshake, so frames are
+//! compressed and decompressed by the client itself (instead of
+//! `CompressLayer` and `DecompressLayer`) with the compression of the
+//! current connection. Frames queued while offline are compressed when
+//! they are written.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use cubby_connect_client::codec::{DecodeLayer, EncodeLayer};
+//! use cubby_connect_client::client::Deliver;
+//! use cubby_connect_client::config::ClientConfig;
+//! use cubby_connect_client::reconnect::{Backoff, ConnectionState, Reconnect, ReconnectingClient};
+//! use cubby_connect_server_core::apply;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let reconnect = Reconnect::new()
+//!     .backoff(Backoff::new(Duration::from_millis(500), Duration::from_secs(30)))
+//!     .queue(256);
+//! let config = ClientConfig::builder().build()?;
+//! let client = ReconnectingClient::connect("127.0.0.1:20202", &config, reconnect).await?;
+//!
+//! let mut state = client.state();
+//! tokio::spawn(async move {
+//!     while state.changed().await.is_ok() {
+//!         if let ConnectionState::Reconnecting { attempt, .. } = *state.borrow() {
+//!             println!("reconnecting ({attempt})");
+//!         }
+//!     }
+//! });
+//!
+//! let sink = client.sink();
+//! let mut client = client
+//!     .outgoing(apply!(EncodeLayer::<String>::new() to sink))
+//!     .incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+//!
+//! // queued while offline
+//! client.send(String::from("hello")).await?;
+//! while let Some(msg) = client.recv().await? {
+//!     println!("{msg}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use cubby_connect_server_core::codec::compress::{CompressError, Compression, DeflateContext};
+use cubby_connect_server_core::codec::framed::{FrameCodec, FramedRead};
+use cubby_connect_server_core::error::CubbyError;
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::heartbeat::answer;
+use cubby_connect_server_core::transport::write_frame;
+use futures::future::BoxFuture;
+use tokio::io::{split, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use tokio::time::{sleep, timeout};
+
+use crate::client::{open, BoxStream, Deliver};
+use crate::config::ClientConfig;
+use crate::error::ConnectError;
+use crate::hooks::{ClientEvent, Hooks};
+
+/// number of received frames that wait for `recv`
+const RECEIVED: usize = 64;
+
+/// frames smaller than this are sent uncompressed
+const COMPRESS_THRESHOLD: usize = 1024;
+
+/// delays between attempts to reconnect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    /// waits `initial` before the first attempt, and doubles it for each
+    /// failed attempt up to `max`.
+    /// By default, up to half of each delay is cut at random.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            factor: 2,
+            jitter: 0.5,
+        }
+    }
+
+    /// sets how many times longer each delay is than the previous one
+    pub fn factor(mut self, factor: u32) -> Self {
+        self.factor = factor;
+        self
+    }
+
+    /// sets the part of each delay (from 0 to 1) that is cut at random.
+    /// With 0, delays are exact, and with 1, they are anywhere from zero
+    /// to the full delay.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// returns the delay before the attempt after `failed` failed attempts
+    pub fn delay(&self, failed: u32) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(self.factor.saturating_pow(failed))
+            .min(self.max);
+        delay.mul_f64(1.0 - self.jitter * random())
+    }
+}
+
+/// returns a random number in `[0, 1)`
+fn random() -> f64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// options of reconnecting
+#[derive(Clone, Debug, Default)]
+pub struct Reconnect {
+    backoff: Backoff,
+    max_attempts: Option<u32>,
+    queue: usize,
+    read_timeout: Option<Duration>,
+    hooks: Hooks,
+}
+
+impl Reconnect {
+    /// reconnects forever with the default `Backoff`, without queueing
+    /// frames while offline
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets delays between attempts
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// gives up after `max_attempts` failed attempts in a row, and closes
+    /// the client
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// queues up to `queue` frames while offline.
+    /// Sending fails while offline if the queue is full (or 0).
+    pub fn queue(mut self, queue: usize) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// considers the connection broken if nothing is read for
+    /// `read_timeout` (default: never).
+    /// Against a server that pings with `Server::heartbeat`, this should be
+    /// longer than `heartbeat_interval` of its `Config`, such as
+    /// `heartbeat_interval + heartbeat_timeout`.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// reports every attempt to reconnect to `hooks`
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+}
+
+/// state of the connection of `ReconnectingClient`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+
+    /// the connection broke
+    Disconnected,
+
+    /// `attempt`th attempt is made after `delay`
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+
+    /// closed by `close`, or gave up reconnecting
+    Closed,
+}
+
+enum Link {
+    Online(Writer),
+    Offline(VecDeque<Vec<u8>>),
+    Closed,
+}
+
+/// writing half of a connection, and the compression negotiated by it
+struct Writer {
+    writer: WriteHalf<BoxStream>,
+    compression: Compression,
+}
+
+/// state shared between the client, its sinks and the reconnecting task
+struct Shared {
+    link: Mutex<Link>,
+    queue: usize,
+
+    /// negotiated by the current (or last) connection
+    compression: std::sync::Mutex<Compression>,
+
+    /// wakes the task when writing found a broken connection, or when the
+    /// client is closed
+    broken: Notify,
+}
+
+/// connection to a server that reconnects when it breaks, which sends
+/// messages through `S` and receives messages through `R`
+pub struct ReconnectingClient<S = QueueSink, R = Deliver> {
+    frames: mpsc::Receiver<Bytes>,
+    sink: QueueSink,
+    outgoing: S,
+    incoming: R,
+    state: watch::Receiver<ConnectionState>,
+
+    /// negotiated in the first handshake
+    codec: Option<String>,
+}
+
+impl ReconnectingClient {
+    /// connects to `addr` with `config`, and reconnects with `reconnect`
+    /// whenever the connection breaks
+    pub async fn connect<A>(
+        addr: A,
+        config: &ClientConfig,
+        reconnect: Reconnect,
+    ) -> Result<Self, ConnectError>
+    where
+        A: ToSocketAddrs + Clone + Send + Sync + 'static,
+    {
+        let (stream, codec, compression) = open(addr.clone(), config).await?;
+        let (reader, writer) = split(stream);
+
+        let shared = Arc::new(Shared {
+            link: Mutex::new(Link::Online(Writer {
+                writer,
+                compression,
+            })),
+            queue: reconnect.queue,
+            compression: std::sync::Mutex::new(compression),
+            broken: Notify::new(),
+        });
+        let config = match &codec {
+            Some(codec) => ClientConfig {
+                codecs: vec![codec.clone()],
+                ..config.clone()
+            },
+            None => config.clone(),
+        };
+        let (frames, received) = mpsc::channel(RECEIVED);
+        let (state, state_rx) = watch::channel(ConnectionState::Connected);
+        tokio::spawn(run(
+            shared.clone(),
+            addr,
+            config,
+            reconnect,
+            (reader, compression),
+            frames,
+            state,
+        ));
+
+        let sink = QueueSink { shared };
+        Ok(Self {
+            frames: received,
+            sink: sink.clone(),
+            outgoing: sink,
+            incoming: Deliver,
+            state: state_rx,
+            codec,
+        })
+    }
+}
+
+impl<S, R> ReconnectingClient<S, R> {
+    /// returns the handler that writes frames to the server (or queues them
+    /// while offline), which the outgoing chain ends with
+    pub fn sink(&self) -> QueueSink {
+        self.sink.clone()
+    }
+
+    /// sends messages through `outgoing`, which should end with `sink`
+    pub fn outgoing<S2>(self, outgoing: S2) -> ReconnectingClient<S2, R> {
+        ReconnectingClient {
+            frames: self.frames,
+            sink: self.sink,
+            outgoing,
+            incoming: self.incoming,
+            state: self.state,
+            codec: self.codec,
+        }
+    }
+
+    /// receives frames through `incoming`
+    pub fn incoming<R2>(self, incoming: R2) -> ReconnectingClient<S, R2> {
+        ReconnectingClient {
+            frames: self.frames,
+            sink: self.sink,
+            outgoing: self.outgoing,
+            incoming,
+            state: self.state,
+            codec: self.codec,
+        }
+    }
+
+    /// returns the codec negotiated in the first handshake
+    pub fn codec(&self) -> Option<&str> {
+        self.codec.as_deref()
+    }
+
+    /// returns the compression negotiated by the current (or last)
+    /// connection
+    pub fn compression(&self) -> Compression {
+        *self.sink.shared.compression.lock().unwrap()
+    }
+
+    /// returns a receiver of changes of the connection
+    pub fn state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    /// sends `msg` through the outgoing chain
+    pub async fn send<T>(&self, msg: T) -> Result<S::Output, S::Error>
+    where
+        S: Handler<T>,
+    {
+        self.outgoing.call(msg).await
+    }
+
+    /// waits for the next frame (across reconnections), and returns the
+    /// output of the incoming chain for it.
+    /// Returns `None` if the client is closed.
+    pub async fn recv(&mut self) -> Result<Option<R::Output>, R::Error>
+    where
+        R: Handler<Vec<u8>>,
+    {
+        match self.frames.recv().await {
+            Some(frame) => Ok(Some(self.incoming.call(frame.to_vec()).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// closes the connection and stops reconnecting.
+    /// Queued frames are dropped.
+    pub async fn close(&self) -> io::Result<()> {
+        let link = std::mem::replace(&mut *self.sink.shared.link.lock().await, Link::Closed);
+        self.sink.shared.broken.notify_one();
+        match link {
+            Link::Online(mut online) => online.writer.shutdown().await,
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `Handler` that writes frames to the server, or queues them while
+/// offline.
+/// Clones write to the same connection.
+#[derive(Clone)]
+pub struct QueueSink {
+    shared: Arc<Shared>,
+}
+
+impl Handler<Vec<u8>> for QueueSink {
+    type Output = ();
+    type Error = CubbyError;
+    type Future = BoxFuture<'static, Result<(), CubbyError>>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        let shared = self.shared.clone();
+        Box::pin(async move {
+            let mut link = shared.link.lock().await;
+            match &mut *link {
+                Link::Online(writer) => match write(writer, &frame).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        // the frame is sent again after reconnecting
+                        let mut queue = VecDeque::new();
+                        if shared.queue > 0 {
+                            queue.push_back(frame);
+                        }
+                        *link = Link::Offline(queue);
+                        shared.broken.notify_one();
+                        if shared.queue > 0 {
+                            Ok(())
+                        } else {
+                            Err(e.into())
+                        }
+                    }
+                },
+                Link::Offline(queue) if queue.len() < shared.queue => {
+                    queue.push_back(frame);
+                    Ok(())
+                }
+                Link::Offline(_) => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "offline and the queue is full",
+                )
+                .into()),
+                Link::Closed => Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+            }
+        })
+    }
+}
+
+/// compresses `frame` with the compression of the connection, and writes it
+async fn write(online: &mut Writer, frame: &[u8]) -> io::Result<()> {
+    let frame = online
+        .compression
+        .compress(frame, COMPRESS_THRESHOLD)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_raw(&mut online.writer, &frame).await
+}
+
+/// writes `frame` as it is, like pongs, which are never compressed
+async fn write_raw(writer: &mut WriteHalf<BoxStream>, frame: &[u8]) -> io::Result<()> {
+    write_frame(writer, frame).await?;
+    writer.flush().await
+}
+
+/// reads the next frame.
+/// Fails if nothing is read for `read_timeout`.
+async fn read(
+    reader: &mut FramedRead<ReadHalf<BoxStream>>,
+    read_timeout: Option<Duration>,
+) -> io::Result<Option<Bytes>> {
+    match read_timeout {
+        Some(read_timeout) => timeout(read_timeout, reader.next())
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => reader.next().await,
+    }
+}
+
+/// decompresses a frame (other than heartbeats) with the compression of
+/// the connection
+fn decompress(
+    frame: Bytes,
+    compression: Compression,
+    deflate: &DeflateContext,
+) -> Result<Bytes, CompressError> {
+    if compression == Compression::None {
+        return Ok(frame);
+    }
+    deflate.decompress(&frame).map(Bytes::from)
+}
+
+/// reads frames into `frames`, and reconnects when the connection breaks
+async fn run<A>(
+    shared: Arc<Shared>,
+    addr: A,
+    config: ClientConfig,
+    reconnect: Reconnect,
+    (reader, compression): (ReadHalf<BoxStream>, Compression),
+    frames: mpsc::Sender<Bytes>,
+    state: watch::Sender<ConnectionState>,
+) where
+    A: ToSocketAddrs + Clone,
+{
+    let mut reader = FramedRead::new(reader, FrameCodec::default());
+    let mut compression = compression;
+    loop {
+        // the deflate stream of the server starts again with each connection
+        let deflate = DeflateContext::new();
+        loop {
+            tokio::select! {
+                frame = read(&mut reader, reconnect.read_timeout) => match frame {
+                    Ok(Some(frame)) => {
+                        if let Some(pong) = answer(&frame) {
+                            if let Link::Online(online) = &mut *shared.link.lock().await {
+                                let _ = write_raw(&mut online.writer, &pong).await;
+                            }
+                            continue;
+                        }
+                        let Ok(frame) = decompress(frame, compression, &deflate) else {
+                            break;
+                        };
+                        if frames.send(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) | Err(_) => break,
+                },
+                _ = shared.broken.notified() => {
+                    // permits of connections that are replaced already
+                    if !matches!(*shared.link.lock().await, Link::Online(_)) {
+                        break;
+                    }
+                }
+                _ = frames.closed() => break,
+            }
+        }
+
+        {
+            let mut link = shared.link.lock().await;
+            if frames.is_closed() || matches!(*link, Link::Closed) {
+                *link = Link::Closed;
+                let _ = state.send(ConnectionState::Closed);
+                return;
+            }
+            if let Link::Online(_) = *link {
+                *link = Link::Offline(VecDeque::new());
+            }
+        }
+        let _ = state.send(ConnectionState::Disconnected);
+
+        match reconnect_with(&shared, &addr, &config, &reconnect, &frames, &state).await {
+            Some((connected, negotiated)) => {
+                reader = FramedRead::new(connected, FrameCodec::default());
+                compression = negotiated;
+            }
+            None => {
+                *shared.link.lock().await = Link::Closed;
+                let _ = state.send(ConnectionState::Closed);
+                return;
+            }
+        }
+    }
+}
+
+/// connects again and writes queued frames, and returns the reading half
+/// and the compression of the new connection.
+/// Returns `None` if it gave up, or if the client is closed.
+async fn reconnect_with<A>(
+    shared: &Shared,
+    addr: &A,
+    config: &ClientConfig,
+    reconnect: &Reconnect,
+    frames: &mpsc::Sender<Bytes>,
+    state: &watch::Sender<ConnectionState>,
+) -> Option<(ReadHalf<BoxStream>, Compression)>
+where
+    A: ToSocketAddrs + Clone,
+{
+    let mut failed = 0;
+    loop {
+        if reconnect.max_attempts.is_some_and(|max| failed >= max) {
+            return None;
+        }
+        let (attempt, delay) = (failed + 1, reconnect.backoff.delay(failed));
+        let _ = state.send(ConnectionState::Reconnecting { attempt, delay });
+        let wake = sleep(delay);
+        tokio::pin!(wake);
+        loop {
+            tokio::select! {
+                _ = &mut wake => break,
+                _ = frames.closed() => return None,
+                _ = shared.broken.notified() => {
+                    if matches!(*shared.link.lock().await, Link::Closed) {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        reconnect.hooks.emit(&ClientEvent::Retry { attempt });
+        if let Ok((stream, _, compression)) = open(addr.clone(), config).await {
+            let (reader, writer) = split(stream);
+            let mut writer = Writer {
+                writer,
+                compression,
+            };
+            let mut link = shared.link.lock().await;
+            let Link::Offline(queue) = &mut *link else {
+                return None;
+            };
+
+            let mut flushed = true;
+            while let Some(frame) = queue.front() {
+                if write(&mut writer, frame).await.is_err() {
+                    flushed = false;
+                    break;
+                }
+                queue.pop_front();
+            }
+            if flushed {
+                *link = Link::Online(writer);
+                *shared.compression.lock().unwrap() = compression;
+                let _ = state.send(ConnectionState::Connected);
+                return Some((reader, compression));
+            }
+        }
+        failed = failed.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cubby_connect_server_core::apply;
+    use cubby_connect_server_core::codec::compress::{FLAG_LZ4, FLAG_ZSTD};
+    use cubby_connect_server_core::codec::{Codecs, Protobuf};
+    use cubby_connect_server_core::config::Config;
+    use cubby_connect_server_core::context::{Missing, Request};
+    use cubby_connect_server_core::fn_handler::fn_handler;
+    use cubby_connect_server_core::handshake::accept_hello;
+    use cubby_connect_server_core::server::{Server, ServerHandle};
+    use cubby_connect_server_core::transport::read_frame;
+
+    use crate::codec::{DecodeLayer, EncodeLayer};
+
+    use super::*;
+
+    async fn echo(request: Request<String>) -> Result<String, Missing> {
+        Ok(request.msg)
+    }
+
+    async fn serve(port: u16) -> Result<ServerHandle, Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(port)
+            .build()?;
+        Ok(Server::from_config(config)
+            .handler(fn_handler(echo))
+            .run()
+            .await?)
+    }
+
+    #[test]
+    fn backoff_test() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).jitter(0.0);
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(10), Duration::from_secs(1));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+
+        let backoff = backoff.jitter(0.5);
+        for failed in 0..10 {
+            let delay = backoff.delay(failed);
+            let full = Duration::from_millis(100 << failed.min(4)).min(Duration::from_secs(1));
+            assert!(full / 2 <= delay && delay <= full);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_test() -> Result<(), Box<dyn std::error::Error>> {
+        let server = serve(0).await?;
+        let addr = server.tcp_addr();
+
+        let reconnect = Reconnect::new()
+            .backoff(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(50),
+            ))
+            .queue(2);
+        let config = ClientConfig::builder().build()?;
+        let client = ReconnectingClient::connect(addr, &config, reconnect).await?;
+        let mut state = client.state();
+        let sink = client.sink();
+        let mut client = client
+            .outgoing(apply!(EncodeLayer::<String>::new() to sink))
+            .incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+
+        client.send(String::from("first")).await?;
+        assert_eq!(client.recv().await?, Some(String::from("first")));
+
+        server.shutdown().await?;
+        state
+            .wait_for(|state| matches!(state, ConnectionState::Reconnecting { .. }))
+            .await?;
+
+        // queued while offline
+        client.send(String::from("second")).await?;
+        client.send(String::from("third")).await?;
+        assert!(client.send(String::from("fourth")).await.is_err());
+
+        let server = serve(addr.port()).await?;
+        state
+            .wait_for(|state| *state == ConnectionState::Connected)
+            .await?;
+        assert_eq!(client.recv().await?, Some(String::from("second")));
+        assert_eq!(client.recv().await?, Some(String::from("third")));
+
+        client.close().await?;
+        assert_eq!(client.recv().await?, None);
+        assert_eq!(*state.borrow(), ConnectionState::Closed);
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn renegotiate_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            // the compression changes when the client reconnects
+            for (compression, flag) in
+                [(Compression::Lz4, FLAG_LZ4), (Compression::Zstd, FLAG_ZSTD)]
+            {
+                let (mut stream, _) = listener.accept().await?;
+                let codecs = Codecs::<String>::new().with(Protobuf);
+                accept_hello(&mut stream, &codecs, &[compression]).await?;
+
+                let frame = read_frame(&mut stream).await?.unwrap();
+                assert_eq!(frame[0], flag);
+                write_frame(&mut stream, &frame).await?;
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        });
+
+        let reconnect = Reconnect::new().backoff(Backoff::new(
+            Duration::from_millis(10),
+            Duration::from_millis(50),
+        ));
+        let config = ClientConfig::builder()
+            .handshake(true)
+            .compressions(vec![Compression::Zstd, Compression::Lz4])
+            .build()?;
+        let client = ReconnectingClient::connect(addr, &config, reconnect).await?;
+        assert_eq!(client.compression(), Compression::Lz4);
+        let mut state = client.state();
+        let sink = client.sink();
+        let mut client = client
+            .outgoing(apply!(EncodeLayer::<String>::new() to sink))
+            .incoming(apply!(DecodeLayer::<String>::new() to Deliver));
+
+        client.send("x".repeat(2000)).await?;
+        assert_eq!(client.recv().await?, Some("x".repeat(2000)));
+
+        state
+            .wait_for(|state| matches!(state, ConnectionState::Reconnecting { .. }))
+            .await?;
+        state
+            .wait_for(|state| *state == ConnectionState::Connected)
+            .await?;
+        assert_eq!(client.compression(), Compression::Zstd);
+        client.send("y".repeat(2000)).await?;
+        assert_eq!(client.recv().await?, Some("y".repeat(2000)));
+
+        server.await?.unwrap();
+        client.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_timeout_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let reconnect = Reconnect::new()
+            .backoff(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(50),
+            ))
+            .read_timeout(Duration::from_millis(100));
+        let config = ClientConfig::builder().handshake(false).build()?;
+        let (client, first) = tokio::join!(
+            ReconnectingClient::connect(addr, &config, reconnect),
+            listener.accept()
+        );
+        let (client, _first) = (client?, first?);
+        let mut state = client.state();
+
+        // the first connection stays open, but is silent
+        state
+            .wait_for(|state| matches!(state, ConnectionState::Reconnecting { .. }))
+            .await?;
+        listener.accept().await?;
+        state
+            .wait_for(|state| *state == ConnectionState::Connected)
+            .await?;
+        client.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_attempts_test() -> Result<(), Box<dyn std::error::Error>> {
+        let server = serve(0).await?;
+        let retries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hooks = Hooks::new().on({
+            let retries = retries.clone();
+            move |event: &ClientEvent| {
+                if let ClientEvent::Retry { attempt } = event {
+                    retries.lock().unwrap().push(*attempt);
+                }
+            }
+        });
+        let reconnect = Reconnect::new()
+            .backoff(Backoff::new(
+                Duration::from_millis(1),
+                Duration::from_millis(5),
+            ))
+            .max_attempts(3)
+            .hooks(hooks);
+        let config = ClientConfig::builder().build()?;
+        let mut client = ReconnectingClient::connect(server.tcp_addr(), &config, reconnect).await?;
+
+        server.shutdown().await?;
+        assert_eq!(client.recv().await?, None);
+        assert_eq!(*client.state().borrow(), ConnectionState::Closed);
+        assert!(client.send(vec![1]).await.is_err());
+        assert_eq!(*retries.lock().unwrap(), [1, 2, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! Caching of responses on clients
+//!
+//! Requests that only read (a leaderboard, a shop catalog) get the same
+//! response for a while. `ResponseCacheLayer` sits in the request chain of
+//! a client, and remembers successful responses per key of the request:
+//!
+//! - a response younger than `ttl` is returned without sending the request
+//! - a stale response, younger than `ttl` + `stale_while_revalidate`, is
+//!   returned right away while the request is sent again in a spawned task
+//!   to refresh it, so flaky links still get recent data quickly
+//! - older responses are forgotten, and the request is sent as usual
+//!
+//! Errors are never cached. Only idempotent requests should have keys.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_client::response_cache::ResponseCacheLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn leaderboard(top: u32) -> Result<Vec<String>, ()> {
+//!     println!("request top {top}");
+//!     Ok(vec![String::from("cubby"); top as usize])
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = ResponseCacheLayer::new(Duration::from_secs(10), |top: &u32| Some(*top))
+//!     .stale_while_revalidate(Duration::from_secs(60));
+//! let handler = apply!(layer to leaderboard);
+//!
+//! // this would print "request top 3" only once
+//! assert_eq!(handler.call(3).await?.len(), 3);
+//! assert_eq!(handler.call(3).await?.len(), 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cubby_connect_server_core::handler::Handler;
+use cubby_connect_server_core::layer::Layer;
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+
+struct Entry<O> {
+    fetched: Instant,
+    output: O,
+
+    /// true while a spawned request refreshes the entry
+    refreshing: bool,
+}
+
+/// remembered responses of each key
+struct Cache<K, O> {
+    ttl: Duration,
+    stale: Duration,
+    entries: HashMap<K, Entry<O>>,
+}
+
+impl<K: Hash + Eq, O> Cache<K, O> {
+    /// removes every entry that is too old to be returned
+    fn purge(&mut self, now: Instant) {
+        let max_age = self.ttl + self.stale;
+        self.entries
+            .retain(|_, entry| now.duration_since(entry.fetched) < max_age);
+    }
+
+    fn store(&mut self, key: K, output: O) {
+        self.entries.insert(
+            key,
+            Entry {
+                fetched: Instant::now(),
+                output,
+                refreshing: false,
+            },
+        );
+    }
+}
+
+/// `Layer` that builds `ResponseCacheHandler`
+pub struct ResponseCacheLayer<F> {
+    ttl: Duration,
+    stale: Duration,
+    key: Arc<F>,
+}
+
+impl<F> ResponseCacheLayer<F> {
+    /// `key` extracts the cache key from a request.
+    /// Requests without key (`None`) are always sent.
+    ///
+    /// Responses are fresh for `ttl` after they arrived.
+    pub fn new(ttl: Duration, key: F) -> Self {
+        Self {
+            ttl,
+            stale: Duration::ZERO,
+            key: Arc::new(key),
+        }
+    }
+
+    /// returns stale responses for `stale` after `ttl` while refreshing them
+    pub fn stale_while_revalidate(mut self, stale: Duration) -> Self {
+        self.stale = stale;
+        self
+    }
+}
+
+impl<T, K, F, H> Layer<T, H> for ResponseCacheLayer<F>
+where
+    F: Fn(&T) -> Option<K>,
+    K: Hash + Eq + Clone + Send + 'static,
+    H: Handler<T>,
+    H::Output: Clone + Send + 'static,
+    H::Error: 'static,
+    H::Future: Send + 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = ResponseCacheHandler<T, K, F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ResponseCacheHandler {
+            key: self.key.clone(),
+            cache: Arc::new(Mutex::new(Cache {
+                ttl: self.ttl,
+                stale: self.stale,
+                entries: HashMap::new(),
+            })),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that returns remembered responses of the previous handler
+pub struct ResponseCacheHandler<T, K, F, H>
+where
+    H: Handler<T>,
+{
+    key: Arc<F>,
+    cache: Arc<Mutex<Cache<K, H::Output>>>,
+    prev: H,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, K, F, H> ResponseCacheHandler<T, K, F, H>
+where
+    K: Hash + Eq,
+    H: Handler<T>,
+{
+    /// returns the number of responses that can be returned now
+    pub fn len(&self) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        cache.purge(Instant::now());
+        cache.entries.len()
+    }
+
+    /// returns true if no response is remembered now
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, K, F, H> Handler<T> for ResponseCacheHandler<T, K, F, H>
+where
+    F: Fn(&T) -> Option<K>,
+    K: Hash + Eq + Clone + Send + 'static,
+    H: Handler<T>,
+    H::Output: Clone + Send + 'static,
+    H::Error: 'static,
+    H::Future: Send + 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<
+        Ready<Result<H::Output, H::Error>>,
+        LocalBoxFuture<'static, Result<H::Output, H::Error>>,
+    >;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let key = match (self.key)(&msg) {
+            Some(key) => key,
+            None => return Either::Right(Box::pin(self.prev.call(msg))),
+        };
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.purge(now);
+        let ttl = cache.ttl;
+
+        if let Some(entry) = cache.entries.get_mut(&key) {
+            let output = entry.output.clone();
+            if now.duration_since(entry.fetched) >= ttl && !entry.refreshing {
+                entry.refreshing = true;
+                let refresh = self.prev.call(msg);
+                let cache = self.cache.clone();
+                tokio::spawn(async move {
+                    let result = refresh.await;
+                    let mut cache = cache.lock().unwrap();
+                    match result {
+                        Ok(output) => cache.store(key, output),
+                        Err(_) => {
+                            if let Some(entry) = cache.entries.get_mut(&key) {
+                                entry.refreshing = false;
+                            }
+                        }
+                    }
+                });
+            }
+            return Either::Left(ok(output));
+        }
+        drop(cache);
+
+        let future = self.prev.call(msg);
+        let cache = self.cache.clone();
+        Either::Right(Box::pin(async move {
+            let output = future.await?;
+            cache.lock().unwrap().store(key, output.clone());
+            Ok(output)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::future::{err, Ready};
+
+    use cubby_connect_server_core::layer::connect;
+
+    use super::*;
+
+    /// returns the number of calls so far, or fails with odd requests
+    struct Count(Arc<AtomicU32>);
+
+    impl Handler<u32> for Count {
+        type Output = u32;
+        type Error = ();
+        type Future = Ready<Result<u32, ()>>;
+
+        fn call(&self, req: u32) -> Self::Future {
+            let count = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            if req.is_multiple_of(2) {
+                ok(count)
+            } else {
+                err(())
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_test() -> Result<(), ()> {
+        let count = Arc::new(AtomicU32::new(0));
+        let layer = ResponseCacheLayer::new(Duration::from_secs(10), |req: &u32| Some(*req));
+        let handler = connect(layer, Count(count.clone())).await?;
+
+        assert_eq!(handler.call(2).await?, 1);
+        assert_eq!(handler.call(2).await?, 1);
+        assert_eq!(handler.call(4).await?, 2);
+
+        // errors are not cached
+        assert!(handler.call(1).await.is_err());
+        assert!(handler.call(1).await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        assert_eq!(handler.len(), 2);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(handler.is_empty());
+        assert_eq!(handler.call(2).await?, 5);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_while_revalidate_test() -> Result<(), ()> {
+        let count = Arc::new(AtomicU32::new(0));
+        let layer = ResponseCacheLayer::new(Duration::from_secs(10), |req: &u32| Some(*req))
+            .stale_while_revalidate(Duration::from_secs(30));
+        let handler = connect(layer, Count(count.clone())).await?;
+        assert_eq!(handler.call(2).await?, 1);
+
+        // the stale response is returned, and refreshed in the background
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(handler.call(2).await?, 1);
+        assert_eq!(handler.call(2).await?, 1);
+        tokio::task::yield_now().await;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(handler.call(2).await?, 2);
+
+        // too old to be returned
+        tokio::time::advance(Duration::from_secs(40)).await;
+        assert_eq!(handler.call(2).await?, 3);
+        Ok(())
+    }
+}
@@ -1,3 +1,23 @@
+use std::path::PathBuf;
+
 fn main() {
-    prost_build::compile_protos(&["../../protobuf/sample.proto"], &["../../protobuf"]).unwrap();
+    // descriptors are embedded by `spec` to describe the protocol
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    prost_build::Config::new()
+        .file_descriptor_set_path(out_dir.join("protocol.bin"))
+        .compile_protos(
+            &[
+                "../../protobuf/sample.proto",
+                "../../protobuf/auth.proto",
+                "../../protobuf/discovery.proto",
+                "../../protobuf/envelope.proto",
+                "../../protobuf/feature_flags.proto",
+                "../../protobuf/handshake.proto",
+                "../../protobuf/heartbeat.proto",
+                "../../protobuf/rendezvous.proto",
+                "../../protobuf/unsupported.proto",
+            ],
+            &["../../protobuf"],
+        )
+        .unwrap();
 }
@@ -0,0 +1,315 @@
+//! Access log of processed messages
+//!
+//! `AccessLogLayer` writes one line per message to a sink: the peer
+//! (`PeerId` in the context), the message type (from `Headers` in the
+//! context), the size of the message, how long the next handler took, and
+//! whether it succeeded. Missing values are written as `-`.
+//!
+//! Lines are formatted by an `AccessFormat`: `Text` (the default) is for
+//! humans, and `JsonLines` is for log collectors. Any
+//! `Fn(&AccessEntry) -> String` can be a format as well.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use cubby_connect_server_core::access_log::{AccessLogLayer, JsonLines};
+//! use cubby_connect_server_core::context::Request;
+//! use cubby_connect_server_core::envelope::Envelope;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(req: Request<Vec<u8>>) -> Result<usize, ()> {
+//!     Ok(req.msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let lines = Arc::new(Mutex::new(Vec::new()));
+//! let lines_ = lines.clone();
+//! let handler = apply!(AccessLogLayer::new(move |line: &str| {
+//!     lines_.lock().unwrap().push(line.to_string())
+//! }) to chat);
+//!
+//! let mut env = Envelope::new(b"hello".to_vec());
+//! env.headers.message_type = Some(String::from("chat"));
+//! handler.call(env.into_request()).await?;
+//! // like "- chat 5B 0.012ms ok"
+//! assert!(lines.lock().unwrap()[0].starts_with("- chat 5B "));
+//!
+//! // for log collectors
+//! let handler = apply!(AccessLogLayer::new(|line: &str| println!("{line}")).format(JsonLines) to chat);
+//! handler.call(Request::new(b"hello".to_vec())).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+
+use crate::context::Request;
+use crate::envelope::Headers;
+use crate::handler::Handler;
+use crate::layer::Layer;
+use crate::peer_trace::PeerId;
+
+/// a processed message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessEntry {
+    /// when the message arrived
+    pub time: SystemTime,
+    pub peer: Option<PeerId>,
+    pub message_type: Option<String>,
+
+    /// size of the message in bytes
+    pub size: usize,
+    pub latency: Duration,
+    pub ok: bool,
+}
+
+/// format of access log lines
+pub trait AccessFormat {
+    fn format(&self, entry: &AccessEntry) -> String;
+}
+
+impl<F> AccessFormat for F
+where
+    F: Fn(&AccessEntry) -> String,
+{
+    fn format(&self, entry: &AccessEntry) -> String {
+        self(entry)
+    }
+}
+
+/// human-readable format like `player-42 chat 5B 1.200ms ok`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Text;
+
+impl AccessFormat for Text {
+    fn format(&self, entry: &AccessEntry) -> String {
+        format!(
+            "{} {} {}B {:.3}ms {}",
+            entry.peer.as_ref().map_or("-", |peer| peer.0.as_str()),
+            entry.message_type.as_deref().unwrap_or("-"),
+            entry.size,
+            entry.latency.as_secs_f64() * 1000.0,
+            if entry.ok { "ok" } else { "error" },
+        )
+    }
+}
+
+/// a JSON object per line, with the time in milliseconds since unix epoch
+/// and the latency in microseconds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonLines;
+
+/// writes `s` as a JSON string
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn json_option(out: &mut String, s: Option<&str>) {
+    match s {
+        Some(s) => json_string(out, s),
+        None => out.push_str("null"),
+    }
+}
+
+impl AccessFormat for JsonLines {
+    fn format(&self, entry: &AccessEntry) -> String {
+        let time = entry
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut line = format!("{{\"time\":{time},\"peer\":");
+        json_option(&mut line, entry.peer.as_ref().map(|peer| peer.0.as_str()));
+        line.push_str(",\"message_type\":");
+        json_option(&mut line, entry.message_type.as_deref());
+        write!(
+            line,
+            ",\"size\":{},\"latency_us\":{},\"ok\":{}}}",
+            entry.size,
+            entry.latency.as_micros(),
+            entry.ok
+        )
+        .unwrap();
+        line
+    }
+}
+
+type Sink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// `Layer` that builds `AccessLogHandler`
+pub struct AccessLogLayer<F = Text> {
+    sink: Sink,
+    format: Arc<F>,
+}
+
+impl AccessLogLayer {
+    /// writes lines in `Text` to `sink`
+    pub fn new<S>(sink: S) -> Self
+    where
+        S: Fn(&str) + Send + Sync + 'static,
+    {
+        Self {
+            sink: Arc::new(sink),
+            format: Arc::new(Text),
+        }
+    }
+}
+
+impl<F> AccessLogLayer<F> {
+    /// formats lines with `format`
+    pub fn format<G: AccessFormat>(self, format: G) -> AccessLogLayer<G> {
+        AccessLogLayer {
+            sink: self.sink,
+            format: Arc::new(format),
+        }
+    }
+}
+
+impl<T, F, H> Layer<Request<T>, H> for AccessLogLayer<F>
+where
+    T: AsRef<[u8]>,
+    F: AccessFormat + 'static,
+    H: Handler<Request<T>>,
+    H::Future: 'static,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = AccessLogHandler<F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(AccessLogHandler {
+            sink: self.sink.clone(),
+            format: self.format.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that writes a line of every message to a sink
+pub struct AccessLogHandler<F, H> {
+    sink: Sink,
+    format: Arc<F>,
+    prev: H,
+}
+
+impl<T, F, H> Handler<Request<T>> for AccessLogHandler<F, H>
+where
+    T: AsRef<[u8]>,
+    F: AccessFormat + 'static,
+    H: Handler<Request<T>>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, req: Request<T>) -> Self::Future {
+        let mut entry = AccessEntry {
+            time: SystemTime::now(),
+            peer: req.context.get::<PeerId>().cloned(),
+            message_type: req
+                .context
+                .get::<Headers>()
+                .and_then(|headers| headers.message_type.clone()),
+            size: req.msg.as_ref().len(),
+            latency: Duration::ZERO,
+            ok: false,
+        };
+        let sink = self.sink.clone();
+        let format = self.format.clone();
+        let start = Instant::now();
+        let future = self.prev.call(req);
+
+        Box::pin(async move {
+            let result = future.await;
+            entry.latency = start.elapsed();
+            entry.ok = result.is_ok();
+            sink(&format.format(&entry));
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            peer: Some(PeerId::new("10.0.0.1:5000")),
+            message_type: Some(String::from("say \"hi\"")),
+            size: 42,
+            latency: Duration::from_micros(1200),
+            ok: true,
+        }
+    }
+
+    #[test]
+    fn format_test() {
+        assert_eq!(
+            Text.format(&entry()),
+            "10.0.0.1:5000 say \"hi\" 42B 1.200ms ok"
+        );
+        assert_eq!(
+            JsonLines.format(&entry()),
+            r#"{"time":1700000000123,"peer":"10.0.0.1:5000","message_type":"say \"hi\"","size":42,"latency_us":1200,"ok":true}"#
+        );
+
+        let mut entry = entry();
+        entry.peer = None;
+        entry.ok = false;
+        assert!(JsonLines.format(&entry).contains(r#""peer":null"#));
+        assert!(Text.format(&entry).starts_with("- "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_test() -> Result<(), ()> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines_ = lines.clone();
+        let layer =
+            AccessLogLayer::new(move |line: &str| lines_.lock().unwrap().push(line.to_string()))
+                .format(|entry: &AccessEntry| format!("{} {:?}", entry.size, entry.latency));
+        let work = fn_handler(|req: Request<String>| async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if req.msg.is_empty() {
+                Err(())
+            } else {
+                Ok(())
+            }
+        });
+        let handler = connect(layer, work).await?;
+
+        handler.call(Request::new(String::from("move"))).await?;
+        assert!(handler.call(Request::new(String::new())).await.is_err());
+        assert_eq!(*lines.lock().unwrap(), vec!["4 5ms", "0 5ms"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+//! Accounting of bandwidth and messages per identity
+//!
+//! `Accounting` keeps cumulative usage (messages and bytes) of each
+//! identity across all of its connections. `AccountingLayer` records every
+//! message passing through the chain. The usage can be exported for
+//! billing or abuse analysis with `Accounting::snapshot`, `Accounting::take`
+//! or periodically with `Accounting::export_every`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::accounting::{Accounting, AccountingLayer};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//! use std::sync::Arc;
+//!
+//! struct Chat {
+//!     user: u64,
+//!     text: String,
+//! }
+//!
+//! async fn chat(msg: Chat) -> Result<(), ()> {
+//!     println!("{}: {}", msg.user, msg.text);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let accounting = Arc::new(Accounting::new());
+//! let layer = AccountingLayer::new(accounting.clone(), |msg: &Chat| {
+//!     Some((msg.user, msg.text.len()))
+//! });
+//! let handler = apply!(layer to chat);
+//!
+//! handler.call(Chat { user: 1, text: "hello".to_string() }).await?;
+//! handler.call(Chat { user: 1, text: "world".to_string() }).await?;
+//! assert_eq!(accounting.usage(&1).bytes, 10);
+//!
+//! // prints usage every minute
+//! tokio::spawn(async move {
+//!     accounting
+//!         .export_every(std::time::Duration::from_secs(60), |usages| {
+//!             println!("{usages:?}");
+//!         })
+//!         .await
+//! });
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, Ready};
+use tokio::time::interval;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// cumulative usage of an identity
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// usage of every identity
+pub struct Accounting<I> {
+    usages: Mutex<HashMap<I, Usage>>,
+}
+
+impl<I> Default for Accounting<I> {
+    fn default() -> Self {
+        Self {
+            usages: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<I> Accounting<I>
+where
+    I: Hash + Eq + Clone,
+{
+    /// returns an empty accounting
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a message of `bytes` to `identity`
+    pub fn record(&self, identity: I, bytes: usize) {
+        let mut usages = self.usages.lock().unwrap();
+        let usage = usages.entry(identity).or_default();
+        usage.messages += 1;
+        usage.bytes += bytes as u64;
+    }
+
+    /// returns usage of `identity`
+    pub fn usage(&self, identity: &I) -> Usage {
+        let usages = self.usages.lock().unwrap();
+        usages.get(identity).copied().unwrap_or_default()
+    }
+
+    /// returns usage of every identity
+    pub fn snapshot(&self) -> HashMap<I, Usage> {
+        self.usages.lock().unwrap().clone()
+    }
+
+    /// returns usage of every identity and resets it to zero.
+    /// Use this to export usage of each billing period.
+    pub fn take(&self) -> HashMap<I, Usage> {
+        std::mem::take(&mut *self.usages.lock().unwrap())
+    }
+
+    /// calls `export` with `snapshot` every `period` forever
+    pub async fn export_every<F>(&self, period: Duration, mut export: F)
+    where
+        F: FnMut(HashMap<I, Usage>),
+    {
+        let mut ticker = interval(period);
+        // first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            export(self.snapshot());
+        }
+    }
+}
+
+/// `Layer` that builds `AccountingHandler`
+pub struct AccountingLayer<I, F> {
+    accounting: Arc<Accounting<I>>,
+    measure: Arc<F>,
+}
+
+impl<I, F> AccountingLayer<I, F> {
+    /// `measure` returns the identity of the message and its size in bytes.
+    /// Messages without identity (`None`) are not recorded.
+    pub fn new(accounting: Arc<Accounting<I>>, measure: F) -> Self {
+        Self {
+            accounting,
+            measure: Arc::new(measure),
+        }
+    }
+}
+
+impl<T, I, F, H> Layer<T, H> for AccountingLayer<I, F>
+where
+    I: Hash + Eq + Clone,
+    F: Fn(&T) -> Option<(I, usize)>,
+    H: Handler<T>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = AccountingHandler<T, I, F, H>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(AccountingHandler {
+            accounting: self.accounting.clone(),
+            measure: self.measure.clone(),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that records usage of each message before passing it
+pub struct AccountingHandler<T, I, F, H> {
+    accounting: Arc<Accounting<I>>,
+    measure: Arc<F>,
+    prev: H,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, I, F, H> Handler<T> for AccountingHandler<T, I, F, H>
+where
+    I: Hash + Eq + Clone,
+    F: Fn(&T) -> Option<(I, usize)>,
+    H: Handler<T>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = H::Future;
+
+    fn call(&self, msg: T) -> Self::Future {
+        if let Some((identity, bytes)) = (self.measure)(&msg) {
+            self.accounting.record(identity, bytes);
+        }
+        self.prev.call(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn ignore(_: (Option<&'static str>, &'static str)) -> Result<(), ()> {
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accounting_test() -> Result<(), ()> {
+        let accounting = Arc::new(Accounting::new());
+        let layer = AccountingLayer::new(
+            accounting.clone(),
+            |(id, s): &(Option<&'static str>, &'static str)| id.map(|id| (id, s.len())),
+        );
+        let handler = connect(layer, ignore).await?;
+
+        handler.call((Some("a"), "hello")).await?;
+        handler.call((Some("a"), "hi")).await?;
+        handler.call((Some("b"), "")).await?;
+        handler.call((None, "nobody")).await?;
+
+        assert_eq!(
+            accounting.usage(&"a"),
+            Usage {
+                messages: 2,
+                bytes: 7
+            }
+        );
+        assert_eq!(accounting.snapshot().len(), 2);
+        assert_eq!(accounting.take()[&"b"].messages, 1);
+        assert_eq!(accounting.usage(&"a"), Usage::default());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn export_test() {
+        let accounting = Arc::new(Accounting::new());
+        accounting.record(1, 10);
+
+        let exported = Arc::new(Mutex::new(Vec::new()));
+        let exported_ = exported.clone();
+        let accounting_ = accounting.clone();
+        tokio::spawn(async move {
+            accounting_
+                .export_every(Duration::from_secs(10), |u| {
+                    exported_.lock().unwrap().push(u)
+                })
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        let exported = exported.lock().unwrap();
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0][&1].bytes, 10);
+    }
+}
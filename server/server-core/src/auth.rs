@@ -0,0 +1,778 @@
+//! Client of the credential server
+//!
+//! `Config::auth_config` tells where the credential server is, and the
+//! username and password of this server on it. `AuthClient` connects to it
+//! and verifies credentials that clients present, so that an auth layer
+//! only needs `verify`:
+//!
+//! - the server logs in with the username and password of `AuthServer`,
+//!   and the issued session token is kept until it expires. When the
+//!   credential server says the session expired earlier, it logs in again.
+//! - `verify` returns the `Identity` of credentials, like a token issued to
+//!   the client or a username with a password
+//! - verified identities are cached until they expire, so messages of the
+//!   same client don't go to the credential server every time. The cache
+//!   keeps a hash of credentials instead of them, and up to
+//!   `AuthServer::cache_size` of them.
+//! - requests go through one TLS connection, which is made again on the
+//!   next request after it fails
+//! - each `verify` waits for `AuthServer::timeout` at most, and a request
+//!   that timed out drops the connection, so a hung credential server
+//!   doesn't block logins for longer
+//!
+//! Requests are `auth.AuthRequest` frames (see `transport::write_frame`),
+//! each answered by an `auth.AuthResponse` frame in order.
+//!
+//! `Identity` is `FromContext`, so handlers take it as an argument after an
+//! auth layer put it into the context.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::auth::{AuthClient, Credentials};
+//! use cubby_connect_server_core::config::{AuthServer, Config};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::auth::AuthError> {
+//! let auth_server = AuthServer::builder()
+//!     .host("10.0.0.5")
+//!     .port(7000)
+//!     .ca_path("auth-ca.pem")
+//!     .server_name("auth.cubby.internal")
+//!     .build()
+//!     .unwrap();
+//! let config = Config::builder().auth_config(auth_server).build().unwrap();
+//! let auth = AuthClient::from_config(&config);
+//!
+//! let identity = auth.verify(&Credentials::Token(String::from("eyJhbGciOi..."))).await?;
+//! println!("{} has roles {:?}", identity.subject, identity.roles);
+//!
+//! // from the cache
+//! auth.verify(&Credentials::Token(String::from("eyJhbGciOi..."))).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use prost::Message;
+use ring::digest;
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
+use tokio_rustls::TlsConnector;
+
+use crate::config::{AuthServer, Config};
+use crate::context::{Context, FromContext};
+use crate::handler::Handler;
+use crate::protobuf::auth::{self as proto, auth_request, auth_response, verify};
+use crate::transport::{load_certs, read_frame, write_frame, Stream, TransportError};
+
+/// error of the credential server when the session is no longer valid
+const SESSION_EXPIRED: &str = "session_expired";
+
+/// error when verifying credentials
+#[derive(Debug)]
+pub enum AuthError {
+    /// error from the connection to the credential server
+    Io(io::Error),
+
+    /// the credential server didn't let this server log in.
+    /// It has the reason from the credential server.
+    LoginFailed(String),
+
+    /// the credentials are not valid.
+    /// It has the reason from the credential server.
+    Rejected(String),
+
+    /// the credential server sent something that is not an answer
+    Malformed,
+
+    /// invalid certificate of the credential server
+    Tls(rustls::Error),
+
+    /// `AuthServer::tls` is on without `AuthServer::ca_path`
+    NoCa,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Io(e) => write!(f, "io error: {e}"),
+            AuthError::LoginFailed(reason) => write!(f, "login to auth server failed: {reason}"),
+            AuthError::Rejected(reason) => write!(f, "credentials rejected: {reason}"),
+            AuthError::Malformed => write!(f, "malformed answer from auth server"),
+            AuthError::Tls(e) => write!(f, "tls error: {e}"),
+            AuthError::NoCa => write!(f, "ca_path of auth server is not configured"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<io::Error> for AuthError {
+    fn from(e: io::Error) -> Self {
+        AuthError::Io(e)
+    }
+}
+
+impl From<rustls::Error> for AuthError {
+    fn from(e: rustls::Error) -> Self {
+        AuthError::Tls(e)
+    }
+}
+
+impl From<TransportError> for AuthError {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Io(e) => AuthError::Io(e),
+            TransportError::Tls(e) => AuthError::Tls(e),
+            TransportError::NoTls => AuthError::NoCa,
+        }
+    }
+}
+
+/// what a client presents to prove who it is
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Credentials {
+    /// token issued to the client by the credential server
+    Token(String),
+
+    Password {
+        username: String,
+        password: String,
+    },
+}
+
+impl From<Credentials> for verify::Credentials {
+    fn from(credentials: Credentials) -> Self {
+        match credentials {
+            Credentials::Token(token) => verify::Credentials::Token(token),
+            Credentials::Password { username, password } => {
+                verify::Credentials::Password(proto::UserPassword { username, password })
+            }
+        }
+    }
+}
+
+/// who verified credentials belong to
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Identity {
+    /// id of the user
+    pub subject: String,
+
+    pub roles: Vec<String>,
+}
+
+impl Identity {
+    /// returns true if the user has `role`
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+impl FromContext for Identity {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Identity>().cloned()
+    }
+}
+
+/// connection to the credential server with its session
+struct Connection {
+    stream: Box<dyn Stream>,
+
+    /// token of the session and when it expires
+    session: Option<(String, Instant)>,
+}
+
+/// SHA-256 of credentials, so that the cache doesn't keep passwords and
+/// tokens in memory
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    fn new(credentials: &Credentials) -> Self {
+        let mut context = digest::Context::new(&digest::SHA256);
+        match credentials {
+            Credentials::Token(token) => {
+                context.update(b"token");
+                context.update(token.as_bytes());
+            }
+            Credentials::Password { username, password } => {
+                // the length keeps ("ab", "c") and ("a", "bc") apart
+                context.update(b"password");
+                context.update(&(username.len() as u64).to_be_bytes());
+                context.update(username.as_bytes());
+                context.update(password.as_bytes());
+            }
+        }
+        let mut key = [0; 32];
+        key.copy_from_slice(context.finish().as_ref());
+        Self(key)
+    }
+}
+
+/// verified identities with when they expire
+struct Cache {
+    identities: HashMap<CacheKey, (Identity, Instant)>,
+    capacity: usize,
+}
+
+impl Cache {
+    /// inserts `identity`, evicting expired identities when the cache is
+    /// full, and the one that expires first if none expired
+    fn insert(&mut self, key: CacheKey, identity: Identity, expires_at: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.identities.contains_key(&key) && self.identities.len() >= self.capacity {
+            self.evict_expired();
+        }
+        if !self.identities.contains_key(&key) && self.identities.len() >= self.capacity {
+            let first = self
+                .identities
+                .iter()
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(key, _)| *key);
+            if let Some(first) = first {
+                self.identities.remove(&first);
+            }
+        }
+        self.identities.insert(key, (identity, expires_at));
+    }
+
+    /// removes expired identities, and returns the number of them
+    fn evict_expired(&mut self) -> usize {
+        let before = self.identities.len();
+        let now = Instant::now();
+        self.identities
+            .retain(|_, (_, expires_at)| *expires_at > now);
+        before - self.identities.len()
+    }
+}
+
+/// client of the credential server.
+/// Clones share the same connection and cache.
+#[derive(Clone)]
+pub struct AuthClient {
+    config: AuthServer,
+    connection: Arc<tokio::sync::Mutex<Option<Connection>>>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl AuthClient {
+    /// returns a client of the credential server of `config`.
+    /// It connects on the first `verify`.
+    pub fn new(config: AuthServer) -> Self {
+        let cache = Cache {
+            identities: HashMap::new(),
+            capacity: config.cache_size,
+        };
+        Self {
+            config,
+            connection: Arc::new(tokio::sync::Mutex::new(None)),
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    /// returns a client of `Config::auth_config`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.auth_config.clone())
+    }
+
+    /// returns the identity of `credentials`, from the cache if it is
+    /// verified before and not expired yet.
+    /// It waits for `AuthServer::timeout` at most, including the time
+    /// waiting for other requests on the connection.
+    pub async fn verify(&self, credentials: &Credentials) -> Result<Identity, AuthError> {
+        let key = CacheKey::new(credentials);
+        if let Some(identity) = self.cached(&key) {
+            return Ok(identity);
+        }
+
+        let deadline = Instant::now() + self.config.timeout;
+        let mut connection = timeout_at(deadline, self.connection.lock())
+            .await
+            .map_err(|_| timed_out())?;
+        if Instant::now() >= deadline {
+            return Err(AuthError::Io(timed_out()));
+        }
+        let result = timeout_at(deadline, self.request_verify(&mut connection, credentials))
+            .await
+            .unwrap_or_else(|_| Err(AuthError::Io(timed_out())));
+        // the answer of a request that timed out may come later, so the
+        // connection is not used again
+        if matches!(result, Err(AuthError::Io(_) | AuthError::Malformed)) {
+            *connection = None;
+        }
+        drop(connection);
+        let (identity, expires_in) = result?;
+
+        if !expires_in.is_zero() {
+            let expires_at = Instant::now() + expires_in;
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(key, identity.clone(), expires_at);
+        }
+        Ok(identity)
+    }
+
+    /// removes `credentials` from the cache, e.g. after the user logged out
+    pub fn forget(&self, credentials: &Credentials) {
+        self.cache
+            .lock()
+            .unwrap()
+            .identities
+            .remove(&CacheKey::new(credentials));
+    }
+
+    /// removes expired identities from the cache, and returns the number of
+    /// removed ones
+    pub fn evict_expired(&self) -> usize {
+        self.cache.lock().unwrap().evict_expired()
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<Identity> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.identities.get(key) {
+            Some((identity, expires_at)) if *expires_at > Instant::now() => Some(identity.clone()),
+            Some(_) => {
+                cache.identities.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// verifies `credentials`, logging in again once when the session
+    /// expired before its time
+    async fn request_verify(
+        &self,
+        slot: &mut Option<Connection>,
+        credentials: &Credentials,
+    ) -> Result<(Identity, Duration), AuthError> {
+        let mut logged_in_again = false;
+        loop {
+            let (connection, session) = self.logged_in(slot).await?;
+            let verify = proto::Verify {
+                session,
+                credentials: Some(credentials.clone().into()),
+            };
+            let response = request(
+                &mut connection.stream,
+                auth_request::Request::Verify(verify),
+            )
+            .await?;
+
+            match response {
+                auth_response::Response::Identity(identity) => {
+                    let expires_in = Duration::from_millis(identity.expires_in);
+                    let identity = Identity {
+                        subject: identity.subject,
+                        roles: identity.roles,
+                    };
+                    return Ok((identity, expires_in));
+                }
+                auth_response::Response::Error(e) if e == SESSION_EXPIRED && !logged_in_again => {
+                    connection.session = None;
+                    logged_in_again = true;
+                }
+                auth_response::Response::Error(e) => return Err(AuthError::Rejected(e)),
+                auth_response::Response::Session(_) => return Err(AuthError::Malformed),
+            }
+        }
+    }
+
+    /// returns the connection with the token of a session that is not
+    /// expired, connecting and logging in if needed
+    async fn logged_in<'a>(
+        &self,
+        slot: &'a mut Option<Connection>,
+    ) -> Result<(&'a mut Connection, String), AuthError> {
+        if slot.is_none() {
+            *slot = Some(Connection {
+                stream: self.connect().await?,
+                session: None,
+            });
+        }
+        let connection = slot.as_mut().unwrap();
+
+        let now = Instant::now();
+        if let Some((token, expires_at)) = &connection.session {
+            if *expires_at > now {
+                let token = token.clone();
+                return Ok((connection, token));
+            }
+        }
+
+        let login = proto::Login {
+            username: self.config.username.clone(),
+            password: self.config.password.clone(),
+        };
+        let response = request(&mut connection.stream, auth_request::Request::Login(login)).await?;
+        match response {
+            auth_response::Response::Session(session) => {
+                let expires_at = now + Duration::from_millis(session.expires_in);
+                connection.session = Some((session.token.clone(), expires_at));
+                Ok((connection, session.token))
+            }
+            auth_response::Response::Error(e) => Err(AuthError::LoginFailed(e)),
+            auth_response::Response::Identity(_) => Err(AuthError::Malformed),
+        }
+    }
+
+    /// connects to the credential server, with TLS unless it is turned off
+    async fn connect(&self) -> Result<Box<dyn Stream>, AuthError> {
+        let config = &self.config;
+        let connector = match (config.tls, &config.ca_path) {
+            (false, _) => None,
+            (true, None) => return Err(AuthError::NoCa),
+            (true, Some(ca_path)) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for cert in load_certs(ca_path)? {
+                    roots.add(cert)?;
+                }
+                let provider = Arc::new(rustls::crypto::ring::default_provider());
+                let tls = rustls::ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                Some(TlsConnector::from(Arc::new(tls)))
+            }
+        };
+
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        let _ = stream.set_nodelay(true);
+        let Some(connector) = connector else {
+            return Ok(Box::new(stream));
+        };
+        let name = config.server_name.as_ref().unwrap_or(&config.host);
+        let name = ServerName::try_from(name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Box::new(connector.connect(name, stream).await?))
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "auth server did not answer in time",
+    )
+}
+
+/// sends `request` and reads its answer
+async fn request(
+    stream: &mut Box<dyn Stream>,
+    request: auth_request::Request,
+) -> Result<auth_response::Response, AuthError> {
+    let request = proto::AuthRequest {
+        request: Some(request),
+    };
+    write_frame(stream, &request.encode_to_vec()).await?;
+    let frame = read_frame(stream)
+        .await?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    proto::AuthResponse::decode(frame.as_slice())
+        .map_err(|_| AuthError::Malformed)?
+        .response
+        .ok_or(AuthError::Malformed)
+}
+
+impl Handler<Credentials> for AuthClient {
+    type Output = Identity;
+    type Error = AuthError;
+    type Future = BoxFuture<'static, Result<Identity, AuthError>>;
+
+    fn call(&self, credentials: Credentials) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move { client.verify(&credentials).await })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::transport::server_tls;
+
+    use super::*;
+
+    /// requests that the credential server answered
+    #[derive(Default)]
+    struct Counts {
+        logins: AtomicUsize,
+        verifies: AtomicUsize,
+
+        /// makes the current session expire
+        expire: AtomicBool,
+    }
+
+    fn answer(
+        request: auth_request::Request,
+        session: &mut Option<String>,
+        counts: &Counts,
+    ) -> auth_response::Response {
+        match request {
+            auth_request::Request::Login(login) => {
+                if login.password != "secret" {
+                    return auth_response::Response::Error(String::from("invalid_login"));
+                }
+                let n = counts.logins.fetch_add(1, Ordering::Relaxed);
+                let token = format!("session-{n}");
+                *session = Some(token.clone());
+                auth_response::Response::Session(proto::Session {
+                    token,
+                    expires_in: 60_000,
+                })
+            }
+            auth_request::Request::Verify(verify) => {
+                counts.verifies.fetch_add(1, Ordering::Relaxed);
+                if counts.expire.swap(false, Ordering::Relaxed) {
+                    *session = None;
+                }
+                if session.as_deref() != Some(verify.session.as_str()) {
+                    return auth_response::Response::Error(String::from(SESSION_EXPIRED));
+                }
+                let (subject, expires_in) = match verify.credentials {
+                    Some(verify::Credentials::Token(token)) if token == "alice" => {
+                        ("alice", 60_000)
+                    }
+                    Some(verify::Credentials::Password(user)) if user.password == "hunter2" => {
+                        ("bob", 0)
+                    }
+                    _ => {
+                        return auth_response::Response::Error(String::from("invalid_credentials"))
+                    }
+                };
+                auth_response::Response::Identity(proto::Identity {
+                    subject: subject.to_string(),
+                    roles: vec![String::from("player")],
+                    expires_in,
+                })
+            }
+        }
+    }
+
+    async fn serve(mut stream: impl Stream, counts: Arc<Counts>) {
+        let mut session = None;
+        while let Ok(Some(frame)) = read_frame(&mut stream).await {
+            let request = proto::AuthRequest::decode(frame.as_slice()).unwrap();
+            let response = proto::AuthResponse {
+                response: Some(answer(request.request.unwrap(), &mut session, &counts)),
+            };
+            write_frame(&mut stream, &response.encode_to_vec())
+                .await
+                .unwrap();
+        }
+    }
+
+    /// runs a credential server, which speaks TLS with `tls`
+    async fn credential_server(counts: Arc<Counts>, tls: Option<TlsAcceptor>) -> io::Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (counts, tls) = (counts.clone(), tls.clone());
+                tokio::spawn(async move {
+                    match tls {
+                        Some(tls) => serve(tls.accept(stream).await.unwrap(), counts).await,
+                        None => serve(stream, counts).await,
+                    }
+                });
+            }
+        });
+        Ok(port)
+    }
+
+    fn client(port: u16, password: &str) -> AuthClient {
+        let config = AuthServer::builder()
+            .port(port)
+            .password(password)
+            .tls(false)
+            .build()
+            .unwrap();
+        AuthClient::new(config)
+    }
+
+    #[tokio::test]
+    async fn verify_test() -> Result<(), Box<dyn std::error::Error>> {
+        let counts = Arc::new(Counts::default());
+        let auth = client(credential_server(counts.clone(), None).await?, "secret");
+        let alice = Credentials::Token(String::from("alice"));
+
+        let identity = auth.verify(&alice).await?;
+        assert_eq!(identity.subject, "alice");
+        assert!(identity.has_role("player"));
+
+        // cached
+        auth.call(alice.clone()).await?;
+        assert_eq!(counts.verifies.load(Ordering::Relaxed), 1);
+
+        // not cached because it expires right away
+        let bob = Credentials::Password {
+            username: String::from("bob"),
+            password: String::from("hunter2"),
+        };
+        assert_eq!(auth.verify(&bob).await?.subject, "bob");
+        auth.verify(&bob).await?;
+        assert_eq!(counts.verifies.load(Ordering::Relaxed), 3);
+
+        assert!(matches!(
+            auth.verify(&Credentials::Token(String::from("mallory"))).await,
+            Err(AuthError::Rejected(reason)) if reason == "invalid_credentials"
+        ));
+
+        // the session expired early, so it logs in again
+        counts.expire.store(true, Ordering::Relaxed);
+        auth.forget(&alice);
+        auth.verify(&alice).await?;
+        assert_eq!(counts.logins.load(Ordering::Relaxed), 2);
+        assert_eq!(auth.evict_expired(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn login_failed_test() -> Result<(), Box<dyn std::error::Error>> {
+        let counts = Arc::new(Counts::default());
+        let auth = client(credential_server(counts, None).await?, "wrong");
+        assert!(matches!(
+            auth.verify(&Credentials::Token(String::from("alice"))).await,
+            Err(AuthError::LoginFailed(reason)) if reason == "invalid_login"
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        drop(listener);
+        assert!(matches!(
+            client(port, "secret")
+                .verify(&Credentials::Token(String::from("alice")))
+                .await,
+            Err(AuthError::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let ca_path =
+            std::env::temp_dir().join(format!("cubby-auth-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_path, cert.cert.pem())?;
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let tls = server_tls(vec![CertificateDer::from(cert.cert)], key.into(), None)?;
+
+        let counts = Arc::new(Counts::default());
+        let port =
+            credential_server(counts.clone(), Some(TlsAcceptor::from(Arc::new(tls)))).await?;
+        let mut config = AuthServer::builder();
+        config
+            .port(port)
+            .password("secret")
+            .ca_path(&ca_path)
+            .server_name("localhost");
+        let auth = AuthClient::new(config.build()?);
+        let identity = auth
+            .verify(&Credentials::Token(String::from("alice")))
+            .await;
+        assert_eq!(identity?.subject, "alice");
+
+        // the certificate is not for the host
+        let auth = AuthClient::new(config.clone().server_name("auth.example").build()?);
+        let result = auth
+            .verify(&Credentials::Token(String::from("alice")))
+            .await;
+        assert!(matches!(result, Err(AuthError::Io(_))));
+
+        // credentials are not sent without a certificate to trust
+        let auth = AuthClient::new(AuthServer::builder().port(port).build()?);
+        let result = auth
+            .verify(&Credentials::Token(String::from("alice")))
+            .await;
+        assert!(matches!(result, Err(AuthError::NoCa)));
+        assert_eq!(counts.verifies.load(Ordering::Relaxed), 1);
+
+        std::fs::remove_file(ca_path)?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_test() -> Result<(), Box<dyn std::error::Error>> {
+        // a credential server that accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted_ = accepted.clone();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted_.fetch_add(1, Ordering::Relaxed);
+                streams.push(stream);
+            }
+        });
+
+        let auth = client(port, "secret");
+        let alice = Credentials::Token(String::from("alice"));
+        let (first, second) = tokio::join!(auth.verify(&alice), auth.verify(&alice));
+        for result in [first, second] {
+            assert!(matches!(result, Err(AuthError::Io(e)) if e.kind() == io::ErrorKind::TimedOut));
+        }
+
+        // the hung connection is dropped, and the next request connects again
+        let result = auth.verify(&alice).await;
+        assert!(matches!(result, Err(AuthError::Io(e)) if e.kind() == io::ErrorKind::TimedOut));
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cache_test() {
+        let mut cache = Cache {
+            identities: HashMap::new(),
+            capacity: 2,
+        };
+        let identity = |subject: &str| Identity {
+            subject: subject.to_string(),
+            roles: Vec::new(),
+        };
+        let key = |token: &str| CacheKey::new(&Credentials::Token(token.to_string()));
+        let now = Instant::now();
+
+        cache.insert(key("a"), identity("a"), now + Duration::from_secs(20));
+        cache.insert(key("b"), identity("b"), now + Duration::from_secs(10));
+        cache.insert(key("c"), identity("c"), now + Duration::from_secs(30));
+
+        // the one that expires first is evicted
+        assert_eq!(cache.identities.len(), 2);
+        assert!(!cache.identities.contains_key(&key("b")));
+
+        cache.insert(key("d"), identity("d"), now - Duration::from_secs(1));
+        assert_eq!(cache.evict_expired(), 1);
+        assert_eq!(cache.identities.len(), 1);
+
+        // the cache keeps hashes of credentials
+        let password = |password: &str| {
+            CacheKey::new(&Credentials::Password {
+                username: String::from("ab"),
+                password: password.to_string(),
+            })
+        };
+        assert_ne!(password("c"), password("cd"));
+        assert_ne!(
+            password("c"),
+            CacheKey::new(&Credentials::Password {
+                username: String::from("a"),
+                password: String::from("bc"),
+            })
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Atomic processing of a batch of messages
+//!
+//! `BatchLayer` turns a `Handler<T>` into a handler of `Batch<T>`.
+//! Every message in a batch goes through the inner handler in order,
+//! surrounded by the `begin`/`commit`/`rollback` hooks of a `Transaction`.
+//! If any message fails, the transaction is rolled back and the rest of the
+//! batch is not processed.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::batch::{fn_transaction, Batch, BatchLayer};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn begin() -> Result<(), ()> {
+//!     println!("begin");
+//!     Ok(())
+//! }
+//!
+//! async fn commit() -> Result<(), ()> {
+//!     println!("commit");
+//!     Ok(())
+//! }
+//!
+//! async fn rollback() -> Result<(), ()> {
+//!     println!("rollback");
+//!     Ok(())
+//! }
+//!
+//! async fn add_item(item: &'static str) -> Result<(), ()> {
+//!     println!("add {item}");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = BatchLayer::new(fn_transaction(begin, commit, rollback));
+//! let handler = apply!(layer to add_item);
+//! // this would print "begin", "add sword", "add shield", "commit"
+//! handler.call(Batch::from(vec!["sword", "shield"])).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// messages that should be processed atomically
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Batch<T>(pub Vec<T>);
+
+impl<T> Batch<T> {
+    /// returns an empty batch
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// adds a message at the end of the batch
+    pub fn push(&mut self, msg: T) {
+        self.0.push(msg);
+    }
+
+    /// returns the number of messages in the batch
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// returns true if there is no message in the batch
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T> From<Vec<T>> for Batch<T> {
+    fn from(v: Vec<T>) -> Self {
+        Self(v)
+    }
+}
+
+/// hooks that are called around processing of a `Batch`
+pub trait Transaction {
+    /// error when running hooks
+    type Error;
+
+    /// future of `begin`
+    type BeginFuture: Future<Output = Result<(), Self::Error>>;
+
+    /// future of `commit`
+    type CommitFuture: Future<Output = Result<(), Self::Error>>;
+
+    /// future of `rollback`
+    type RollbackFuture: Future<Output = Result<(), Self::Error>>;
+
+    /// called before the first message of the batch
+    fn begin(&self) -> Self::BeginFuture;
+
+    /// called after every message of the batch is processed successfully
+    fn commit(&self) -> Self::CommitFuture;
+
+    /// called when one of the messages failed
+    fn rollback(&self) -> Self::RollbackFuture;
+}
+
+/// `Transaction` for closures/functions for simple definition of use.
+/// The type of each function would be as: `async fn() -> Result<(), Err>`
+pub struct FnTransaction<B, C, R> {
+    begin: B,
+    commit: C,
+    rollback: R,
+}
+
+impl<B, C, R, BFut, CFut, RFut, Err> Transaction for FnTransaction<B, C, R>
+where
+    B: Fn() -> BFut,
+    C: Fn() -> CFut,
+    R: Fn() -> RFut,
+    BFut: Future<Output = Result<(), Err>>,
+    CFut: Future<Output = Result<(), Err>>,
+    RFut: Future<Output = Result<(), Err>>,
+{
+    type Error = Err;
+    type BeginFuture = BFut;
+    type CommitFuture = CFut;
+    type RollbackFuture = RFut;
+
+    fn begin(&self) -> Self::BeginFuture {
+        (self.begin)()
+    }
+
+    fn commit(&self) -> Self::CommitFuture {
+        (self.commit)()
+    }
+
+    fn rollback(&self) -> Self::RollbackFuture {
+        (self.rollback)()
+    }
+}
+
+/// public function wrapper of `FnTransaction`
+/// use this to change functions into `Transaction`
+pub fn fn_transaction<B, C, R, BFut, CFut, RFut, Err>(
+    begin: B,
+    commit: C,
+    rollback: R,
+) -> FnTransaction<B, C, R>
+where
+    B: Fn() -> BFut,
+    C: Fn() -> CFut,
+    R: Fn() -> RFut,
+    BFut: Future<Output = Result<(), Err>>,
+    CFut: Future<Output = Result<(), Err>>,
+    RFut: Future<Output = Result<(), Err>>,
+{
+    FnTransaction {
+        begin,
+        commit,
+        rollback,
+    }
+}
+
+/// `Layer` that builds `BatchHandler`
+pub struct BatchLayer<X> {
+    transaction: Arc<X>,
+}
+
+impl<X> BatchLayer<X>
+where
+    X: Transaction,
+{
+    /// makes a layer that uses `transaction` as hooks
+    pub fn new(transaction: X) -> Self {
+        Self {
+            transaction: Arc::new(transaction),
+        }
+    }
+}
+
+impl<T, X, H> Layer<Batch<T>, H> for BatchLayer<X>
+where
+    T: 'static,
+    X: Transaction + 'static,
+    H: Handler<T, Error = X::Error> + 'static,
+{
+    type Next = T;
+    type Error = X::Error;
+    type Handler = BatchHandler<T, X, H>;
+    type InitError = X::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(BatchHandler {
+            transaction: self.transaction.clone(),
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that processes every message of `Batch` in one transaction
+pub struct BatchHandler<T, X, H> {
+    transaction: Arc<X>,
+    prev: Arc<H>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, X, H> Handler<Batch<T>> for BatchHandler<T, X, H>
+where
+    T: 'static,
+    X: Transaction + 'static,
+    H: Handler<T, Error = X::Error> + 'static,
+{
+    type Output = Vec<H::Output>;
+    type Error = X::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// Outputs of every message are returned in order after commit.
+    ///
+    /// When a message fails, the error of the message is returned even if
+    /// `rollback` succeeded. Error of `rollback` is returned instead when
+    /// `rollback` also fails.
+    fn call(&self, msg: Batch<T>) -> Self::Future {
+        let transaction = self.transaction.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            transaction.begin().await?;
+
+            let mut outputs = Vec::with_capacity(msg.len());
+            for m in msg.0 {
+                match prev.call(m).await {
+                    Ok(output) => outputs.push(output),
+                    Err(e) => {
+                        transaction.rollback().await?;
+                        return Err(e);
+                    }
+                }
+            }
+
+            transaction.commit().await?;
+            Ok(outputs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn push(&self, s: &str) -> Ready<Result<(), ()>> {
+            self.0.lock().unwrap().push(s.to_string());
+            ok(())
+        }
+    }
+
+    impl Transaction for Recorder {
+        type Error = ();
+        type BeginFuture = Ready<Result<(), ()>>;
+        type CommitFuture = Ready<Result<(), ()>>;
+        type RollbackFuture = Ready<Result<(), ()>>;
+
+        fn begin(&self) -> Self::BeginFuture {
+            self.push("begin")
+        }
+
+        fn commit(&self) -> Self::CommitFuture {
+            self.push("commit")
+        }
+
+        fn rollback(&self) -> Self::RollbackFuture {
+            self.push("rollback")
+        }
+    }
+
+    async fn positive(i: i32) -> Result<i32, ()> {
+        if i > 0 {
+            Ok(i)
+        } else {
+            Err(())
+        }
+    }
+
+    #[tokio::test]
+    async fn commit_test() -> Result<(), ()> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler = connect(BatchLayer::new(Recorder(log.clone())), positive).await?;
+        assert_eq!(
+            handler.call(Batch::from(vec![1, 2, 3])).await?,
+            vec![1, 2, 3]
+        );
+        assert_eq!(*log.lock().unwrap(), vec!["begin", "commit"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rollback_test() -> Result<(), ()> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let handler = connect(BatchLayer::new(Recorder(log.clone())), positive).await?;
+        assert!(handler.call(Batch::from(vec![1, -2, 3])).await.is_err());
+        assert_eq!(*log.lock().unwrap(), vec!["begin", "rollback"]);
+        Ok(())
+    }
+}
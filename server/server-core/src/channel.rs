@@ -0,0 +1,154 @@
+//! Bridges between handlers and `tokio::sync` channels
+//!
+//! Components of an application can feed or consume pipeline traffic
+//! through channels without touching transports.
+//!
+//! - `ChannelHandler` sends every message into a `mpsc` channel. It waits for
+//!   capacity when the channel is full.
+//! - `BroadcastHandler` sends every message to all receivers of a
+//!   `broadcast` channel.
+//! - `channel_source` and `broadcast_source` turn receivers into streams,
+//!   which can drive a pipeline with `stream::drive`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::channel::{channel_source, ChannelHandler};
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::stream::drive;
+//! use tokio::sync::mpsc;
+//!
+//! async fn print(s: &'static str) -> Result<(), ()> {
+//!     println!("{s}");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let (tx, rx) = mpsc::channel(16);
+//! let handler = ChannelHandler::new(tx);
+//! handler.call("hello").await.unwrap();
+//! drop(handler);
+//!
+//! // this would print "hello"
+//! drive(channel_source(rx), &fn_handler(print)).await.unwrap();
+//! # }
+//! ```
+
+use futures::future::{ready, BoxFuture, Ready};
+use futures::stream::{self, Stream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::handler::Handler;
+
+/// `Handler` that sends messages into a `mpsc` channel
+pub struct ChannelHandler<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T> ChannelHandler<T> {
+    pub fn new(tx: mpsc::Sender<T>) -> Self {
+        Self { tx }
+    }
+}
+
+/// The error has the message back when the receiver is closed
+impl<T: Send + 'static> Handler<T> for ChannelHandler<T> {
+    type Output = ();
+    type Error = mpsc::error::SendError<T>;
+    type Future = BoxFuture<'static, Result<(), Self::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let tx = self.tx.clone();
+        Box::pin(async move { tx.send(msg).await })
+    }
+}
+
+/// `Handler` that sends messages to every receiver of a `broadcast` channel
+pub struct BroadcastHandler<T> {
+    tx: broadcast::Sender<T>,
+}
+
+impl<T> BroadcastHandler<T> {
+    pub fn new(tx: broadcast::Sender<T>) -> Self {
+        Self { tx }
+    }
+}
+
+/// The output is the number of receivers. It fails when there is no receiver.
+impl<T> Handler<T> for BroadcastHandler<T> {
+    type Output = usize;
+    type Error = broadcast::error::SendError<T>;
+    type Future = Ready<Result<usize, Self::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        ready(self.tx.send(msg))
+    }
+}
+
+/// stream of messages received from a `mpsc` channel.
+/// It ends when every sender is dropped.
+pub fn channel_source<T>(rx: mpsc::Receiver<T>) -> impl Stream<Item = T> {
+    stream::unfold(
+        rx,
+        |mut rx| async move { rx.recv().await.map(|msg| (msg, rx)) },
+    )
+}
+
+/// stream of messages received from a `broadcast` channel.
+/// Messages missed by lagging behind are skipped.
+/// It ends when every sender is dropped.
+pub fn broadcast_source<T: Clone>(rx: broadcast::Receiver<T>) -> impl Stream<Item = T> {
+    stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => return Some((msg, rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn channel_test() {
+        let (tx, rx) = mpsc::channel(1);
+        let handler = ChannelHandler::new(tx);
+        handler.call(1).await.unwrap();
+
+        // waits for capacity
+        let send = tokio::spawn(handler.call(2));
+        let mut source = Box::pin(channel_source(rx));
+        assert_eq!(source.next().await, Some(1));
+        send.await.unwrap().unwrap();
+        assert_eq!(source.next().await, Some(2));
+
+        drop(handler);
+        assert_eq!(source.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn broadcast_test() {
+        let (tx, rx) = broadcast::channel(2);
+        let handler = BroadcastHandler::new(tx);
+        let mut source = Box::pin(broadcast_source(rx));
+
+        // 1 is lost by lagging behind
+        for i in 1..=3 {
+            assert_eq!(handler.call(i).await.unwrap(), 1);
+        }
+        assert_eq!(source.next().await, Some(2));
+        assert_eq!(source.next().await, Some(3));
+
+        drop(handler);
+        assert_eq!(source.next().await, None);
+    }
+}
@@ -0,0 +1,405 @@
+//! Self-check of deployment before serving
+//!
+//! Mistakes in deployment (a wrong certificate path, a broken `.proto` file,
+//! a typo in the auth server host) should be found before traffic is routed
+//! to the server. `run` checks `Config` and everything it points to, and
+//! returns a `Report` with the result of each check. The server binary runs
+//! it with `--check` and exits with failure when the report is not ok.
+//!
+//! With the `check` feature, `.proto` files are compiled with the protoc of
+//! `prost-build` (or the one in the `PROTOC` environment variable), so every
+//! error protoc finds is reported. Without it, they are only listed.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::check::{run, Status};
+//! use cubby_connect_server_core::config::Config;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let config = Config::builder().protobuf_dir("../../protobuf").build().unwrap();
+//! let report = run(&config).await;
+//! println!("{report}");
+//!
+//! // tls is not configured, which is just a warning
+//! assert_eq!(report.get("tls").unwrap().status, Status::Warn);
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+#[cfg(feature = "check")]
+use std::process::Command;
+use std::time::Duration;
+
+#[cfg(feature = "check")]
+use prost::Message;
+#[cfg(feature = "check")]
+use prost_types::{DescriptorProto, FileDescriptorSet};
+use tokio::net::lookup_host;
+
+use crate::config::Config;
+use crate::transport::mtls::load_client_auth;
+use crate::transport::{load_certs, load_tls, server_tls, TransportError};
+
+/// time to wait for resolving the auth server
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// codecs that this crate can provide
+const KNOWN_CODECS: [&str; 4] = ["protobuf", "json", "msgpack", "cbor"];
+
+/// result of a check
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Status {
+    Ok,
+
+    /// works, but probably not intended
+    Warn,
+
+    /// server cannot run correctly
+    Fail,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Ok => write!(f, " OK "),
+            Status::Warn => write!(f, "WARN"),
+            Status::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
+/// result of a check with details
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Item {
+    pub name: &'static str,
+    pub status: Status,
+    pub details: Vec<String>,
+}
+
+impl Item {
+    pub(crate) fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            details: Vec::new(),
+        }
+    }
+
+    /// adds a detail, making the status at least `status`
+    pub(crate) fn add<S: Into<String>>(&mut self, status: Status, detail: S) {
+        self.status = self.status.max(status);
+        self.details.push(detail.into());
+    }
+}
+
+/// results of every check
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    pub items: Vec<Item>,
+}
+
+impl Report {
+    /// returns true if nothing failed (warnings are ok)
+    pub fn is_ok(&self) -> bool {
+        self.items.iter().all(|item| item.status != Status::Fail)
+    }
+
+    /// returns the check of `name`
+    pub fn get(&self, name: &str) -> Option<&Item> {
+        self.items.iter().find(|item| item.name == name)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for item in &self.items {
+            writeln!(f, "[{}] {}", item.status, item.name)?;
+            for detail in &item.details {
+                writeln!(f, "       {detail}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// checks `config` and everything it points to
+pub async fn run(config: &Config) -> Report {
+    Report {
+        items: vec![
+            check_config(config),
+            check_tls(config),
+            check_protobuf(&config.protobuf_dir),
+            check_auth(config).await,
+        ],
+    }
+}
+
+fn check_config(config: &Config) -> Item {
+    let mut item = Item::new("config");
+
+    if config.codecs.is_empty() {
+        item.add(Status::Fail, "no codec is enabled");
+    }
+    for codec in &config.codecs {
+        if !KNOWN_CODECS.contains(&codec.as_str()) {
+            item.add(Status::Warn, format!("unknown codec `{codec}`"));
+        }
+    }
+    if config.quic_port == config.udp_port {
+        item.add(
+            Status::Fail,
+            format!("quic and udp use the same port {}", config.udp_port),
+        );
+    }
+    if config.verbose > 5 {
+        item.add(
+            Status::Warn,
+            format!("verbose {} is larger than 5", config.verbose),
+        );
+    }
+    if item.details.is_empty() {
+        item.add(Status::Ok, format!("codecs: {}", config.codecs.join(", ")));
+    }
+    item
+}
+
+fn check_tls(config: &Config) -> Item {
+    let mut item = Item::new("tls");
+
+    match (&config.cert_path, &config.key_path) {
+        (None, None) => item.add(Status::Warn, "tls is disabled (no cert_path and key_path)"),
+        (Some(_), None) => item.add(Status::Fail, "cert_path is set without key_path"),
+        (None, Some(_)) => item.add(Status::Fail, "key_path is set without cert_path"),
+        _ => match load_tls(config).and_then(|(certs, key)| {
+            let len = certs.len();
+            server_tls(certs, key, None).map(|_| len)
+        }) {
+            Ok(0) => item.add(Status::Fail, "no certificate in cert_path"),
+            Ok(len) => item.add(
+                Status::Ok,
+                format!("{len} certificate(s) with matching key"),
+            ),
+            Err(TransportError::Tls(e)) => item.add(Status::Fail, format!("invalid tls: {e}")),
+            Err(e) => item.add(Status::Fail, e.to_string()),
+        },
+    }
+
+    match &config.client_ca_path {
+        None => {}
+        Some(_) if config.cert_path.is_none() => {
+            item.add(Status::Fail, "client_ca_path is set without tls")
+        }
+        Some(_) => match load_client_auth(config) {
+            Ok(_) => item.add(
+                Status::Ok,
+                format!("client certificates are {:?}", config.client_auth).to_lowercase(),
+            ),
+            Err(e) => item.add(Status::Fail, format!("invalid client_ca_path: {e}")),
+        },
+    }
+    item
+}
+
+fn check_protobuf(dir: &Path) -> Item {
+    let mut item = Item::new("protobuf");
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            item.add(Status::Fail, format!("{}: {e}", dir.display()));
+            return item;
+        }
+    };
+
+    let mut files: Vec<_> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "proto"))
+        .collect();
+    files.sort();
+
+    if files.is_empty() {
+        item.add(Status::Warn, format!("no .proto file in {}", dir.display()));
+    }
+    #[cfg(feature = "check")]
+    for file in files {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        match validate_proto(&file, dir) {
+            Ok(messages) => item.add(Status::Ok, format!("{name}: {messages} message(s)")),
+            Err(e) => item.add(Status::Fail, format!("{name}: {e}")),
+        }
+    }
+    #[cfg(not(feature = "check"))]
+    item.add(
+        Status::Warn,
+        format!(
+            "{} .proto file(s) are not compiled without the `check` feature",
+            files.len()
+        ),
+    );
+    item
+}
+
+async fn check_auth(config: &Config) -> Item {
+    let mut item = Item::new("auth server");
+    let auth = &config.auth_config;
+
+    let resolved = tokio::time::timeout(
+        RESOLVE_TIMEOUT,
+        lookup_host((auth.host.as_str(), auth.port)),
+    )
+    .await;
+    match resolved {
+        Ok(Ok(mut addrs)) => match addrs.next() {
+            Some(addr) => item.add(Status::Ok, format!("{} resolved to {addr}", auth.host)),
+            None => item.add(Status::Fail, format!("{} has no address", auth.host)),
+        },
+        Ok(Err(e)) => item.add(Status::Fail, format!("cannot resolve {}: {e}", auth.host)),
+        Err(_) => item.add(Status::Fail, format!("resolving {} timed out", auth.host)),
+    }
+
+    match (auth.tls, &auth.ca_path) {
+        (false, _) => item.add(Status::Warn, "credentials are sent without tls"),
+        (true, None) => item.add(Status::Fail, "tls is on without ca_path"),
+        (true, Some(path)) => match load_certs(path) {
+            Ok(certs) if certs.is_empty() => item.add(Status::Fail, "no certificate in ca_path"),
+            Ok(certs) => item.add(Status::Ok, format!("{} ca certificate(s)", certs.len())),
+            Err(e) => item.add(Status::Fail, format!("ca_path: {e}")),
+        },
+    }
+    item
+}
+
+/// compiles `file` with protoc, looking up imports in `include`.
+/// Returns the number of messages, including nested ones.
+#[cfg(feature = "check")]
+pub fn validate_proto(file: &Path, include: &Path) -> Result<usize, String> {
+    let descriptors = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+    let output = Command::new(prost_build::protoc())
+        .arg("-I")
+        .arg(include)
+        .arg("-I")
+        .arg(prost_build::protoc_include())
+        .arg("-o")
+        .arg(descriptors.path())
+        .arg(file)
+        .output()
+        .map_err(|e| format!("cannot run protoc: {e}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let buf = std::fs::read(descriptors.path()).map_err(|e| e.to_string())?;
+    let set = FileDescriptorSet::decode(buf.as_slice()).map_err(|e| e.to_string())?;
+    Ok(set
+        .file
+        .iter()
+        .map(|f| count_messages(&f.message_type))
+        .sum())
+}
+
+#[cfg(feature = "check")]
+fn count_messages(messages: &[DescriptorProto]) -> usize {
+    messages
+        .iter()
+        .map(|m| 1 + count_messages(&m.nested_type))
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::AuthServer;
+
+    use super::*;
+
+    /// writes `source` as `test.proto` in `dir`, and validates it
+    #[cfg(feature = "check")]
+    fn validate(dir: &Path, source: &str) -> Result<usize, String> {
+        let file = dir.join("test.proto");
+        std::fs::write(&file, source).unwrap();
+        validate_proto(&file, dir)
+    }
+
+    #[cfg(feature = "check")]
+    #[test]
+    fn validate_proto_test() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("common.proto"),
+            "syntax = \"proto3\"; package common; message Id { uint64 id = 1; }",
+        )
+        .unwrap();
+        let source = r#"
+            syntax = "proto3";
+            package sample;
+            import "common.proto";
+            import "google/protobuf/timestamp.proto";
+            // comment with message Fake { }
+            message Outer {
+              string name = 1;
+              map<string, string> headers = 2;
+              /* block = 3; */
+              oneof kind {
+                int32 a = 3;
+                common.Id b = 4;
+              }
+              message Inner {
+                google.protobuf.Timestamp x = 1;
+              }
+              reserved 5, 6;
+            }
+        "#;
+        // the entry of the map is a nested message as well
+        assert_eq!(validate(dir.path(), source), Ok(3));
+
+        let invalid = [
+            "syntax = \"proto3\"; message A { int32 a = 1; int32 b = 1; }",
+            "syntax = \"proto3\"; message A { int32 a = 19000; }",
+            "syntax = \"proto3\"; message A { int32 a = x; }",
+            "syntax = \"proto3\"; message A { int32 a 1; }",
+            "syntax = \"proto3\"; message A { Unknown a = 1; }",
+            "syntax = \"proto3\"; message A { int32 a = 1;",
+            "}",
+        ];
+        for source in invalid {
+            assert!(validate(dir.path(), source).is_err(), "{source}");
+        }
+        assert!(
+            validate(dir.path(), "syntax = \"proto3\"; message A { int32 a 1; }")
+                .unwrap_err()
+                .contains("test.proto")
+        );
+    }
+
+    #[tokio::test]
+    async fn run_test() {
+        let config = Config::builder()
+            .protobuf_dir("../../protobuf")
+            .cert_path("missing.pem")
+            .quic_port(1000)
+            .udp_port(1000)
+            .codecs(vec!["protobuf".to_string(), "xml".to_string()])
+            .auth_config(
+                AuthServer::builder()
+                    .host("localhost")
+                    .tls(false)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let report = run(&config).await;
+        assert!(!report.is_ok());
+
+        let item = report.get("config").unwrap();
+        assert_eq!(item.status, Status::Fail);
+        assert_eq!(item.details.len(), 2);
+        assert_eq!(report.get("tls").unwrap().status, Status::Fail);
+        #[cfg(feature = "check")]
+        assert_eq!(report.get("protobuf").unwrap().status, Status::Ok);
+        assert_eq!(report.get("auth server").unwrap().status, Status::Warn);
+        assert!(report.to_string().contains("[FAIL] tls\n"));
+    }
+}
@@ -0,0 +1,452 @@
+//! Minimum client version enforced in the handshake
+//!
+//! Operators retire old clients in two stages. First a soft minimum blocks
+//! a percentage of the clients below it, so a deprecation can be rolled
+//! out (and rolled back) gradually. Then the hard minimum blocks every
+//! client below it.
+//!
+//! `VersionPolicy` checks the crate version of `handshake::ClientInfo`.
+//! Clients that do not send it are older than any version with client
+//! info, so they are treated as `0.0.0`. Which clients fall in the
+//! percentage depends on a key given by the server (e.g. the IP address of
+//! the peer), so a client that reconnects gets the same answer.
+//!
+//! Blocked clients are told why in `ServerHello` with a reason of `Blocked`,
+//! and the connection is closed.
+//!
+//! A policy can also require a semver range with `VersionReq`, like
+//! `>=0.3, <0.5` or `^0.4` (the syntax of Cargo). A client outside of it
+//! is incompatible rather than blocked, and gets
+//! `handshake::IncompatibleVersion` with the requirement.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::client_version::{Blocked, Version, VersionPolicy, VersionReq};
+//! use cubby_connect_server_core::handshake::ClientInfo;
+//!
+//! let policy = VersionPolicy::new()
+//!     .min("0.1.0".parse().unwrap())
+//!     .soft_min("0.3.0".parse().unwrap(), 100);
+//!
+//! let mut info = ClientInfo::current();
+//! info.crate_version = String::from("0.2.5");
+//! assert_eq!(policy.check(Some(&info), "10.0.0.1"), Err(Blocked::Deprecated));
+//! info.crate_version = String::from("0.0.9");
+//! assert_eq!(policy.check(Some(&info), "10.0.0.1"), Err(Blocked::TooOld));
+//! assert_eq!(policy.check(None, "10.0.0.1"), Err(Blocked::TooOld));
+//! assert_eq!(Version::new(0, 3, 0).to_string(), "0.3.0");
+//!
+//! let req: VersionReq = ">=0.3, <0.5".parse().unwrap();
+//! assert!(req.matches(&Version::new(0, 4, 2)));
+//! assert!(!req.matches(&Version::new(0, 5, 0)));
+//! ```
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::handshake::ClientInfo;
+
+/// version as `major.minor.patch`.
+/// A pre-release or build suffix (`-beta.1`, `+abc`) is ignored.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// error when a version is not `major.minor.patch`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseVersionError(pub String);
+
+impl Display for ParseVersionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid version `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseVersionError {}
+
+impl FromStr for Version {
+    type Err = ParseVersionError;
+
+    /// missing minor and patch are 0, so `1` is `1.0.0`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseVersionError(s.to_string());
+        let core = s.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = core.split('.').map(|part| part.parse::<u64>());
+        let mut next = |required| match parts.next() {
+            Some(part) => part.map_err(|_| error()),
+            None if required => Err(error()),
+            None => Ok(0),
+        };
+        let version = Version::new(next(true)?, next(false)?, next(false)?);
+        match parts.next() {
+            Some(_) => Err(error()),
+            None => Ok(version),
+        }
+    }
+}
+
+/// operator of a comparator in `VersionReq`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+}
+
+/// semver requirement with the syntax of Cargo, like `^1.2`, `~0.3.1`,
+/// `>=0.2, <0.4` or `*`. A version without an operator is a caret
+/// requirement, and every comparator separated by commas must match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionReq {
+    text: String,
+
+    /// allowed range of each comparator, from inclusive to exclusive
+    ranges: Vec<(Version, Option<Version>)>,
+}
+
+impl VersionReq {
+    /// returns a requirement that every version matches
+    pub fn any() -> Self {
+        Self {
+            text: String::from("*"),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// returns true if `version` matches every comparator
+    pub fn matches(&self, version: &Version) -> bool {
+        self.ranges
+            .iter()
+            .all(|(min, end)| min <= version && end.is_none_or(|end| *version < end))
+    }
+}
+
+impl Display for VersionReq {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for comparator in s.split(',').map(str::trim) {
+            if comparator != "*" {
+                ranges.push(range(comparator).ok_or_else(|| ParseVersionError(s.to_string()))?);
+            }
+        }
+        Ok(Self {
+            text: s.trim().to_string(),
+            ranges,
+        })
+    }
+}
+
+/// returns the range of versions that `comparator` allows
+fn range(comparator: &str) -> Option<(Version, Option<Version>)> {
+    let (op, partial) = [
+        (">=", Op::GreaterEq),
+        ("<=", Op::LessEq),
+        (">", Op::Greater),
+        ("<", Op::Less),
+        ("=", Op::Exact),
+        ("~", Op::Tilde),
+        ("^", Op::Caret),
+    ]
+    .into_iter()
+    .find_map(|(prefix, op)| comparator.strip_prefix(prefix).map(|rest| (op, rest)))
+    .unwrap_or((Op::Caret, comparator));
+
+    // parts after a wildcard are ignored, so `1.*` is `1`
+    let core = partial.trim().split(['-', '+']).next()?;
+    let mut parts = Vec::new();
+    for part in core.split('.') {
+        match part {
+            "*" | "x" | "X" => break,
+            part => parts.push(part.parse::<u64>().ok()?),
+        }
+    }
+    let (major, minor, patch) = match parts[..] {
+        [major] => (major, None, None),
+        [major, minor] => (major, Some(minor), None),
+        [major, minor, patch] => (major, Some(minor), Some(patch)),
+        _ => return None,
+    };
+
+    let low = Version::new(major, minor.unwrap_or(0), patch.unwrap_or(0));
+    // the first version after every version that `partial` stands for
+    let next = match (minor, patch) {
+        (Some(minor), Some(patch)) => Version::new(major, minor, patch + 1),
+        (Some(minor), None) => Version::new(major, minor + 1, 0),
+        _ => Version::new(major + 1, 0, 0),
+    };
+    let zero = Version::default();
+    Some(match op {
+        Op::Exact => (low, Some(next)),
+        Op::Greater => (next, None),
+        Op::GreaterEq => (low, None),
+        Op::Less => (zero, Some(low)),
+        Op::LessEq => (zero, Some(next)),
+        Op::Tilde => match minor {
+            Some(minor) => (low, Some(Version::new(major, minor + 1, 0))),
+            None => (low, Some(next)),
+        },
+        Op::Caret => match (major, minor, patch) {
+            (0, Some(0), Some(_)) => (low, Some(next)),
+            (0, Some(minor), _) => (low, Some(Version::new(0, minor + 1, 0))),
+            _ => (low, Some(Version::new(major + 1, 0, 0))),
+        },
+    })
+}
+
+/// reason why a client is blocked
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Blocked {
+    /// the client is below the minimum version
+    TooOld,
+
+    /// the client is below the soft minimum, and in the blocked percentage
+    Deprecated,
+}
+
+impl Blocked {
+    /// name of the reason in `ServerHello`
+    pub fn name(self) -> &'static str {
+        match self {
+            Blocked::TooOld => "client_too_old",
+            Blocked::Deprecated => "client_deprecated",
+        }
+    }
+
+    /// returns the reason of `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "client_too_old" => Some(Blocked::TooOld),
+            "client_deprecated" => Some(Blocked::Deprecated),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Blocked {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Blocked::TooOld => write!(f, "client is older than the minimum version"),
+            Blocked::Deprecated => write!(f, "client version is deprecated"),
+        }
+    }
+}
+
+/// minimum versions of clients allowed to connect
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VersionPolicy {
+    min: Option<Version>,
+    soft_min: Option<(Version, u8)>,
+    requirement: Option<VersionReq>,
+}
+
+impl VersionPolicy {
+    /// returns a policy that allows every client
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns the policy of `Config::min_client_version`,
+    /// `Config::soft_min_client_version` and `Config::client_version_req`
+    pub fn from_config(config: &Config) -> Result<Self, ParseVersionError> {
+        let mut policy = Self::new();
+        if let Some(requirement) = &config.client_version_req {
+            policy = policy.require(requirement.parse()?);
+        }
+        if let Some(min) = &config.min_client_version {
+            policy = policy.min(min.parse()?);
+        }
+        if let Some(soft_min) = &config.soft_min_client_version {
+            policy = policy.soft_min(soft_min.parse()?, config.soft_block_percent);
+        }
+        Ok(policy)
+    }
+
+    /// blocks every client below `version`
+    pub fn min(mut self, version: Version) -> Self {
+        self.min = Some(version);
+        self
+    }
+
+    /// blocks `percent` (0 to 100) of the clients below `version`
+    pub fn soft_min(mut self, version: Version, percent: u8) -> Self {
+        self.soft_min = Some((version, percent.min(100)));
+        self
+    }
+
+    /// makes clients that don't match `requirement` incompatible
+    pub fn require(mut self, requirement: VersionReq) -> Self {
+        self.requirement = Some(requirement);
+        self
+    }
+
+    /// returns the semver requirement of clients
+    pub fn requirement(&self) -> Option<&VersionReq> {
+        self.requirement.as_ref()
+    }
+
+    /// returns true if no client is blocked or incompatible
+    pub fn allows_all(&self) -> bool {
+        self.min.is_none()
+            && self.soft_min.is_none_or(|(_, percent)| percent == 0)
+            && self.requirement.is_none()
+    }
+
+    /// checks a client that sent `info`.
+    /// `key` decides if the client is in the blocked percentage.
+    /// A version that cannot be parsed is treated as `0.0.0`.
+    pub fn check<K: Hash + ?Sized>(
+        &self,
+        info: Option<&ClientInfo>,
+        key: &K,
+    ) -> Result<(), Blocked> {
+        let version: Version = info
+            .and_then(|info| info.crate_version.parse().ok())
+            .unwrap_or_default();
+        if self.min.is_some_and(|min| version < min) {
+            return Err(Blocked::TooOld);
+        }
+        match self.soft_min {
+            Some((min, percent)) if version < min && bucket(key) < percent => {
+                Err(Blocked::Deprecated)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// returns the bucket of `key` in 0 to 99
+fn bucket<K: Hash + ?Sized>(key: &K) -> u8 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % 100) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info(version: &str) -> ClientInfo {
+        ClientInfo {
+            crate_version: version.to_string(),
+            ..ClientInfo::current()
+        }
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!("1.2.3".parse(), Ok(Version::new(1, 2, 3)));
+        assert_eq!("1.2".parse(), Ok(Version::new(1, 2, 0)));
+        assert_eq!("0.4.0-beta.1+abc".parse(), Ok(Version::new(0, 4, 0)));
+        assert!("1.2.3.4".parse::<Version>().is_err());
+        assert!("v1".parse::<Version>().is_err());
+        assert!("".parse::<Version>().is_err());
+        assert!(Version::new(0, 10, 0) > Version::new(0, 9, 9));
+    }
+
+    #[test]
+    fn requirement_test() {
+        let matches = |req: &str, version: &str| {
+            let req: VersionReq = req.parse().unwrap();
+            req.matches(&version.parse().unwrap())
+        };
+        assert!(matches("^1.2.3", "1.9.0"));
+        assert!(!matches("^1.2.3", "2.0.0"));
+        assert!(!matches("^1.2.3", "1.2.2"));
+        assert!(matches("0.3", "0.3.7"));
+        assert!(!matches("0.3", "0.4.0"));
+        assert!(!matches("^0.0.3", "0.0.4"));
+        assert!(matches("~1.2", "1.2.9"));
+        assert!(!matches("~1.2", "1.3.0"));
+        assert!(matches("=1.2", "1.2.5"));
+        assert!(!matches(">1.2", "1.2.9"));
+        assert!(matches("<=1.2", "1.2.9"));
+        assert!(matches(">=0.2, <0.4", "0.3.1"));
+        assert!(!matches(">=0.2, <0.4", "0.4.0"));
+        assert!(matches("1.*", "1.5.0"));
+        assert!(matches("*", "0.0.0"));
+        assert!(VersionReq::any().matches(&Version::new(9, 9, 9)));
+        assert!("^".parse::<VersionReq>().is_err());
+        assert!(">=1.2.3.4".parse::<VersionReq>().is_err());
+        assert_eq!(
+            ">=0.2, <0.4".parse::<VersionReq>().unwrap().to_string(),
+            ">=0.2, <0.4"
+        );
+    }
+
+    #[test]
+    fn staged_test() {
+        let policy = VersionPolicy::new().soft_min(Version::new(1, 0, 0), 30);
+        let keys: Vec<String> = (0..1000)
+            .map(|i| format!("10.0.{}.{}", i / 256, i % 256))
+            .collect();
+        let blocked = keys
+            .iter()
+            .filter(|key| policy.check(Some(&info("0.9.0")), key.as_str()).is_err())
+            .count();
+        assert!((200..400).contains(&blocked), "{blocked} blocked");
+
+        // the same key gets the same answer, and new clients are never blocked
+        for key in &keys {
+            let first = policy.check(Some(&info("0.9.0")), key.as_str());
+            assert_eq!(policy.check(Some(&info("0.9.0")), key.as_str()), first);
+            assert_eq!(policy.check(Some(&info("1.0.0")), key.as_str()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn from_config_test() {
+        let config = Config::builder()
+            .min_client_version("0.1.0")
+            .client_version_req("^0.1")
+            .build()
+            .unwrap();
+        let policy = VersionPolicy::from_config(&config).unwrap();
+        assert!(!policy.allows_all());
+        assert_eq!(policy.requirement().unwrap().to_string(), "^0.1");
+        assert_eq!(policy.check(Some(&info("0.1.0")), "key"), Ok(()));
+        assert_eq!(
+            policy.check(Some(&info("garbage")), "key"),
+            Err(Blocked::TooOld)
+        );
+        assert!(
+            VersionPolicy::from_config(&Config::builder().build().unwrap())
+                .unwrap()
+                .allows_all()
+        );
+    }
+}
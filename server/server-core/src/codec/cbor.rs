@@ -0,0 +1,69 @@
+//! CBOR codec with `ciborium`
+//!
+//! Payloads are carried in the same frames as other codecs (see `framed`).
+//!
+//! This module needs the `cbor` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::cbor::Cbor;
+//! use cubby_connect_server_core::codec::Codec;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct Move {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! let bytes = Cbor.encode(&Move { x: 1, y: 2 }).unwrap();
+//! let msg: Move = Cbor.decode(&bytes).unwrap();
+//! assert_eq!(msg, Move { x: 1, y: 2 });
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::{Codec, CodecError};
+
+/// CBOR codec of `serde` messages
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Cbor;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for Cbor {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, msg: &M) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(msg, &mut bytes).map_err(|e| CodecError::Encode(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        ciborium::from_reader(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode_test() {
+        let msg = BTreeMap::from([("hp".to_string(), 10u8)]);
+        let bytes = Cbor.encode(&msg).unwrap();
+        // map(1), text(2) "hp", unsigned(10)
+        assert_eq!(bytes, [0xa1, 0x62, b'h', b'p', 0x0a]);
+        assert_eq!(Cbor.decode(&bytes), Ok(msg));
+
+        assert!(matches!(
+            Codec::<BTreeMap<String, u8>>::decode(&Cbor, &[0xa1]),
+            Err(CodecError::Decode(_))
+        ));
+    }
+}
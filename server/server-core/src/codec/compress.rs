@@ -0,0 +1,632 @@
+//! Compression of frames
+//!
+//! Large payloads like world snapshots can be compressed with zstd (with the
+//! `zstd` feature, which is on by default), lz4 or deflate.
+//! The algorithm is negotiated for each connection during the handshake
+//! (see `handshake::accept_with`), and put into the context of the
+//! connection as `Compression`.
+//!
+//! When a compression is negotiated, every frame starts with a byte telling
+//! how the rest is compressed. Frames smaller than the threshold are not
+//! worth compressing, so they are sent as they are with `FLAG_NONE`.
+//! When nothing is negotiated (the peer does not support compression),
+//! frames are sent as they are without the flag.
+//!
+//! Deflate can retain its context across frames of a connection (context
+//! takeover), so later frames refer to earlier ones and compress better,
+//! at the cost of keeping the streams in memory for each connection
+//! (`DeflateContext`). Such frames are flagged with `FLAG_DEFLATE_TAKEOVER`
+//! and must be decompressed in the order they were compressed. A sender
+//! only retains its context when enabled (with `Config::context_takeover`
+//! for frames of `Server`, or `CompressLayer::context_takeover`) and
+//! deflate was negotiated for the connection; a receiver follows the flags
+//! of the peer.
+//! Streams multiplexed on one connection (QUIC) each get their own
+//! `DeflateContext`, since their frames are not ordered with each other.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::compress::{CompressLayer, Compression, CompressError};
+//! use cubby_connect_server_core::context::Request;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn snapshot(_: Request<Vec<u8>>) -> Result<Vec<u8>, CompressError> {
+//!     Ok(vec![0; 4096])
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CompressError> {
+//! let handler = apply!(CompressLayer::new(1024) to snapshot);
+//!
+//! let mut req = Request::new(Compression::Zstd.compress(b"get", 1024)?);
+//! req.context.insert(Compression::Zstd);
+//! let compressed = handler.call(req).await?;
+//! assert!(compressed.len() < 100);
+//! assert_eq!(Compression::decompress(&compressed)?, vec![0; 4096]);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::codec::framed::MAX_FRAME;
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// flag of a frame that is not compressed
+pub const FLAG_NONE: u8 = 0;
+
+/// flag of a frame compressed with zstd
+pub const FLAG_ZSTD: u8 = 1;
+
+/// flag of a frame compressed with lz4
+pub const FLAG_LZ4: u8 = 2;
+
+/// flag of a frame compressed with deflate from a fresh context
+pub const FLAG_DEFLATE: u8 = 3;
+
+/// flag of a frame compressed with deflate, continuing the context of the
+/// previous frames with this flag in the same direction of the connection
+pub const FLAG_DEFLATE_TAKEOVER: u8 = 4;
+
+/// end of a deflate block flushed with sync flush, which is not sent
+const SYNC_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+/// reason why zstd frames fail without the `zstd` feature
+#[cfg(not(feature = "zstd"))]
+const ZSTD_OFF: &str = "zstd is not supported without the `zstd` feature";
+
+/// error when compressing or decompressing frames
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompressError {
+    /// frame cannot be compressed
+    Compress(String),
+
+    /// frame cannot be decompressed
+    Decompress(String),
+
+    /// frame starts with an unknown flag
+    UnknownFlag(u8),
+}
+
+impl Display for CompressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressError::Compress(e) => write!(f, "failed to compress: {e}"),
+            CompressError::Decompress(e) => write!(f, "failed to decompress: {e}"),
+            CompressError::UnknownFlag(flag) => write!(f, "unknown compression flag {flag}"),
+        }
+    }
+}
+
+impl std::error::Error for CompressError {}
+
+/// compression algorithm of a connection
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Compression {
+    /// frames are not compressed and have no flag
+    #[default]
+    None,
+
+    /// zstd, better ratio
+    Zstd,
+
+    /// lz4, faster
+    Lz4,
+
+    /// deflate, whose context can be retained across frames
+    Deflate,
+}
+
+impl Compression {
+    /// name that is used in negotiation
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// returns the compression of `name`.
+    /// Returns `None` for `zstd` without the `zstd` feature, so it is never
+    /// negotiated.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            "deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    /// chooses the most preferred compression in `enabled` that is `offered`.
+    /// Returns `Compression::None` when there is nothing in common.
+    pub fn negotiate<S: AsRef<str>>(enabled: &[Compression], offered: &[S]) -> Self {
+        enabled
+            .iter()
+            .copied()
+            .find(|c| offered.iter().any(|o| o.as_ref() == c.name()))
+            .unwrap_or_default()
+    }
+
+    /// compresses `data` with the flag.
+    /// `data` smaller than `threshold` is not compressed.
+    /// Deflate starts from a fresh context (see `DeflateContext` to retain it).
+    ///
+    /// `data` is returned as it is with `Compression::None`.
+    pub fn compress(&self, data: &[u8], threshold: usize) -> Result<Vec<u8>, CompressError> {
+        let compress_err = |e: std::io::Error| CompressError::Compress(e.to_string());
+
+        let (flag, body) = match self {
+            Compression::None => return Ok(data.to_vec()),
+            _ if data.len() < threshold => (FLAG_NONE, data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => (
+                FLAG_ZSTD,
+                zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .map_err(compress_err)?,
+            ),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => return Err(CompressError::Compress(ZSTD_OFF.to_string())),
+            Compression::Lz4 => (FLAG_LZ4, lz4_flex::compress_prepend_size(data)),
+            Compression::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).map_err(compress_err)?;
+                (FLAG_DEFLATE, encoder.finish().map_err(compress_err)?)
+            }
+        };
+
+        let mut frame = Vec::with_capacity(body.len() + 1);
+        frame.push(flag);
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    /// decompresses a frame made by `compress` of any algorithm except
+    /// `Compression::None`.
+    /// Frames larger than `MAX_FRAME` after decompression are rejected, and
+    /// so are frames of `FLAG_DEFLATE_TAKEOVER`, which need a `DeflateContext`.
+    pub fn decompress(frame: &[u8]) -> Result<Vec<u8>, CompressError> {
+        let decompress_err = |e: String| CompressError::Decompress(e);
+
+        let (flag, body) = frame
+            .split_first()
+            .ok_or_else(|| decompress_err("empty frame".to_string()))?;
+        match *flag {
+            FLAG_NONE => Ok(body.to_vec()),
+            #[cfg(feature = "zstd")]
+            FLAG_ZSTD => {
+                // `zstd::bulk::decompress` would reserve `MAX_FRAME` for
+                // every frame, so the buffer grows with the data instead
+                let decoder = zstd::stream::read::Decoder::with_buffer(body)
+                    .map_err(|e| decompress_err(e.to_string()))?;
+                read_limited(decoder)
+            }
+            #[cfg(not(feature = "zstd"))]
+            FLAG_ZSTD => Err(decompress_err(ZSTD_OFF.to_string())),
+            FLAG_LZ4 => {
+                let len = body
+                    .get(..4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                    .ok_or_else(|| decompress_err("missing size".to_string()))?;
+                if len > MAX_FRAME {
+                    return Err(decompress_err(format!("{len} bytes is too large")));
+                }
+                lz4_flex::decompress_size_prepended(body).map_err(|e| decompress_err(e.to_string()))
+            }
+            FLAG_DEFLATE => read_limited(flate2::read::DeflateDecoder::new(body)),
+            FLAG_DEFLATE_TAKEOVER => Err(decompress_err(
+                "deflate context of the connection is needed".to_string(),
+            )),
+            flag => Err(CompressError::UnknownFlag(flag)),
+        }
+    }
+}
+
+/// reads decompressed data of a frame, which is rejected when it is larger
+/// than `MAX_FRAME`
+fn read_limited<R: Read>(decoder: R) -> Result<Vec<u8>, CompressError> {
+    let mut data = Vec::new();
+    decoder
+        .take(MAX_FRAME as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| CompressError::Decompress(e.to_string()))?;
+    if data.len() > MAX_FRAME {
+        return Err(CompressError::Decompress("frame is too large".to_string()));
+    }
+    Ok(data)
+}
+
+impl FromContext for Compression {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Compression>().copied()
+    }
+}
+
+/// deflate streams of a connection that are retained across frames for
+/// context takeover. Each stream is made at its first frame, so a
+/// connection that does not use context takeover keeps no memory.
+///
+/// It is put into the context of a connection that negotiated
+/// `Compression::Deflate` (see `handshake::Accepted::insert_into`).
+/// Clones share the same streams, so a context must not be shared by
+/// streams whose frames are not ordered with each other.
+#[derive(Clone, Default)]
+pub struct DeflateContext {
+    streams: Arc<Mutex<Streams>>,
+}
+
+#[derive(Default)]
+struct Streams {
+    compress: Option<Compress>,
+    decompress: Option<Decompress>,
+}
+
+impl DeflateContext {
+    /// returns a context without streams
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// compresses `data` with `Compression::Deflate`. With `takeover`, the
+    /// stream of the previous frames is continued and the frame is flagged
+    /// with `FLAG_DEFLATE_TAKEOVER`.
+    /// `data` smaller than `threshold` is not compressed.
+    pub fn compress(
+        &self,
+        data: &[u8],
+        threshold: usize,
+        takeover: bool,
+    ) -> Result<Vec<u8>, CompressError> {
+        if !takeover || data.len() < threshold {
+            return Compression::Deflate.compress(data, threshold);
+        }
+
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams
+            .compress
+            .get_or_insert_with(|| Compress::new(flate2::Compression::default(), false));
+
+        let mut frame = Vec::with_capacity(data.len() / 2 + 64);
+        frame.push(FLAG_DEFLATE_TAKEOVER);
+        let mut input = data;
+        loop {
+            let total_in = stream.total_in();
+            stream
+                .compress_vec(input, &mut frame, FlushCompress::Sync)
+                .map_err(|e| CompressError::Compress(e.to_string()))?;
+            input = &input[(stream.total_in() - total_in) as usize..];
+
+            // flushed when there is room left in the output
+            if input.is_empty() && frame.len() < frame.capacity() {
+                break;
+            }
+            frame.reserve(frame.capacity());
+        }
+        if frame.ends_with(&SYNC_TAIL) {
+            frame.truncate(frame.len() - SYNC_TAIL.len());
+        }
+        Ok(frame)
+    }
+
+    /// decompresses a frame of any flag. Frames of `FLAG_DEFLATE_TAKEOVER`
+    /// continue the stream of the previous ones.
+    /// Frames larger than `MAX_FRAME` after decompression are rejected.
+    pub fn decompress(&self, frame: &[u8]) -> Result<Vec<u8>, CompressError> {
+        let decompress_err = |e: String| CompressError::Decompress(e);
+
+        let body = match frame.split_first() {
+            Some((&FLAG_DEFLATE_TAKEOVER, body)) => body,
+            _ => return Compression::decompress(frame),
+        };
+
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams
+            .decompress
+            .get_or_insert_with(|| Decompress::new(false));
+
+        let mut data = Vec::with_capacity(body.len() * 2 + 64);
+        for mut input in [body, &SYNC_TAIL] {
+            loop {
+                let total_in = stream.total_in();
+                let status = stream
+                    .decompress_vec(input, &mut data, FlushDecompress::Sync)
+                    .map_err(|e| decompress_err(e.to_string()))?;
+                input = &input[(stream.total_in() - total_in) as usize..];
+
+                if data.len() > MAX_FRAME {
+                    return Err(decompress_err("frame is too large".to_string()));
+                }
+                if status == Status::StreamEnd {
+                    return Err(decompress_err("deflate stream is finished".to_string()));
+                }
+                if input.is_empty() && data.len() < data.capacity() {
+                    break;
+                }
+                data.reserve(data.capacity());
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Debug for DeflateContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let streams = self.streams.lock().unwrap();
+        f.debug_struct("DeflateContext")
+            .field("compress", &streams.compress.is_some())
+            .field("decompress", &streams.decompress.is_some())
+            .finish()
+    }
+}
+
+impl FromContext for DeflateContext {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<DeflateContext>().cloned()
+    }
+}
+
+/// `Layer` that builds `CompressHandler`
+pub struct CompressLayer {
+    threshold: usize,
+    context_takeover: bool,
+}
+
+impl CompressLayer {
+    /// compresses responses that are not smaller than `threshold` bytes
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            context_takeover: false,
+        }
+    }
+
+    /// sets whether deflate retains its context across responses of a
+    /// connection that negotiated deflate (default: false). Retaining
+    /// compresses better, resetting keeps no deflate stream in memory for
+    /// each connection.
+    pub fn context_takeover(mut self, context_takeover: bool) -> Self {
+        self.context_takeover = context_takeover;
+        self
+    }
+}
+
+impl<H> Layer<Request<Vec<u8>>, H> for CompressLayer
+where
+    H: Handler<Request<Vec<u8>>, Output = Vec<u8>> + 'static,
+    H::Error: From<CompressError> + 'static,
+{
+    type Next = Request<Vec<u8>>;
+    type Error = H::Error;
+    type Handler = CompressHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(CompressHandler {
+            threshold: self.threshold,
+            context_takeover: self.context_takeover,
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that decompresses requests and compresses responses with the
+/// compression negotiated for the connection, and the `DeflateContext` of
+/// the connection if it has one.
+/// Empty responses are kept empty, since they mean no response.
+pub struct CompressHandler<H> {
+    threshold: usize,
+    context_takeover: bool,
+    prev: Arc<H>,
+    _marker: PhantomData<fn()>,
+}
+
+impl<H> Handler<Request<Vec<u8>>> for CompressHandler<H>
+where
+    H: Handler<Request<Vec<u8>>, Output = Vec<u8>> + 'static,
+    H::Error: From<CompressError> + 'static,
+{
+    type Output = Vec<u8>;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Vec<u8>, H::Error>>;
+
+    fn call(&self, mut req: Request<Vec<u8>>) -> Self::Future {
+        let compression = Compression::from_context(&req.context).unwrap_or_default();
+        let deflate = DeflateContext::from_context(&req.context);
+        let (threshold, takeover) = (self.threshold, self.context_takeover);
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            if compression != Compression::None {
+                req.msg = match &deflate {
+                    Some(deflate) => deflate.decompress(&req.msg)?,
+                    None => Compression::decompress(&req.msg)?,
+                };
+            }
+
+            let output = prev.call(req).await?;
+            if output.is_empty() {
+                return Ok(output);
+            }
+            match (compression, deflate) {
+                (Compression::Deflate, Some(deflate)) => {
+                    Ok(deflate.compress(&output, threshold, takeover)?)
+                }
+                _ => Ok(compression.compress(&output, threshold)?),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn compress_test() -> Result<(), CompressError> {
+        let data = b"snapshot ".repeat(100);
+        for compression in [Compression::Zstd, Compression::Lz4, Compression::Deflate] {
+            let frame = compression.compress(&data, 100)?;
+            assert!(frame.len() < data.len());
+            assert_eq!(Compression::decompress(&frame)?, data);
+
+            // small frame is not compressed
+            let frame = compression.compress(b"hi", 100)?;
+            assert_eq!(frame, [FLAG_NONE, b'h', b'i']);
+            assert_eq!(Compression::decompress(&frame)?, b"hi");
+        }
+
+        assert_eq!(Compression::None.compress(&data, 0)?, data);
+        assert_eq!(
+            Compression::decompress(&[9, 1, 2]),
+            Err(CompressError::UnknownFlag(9))
+        );
+        assert!(Compression::decompress(&[FLAG_LZ4, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(Compression::decompress(&[]).is_err());
+        assert!(Compression::decompress(&[FLAG_ZSTD, 1, 2, 3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_limit_test() -> Result<(), CompressError> {
+        // small frames don't reserve the largest frame
+        let frame = Compression::Zstd.compress(&b"hi ".repeat(100), 0)?;
+        let data = Compression::decompress(&frame)?;
+        assert_eq!(data, b"hi ".repeat(100));
+        assert!(data.capacity() < MAX_FRAME / 16);
+
+        // frames larger than `MAX_FRAME` are rejected
+        let large = vec![0; MAX_FRAME + 1];
+        for compression in [Compression::Zstd, Compression::Lz4, Compression::Deflate] {
+            let frame = compression.compress(&large, 0)?;
+            assert!(frame.len() < 1 << 20);
+            assert!(Compression::decompress(&frame).is_err());
+        }
+        let frame = Compression::Zstd.compress(&large[1..], 0)?;
+        assert_eq!(Compression::decompress(&frame)?.len(), MAX_FRAME);
+        Ok(())
+    }
+
+    #[test]
+    fn context_takeover_test() -> Result<(), CompressError> {
+        let (sender, receiver) = (DeflateContext::new(), DeflateContext::new());
+        let frames: Vec<_> = (0..3)
+            .map(|i| format!("player {i} moved to the north gate of the castle ").repeat(4))
+            .collect();
+
+        let mut sizes = Vec::new();
+        for frame in &frames {
+            let compressed = sender.compress(frame.as_bytes(), 16, true)?;
+            assert_eq!(compressed[0], FLAG_DEFLATE_TAKEOVER);
+            sizes.push(compressed.len());
+            assert_eq!(receiver.decompress(&compressed)?, frame.as_bytes());
+        }
+        // later frames refer to the earlier ones
+        assert!(sizes[1] < sizes[0] / 2);
+
+        // reset frames do not need the context
+        let compressed = sender.compress(frames[0].as_bytes(), 16, false)?;
+        assert_eq!(compressed[0], FLAG_DEFLATE);
+        assert!(compressed.len() > sizes[1]);
+        assert_eq!(Compression::decompress(&compressed)?, frames[0].as_bytes());
+        assert_eq!(receiver.decompress(&compressed)?, frames[0].as_bytes());
+
+        // frames with the context cannot be decompressed without it
+        let compressed = sender.compress(frames[1].as_bytes(), 16, true)?;
+        assert!(Compression::decompress(&compressed).is_err());
+        assert_eq!(receiver.decompress(&compressed)?, frames[1].as_bytes());
+
+        // small frames are not compressed, and do not touch the context
+        assert_eq!(sender.compress(b"hi", 16, true)?, [FLAG_NONE, b'h', b'i']);
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let enabled = [Compression::Zstd, Compression::Lz4];
+        assert_eq!(
+            Compression::negotiate(&enabled, &["lz4", "zstd"]),
+            Compression::Zstd
+        );
+        assert_eq!(Compression::negotiate(&enabled, &["lz4"]), Compression::Lz4);
+        assert_eq!(
+            Compression::negotiate(&enabled, &["brotli"]),
+            Compression::None
+        );
+        assert_eq!(Compression::from_name("lz4"), Some(Compression::Lz4));
+    }
+
+    async fn echo(req: Request<Vec<u8>>) -> Result<Vec<u8>, CompressError> {
+        Ok(req.msg)
+    }
+
+    #[tokio::test]
+    async fn layer_test() -> Result<(), CompressError> {
+        let handler = connect(CompressLayer::new(16), echo).await?;
+        let data = vec![7u8; 1000];
+
+        // peer does not support compression
+        assert_eq!(handler.call(Request::new(data.clone())).await?, data);
+
+        let mut req = Request::new(Compression::Lz4.compress(&data, 16)?);
+        req.context.insert(Compression::Lz4);
+        let frame = handler.call(req).await?;
+        assert_eq!(frame[0], FLAG_LZ4);
+        assert_eq!(Compression::decompress(&frame)?, data);
+
+        // context takeover is not used unless enabled
+        let mut req = Request::new(Compression::Deflate.compress(&data, 16)?);
+        req.context.insert(Compression::Deflate);
+        req.context.insert(DeflateContext::new());
+        let frame = handler.call(req).await?;
+        assert_eq!(frame[0], FLAG_DEFLATE);
+
+        let mut req = Request::new(Vec::new());
+        req.context.insert(Compression::Zstd);
+        assert!(handler.call(req).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deflate_layer_test() -> Result<(), CompressError> {
+        let data = b"snapshot ".repeat(100);
+        for takeover in [true, false] {
+            let peer = DeflateContext::new();
+            let handler = connect(CompressLayer::new(16).context_takeover(takeover), echo).await?;
+            let mut context = Context::new();
+            context.insert(Compression::Deflate);
+            context.insert(DeflateContext::new());
+
+            for _ in 0..2 {
+                let req = Request::with_context(peer.compress(&data, 16, true)?, context.clone());
+                let frame = handler.call(req).await?;
+                let flag = if takeover {
+                    FLAG_DEFLATE_TAKEOVER
+                } else {
+                    FLAG_DEFLATE
+                };
+                assert_eq!(frame[0], flag);
+                assert_eq!(peer.decompress(&frame)?, data);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+//! Length-prefixed framing
+//!
+//! Streams don't keep message boundaries, so every message is sent as a
+//! frame: the length of the message followed by the message itself.
+//! The length is a 4-byte big-endian integer by default, or a varint
+//! (like protobuf) with `Length::Varint`. Frames larger than `max_frame`
+//! are rejected without reading them.
+//!
+//! `FrameCodec` encodes frames into a buffer and decodes frames from a
+//! buffer, returning `None` until a whole frame is in the buffer.
+//! `FramedRead` reads frames from an `AsyncRead`, handling partial reads.
+//! Transports use the default codec.
+//!
+//! # Examples
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use cubby_connect_server_core::codec::framed::{FrameCodec, Length};
+//!
+//! let codec = FrameCodec::new().length(Length::Varint).max_frame(1024);
+//! let mut buf = BytesMut::new();
+//! codec.encode(b"hello", &mut buf).unwrap();
+//! assert_eq!(&buf[..], b"\x05hello");
+//!
+//! // only a part of the frame arrived
+//! let mut partial = BytesMut::from(&buf[..3]);
+//! assert_eq!(codec.decode(&mut partial).unwrap(), None);
+//! partial.extend_from_slice(&buf[3..]);
+//! assert_eq!(&codec.decode(&mut partial).unwrap().unwrap()[..], b"hello");
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// default largest frame
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// error of framing
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FrameError {
+    /// frame is larger than `max_frame`
+    TooLarge { len: u64, max: usize },
+
+    /// varint length is longer than 10 bytes
+    MalformedLength,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {len} bytes is larger than {max} bytes")
+            }
+            FrameError::MalformedLength => write!(f, "malformed frame length"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// encoding of frame length
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Length {
+    /// 4-byte big-endian integer
+    #[default]
+    U32,
+
+    /// unsigned varint of protobuf
+    Varint,
+}
+
+/// encoder/decoder of frames
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameCodec {
+    length: Length,
+    max_frame: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self {
+            length: Length::U32,
+            max_frame: MAX_FRAME,
+        }
+    }
+}
+
+impl FrameCodec {
+    /// returns the default codec (`Length::U32` and `MAX_FRAME`)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets encoding of frame length
+    pub fn length(mut self, length: Length) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// sets largest frame that is accepted
+    pub fn max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// appends `frame` with its length to `dst`
+    pub fn encode(&self, frame: &[u8], dst: &mut BytesMut) -> Result<(), FrameError> {
+        if frame.len() > self.max_frame || u32::try_from(frame.len()).is_err() {
+            return Err(FrameError::TooLarge {
+                len: frame.len() as u64,
+                max: self.max_frame,
+            });
+        }
+
+        match self.length {
+            Length::U32 => dst.put_u32(frame.len() as u32),
+            Length::Varint => {
+                let mut len = frame.len() as u64;
+                while len >= 0x80 {
+                    dst.put_u8((len as u8) | 0x80);
+                    len >>= 7;
+                }
+                dst.put_u8(len as u8);
+            }
+        }
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+
+    /// takes a frame from the front of `src`.
+    /// Returns `None` when `src` does not have a whole frame yet.
+    pub fn decode(&self, src: &mut BytesMut) -> Result<Option<Bytes>, FrameError> {
+        let (len, header) = match self.peek_length(src)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        if len > self.max_frame as u64 {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame,
+            });
+        }
+
+        let len = len as usize;
+        if src.len() < header + len {
+            src.reserve(header + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(header);
+        Ok(Some(src.split_to(len).freeze()))
+    }
+
+    /// returns length of the frame and the size of the length itself
+    fn peek_length(&self, src: &[u8]) -> Result<Option<(u64, usize)>, FrameError> {
+        match self.length {
+            Length::U32 => Ok(src
+                .get(..4)
+                .map(|b| (u64::from(u32::from_be_bytes([b[0], b[1], b[2], b[3]])), 4))),
+            Length::Varint => {
+                let mut len = 0u64;
+                for (i, b) in src.iter().enumerate().take(10) {
+                    len |= u64::from(b & 0x7f) << (7 * i);
+                    if b & 0x80 == 0 {
+                        return Ok(Some((len, i + 1)));
+                    }
+                }
+                if src.len() >= 10 {
+                    Err(FrameError::MalformedLength)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+/// reads frames from `R`
+pub struct FramedRead<R> {
+    reader: R,
+    codec: FrameCodec,
+    buf: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin> FramedRead<R> {
+    /// reads frames of `codec` from `reader`
+    pub fn new(reader: R, codec: FrameCodec) -> Self {
+        Self {
+            reader,
+            codec,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// reads the next frame.
+    /// Returns `None` when the stream is finished between frames.
+    pub async fn next(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream finished in the middle of frame",
+                    ))
+                };
+            }
+        }
+    }
+
+    /// returns the inner reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// reads a frame of the default codec without buffering more than the frame.
+/// Returns `None` when the stream is finished.
+pub async fn read_frame<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(FrameError::TooLarge {
+            len: len as u64,
+            max: MAX_FRAME,
+        }
+        .into());
+    }
+
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+/// writes `frame` with the default codec
+pub async fn write_frame<W>(writer: &mut W, frame: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(frame.len() + 4);
+    FrameCodec::default().encode(frame, &mut buf)?;
+    writer.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codec_test() {
+        for length in [Length::U32, Length::Varint] {
+            let codec = FrameCodec::new().length(length).max_frame(300);
+            let mut buf = BytesMut::new();
+            codec.encode(b"a", &mut buf).unwrap();
+            codec.encode(&[7; 200], &mut buf).unwrap();
+            codec.encode(b"", &mut buf).unwrap();
+            assert!(codec.encode(&[0; 301], &mut buf).is_err());
+
+            assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"a");
+            assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 200);
+            assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"");
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+
+        let codec = FrameCodec::new().max_frame(10);
+        let mut buf = BytesMut::from(&[0, 0, 1, 0][..]);
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(FrameError::TooLarge { len: 256, max: 10 })
+        );
+
+        let codec = FrameCodec::new().length(Length::Varint);
+        let mut buf = BytesMut::from(&[0xff; 10][..]);
+        assert_eq!(codec.decode(&mut buf), Err(FrameError::MalformedLength));
+    }
+
+    #[tokio::test]
+    async fn frame_test() -> io::Result<()> {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        write_frame(&mut a, b"hello").await?;
+        write_frame(&mut a, b"").await?;
+        drop(a);
+
+        assert_eq!(read_frame(&mut b).await?, Some(b"hello".to_vec()));
+        assert_eq!(read_frame(&mut b).await?, Some(Vec::new()));
+        assert_eq!(read_frame(&mut b).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn framed_read_test() -> io::Result<()> {
+        let (mut a, b) = tokio::io::duplex(4);
+        let writer = tokio::spawn(async move {
+            // frames are split into small pieces by the duplex buffer
+            write_frame(&mut a, b"hello world").await?;
+            write_frame(&mut a, b"!").await?;
+            a.write_all(&[0, 0]).await
+        });
+
+        let mut framed = FramedRead::new(b, FrameCodec::new());
+        assert_eq!(&framed.next().await?.unwrap()[..], b"hello world");
+        assert_eq!(&framed.next().await?.unwrap()[..], b"!");
+        writer.await.unwrap()?;
+        assert_eq!(
+            framed.next().await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! JSON codec with `serde_json`
+//!
+//! JSON is easy to read and to write by hand, so it is handy for prototyping
+//! and debugging. Any message implementing `Serialize` and `Deserialize` can
+//! be sent as JSON. Messages generated by `prost` get them with
+//! `type_attribute` of `prost_build::Config`.
+//!
+//! This module needs the `json` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::json::Json;
+//! use cubby_connect_server_core::codec::{Codecs, Protobuf};
+//! use cubby_connect_server_core::config::ConfigBuilder;
+//!
+//! let config = ConfigBuilder::default()
+//!     .codecs(vec!["json".to_string()])
+//!     .build()
+//!     .unwrap();
+//!
+//! // only the codecs in `Config::codecs` are enabled
+//! let codecs = Codecs::<String>::new()
+//!     .with(Protobuf)
+//!     .with(Json)
+//!     .enabled(&config.codecs);
+//! assert_eq!(codecs.names(), vec!["json"]);
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::{Codec, CodecError};
+
+/// JSON codec of `serde` messages
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Json;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, msg: &M) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(msg).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        serde_json::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::codec::{CodecLayer, Codecs, Negotiated, Protobuf};
+    use crate::context::{Context, Request};
+    use crate::handler::Handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode_test() {
+        let msg = HashMap::from([("hp".to_string(), 10)]);
+        let bytes = Json.encode(&msg).unwrap();
+        assert_eq!(bytes, br#"{"hp":10}"#);
+        assert_eq!(Json.decode(&bytes), Ok(msg));
+
+        assert!(matches!(
+            Codec::<HashMap<String, i32>>::decode(&Json, b"{"),
+            Err(CodecError::Decode(_))
+        ));
+    }
+
+    async fn echo(req: Request<String>) -> Result<String, CodecError> {
+        Ok(req.msg)
+    }
+
+    #[tokio::test]
+    async fn switch_test() -> Result<(), CodecError> {
+        let codecs = Codecs::new().with(Protobuf).with(Json);
+        let handler = connect(CodecLayer::new(codecs.clone()), echo).await?;
+
+        let mut context = Context::new();
+        context.insert(Negotiated("json"));
+        let req = Request::with_context(br#""hi""#.to_vec(), context);
+        assert_eq!(handler.call(req).await?, "hi");
+
+        // json is used without negotiation when it is the only one enabled
+        let handler = connect(CodecLayer::new(codecs.enabled(&["json"])), echo).await?;
+        assert_eq!(handler.call(Request::new(br#""hi""#.to_vec())).await?, "hi");
+        Ok(())
+    }
+}
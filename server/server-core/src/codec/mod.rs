@@ -0,0 +1,343 @@
+//! Codecs of payloads
+//!
+//! Payloads can be encoded in different formats. Every format implements
+//! `Codec<M>` for the message types it can encode. The server enables a set
+//! of codecs as `Codecs<M>`, and one of them is negotiated for each
+//! connection during the handshake (see `handshake`).
+//!
+//! `CodecLayer` decodes raw payloads with the codec negotiated for the
+//! connection, which is found as `Negotiated` in the context of the request.
+//!
+//! - `cbor`: CBOR codec (needs the `cbor` feature)
+//! - `compress`: compression of frames with zstd or lz4
+//! - `framed`: length-prefixed frames that carry payloads in streams
+//! - `json`: JSON codec (needs the `json` feature)
+//! - `msgpack`: MessagePack codec (needs the `msgpack` feature)
+//! - `proto`: layer that decodes protobuf messages and encodes responses
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::{CodecError, CodecLayer, Codecs, Negotiated, Protobuf};
+//! use cubby_connect_server_core::context::Request;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//! use prost::Message;
+//!
+//! async fn hello(req: Request<String>) -> Result<(), CodecError> {
+//!     assert_eq!(req.msg, "hello");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CodecError> {
+//! let codecs = Codecs::new().with(Protobuf);
+//! assert_eq!(codecs.negotiate(&["json", "protobuf"]), Some("protobuf"));
+//!
+//! let handler = apply!(CodecLayer::new(codecs) to hello);
+//! let mut req = Request::new("hello".to_string().encode_to_vec());
+//! req.context.insert(Negotiated("protobuf"));
+//! handler.call(req).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{err, ok, Either, Ready};
+
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod compress;
+pub mod framed;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod proto;
+
+/// error when encoding or decoding payloads
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CodecError {
+    /// message cannot be encoded
+    Encode(String),
+
+    /// payload cannot be decoded
+    Decode(String),
+
+    /// codec is not enabled
+    Unsupported(String),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Encode(e) => write!(f, "failed to encode: {e}"),
+            CodecError::Decode(e) => write!(f, "failed to decode: {e}"),
+            CodecError::Unsupported(name) => write!(f, "codec `{name}` is not enabled"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// format of payloads of `M`
+pub trait Codec<M> {
+    /// name that is used in negotiation
+    fn name(&self) -> &'static str;
+
+    /// encodes `msg` into bytes
+    fn encode(&self, msg: &M) -> Result<Vec<u8>, CodecError>;
+
+    /// decodes bytes into a message
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError>;
+}
+
+/// protobuf codec of `prost` messages
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Protobuf;
+
+impl<M: prost::Message + Default> Codec<M> for Protobuf {
+    fn name(&self) -> &'static str {
+        "protobuf"
+    }
+
+    fn encode(&self, msg: &M) -> Result<Vec<u8>, CodecError> {
+        Ok(msg.encode_to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        M::decode(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+/// codec shared by connections
+pub type DynCodec<M> = Arc<dyn Codec<M> + Send + Sync>;
+
+/// codecs that are enabled, in order of preference
+pub struct Codecs<M> {
+    codecs: Vec<DynCodec<M>>,
+}
+
+impl<M> Clone for Codecs<M> {
+    fn clone(&self) -> Self {
+        Self {
+            codecs: self.codecs.clone(),
+        }
+    }
+}
+
+impl<M> Default for Codecs<M> {
+    fn default() -> Self {
+        Self { codecs: Vec::new() }
+    }
+}
+
+impl<M> Codecs<M> {
+    /// returns an empty set of codecs
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// enables `codec` with lower preference than the ones before
+    pub fn with<C>(mut self, codec: C) -> Self
+    where
+        C: Codec<M> + Send + Sync + 'static,
+    {
+        self.codecs.push(Arc::new(codec));
+        self
+    }
+
+    /// keeps only codecs in `names` (e.g. `Config::codecs`), in that order
+    pub fn enabled<S: AsRef<str>>(self, names: &[S]) -> Self {
+        let codecs = names
+            .iter()
+            .filter_map(|name| self.get(name.as_ref()))
+            .collect();
+        Self { codecs }
+    }
+
+    /// returns the codec of `name`
+    pub fn get(&self, name: &str) -> Option<DynCodec<M>> {
+        self.codecs.iter().find(|c| c.name() == name).cloned()
+    }
+
+    /// returns names of codecs in order of preference
+    pub fn names(&self) -> Vec<&'static str> {
+        self.codecs.iter().map(|c| c.name()).collect()
+    }
+
+    /// chooses the most preferred codec (by the server) that is `offered`
+    pub fn negotiate<S: AsRef<str>>(&self, offered: &[S]) -> Option<&'static str> {
+        self.codecs
+            .iter()
+            .map(|c| c.name())
+            .find(|name| offered.iter().any(|o| o.as_ref() == *name))
+    }
+}
+
+/// codec negotiated for the connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Negotiated(pub &'static str);
+
+impl FromContext for Negotiated {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Negotiated>().copied()
+    }
+}
+
+/// `Layer` that builds `CodecHandler`
+pub struct CodecLayer<M> {
+    codecs: Codecs<M>,
+}
+
+impl<M> CodecLayer<M> {
+    /// decodes payloads with one of `codecs`
+    pub fn new(codecs: Codecs<M>) -> Self {
+        Self { codecs }
+    }
+}
+
+impl<M, H> Layer<Request<Vec<u8>>, H> for CodecLayer<M>
+where
+    H: Handler<Request<M>>,
+    H::Error: From<CodecError>,
+{
+    type Next = Request<M>;
+    type Error = H::Error;
+    type Handler = CodecHandler<M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(CodecHandler {
+            codecs: self.codecs.clone(),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that decodes payloads with the negotiated codec.
+/// The most preferred codec is used when nothing is negotiated.
+pub struct CodecHandler<M, H> {
+    codecs: Codecs<M>,
+    prev: H,
+    _marker: PhantomData<fn(M)>,
+}
+
+impl<M, H> CodecHandler<M, H> {
+    fn codec(&self, context: &Context) -> Result<DynCodec<M>, CodecError> {
+        match Negotiated::from_context(context) {
+            Some(Negotiated(name)) => self
+                .codecs
+                .get(name)
+                .ok_or_else(|| CodecError::Unsupported(name.to_string())),
+            None => self
+                .codecs
+                .codecs
+                .first()
+                .cloned()
+                .ok_or_else(|| CodecError::Unsupported(String::new())),
+        }
+    }
+}
+
+impl<M, H> Handler<Request<Vec<u8>>> for CodecHandler<M, H>
+where
+    H: Handler<Request<M>>,
+    H::Error: From<CodecError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, req: Request<Vec<u8>>) -> Self::Future {
+        let decoded = self
+            .codec(&req.context)
+            .and_then(|codec| codec.decode(&req.msg));
+
+        match decoded {
+            Ok(msg) => Either::Left(self.prev.call(Request::with_context(msg, req.context))),
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    /// codec for test that reads bytes as utf-8
+    struct Text;
+
+    impl Codec<String> for Text {
+        fn name(&self) -> &'static str {
+            "text"
+        }
+
+        fn encode(&self, msg: &String) -> Result<Vec<u8>, CodecError> {
+            Ok(msg.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, CodecError> {
+            String::from_utf8(bytes.to_vec()).map_err(|e| CodecError::Decode(e.to_string()))
+        }
+    }
+
+    #[test]
+    fn negotiate_test() {
+        let codecs = Codecs::<String>::new().with(Protobuf).with(Text);
+        assert_eq!(codecs.names(), vec!["protobuf", "text"]);
+        assert_eq!(codecs.negotiate(&["text", "protobuf"]), Some("protobuf"));
+        assert_eq!(codecs.negotiate(&["text"]), Some("text"));
+        assert_eq!(codecs.negotiate(&["json"]), None);
+
+        let codecs = codecs.enabled(&["text".to_string(), "json".to_string()]);
+        assert_eq!(codecs.names(), vec!["text"]);
+    }
+
+    async fn echo(req: Request<String>) -> Result<String, CodecError> {
+        Ok(req.msg)
+    }
+
+    #[tokio::test]
+    async fn codec_layer_test() -> Result<(), CodecError> {
+        let codecs = Codecs::new().with(Protobuf).with(Text);
+        let handler = connect(CodecLayer::new(codecs), echo).await?;
+
+        // protobuf is used without negotiation
+        let bytes = "hi".to_string().encode_to_vec();
+        assert_eq!(handler.call(Request::new(bytes)).await?, "hi");
+
+        let mut context = Context::new();
+        context.insert(Negotiated("text"));
+        let req = Request::with_context(b"hi".to_vec(), context.clone());
+        assert_eq!(handler.call(req).await?, "hi");
+
+        let req = Request::with_context(vec![0xff], context);
+        assert!(matches!(
+            handler.call(req).await,
+            Err(CodecError::Decode(_))
+        ));
+
+        let mut context = Context::new();
+        context.insert(Negotiated("json"));
+        let req = Request::with_context(Vec::new(), context);
+        assert_eq!(
+            handler.call(req).await,
+            Err(CodecError::Unsupported("json".to_string()))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! MessagePack codec with `rmp-serde`
+//!
+//! Structs are encoded as maps with field names, so that clients in other
+//! languages can read them without knowing the order of fields.
+//! Payloads are carried in the same frames as other codecs (see `framed`).
+//!
+//! This module needs the `msgpack` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::msgpack::MessagePack;
+//! use cubby_connect_server_core::codec::Codec;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct Move {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! let bytes = MessagePack.encode(&Move { x: 1, y: 2 }).unwrap();
+//! let msg: Move = MessagePack.decode(&bytes).unwrap();
+//! assert_eq!(msg, Move { x: 1, y: 2 });
+//! ```
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::{Codec, CodecError};
+
+/// MessagePack codec of `serde` messages
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MessagePack;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for MessagePack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, msg: &M) -> Result<Vec<u8>, CodecError> {
+        rmp_serde::to_vec_named(msg).map_err(|e| CodecError::Encode(e.to_string()))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<M, CodecError> {
+        rmp_serde::from_slice(bytes).map_err(|e| CodecError::Decode(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use serde::Deserialize;
+
+    use crate::codec::framed::FrameCodec;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chat {
+        from: String,
+        text: String,
+    }
+
+    #[test]
+    fn encode_decode_test() {
+        let msg = Chat {
+            from: "cubby".to_string(),
+            text: "hi".to_string(),
+        };
+        let bytes = MessagePack.encode(&msg).unwrap();
+        // map of 2 entries with field names
+        assert_eq!(bytes[0], 0x82);
+        assert_eq!(MessagePack.decode(&bytes), Ok(msg));
+
+        assert!(matches!(
+            Codec::<Chat>::decode(&MessagePack, &[0xc1]),
+            Err(CodecError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn framed_test() {
+        let frames = FrameCodec::new();
+        let mut buf = BytesMut::new();
+        for text in ["a", "b"] {
+            let msg = Chat {
+                from: "cubby".to_string(),
+                text: text.to_string(),
+            };
+            frames
+                .encode(&MessagePack.encode(&msg).unwrap(), &mut buf)
+                .unwrap();
+        }
+
+        for text in ["a", "b"] {
+            let frame = frames.decode(&mut buf).unwrap().unwrap();
+            let msg: Chat = MessagePack.decode(&frame).unwrap();
+            assert_eq!(msg.text, text);
+        }
+        assert!(buf.is_empty());
+    }
+}
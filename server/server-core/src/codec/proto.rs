@@ -0,0 +1,139 @@
+//! Protobuf decode/encode layer
+//!
+//! `ProtoDecodeLayer<M>` is put at the head of a chain. It decodes raw
+//! `Bytes` from the transport into the protobuf message `M` before the next
+//! handler, and encodes the output of the chain back into `Bytes` on the
+//! response path. Decode errors are returned as the error of the chain, so
+//! it should implement `From<prost::DecodeError>`.
+//!
+//! # Examples
+//!
+//! ```
+//! use bytes::Bytes;
+//! use cubby_connect_server_core::codec::proto::ProtoDecodeLayer;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//! use prost::{DecodeError, Message};
+//!
+//! async fn greet(name: String) -> Result<String, DecodeError> {
+//!     Ok(format!("hello {name}"))
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), DecodeError> {
+//! let handler = apply!(ProtoDecodeLayer::<String>::new() to greet);
+//!
+//! let response = handler.call(Bytes::from("world".to_string().encode_to_vec())).await?;
+//! assert_eq!(String::decode(response)?, "hello world");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+
+use bytes::Bytes;
+use futures::future::{err, ok, Either, MapOk, Ready};
+use futures::TryFutureExt;
+use prost::{DecodeError, Message};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `ProtoDecodeHandler`
+pub struct ProtoDecodeLayer<M> {
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<M> Default for ProtoDecodeLayer<M> {
+    fn default() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<M> ProtoDecodeLayer<M> {
+    /// decodes payloads into `M`
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<M, H> Layer<Bytes, H> for ProtoDecodeLayer<M>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Output: Message,
+    H::Error: From<DecodeError>,
+{
+    type Next = M;
+    type Error = H::Error;
+    type Handler = ProtoDecodeHandler<M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ProtoDecodeHandler {
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that decodes `Bytes` into `M` and encodes the output
+pub struct ProtoDecodeHandler<M, H> {
+    prev: H,
+    _marker: PhantomData<fn(M)>,
+}
+
+fn encode<O: Message>(output: O) -> Bytes {
+    Bytes::from(output.encode_to_vec())
+}
+
+impl<M, H> Handler<Bytes> for ProtoDecodeHandler<M, H>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Output: Message,
+    H::Error: From<DecodeError>,
+{
+    type Output = Bytes;
+    type Error = H::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<MapOk<H::Future, fn(H::Output) -> Bytes>, Ready<Result<Bytes, H::Error>>>;
+
+    fn call(&self, bytes: Bytes) -> Self::Future {
+        match M::decode(bytes) {
+            Ok(msg) => Either::Left(self.prev.call(msg).map_ok(encode as fn(_) -> _)),
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn len(s: String) -> Result<u32, DecodeError> {
+        Ok(s.len() as u32)
+    }
+
+    async fn nothing(_: String) -> Result<(), DecodeError> {
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decode_test() -> Result<(), DecodeError> {
+        let handler = connect(ProtoDecodeLayer::new(), len).await?;
+        let bytes = Bytes::from("hello".to_string().encode_to_vec());
+        assert_eq!(u32::decode(handler.call(bytes).await?)?, 5);
+        assert!(handler.call(Bytes::from_static(&[0xff])).await.is_err());
+
+        // `()` is encoded into empty bytes
+        let handler = connect(ProtoDecodeLayer::new(), nothing).await?;
+        assert!(handler.call(Bytes::new()).await?.is_empty());
+        Ok(())
+    }
+}
@@ -17,6 +17,7 @@
 //! ```
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[cfg(feature = "serial")]
 use serde::{Deserialize, Serialize};
@@ -50,6 +51,31 @@ pub struct AuthServer {
     /// password to login to auth server
     #[builder(default = "String::from(\"cubby-auth\")", setter(into))]
     pub password: String,
+
+    /// whether to connect to auth server with TLS, which protects the
+    /// password of this server and the credentials of clients.
+    /// Turn it off only for an auth server on the same host.
+    #[builder(default = "true")]
+    pub tls: bool,
+
+    /// PEM file of certificates that the certificate of auth server is
+    /// signed with, which `tls` needs
+    #[builder(default = "None", setter(strip_option, into))]
+    pub ca_path: Option<PathBuf>,
+
+    /// name of auth server in its certificate
+    /// if this value is `None`, `host` is used
+    #[builder(default = "None", setter(strip_option, into))]
+    pub server_name: Option<String>,
+
+    /// time to wait for a request to auth server, including connecting
+    /// and logging in
+    #[builder(default = "Duration::from_secs(5)")]
+    pub timeout: Duration,
+
+    /// maximum number of verified credentials that are cached
+    #[builder(default = "10_000")]
+    pub cache_size: usize,
 }
 
 impl AuthServer {
@@ -59,6 +85,110 @@ impl AuthServer {
     }
 }
 
+/// how datagrams and connections are spread over sockets bound to the same
+/// port with `SO_REUSEPORT`
+#[cfg_attr(not(feature = "serial"), derive(Clone, Copy, Debug, Eq, PartialEq))]
+#[cfg_attr(
+    feature = "serial",
+    derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)
+)]
+pub enum Steering {
+    /// hash of the addresses and ports of both ends, which is the default
+    /// of the OS. It changes for the same peer when sockets are added or
+    /// removed.
+    Hash,
+
+    /// the socket of the CPU that received the packet
+    /// (`SO_ATTACH_REUSEPORT_CBPF`). With flows steered to CPUs by RSS or an
+    /// XDP program, packets of a connection land on the same socket.
+    Cpu,
+}
+
+/// options of listening sockets of TCP, UDP and QUIC
+#[cfg_attr(not(feature = "serial"), derive(Builder, Clone, Debug, Eq, PartialEq))]
+#[cfg_attr(
+    feature = "serial",
+    derive(Builder, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)
+)]
+#[cfg_attr(not(feature = "serial"), builder(derive(Debug, Eq, PartialEq)))]
+#[cfg_attr(
+    feature = "serial",
+    builder(derive(Debug, Eq, PartialEq, Serialize, Deserialize))
+)]
+pub struct SocketOptions {
+    /// sets `SO_REUSEPORT`, so that other sockets (e.g. other processes)
+    /// can bind the same port. Only supported on Unix.
+    #[builder(default = "false")]
+    pub reuse_port: bool,
+
+    /// number of sockets bound to each port of TCP and UDP, each with its
+    /// own accepting task. With more than one, `SO_REUSEPORT` is set and the
+    /// OS spreads connections and datagrams over them, which removes the
+    /// bottleneck of a single acceptor. `0` binds one for each CPU core
+    #[builder(default = "1")]
+    pub acceptors: usize,
+
+    /// how the OS picks one of the `acceptors` sockets for a datagram or a
+    /// connection. Anything but `Steering::Hash` is only supported on Linux.
+    /// QUIC binds a single socket, so it is not steered
+    #[builder(default = "Steering::Hash")]
+    pub steering: Steering,
+
+    /// largest number of TCP connections waiting to be accepted
+    #[builder(default = "1024")]
+    pub backlog: u32,
+
+    /// sets `TCP_NODELAY` on accepted TCP connections, so small messages
+    /// are sent without delay
+    #[builder(default = "true")]
+    pub nodelay: bool,
+
+    /// size of the receive buffer (`SO_RCVBUF`) in bytes.
+    /// if this value is `None`, the default of the OS is used
+    #[builder(default = "None", setter(strip_option))]
+    pub recv_buffer_size: Option<usize>,
+
+    /// size of the send buffer (`SO_SNDBUF`) in bytes.
+    /// if this value is `None`, the default of the OS is used
+    #[builder(default = "None", setter(strip_option))]
+    pub send_buffer_size: Option<usize>,
+
+    /// type of service of sent packets (`IP_TOS`, or `IPV6_TCLASS` for
+    /// IPv6), which is the DSCP shifted left by 2, e.g. `0xb8` for
+    /// expedited forwarding.
+    /// if this value is `None`, packets are not marked
+    #[builder(default = "None", setter(strip_option))]
+    pub tos: Option<u32>,
+}
+
+impl SocketOptions {
+    /// returns default builder of `SocketOptions`
+    pub fn builder() -> SocketOptionsBuilder {
+        SocketOptionsBuilder::default()
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self::builder().build().unwrap()
+    }
+}
+
+/// whether clients must present a certificate with mutual TLS
+#[cfg_attr(not(feature = "serial"), derive(Clone, Copy, Debug, Eq, PartialEq))]
+#[cfg_attr(
+    feature = "serial",
+    derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)
+)]
+pub enum ClientAuth {
+    /// clients without a certificate are accepted too, but a certificate
+    /// that is presented must be valid
+    Optional,
+
+    /// connections without a valid certificate are rejected
+    Required,
+}
+
 /// configuration for connection
 #[cfg_attr(not(feature = "serial"), derive(Builder, Clone, Debug, Eq, PartialEq))]
 #[cfg_attr(
@@ -79,10 +209,87 @@ pub struct Config {
     #[builder(default = "20202")]
     pub quic_port: u16,
 
+    /// port to bind tcp connection.
+    /// It is used as a fallback when UDP is blocked.
+    #[builder(default = "20202")]
+    pub tcp_port: u16,
+
+    /// port to bind udp datagrams.
+    /// Messages on this port are unreliable and unordered.
+    #[builder(default = "20204")]
+    pub udp_port: u16,
+
+    /// options of sockets of `quic_port`, `tcp_port` and `udp_port`
+    #[builder(default = "SocketOptions::default()")]
+    pub socket_options: SocketOptions,
+
+    /// path of unix domain socket (name of named pipe on Windows)
+    /// for processes on the same host.
+    /// if this value is `None`, there is no local connection
+    #[builder(default = "None", setter(strip_option, into))]
+    pub local_path: Option<PathBuf>,
+
     /// directory of protobuf files for connection
     #[builder(default = "PathBuf::from(\"./protobuf\")", setter(into))]
     pub protobuf_dir: PathBuf,
 
+    /// whether every connection starts with the handshake (see
+    /// `handshake`). Without it, nothing is negotiated nor checked, and
+    /// frames are served from the start
+    #[builder(default = "true")]
+    pub handshake: bool,
+
+    /// time that a connection has for the TLS handshake and the handshake
+    /// of `handshake`, after which it is closed
+    #[builder(default = "Duration::from_secs(10)")]
+    pub handshake_timeout: Duration,
+
+    /// codecs of payloads that are enabled, in order of preference.
+    /// A codec is negotiated from these for each connection.
+    #[builder(default = "vec![String::from(\"protobuf\")]")]
+    pub codecs: Vec<String>,
+
+    /// compressions of frames that are enabled (like `zstd`), in order of
+    /// preference. A compression is negotiated from these for each
+    /// connection; without one, frames are not compressed
+    #[builder(default = "Vec::new()")]
+    pub compressions: Vec<String>,
+
+    /// frames smaller than this are not compressed
+    #[builder(default = "1024")]
+    pub compression_threshold: usize,
+
+    /// whether deflate retains its context across frames sent to a
+    /// connection that negotiated it (see `codec::compress`). Retaining
+    /// compresses better, but keeps a deflate stream for each connection
+    #[builder(default = "false")]
+    pub context_takeover: bool,
+
+    /// names of middleware layers of the pipeline, outermost first.
+    /// They are looked up in the registry of `pipeline::PipelineBuilder`.
+    #[builder(default = "Vec::new()")]
+    pub layers: Vec<String>,
+
+    /// clients below this version are not allowed to connect.
+    /// if this value is `None`, there is no minimum
+    #[builder(default = "None", setter(strip_option, into))]
+    pub min_client_version: Option<String>,
+
+    /// `soft_block_percent` percent of clients below this version are not
+    /// allowed to connect, to deprecate the version in stages
+    #[builder(default = "None", setter(strip_option, into))]
+    pub soft_min_client_version: Option<String>,
+
+    /// percentage (0 to 100) of clients below `soft_min_client_version`
+    /// that are blocked
+    #[builder(default = "0")]
+    pub soft_block_percent: u8,
+
+    /// semver requirement of client versions, like `^0.4` or `>=0.3, <0.5`.
+    /// if this value is `None`, every version is compatible
+    #[builder(default = "None", setter(strip_option, into))]
+    pub client_version_req: Option<String>,
+
     /// key file of tls connection
     /// if this value is `None`, there is no tls connection
     #[builder(default = "None", setter(strip_option, into))]
@@ -93,6 +300,38 @@ pub struct Config {
     #[builder(default = "None", setter(strip_option, into))]
     pub cert_path: Option<PathBuf>,
 
+    /// CA bundle (PEM) that client certificates are verified with.
+    /// if this value is `None`, clients are not asked for certificates
+    #[builder(default = "None", setter(strip_option, into))]
+    pub client_ca_path: Option<PathBuf>,
+
+    /// whether a client certificate is required when `client_ca_path` is set
+    #[builder(default = "ClientAuth::Required")]
+    pub client_auth: ClientAuth,
+
+    /// time between pings of `heartbeat`
+    #[builder(default = "Duration::from_secs(15)")]
+    pub heartbeat_interval: Duration,
+
+    /// time to wait for the pong of a ping
+    #[builder(default = "Duration::from_secs(5)")]
+    pub heartbeat_timeout: Duration,
+
+    /// number of pongs in a row that can be missed before the peer is
+    /// considered dead
+    #[builder(default = "3")]
+    pub heartbeat_misses: u32,
+
+    /// time that a session is kept after its connection closes, so that
+    /// the client can resume it (see `session`)
+    #[builder(default = "Duration::from_secs(10 * 60)")]
+    pub session_ttl: Duration,
+
+    /// largest number of messages kept for a session while its client is
+    /// away. The oldest are dropped first
+    #[builder(default = "256")]
+    pub session_max_pending: usize,
+
     /// auth server configuration
     #[builder(default = "AuthServer::builder().build().unwrap()")]
     pub auth_config: AuthServer,
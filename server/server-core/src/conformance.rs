@@ -0,0 +1,263 @@
+//! Conformance checks of a server over the wire
+//!
+//! Clients of other languages (C++, TypeScript, ...) implement the protocol
+//! on their own, and servers can be deployed with other implementations too.
+//! `run` connects to a server at an address over tcp and runs a scripted
+//! battery of protocol checks, so any implementation can be checked against
+//! the behaviour of this crate:
+//!
+//! - `handshake`: `ClientHello` is answered with a codec
+//! - `codec mismatch`: an unknown codec is refused with the supported codecs
+//! - `malformed hello`: the connection is closed after a broken hello
+//! - `oversized frame`: the connection is closed after a frame larger than
+//!   `MAX_FRAME`, without waiting for its bytes
+//! - `reconnect`: the same client can connect again after disconnecting
+//! - `version mismatch`, `heartbeat miss`: reported as warnings, since the
+//!   protocol has no versions or heartbeats yet
+//!
+//! The `conformance` binary of the server crate runs it from the command
+//! line.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::conformance::run;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let report = run("127.0.0.1:7000".parse().unwrap(), &["protobuf"]).await;
+//! print!("{report}");
+//! assert!(report.is_ok());
+//! # }
+//! ```
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::check::{Item, Report, Status};
+use crate::codec::compress::Compression;
+use crate::handshake::{connect_with, HandshakeError};
+use crate::transport::{write_frame, MAX_FRAME};
+
+/// time to wait for each step of a check
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// codec that no implementation should support
+const UNKNOWN_CODEC: &str = "cubby-conformance-unknown";
+
+/// number of connections of `reconnect`
+const RECONNECTS: usize = 3;
+
+/// runs every check against the server at `addr`.
+/// `codecs` are offered in the handshake in order of preference.
+pub async fn run<C: AsRef<str>>(addr: SocketAddr, codecs: &[C]) -> Report {
+    Report {
+        items: vec![
+            check_handshake(addr, codecs).await,
+            check_codec_mismatch(addr).await,
+            check_malformed_hello(addr).await,
+            check_oversized_frame(addr, codecs).await,
+            check_reconnect(addr, codecs).await,
+            skipped("version mismatch", "the handshake has no protocol version"),
+            skipped("heartbeat miss", "the protocol has no heartbeat"),
+        ],
+    }
+}
+
+fn skipped(name: &'static str, reason: &str) -> Item {
+    let mut item = Item::new(name);
+    item.add(Status::Warn, format!("skipped: {reason}"));
+    item
+}
+
+async fn open(addr: SocketAddr) -> io::Result<TcpStream> {
+    timeout(STEP_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))?
+}
+
+/// connects and runs the handshake
+async fn handshake<C: AsRef<str>>(
+    addr: SocketAddr,
+    codecs: &[C],
+) -> Result<(TcpStream, String, Compression), HandshakeError> {
+    let mut stream = open(addr).await?;
+    let compressions = [Compression::Zstd, Compression::Lz4];
+    let (codec, compression) = timeout(
+        STEP_TIMEOUT,
+        connect_with(&mut stream, codecs, &compressions),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no ServerHello in time"))??;
+    Ok((stream, codec, compression))
+}
+
+/// returns true if the peer closes `stream` in time.
+/// Anything the peer sends before closing is ignored.
+async fn closes(stream: &mut TcpStream) -> bool {
+    let mut buf = [0u8; 1024];
+    let read_to_end = async {
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    };
+    timeout(STEP_TIMEOUT, read_to_end).await.is_ok()
+}
+
+async fn check_handshake<C: AsRef<str>>(addr: SocketAddr, codecs: &[C]) -> Item {
+    let mut item = Item::new("handshake");
+    match handshake(addr, codecs).await {
+        Ok((_, codec, compression)) => item.add(
+            Status::Ok,
+            format!("codec {codec}, compression {}", compression.name()),
+        ),
+        Err(e) => item.add(Status::Fail, e.to_string()),
+    }
+    item
+}
+
+async fn check_codec_mismatch(addr: SocketAddr) -> Item {
+    let mut item = Item::new("codec mismatch");
+    match handshake(addr, &[UNKNOWN_CODEC]).await {
+        Err(HandshakeError::NoCommonCodec(supported)) if supported.is_empty() => {
+            item.add(Status::Fail, "refused without the supported codecs")
+        }
+        Err(HandshakeError::NoCommonCodec(supported)) => item.add(
+            Status::Ok,
+            format!("refused, server supports {}", supported.join(", ")),
+        ),
+        Err(e) => item.add(Status::Fail, e.to_string()),
+        Ok((_, codec, _)) => item.add(
+            Status::Fail,
+            format!("codec {codec} was chosen for `{UNKNOWN_CODEC}`"),
+        ),
+    }
+    item
+}
+
+async fn check_malformed_hello(addr: SocketAddr) -> Item {
+    let mut item = Item::new("malformed hello");
+    let mut stream = match open(addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            item.add(Status::Fail, e.to_string());
+            return item;
+        }
+    };
+
+    // a truncated varint is not a valid protobuf message
+    if let Err(e) = write_frame(&mut stream, &[0xff, 0xff, 0xff]).await {
+        item.add(Status::Fail, e.to_string());
+    } else if closes(&mut stream).await {
+        item.add(Status::Ok, "connection closed");
+    } else {
+        item.add(Status::Fail, "connection is still open");
+    }
+    item
+}
+
+async fn check_oversized_frame<C: AsRef<str>>(addr: SocketAddr, codecs: &[C]) -> Item {
+    let mut item = Item::new("oversized frame");
+    let mut stream = match handshake(addr, codecs).await {
+        Ok((stream, _, _)) => stream,
+        Err(e) => {
+            item.add(Status::Fail, format!("handshake failed: {e}"));
+            return item;
+        }
+    };
+
+    // only the length is sent, so a server reading the whole frame waits
+    let len = (MAX_FRAME as u32 + 1).to_be_bytes();
+    if let Err(e) = stream.write_all(&len).await {
+        item.add(Status::Fail, e.to_string());
+    } else if closes(&mut stream).await {
+        item.add(
+            Status::Ok,
+            format!("connection closed (max {MAX_FRAME} bytes)"),
+        );
+    } else {
+        item.add(Status::Fail, "connection is still open");
+    }
+    item
+}
+
+async fn check_reconnect<C: AsRef<str>>(addr: SocketAddr, codecs: &[C]) -> Item {
+    let mut item = Item::new("reconnect");
+    for i in 1..=RECONNECTS {
+        if let Err(e) = handshake(addr, codecs).await {
+            item.add(Status::Fail, format!("connection {i}: {e}"));
+            return item;
+        }
+    }
+    item.add(Status::Ok, format!("{RECONNECTS} connections in a row"));
+    item
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use crate::codec::{Codecs, Protobuf};
+    use crate::handshake::accept_with;
+    use crate::transport::read_frame;
+
+    use super::*;
+
+    /// serves handshakes and frames like the transports of this crate
+    async fn serve() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let codecs = Codecs::<String>::new().with(Protobuf);
+                    if accept_with(&mut stream, &codecs, &[Compression::Lz4])
+                        .await
+                        .is_ok()
+                    {
+                        while let Ok(Some(_)) = read_frame(&mut stream).await {}
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn conformance_test() {
+        let addr = serve().await;
+        let report = run(addr, &["json", "protobuf"]).await;
+        assert!(report.is_ok(), "{report}");
+
+        let handshake = report.get("handshake").unwrap();
+        assert_eq!(handshake.details, vec!["codec protobuf, compression lz4"]);
+        for name in [
+            "codec mismatch",
+            "malformed hello",
+            "oversized frame",
+            "reconnect",
+        ] {
+            assert_eq!(report.get(name).unwrap().status, Status::Ok, "{report}");
+        }
+        assert_eq!(report.get("heartbeat miss").unwrap().status, Status::Warn);
+    }
+
+    #[tokio::test]
+    async fn unreachable_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let report = run(addr, &["protobuf"]).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.get("handshake").unwrap().status, Status::Fail);
+    }
+}
@@ -0,0 +1,429 @@
+//! Registry of open connections with tags
+//!
+//! Handlers put tags on their connection (e.g. `region:eu`, `beta`), and
+//! the server pushes a message to every connection with a tag by
+//! `Connections::broadcast_to`, without a topic for each cohort. Tags live
+//! as long as the connection.
+//!
+//! Each connection task registers itself with `Connections::register`, puts
+//! `Registration::connection` into the context of its requests, and sends
+//! what it receives from the returned receiver to the peer. Dropping the
+//! `Registration` removes the connection with its tags.
+//!
+//! Messages can also be pushed later with `send_at` or `send_after` (e.g. a
+//! reminder when an event starts), through a `timer::Timer` of the registry.
+//! They are dropped if the connection is closed by then.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::connections::Connections;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let connections = Connections::new();
+//! let (eu, mut eu_rx) = connections.register();
+//! let (us, mut us_rx) = connections.register();
+//!
+//! // e.g. in a login handler, with `Connection` from the context
+//! eu.connection().tag("region:eu");
+//! us.connection().tag("region:us");
+//!
+//! assert_eq!(connections.broadcast_to("region:eu", "maintenance at 3am"), 1);
+//! assert_eq!(eu_rx.recv().await, Some("maintenance at 3am"));
+//! assert!(us_rx.try_recv().is_err());
+//!
+//! drop(eu);
+//! assert_eq!(connections.tagged("region:eu"), 0);
+//! # }
+//! ```
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+use crate::context::{Context, FromContext};
+use crate::timer::{Timer, TimerId};
+
+/// id of a connection, unique in its `Connections`
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ConnectionId(pub u64);
+
+struct Entry<T> {
+    tags: BTreeSet<String>,
+    tx: UnboundedSender<T>,
+}
+
+struct Inner<T> {
+    next_id: u64,
+    connections: HashMap<ConnectionId, Entry<T>>,
+
+    /// connections of each tag
+    tags: HashMap<String, HashSet<ConnectionId>>,
+
+    /// timer of scheduled messages, spawned at the first one
+    timer: Option<Timer<(ConnectionId, T)>>,
+}
+
+/// open connections that messages of type `T` are pushed to.
+/// Clones share the same connections.
+pub struct Connections<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Clone for Connections<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for Connections<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 1,
+                connections: HashMap::new(),
+                tags: HashMap::new(),
+                timer: None,
+            })),
+        }
+    }
+}
+
+impl<T> Debug for Connections<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connections")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<T: Clone> Connections<T> {
+    /// returns a registry without any connection
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a new connection without tags.
+    /// Messages pushed to it come out of the returned receiver.
+    pub fn register(&self) -> (Registration<T>, UnboundedReceiver<T>) {
+        let (tx, rx) = unbounded_channel();
+        let mut inner = self.inner.lock().unwrap();
+        let id = ConnectionId(inner.next_id);
+        inner.next_id += 1;
+        inner.connections.insert(
+            id,
+            Entry {
+                tags: BTreeSet::new(),
+                tx,
+            },
+        );
+
+        let connection = Connection {
+            id,
+            connections: self.clone(),
+        };
+        (Registration { connection }, rx)
+    }
+
+    /// pushes `msg` to every connection with `tag`, and returns to how many
+    pub fn broadcast_to(&self, tag: &str, msg: T) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let Some(ids) = inner.tags.get(tag) else {
+            return 0;
+        };
+        ids.iter()
+            .filter_map(|id| inner.connections.get(id))
+            .filter(|entry| entry.tx.send(msg.clone()).is_ok())
+            .count()
+    }
+
+    /// pushes `msg` to every connection, and returns to how many
+    pub fn broadcast(&self, msg: T) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .connections
+            .values()
+            .filter(|entry| entry.tx.send(msg.clone()).is_ok())
+            .count()
+    }
+
+    /// pushes `msg` to the connection of `id`.
+    /// Returns false if the connection is closed.
+    pub fn send_to(&self, id: ConnectionId, msg: T) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .connections
+            .get(&id)
+            .is_some_and(|entry| entry.tx.send(msg).is_ok())
+    }
+}
+
+impl<T: Clone + Send + 'static> Connections<T> {
+    /// pushes `msg` to the connection of `id` at `at`.
+    /// It is dropped if the connection is closed by then.
+    pub fn send_at(&self, at: Instant, id: ConnectionId, msg: T) -> TimerId {
+        let mut inner = self.inner.lock().unwrap();
+        let timer = inner.timer.get_or_insert_with(|| {
+            // the timer must not keep the registry alive
+            let weak = Arc::downgrade(&self.inner);
+            Timer::spawn(move |(id, msg)| {
+                if let Some(inner) = weak.upgrade() {
+                    Connections { inner }.send_to(id, msg);
+                }
+            })
+        });
+        timer.schedule(at, (id, msg))
+    }
+
+    /// pushes `msg` to the connection of `id` after `after`
+    pub fn send_after(&self, after: Duration, id: ConnectionId, msg: T) -> TimerId {
+        self.send_at(Instant::now() + after, id, msg)
+    }
+
+    /// cancels a message of `send_at` or `send_after` that is not pushed yet
+    pub fn cancel_send(&self, id: TimerId) {
+        if let Some(timer) = &self.inner.lock().unwrap().timer {
+            timer.cancel(id);
+        }
+    }
+}
+
+impl<T> Connections<T> {
+    /// returns the number of connections
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().connections.len()
+    }
+
+    /// returns true if there is no connection
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// returns the number of connections with `tag`
+    pub fn tagged(&self, tag: &str) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .tags
+            .get(tag)
+            .map_or(0, HashSet::len)
+    }
+
+    fn unregister(&self, id: ConnectionId) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.connections.remove(&id) {
+            for tag in entry.tags {
+                remove_tag(&mut inner.tags, &tag, id);
+            }
+        }
+    }
+}
+
+fn remove_tag(tags: &mut HashMap<String, HashSet<ConnectionId>>, tag: &str, id: ConnectionId) {
+    if let Some(ids) = tags.get_mut(tag) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            tags.remove(tag);
+        }
+    }
+}
+
+/// a connection in `Connections`, which handlers take from the context to
+/// tag it
+pub struct Connection<T> {
+    id: ConnectionId,
+    connections: Connections<T>,
+}
+
+impl<T> Clone for Connection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            connections: self.connections.clone(),
+        }
+    }
+}
+
+impl<T> Debug for Connection<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Connection").field(&self.id.0).finish()
+    }
+}
+
+impl<T> Connection<T> {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// puts `tag` on the connection.
+    /// Returns false if it already has the tag, or it is closed.
+    pub fn tag<S: Into<String>>(&self, tag: S) -> bool {
+        let tag = tag.into();
+        let mut inner = self.connections.inner.lock().unwrap();
+        let inserted = match inner.connections.get_mut(&self.id) {
+            Some(entry) => entry.tags.insert(tag.clone()),
+            None => false,
+        };
+        if inserted {
+            inner.tags.entry(tag).or_default().insert(self.id);
+        }
+        inserted
+    }
+
+    /// removes `tag` from the connection.
+    /// Returns false if it does not have the tag.
+    pub fn untag(&self, tag: &str) -> bool {
+        let mut inner = self.connections.inner.lock().unwrap();
+        let removed = inner
+            .connections
+            .get_mut(&self.id)
+            .is_some_and(|entry| entry.tags.remove(tag));
+        if removed {
+            remove_tag(&mut inner.tags, tag, self.id);
+        }
+        removed
+    }
+
+    /// returns true if the connection has `tag`
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let inner = self.connections.inner.lock().unwrap();
+        inner
+            .connections
+            .get(&self.id)
+            .is_some_and(|entry| entry.tags.contains(tag))
+    }
+
+    /// returns the tags of the connection in order
+    pub fn tags(&self) -> Vec<String> {
+        let inner = self.connections.inner.lock().unwrap();
+        inner
+            .connections
+            .get(&self.id)
+            .map(|entry| entry.tags.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl<T: Clone + Send + 'static> Connection<T> {
+    /// pushes `msg` to the connection.
+    /// Returns false if it is closed.
+    pub fn send(&self, msg: T) -> bool {
+        self.connections.send_to(self.id, msg)
+    }
+
+    /// pushes `msg` to the connection at `at`
+    pub fn send_at(&self, at: Instant, msg: T) -> TimerId {
+        self.connections.send_at(at, self.id, msg)
+    }
+
+    /// pushes `msg` to the connection after `after`
+    pub fn send_after(&self, after: Duration, msg: T) -> TimerId {
+        self.connections.send_after(after, self.id, msg)
+    }
+}
+
+impl<T: Send + 'static> FromContext for Connection<T> {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Connection<T>>().cloned()
+    }
+}
+
+/// registration of a connection, which removes it when dropped
+pub struct Registration<T> {
+    connection: Connection<T>,
+}
+
+impl<T> Registration<T> {
+    /// returns the connection to put into the context
+    pub fn connection(&self) -> Connection<T> {
+        self.connection.clone()
+    }
+}
+
+impl<T> Drop for Registration<T> {
+    fn drop(&mut self) {
+        self.connection.connections.unregister(self.connection.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tag_test() {
+        let connections = Connections::new();
+        let (a, mut a_rx) = connections.register();
+        let (b, mut b_rx) = connections.register();
+        let (a, b) = (a.connection(), b.connection());
+        assert_ne!(a.id(), b.id());
+
+        assert!(a.tag("beta"));
+        assert!(!a.tag("beta"));
+        assert!(b.tag("beta"));
+        assert!(a.tag("region:eu"));
+        assert_eq!(a.tags(), vec!["beta", "region:eu"]);
+        assert_eq!(connections.tagged("beta"), 2);
+
+        assert_eq!(connections.broadcast_to("beta", 1), 2);
+        assert!(b.untag("beta"));
+        assert!(!b.has_tag("beta"));
+        assert_eq!(connections.broadcast_to("beta", 2), 1);
+        assert_eq!(connections.broadcast_to("alpha", 3), 0);
+        assert!(connections.send_to(b.id(), 4));
+
+        assert_eq!(a_rx.try_recv(), Ok(1));
+        assert_eq!(a_rx.try_recv(), Ok(2));
+        assert_eq!(b_rx.try_recv(), Ok(1));
+        assert_eq!(b_rx.try_recv(), Ok(4));
+        assert!(b_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn drop_test() {
+        let connections = Connections::new();
+        let (registration, rx) = connections.register();
+        let connection = registration.connection();
+        connection.tag("beta");
+        assert_eq!(connections.len(), 1);
+
+        drop(registration);
+        assert!(connections.is_empty());
+        assert_eq!(connections.tagged("beta"), 0);
+        assert!(!connection.tag("beta"));
+        assert!(!connections.send_to(connection.id(), ()));
+        drop(rx);
+
+        // a connection whose receiver is dropped is not counted
+        let (_registration, rx) = connections.register();
+        drop(rx);
+        assert_eq!(connections.broadcast(()), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_after_test() {
+        let connections = Connections::new();
+        let (registration, mut rx) = connections.register();
+        let connection = registration.connection();
+
+        connection.send_after(Duration::from_secs(2), "starts");
+        let id = connection.send_after(Duration::from_secs(1), "cancelled");
+        connections.cancel_send(id);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.recv().await, Some("starts"));
+
+        // messages to closed connections are dropped
+        connection.send_after(Duration::from_secs(1), "closed");
+        drop(registration);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(rx.recv().await, None);
+    }
+}
@@ -0,0 +1,405 @@
+//! Context of connection and extractors for handler arguments
+//!
+//! `Context` is a type map that holds values shared by messages, like
+//! application state or the state of the connection. A message travels with
+//! its context as `Request<T>`.
+//!
+//! Handler functions can declare what they need from the context by the types
+//! of their arguments. The first argument is the message, and each of the
+//! others is a type implementing `FromContext`. Such functions can be used
+//! as a `Handler<Request<T>>` directly.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::context::{Context, ContextLayer, Data, Missing};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! struct Db {
+//!     name: &'static str,
+//! }
+//!
+//! #[derive(Debug)]
+//! struct Error;
+//!
+//! impl From<Missing> for Error {
+//!     fn from(_: Missing) -> Self {
+//!         Error
+//!     }
+//! }
+//!
+//! async fn chat(msg: &'static str, db: Data<Db>) -> Result<(), Error> {
+//!     println!("saving {msg} to {}", db.name);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Error> {
+//! let mut context = Context::new();
+//! context.insert(Data::new(Db { name: "chat-db" }));
+//!
+//! // every message gets `context`, and `chat` takes `Data<Db>` out of it
+//! let handler = apply!(ContextLayer::new(context) to chat);
+//! // this would print "saving hello to chat-db"
+//! handler.call("hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use futures::future::{err, ok, Either, Ready};
+
+use crate::handler::{Handler, IntoHandler};
+use crate::layer::Layer;
+
+/// type map of values shared by messages
+#[derive(Clone, Default)]
+pub struct Context {
+    extensions: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Context {
+    /// returns an empty context
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// inserts a value.
+    /// A value of the same type is replaced and returned.
+    pub fn insert<V: Any + Send + Sync>(&mut self, value: V) -> Option<Arc<V>> {
+        Arc::make_mut(&mut self.extensions)
+            .insert(TypeId::of::<V>(), Arc::new(value))
+            .and_then(|old| old.downcast().ok())
+    }
+
+    /// returns the value of type `V`
+    pub fn get<V: Any + Send + Sync>(&self) -> Option<&V> {
+        self.extensions.get(&TypeId::of::<V>())?.downcast_ref()
+    }
+
+    /// removes the value of type `V`
+    pub fn remove<V: Any + Send + Sync>(&mut self) -> Option<Arc<V>> {
+        Arc::make_mut(&mut self.extensions)
+            .remove(&TypeId::of::<V>())
+            .and_then(|old| old.downcast().ok())
+    }
+
+    /// returns true if there is a value of type `V`
+    pub fn contains<V: Any + Send + Sync>(&self) -> bool {
+        self.extensions.contains_key(&TypeId::of::<V>())
+    }
+}
+
+impl Debug for Context {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context")
+            .field("len", &self.extensions.len())
+            .finish()
+    }
+}
+
+/// message with its context
+#[derive(Clone, Debug)]
+pub struct Request<T> {
+    pub msg: T,
+    pub context: Context,
+}
+
+impl<T> Request<T> {
+    /// returns a request with an empty context
+    pub fn new(msg: T) -> Self {
+        Self {
+            msg,
+            context: Context::new(),
+        }
+    }
+
+    /// returns a request of `msg` with `context`
+    pub fn with_context(msg: T, context: Context) -> Self {
+        Self { msg, context }
+    }
+}
+
+/// error when a handler argument cannot be extracted from the context.
+/// Error type of handler functions should implement `From<Missing>`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Missing(pub &'static str);
+
+impl Display for Missing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` is missing in context", self.0)
+    }
+}
+
+impl std::error::Error for Missing {}
+
+/// types that can be extracted from `Context` as a handler argument
+pub trait FromContext: Sized {
+    /// returns `None` when the context does not have it
+    fn from_context(context: &Context) -> Option<Self>;
+}
+
+/// the whole context
+impl FromContext for Context {
+    fn from_context(context: &Context) -> Option<Self> {
+        Some(context.clone())
+    }
+}
+
+/// shared state of application
+///
+/// Insert `Data::new(value)` into the context, then `Data<T>` can be taken
+/// as a handler argument.
+pub struct Data<T: ?Sized>(Arc<T>);
+
+impl<T> Data<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+}
+
+impl<T: ?Sized> Data<T> {
+    /// returns the inner `Arc`
+    pub fn into_inner(self) -> Arc<T> {
+        self.0
+    }
+}
+
+impl<T: ?Sized> From<Arc<T>> for Data<T> {
+    fn from(arc: Arc<T>) -> Self {
+        Self(arc)
+    }
+}
+
+impl<T: ?Sized> Clone for Data<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> Deref for Data<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized + Send + Sync + 'static> FromContext for Data<T> {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Data<T>>().cloned()
+    }
+}
+
+/// clone of any value in the context
+#[derive(Clone, Debug)]
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromContext for Extension<T> {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<T>().cloned().map(Extension)
+    }
+}
+
+impl<T: FromContext> FromContext for Option<T> {
+    fn from_context(context: &Context) -> Option<Self> {
+        Some(T::from_context(context))
+    }
+}
+
+/// `Handler` of `Request<T>` for functions with extractor arguments.
+/// The type of function would be as:
+/// `async fn<T, A1, A2, ...>(T, A1, A2, ...) -> Result<O, Err>`
+/// where each `A` implements `FromContext`.
+pub struct ExtractHandler<F, T, Args, Fut, Err> {
+    f: F,
+    #[allow(clippy::type_complexity)]
+    _marker: PhantomData<fn(T, Args) -> (Fut, Err)>,
+}
+
+/// public function wrapper of `ExtractHandler`
+/// use this to change function with extractor arguments into `Handler`
+pub fn extract<F, T, Args, Fut, Err>(f: F) -> ExtractHandler<F, T, Args, Fut, Err>
+where
+    ExtractHandler<F, T, Args, Fut, Err>: Handler<Request<T>>,
+{
+    ExtractHandler {
+        f,
+        _marker: PhantomData,
+    }
+}
+
+macro_rules! impl_extract_handler {
+    ($($arg:ident),+) => {
+        impl<F, T, $($arg,)+ Fut, O, Err> Handler<Request<T>>
+            for ExtractHandler<F, T, ($($arg,)+), Fut, Err>
+        where
+            F: Fn(T, $($arg),+) -> Fut,
+            $($arg: FromContext,)+
+            Fut: Future<Output = Result<O, Err>>,
+            Err: From<Missing>,
+        {
+            type Output = O;
+            type Error = Err;
+            type Future = Either<Ready<Result<O, Err>>, Fut>;
+
+            #[allow(non_snake_case)]
+            fn call(&self, req: Request<T>) -> Self::Future {
+                $(
+                    let $arg = match $arg::from_context(&req.context) {
+                        Some(v) => v,
+                        None => return Either::Left(err(Missing(type_name::<$arg>()).into())),
+                    };
+                )+
+                Either::Right((self.f)(req.msg, $($arg),+))
+            }
+        }
+
+        impl<F, T, $($arg,)+ Fut, O, Err>
+            IntoHandler<ExtractHandler<F, T, ($($arg,)+), Fut, Err>, Request<T>> for F
+        where
+            F: Fn(T, $($arg),+) -> Fut,
+            $($arg: FromContext,)+
+            Fut: Future<Output = Result<O, Err>>,
+            Err: From<Missing>,
+        {
+            fn into_handler(self) -> ExtractHandler<F, T, ($($arg,)+), Fut, Err> {
+                extract(self)
+            }
+        }
+    };
+}
+
+impl_extract_handler!(A1);
+impl_extract_handler!(A1, A2);
+impl_extract_handler!(A1, A2, A3);
+impl_extract_handler!(A1, A2, A3, A4);
+impl_extract_handler!(A1, A2, A3, A4, A5);
+impl_extract_handler!(A1, A2, A3, A4, A5, A6);
+impl_extract_handler!(A1, A2, A3, A4, A5, A6, A7);
+impl_extract_handler!(A1, A2, A3, A4, A5, A6, A7, A8);
+
+/// `Layer` that attaches a context to every message
+pub struct ContextLayer {
+    context: Context,
+}
+
+impl ContextLayer {
+    pub fn new(context: Context) -> Self {
+        Self { context }
+    }
+}
+
+impl<T, H> Layer<T, H> for ContextLayer
+where
+    H: Handler<Request<T>>,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = ContextHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ContextHandler {
+            context: self.context.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that makes `Request<T>` from `T` with the context
+pub struct ContextHandler<H> {
+    context: Context,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for ContextHandler<H>
+where
+    H: Handler<Request<T>>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = H::Future;
+
+    fn call(&self, msg: T) -> Self::Future {
+        self.prev
+            .call(Request::with_context(msg, self.context.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Error {
+        Missing(&'static str),
+        Wrong,
+    }
+
+    impl From<Missing> for Error {
+        fn from(m: Missing) -> Self {
+            Error::Missing(m.0)
+        }
+    }
+
+    #[derive(Clone)]
+    struct Session(u32);
+
+    async fn check(
+        msg: u32,
+        data: Data<String>,
+        session: Extension<Session>,
+        none: Option<Data<u8>>,
+    ) -> Result<(), Error> {
+        if msg == session.0 .0 && data.as_str() == "data" && none.is_none() {
+            Ok(())
+        } else {
+            Err(Error::Wrong)
+        }
+    }
+
+    #[test]
+    fn context_test() {
+        let mut context = Context::new();
+        assert!(context.insert(1u32).is_none());
+        assert_eq!(context.insert(2u32).as_deref(), Some(&1));
+
+        let cloned = context.clone();
+        context.insert("hello");
+        assert_eq!(context.get::<&str>(), Some(&"hello"));
+        assert_eq!(cloned.get::<&str>(), None);
+        assert_eq!(context.remove::<u32>().as_deref(), Some(&2));
+        assert!(!context.contains::<u32>());
+        assert!(cloned.contains::<u32>());
+    }
+
+    #[tokio::test]
+    async fn extract_test() -> Result<(), Error> {
+        let mut context = Context::new();
+        context.insert(Data::new("data".to_string()));
+        context.insert(Session(3));
+
+        let handler = connect(ContextLayer::new(context), check).await.unwrap();
+        handler.call(3).await?;
+        assert_eq!(handler.call(4).await, Err(Error::Wrong));
+
+        let handler = extract(check);
+        assert_eq!(
+            handler.call(Request::new(3)).await,
+            Err(Error::Missing(type_name::<Data<String>>()))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! Database transactions per message with `sqlx`
+//!
+//! A connection pool is application state like any other: insert
+//! `Data::new(pool)` into the context and take `Data<Pool<DB>>` as a handler
+//! argument.
+//!
+//! `TransactionLayer` opens a transaction from the pool for every message and
+//! puts it in the context as `Tx<DB>`. When the handler succeeds, the
+//! transaction is committed. When the handler fails, it is rolled back.
+//!
+//! This module needs the `database` feature.
+//!
+//! # Examples
+//!
+//! The examples use SQLite, so they run with the `sqlite` feature.
+//!
+#![cfg_attr(feature = "sqlite", doc = "```")]
+#![cfg_attr(not(feature = "sqlite"), doc = "```ignore")]
+//! use cubby_connect_server_core::context::{Context, ContextLayer, Data, Missing};
+//! use cubby_connect_server_core::database::{Tx, TransactionLayer};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//! use sqlx::sqlite::{Sqlite, SqlitePoolOptions};
+//!
+//! #[derive(Debug)]
+//! enum Error {
+//!     Missing,
+//!     Db(sqlx::Error),
+//! }
+//!
+//! impl From<Missing> for Error {
+//!     fn from(_: Missing) -> Self {
+//!         Error::Missing
+//!     }
+//! }
+//!
+//! impl From<sqlx::Error> for Error {
+//!     fn from(e: sqlx::Error) -> Self {
+//!         Error::Db(e)
+//!     }
+//! }
+//!
+//! async fn save(msg: &'static str, tx: Tx<Sqlite>) -> Result<(), Error> {
+//!     sqlx::query("INSERT INTO chat (msg) VALUES (?)")
+//!         .bind(msg)
+//!         .execute(&mut **tx.lock().await)
+//!         .await?;
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Error> {
+//! let pool = SqlitePoolOptions::new()
+//!     .max_connections(1)
+//!     .connect("sqlite::memory:")
+//!     .await?;
+//! sqlx::query("CREATE TABLE chat (msg TEXT)").execute(&pool).await?;
+//!
+//! let mut context = Context::new();
+//! context.insert(Data::new(pool.clone()));
+//!
+//! let handler = apply!(
+//!     ContextLayer::new(context),
+//!     TransactionLayer::new(pool.clone())
+//!     to save
+//! );
+//! // "hello" is committed after `save` returned
+//! handler.call("hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use sqlx::{Database, Pool, Transaction};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// transaction of the current message.
+///
+/// `TransactionLayer` puts it in the context, and handlers take it as an
+/// argument. The transaction is finished by the layer after the handler
+/// returned.
+pub struct Tx<DB: Database> {
+    inner: Arc<Mutex<Option<Transaction<'static, DB>>>>,
+}
+
+impl<DB: Database> Tx<DB> {
+    pub(crate) fn new(tx: Transaction<'static, DB>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Some(tx))),
+        }
+    }
+
+    /// locks the transaction to run queries on it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the transaction is already finished, which means `Tx` is
+    /// used after the handler returned.
+    pub async fn lock(&self) -> MappedMutexGuard<'_, Transaction<'static, DB>> {
+        MutexGuard::map(self.inner.lock().await, |tx| {
+            tx.as_mut().expect("transaction is already finished")
+        })
+    }
+
+    /// takes the transaction out to finish it
+    pub(crate) async fn take(&self) -> Option<Transaction<'static, DB>> {
+        self.inner.lock().await.take()
+    }
+}
+
+impl<DB: Database> Clone for Tx<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<DB: Database> FromContext for Tx<DB> {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Tx<DB>>().cloned()
+    }
+}
+
+/// `Layer` that builds `TransactionHandler`
+pub struct TransactionLayer<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> TransactionLayer<DB> {
+    /// makes a layer that begins transactions from `pool`
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self { pool }
+    }
+}
+
+impl<T, DB, H> Layer<Request<T>, H> for TransactionLayer<DB>
+where
+    T: 'static,
+    DB: Database,
+    H: Handler<Request<T>> + 'static,
+    H::Error: From<sqlx::Error> + 'static,
+    H::Output: 'static,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = TransactionHandler<T, DB, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(TransactionHandler {
+            pool: self.pool.clone(),
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that runs previous handler in a database transaction
+pub struct TransactionHandler<T, DB: Database, H> {
+    pool: Pool<DB>,
+    prev: Arc<H>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, DB, H> Handler<Request<T>> for TransactionHandler<T, DB, H>
+where
+    T: 'static,
+    DB: Database,
+    H: Handler<Request<T>> + 'static,
+    H::Error: From<sqlx::Error> + 'static,
+    H::Output: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// When the handler fails, its error is returned even if rollback
+    /// succeeded. Error of commit or rollback is returned when they fail.
+    fn call(&self, mut req: Request<T>) -> Self::Future {
+        let pool = self.pool.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            let tx = Tx::new(pool.begin().await?);
+            req.context.insert(tx.clone());
+            let result = prev.call(req).await;
+
+            match (result, tx.take().await) {
+                (Ok(output), Some(tx)) => {
+                    tx.commit().await?;
+                    Ok(output)
+                }
+                (Err(e), Some(tx)) => {
+                    tx.rollback().await?;
+                    Err(e)
+                }
+                (result, None) => result,
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use sqlx::sqlite::{Sqlite, SqlitePool, SqlitePoolOptions};
+
+    use crate::context::{extract, Missing};
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[derive(Debug)]
+    enum Error {
+        Missing,
+        Db,
+        Rejected,
+    }
+
+    impl From<Missing> for Error {
+        fn from(_: Missing) -> Self {
+            Error::Missing
+        }
+    }
+
+    impl From<sqlx::Error> for Error {
+        fn from(_: sqlx::Error) -> Self {
+            Error::Db
+        }
+    }
+
+    async fn insert(score: i64, tx: Tx<Sqlite>) -> Result<(), Error> {
+        sqlx::query("INSERT INTO scores (score) VALUES (?)")
+            .bind(score)
+            .execute(&mut **tx.lock().await)
+            .await?;
+        if score < 0 {
+            Err(Error::Rejected)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn pool() -> Result<SqlitePool, Error> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::query("CREATE TABLE scores (score INTEGER)")
+            .execute(&pool)
+            .await?;
+        Ok(pool)
+    }
+
+    async fn scores(pool: &SqlitePool) -> Result<Vec<i64>, Error> {
+        Ok(sqlx::query_scalar("SELECT score FROM scores")
+            .fetch_all(pool)
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn commit_and_rollback_test() -> Result<(), Error> {
+        let pool = pool().await?;
+        let handler = connect(TransactionLayer::new(pool.clone()), extract(insert)).await?;
+
+        handler.call(Request::new(10)).await?;
+        assert!(matches!(
+            handler.call(Request::new(-1)).await,
+            Err(Error::Rejected)
+        ));
+        handler.call(Request::new(20)).await?;
+
+        assert_eq!(scores(&pool).await?, vec![10, 20]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_tx_test() -> Result<(), Error> {
+        let handler = extract(insert);
+        assert!(matches!(
+            handler.call(Request::new(1)).await,
+            Err(Error::Missing)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+//! Deduplication of retransmitted messages
+//!
+//! Clients retransmit messages over lossy links, so the server can receive
+//! the same message more than once. `DedupLayer` takes a key of each
+//! `Envelope` (the `message-id` header by default), remembers keys that were
+//! seen in a time window, and drops repeats before they reach the next
+//! handler. Dropped messages resolve to `None`.
+//!
+//! Unlike `idempotency::IdempotencyLayer`, results are not remembered, so
+//! it fits messages without responses, like game inputs. The number of
+//! remembered keys is bounded: the oldest keys are forgotten first when the
+//! cache is full.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::dedup::{DedupLayer, MESSAGE_ID};
+//! use cubby_connect_server_core::envelope::Envelope;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn jump(_: Envelope<Vec<u8>>) -> Result<(), ()> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let handler = apply!(DedupLayer::new(Duration::from_secs(30), 10_000) to jump);
+//!
+//! let mut env = Envelope::new(b"jump".to_vec());
+//! env.headers.insert(MESSAGE_ID, "c1-1001");
+//! assert_eq!(handler.call(env.clone()).await?, Some(()));
+//! assert_eq!(handler.call(env).await?, None);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, Either, MapOk, Ready};
+use futures::TryFutureExt;
+use tokio::time::Instant;
+
+use crate::envelope::{Envelope, Headers};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// header of the message id that is used by default
+pub const MESSAGE_ID: &str = "message-id";
+
+/// returns the `message-id` header
+pub fn message_id(headers: &Headers) -> Option<String> {
+    headers.get(MESSAGE_ID).map(String::from)
+}
+
+/// keys seen in the window, in order of arrival
+struct Seen {
+    window: Duration,
+    capacity: usize,
+    order: VecDeque<(Instant, String)>,
+    keys: HashSet<String>,
+}
+
+impl Seen {
+    /// returns true if `key` is new, and remembers it
+    fn insert(&mut self, key: String, now: Instant) -> bool {
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.forget_oldest();
+        }
+        if self.keys.contains(&key) {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            self.forget_oldest();
+        }
+        self.keys.insert(key.clone());
+        self.order.push_back((now, key));
+        true
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_front() {
+            self.keys.remove(&key);
+        }
+    }
+}
+
+/// `Layer` that builds `DedupHandler`
+pub struct DedupLayer<F = fn(&Headers) -> Option<String>> {
+    window: Duration,
+    capacity: usize,
+    key: Arc<F>,
+}
+
+impl DedupLayer {
+    /// drops messages whose `message-id` was seen in `window`.
+    /// At most `capacity` keys are remembered.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity should be positive");
+        Self {
+            window,
+            capacity,
+            key: Arc::new(message_id),
+        }
+    }
+}
+
+impl<F> DedupLayer<F> {
+    /// takes keys from headers with `key` instead of `message-id`.
+    /// Messages without a key (`None`) are never dropped.
+    pub fn key<G>(self, key: G) -> DedupLayer<G>
+    where
+        G: Fn(&Headers) -> Option<String>,
+    {
+        DedupLayer {
+            window: self.window,
+            capacity: self.capacity,
+            key: Arc::new(key),
+        }
+    }
+}
+
+impl<T, F, H> Layer<Envelope<T>, H> for DedupLayer<F>
+where
+    F: Fn(&Headers) -> Option<String>,
+    H: Handler<Envelope<T>>,
+{
+    type Next = Envelope<T>;
+    type Error = H::Error;
+    type Handler = DedupHandler<F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(DedupHandler {
+            key: self.key.clone(),
+            seen: Mutex::new(Seen {
+                window: self.window,
+                capacity: self.capacity,
+                order: VecDeque::new(),
+                keys: HashSet::new(),
+            }),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that drops messages whose keys were seen recently
+pub struct DedupHandler<F, H> {
+    key: Arc<F>,
+    seen: Mutex<Seen>,
+    prev: H,
+}
+
+impl<F, H> DedupHandler<F, H> {
+    /// returns the number of remembered keys
+    pub fn remembered(&self) -> usize {
+        self.seen.lock().unwrap().keys.len()
+    }
+}
+
+impl<T, F, H> Handler<Envelope<T>> for DedupHandler<F, H>
+where
+    F: Fn(&Headers) -> Option<String>,
+    H: Handler<Envelope<T>>,
+{
+    type Output = Option<H::Output>;
+    type Error = H::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<
+        MapOk<H::Future, fn(H::Output) -> Option<H::Output>>,
+        Ready<Result<Option<H::Output>, H::Error>>,
+    >;
+
+    /// returns `None` if the message is a repeat
+    fn call(&self, env: Envelope<T>) -> Self::Future {
+        if let Some(key) = (self.key)(&env.headers) {
+            let mut seen = self.seen.lock().unwrap();
+            if !seen.insert(key, Instant::now()) {
+                return Either::Right(ok(None));
+            }
+        }
+        Either::Left(self.prev.call(env).map_ok(Some as fn(_) -> _))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn payload(env: Envelope<u32>) -> Result<u32, ()> {
+        Ok(env.msg)
+    }
+
+    fn env(id: Option<&str>, msg: u32) -> Envelope<u32> {
+        let mut env = Envelope::new(msg);
+        if let Some(id) = id {
+            env.headers.insert(MESSAGE_ID, id);
+        }
+        env
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_test() -> Result<(), ()> {
+        let handler = connect(DedupLayer::new(Duration::from_secs(10), 100), payload).await?;
+        assert_eq!(handler.call(env(Some("a"), 1)).await?, Some(1));
+        assert_eq!(handler.call(env(Some("a"), 2)).await?, None);
+        assert_eq!(handler.call(env(None, 3)).await?, Some(3));
+        assert_eq!(handler.call(env(None, 3)).await?, Some(3));
+
+        // keys are forgotten after the window
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(handler.call(env(Some("a"), 4)).await?, Some(4));
+        assert_eq!(handler.remembered(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn capacity_test() -> Result<(), ()> {
+        let layer = DedupLayer::new(Duration::from_secs(10), 2)
+            .key(|h: &Headers| h.correlation_id.map(|id| id.to_string()));
+        let handler = connect(layer, payload).await?;
+
+        for id in [1, 2, 3] {
+            let mut env = Envelope::new(id);
+            env.headers.correlation_id = Some(id as u64);
+            assert_eq!(handler.call(env).await?, Some(id));
+        }
+        assert_eq!(handler.remembered(), 2);
+
+        // the oldest key is forgotten first
+        let mut env = Envelope::new(1);
+        env.headers.correlation_id = Some(1);
+        assert_eq!(handler.call(env).await?, Some(1));
+        let mut env = Envelope::new(3);
+        env.headers.correlation_id = Some(3);
+        assert_eq!(handler.call(env).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,474 @@
+//! Delta encoding for large payloads that are sent repeatedly
+//!
+//! Payloads like asset manifests are sent again and again with small changes.
+//! `DeltaEncoder` remembers the last version of each blob that the peer
+//! acknowledged, and sends only the difference against it.
+//! When there is no acknowledged version (or the delta is not smaller),
+//! it falls back to the full payload.
+//!
+//! The delta is a list of copy (from the base) and insert (new bytes)
+//! instructions, like xdelta.
+//!
+//! Memory is bounded on both sides: only the last few versions of a blob
+//! are kept, and when more than `max_blobs` blobs are known, the blob that
+//! was used least recently is forgotten (its next payload is sent in full).
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::delta::{DeltaDecoder, DeltaEncoder, Payload};
+//!
+//! let mut encoder = DeltaEncoder::new();
+//! let mut decoder = DeltaDecoder::new();
+//!
+//! let v1 = vec![7u8; 4096];
+//! let mut v2 = v1.clone();
+//! v2[100] = 0;
+//!
+//! // there is no acknowledged version yet
+//! let p1 = encoder.encode("manifest", 1, &v1);
+//! assert!(matches!(p1, Payload::Full { .. }));
+//! assert_eq!(decoder.decode("manifest", p1).unwrap(), v1);
+//! encoder.ack("manifest", 1);
+//!
+//! let p2 = encoder.encode("manifest", 2, &v2);
+//! assert!(matches!(p2, Payload::Delta { .. }));
+//! assert_eq!(decoder.decode("manifest", p2).unwrap(), v2);
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+
+/// size of a block that is looked up in base
+const BLOCK: usize = 16;
+
+/// number of versions kept per blob: versions that are sent but not
+/// acknowledged in `DeltaEncoder`, and decoded versions in `DeltaDecoder`
+const HISTORY: usize = 4;
+
+/// default number of blobs that are remembered
+const DEFAULT_MAX_BLOBS: usize = 1024;
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// error when decoding delta
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeltaError {
+    /// base version of delta is not known in decoder.
+    /// sender should send full payload.
+    UnknownBase { name: String, base: u64 },
+
+    /// delta is broken
+    Malformed,
+}
+
+impl Display for DeltaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::UnknownBase { name, base } => {
+                write!(f, "unknown base version {base} of `{name}`")
+            }
+            DeltaError::Malformed => write!(f, "malformed delta"),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// payload that is sent to the peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Payload {
+    /// whole data
+    Full { version: u64, data: Vec<u8> },
+
+    /// difference from `base` version
+    Delta {
+        base: u64,
+        version: u64,
+        delta: Vec<u8>,
+    },
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, DeltaError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos).ok_or(DeltaError::Malformed)?;
+        *pos += 1;
+        v |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(DeltaError::Malformed)
+}
+
+fn flush_insert(out: &mut Vec<u8>, literal: &[u8]) {
+    if !literal.is_empty() {
+        out.push(OP_INSERT);
+        write_varint(out, literal.len() as u64);
+        out.extend_from_slice(literal);
+    }
+}
+
+/// makes delta that changes `base` into `target`
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK).enumerate() {
+        blocks.entry(block).or_insert(i * BLOCK);
+    }
+
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i + BLOCK <= target.len() {
+        let offset = match blocks.get(&target[i..i + BLOCK]) {
+            Some(&offset) => offset,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut len = BLOCK;
+        while offset + len < base.len()
+            && i + len < target.len()
+            && base[offset + len] == target[i + len]
+        {
+            len += 1;
+        }
+
+        flush_insert(&mut out, &target[literal_start..i]);
+        out.push(OP_COPY);
+        write_varint(&mut out, offset as u64);
+        write_varint(&mut out, len as u64);
+
+        i += len;
+        literal_start = i;
+    }
+
+    flush_insert(&mut out, &target[literal_start..]);
+    out
+}
+
+/// applies delta made by `diff` to `base`
+pub fn patch(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_COPY => {
+                let offset = read_varint(delta, &mut pos)? as usize;
+                let len = read_varint(delta, &mut pos)? as usize;
+                let end = offset.checked_add(len).ok_or(DeltaError::Malformed)?;
+                out.extend_from_slice(base.get(offset..end).ok_or(DeltaError::Malformed)?);
+            }
+            OP_INSERT => {
+                let len = read_varint(delta, &mut pos)? as usize;
+                let end = pos.checked_add(len).ok_or(DeltaError::Malformed)?;
+                out.extend_from_slice(delta.get(pos..end).ok_or(DeltaError::Malformed)?);
+                pos = end;
+            }
+            _ => return Err(DeltaError::Malformed),
+        }
+    }
+
+    Ok(out)
+}
+
+/// blobs in the order of use, to forget the one used least recently
+#[derive(Debug)]
+struct Recent {
+    used: HashMap<String, u64>,
+    clock: u64,
+    max: usize,
+}
+
+impl Default for Recent {
+    fn default() -> Self {
+        Self {
+            used: HashMap::new(),
+            clock: 0,
+            max: DEFAULT_MAX_BLOBS,
+        }
+    }
+}
+
+impl Recent {
+    /// marks `name` as used. Returns the blob to forget if there are too
+    /// many blobs.
+    fn touch(&mut self, name: &str) -> Option<String> {
+        self.clock += 1;
+        if let Some(used) = self.used.get_mut(name) {
+            *used = self.clock;
+            return None;
+        }
+
+        let evicted = match self.used.len() >= self.max {
+            true => self
+                .used
+                .iter()
+                .min_by_key(|(_, used)| **used)
+                .map(|(name, _)| name.clone()),
+            false => None,
+        };
+        if let Some(evicted) = &evicted {
+            self.used.remove(evicted);
+        }
+        self.used.insert(name.to_string(), self.clock);
+        evicted
+    }
+}
+
+/// sender side of delta encoding for one connection
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    /// versions that are sent but not acknowledged yet
+    sent: HashMap<String, VecDeque<(u64, Vec<u8>)>>,
+
+    /// last acknowledged version of each blob
+    acked: HashMap<String, (u64, Vec<u8>)>,
+
+    recent: Recent,
+}
+
+impl DeltaEncoder {
+    /// returns an encoder that knows nothing about the peer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// remembers at most `max` blobs (1024 by default)
+    pub fn max_blobs(mut self, max: usize) -> Self {
+        self.recent.max = max.max(1);
+        self
+    }
+
+    /// makes payload of `data` as `version` of blob `name`.
+    ///
+    /// Delta is made against the last acknowledged version.
+    /// Full payload is returned when there is no acknowledged version or
+    /// delta is not smaller than `data`.
+    pub fn encode(&mut self, name: &str, version: u64, data: &[u8]) -> Payload {
+        if let Some(evicted) = self.recent.touch(name) {
+            self.sent.remove(&evicted);
+            self.acked.remove(&evicted);
+        }
+        let sent = self.sent.entry(name.to_string()).or_default();
+        if sent.len() == HISTORY {
+            sent.pop_front();
+        }
+        sent.push_back((version, data.to_vec()));
+
+        if let Some((base, base_data)) = self.acked.get(name) {
+            let delta = diff(base_data, data);
+            if delta.len() < data.len() {
+                return Payload::Delta {
+                    base: *base,
+                    version,
+                    delta,
+                };
+            }
+        }
+
+        Payload::Full {
+            version,
+            data: data.to_vec(),
+        }
+    }
+
+    /// peer has received `version` of `name`.
+    /// Later payloads of `name` are made against this version.
+    pub fn ack(&mut self, name: &str, version: u64) {
+        if let Some(sent) = self.sent.get_mut(name) {
+            if let Some(i) = sent.iter().position(|(v, _)| *v == version) {
+                let (v, data) = sent.remove(i).unwrap_or_default();
+                sent.retain(|(other, _)| *other > v);
+                self.acked.insert(name.to_string(), (v, data));
+            }
+        }
+    }
+
+    /// forgets the acknowledged version of `name`,
+    /// so that the next payload is sent in full.
+    /// Use this when the peer reports `DeltaError::UnknownBase`.
+    pub fn reset(&mut self, name: &str) {
+        self.acked.remove(name);
+    }
+}
+
+/// receiver side of delta encoding for one connection
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    versions: HashMap<String, VecDeque<(u64, Vec<u8>)>>,
+    recent: Recent,
+}
+
+impl DeltaDecoder {
+    /// returns a decoder without any version
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// remembers at most `max` blobs (1024 by default)
+    pub fn max_blobs(mut self, max: usize) -> Self {
+        self.recent.max = max.max(1);
+        self
+    }
+
+    /// restores data of blob `name` from `payload`
+    pub fn decode(&mut self, name: &str, payload: Payload) -> Result<Vec<u8>, DeltaError> {
+        if let Some(evicted) = self.recent.touch(name) {
+            self.versions.remove(&evicted);
+        }
+        let history = self.versions.entry(name.to_string()).or_default();
+
+        let (version, data) =
+            match payload {
+                Payload::Full { version, data } => (version, data),
+                Payload::Delta {
+                    base,
+                    version,
+                    delta,
+                } => {
+                    let (_, base_data) = history.iter().find(|(v, _)| *v == base).ok_or(
+                        DeltaError::UnknownBase {
+                            name: name.to_string(),
+                            base,
+                        },
+                    )?;
+                    (version, patch(base_data, &delta)?)
+                }
+            };
+
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back((version, data.clone()));
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_patch_test() {
+        let base = sample(10_000, 1);
+        let mut target = base.clone();
+        target.splice(5000..5010, [1, 2, 3]);
+        target.extend_from_slice(b"tail");
+        target.drain(..7);
+
+        let delta = diff(&base, &target);
+        assert!(delta.len() < 100);
+        assert_eq!(patch(&base, &delta).unwrap(), target);
+
+        // totally different data
+        let other = sample(1000, 2);
+        assert_eq!(patch(&base, &diff(&base, &other)).unwrap(), other);
+        assert_eq!(patch(&base, &diff(&base, &[])).unwrap(), Vec::<u8>::new());
+        assert_eq!(patch(&base, &[OP_COPY, 0]), Err(DeltaError::Malformed));
+    }
+
+    #[test]
+    fn encoder_test() {
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let v1 = sample(4096, 3);
+        let mut v2 = v1.clone();
+        v2[10] ^= 0xff;
+        let mut v3 = v2.clone();
+        v3[2000] ^= 0xff;
+
+        let p = encoder.encode("a", 1, &v1);
+        assert!(matches!(p, Payload::Full { .. }));
+        assert_eq!(decoder.decode("a", p).unwrap(), v1);
+        encoder.ack("a", 1);
+
+        // v2 is not acknowledged, so v3 is also made against v1
+        let p2 = encoder.encode("a", 2, &v2);
+        let p3 = encoder.encode("a", 3, &v3);
+        assert!(matches!(p3, Payload::Delta { base: 1, .. }));
+        assert_eq!(decoder.decode("a", p2).unwrap(), v2);
+        assert_eq!(decoder.decode("a", p3).unwrap(), v3);
+
+        // other decoder does not know the base
+        let mut fresh = DeltaDecoder::new();
+        encoder.ack("a", 3);
+        let p4 = encoder.encode("a", 4, &v1);
+        assert_eq!(
+            fresh.decode("a", p4),
+            Err(DeltaError::UnknownBase {
+                name: "a".to_string(),
+                base: 3
+            })
+        );
+        encoder.reset("a");
+        let p4 = encoder.encode("a", 4, &v1);
+        assert_eq!(fresh.decode("a", p4).unwrap(), v1);
+    }
+
+    #[test]
+    fn bounded_test() {
+        let mut encoder = DeltaEncoder::new().max_blobs(2);
+        let v1 = sample(4096, 4);
+
+        // only the last versions that are not acknowledged are kept
+        for version in 1..=10 {
+            encoder.encode("a", version, &v1);
+        }
+        assert_eq!(encoder.sent["a"].len(), HISTORY);
+        encoder.ack("a", 1);
+        assert!(encoder.acked.is_empty());
+        encoder.ack("a", 10);
+        assert!(matches!(
+            encoder.encode("a", 11, &v1),
+            Payload::Delta { base: 10, .. }
+        ));
+
+        // `b` is used after `a`, so `a` is forgotten for `c`
+        encoder.encode("b", 1, &v1);
+        encoder.ack("b", 1);
+        encoder.encode("b", 2, &v1);
+        encoder.encode("c", 1, &v1);
+        assert_eq!(encoder.sent.len(), 2);
+        assert!(!encoder.acked.contains_key("a"));
+        assert!(matches!(encoder.encode("a", 12, &v1), Payload::Full { .. }));
+        assert!(!encoder.sent.contains_key("b"));
+
+        let mut decoder = DeltaDecoder::new().max_blobs(1);
+        let full = |version| Payload::Full {
+            version,
+            data: v1.clone(),
+        };
+        decoder.decode("a", full(1)).unwrap();
+        decoder.decode("b", full(1)).unwrap();
+        assert_eq!(decoder.versions.len(), 1);
+        assert!(decoder.versions.contains_key("b"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Discovery of servers in LAN using UDP multicast
+//!
+//! Servers announce themselves periodically to a multicast group with
+//! `Discovery::announce`. Clients listen to the group for a while with
+//! `Discovery::discover` (or `Client::discover` of the client crate) and
+//! get the list of servers that announced. This is useful for local play
+//! and on-premise installations that don't have fixed addresses.
+//!
+//! Sockets that listen to the group share its port with `SO_REUSEADDR`
+//! (and `SO_REUSEPORT` on Unix), so several clients on the same host can
+//! discover at the same time.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::discovery::Discovery;
+//! use std::time::Duration;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> std::io::Result<()> {
+//! // in server
+//! let discovery = Discovery::default();
+//! tokio::spawn(async move {
+//!     discovery.announce("my server", 20202, Duration::from_secs(1)).await
+//! });
+//!
+//! // in client
+//! let servers = Discovery::default().discover(Duration::from_secs(3)).await?;
+//! for server in servers {
+//!     println!("{} ({}) at {}", server.name, server.version, server.addr);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use prost::Message;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::{interval, timeout_at, Instant};
+
+use crate::protobuf::discovery::Announcement;
+use crate::VERSION;
+
+/// server found by `Discovery::discover`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerInfo {
+    /// name that the server announced
+    pub name: String,
+
+    /// version of CubbyConnect that the server runs
+    pub version: String,
+
+    /// address to connect to the server
+    pub addr: SocketAddr,
+}
+
+/// multicast group to announce and discover servers
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Discovery {
+    /// multicast address of the group
+    pub group: Ipv4Addr,
+
+    /// port of the group
+    pub port: u16,
+
+    /// interface to join the group
+    pub interface: Ipv4Addr,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            group: Ipv4Addr::new(239, 255, 20, 20),
+            port: 20203,
+            interface: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+impl Discovery {
+    /// announces this server as `name` every `period` forever.
+    /// `port` is the port that clients should connect to.
+    ///
+    /// This only returns when sending fails.
+    pub async fn announce<S: Into<String>>(
+        &self,
+        name: S,
+        port: u16,
+        period: Duration,
+    ) -> io::Result<()> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(self.interface, 0)).await?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let packet = Announcement {
+            name: name.into(),
+            version: VERSION.to_string(),
+            port: port.into(),
+        }
+        .encode_to_vec();
+
+        let mut ticker = interval(period);
+        loop {
+            ticker.tick().await;
+            socket
+                .send_to(&packet, SocketAddrV4::new(self.group, self.port))
+                .await?;
+        }
+    }
+
+    /// listens to the group for `duration` and returns servers that announced.
+    /// Each server shows up only once even if it announced several times.
+    pub async fn discover(&self, duration: Duration) -> io::Result<Vec<ServerInfo>> {
+        let socket = self.join()?;
+
+        let deadline = Instant::now() + duration;
+        let mut servers: Vec<ServerInfo> = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+
+            // packets that are not announcements are ignored
+            let info = match parse(&buf[..len], from) {
+                Some(info) => info,
+                None => continue,
+            };
+
+            if !servers.contains(&info) {
+                servers.push(info);
+            }
+        }
+
+        Ok(servers)
+    }
+
+    /// binds a socket to the port of the group, which other sockets can
+    /// bind too, and joins the group
+    fn join(&self) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.port).into())?;
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket.join_multicast_v4(self.group, self.interface)?;
+        Ok(socket)
+    }
+}
+
+/// makes `ServerInfo` from an announcement sent from `from`
+fn parse(packet: &[u8], from: SocketAddr) -> Option<ServerInfo> {
+    let announcement = Announcement::decode(packet).ok()?;
+    let port = u16::try_from(announcement.port).ok()?;
+
+    Some(ServerInfo {
+        name: announcement.name,
+        version: announcement.version,
+        addr: SocketAddr::new(from.ip(), port),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let from: SocketAddr = "192.168.0.2:5000".parse().unwrap();
+        let packet = Announcement {
+            name: "hello".to_string(),
+            version: VERSION.to_string(),
+            port: 20202,
+        }
+        .encode_to_vec();
+
+        assert_eq!(
+            parse(&packet, from),
+            Some(ServerInfo {
+                name: "hello".to_string(),
+                version: VERSION.to_string(),
+                addr: "192.168.0.2:20202".parse().unwrap(),
+            })
+        );
+        assert_eq!(parse(b"garbage", from), None);
+
+        let packet = Announcement {
+            name: "hello".to_string(),
+            version: VERSION.to_string(),
+            port: 100_000,
+        }
+        .encode_to_vec();
+        assert_eq!(parse(&packet, from), None);
+    }
+
+    #[tokio::test]
+    async fn shared_port_test() -> io::Result<()> {
+        // two clients on the same host discover at the same time
+        let discovery = Discovery {
+            port: 0,
+            ..Discovery::default()
+        };
+        let first = match discovery.join() {
+            Ok(socket) => socket,
+            // no multicast route in this environment
+            Err(e) if e.kind() != io::ErrorKind::AddrInUse => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let discovery = Discovery {
+            port: first.local_addr()?.port(),
+            ..discovery
+        };
+        let second = discovery.join()?;
+        assert_eq!(first.local_addr()?, second.local_addr()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+//! Message envelope with headers
+//!
+//! `Envelope<T>` wraps a message with `Headers`: correlation id, timestamp,
+//! content type and custom key/values. Middleware like tracing, dedup and
+//! deadlines read and attach their data there, instead of changing the
+//! message itself.
+//!
+//! On the wire, an envelope is a protobuf message that carries the headers
+//! and the encoded payload. `EnvelopeLayer` decodes frames into
+//! `Envelope<Vec<u8>>`, stamping the time of arrival when the sender did not,
+//! and starting a trace (see `trace_id`) when the message has none.
+//! `Envelope::into_request` turns it into a `Request`, so that handler
+//! functions can take `Headers`, `ContentType` and `TraceId` as arguments.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::envelope::{Envelope, EnvelopeLayer};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//! use prost::DecodeError;
+//!
+//! async fn handle(env: Envelope<Vec<u8>>) -> Result<(), DecodeError> {
+//!     assert_eq!(env.headers.correlation_id, Some(7));
+//!     assert_eq!(env.headers.get("region"), Some("kr"));
+//!     assert!(env.headers.timestamp.is_some());
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), DecodeError> {
+//! let mut env = Envelope::new(b"payload".to_vec());
+//! env.headers.correlation_id = Some(7);
+//! env.headers.insert("region", "kr");
+//!
+//! let handler = apply!(EnvelopeLayer to handle);
+//! handler.call(env.encode()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::{err, ok, Either, Ready};
+use prost::{DecodeError, Message};
+
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+use crate::protobuf::envelope as proto;
+use crate::router::ContentType;
+use crate::trace_id::TraceId;
+
+/// headers of a message
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Headers {
+    /// id that relates a response to its request
+    pub correlation_id: Option<u64>,
+
+    /// when the message was sent (or received, when the sender did not tell)
+    pub timestamp: Option<SystemTime>,
+
+    /// content type of the payload, like `protobuf` or `json`
+    pub content_type: Option<String>,
+
+    /// type name of the message, used by `router::RouterLayer`
+    pub message_type: Option<String>,
+
+    /// trace of the request that caused the message, which is kept when
+    /// the message is relayed to other connections or nodes
+    pub trace_id: Option<TraceId>,
+
+    /// custom key/values
+    pub custom: BTreeMap<String, String>,
+}
+
+impl Headers {
+    /// returns empty headers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns the custom header of `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.custom.get(key).map(String::as_str)
+    }
+
+    /// sets a custom header.
+    /// The old value of `key` is returned.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Option<String> {
+        self.custom.insert(key.into(), value.into())
+    }
+
+    /// removes a custom header
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.custom.remove(key)
+    }
+}
+
+impl FromContext for Headers {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Headers>().cloned()
+    }
+}
+
+/// message with its headers
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Envelope<T> {
+    pub headers: Headers,
+    pub msg: T,
+}
+
+impl<T> Envelope<T> {
+    /// returns an envelope of `msg` without headers
+    pub fn new(msg: T) -> Self {
+        Self {
+            headers: Headers::new(),
+            msg,
+        }
+    }
+
+    /// returns an envelope of `msg` with `headers`
+    pub fn with_headers(headers: Headers, msg: T) -> Self {
+        Self { headers, msg }
+    }
+
+    /// changes the message, keeping the headers
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Envelope<U> {
+        Envelope {
+            headers: self.headers,
+            msg: f(self.msg),
+        }
+    }
+
+    /// turns into a `Request` that has `Headers` (and `ContentType` and
+    /// `TraceId` if any) in its context
+    pub fn into_request(self) -> Request<T> {
+        let mut req = Request::new(self.msg);
+        if let Some(content_type) = &self.headers.content_type {
+            req.context.insert(ContentType::new(content_type.clone()));
+        }
+        if let Some(trace_id) = self.headers.trace_id {
+            req.context.insert(trace_id);
+        }
+        req.context.insert(self.headers);
+        req
+    }
+}
+
+/// bytes of the payload, so that envelopes can be relayed with their headers
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Envelope<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.msg.as_ref()
+    }
+}
+
+impl Envelope<Vec<u8>> {
+    /// encodes into bytes that are sent as a frame
+    pub fn encode(&self) -> Vec<u8> {
+        let timestamp = self
+            .headers
+            .timestamp
+            .map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64);
+
+        proto::Envelope {
+            correlation_id: self.headers.correlation_id,
+            timestamp,
+            content_type: self.headers.content_type.clone(),
+            message_type: self.headers.message_type.clone(),
+            headers: self.headers.custom.clone().into_iter().collect(),
+            payload: Some(self.msg.clone()),
+            trace_id: self.headers.trace_id.map(|id| id.0.to_be_bytes().to_vec()),
+        }
+        .encode_to_vec()
+    }
+
+    /// decodes bytes made by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let env = proto::Envelope::decode(bytes)?;
+        let trace_id = env
+            .trace_id
+            .map(|id| {
+                <[u8; 16]>::try_from(id.as_slice())
+                    .map(|id| TraceId(u128::from_be_bytes(id)))
+                    .map_err(|_| DecodeError::new("trace id is not 16 bytes"))
+            })
+            .transpose()?;
+        let headers = Headers {
+            correlation_id: env.correlation_id,
+            timestamp: env
+                .timestamp
+                .map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+            content_type: env.content_type,
+            message_type: env.message_type,
+            trace_id,
+            custom: env.headers.into_iter().collect(),
+        };
+        Ok(Self::with_headers(headers, env.payload.unwrap_or_default()))
+    }
+}
+
+/// `Layer` that builds `EnvelopeHandler`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnvelopeLayer;
+
+impl<H> Layer<Vec<u8>, H> for EnvelopeLayer
+where
+    H: Handler<Envelope<Vec<u8>>>,
+    H::Error: From<DecodeError>,
+{
+    type Next = Envelope<Vec<u8>>;
+    type Error = H::Error;
+    type Handler = EnvelopeHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(EnvelopeHandler { prev })
+    }
+}
+
+/// `Handler` that decodes frames into `Envelope`
+pub struct EnvelopeHandler<H> {
+    prev: H,
+}
+
+impl<H> Handler<Vec<u8>> for EnvelopeHandler<H>
+where
+    H: Handler<Envelope<Vec<u8>>>,
+    H::Error: From<DecodeError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, msg: Vec<u8>) -> Self::Future {
+        match Envelope::decode(&msg) {
+            Ok(mut env) => {
+                env.headers.timestamp.get_or_insert_with(SystemTime::now);
+                env.headers.trace_id.get_or_insert_with(TraceId::new);
+                Either::Left(self.prev.call(env))
+            }
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode_test() -> Result<(), DecodeError> {
+        let mut headers = Headers::new();
+        headers.correlation_id = Some(3);
+        headers.timestamp = Some(UNIX_EPOCH + Duration::from_millis(1_600_000_000_123));
+        headers.content_type = Some("json".to_string());
+        headers.message_type = Some("chat".to_string());
+        headers.trace_id = Some(TraceId(0x0af7651916cd43dd8448eb211c80319c));
+        headers.insert("deadline", "100");
+        assert_eq!(headers.insert("deadline", "200").as_deref(), Some("100"));
+
+        let env = Envelope::with_headers(headers, b"{}".to_vec());
+        assert_eq!(Envelope::decode(&env.encode())?, env);
+        assert_eq!(
+            Envelope::decode(&Envelope::new(Vec::new()).encode())?,
+            Envelope::new(Vec::new())
+        );
+        assert!(Envelope::decode(&[0xff]).is_err());
+
+        // a trace id of another length
+        let env = proto::Envelope {
+            trace_id: Some(vec![1; 8]),
+            ..Default::default()
+        };
+        assert!(Envelope::decode(&env.encode_to_vec()).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn layer_test() -> Result<(), DecodeError> {
+        async fn timestamp(env: Envelope<Vec<u8>>) -> Result<Option<SystemTime>, DecodeError> {
+            Ok(env.headers.timestamp)
+        }
+
+        let handler = connect(EnvelopeLayer, timestamp).await?;
+        let before = SystemTime::now();
+        let stamped = handler.call(Envelope::new(vec![1]).encode()).await?;
+        assert!(stamped.unwrap() >= before - Duration::from_millis(1));
+
+        // timestamp of the sender is kept
+        let mut env = Envelope::new(vec![1]);
+        env.headers.timestamp = Some(UNIX_EPOCH);
+        assert_eq!(handler.call(env.encode()).await?, Some(UNIX_EPOCH));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_id_test() -> Result<(), DecodeError> {
+        async fn trace_id(env: Envelope<Vec<u8>>) -> Result<Option<TraceId>, DecodeError> {
+            Ok(TraceId::from_context(&env.into_request().context))
+        }
+
+        // a trace starts where the message has none
+        let handler = connect(EnvelopeLayer, trace_id).await?;
+        let first = handler.call(Envelope::new(vec![1]).encode()).await?;
+        let second = handler.call(Envelope::new(vec![1]).encode()).await?;
+        assert!(first.is_some() && first != second);
+
+        // and the trace of the sender is kept
+        let mut env = Envelope::new(vec![1]);
+        env.headers.trace_id = first;
+        assert_eq!(handler.call(env.encode()).await?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn into_request_test() {
+        let mut env = Envelope::new(1u32).map(|n| n + 1);
+        env.headers.content_type = Some("protobuf".to_string());
+        env.headers.insert("user", "cubby");
+
+        let req = env.into_request();
+        assert_eq!(req.msg, 2);
+        assert_eq!(
+            ContentType::from_context(&req.context),
+            Some(ContentType::new("protobuf"))
+        );
+        let headers = Headers::from_context(&req.context).unwrap();
+        assert_eq!(headers.get("user"), Some("cubby"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Error of whole handler chains
+//!
+//! Layers fail with their own error types (`TimedOut`, `RateLimited`, ...),
+//! and require the error of the next handler to convert from them
+//! (`H::Error: From<TimedOut>`). A chain of many layers needs an error type
+//! that converts from all of them. `CubbyError` is such a type: it converts
+//! from every error of this crate with `From`, and from other errors with
+//! `CubbyError::other`.
+//!
+//! Handlers of other error types can be put into a chain of `CubbyError`
+//! with `middleware::map_err::ErrIntoLayer` or `MapErrLayer`, and a whole
+//! chain converts every error into it with `apply!(... to handler into
+//! CubbyError)`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::error::CubbyError;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::filter::FilterLayer;
+//! use cubby_connect_server_core::middleware::timeout::TimeoutLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(msg: String) -> Result<usize, CubbyError> {
+//!     Ok(msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CubbyError> {
+//! let handler = apply!(
+//!     TimeoutLayer::new(Duration::from_secs(1)),
+//!     FilterLayer::new(|msg: &String| !msg.is_empty()).reject()
+//!     to chat
+//! );
+//! assert!(matches!(handler.call(String::new()).await, Err(CubbyError::Filtered)));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::auth::AuthError;
+use crate::codec::compress::CompressError;
+use crate::codec::CodecError;
+use crate::context::Missing;
+use crate::middleware::buffer::BufferClosed;
+use crate::middleware::filter::Filtered;
+use crate::middleware::rate_limit::RateLimited;
+use crate::middleware::timeout::TimedOut;
+use crate::router::NoRoute;
+use crate::session::SessionError;
+use crate::warmup::NotReady;
+use crate::watchdog::Overloaded;
+
+/// error that any error of this crate converts into
+#[derive(Debug)]
+pub enum CubbyError {
+    TimedOut,
+    RateLimited,
+    Overloaded,
+    Filtered,
+    BufferClosed,
+    NotReady,
+
+    /// a handler argument is missing in the context
+    Missing(&'static str),
+
+    /// no route for the content type
+    NoRoute(Option<String>),
+
+    Codec(CodecError),
+    Compress(CompressError),
+    Decode(prost::DecodeError),
+    Io(io::Error),
+
+    /// error of other crates or applications
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl CubbyError {
+    /// wraps an error that doesn't have its own variant
+    pub fn other<E>(e: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        CubbyError::Other(Box::new(e))
+    }
+}
+
+impl Display for CubbyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CubbyError::TimedOut => TimedOut.fmt(f),
+            CubbyError::RateLimited => RateLimited.fmt(f),
+            CubbyError::Overloaded => Overloaded.fmt(f),
+            CubbyError::Filtered => Filtered.fmt(f),
+            CubbyError::BufferClosed => BufferClosed.fmt(f),
+            CubbyError::NotReady => NotReady.fmt(f),
+            CubbyError::Missing(name) => Missing(name).fmt(f),
+            CubbyError::NoRoute(content_type) => NoRoute(content_type.clone()).fmt(f),
+            CubbyError::Codec(e) => e.fmt(f),
+            CubbyError::Compress(e) => e.fmt(f),
+            CubbyError::Decode(e) => e.fmt(f),
+            CubbyError::Io(e) => e.fmt(f),
+            CubbyError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CubbyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CubbyError::Codec(e) => Some(e),
+            CubbyError::Compress(e) => Some(e),
+            CubbyError::Decode(e) => Some(e),
+            CubbyError::Io(e) => Some(e),
+            CubbyError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_from_unit {
+    ($($error:ident),+) => {
+        $(
+            impl From<$error> for CubbyError {
+                fn from(_: $error) -> Self {
+                    CubbyError::$error
+                }
+            }
+        )+
+    };
+}
+
+impl_from_unit!(
+    TimedOut,
+    RateLimited,
+    Overloaded,
+    Filtered,
+    BufferClosed,
+    NotReady
+);
+
+impl From<Missing> for CubbyError {
+    fn from(e: Missing) -> Self {
+        CubbyError::Missing(e.0)
+    }
+}
+
+impl From<NoRoute> for CubbyError {
+    fn from(e: NoRoute) -> Self {
+        CubbyError::NoRoute(e.0)
+    }
+}
+
+impl From<CodecError> for CubbyError {
+    fn from(e: CodecError) -> Self {
+        CubbyError::Codec(e)
+    }
+}
+
+impl From<CompressError> for CubbyError {
+    fn from(e: CompressError) -> Self {
+        CubbyError::Compress(e)
+    }
+}
+
+impl From<prost::DecodeError> for CubbyError {
+    fn from(e: prost::DecodeError) -> Self {
+        CubbyError::Decode(e)
+    }
+}
+
+impl From<io::Error> for CubbyError {
+    fn from(e: io::Error) -> Self {
+        CubbyError::Io(e)
+    }
+}
+
+impl From<AuthError> for CubbyError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Io(e) => CubbyError::Io(e),
+            e => CubbyError::other(e),
+        }
+    }
+}
+
+impl From<SessionError> for CubbyError {
+    fn from(e: SessionError) -> Self {
+        CubbyError::other(e)
+    }
+}
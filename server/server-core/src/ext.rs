@@ -0,0 +1,269 @@
+//! Combinators of `Handler` and `Layer`
+//!
+//! `HandlerExt` and `LayerExt` are implemented for every handler and layer.
+//! They tweak a pipeline fluently with the general purpose middleware
+//! (`map_err`, `and_then`, `timeout`, `inspect`) instead of another
+//! `connect` with a layer, or a new handler type:
+//!
+//! - `HandlerExt` wraps a handler right away
+//! - `LayerExt` wraps every handler that a layer builds, so the layer can
+//!   still be passed to `apply!`
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::error::CubbyError;
+//! use cubby_connect_server_core::ext::{HandlerExt, LayerExt};
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::concurrency_limit::ConcurrencyLimitLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn parse(msg: String) -> Result<u32, std::num::ParseIntError> {
+//!     msg.parse()
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CubbyError> {
+//! let handler = fn_handler(parse)
+//!     .map_err(CubbyError::other)
+//!     .and_then(|n| async move { Ok(n * 2) })
+//!     .inspect(|msg: &String| println!("parse {msg}"))
+//!     .timeout(Duration::from_secs(1));
+//! assert_eq!(handler.call(String::from("21")).await?, 42);
+//!
+//! // the same on handlers built by a layer
+//! let layer = ConcurrencyLimitLayer::new(16).timeout(Duration::from_secs(1));
+//! let handler = apply!(layer to handler);
+//! assert_eq!(handler.call(String::from("1")).await?, 2);
+//!
+//! // handlers of different types in one `Vec`
+//! let handlers = vec![handler.boxed(), fn_handler(|_: String| async { Ok(0) }).boxed()];
+//! assert_eq!(handlers[1].call(String::from("2")).await?, 0);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+
+use crate::handler::{BoxHandler, Handler};
+use crate::layer::Layer;
+use crate::middleware::and_then::{and_then, AndThenHandler};
+use crate::middleware::inspect::{inspect, InspectHandler};
+use crate::middleware::map_err::{map_err, MapErrHandler};
+use crate::middleware::timeout::{timeout, TimedOut, TimeoutHandler};
+
+/// combinators of every `Handler`
+pub trait HandlerExt<T>: Handler<T> + Sized {
+    /// converts errors with `f`
+    fn map_err<F, E>(self, f: F) -> MapErrHandler<F, Self>
+    where
+        F: Fn(Self::Error) -> E + Clone,
+    {
+        map_err(self, f)
+    }
+
+    /// passes outputs to the async function `f`
+    fn and_then<F, Fut, O>(self, f: F) -> AndThenHandler<F, Self>
+    where
+        F: FnOnce(Self::Output) -> Fut + Clone,
+        Fut: Future<Output = Result<O, Self::Error>>,
+    {
+        and_then(self, f)
+    }
+
+    /// erases the type of the handler
+    fn boxed(self) -> BoxHandler<T, Self::Output, Self::Error>
+    where
+        Self: 'static,
+        Self::Future: 'static,
+    {
+        BoxHandler::new(self)
+    }
+
+    /// fails messages that are not processed in `duration`
+    fn timeout(self, duration: Duration) -> TimeoutHandler<Self>
+    where
+        Self::Error: From<TimedOut>,
+    {
+        timeout(self, duration)
+    }
+
+    /// calls `f` with every message first
+    fn inspect<F>(self, f: F) -> InspectHandler<F, Self>
+    where
+        F: Fn(&T),
+    {
+        inspect(self, f)
+    }
+}
+
+impl<T, H: Handler<T>> HandlerExt<T> for H {}
+
+/// combinators of every `Layer`, applied to the handlers it builds
+pub trait LayerExt<T, H>: Layer<T, H> + Sized
+where
+    H: Handler<Self::Next>,
+{
+    /// passes every handler built by the layer to `f`
+    fn map_handler<F, H2>(self, f: F) -> MapHandlerLayer<Self, F>
+    where
+        F: Fn(Self::Handler) -> H2,
+        H2: Handler<T>,
+    {
+        MapHandlerLayer {
+            layer: self,
+            f: Arc::new(f),
+        }
+    }
+
+    /// converts errors of built handlers with `f`
+    fn map_err<F, E>(
+        self,
+        f: F,
+    ) -> MapHandlerLayer<Self, impl Fn(Self::Handler) -> MapErrHandler<F, Self::Handler>>
+    where
+        F: Fn(Self::Error) -> E + Clone,
+    {
+        self.map_handler(move |handler| map_err(handler, f.clone()))
+    }
+
+    /// passes outputs of built handlers to the async function `f`
+    fn and_then<F, Fut, O>(
+        self,
+        f: F,
+    ) -> MapHandlerLayer<Self, impl Fn(Self::Handler) -> AndThenHandler<F, Self::Handler>>
+    where
+        F: FnOnce(<Self::Handler as Handler<T>>::Output) -> Fut + Clone,
+        Fut: Future<Output = Result<O, Self::Error>>,
+    {
+        self.map_handler(move |handler| and_then(handler, f.clone()))
+    }
+
+    /// fails messages that built handlers don't process in `duration`
+    fn timeout(
+        self,
+        duration: Duration,
+    ) -> MapHandlerLayer<Self, impl Fn(Self::Handler) -> TimeoutHandler<Self::Handler>>
+    where
+        Self::Error: From<TimedOut>,
+    {
+        self.map_handler(move |handler| timeout(handler, duration))
+    }
+
+    /// calls `f` with every message of built handlers first
+    fn inspect<F>(
+        self,
+        f: F,
+    ) -> MapHandlerLayer<Self, impl Fn(Self::Handler) -> InspectHandler<F, Self::Handler>>
+    where
+        F: Fn(&T) + Clone,
+    {
+        self.map_handler(move |handler| inspect(handler, f.clone()))
+    }
+}
+
+impl<T, H, L> LayerExt<T, H> for L
+where
+    L: Layer<T, H>,
+    H: Handler<L::Next>,
+{
+}
+
+/// `Layer` that passes handlers built by another layer to a function
+pub struct MapHandlerLayer<L, F> {
+    layer: L,
+    f: Arc<F>,
+}
+
+impl<T, H, H2, L, F> Layer<T, H> for MapHandlerLayer<L, F>
+where
+    L: Layer<T, H>,
+    L::Future: 'static,
+    H: Handler<L::Next>,
+    F: Fn(L::Handler) -> H2 + 'static,
+    H2: Handler<T>,
+{
+    type Next = L::Next;
+    type Error = H2::Error;
+    type Handler = H2;
+    type InitError = L::InitError;
+    type Future = LocalBoxFuture<'static, Result<H2, L::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        let future = self.layer.new_handler(prev);
+        let f = self.f.clone();
+        Box::pin(async move { Ok(f(future.await?)) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+    use crate::middleware::inspect::InspectLayer;
+
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Error {
+        TimedOut,
+        Negative(i64),
+    }
+
+    impl From<TimedOut> for Error {
+        fn from(_: TimedOut) -> Self {
+            Error::TimedOut
+        }
+    }
+
+    async fn sleep(secs: i64) -> Result<i64, Error> {
+        if secs < 0 {
+            return Err(Error::Negative(secs));
+        }
+        tokio::time::sleep(Duration::from_secs(secs as u64)).await;
+        Ok(secs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handler_ext_test() -> Result<(), String> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ = seen.clone();
+        let handler = fn_handler(sleep)
+            .timeout(Duration::from_secs(5))
+            .inspect(move |secs: &i64| seen_.lock().unwrap().push(*secs))
+            .and_then(|secs| async move { Ok(secs * 10) })
+            .map_err(|e| format!("{e:?}"))
+            .boxed();
+
+        assert_eq!(handler.call(1).await?, 10);
+        assert_eq!(handler.call(-1).await, Err(String::from("Negative(-1)")));
+        assert_eq!(handler.call(10).await, Err(String::from("TimedOut")));
+        assert_eq!(*seen.lock().unwrap(), vec![1, -1, 10]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_ext_test() -> Result<(), String> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ = seen.clone();
+        let layer = InspectLayer::new(|_: &i64| {})
+            .timeout(Duration::from_secs(5))
+            .inspect(move |secs: &i64| seen_.lock().unwrap().push(*secs))
+            .and_then(|secs| async move { Ok(secs + 1) })
+            .map_err(|e| format!("{e:?}"));
+        let handler = connect(layer, sleep).await.map_err(|e| format!("{e:?}"))?;
+
+        assert_eq!(handler.call(1).await?, 2);
+        assert_eq!(handler.call(10).await, Err(String::from("TimedOut")));
+        assert_eq!(*seen.lock().unwrap(), vec![1, 10]);
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Feature flags pushed to clients
+//!
+//! Applications gate features by flags that the server tells, instead of
+//! asking a separate config service. `FlagStore` has the flags of the
+//! server. Each connection subscribes to it with `FlagStore::watch`, sends
+//! the flags right after the handshake, and sends them again whenever a
+//! flag changes, which `Server::flags` does for every connection.
+//!
+//! Flags are sent as an envelope of the type `FLAGS_TYPE` (a control
+//! frame), which has every flag and replaces the flags that the client had.
+//! So a client that missed an update only needs the next one. Clients tell
+//! it from other envelopes, and decode it, with `feature_flags::decode` of
+//! the client crate.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::feature_flags::{FlagStore, Flags, FLAGS_TYPE};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let store = FlagStore::new();
+//! store.set("new_inventory", "true");
+//!
+//! // a connection sends the flags after the handshake
+//! let mut watch = store.watch();
+//! let flags: Flags = watch.changed().await.unwrap();
+//! assert!(flags.is_enabled("new_inventory"));
+//! let env = flags.to_envelope();
+//! assert_eq!(env.headers.message_type.as_deref(), Some(FLAGS_TYPE));
+//!
+//! // and sends them again when a flag changes
+//! store.set("max_party", "8");
+//! let flags = watch.changed().await.unwrap();
+//! assert_eq!(flags.get("max_party"), Some("8"));
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use prost::Message;
+use tokio::sync::watch;
+
+use crate::context::{Context, FromContext};
+use crate::envelope::Envelope;
+use crate::protobuf::feature_flags as proto;
+
+/// message type of envelopes with flags
+pub const FLAGS_TYPE: &str = "feature_flags.Flags";
+
+/// values of feature flags by name
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Flags {
+    flags: BTreeMap<String, String>,
+}
+
+impl Flags {
+    /// returns flags without any flag
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns the value of `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
+    }
+
+    /// returns true if `name` is `true`, `1` or `on`
+    pub fn is_enabled(&self, name: &str) -> bool {
+        matches!(self.get(name), Some("true" | "1" | "on"))
+    }
+
+    /// iterates flags in order of name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.flags.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    /// encodes the flags into an envelope of `FLAGS_TYPE`
+    pub fn to_envelope(&self) -> Envelope<Vec<u8>> {
+        let msg = proto::Flags {
+            flags: self.flags.clone().into_iter().collect(),
+        };
+        let mut env = Envelope::new(msg.encode_to_vec());
+        env.headers.message_type = Some(FLAGS_TYPE.to_string());
+        env
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Flags {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self {
+            flags: iter
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        }
+    }
+}
+
+/// flags that the connection was told last
+impl FromContext for Flags {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Flags>().cloned()
+    }
+}
+
+/// feature flags of the server.
+/// Clones share the same flags.
+#[derive(Clone)]
+pub struct FlagStore {
+    flags: Arc<watch::Sender<Flags>>,
+}
+
+impl Default for FlagStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlagStore {
+    /// returns a store without any flag
+    pub fn new() -> Self {
+        Self::with_flags(Flags::new())
+    }
+
+    /// returns a store with `flags`
+    pub fn with_flags(flags: Flags) -> Self {
+        Self {
+            flags: Arc::new(watch::channel(flags).0),
+        }
+    }
+
+    /// sets `name` to `value`.
+    /// Watchers are not notified if the value is the same.
+    pub fn set<K: Into<String>, V: Into<String>>(&self, name: K, value: V) {
+        let (name, value) = (name.into(), value.into());
+        self.flags.send_if_modified(|flags| {
+            flags.flags.insert(name, value.clone()).as_ref() != Some(&value)
+        });
+    }
+
+    /// removes `name`
+    pub fn remove(&self, name: &str) {
+        self.flags
+            .send_if_modified(|flags| flags.flags.remove(name).is_some());
+    }
+
+    /// replaces every flag with `flags`
+    pub fn replace(&self, flags: Flags) {
+        self.flags.send_if_modified(|old| {
+            let modified = *old != flags;
+            *old = flags;
+            modified
+        });
+    }
+
+    /// returns the current flags
+    pub fn flags(&self) -> Flags {
+        self.flags.borrow().clone()
+    }
+
+    /// returns a watcher of changes, which returns the current flags first
+    pub fn watch(&self) -> FlagWatch {
+        let mut flags = self.flags.subscribe();
+        flags.mark_changed();
+        FlagWatch { flags }
+    }
+}
+
+/// watcher of changes of a `FlagStore`
+pub struct FlagWatch {
+    flags: watch::Receiver<Flags>,
+}
+
+impl FlagWatch {
+    /// waits until the flags change, and returns them.
+    /// Changes in between are merged into the latest flags.
+    /// Returns `None` if every clone of the store is dropped.
+    ///
+    /// This is cancel safe, so it can be used in `select!`.
+    pub async fn changed(&mut self) -> Option<Flags> {
+        self.flags.changed().await.ok()?;
+        Some(self.flags.borrow_and_update().clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_test() {
+        let store = FlagStore::with_flags([("a", "1")].into_iter().collect());
+        let mut watch = store.watch();
+        assert_eq!(watch.changed().await.unwrap().get("a"), Some("1"));
+
+        // the same value is not a change, and changes are merged
+        store.set("a", "1");
+        store.set("b", "off");
+        store.remove("a");
+        let flags = watch.changed().await.unwrap();
+        assert_eq!(flags.iter().collect::<Vec<_>>(), vec![("b", "off")]);
+        assert!(!flags.is_enabled("b"));
+
+        store.replace(flags);
+        let timeout = tokio::time::timeout(Duration::from_secs(1), watch.changed()).await;
+        assert!(timeout.is_err());
+
+        drop(store);
+        assert_eq!(watch.changed().await, None);
+    }
+
+    #[test]
+    fn envelope_test() {
+        let flags: Flags = [("x", "on"), ("y", "3")].into_iter().collect();
+        let env = flags.to_envelope();
+        assert_eq!(env.headers.message_type.as_deref(), Some(FLAGS_TYPE));
+        let msg = proto::Flags::decode(env.msg.as_slice()).unwrap();
+        assert_eq!(msg.flags.into_iter().collect::<Flags>(), flags);
+    }
+}
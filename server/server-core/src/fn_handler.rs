@@ -27,21 +27,14 @@ use std::marker::PhantomData;
 use crate::handler::{Handler, IntoHandler};
 
 /// `Handler` for closures/functions for simple definition of use.
-/// The type of function would be as: `async fn<T>(T) -> Result<(), Err>`
-pub struct FnHandler<F, T, Fut, Err>
-where
-    F: Fn(T) -> Fut,
-    Fut: Future<Output = Result<(), Err>>,
-{
+/// The type of function would be as: `async fn<T, O>(T) -> Result<O, Err>`
+/// where `O` is the output (response) of the handler.
+pub struct FnHandler<F, T, Fut, Err> {
     f: F,
-    _marker: PhantomData<fn(T)>,
+    _marker: PhantomData<fn(T) -> (Fut, Err)>,
 }
 
-impl<F, T, Fut, Err> FnHandler<F, T, Fut, Err>
-where
-    F: Fn(T) -> Fut,
-    Fut: Future<Output = Result<(), Err>>,
-{
+impl<F, T, Fut, Err> FnHandler<F, T, Fut, Err> {
     fn new(f: F) -> Self {
         Self {
             f,
@@ -51,11 +44,12 @@ where
 }
 
 /// This would simply call the function
-impl<F, T, Fut, Err> Handler<T> for FnHandler<F, T, Fut, Err>
+impl<F, T, Fut, O, Err> Handler<T> for FnHandler<F, T, Fut, Err>
 where
     F: Fn(T) -> Fut,
-    Fut: Future<Output = Result<(), Err>>,
+    Fut: Future<Output = Result<O, Err>>,
 {
+    type Output = O;
     type Error = Err;
     type Future = Fut;
 
@@ -64,10 +58,10 @@ where
     }
 }
 
-impl<F, T, Fut, Err> IntoHandler<FnHandler<F, T, Fut, Err>, T> for F
+impl<F, T, Fut, O, Err> IntoHandler<FnHandler<F, T, Fut, Err>, T> for F
 where
     F: Fn(T) -> Fut,
-    Fut: Future<Output = Result<(), Err>>,
+    Fut: Future<Output = Result<O, Err>>,
 {
     fn into_handler(self) -> FnHandler<F, T, Fut, Err> {
         FnHandler::new(self)
@@ -76,10 +70,10 @@ where
 
 /// public function wrapper of `FnPipe`
 /// use this to change function into `Pipe`
-pub fn fn_handler<F, T, Fut, Err>(f: F) -> FnHandler<F, T, Fut, Err>
+pub fn fn_handler<F, T, Fut, O, Err>(f: F) -> FnHandler<F, T, Fut, Err>
 where
     F: Fn(T) -> Fut,
-    Fut: Future<Output = Result<(), Err>>,
+    Fut: Future<Output = Result<O, Err>>,
 {
     FnHandler::new(f)
 }
@@ -105,4 +99,14 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn fn_handler_output_test() -> Result<(), ()> {
+        async fn length(s: &str) -> Result<usize, ()> {
+            Ok(s.len())
+        }
+
+        assert_eq!(fn_handler(length).call("hello").await?, 5);
+        Ok(())
+    }
 }
@@ -41,12 +41,39 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! Handlers built by `FnLayer` are not `Send`, so they cannot be moved into
+//! `tokio::spawn`. Use `send_fn_layer` for multi-threaded runtimes.
+//!
+//! ```
+//! use cubby_connect_server_core::fn_layer::send_fn_layer;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn echo<T>(t: T) -> Result<T, ()> {
+//!     Ok(t)
+//! }
+//!
+//! async fn print(s: &'static str) -> Result<(), ()> {
+//!     print!("{s}");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let e = apply!(send_fn_layer(echo) to print);
+//! tokio::spawn(async move { e.call("Hello, World!").await })
+//!     .await
+//!     .unwrap()?;
+//! # Ok(())
+//! # }
+//! ```
 
 use std::future::Future;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
-use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::future::{ok, BoxFuture, LocalBoxFuture, Ready};
 
 use crate::fn_handler::{fn_handler, FnHandler};
 use crate::handler::Handler;
@@ -54,8 +81,9 @@ use crate::layer::{IntoLayer, Layer};
 
 /// `PipeFactory` for closures/functions for simple definition of use.
 /// The type of function would be as: `async fn<T, U>(T) -> Result<U, Err>`
-/// This would be connected to other `Pipe` as: `async fn<U>(U) -> Result<(), Err>`
+/// This would be connected to other `Pipe` as: `async fn<U, O>(U) -> Result<O, Err>`
 /// It would be easier to know the data flow.
+/// The output `O` of the connected `Pipe` is returned as is.
 ///
 /// The lifetime is same as the closure.
 ///
@@ -88,14 +116,15 @@ where
     F: Fn(T1) -> Fut,
     Fut: Future<Output = Result<T2, Err>>,
     H: Handler<T2, Error = Err> + 'a,
+    H::Output: 'a,
 {
     type Next = T2;
     type Error = Err;
     #[allow(clippy::type_complexity)]
     type Handler = FnHandler<
-        Box<dyn Fn(T1) -> LocalBoxFuture<'a, Result<(), Err>> + 'a>,
+        Box<dyn Fn(T1) -> LocalBoxFuture<'a, Result<H::Output, Err>> + 'a>,
         T1,
-        LocalBoxFuture<'a, Result<(), Err>>,
+        LocalBoxFuture<'a, Result<H::Output, Err>>,
         Err,
     >;
     type InitError = Err;
@@ -113,10 +142,7 @@ where
         ok(fn_handler(Box::new(move |msg| {
             let prev_ = prev.clone();
             let f_ = f.clone();
-            Box::pin(async move {
-                prev_.call(f_(msg).await?).await?;
-                Ok(())
-            })
+            Box::pin(async move { prev_.call(f_(msg).await?).await })
         })))
     }
 }
@@ -126,6 +152,7 @@ where
     F: Fn(T1) -> Fut + 'a,
     Fut: Future<Output = Result<T2, Err>>,
     H: Handler<T2, Error = Err> + 'a,
+    H::Output: 'a,
 {
     fn into_layer(self) -> FnLayer<'a, F, T1, T2, Fut, Err> {
         FnLayer::new(self)
@@ -142,6 +169,81 @@ where
     FnLayer::new(f)
 }
 
+/// `Send + Sync` variant of `FnLayer`.
+/// Handlers built by this layer return `BoxFuture`,
+/// so they can be moved across threads (e.g. into `tokio::spawn`).
+///
+/// The function, messages, and previous handler should be `Send` as well.
+pub struct SendFnLayer<'a, F, T1, T2, Fut, Err>
+where
+    F: Fn(T1) -> Fut + Send + Sync + 'a,
+    Fut: Future<Output = Result<T2, Err>> + Send,
+{
+    f: Arc<F>,
+    _marker: PhantomData<&'a fn(T1) -> T2>,
+}
+
+impl<'a, F, T1, T2, Fut, Err> SendFnLayer<'a, F, T1, T2, Fut, Err>
+where
+    F: Fn(T1) -> Fut + Send + Sync + 'a,
+    Fut: Future<Output = Result<T2, Err>> + Send,
+{
+    fn new(f: F) -> Self {
+        Self {
+            f: Arc::new(f),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, F, T1, T2, Fut, Err, H> Layer<T1, H> for SendFnLayer<'a, F, T1, T2, Fut, Err>
+where
+    F: Fn(T1) -> Fut + Send + Sync + 'a,
+    Fut: Future<Output = Result<T2, Err>> + Send,
+    T1: Send + 'a,
+    Err: Send + 'a,
+    H: Handler<T2, Error = Err> + Send + Sync + 'a,
+    H::Output: Send + 'a,
+    H::Future: Send,
+{
+    type Next = T2;
+    type Error = Err;
+    #[allow(clippy::type_complexity)]
+    type Handler = FnHandler<
+        Box<dyn Fn(T1) -> BoxFuture<'a, Result<H::Output, Err>> + Send + Sync + 'a>,
+        T1,
+        BoxFuture<'a, Result<H::Output, Err>>,
+        Err,
+    >;
+    type InitError = Err;
+    type Future = Ready<Result<Self::Handler, Err>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        // same as `FnLayer`, `prev` should be shared between calls
+        let prev = Arc::new(prev);
+        let f = self.f.clone();
+
+        ok(fn_handler(Box::new(move |msg| {
+            let prev_ = prev.clone();
+            let f_ = f.clone();
+            Box::pin(async move {
+                let next = f_(msg).await?;
+                prev_.call(next).await
+            })
+        })))
+    }
+}
+
+/// public function wrapper of `SendFnLayer`
+/// use this to change function to `Layer` that can be sent to other threads
+pub fn send_fn_layer<'a, F, T1, T2, Fut, Err>(f: F) -> SendFnLayer<'a, F, T1, T2, Fut, Err>
+where
+    F: Fn(T1) -> Fut + Send + Sync + 'a,
+    Fut: Future<Output = Result<T2, Err>> + Send,
+{
+    SendFnLayer::new(f)
+}
+
 #[cfg(test)]
 mod test {
     use num_traits::PrimInt;
@@ -173,6 +275,17 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn output_test() -> Result<(), ()> {
+        async fn double(i: i32) -> Result<i32, ()> {
+            Ok(i * 2)
+        }
+
+        let handler = connect(plus_one, connect(plus_one, double).await?).await?;
+        assert_eq!(handler.call(1).await?, 6);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn plus_multi_times_test() -> Result<(), ()> {
         make_check!("5");
@@ -184,4 +297,20 @@ mod test {
         handler.call(2).await?;
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn send_test() -> Result<(), ()> {
+        async fn double(i: i32) -> Result<i32, ()> {
+            Ok(i * 2)
+        }
+
+        let handler = connect(
+            send_fn_layer(plus_one),
+            connect(send_fn_layer(plus_one), double).await?,
+        )
+        .await?;
+        let task = tokio::spawn(async move { handler.call(1).await });
+        assert_eq!(task.await.unwrap()?, 6);
+        Ok(())
+    }
 }
@@ -10,6 +10,7 @@
 //! struct Hello;
 //!
 //! impl<S: Display> Handler<S> for Hello {
+//!     type Output = ();
 //!     type Error = ();
 //!     type Future = Ready<Result<(), ()>>;
 //!
@@ -30,13 +31,21 @@
 
 use std::future::Future;
 
+use futures::future::LocalBoxFuture;
+
+pub use cubby_connect_server_macro::handler;
+
 /// This is a handler to send data easily using future
 pub trait Handler<T> {
+    /// response when processing is done.
+    /// It is `()` for handlers that don't respond.
+    type Output;
+
     /// error when processing
     type Error;
 
     /// future when building handler
-    type Future: Future<Output = Result<(), Self::Error>>;
+    type Future: Future<Output = Result<Self::Output, Self::Error>>;
 
     fn call(&self, msg: T) -> Self::Future;
 }
@@ -59,6 +68,37 @@ where
     }
 }
 
+/// `Handler` with its type erased.
+/// Handlers of different types (with the same input, output and error) can be
+/// stored together as this, e.g. in routers.
+pub struct BoxHandler<T, O, E> {
+    #[allow(clippy::type_complexity)]
+    f: Box<dyn Fn(T) -> LocalBoxFuture<'static, Result<O, E>>>,
+}
+
+impl<T, O, E> BoxHandler<T, O, E> {
+    /// erases the type of `handler`
+    pub fn new<H>(handler: H) -> Self
+    where
+        H: Handler<T, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        Self {
+            f: Box::new(move |msg| Box::pin(handler.call(msg))),
+        }
+    }
+}
+
+impl<T, O, E> Handler<T> for BoxHandler<T, O, E> {
+    type Output = O;
+    type Error = E;
+    type Future = LocalBoxFuture<'static, Result<O, E>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        (self.f)(msg)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::fmt::Display;
@@ -70,6 +110,7 @@ mod test {
     struct Check(String);
 
     impl<S: Display> Handler<S> for Check {
+        type Output = ();
         type Error = ();
         type Future = Ready<Result<(), ()>>;
 
@@ -85,4 +126,16 @@ mod test {
         handler.call("hello").await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn box_handler_test() -> Result<(), ()> {
+        let handlers: Vec<BoxHandler<&str, (), ()>> = vec![
+            BoxHandler::new(Check("hello".to_string())),
+            BoxHandler::new(crate::fn_handler::fn_handler(|_: &str| ok(()))),
+        ];
+        for handler in handlers {
+            handler.call("hello").await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,695 @@
+//! Handshake at the start of a connection
+//!
+//! Before any message, the client sends `ClientHello` and the server answers
+//! with `ServerHello` as frames (see `transport::write_frame`). The client
+//! offers the codecs it can use, and the server chooses one of its enabled
+//! codecs. The chosen codec is put into the context of the connection as
+//! `Negotiated`.
+//!
+//! `accept_with` and `connect_with` also negotiate the compression of frames
+//! in the same way. When there is no common compression, frames are not
+//! compressed (`Compression::None`).
+//!
+//! With `connect_as`, the client also tells what it is as `ClientInfo`
+//! (version of this crate and of the app, OS and architecture), so rollouts
+//! can be watched per version. `accept_hello` returns it to be put into the
+//! context of the connection, where handlers take it with `FromContext`.
+//! Clients of older versions do not send it.
+//!
+//! `accept_versioned` also checks `ClientInfo` with a
+//! `client_version::VersionPolicy`. A blocked client gets the reason in
+//! `ServerHello`, and fails with `HandshakeError::Blocked`.
+//!
+//! Both peers tell their `VERSION` and `PROTOCOL_VERSION` in the hellos.
+//! The connection speaks the older protocol version of the two, so it is
+//! incompatible when that is below `MIN_PROTOCOL_VERSION` of either peer.
+//! It is also incompatible when the client doesn't match the semver
+//! requirement of the policy (`VersionPolicy::require`). Then both peers
+//! fail with `HandshakeError::IncompatibleVersion`, which tells the versions
+//! of both and the requirement, instead of talking in a protocol that the
+//! other doesn't understand. Peers of older versions don't tell, and speak
+//! the first protocol version.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::codec::{Codecs, Negotiated, Protobuf};
+//! use cubby_connect_server_core::handshake::{accept, connect};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::handshake::HandshakeError> {
+//! let (mut client, mut server) = tokio::io::duplex(1024);
+//! let codecs = Codecs::<String>::new().with(Protobuf);
+//!
+//! let (server, client) = tokio::join!(
+//!     accept(&mut server, &codecs),
+//!     connect(&mut client, &["json", "protobuf"])
+//! );
+//! assert_eq!(server?, Negotiated("protobuf"));
+//! assert_eq!(client?, "protobuf");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::hash::Hash;
+use std::io;
+
+use prost::Message;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::client_version::{Blocked, Version, VersionPolicy, VersionReq};
+use crate::codec::compress::{Compression, DeflateContext};
+use crate::codec::{Codecs, Negotiated};
+use crate::context::{Context, FromContext};
+use crate::protobuf::handshake::{self as proto, ClientHello, ServerHello};
+use crate::transport::{read_frame, write_frame};
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, VERSION};
+
+/// protocol version of peers that don't tell theirs
+const FIRST_PROTOCOL_VERSION: u32 = 1;
+
+/// error during handshake
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// error from the stream
+    Io(io::Error),
+
+    /// the peer sent something that is not a handshake
+    Malformed,
+
+    /// no codec offered by the client is enabled in the server.
+    /// It has the codecs that the server supports.
+    NoCommonCodec(Vec<String>),
+
+    /// the version of the client is not allowed by the server
+    Blocked(Blocked),
+
+    /// the versions of the client and the server don't work together
+    IncompatibleVersion(IncompatibleVersion),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "io error: {e}"),
+            HandshakeError::Malformed => write!(f, "malformed handshake"),
+            HandshakeError::NoCommonCodec(supported) => {
+                write!(f, "no common codec (server supports {supported:?})")
+            }
+            HandshakeError::Blocked(blocked) => write!(f, "blocked: {blocked}"),
+            HandshakeError::IncompatibleVersion(incompatible) => write!(f, "{incompatible}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+/// versions of a client and a server that don't work together
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct IncompatibleVersion {
+    /// version of cubby-connect of the client (`0.0.0` if it didn't tell)
+    pub client_version: String,
+
+    /// protocol version of the client
+    pub client_protocol: u32,
+
+    /// version of cubby-connect of the server (`0.0.0` if it didn't tell)
+    pub server_version: String,
+
+    /// protocol version of the server
+    pub server_protocol: u32,
+
+    /// semver requirement of the server that the client doesn't match.
+    /// `None` when only the protocol versions are incompatible.
+    pub requirement: Option<String>,
+}
+
+impl Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "client {} (protocol {}) is incompatible with server {} (protocol {})",
+            self.client_version, self.client_protocol, self.server_version, self.server_protocol
+        )?;
+        match &self.requirement {
+            Some(requirement) => write!(f, ", which requires {requirement}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// returns true if a peer of `protocol` can talk with this build
+fn speaks(protocol: u32) -> bool {
+    protocol.min(PROTOCOL_VERSION) >= MIN_PROTOCOL_VERSION
+}
+
+/// what the client is, sent with `connect_as`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClientInfo {
+    /// version of cubby-connect of the client
+    pub crate_version: String,
+
+    /// version of the app that embeds the client
+    pub app_version: Option<String>,
+
+    /// operating system, like `linux` or `ios`
+    pub os: String,
+
+    /// cpu architecture, like `x86_64` or `aarch64`
+    pub arch: String,
+}
+
+impl ClientInfo {
+    /// returns the info of this build without an app version
+    pub fn current() -> Self {
+        Self {
+            crate_version: crate::VERSION.to_string(),
+            app_version: None,
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+
+    /// sets the version of the app
+    pub fn app_version<S: Into<String>>(mut self, version: S) -> Self {
+        self.app_version = Some(version.into());
+        self
+    }
+}
+
+impl Default for ClientInfo {
+    fn default() -> Self {
+        Self::current()
+    }
+}
+
+/// formatted like a user agent, e.g. `cubby-connect/0.1.0 (linux; x86_64) app/2.3.1`
+impl Display for ClientInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cubby-connect/{} ({}; {})",
+            self.crate_version, self.os, self.arch
+        )?;
+        match &self.app_version {
+            Some(version) => write!(f, " app/{version}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromContext for ClientInfo {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<ClientInfo>().cloned()
+    }
+}
+
+impl From<proto::ClientInfo> for ClientInfo {
+    fn from(info: proto::ClientInfo) -> Self {
+        Self {
+            crate_version: info.crate_version,
+            app_version: info.app_version,
+            os: info.os,
+            arch: info.arch,
+        }
+    }
+}
+
+impl From<ClientInfo> for proto::ClientInfo {
+    fn from(info: ClientInfo) -> Self {
+        Self {
+            crate_version: info.crate_version,
+            app_version: info.app_version,
+            os: info.os,
+            arch: info.arch,
+        }
+    }
+}
+
+/// result of the server side of handshake
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Accepted {
+    /// codec chosen for the connection
+    pub negotiated: Negotiated,
+
+    /// compression chosen for the connection
+    pub compression: Compression,
+
+    /// protocol version of the connection, the older one of the peers
+    pub protocol_version: u32,
+
+    /// what the client is, if it told
+    pub client_info: Option<ClientInfo>,
+}
+
+impl Accepted {
+    /// puts `Negotiated`, `Compression` and `ClientInfo` into `context`,
+    /// with a `DeflateContext` for deflate
+    pub fn insert_into(&self, context: &mut Context) {
+        context.insert(self.negotiated);
+        context.insert(self.compression);
+        if self.compression == Compression::Deflate {
+            context.insert(DeflateContext::new());
+        }
+        if let Some(info) = &self.client_info {
+            context.insert(info.clone());
+        }
+    }
+}
+
+async fn read_message<M, S>(stream: &mut S) -> Result<M, HandshakeError>
+where
+    M: Message + Default,
+    S: AsyncRead + Unpin,
+{
+    let frame = read_frame(stream).await?.ok_or(HandshakeError::Malformed)?;
+    M::decode(frame.as_slice()).map_err(|_| HandshakeError::Malformed)
+}
+
+async fn write_message<M, S>(stream: &mut S, msg: &M) -> Result<(), HandshakeError>
+where
+    M: Message,
+    S: AsyncWrite + Unpin,
+{
+    write_frame(stream, &msg.encode_to_vec()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// server side of handshake.
+/// The client is told the supported codecs even when negotiation fails.
+pub async fn accept<M, S>(stream: &mut S, codecs: &Codecs<M>) -> Result<Negotiated, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(accept_with(stream, codecs, &[]).await?.0)
+}
+
+/// server side of handshake with compression.
+/// `compressions` are enabled compressions in order of preference.
+pub async fn accept_with<M, S>(
+    stream: &mut S,
+    codecs: &Codecs<M>,
+    compressions: &[Compression],
+) -> Result<(Negotiated, Compression), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let accepted = accept_hello(stream, codecs, compressions).await?;
+    Ok((accepted.negotiated, accepted.compression))
+}
+
+/// server side of handshake that also returns what the client is
+pub async fn accept_hello<M, S>(
+    stream: &mut S,
+    codecs: &Codecs<M>,
+    compressions: &[Compression],
+) -> Result<Accepted, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    accept_checked(stream, codecs, compressions, None, |_| Ok(())).await
+}
+
+/// server side of handshake that blocks clients not allowed by `policy`,
+/// and rejects clients that don't match its requirement as incompatible.
+/// `key` identifies the client for the staged rollout of `policy`, e.g. the
+/// IP address of the peer.
+pub async fn accept_versioned<M, S, K>(
+    stream: &mut S,
+    codecs: &Codecs<M>,
+    compressions: &[Compression],
+    policy: &VersionPolicy,
+    key: &K,
+) -> Result<Accepted, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    K: Hash + ?Sized,
+{
+    let requirement = policy.requirement();
+    accept_checked(stream, codecs, compressions, requirement, |info| {
+        policy.check(info, key)
+    })
+    .await
+}
+
+async fn accept_checked<M, S, F>(
+    stream: &mut S,
+    codecs: &Codecs<M>,
+    compressions: &[Compression],
+    requirement: Option<&VersionReq>,
+    check: F,
+) -> Result<Accepted, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(Option<&ClientInfo>) -> Result<(), Blocked>,
+{
+    let hello: ClientHello = read_message(stream).await?;
+    let client_info = hello.client_info.map(ClientInfo::from);
+    let supported: Vec<String> = codecs.names().into_iter().map(String::from).collect();
+    let mut reply = ServerHello {
+        codec: None,
+        supported_codecs: supported.clone(),
+        compression: None,
+        blocked: None,
+        version: Some(VERSION.to_string()),
+        protocol_version: Some(PROTOCOL_VERSION),
+        incompatible: None,
+    };
+
+    let client_version = hello
+        .version
+        .or_else(|| client_info.as_ref().map(|info| info.crate_version.clone()))
+        .unwrap_or_else(|| Version::default().to_string());
+    let client_protocol = hello.protocol_version.unwrap_or(FIRST_PROTOCOL_VERSION);
+    let unmatched = requirement.filter(|requirement| {
+        let version = client_version.parse().unwrap_or_default();
+        !requirement.matches(&version)
+    });
+    if unmatched.is_some() || !speaks(client_protocol) {
+        let requirement = unmatched.map(ToString::to_string);
+        reply.incompatible = Some(proto::IncompatibleVersion {
+            requirement: requirement.clone(),
+        });
+        write_message(stream, &reply).await?;
+        return Err(HandshakeError::IncompatibleVersion(IncompatibleVersion {
+            client_version,
+            client_protocol,
+            server_version: VERSION.to_string(),
+            server_protocol: PROTOCOL_VERSION,
+            requirement,
+        }));
+    }
+
+    if let Err(blocked) = check(client_info.as_ref()) {
+        reply.blocked = Some(blocked.name().to_string());
+        write_message(stream, &reply).await?;
+        return Err(HandshakeError::Blocked(blocked));
+    }
+
+    let codec = codecs.negotiate(&hello.codecs);
+    let compression = Compression::negotiate(compressions, &hello.compressions);
+    reply.codec = codec.map(String::from);
+    reply.compression = match compression {
+        Compression::None => None,
+        c => Some(c.name().to_string()),
+    };
+    write_message(stream, &reply).await?;
+
+    let negotiated = codec.ok_or(HandshakeError::NoCommonCodec(supported))?;
+    Ok(Accepted {
+        negotiated: Negotiated(negotiated),
+        compression,
+        protocol_version: client_protocol.min(PROTOCOL_VERSION),
+        client_info,
+    })
+}
+
+/// client side of handshake.
+/// Returns the name of the codec chosen by the server.
+pub async fn connect<S, C>(stream: &mut S, codecs: &[C]) -> Result<String, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: AsRef<str>,
+{
+    Ok(connect_with(stream, codecs, &[]).await?.0)
+}
+
+/// client side of handshake with compression.
+/// Returns the name of the codec and the compression chosen by the server.
+pub async fn connect_with<S, C>(
+    stream: &mut S,
+    codecs: &[C],
+    compressions: &[Compression],
+) -> Result<(String, Compression), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: AsRef<str>,
+{
+    hello(stream, codecs, compressions, None).await
+}
+
+/// client side of handshake that also tells what the client is
+pub async fn connect_as<S, C>(
+    stream: &mut S,
+    codecs: &[C],
+    compressions: &[Compression],
+    info: &ClientInfo,
+) -> Result<(String, Compression), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: AsRef<str>,
+{
+    hello(stream, codecs, compressions, Some(info.clone().into())).await
+}
+
+async fn hello<S, C>(
+    stream: &mut S,
+    codecs: &[C],
+    compressions: &[Compression],
+    client_info: Option<proto::ClientInfo>,
+) -> Result<(String, Compression), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: AsRef<str>,
+{
+    let hello = ClientHello {
+        codecs: codecs.iter().map(|c| c.as_ref().to_string()).collect(),
+        compressions: compressions.iter().map(|c| c.name().to_string()).collect(),
+        client_info,
+        version: Some(VERSION.to_string()),
+        protocol_version: Some(PROTOCOL_VERSION),
+    };
+    write_message(stream, &hello).await?;
+
+    let reply: ServerHello = read_message(stream).await?;
+    let server_protocol = reply.protocol_version.unwrap_or(FIRST_PROTOCOL_VERSION);
+    if reply.incompatible.is_some() || !speaks(server_protocol) {
+        return Err(HandshakeError::IncompatibleVersion(IncompatibleVersion {
+            client_version: VERSION.to_string(),
+            client_protocol: PROTOCOL_VERSION,
+            server_version: reply
+                .version
+                .unwrap_or_else(|| Version::default().to_string()),
+            server_protocol,
+            requirement: reply
+                .incompatible
+                .and_then(|incompatible| incompatible.requirement),
+        }));
+    }
+    if let Some(name) = reply.blocked.as_deref() {
+        let blocked = Blocked::from_name(name).ok_or(HandshakeError::Malformed)?;
+        return Err(HandshakeError::Blocked(blocked));
+    }
+    let compression = match reply.compression.as_deref() {
+        Some(name) => Compression::from_name(name).ok_or(HandshakeError::Malformed)?,
+        None => Compression::None,
+    };
+    reply
+        .codec
+        .map(|codec| (codec, compression))
+        .ok_or(HandshakeError::NoCommonCodec(reply.supported_codecs))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::Protobuf;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn no_common_codec_test() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let codecs = Codecs::<String>::new().with(Protobuf);
+
+        let (server, client) = tokio::join!(
+            accept(&mut server, &codecs),
+            connect(&mut client, &["json"])
+        );
+        match (server, client) {
+            (Err(HandshakeError::NoCommonCodec(a)), Err(HandshakeError::NoCommonCodec(b))) => {
+                assert_eq!(a, vec!["protobuf"]);
+                assert_eq!(b, vec!["protobuf"]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn compression_test() -> Result<(), HandshakeError> {
+        let codecs = Codecs::<String>::new().with(Protobuf);
+        let enabled = [Compression::Zstd, Compression::Lz4];
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_with(&mut server, &codecs, &enabled),
+            connect_with(&mut client, &["protobuf"], &[Compression::Lz4])
+        );
+        assert_eq!(server?, (Negotiated("protobuf"), Compression::Lz4));
+        assert_eq!(client?, ("protobuf".to_string(), Compression::Lz4));
+
+        // the client does not support compression
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_with(&mut server, &codecs, &enabled),
+            connect(&mut client, &["protobuf"])
+        );
+        assert_eq!(server?, (Negotiated("protobuf"), Compression::None));
+        assert_eq!(client?, "protobuf");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_info_test() -> Result<(), HandshakeError> {
+        let codecs = Codecs::<String>::new().with(Protobuf);
+        let info = ClientInfo::current().app_version("2.3.1");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_hello(&mut server, &codecs, &[]),
+            connect_as(&mut client, &["protobuf"], &[], &info)
+        );
+        let accepted = server?;
+        assert_eq!(accepted.client_info.as_ref(), Some(&info));
+        assert_eq!(client?.0, "protobuf");
+
+        let mut context = Context::new();
+        accepted.insert_into(&mut context);
+        assert_eq!(ClientInfo::from_context(&context), Some(info.clone()));
+        assert_eq!(
+            info.to_string(),
+            format!(
+                "cubby-connect/{} ({}; {}) app/2.3.1",
+                crate::VERSION,
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )
+        );
+
+        // older clients do not tell
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, _) = tokio::join!(
+            accept_hello(&mut server, &codecs, &[]),
+            connect(&mut client, &["protobuf"])
+        );
+        assert_eq!(server?.client_info, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocked_test() -> Result<(), HandshakeError> {
+        let codecs = Codecs::<String>::new().with(Protobuf);
+        let info = ClientInfo::current();
+        let policy = VersionPolicy::new().min("99.0.0".parse().unwrap());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_versioned(&mut server, &codecs, &[], &policy, "10.0.0.1"),
+            connect_as(&mut client, &["protobuf"], &[], &info)
+        );
+        assert!(matches!(
+            server,
+            Err(HandshakeError::Blocked(Blocked::TooOld))
+        ));
+        assert!(matches!(
+            client,
+            Err(HandshakeError::Blocked(Blocked::TooOld))
+        ));
+
+        let policy = VersionPolicy::new().min("0.0.1".parse().unwrap());
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_versioned(&mut server, &codecs, &[], &policy, "10.0.0.1"),
+            connect_as(&mut client, &["protobuf"], &[], &info)
+        );
+        assert_eq!(server?.negotiated, Negotiated("protobuf"));
+        assert_eq!(client?.0, "protobuf");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incompatible_test() -> Result<(), HandshakeError> {
+        let codecs = Codecs::<String>::new().with(Protobuf);
+        let policy = VersionPolicy::new().require(">=99.0".parse().unwrap());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_versioned(&mut server, &codecs, &[], &policy, "10.0.0.1"),
+            connect(&mut client, &["protobuf"])
+        );
+        let expected = IncompatibleVersion {
+            client_version: VERSION.to_string(),
+            client_protocol: PROTOCOL_VERSION,
+            server_version: VERSION.to_string(),
+            server_protocol: PROTOCOL_VERSION,
+            requirement: Some(String::from(">=99.0")),
+        };
+        match (server, client) {
+            (
+                Err(HandshakeError::IncompatibleVersion(a)),
+                Err(HandshakeError::IncompatibleVersion(b)),
+            ) => {
+                assert_eq!(a, expected);
+                assert_eq!(b, expected);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        // a client of a protocol version that the server no longer speaks
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let hello = ClientHello {
+            codecs: vec![String::from("protobuf")],
+            protocol_version: Some(0),
+            ..Default::default()
+        };
+        write_message(&mut client, &hello).await?;
+        assert!(matches!(
+            accept(&mut server, &codecs).await,
+            Err(HandshakeError::IncompatibleVersion(IncompatibleVersion {
+                client_protocol: 0,
+                requirement: None,
+                ..
+            }))
+        ));
+        let reply: ServerHello = read_message(&mut client).await?;
+        assert_eq!(reply.incompatible, Some(Default::default()));
+
+        // older clients don't tell, and speak the first protocol version
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let hello = ClientHello {
+            codecs: vec![String::from("protobuf")],
+            ..Default::default()
+        };
+        write_message(&mut client, &hello).await?;
+        let accepted = accept(&mut server, &codecs).await;
+        assert_eq!(accepted?, Negotiated("protobuf"));
+
+        let policy = VersionPolicy::new().require(VERSION.parse().unwrap());
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (server, client) = tokio::join!(
+            accept_versioned(&mut server, &codecs, &[], &policy, "10.0.0.1"),
+            connect(&mut client, &["protobuf"])
+        );
+        assert_eq!(server?.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(client?, "protobuf");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_test() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_frame(&mut client, &[0xff, 0xff]).await.unwrap();
+        let codecs = Codecs::<String>::new().with(Protobuf);
+        assert!(matches!(
+            accept(&mut server, &codecs).await,
+            Err(HandshakeError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,401 @@
+//! Heartbeat with dead-peer detection
+//!
+//! A connection can look open long after its peer is gone: a phone lost
+//! its signal, or a NAT dropped the mapping, and nothing is read from it
+//! again. `Heartbeat` pings each peer every `Config::heartbeat_interval`
+//! and waits `Config::heartbeat_timeout` for the pong:
+//!
+//! - the round-trip time of pongs is tracked as a smoothed RTT
+//! - a missed pong makes the peer `Liveness::Unresponsive`, and
+//!   `Config::heartbeat_misses` missed pongs in a row make it
+//!   `Liveness::Dead`, which stops the pings
+//! - a pong makes an unresponsive peer `Liveness::Alive` again
+//! - `Heartbeat::on_change` is called whenever the liveness changes
+//!
+//! Pings and pongs are `heartbeat.Ping` and `heartbeat.Pong` in envelopes
+//! whose message type is their name. Peers answer pings with `answer`,
+//! which clients do by themselves. `Server::heartbeat` pings every
+//! connection and closes dead ones.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use bytes::Bytes;
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::connections::ConnectionId;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::heartbeat::{answer, Heartbeat, Liveness};
+//! use tokio::sync::mpsc::unbounded_channel;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let config = Config::builder()
+//!     .heartbeat_interval(Duration::from_millis(10))
+//!     .build()
+//!     .unwrap();
+//! let heartbeat = Heartbeat::from_config(&config)
+//!     .on_change(|id, liveness| println!("{id:?} is {liveness:?}"));
+//!
+//! // frames to the peer
+//! let (tx, mut rx) = unbounded_channel();
+//! let pinger = heartbeat.spawn(
+//!     ConnectionId(1),
+//!     fn_handler(move |frame: Bytes| {
+//!         let sent = tx.send(frame).map_err(|_| ());
+//!         async move { sent }
+//!     }),
+//! );
+//!
+//! // the peer answers the ping
+//! let ping = rx.recv().await.unwrap();
+//! let pong = answer(&ping).unwrap();
+//! assert!(pinger.receive(&pong));
+//! assert_eq!(pinger.liveness(), Liveness::Alive);
+//! assert!(pinger.rtt().is_some());
+//! # }
+//! ```
+
+use std::future::pending;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::Bytes;
+use prost::Message;
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, Instant};
+
+use crate::config::Config;
+use crate::connections::ConnectionId;
+use crate::handler::Handler;
+use crate::protobuf::envelope::Envelope;
+use crate::protobuf::heartbeat::{Ping, Pong};
+
+/// message type of pings in envelopes
+pub const PING: &str = "heartbeat.Ping";
+
+/// message type of pongs in envelopes
+pub const PONG: &str = "heartbeat.Pong";
+
+/// whether a peer answers pings
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Liveness {
+    /// answered the last ping
+    #[default]
+    Alive,
+
+    /// missed the last `missed` pongs
+    Unresponsive { missed: u32 },
+
+    /// missed too many pongs in a row
+    Dead,
+}
+
+type OnChange = Arc<dyn Fn(ConnectionId, Liveness) + Send + Sync>;
+
+/// settings of pinging, which spawns a `Pinger` for each peer
+#[derive(Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    misses: u32,
+    on_change: Option<OnChange>,
+}
+
+impl Heartbeat {
+    /// pings with the heartbeat settings of `config`
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interval: config.heartbeat_interval,
+            timeout: config.heartbeat_timeout,
+            misses: config.heartbeat_misses.max(1),
+            on_change: None,
+        }
+    }
+
+    /// sets the time between pings
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// sets the time to wait for the pong of a ping
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// sets the number of pongs in a row that can be missed before the
+    /// peer is dead
+    pub fn misses(mut self, misses: u32) -> Self {
+        self.misses = misses.max(1);
+        self
+    }
+
+    /// calls `on_change` with the id of the connection whenever the
+    /// liveness of its peer changes
+    pub fn on_change<F>(mut self, on_change: F) -> Self
+    where
+        F: Fn(ConnectionId, Liveness) + Send + Sync + 'static,
+    {
+        self.on_change = Some(Arc::new(on_change));
+        self
+    }
+
+    /// starts pinging the peer of connection `id` by sending frames to
+    /// `send`. Pinging stops when the peer is dead, or when every clone of
+    /// the returned `Pinger` is dropped.
+    pub fn spawn<H>(&self, id: ConnectionId, send: H) -> Pinger
+    where
+        H: Handler<Bytes> + Send + 'static,
+        H::Future: Send,
+    {
+        let (liveness, _) = watch::channel(Liveness::Alive);
+        let shared = Arc::new(Shared {
+            id,
+            state: Mutex::new(State::default()),
+            liveness,
+            on_change: self.on_change.clone(),
+        });
+        tokio::spawn(ping(Arc::downgrade(&shared), send, self.clone()));
+        Pinger { shared }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// seq of the last ping
+    seq: u64,
+    sent: Option<Instant>,
+    ponged: bool,
+    missed: u32,
+    rtt: Option<Duration>,
+}
+
+struct Shared {
+    id: ConnectionId,
+    state: Mutex<State>,
+    liveness: watch::Sender<Liveness>,
+    on_change: Option<OnChange>,
+}
+
+impl Shared {
+    fn set(&self, liveness: Liveness) {
+        let changed = self.liveness.send_if_modified(|current| {
+            let changed = *current != liveness;
+            *current = liveness;
+            changed
+        });
+        if let (true, Some(on_change)) = (changed, &self.on_change) {
+            on_change(self.id, liveness);
+        }
+    }
+}
+
+/// pings of a peer.
+/// Clones share the same pings.
+#[derive(Clone)]
+pub struct Pinger {
+    shared: Arc<Shared>,
+}
+
+impl Pinger {
+    /// takes a pong from `frame`, and returns false if `frame` is not a
+    /// pong. Pongs of earlier pings are ignored.
+    pub fn receive(&self, frame: &[u8]) -> bool {
+        let Some(Pong { seq }) = decode::<Pong>(frame, PONG) else {
+            return false;
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        if seq != state.seq || state.ponged {
+            return true;
+        }
+        if let Some(sent) = state.sent {
+            let sample = sent.elapsed();
+            state.rtt = Some(match state.rtt {
+                // like the smoothed RTT of TCP
+                Some(rtt) => (rtt * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+        state.ponged = true;
+        state.missed = 0;
+        drop(state);
+
+        if self.liveness() != Liveness::Dead {
+            self.shared.set(Liveness::Alive);
+        }
+        true
+    }
+
+    /// returns the smoothed round-trip time of pings.
+    /// Returns `None` before the first pong.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.shared.state.lock().unwrap().rtt
+    }
+
+    /// returns the current liveness of the peer
+    pub fn liveness(&self) -> Liveness {
+        *self.shared.liveness.borrow()
+    }
+
+    /// returns a receiver of changes of the liveness
+    pub fn watch(&self) -> watch::Receiver<Liveness> {
+        self.shared.liveness.subscribe()
+    }
+
+    /// waits until the peer is dead
+    pub async fn dead(&self) {
+        // the sender lives as long as `self`
+        let _ = self.watch().wait_for(|l| *l == Liveness::Dead).await;
+    }
+}
+
+/// waits until the peer of `pinger` is dead, or forever without a pinger
+pub(crate) async fn dead(pinger: Option<&Pinger>) {
+    match pinger {
+        Some(pinger) => pinger.dead().await,
+        None => pending().await,
+    }
+}
+
+/// returns the pong frame for `frame` if it is a ping
+pub fn answer(frame: &[u8]) -> Option<Vec<u8>> {
+    let Ping { seq } = decode::<Ping>(frame, PING)?;
+    Some(encode(PONG, &Pong { seq }))
+}
+
+/// returns true if `frame` is a ping
+pub(crate) fn is_ping(frame: &[u8]) -> bool {
+    decode::<Ping>(frame, PING).is_some()
+}
+
+/// returns the ping frame of `seq`
+pub fn ping_frame(seq: u64) -> Vec<u8> {
+    encode(PING, &Ping { seq })
+}
+
+fn encode<M: Message>(message_type: &str, msg: &M) -> Vec<u8> {
+    Envelope {
+        payload: Some(msg.encode_to_vec()),
+        message_type: Some(message_type.to_owned()),
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+fn decode<M: Message + Default>(frame: &[u8], message_type: &str) -> Option<M> {
+    let envelope = Envelope::decode(frame).ok()?;
+    if envelope.message_type.as_deref() != Some(message_type) {
+        return None;
+    }
+    M::decode(envelope.payload.unwrap_or_default().as_slice()).ok()
+}
+
+/// sends pings to `send` until the peer is dead, or `shared` is dropped
+async fn ping<H: Handler<Bytes>>(shared: Weak<Shared>, send: H, heartbeat: Heartbeat) {
+    let mut next = Instant::now() + heartbeat.interval;
+    loop {
+        sleep_until(next).await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        let seq = {
+            let mut state = shared.state.lock().unwrap();
+            state.seq += 1;
+            state.sent = Some(Instant::now());
+            state.ponged = false;
+            state.seq
+        };
+        // a ping that can't be sent is never answered
+        let _ = send.call(Bytes::from(ping_frame(seq))).await;
+        sleep(heartbeat.timeout).await;
+
+        let missed = {
+            let mut state = shared.state.lock().unwrap();
+            if !state.ponged {
+                state.missed += 1;
+            }
+            state.missed
+        };
+        if missed >= heartbeat.misses {
+            shared.set(Liveness::Dead);
+            return;
+        }
+        if missed > 0 {
+            shared.set(Liveness::Unresponsive { missed });
+        }
+        next = (next + heartbeat.interval).max(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::fn_handler::fn_handler;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_test() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let config = Config::builder().build().unwrap();
+        let heartbeat = Heartbeat::from_config(&config)
+            .interval(Duration::from_secs(10))
+            .timeout(Duration::from_secs(2))
+            .misses(2)
+            .on_change({
+                let changes = changes.clone();
+                move |id, liveness| changes.lock().unwrap().push((id, liveness))
+            });
+
+        let (tx, mut rx) = unbounded_channel();
+        let pinger = heartbeat.spawn(
+            ConnectionId(7),
+            fn_handler(move |frame: Bytes| {
+                let sent = tx.send((Instant::now(), frame)).map_err(|_| ());
+                async move { sent }
+            }),
+        );
+        let start = Instant::now();
+
+        // answered after 1 second
+        let (at, ping) = rx.recv().await.unwrap();
+        assert_eq!(at - start, Duration::from_secs(10));
+        assert!(!pinger.receive(&ping));
+        sleep(Duration::from_secs(1)).await;
+        assert!(pinger.receive(&answer(&ping).unwrap()));
+        assert_eq!(pinger.rtt(), Some(Duration::from_secs(1)));
+
+        // missed, but a late pong before the next ping still counts
+        let (at, ping) = rx.recv().await.unwrap();
+        assert_eq!(at - start, Duration::from_secs(20));
+        sleep(Duration::from_secs(3)).await;
+        assert_eq!(pinger.liveness(), Liveness::Unresponsive { missed: 1 });
+        assert!(pinger.receive(&answer(&ping).unwrap()));
+        assert_eq!(pinger.liveness(), Liveness::Alive);
+        assert_eq!(pinger.rtt(), Some(Duration::from_millis(1250)));
+
+        // missed twice
+        pinger.dead().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(42));
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                (ConnectionId(7), Liveness::Unresponsive { missed: 1 }),
+                (ConnectionId(7), Liveness::Alive),
+                (ConnectionId(7), Liveness::Unresponsive { missed: 1 }),
+                (ConnectionId(7), Liveness::Dead),
+            ]
+        );
+
+        // pinging stopped
+        let at = |(at, _): (Instant, Bytes)| at - start;
+        assert_eq!(rx.recv().await.map(at), Some(Duration::from_secs(30)));
+        assert_eq!(rx.recv().await.map(at), Some(Duration::from_secs(40)));
+        assert!(rx.recv().await.is_none());
+    }
+}
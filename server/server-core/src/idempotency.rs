@@ -0,0 +1,287 @@
+//! Idempotency keys for request handlers
+//!
+//! Clients can retry a request after a timeout even though the server has
+//! already processed it. `IdempotencyLayer` extracts a client-supplied key
+//! from each message and remembers the response per key for a while.
+//! When a message with the same key comes again, the remembered response is
+//! returned instead of calling the handler again. If the first call is still
+//! running, the retry waits for it and shares its result.
+//!
+//! Errors are often transient (e.g. a database that is down for a moment),
+//! so they are forgotten as soon as the call fails, and a retry calls the
+//! handler again. `IdempotencyLayer::error_ttl` remembers them for a while
+//! instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::idempotency::IdempotencyLayer;
+//! use cubby_connect_server_core::apply;
+//! use std::time::Duration;
+//!
+//! struct Order {
+//!     key: u64,
+//!     item: &'static str,
+//! }
+//!
+//! async fn buy(order: Order) -> Result<(), ()> {
+//!     println!("bought {}", order.item);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = IdempotencyLayer::new(Duration::from_secs(60), |o: &Order| Some(o.key));
+//! let handler = apply!(layer to buy);
+//! // this would print "bought sword" only once
+//! handler.call(Order { key: 1, item: "sword" }).await?;
+//! handler.call(Order { key: 1, item: "sword" }).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{ok, LocalBoxFuture, Ready, Shared};
+use futures::FutureExt;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+type SharedResult<O, E> = Shared<LocalBoxFuture<'static, Result<O, E>>>;
+
+/// remembered results of each key
+struct Cache<K, O, E> {
+    ttl: Duration,
+    error_ttl: Duration,
+    entries: HashMap<K, (Instant, SharedResult<O, E>)>,
+}
+
+impl<K, O, E> Cache<K, O, E>
+where
+    K: Hash + Eq,
+    O: Clone,
+    E: Clone,
+{
+    /// removes every entry that is older than ttl, or than error ttl if it
+    /// failed
+    fn purge(&mut self, now: Instant) {
+        let (ttl, error_ttl) = (self.ttl, self.error_ttl);
+        self.entries.retain(|_, (inserted, result)| {
+            let ttl = match result.peek() {
+                Some(Err(_)) => error_ttl,
+                _ => ttl,
+            };
+            now.duration_since(*inserted) < ttl
+        });
+    }
+}
+
+/// `Layer` that builds `IdempotencyHandler`
+pub struct IdempotencyLayer<F> {
+    ttl: Duration,
+    error_ttl: Duration,
+    key: Arc<F>,
+}
+
+impl<F> IdempotencyLayer<F> {
+    /// `key` extracts idempotency key from a message.
+    /// Messages without key (`None`) are always processed.
+    ///
+    /// Responses are remembered for `ttl` after the first message with the
+    /// key arrived.
+    pub fn new(ttl: Duration, key: F) -> Self {
+        Self {
+            ttl,
+            error_ttl: Duration::ZERO,
+            key: Arc::new(key),
+        }
+    }
+
+    /// remembers errors for `error_ttl` after the first message with the key
+    /// arrived (default: zero, so that retries call the handler again).
+    /// It should be shorter than `ttl`.
+    pub fn error_ttl(mut self, error_ttl: Duration) -> Self {
+        self.error_ttl = error_ttl;
+        self
+    }
+}
+
+impl<T, K, F, H> Layer<T, H> for IdempotencyLayer<F>
+where
+    F: Fn(&T) -> Option<K>,
+    K: Hash + Eq,
+    H: Handler<T>,
+    H::Output: Clone + 'static,
+    H::Error: Clone + 'static,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = IdempotencyHandler<T, K, F, H>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(IdempotencyHandler {
+            key: self.key.clone(),
+            cache: Arc::new(Mutex::new(Cache {
+                ttl: self.ttl,
+                error_ttl: self.error_ttl,
+                entries: HashMap::new(),
+            })),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that calls previous handler at most once per idempotency key
+pub struct IdempotencyHandler<T, K, F, H>
+where
+    H: Handler<T>,
+{
+    key: Arc<F>,
+    #[allow(clippy::type_complexity)]
+    cache: Arc<Mutex<Cache<K, H::Output, H::Error>>>,
+    prev: H,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, K, F, H> IdempotencyHandler<T, K, F, H>
+where
+    K: Hash + Eq,
+    H: Handler<T>,
+    H::Output: Clone,
+    H::Error: Clone,
+{
+    /// returns the number of keys that are remembered now
+    pub fn len(&self) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        cache.purge(Instant::now());
+        cache.entries.len()
+    }
+
+    /// returns true if no key is remembered now
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, K, F, H> Handler<T> for IdempotencyHandler<T, K, F, H>
+where
+    F: Fn(&T) -> Option<K>,
+    K: Hash + Eq,
+    H: Handler<T>,
+    H::Output: Clone + 'static,
+    H::Error: Clone + 'static,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = SharedResult<H::Output, H::Error>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let key = match (self.key)(&msg) {
+            Some(key) => key,
+            None => return self.prev.call(msg).boxed_local().shared(),
+        };
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.purge(now);
+
+        if let Some((_, result)) = cache.entries.get(&key) {
+            return result.clone();
+        }
+
+        let result = self.prev.call(msg).boxed_local().shared();
+        cache.entries.insert(key, (now, result.clone()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::{err, Ready};
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    struct Count(Arc<AtomicUsize>);
+
+    impl Handler<(Option<u32>, bool)> for Count {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, (_, success): (Option<u32>, bool)) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            if success {
+                ok(())
+            } else {
+                err(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn same_key_test() -> Result<(), ()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let layer = IdempotencyLayer::new(Duration::from_secs(60), |m: &(Option<u32>, bool)| m.0);
+        let handler = connect(layer, Count(count.clone())).await?;
+
+        handler.call((Some(1), true)).await?;
+        handler.call((Some(1), true)).await?;
+        handler.call((Some(2), true)).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(handler.len(), 2);
+
+        // errors are not remembered
+        assert!(handler.call((Some(3), false)).await.is_err());
+        handler.call((Some(3), true)).await?;
+        handler.call((Some(3), false)).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn error_ttl_test() -> Result<(), ()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let layer = IdempotencyLayer::new(Duration::from_secs(60), |m: &(Option<u32>, bool)| m.0)
+            .error_ttl(Duration::from_millis(50));
+        let handler = connect(layer, Count(count.clone())).await?;
+
+        // remembered error is returned again until the error ttl passes
+        assert!(handler.call((Some(1), false)).await.is_err());
+        assert!(handler.call((Some(1), true)).await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handler.call((Some(1), true)).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_key_and_expire_test() -> Result<(), ()> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let layer = IdempotencyLayer::new(Duration::ZERO, |m: &(Option<u32>, bool)| m.0);
+        let handler = connect(layer, Count(count.clone())).await?;
+
+        handler.call((None, true)).await?;
+        handler.call((None, true)).await?;
+        handler.call((Some(1), true)).await?;
+        handler.call((Some(1), true)).await?;
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        assert!(handler.is_empty());
+        Ok(())
+    }
+}
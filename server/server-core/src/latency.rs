@@ -0,0 +1,339 @@
+//! Summaries of handler latencies
+//!
+//! Deployments without a metrics system still need to know how fast the
+//! server is. `LatencyLayer` measures how long each call of the next handler
+//! takes, and records it in an exponential histogram of its name (e.g. a
+//! route) in `Latencies`. `Latencies::log_every` calls a logging function
+//! with the count and p50/p95/p99 of each name periodically.
+//!
+//! Buckets of `Histogram` grow exponentially (4 per doubling), so quantiles
+//! are within about 19% of the real value for any latency from
+//! microseconds to days, with a fixed amount of memory.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::latency::{Latencies, LatencyLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(_: &'static str) -> Result<(), ()> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let latencies = Arc::new(Latencies::new());
+//! let handler = apply!(LatencyLayer::new(latencies.clone(), "chat") to chat);
+//! handler.call("hello").await?;
+//!
+//! // usually spawned with `log_every` instead
+//! for summary in latencies.take() {
+//!     // this would print like "chat: count=1 p50=1µs p95=1µs p99=1µs"
+//!     println!("{summary}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::time::{interval, Instant};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// number of buckets per doubling
+const SUB_BUCKETS: f64 = 4.0;
+
+/// number of buckets, which covers up to 2^40µs (about 12 days)
+const BUCKETS: usize = 40 * SUB_BUCKETS as usize + 1;
+
+/// histogram of durations with exponential buckets
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    /// returns an empty histogram
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a duration
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().max(1) as f64;
+        let index = (micros.log2() * SUB_BUCKETS) as usize;
+        self.buckets[index.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(duration);
+    }
+
+    /// returns the number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// returns the largest recorded duration
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// returns the upper bound of the bucket of quantile `q` (`0.0..=1.0`).
+    /// Returns zero when nothing is recorded.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let upper = 2f64.powf((index + 1) as f64 / SUB_BUCKETS);
+                return Duration::from_micros(upper as u64).min(self.max);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// latency summary of a name
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Summary {
+    pub name: String,
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: count={} p50={:?} p95={:?} p99={:?}",
+            self.name, self.count, self.p50, self.p95, self.p99
+        )
+    }
+}
+
+/// histograms of latencies by name
+#[derive(Debug, Default)]
+pub struct Latencies {
+    histograms: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl Latencies {
+    /// returns empty histograms
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a latency of `name`
+    pub fn record(&self, name: &str, duration: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        match histograms.get_mut(name) {
+            Some(histogram) => histogram.record(duration),
+            None => {
+                let mut histogram = Histogram::new();
+                histogram.record(duration);
+                histograms.insert(name.to_string(), histogram);
+            }
+        }
+    }
+
+    /// returns summaries of every name in order of names
+    pub fn snapshot(&self) -> Vec<Summary> {
+        summarize(&self.histograms.lock().unwrap())
+    }
+
+    /// returns summaries of every name and resets the histograms.
+    /// Use this for summaries of each period.
+    pub fn take(&self) -> Vec<Summary> {
+        summarize(&std::mem::take(&mut *self.histograms.lock().unwrap()))
+    }
+
+    /// calls `log` with summaries of each `period` forever.
+    /// Nothing is logged for a period without any call.
+    pub async fn log_every<F>(&self, period: Duration, mut log: F)
+    where
+        F: FnMut(Vec<Summary>),
+    {
+        let mut ticker = interval(period);
+        // first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let summaries = self.take();
+            if !summaries.is_empty() {
+                log(summaries);
+            }
+        }
+    }
+}
+
+fn summarize(histograms: &BTreeMap<String, Histogram>) -> Vec<Summary> {
+    histograms
+        .iter()
+        .map(|(name, h)| Summary {
+            name: name.clone(),
+            count: h.count(),
+            p50: h.quantile(0.5),
+            p95: h.quantile(0.95),
+            p99: h.quantile(0.99),
+            max: h.max(),
+        })
+        .collect()
+}
+
+/// `Layer` that builds `LatencyHandler`
+pub struct LatencyLayer {
+    latencies: Arc<Latencies>,
+    name: Arc<str>,
+}
+
+impl LatencyLayer {
+    /// records latencies of the next handler as `name` in `latencies`
+    pub fn new<S: Into<String>>(latencies: Arc<Latencies>, name: S) -> Self {
+        Self {
+            latencies,
+            name: name.into().into(),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for LatencyLayer
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = LatencyHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(LatencyHandler {
+            latencies: self.latencies.clone(),
+            name: self.name.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that records how long the previous handler takes
+pub struct LatencyHandler<H> {
+    latencies: Arc<Latencies>,
+    name: Arc<str>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for LatencyHandler<H>
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    /// failed calls are recorded as well
+    fn call(&self, msg: T) -> Self::Future {
+        let latencies = self.latencies.clone();
+        let name = self.name.clone();
+        let start = Instant::now();
+        let future = self.prev.call(msg);
+
+        Box::pin(async move {
+            let result = future.await;
+            latencies.record(&name, start.elapsed());
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn histogram_test() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), Duration::ZERO);
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+
+        // within the error of a bucket
+        for (q, ms) in [(0.5, 50.0), (0.95, 95.0), (0.99, 99.0)] {
+            let got = histogram.quantile(q).as_secs_f64() * 1000.0;
+            assert!(got >= ms && got <= ms * 1.19, "p{q} = {got}ms");
+        }
+        assert_eq!(histogram.quantile(1.0), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn layer_test() -> Result<(), ()> {
+        let latencies = Arc::new(Latencies::new());
+        let work = fn_handler(|ms: u64| async move {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            if ms > 0 {
+                Ok(())
+            } else {
+                Err(())
+            }
+        });
+        let handler = connect(LatencyLayer::new(latencies.clone(), "work"), work).await?;
+        handler.call(10).await?;
+        handler.call(20).await?;
+        assert!(handler.call(0).await.is_err());
+
+        let summaries = latencies.snapshot();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].count, 3);
+        assert_eq!(summaries[0].max, Duration::from_millis(20));
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let logged_ = logged.clone();
+        let logger = latencies.clone();
+        let task = tokio::spawn(async move {
+            logger
+                .log_every(Duration::from_secs(1), move |s| {
+                    logged_.lock().unwrap().extend(s)
+                })
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        task.abort();
+
+        // the second period is empty
+        assert_eq!(logged.lock().unwrap().len(), 1);
+        assert!(latencies.snapshot().is_empty());
+        Ok(())
+    }
+}
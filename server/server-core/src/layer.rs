@@ -8,7 +8,7 @@
 //! ```
 //! use cubby_connect_server_core::handler::Handler;
 //! use cubby_connect_server_core::layer::Layer;
-//! use futures::future::{ok, LocalBoxFuture, Ready};
+//! use futures::future::{ok, Ready};
 //! use std::fmt::Display;
 //! use std::future::Future;
 //! use std::marker::PhantomData;
@@ -29,7 +29,6 @@
 //! impl<T, H> Layer<T, H> for EchoFactory
 //! where
 //!     H: Handler<T>,
-//!     H::Future: 'static,
 //! {
 //!     type Next = T;
 //!     type Error = H::Error;
@@ -48,20 +47,15 @@
 //! impl<T, H> Handler<T> for Echo<T, H>
 //! where
 //!     H: Handler<T>,
-//!     H::Future: 'static,
 //! {
+//!     type Output = H::Output;
 //!     type Error = H::Error;
-//!     type Future = LocalBoxFuture<'static, Result<(), Self::Error>>;
+//!     type Future = H::Future;
 //!
+//!     // this would act as same future of previous handler,
+//!     // so the output of previous handler flows back as is
 //!     fn call(&self, msg: T) -> Self::Future {
-//!         let prev_call = self.prev.call(msg);
-//!
-//!         // this would act as same future of previous handler,
-//!         // but type of `Ok` is `()`
-//!         Box::pin(async move {
-//!             prev_call.await?;
-//!             Ok(())
-//!         })
+//!         self.prev.call(msg)
 //!     }
 //! }
 //!
@@ -72,6 +66,7 @@
 //! where
 //!     S: Display,
 //! {
+//!     type Output = ();
 //!     type Error = ();
 //!     type Future = Ready<Result<(), Self::Error>>;
 //!
@@ -99,7 +94,12 @@
 
 use std::future::Future;
 
-use crate::handler::{Handler, IntoHandler};
+use futures::future::LocalBoxFuture;
+
+use crate::handler::{BoxHandler, Handler, IntoHandler};
+use crate::middleware::map_err::{err_into, ErrIntoHandler};
+
+pub use cubby_connect_server_macro::layer;
 
 /// This is a factory for `Handler`. Since `Handler` has chain connection,
 /// it have to hold the previous `Pipe`. It would be provided in factory.
@@ -113,7 +113,9 @@ where
     /// error type that would emit when processing handler
     type Error;
 
-    /// handler type to build
+    /// handler type to build.
+    /// Its output is usually the output of previous handler,
+    /// but a layer can also transform it on the way back.
     type Handler: Handler<T, Error = Self::Error>;
 
     /// initial error that would emit when building handler
@@ -160,6 +162,108 @@ where
     layer.into_layer().new_handler(handler.into_handler())
 }
 
+/// `connect` that converts errors into `E` with `Into`, so that layers
+/// and handlers of different error types are chained into one error type
+/// (e.g. `error::CubbyError`).
+///
+/// The layer gets `handler` with its errors converted, and errors of the
+/// built handler and of building it are converted too. Function layers
+/// still fail with `E` itself, since they pass the error of the next
+/// handler through.
+/// `apply!(... to handler into E)` expands to it.
+pub async fn connect_into<E, IL, L, T, IH, H>(
+    layer: IL,
+    handler: IH,
+) -> Result<ErrIntoHandler<E, L::Handler>, E>
+where
+    IL: IntoLayer<L, T, ErrIntoHandler<E, H>>,
+    L: Layer<T, ErrIntoHandler<E, H>>,
+    L::Error: Into<E>,
+    L::InitError: Into<E>,
+    IH: IntoHandler<H, L::Next>,
+    H: Handler<L::Next>,
+    H::Error: Into<E>,
+{
+    let prev = err_into(handler.into_handler());
+    let handler = layer
+        .into_layer()
+        .new_handler(prev)
+        .await
+        .map_err(Into::into)?;
+    Ok(err_into(handler))
+}
+
+/// builds a handler on top of the previous one
+#[allow(clippy::type_complexity)]
+type BuildFn<T, O, E> =
+    Box<dyn Fn(BoxHandler<T, O, E>) -> LocalBoxFuture<'static, Result<BoxHandler<T, O, E>, E>>>;
+
+/// `Layer` with its type erased.
+/// It stacks on `BoxHandler`s with the same message, output and error, so
+/// layers that are only known at runtime can be kept in a `Vec`.
+pub struct BoxLayer<T, O, E> {
+    f: BuildFn<T, O, E>,
+}
+
+impl<T, O, E> BoxLayer<T, O, E>
+where
+    T: 'static,
+    O: 'static,
+    E: 'static,
+{
+    /// erases the type of `layer`
+    pub fn new<L>(layer: L) -> Self
+    where
+        L: Layer<T, BoxHandler<T, O, E>, Next = T, Error = E> + 'static,
+        L::Handler: Handler<T, Output = O> + 'static,
+        <L::Handler as Handler<T>>::Future: 'static,
+        L::InitError: Into<E>,
+        L::Future: 'static,
+    {
+        Self {
+            f: Box::new(move |prev| {
+                let future = layer.new_handler(prev);
+                Box::pin(async move {
+                    let handler = future.await.map_err(Into::into)?;
+                    Ok(BoxHandler::new(handler))
+                })
+            }),
+        }
+    }
+}
+
+impl<T, O, E> Layer<T, BoxHandler<T, O, E>> for BoxLayer<T, O, E> {
+    type Next = T;
+    type Error = E;
+    type Handler = BoxHandler<T, O, E>;
+    type InitError = E;
+    type Future = LocalBoxFuture<'static, Result<BoxHandler<T, O, E>, E>>;
+
+    fn new_handler(&self, prev: BoxHandler<T, O, E>) -> Self::Future {
+        (self.f)(prev)
+    }
+}
+
+/// stacks `layers` on `handler`, the first is the outermost.
+///
+/// This is `apply!` for layers in a `Vec`, and `apply!` expands to it
+/// when a `..layers` spread is given.
+pub async fn connect_all<T, O, E, IH, H>(
+    layers: Vec<BoxLayer<T, O, E>>,
+    handler: IH,
+) -> Result<BoxHandler<T, O, E>, E>
+where
+    IH: IntoHandler<H, T>,
+    H: Handler<T, Output = O, Error = E> + 'static,
+    H::Future: 'static,
+{
+    let mut handler = BoxHandler::new(handler.into_handler());
+    for layer in layers.into_iter().rev() {
+        handler = layer.new_handler(handler).await?;
+    }
+    Ok(handler)
+}
+
 #[cfg(test)]
 mod test {
     use std::fmt::Display;
@@ -207,6 +311,7 @@ mod test {
         H: Handler<T>,
         H::Future: 'static,
     {
+        type Output = ();
         type Error = H::Error;
         type Future = LocalBoxFuture<'static, Result<(), Self::Error>>;
 
@@ -233,6 +338,7 @@ mod test {
     }
 
     impl<T: Display> Handler<T> for Check {
+        type Output = ();
         type Error = ();
         type Future = Ready<Result<(), ()>>;
 
@@ -283,4 +389,15 @@ mod test {
         handler.call(4).await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn connect_all_test() -> Result<(), ()> {
+        let layers = vec![BoxLayer::new(PlusOneFactory), BoxLayer::new(PlusOneFactory)];
+        let handler = connect_all(layers, Check::new("3")).await?;
+        handler.call(1).await?;
+
+        let handler = connect_all(Vec::new(), Check::new("1")).await?;
+        handler.call(1).await?;
+        Ok(())
+    }
 }
@@ -17,16 +17,117 @@
 #[macro_use]
 extern crate derive_builder;
 
-pub use cubby_connect_server_macro::apply;
+pub use cubby_connect_server_macro::{apply, apply_build};
 
+pub mod access_log;
+pub mod accounting;
+pub mod auth;
+pub mod batch;
+pub mod channel;
+pub mod check;
+pub mod client_version;
+pub mod codec;
 pub mod config;
+pub mod conformance;
+pub mod connections;
+pub mod context;
+#[cfg(feature = "database")]
+pub mod database;
+pub mod dedup;
+pub mod delta;
+pub mod discovery;
+pub mod envelope;
+pub mod error;
+pub mod ext;
+pub mod feature_flags;
 pub mod fn_handler;
 pub mod fn_layer;
 pub mod handler;
+pub mod handshake;
+pub mod heartbeat;
+pub mod idempotency;
+pub mod latency;
 pub mod layer;
+pub mod log_level;
+pub mod middleware;
+#[cfg(feature = "database")]
+pub mod migrate;
+pub mod notify;
+#[cfg(feature = "database")]
+pub mod outbox;
+pub mod peer_trace;
+pub mod pipeline;
+pub mod pubsub;
+pub mod relay;
+pub mod rendezvous;
+pub mod reply;
+pub mod router;
+pub mod rpc;
+#[cfg(feature = "database")]
+pub mod scheduled;
+pub mod schema;
+pub mod server;
+#[cfg(feature = "service")]
+pub mod service;
+pub mod session;
+pub mod shutdown;
+#[cfg(feature = "slo")]
+pub mod slo;
+#[cfg(feature = "spec")]
+pub mod spec;
+pub mod state_sync;
+#[cfg(feature = "database")]
+pub mod store;
+pub mod stream;
+pub mod timer;
+pub mod trace_id;
+pub mod transform;
+pub mod transport;
+pub mod unsupported;
+pub mod warmup;
+pub mod watchdog;
 
 mod protobuf {
     include!(concat!(env!("OUT_DIR"), "/sample.rs"));
+
+    pub mod auth {
+        include!(concat!(env!("OUT_DIR"), "/auth.rs"));
+    }
+
+    pub mod discovery {
+        include!(concat!(env!("OUT_DIR"), "/discovery.rs"));
+    }
+
+    pub mod envelope {
+        include!(concat!(env!("OUT_DIR"), "/envelope.rs"));
+    }
+
+    pub mod feature_flags {
+        include!(concat!(env!("OUT_DIR"), "/feature_flags.rs"));
+    }
+
+    pub mod handshake {
+        include!(concat!(env!("OUT_DIR"), "/handshake.rs"));
+    }
+
+    pub mod heartbeat {
+        include!(concat!(env!("OUT_DIR"), "/heartbeat.rs"));
+    }
+
+    pub mod rendezvous {
+        include!(concat!(env!("OUT_DIR"), "/rendezvous.rs"));
+    }
+
+    pub mod unsupported {
+        include!(concat!(env!("OUT_DIR"), "/unsupported.rs"));
+    }
 }
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// version of the protocol schema, which increases when messages change
+/// incompatibly. Peers exchange it in the handshake.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// oldest protocol version that this build still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
@@ -0,0 +1,351 @@
+//! Log levels that can be changed while the server runs
+//!
+//! Debugging a production incident often needs more logs than usual, and
+//! restarting the server with a higher `verbose` loses the state that is
+//! being debugged. `LogLevels` holds the current `LogFilter` (a global level
+//! and levels of modules), which loggers check with `LogLevels::enabled`,
+//! and which can be changed at any time:
+//!
+//! - `LogLevels::admin` returns a handler of admin commands, which can be
+//!   routed like any other chain (e.g. JSON admin commands of
+//!   `ContentRouter`)
+//! - `LogLevels::raise` and `LogLevels::reset` are meant for signals, like
+//!   raising the level on `SIGUSR1` and going back on `SIGUSR2`
+//!
+//! Filters are written like `info,router=debug,store=off`: a global level
+//! and levels of modules, where the level of the longest matching module
+//! path wins.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::log_level::{InvalidFilter, Level, LogLevels};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), InvalidFilter> {
+//! // `verbose` 3 of `Config`
+//! let levels = LogLevels::from_verbose(3);
+//! assert!(!levels.enabled("cubby::router", Level::Debug));
+//!
+//! let admin = levels.admin();
+//! assert_eq!(admin.call(String::from("info,cubby::router=debug")).await?, "info,cubby::router=debug");
+//! assert!(levels.enabled("cubby::router::route", Level::Debug));
+//! assert!(!levels.enabled("cubby::store", Level::Debug));
+//!
+//! admin.call(String::from("reset")).await?;
+//! assert!(!levels.enabled("cubby::router", Level::Debug));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use futures::future::{ready, Ready};
+
+use crate::handler::Handler;
+
+/// level of a log
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    /// don't print anything
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// returns the level of `verbose` of `Config` (0 to 5)
+    pub fn from_verbose(verbose: u8) -> Self {
+        match verbose {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    /// returns the next more verbose level
+    pub fn raise(self) -> Self {
+        match self {
+            Level::Off => Level::Error,
+            Level::Error => Level::Warn,
+            Level::Warn => Level::Info,
+            Level::Info => Level::Debug,
+            Level::Debug | Level::Trace => Level::Trace,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Level {
+    type Err = InvalidFilter;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(InvalidFilter(s.to_string())),
+        }
+    }
+}
+
+/// error of filters that can't be parsed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidFilter(pub String);
+
+impl Display for InvalidFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid log filter: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidFilter {}
+
+/// global level and levels of modules
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogFilter {
+    pub global: Level,
+    pub modules: BTreeMap<String, Level>,
+}
+
+impl LogFilter {
+    /// returns a filter of `global` without any module
+    pub fn new(global: Level) -> Self {
+        Self {
+            global,
+            modules: BTreeMap::new(),
+        }
+    }
+
+    /// returns the level of `module`, which is the level of the longest
+    /// matching module path or the global level
+    pub fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(path, _)| {
+                module == path.as_str()
+                    || module
+                        .strip_prefix(path.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map_or(self.global, |(_, level)| *level)
+    }
+
+    /// returns true if logs of `level` in `module` should be printed
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        level != Level::Off && level <= self.level(module)
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.global)?;
+        for (module, level) in &self.modules {
+            write!(f, ",{module}={level}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = InvalidFilter;
+
+    /// parses filters like `info,router=debug`.
+    /// The global level is `info` when it is not written.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::new(Level::Info);
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(InvalidFilter(directive.to_string()));
+                    }
+                    filter.modules.insert(module.to_string(), level.parse()?);
+                }
+                None => filter.global = directive.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+struct Inner {
+    initial: LogFilter,
+    current: RwLock<LogFilter>,
+}
+
+/// current `LogFilter` of the server.
+/// Clones share the same filter.
+#[derive(Clone)]
+pub struct LogLevels {
+    inner: Arc<Inner>,
+}
+
+impl LogLevels {
+    /// starts with `filter`, which is also the filter of `reset`
+    pub fn new(filter: LogFilter) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                current: RwLock::new(filter.clone()),
+                initial: filter,
+            }),
+        }
+    }
+
+    /// starts with the global level of `verbose` of `Config`
+    pub fn from_verbose(verbose: u8) -> Self {
+        Self::new(LogFilter::new(Level::from_verbose(verbose)))
+    }
+
+    /// returns the current filter
+    pub fn filter(&self) -> LogFilter {
+        self.inner.current.read().unwrap().clone()
+    }
+
+    /// returns true if logs of `level` in `module` should be printed now
+    pub fn enabled(&self, module: &str, level: Level) -> bool {
+        self.inner.current.read().unwrap().enabled(module, level)
+    }
+
+    /// replaces the current filter
+    pub fn set(&self, filter: LogFilter) {
+        *self.inner.current.write().unwrap() = filter;
+    }
+
+    /// sets the level of `module` only
+    pub fn set_module<S: Into<String>>(&self, module: S, level: Level) {
+        let mut current = self.inner.current.write().unwrap();
+        current.modules.insert(module.into(), level);
+    }
+
+    /// raises the global level by one step, and returns the new level
+    pub fn raise(&self) -> Level {
+        let mut current = self.inner.current.write().unwrap();
+        current.global = current.global.raise();
+        current.global
+    }
+
+    /// goes back to the filter that the server started with
+    pub fn reset(&self) {
+        self.set(self.inner.initial.clone());
+    }
+
+    /// returns a handler of admin commands
+    pub fn admin(&self) -> LogAdmin {
+        LogAdmin {
+            levels: self.clone(),
+        }
+    }
+}
+
+/// `Handler` of admin commands that change `LogLevels`.
+///
+/// A command is one of:
+///
+/// - an empty string, which only returns the current filter
+/// - `reset`, which goes back to the initial filter
+/// - `raise`, which raises the global level by one step
+/// - a filter like `info,router=debug`, which replaces the current filter
+///
+/// Every command returns the filter after the command.
+pub struct LogAdmin {
+    levels: LogLevels,
+}
+
+impl LogAdmin {
+    fn run(&self, command: &str) -> Result<String, InvalidFilter> {
+        match command.trim() {
+            "" => {}
+            "reset" => self.levels.reset(),
+            "raise" => {
+                self.levels.raise();
+            }
+            filter => self.levels.set(filter.parse()?),
+        }
+        Ok(self.levels.filter().to_string())
+    }
+}
+
+impl Handler<String> for LogAdmin {
+    type Output = String;
+    type Error = InvalidFilter;
+    type Future = Ready<Result<String, InvalidFilter>>;
+
+    fn call(&self, command: String) -> Self::Future {
+        ready(self.run(&command))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_test() -> Result<(), InvalidFilter> {
+        let filter: LogFilter = "warn, router=debug ,router::json=off,store=TRACE".parse()?;
+        assert_eq!(filter.global, Level::Warn);
+        assert_eq!(filter.level("router"), Level::Debug);
+        assert_eq!(filter.level("router::protobuf"), Level::Debug);
+        assert_eq!(filter.level("router::json::admin"), Level::Off);
+        assert_eq!(filter.level("routers"), Level::Warn);
+        assert!(filter.enabled("store", Level::Trace));
+        assert!(!filter.enabled("router::json", Level::Error));
+        assert!(!filter.enabled("relay", Level::Info));
+        assert_eq!(
+            filter.to_string(),
+            "warn,router=debug,router::json=off,store=trace"
+        );
+
+        assert_eq!("router=debug".parse::<LogFilter>()?.global, Level::Info);
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("=debug".parse::<LogFilter>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_test() -> Result<(), InvalidFilter> {
+        let levels = LogLevels::from_verbose(2);
+        let admin = levels.admin();
+        assert_eq!(admin.call(String::new()).await?, "warn");
+
+        assert_eq!(admin.call(String::from("raise")).await?, "info");
+        levels.set_module("relay", Level::Trace);
+        assert!(levels.enabled("relay", Level::Trace));
+
+        // invalid filters don't change anything
+        assert!(admin.call(String::from("info,relay=loud")).await.is_err());
+        assert_eq!(levels.filter().to_string(), "info,relay=trace");
+
+        assert_eq!(admin.call(String::from("reset")).await?, "warn");
+        assert_eq!(Level::Trace.raise(), Level::Trace);
+        Ok(())
+    }
+}
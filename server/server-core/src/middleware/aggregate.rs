@@ -0,0 +1,271 @@
+//! Reductions of messages over time windows
+//!
+//! Metrics-like pipelines often need a summary of messages per window of
+//! time (sum, count, max, ...), not each message. `AggregateLayer` folds
+//! messages into windows with a reducer and sends the aggregate of each
+//! window to the next handler when the window is over:
+//!
+//! - `AggregateLayer::tumbling(window, ..)`: windows that don't overlap
+//! - `AggregateLayer::sliding(window, step, ..)`: a window starts every
+//!   `step`, so a message is folded into every window that covers it
+//!
+//! Windows are closed when a message comes after their end, or when
+//! `AggregateHandler::flush` is called (e.g. by a timer). Windows without
+//! any message are not emitted.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::layer::connect;
+//! use cubby_connect_server_core::middleware::aggregate::AggregateLayer;
+//!
+//! async fn report(total: u64) -> Result<(), ()> {
+//!     println!("{total} bytes in 100ms");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = AggregateLayer::tumbling(Duration::from_millis(100), || 0, |sum, bytes: &u64| sum + bytes);
+//! let handler = connect(layer, report).await?;
+//! handler.call(100).await?;
+//! handler.call(200).await?;
+//!
+//! tokio::time::sleep(Duration::from_millis(100)).await;
+//! // this would print "300 bytes in 100ms"
+//! handler.flush().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `AggregateHandler`
+pub struct AggregateLayer<I, R> {
+    window: Duration,
+    step: Duration,
+    init: Arc<I>,
+    reduce: Arc<R>,
+}
+
+impl<I, R> AggregateLayer<I, R> {
+    /// folds messages in windows of `window` that follow each other.
+    /// `init` returns the aggregate of an empty window, and `reduce` folds
+    /// a message into an aggregate.
+    pub fn tumbling(window: Duration, init: I, reduce: R) -> Self {
+        Self::sliding(window, window, init, reduce)
+    }
+
+    /// folds messages in windows of `window` that start every `step`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` or `step` is zero.
+    pub fn sliding(window: Duration, step: Duration, init: I, reduce: R) -> Self {
+        assert!(!window.is_zero(), "window should be positive");
+        assert!(!step.is_zero(), "step should be positive");
+        Self {
+            window,
+            step,
+            init: Arc::new(init),
+            reduce: Arc::new(reduce),
+        }
+    }
+}
+
+impl<T, A, I, R, H> Layer<T, H> for AggregateLayer<I, R>
+where
+    A: 'static,
+    I: Fn() -> A,
+    R: Fn(A, &T) -> A,
+    H: Handler<A> + 'static,
+{
+    type Next = A;
+    type Error = H::Error;
+    type Handler = AggregateHandler<T, A, I, R, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(AggregateHandler {
+            window: self.window,
+            step: self.step,
+            init: self.init.clone(),
+            reduce: self.reduce.clone(),
+            state: Mutex::new(Windows {
+                next_start: None,
+                open: VecDeque::new(),
+            }),
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// windows that are not emitted yet
+struct Windows<A> {
+    next_start: Option<Instant>,
+    open: VecDeque<(Instant, A)>,
+}
+
+/// `Handler` that sends aggregates of windows to the previous handler
+pub struct AggregateHandler<T, A, I, R, H> {
+    window: Duration,
+    step: Duration,
+    init: Arc<I>,
+    reduce: Arc<R>,
+    state: Mutex<Windows<A>>,
+    prev: Arc<H>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, A, I, R, H> AggregateHandler<T, A, I, R, H>
+where
+    A: 'static,
+    H: Handler<A> + 'static,
+{
+    /// removes windows that ended at `now`
+    fn close(&self, state: &mut Windows<A>, now: Instant) -> Vec<A> {
+        let mut closed = Vec::new();
+        while let Some((start, _)) = state.open.front() {
+            if *start + self.window > now {
+                break;
+            }
+            closed.push(state.open.pop_front().unwrap().1);
+        }
+        closed
+    }
+
+    /// sends `aggregates` to the previous handler in order
+    fn emit(
+        &self,
+        aggregates: Vec<A>,
+    ) -> LocalBoxFuture<'static, Result<Vec<H::Output>, H::Error>> {
+        let prev = self.prev.clone();
+        Box::pin(async move {
+            let mut outputs = Vec::with_capacity(aggregates.len());
+            for aggregate in aggregates {
+                outputs.push(prev.call(aggregate).await?);
+            }
+            Ok(outputs)
+        })
+    }
+
+    /// emits every window that is over now, without waiting for a message
+    pub fn flush(&self) -> LocalBoxFuture<'static, Result<Vec<H::Output>, H::Error>> {
+        let closed = self.close(&mut self.state.lock().unwrap(), Instant::now());
+        self.emit(closed)
+    }
+}
+
+impl<T, A, I, R, H> Handler<T> for AggregateHandler<T, A, I, R, H>
+where
+    A: 'static,
+    I: Fn() -> A,
+    R: Fn(A, &T) -> A,
+    H: Handler<A> + 'static,
+{
+    type Output = Vec<H::Output>;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// folds `msg` into its windows, and emits the windows that are over.
+    /// Returns outputs of the previous handler for the emitted windows.
+    fn call(&self, msg: T) -> Self::Future {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let closed = self.close(&mut state, now);
+
+        // opens windows that cover now, skipping ones that already ended
+        let mut start = state.next_start.unwrap_or(now);
+        while start <= now {
+            if start + self.window > now {
+                state.open.push_back((start, (self.init)()));
+            }
+            start += self.step;
+        }
+        state.next_start = Some(start);
+
+        for (_, aggregate) in state.open.iter_mut() {
+            // every open window covers now
+            let prev = std::mem::replace(aggregate, (self.init)());
+            *aggregate = (self.reduce)(prev, &msg);
+        }
+        drop(state);
+
+        self.emit(closed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn id(v: Vec<u32>) -> Result<Vec<u32>, ()> {
+        Ok(v)
+    }
+
+    fn push(mut v: Vec<u32>, i: &u32) -> Vec<u32> {
+        v.push(*i);
+        v
+    }
+
+    async fn sleep_ms(ms: u64) {
+        tokio::time::advance(Duration::from_millis(ms)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tumbling_test() -> Result<(), ()> {
+        let layer = AggregateLayer::tumbling(Duration::from_secs(1), Vec::new, push);
+        let handler = connect(layer, id).await?;
+
+        assert!(handler.call(1).await?.is_empty());
+        sleep_ms(500).await;
+        assert!(handler.call(2).await?.is_empty());
+        sleep_ms(500).await;
+        assert_eq!(handler.call(3).await?, vec![vec![1, 2]]);
+
+        // empty windows are skipped
+        sleep_ms(3000).await;
+        assert_eq!(handler.call(4).await?, vec![vec![3]]);
+        sleep_ms(1000).await;
+        assert_eq!(handler.flush().await?, vec![vec![4]]);
+        assert!(handler.flush().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sliding_test() -> Result<(), ()> {
+        let layer = AggregateLayer::sliding(
+            Duration::from_secs(2),
+            Duration::from_secs(1),
+            Vec::new,
+            push,
+        );
+        let handler = connect(layer, id).await?;
+
+        handler.call(1).await?;
+        sleep_ms(1000).await;
+        handler.call(2).await?;
+        sleep_ms(1000).await;
+        assert_eq!(handler.call(3).await?, vec![vec![1, 2]]);
+        sleep_ms(1000).await;
+        assert_eq!(handler.flush().await?, vec![vec![2, 3]]);
+        sleep_ms(1000).await;
+        assert_eq!(handler.flush().await?, vec![vec![3]]);
+        Ok(())
+    }
+}
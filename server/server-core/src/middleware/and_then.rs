@@ -0,0 +1,121 @@
+//! Chaining of async steps after the next handler
+//!
+//! `AndThenLayer` (or `and_then`) passes the output of the next handler to
+//! an async function, and returns what the function returns. Errors of the
+//! next handler skip the function. It is handy for small steps after a
+//! handler, like loading what the handler stored, without a new handler
+//! type.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::and_then::AndThenLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn parse(msg: String) -> Result<u32, String> {
+//!     msg.parse().map_err(|_| format!("not a number: {msg}"))
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), String> {
+//! let handler = apply!(AndThenLayer::new(|n: u32| async move { Ok(n * 2) }) to parse);
+//! assert_eq!(handler.call(String::from("21")).await?, 42);
+//! assert!(handler.call(String::from("x")).await.is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+
+use futures::future::{ok, AndThen, Ready};
+use futures::TryFutureExt;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `AndThenHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct AndThenLayer<F> {
+    f: F,
+}
+
+impl<F> AndThenLayer<F> {
+    /// passes outputs of the next handler to `f`
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<T, F, Fut, O, H> Layer<T, H> for AndThenLayer<F>
+where
+    F: FnOnce(H::Output) -> Fut + Clone,
+    Fut: Future<Output = Result<O, H::Error>>,
+    H: Handler<T>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = AndThenHandler<F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(and_then(prev, self.f.clone()))
+    }
+}
+
+/// returns `handler` whose outputs are passed to `f`
+pub fn and_then<H, F>(handler: H, f: F) -> AndThenHandler<F, H> {
+    AndThenHandler { f, prev: handler }
+}
+
+/// `Handler` that passes outputs of the previous handler to an async function
+pub struct AndThenHandler<F, H> {
+    f: F,
+    prev: H,
+}
+
+impl<T, F, Fut, O, H> Handler<T> for AndThenHandler<F, H>
+where
+    F: FnOnce(H::Output) -> Fut + Clone,
+    Fut: Future<Output = Result<O, H::Error>>,
+    H: Handler<T>,
+{
+    type Output = O;
+    type Error = H::Error;
+    type Future = AndThen<H::Future, Fut, F>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        self.prev.call(msg).and_then(self.f.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::{err, ready};
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn check(i: i32) -> Result<i32, String> {
+        if i < 0 {
+            Err(format!("negative {i}"))
+        } else {
+            Ok(i)
+        }
+    }
+
+    #[tokio::test]
+    async fn and_then_test() -> Result<(), String> {
+        let handler = connect(AndThenLayer::new(|i: i32| ready(Ok(i.to_string()))), check).await?;
+        assert_eq!(handler.call(1).await?, "1");
+        assert_eq!(handler.call(-1).await, Err(String::from("negative -1")));
+
+        // the function can fail as well
+        let handler = and_then(fn_handler(check), |_: i32| err::<(), _>(String::from("no")));
+        assert_eq!(handler.call(1).await, Err(String::from("no")));
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Buffer in front of a single worker
+//!
+//! `BufferLayer` moves the next handler into a worker task, and connects it
+//! to callers with a bounded `mpsc` channel. Callers wait for capacity when
+//! the channel is full, so producers are slowed down to the pace of the
+//! worker. The worker calls the next handler one message at a time, so the
+//! handler doesn't need to be `Sync`.
+//!
+//! The worker is spawned with `tokio::spawn` when the handler is built, and
+//! stops when every `BufferHandler` is dropped.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::cell::Cell;
+//! use futures::future::{ready, Ready};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::layer::connect;
+//! use cubby_connect_server_core::middleware::buffer::{BufferClosed, BufferLayer};
+//!
+//! // `Cell` is not `Sync`
+//! struct Counter(Cell<u32>);
+//!
+//! impl Handler<u32> for Counter {
+//!     type Output = u32;
+//!     type Error = BufferClosed;
+//!     type Future = Ready<Result<u32, BufferClosed>>;
+//!
+//!     fn call(&self, n: u32) -> Self::Future {
+//!         self.0.set(self.0.get() + n);
+//!         ready(Ok(self.0.get()))
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), BufferClosed> {
+//! let handler = connect(BufferLayer::new(16), Counter(Cell::new(0))).await?;
+//! let handler_ = handler.clone();
+//! tokio::spawn(async move { handler_.call(1).await }).await.unwrap()?;
+//! assert_eq!(handler.call(2).await?, 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use futures::future::{ok, BoxFuture, Ready};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+type Job<T, O, E> = (T, oneshot::Sender<Result<O, E>>);
+
+/// error when the worker is gone (e.g. it panicked)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BufferClosed;
+
+impl Display for BufferClosed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "worker of the buffer is closed")
+    }
+}
+
+impl std::error::Error for BufferClosed {}
+
+/// `Layer` that builds `BufferHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct BufferLayer {
+    capacity: usize,
+}
+
+impl BufferLayer {
+    /// buffers up to `capacity` messages that wait for the worker
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity should be positive");
+        Self { capacity }
+    }
+}
+
+impl<T, H> Layer<T, H> for BufferLayer
+where
+    T: Send + 'static,
+    H: Handler<T> + Send + 'static,
+    H::Output: Send + 'static,
+    H::Error: From<BufferClosed> + Send + 'static,
+    H::Future: Send,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = BufferHandler<T, H::Output, H::Error>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        let (tx, mut rx) = mpsc::channel::<Job<T, H::Output, H::Error>>(self.capacity);
+
+        tokio::spawn(async move {
+            while let Some((msg, reply)) = rx.recv().await {
+                // the caller may have given up waiting
+                let _ = reply.send(prev.call(msg).await);
+            }
+        });
+
+        ok(BufferHandler { tx })
+    }
+}
+
+/// `Handler` that sends messages to the worker of the previous handler.
+/// Clones share the same worker.
+pub struct BufferHandler<T, O, E> {
+    tx: mpsc::Sender<Job<T, O, E>>,
+}
+
+impl<T, O, E> Clone for BufferHandler<T, O, E> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T, O, E> BufferHandler<T, O, E> {
+    /// returns the number of messages that can be buffered now
+    pub fn capacity(&self) -> usize {
+        self.tx.capacity()
+    }
+}
+
+impl<T, O, E> Handler<T> for BufferHandler<T, O, E>
+where
+    T: Send + 'static,
+    O: Send + 'static,
+    E: From<BufferClosed> + Send + 'static,
+{
+    type Output = O;
+    type Error = E;
+    type Future = BoxFuture<'static, Result<O, E>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let tx = self.tx.clone();
+
+        Box::pin(async move {
+            let (reply, result) = oneshot::channel();
+            tx.send((msg, reply)).await.map_err(|_| BufferClosed)?;
+            result.await.map_err(|_| BufferClosed)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn backpressure_test() -> Result<(), BufferClosed> {
+        let work = fn_handler(|i: u32| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, BufferClosed>(i)
+        });
+        let handler = connect(BufferLayer::new(2), work).await?;
+        assert_eq!(handler.capacity(), 2);
+
+        // one message runs at a time
+        let start = tokio::time::Instant::now();
+        let outputs = join_all((0..4).map(|i| handler.call(i))).await;
+        assert_eq!(outputs, vec![Ok(0), Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closed_test() -> Result<(), BufferClosed> {
+        let work = fn_handler(|i: u32| async move {
+            if i == 0 {
+                panic!("worker is broken");
+            }
+            Ok::<_, BufferClosed>(i)
+        });
+        let handler = connect(BufferLayer::new(2), work).await?;
+        assert_eq!(handler.call(0).await, Err(BufferClosed));
+        assert_eq!(handler.call(1).await, Err(BufferClosed));
+        Ok(())
+    }
+}
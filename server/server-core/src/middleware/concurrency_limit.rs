@@ -0,0 +1,130 @@
+//! Limit of messages in flight
+//!
+//! Every call of a handler holds some memory until it finishes. Under load,
+//! unbounded calls can exhaust the memory of the server.
+//! `ConcurrencyLimitLayer` bounds how many calls of the next handler run at
+//! the same time: the rest wait for a permit of a semaphore, in the order
+//! they came.
+//!
+//! Each handler built from the layer has its own limit.
+
+use std::sync::Arc;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::sync::Semaphore;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `ConcurrencyLimitHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyLimitLayer {
+    max_in_flight: usize,
+}
+
+impl ConcurrencyLimitLayer {
+    /// runs at most `max_in_flight` calls at the same time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_in_flight` is zero.
+    pub fn new(max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "max_in_flight should be positive");
+        Self { max_in_flight }
+    }
+}
+
+impl<T, H> Layer<T, H> for ConcurrencyLimitLayer
+where
+    T: 'static,
+    H: Handler<T> + 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = ConcurrencyLimitHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ConcurrencyLimitHandler {
+            semaphore: Arc::new(Semaphore::new(self.max_in_flight)),
+            prev: Arc::new(prev),
+        })
+    }
+}
+
+/// `Handler` that bounds concurrent calls of the previous handler
+pub struct ConcurrencyLimitHandler<H> {
+    semaphore: Arc<Semaphore>,
+    prev: Arc<H>,
+}
+
+impl<H> ConcurrencyLimitHandler<H> {
+    /// returns the number of calls that can start now without waiting
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+impl<T, H> Handler<T> for ConcurrencyLimitHandler<H>
+where
+    T: 'static,
+    H: Handler<T> + 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let semaphore = self.semaphore.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            // the semaphore is never closed
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            prev.call(msg).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_test() -> Result<(), ()> {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let (running_, peak_) = (running.clone(), peak.clone());
+        let work = fn_handler(move |i: u32| {
+            let (running, peak) = (running_.clone(), peak_.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, ()>(i)
+            }
+        });
+
+        let handler = connect(ConcurrencyLimitLayer::new(2), work).await?;
+        assert_eq!(handler.available(), 2);
+
+        let start = tokio::time::Instant::now();
+        let outputs = join_all((0..5).map(|i| handler.call(i))).await;
+        assert_eq!(outputs, vec![Ok(0), Ok(1), Ok(2), Ok(3), Ok(4)]);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        assert_eq!(handler.available(), 2);
+        Ok(())
+    }
+}
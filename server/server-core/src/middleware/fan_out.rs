@@ -0,0 +1,243 @@
+//! Fan-out of messages to several handlers
+//!
+//! Some messages should go to more than one chain, like a chat message that
+//! is both persisted and broadcast. `FanOutLayer` clones each message to its
+//! branches (added with `FanOutLayer::to`) and to the next handler, and runs
+//! them concurrently. The outputs are in order: the next handler first, and
+//! then the branches in the order they were added.
+//!
+//! When a handler fails:
+//!
+//! - by default (fail-fast), the call fails with the first error, and the
+//!   handlers that are still running are dropped
+//! - after `FanOutLayer::collect_all`, every handler runs to the end and the
+//!   call returns the result of each of them
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::fan_out::FanOutLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn persist(msg: String) -> Result<usize, String> {
+//!     Ok(msg.len())
+//! }
+//!
+//! async fn broadcast(msg: String) -> Result<usize, String> {
+//!     Err(format!("nobody listens to {msg}"))
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), String> {
+//! let handler = apply!(FanOutLayer::new().to(fn_handler(broadcast)) to persist);
+//! assert_eq!(handler.call(String::from("hi")).await, Err(String::from("nobody listens to hi")));
+//!
+//! let handler = apply!(FanOutLayer::new().to(fn_handler(broadcast)).collect_all() to persist);
+//! let results = handler.call(String::from("hi")).await?;
+//! assert_eq!(results[0], Ok(2));
+//! assert!(results[1].is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{join_all, ok, try_join_all, LocalBoxFuture, Ready};
+
+use crate::handler::{BoxHandler, Handler};
+use crate::layer::Layer;
+
+/// the first error fails the call
+#[derive(Debug)]
+pub enum FailFast {}
+
+/// every handler runs to the end, and results are returned
+#[derive(Debug)]
+pub enum CollectAll {}
+
+/// `Layer` that builds `FanOutHandler`
+pub struct FanOutLayer<T, O, E, M = FailFast> {
+    branches: Vec<Arc<BoxHandler<T, O, E>>>,
+    _mode: PhantomData<M>,
+}
+
+impl<T, O, E> Default for FanOutLayer<T, O, E> {
+    fn default() -> Self {
+        Self {
+            branches: Vec::new(),
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl<T, O, E> FanOutLayer<T, O, E> {
+    /// returns a layer without any branch, which only calls the next handler
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns every result instead of failing with the first error
+    pub fn collect_all(self) -> FanOutLayer<T, O, E, CollectAll> {
+        FanOutLayer {
+            branches: self.branches,
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl<T, O, E, M> FanOutLayer<T, O, E, M> {
+    /// adds `handler` as a branch.
+    /// Handlers built by the layer share the branches.
+    pub fn to<H>(mut self, handler: H) -> Self
+    where
+        H: Handler<T, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        self.branches.push(Arc::new(BoxHandler::new(handler)));
+        self
+    }
+}
+
+impl<T, O, E, M, H> Layer<T, H> for FanOutLayer<T, O, E, M>
+where
+    H: Handler<T, Output = O, Error = E>,
+    FanOutHandler<T, O, E, M, H>: Handler<T>,
+{
+    type Next = T;
+    type Error = <FanOutHandler<T, O, E, M, H> as Handler<T>>::Error;
+    type Handler = FanOutHandler<T, O, E, M, H>;
+    type InitError = E;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(FanOutHandler {
+            branches: self.branches.clone(),
+            prev: Arc::new(prev),
+            _mode: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that calls the previous handler and every branch
+pub struct FanOutHandler<T, O, E, M, H> {
+    branches: Vec<Arc<BoxHandler<T, O, E>>>,
+    prev: Arc<H>,
+    _mode: PhantomData<M>,
+}
+
+impl<T, O, E, M, H> FanOutHandler<T, O, E, M, H>
+where
+    T: Clone + 'static,
+    O: 'static,
+    E: 'static,
+    H: Handler<T, Output = O, Error = E> + 'static,
+{
+    /// calls of the previous handler and every branch, in order
+    fn calls(&self, msg: T) -> Vec<LocalBoxFuture<'static, Result<O, E>>> {
+        let branches = self.branches.iter().map(|branch| branch.call(msg.clone()));
+        let branches: Vec<_> = branches.collect();
+        let prev = self.prev.clone();
+        let mut calls: Vec<LocalBoxFuture<'static, _>> =
+            vec![Box::pin(async move { prev.call(msg).await })];
+        calls.extend(branches);
+        calls
+    }
+}
+
+impl<T, O, E, H> Handler<T> for FanOutHandler<T, O, E, FailFast, H>
+where
+    T: Clone + 'static,
+    O: 'static,
+    E: 'static,
+    H: Handler<T, Output = O, Error = E> + 'static,
+{
+    type Output = Vec<O>;
+    type Error = E;
+    type Future = LocalBoxFuture<'static, Result<Vec<O>, E>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        Box::pin(try_join_all(self.calls(msg)))
+    }
+}
+
+impl<T, O, E, H> Handler<T> for FanOutHandler<T, O, E, CollectAll, H>
+where
+    T: Clone + 'static,
+    O: 'static,
+    E: 'static,
+    H: Handler<T, Output = O, Error = E> + 'static,
+{
+    type Output = Vec<Result<O, E>>;
+    type Error = E;
+    type Future = LocalBoxFuture<'static, Result<Vec<Result<O, E>>, E>>;
+
+    /// never fails by itself
+    fn call(&self, msg: T) -> Self::Future {
+        let calls = join_all(self.calls(msg));
+        Box::pin(async move { Ok(calls.await) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn double(i: u32) -> Result<u32, u32> {
+        Ok(i * 2)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fail_fast_test() -> Result<(), u32> {
+        let done = Rc::new(RefCell::new(Vec::new()));
+        let done_ = done.clone();
+        let slow = fn_handler(move |i: u32| {
+            let done = done_.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                done.borrow_mut().push(i);
+                Ok(i + 1)
+            }
+        });
+        let odd = fn_handler(|i: u32| async move {
+            if i.is_multiple_of(2) {
+                Ok(i)
+            } else {
+                Err(i)
+            }
+        });
+        let layer = FanOutLayer::new().to(slow).to(odd);
+        let handler = connect(layer, double).await?;
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(handler.call(2).await, Ok(vec![4, 3, 2]));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // the slow branch is dropped
+        assert_eq!(handler.call(3).await, Err(3));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(*done.borrow(), vec![2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_all_test() -> Result<(), u32> {
+        let fail = fn_handler(|i: u32| async move { Err::<u32, _>(i) });
+        let layer = FanOutLayer::new()
+            .to(fail)
+            .to(fn_handler(double))
+            .collect_all();
+        let handler = connect(layer, double).await?;
+        assert_eq!(handler.call(1).await?, vec![Ok(2), Err(1), Ok(2)]);
+        Ok(())
+    }
+}
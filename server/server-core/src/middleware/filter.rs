@@ -0,0 +1,250 @@
+//! Dropping of messages by a predicate
+//!
+//! Some messages should not go any further, like stale updates of game
+//! states or kinds of messages that need authentication. `FilterLayer` tests
+//! each message with a predicate, and only messages that pass it go to the
+//! next handler. The rest are dropped silently and resolve to `None`, or
+//! fail with `Filtered` after `FilterLayer::reject`.
+//!
+//! Predicates can be sync (`FilterLayer::new`) or async
+//! (`FilterLayer::new_async`, e.g. a lookup in a database).
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::filter::{FilterLayer, Filtered};
+//! use cubby_connect_server_core::apply;
+//!
+//! struct Update {
+//!     tick: u64,
+//! }
+//!
+//! async fn apply(update: Update) -> Result<u64, Filtered> {
+//!     Ok(update.tick)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Filtered> {
+//! let handler = apply!(FilterLayer::new(|u: &Update| u.tick >= 10) to apply);
+//! assert_eq!(handler.call(Update { tick: 12 }).await?, Some(12));
+//! assert_eq!(handler.call(Update { tick: 3 }).await?, None);
+//!
+//! let handler = apply!(FilterLayer::new(|u: &Update| u.tick >= 10).reject() to apply);
+//! assert_eq!(handler.call(Update { tick: 3 }).await, Err(Filtered));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{ok, ready, LocalBoxFuture, Ready};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// error of messages that didn't pass the predicate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Filtered;
+
+impl Display for Filtered {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message is filtered")
+    }
+}
+
+impl std::error::Error for Filtered {}
+
+/// test of messages that may be async
+pub trait Predicate<T> {
+    /// future of the result
+    type Future: Future<Output = bool>;
+
+    /// returns true if `msg` should go to the next handler
+    fn test(&self, msg: &T) -> Self::Future;
+}
+
+impl<T, F> Predicate<T> for F
+where
+    F: Fn(&T) -> bool,
+{
+    type Future = Ready<bool>;
+
+    fn test(&self, msg: &T) -> Self::Future {
+        ready(self(msg))
+    }
+}
+
+/// `Predicate` of async closures/functions.
+/// The future should not borrow the message.
+pub struct AsyncPredicate<F>(F);
+
+impl<T, F, Fut> Predicate<T> for AsyncPredicate<F>
+where
+    F: Fn(&T) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    type Future = Fut;
+
+    fn test(&self, msg: &T) -> Self::Future {
+        (self.0)(msg)
+    }
+}
+
+/// filtered messages resolve to `None`
+#[derive(Debug)]
+pub enum Skip {}
+
+/// filtered messages fail with `Filtered`
+#[derive(Debug)]
+pub enum Reject {}
+
+/// `Layer` that builds `FilterHandler`
+pub struct FilterLayer<P, M = Skip> {
+    predicate: Arc<P>,
+    _mode: PhantomData<M>,
+}
+
+impl<P> FilterLayer<P> {
+    /// passes messages for which `predicate` returns true
+    pub fn new(predicate: P) -> Self {
+        Self {
+            predicate: Arc::new(predicate),
+            _mode: PhantomData,
+        }
+    }
+
+    /// makes filtered messages fail with `Filtered` instead of `None`
+    pub fn reject(self) -> FilterLayer<P, Reject> {
+        FilterLayer {
+            predicate: self.predicate,
+            _mode: PhantomData,
+        }
+    }
+}
+
+impl<F> FilterLayer<AsyncPredicate<F>> {
+    /// passes messages for which the future of `predicate` resolves to true
+    pub fn new_async(predicate: F) -> Self {
+        Self::new(AsyncPredicate(predicate))
+    }
+}
+
+impl<T, P, M, H> Layer<T, H> for FilterLayer<P, M>
+where
+    P: Predicate<T> + 'static,
+    H: Handler<T>,
+    FilterHandler<P, M, H>: Handler<T>,
+{
+    type Next = T;
+    type Error = <FilterHandler<P, M, H> as Handler<T>>::Error;
+    type Handler = FilterHandler<P, M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(FilterHandler {
+            predicate: self.predicate.clone(),
+            prev: Arc::new(prev),
+            _mode: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that passes only messages that pass the predicate
+pub struct FilterHandler<P, M, H> {
+    predicate: Arc<P>,
+    prev: Arc<H>,
+    _mode: PhantomData<M>,
+}
+
+impl<T, P, H> Handler<T> for FilterHandler<P, Skip, H>
+where
+    T: 'static,
+    P: Predicate<T> + 'static,
+    H: Handler<T> + 'static,
+{
+    type Output = Option<H::Output>;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, H::Error>>;
+
+    /// returns `None` if `msg` is filtered
+    fn call(&self, msg: T) -> Self::Future {
+        let test = self.predicate.test(&msg);
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            if test.await {
+                prev.call(msg).await.map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+    }
+}
+
+impl<T, P, H> Handler<T> for FilterHandler<P, Reject, H>
+where
+    T: 'static,
+    P: Predicate<T> + 'static,
+    H: Handler<T> + 'static,
+    H::Error: From<Filtered>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let test = self.predicate.test(&msg);
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            if test.await {
+                prev.call(msg).await
+            } else {
+                Err(Filtered.into())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn id(i: u32) -> Result<u32, Filtered> {
+        Ok(i)
+    }
+
+    #[tokio::test]
+    async fn skip_test() -> Result<(), Filtered> {
+        let handler = connect(FilterLayer::new(|i: &u32| i.is_multiple_of(2)), id).await?;
+        assert_eq!(handler.call(2).await, Ok(Some(2)));
+        assert_eq!(handler.call(3).await, Ok(None));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_reject_test() -> Result<(), Filtered> {
+        let allowed: Arc<HashSet<u32>> = Arc::new([1, 2].into_iter().collect());
+        let layer = FilterLayer::new_async(move |i: &u32| {
+            let (allowed, i) = (allowed.clone(), *i);
+            async move {
+                tokio::task::yield_now().await;
+                allowed.contains(&i)
+            }
+        })
+        .reject();
+        let handler = connect(layer, id).await?;
+        assert_eq!(handler.call(1).await, Ok(1));
+        assert_eq!(handler.call(3).await, Err(Filtered));
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Observation of messages without changing them
+//!
+//! `InspectLayer` calls a callback with a reference to every message before
+//! it goes to the next handler, which is all that debugging probes, counters
+//! and samplers need. The message and the result of the next handler are not
+//! changed.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::inspect::InspectLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(msg: String) -> Result<usize, ()> {
+//!     Ok(msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let bytes = Arc::new(AtomicUsize::new(0));
+//! let bytes_ = bytes.clone();
+//! let handler = apply!(InspectLayer::new(move |msg: &String| {
+//!     bytes_.fetch_add(msg.len(), Ordering::SeqCst);
+//! }) to chat);
+//!
+//! assert_eq!(handler.call(String::from("hello")).await?, 5);
+//! assert_eq!(bytes.load(Ordering::SeqCst), 5);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use futures::future::{ok, Ready};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `InspectHandler`
+pub struct InspectLayer<F> {
+    f: Arc<F>,
+}
+
+impl<F> InspectLayer<F> {
+    /// calls `f` with every message
+    pub fn new(f: F) -> Self {
+        Self { f: Arc::new(f) }
+    }
+}
+
+impl<T, F, H> Layer<T, H> for InspectLayer<F>
+where
+    F: Fn(&T),
+    H: Handler<T>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = InspectHandler<F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(InspectHandler {
+            f: self.f.clone(),
+            prev,
+        })
+    }
+}
+
+/// returns `handler` that calls `f` with every message first
+pub fn inspect<H, F>(handler: H, f: F) -> InspectHandler<F, H> {
+    InspectHandler {
+        f: Arc::new(f),
+        prev: handler,
+    }
+}
+
+/// `Handler` that shows messages to a callback before the previous handler
+pub struct InspectHandler<F, H> {
+    f: Arc<F>,
+    prev: H,
+}
+
+impl<T, F, H> Handler<T> for InspectHandler<F, H>
+where
+    F: Fn(&T),
+    H: Handler<T>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = H::Future;
+
+    fn call(&self, msg: T) -> Self::Future {
+        (self.f)(&msg);
+        self.prev.call(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn negate(i: i32) -> Result<i32, ()> {
+        Ok(-i)
+    }
+
+    #[tokio::test]
+    async fn inspect_test() -> Result<(), ()> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ = seen.clone();
+        let layer = InspectLayer::new(move |i: &i32| seen_.lock().unwrap().push(*i));
+        let handler = connect(layer, negate).await?;
+
+        assert_eq!(handler.call(1).await?, -1);
+        assert_eq!(handler.call(2).await?, -2);
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Join of two message streams by key
+//!
+//! Some pipelines need to match messages of two routes, like commands with
+//! their confirmations. `JoinLayer` keeps messages of each side for a time
+//! window, and when a message of the other side with the same key comes,
+//! sends the pair to the next handler. Messages that are not matched in the
+//! window are dropped.
+//!
+//! The handler takes `Side<L, R>`. `JoinHandler::left` and
+//! `JoinHandler::right` return handlers of each side, which can be used as
+//! separate routes.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::layer::connect;
+//! use cubby_connect_server_core::middleware::join::JoinLayer;
+//!
+//! struct Command {
+//!     id: u64,
+//!     action: &'static str,
+//! }
+//!
+//! struct Confirm {
+//!     id: u64,
+//! }
+//!
+//! async fn done((command, _): (Command, Confirm)) -> Result<&'static str, ()> {
+//!     Ok(command.action)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = JoinLayer::new(Duration::from_secs(5), |c: &Command| c.id, |c: &Confirm| c.id);
+//! let handler = connect(layer, done).await?;
+//! let (commands, confirms) = (handler.left(), handler.right());
+//!
+//! assert_eq!(commands.call(Command { id: 1, action: "jump" }).await?, None);
+//! assert_eq!(confirms.call(Confirm { id: 1 }).await?, Some("jump"));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, Either, MapOk, Ready};
+use futures::TryFutureExt;
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// message of either side of a join
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Side<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// `Layer` that builds `JoinHandler`
+pub struct JoinLayer<FL, FR> {
+    window: Duration,
+    left_key: Arc<FL>,
+    right_key: Arc<FR>,
+}
+
+impl<FL, FR> JoinLayer<FL, FR> {
+    /// matches messages whose keys are the same within `window`.
+    /// `left_key` and `right_key` extract the keys of each side.
+    pub fn new(window: Duration, left_key: FL, right_key: FR) -> Self {
+        Self {
+            window,
+            left_key: Arc::new(left_key),
+            right_key: Arc::new(right_key),
+        }
+    }
+}
+
+impl<L, R, K, FL, FR, H> Layer<Side<L, R>, H> for JoinLayer<FL, FR>
+where
+    K: Hash + Eq,
+    FL: Fn(&L) -> K,
+    FR: Fn(&R) -> K,
+    H: Handler<(L, R)>,
+{
+    type Next = (L, R);
+    type Error = H::Error;
+    type Handler = JoinHandler<L, R, K, FL, FR, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(JoinHandler {
+            inner: Arc::new(Inner {
+                window: self.window,
+                left_key: self.left_key.clone(),
+                right_key: self.right_key.clone(),
+                pending: Mutex::new((HashMap::new(), HashMap::new())),
+                prev,
+            }),
+        })
+    }
+}
+
+type Pending<K, T> = HashMap<K, VecDeque<(Instant, T)>>;
+
+struct Inner<L, R, K, FL, FR, H> {
+    window: Duration,
+    left_key: Arc<FL>,
+    right_key: Arc<FR>,
+    pending: Mutex<(Pending<K, L>, Pending<K, R>)>,
+    prev: H,
+}
+
+/// removes messages older than `window` from `pending`
+fn purge<K, T>(pending: &mut Pending<K, T>, now: Instant, window: Duration) {
+    pending.retain(|_, queue| {
+        queue.retain(|(at, _)| now.duration_since(*at) < window);
+        !queue.is_empty()
+    });
+}
+
+/// takes the oldest message of `key` from `pending`
+fn take<K: Hash + Eq, T>(pending: &mut Pending<K, T>, key: &K) -> Option<T> {
+    let queue = pending.get_mut(key)?;
+    let (_, msg) = queue.pop_front()?;
+    if queue.is_empty() {
+        pending.remove(key);
+    }
+    Some(msg)
+}
+
+/// `Handler` that sends matched pairs to the previous handler.
+/// Clones share the same pending messages.
+pub struct JoinHandler<L, R, K, FL, FR, H> {
+    inner: Arc<Inner<L, R, K, FL, FR, H>>,
+}
+
+impl<L, R, K, FL, FR, H> Clone for JoinHandler<L, R, K, FL, FR, H> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<L, R, K, FL, FR, H> JoinHandler<L, R, K, FL, FR, H> {
+    /// returns a handler of messages of the left side
+    pub fn left(&self) -> JoinSide<Self, L, Side<L, R>> {
+        JoinSide {
+            join: self.clone(),
+            wrap: Side::Left,
+        }
+    }
+
+    /// returns a handler of messages of the right side
+    pub fn right(&self) -> JoinSide<Self, R, Side<L, R>> {
+        JoinSide {
+            join: self.clone(),
+            wrap: Side::Right,
+        }
+    }
+
+    /// returns the number of messages that wait for a match
+    pub fn pending(&self) -> usize {
+        let pending = self.inner.pending.lock().unwrap();
+        let left: usize = pending.0.values().map(VecDeque::len).sum();
+        let right: usize = pending.1.values().map(VecDeque::len).sum();
+        left + right
+    }
+}
+
+impl<L, R, K, FL, FR, H> Handler<Side<L, R>> for JoinHandler<L, R, K, FL, FR, H>
+where
+    K: Hash + Eq,
+    FL: Fn(&L) -> K,
+    FR: Fn(&R) -> K,
+    H: Handler<(L, R)>,
+{
+    type Output = Option<H::Output>;
+    type Error = H::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<
+        MapOk<H::Future, fn(H::Output) -> Option<H::Output>>,
+        Ready<Result<Option<H::Output>, H::Error>>,
+    >;
+
+    /// returns `None` if the message waits for a match
+    fn call(&self, msg: Side<L, R>) -> Self::Future {
+        let inner = &self.inner;
+        let now = Instant::now();
+        let mut pending = inner.pending.lock().unwrap();
+        let (lefts, rights) = &mut *pending;
+        purge(lefts, now, inner.window);
+        purge(rights, now, inner.window);
+
+        let pair = match msg {
+            Side::Left(l) => {
+                let key = (inner.left_key)(&l);
+                match take(rights, &key) {
+                    Some(r) => (l, r),
+                    None => {
+                        lefts.entry(key).or_default().push_back((now, l));
+                        return Either::Right(ok(None));
+                    }
+                }
+            }
+            Side::Right(r) => {
+                let key = (inner.right_key)(&r);
+                match take(lefts, &key) {
+                    Some(l) => (l, r),
+                    None => {
+                        rights.entry(key).or_default().push_back((now, r));
+                        return Either::Right(ok(None));
+                    }
+                }
+            }
+        };
+        drop(pending);
+
+        Either::Left(inner.prev.call(pair).map_ok(Some as fn(_) -> _))
+    }
+}
+
+/// `Handler` of one side of `JoinHandler`
+pub struct JoinSide<J, T, M> {
+    join: J,
+    wrap: fn(T) -> M,
+}
+
+impl<J, T, M> Handler<T> for JoinSide<J, T, M>
+where
+    J: Handler<M>,
+{
+    type Output = J::Output;
+    type Error = J::Error;
+    type Future = J::Future;
+
+    fn call(&self, msg: T) -> Self::Future {
+        self.join.call((self.wrap)(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn pair(p: (u32, &'static str)) -> Result<(u32, &'static str), ()> {
+        Ok(p)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_test() -> Result<(), ()> {
+        let layer = JoinLayer::new(
+            Duration::from_secs(1),
+            |l: &u32| *l % 10,
+            |r: &&'static str| r.len() as u32,
+        );
+        let handler = connect(layer, pair).await?;
+        let (left, right) = (handler.left(), handler.right());
+
+        assert_eq!(left.call(1).await?, None);
+        assert_eq!(left.call(11).await?, None);
+        assert_eq!(right.call("ab").await?, None);
+        assert_eq!(handler.pending(), 3);
+
+        // the oldest message is matched first
+        assert_eq!(right.call("a").await?, Some((1, "a")));
+        assert_eq!(handler.call(Side::Left(2)).await?, Some((2, "ab")));
+
+        // unmatched messages expire
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(right.call("b").await?, None);
+        assert_eq!(handler.pending(), 1);
+        Ok(())
+    }
+}
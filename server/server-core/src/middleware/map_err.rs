@@ -0,0 +1,241 @@
+//! Conversion of errors of the next handler
+//!
+//! Layers pass the error of the next handler back as their own error, so
+//! every handler of a chain usually shares one error type. A handler of
+//! another error type can join the chain through:
+//!
+//! - `ErrIntoLayer`, which converts errors with `Into` (e.g. into
+//!   `error::CubbyError`)
+//! - `MapErrLayer` or `map_err`, which convert errors with a function
+//!
+//! A whole chain is unified into one error type with
+//! `apply!(... to handler into E)` (or `layer::connect_into`), which
+//! converts errors on both sides of every layer with `Into`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::error::CubbyError;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::map_err::{ErrIntoLayer, MapErrLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn parse(msg: String) -> Result<u32, std::num::ParseIntError> {
+//!     msg.parse()
+//! }
+//!
+//! async fn read(path: String) -> Result<String, std::io::Error> {
+//!     std::fs::read_to_string(path)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), CubbyError> {
+//! let handler = apply!(MapErrLayer::new(CubbyError::other) to parse);
+//! assert_eq!(handler.call(String::from("42")).await?, 42);
+//!
+//! let handler = apply!(ErrIntoLayer::<CubbyError>::new() to read);
+//! assert!(matches!(handler.call(String::from("/nothing")).await, Err(CubbyError::Io(_))));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+
+use futures::future::{ok, ErrInto, MapErr, Ready};
+use futures::TryFutureExt;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `MapErrHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct MapErrLayer<F> {
+    f: F,
+}
+
+impl<F> MapErrLayer<F> {
+    /// converts errors of the next handler with `f`
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<T, F, E, H> Layer<T, H> for MapErrLayer<F>
+where
+    F: Fn(H::Error) -> E + Clone,
+    H: Handler<T>,
+{
+    type Next = T;
+    type Error = E;
+    type Handler = MapErrHandler<F, H>;
+    type InitError = E;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(map_err(prev, self.f.clone()))
+    }
+}
+
+/// returns `handler` whose errors are converted with `f`
+pub fn map_err<H, F>(handler: H, f: F) -> MapErrHandler<F, H> {
+    MapErrHandler { f, prev: handler }
+}
+
+/// `Handler` that converts errors of the previous handler with a function
+pub struct MapErrHandler<F, H> {
+    f: F,
+    prev: H,
+}
+
+impl<T, F, E, H> Handler<T> for MapErrHandler<F, H>
+where
+    F: Fn(H::Error) -> E + Clone,
+    H: Handler<T>,
+{
+    type Output = H::Output;
+    type Error = E;
+    type Future = MapErr<H::Future, F>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        self.prev.call(msg).map_err(self.f.clone())
+    }
+}
+
+/// `Layer` that builds `ErrIntoHandler`
+#[derive(Debug)]
+pub struct ErrIntoLayer<E> {
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<E> Default for ErrIntoLayer<E> {
+    fn default() -> Self {
+        Self {
+            _error: PhantomData,
+        }
+    }
+}
+
+impl<E> ErrIntoLayer<E> {
+    /// converts errors of the next handler into `E`
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T, E, H> Layer<T, H> for ErrIntoLayer<E>
+where
+    H: Handler<T>,
+    H::Error: Into<E>,
+{
+    type Next = T;
+    type Error = E;
+    type Handler = ErrIntoHandler<E, H>;
+    type InitError = E;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(err_into(prev))
+    }
+}
+
+/// returns `handler` whose errors are converted into `E` with `Into`
+pub fn err_into<E, H>(handler: H) -> ErrIntoHandler<E, H> {
+    ErrIntoHandler {
+        prev: handler,
+        _error: PhantomData,
+    }
+}
+
+/// `Handler` that converts errors of the previous handler with `Into`
+pub struct ErrIntoHandler<E, H> {
+    prev: H,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<T, E, H> Handler<T> for ErrIntoHandler<E, H>
+where
+    H: Handler<T>,
+    H::Error: Into<E>,
+{
+    type Output = H::Output;
+    type Error = E;
+    type Future = ErrInto<H::Future, E>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        self.prev.call(msg).err_into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::error::CubbyError;
+    use crate::fn_handler::fn_handler;
+    use crate::layer::{connect, connect_into};
+    use crate::middleware::filter::FilterLayer;
+    use crate::middleware::timeout::TimeoutLayer;
+
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct Negative(i32);
+
+    async fn check(i: i32) -> Result<i32, Negative> {
+        if i < 0 {
+            Err(Negative(i))
+        } else {
+            Ok(i)
+        }
+    }
+
+    #[tokio::test]
+    async fn map_err_test() -> Result<(), String> {
+        let handler = connect(MapErrLayer::new(|e: Negative| format!("{e:?}")), check).await?;
+        assert_eq!(handler.call(1).await?, 1);
+        assert_eq!(handler.call(-1).await, Err(String::from("Negative(-1)")));
+
+        let handler = map_err(fn_handler(check), |e: Negative| e.0);
+        assert_eq!(handler.call(-2).await, Err(-2));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn err_into_test() -> Result<(), CubbyError> {
+        // `TimeoutLayer` needs the error of the next handler to convert
+        // from `TimedOut`, which `io::Error` doesn't
+        let slow = fn_handler(|_: ()| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, std::io::Error>(())
+        });
+        let handler = connect(ErrIntoLayer::<CubbyError>::new(), slow).await?;
+        let handler = connect(TimeoutLayer::new(Duration::from_millis(10)), handler).await?;
+        assert!(matches!(handler.call(()).await, Err(CubbyError::TimedOut)));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_into_test() -> Result<(), CubbyError> {
+        // a filter failing with `Filtered`, a timeout needing
+        // `From<TimedOut>` and a handler failing with `io::Error`
+        let slow = fn_handler(|i: i32| async move {
+            tokio::time::sleep(Duration::from_secs(i as u64)).await;
+            Err::<i32, _>(std::io::Error::other("failed"))
+        });
+        let handler = connect_into::<CubbyError, _, _, _, _, _>(
+            TimeoutLayer::new(Duration::from_millis(10)),
+            slow,
+        )
+        .await?;
+        let handler = connect_into::<CubbyError, _, _, _, _, _>(
+            FilterLayer::new(|i: &i32| *i >= 0).reject(),
+            handler,
+        )
+        .await?;
+
+        assert!(matches!(handler.call(-1).await, Err(CubbyError::Filtered)));
+        assert!(matches!(handler.call(1).await, Err(CubbyError::TimedOut)));
+        assert!(matches!(handler.call(0).await, Err(CubbyError::Io(_))));
+        Ok(())
+    }
+}
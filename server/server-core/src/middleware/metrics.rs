@@ -0,0 +1,237 @@
+//! Metrics of calls through the `metrics` facade
+//!
+//! `MetricsLayer` records how many times the next handler is called, how
+//! many of the calls fail, and how long they take, labeled with a name.
+//! Putting it at several points of an `apply!` pipeline with different
+//! names shows where time is spent: the latency of a name includes every
+//! layer and handler after it.
+//!
+//! Metrics go to the recorder installed by the application (e.g. a
+//! prometheus exporter). Without one, recording does nothing. The names of
+//! the metrics are `CALLS`, `ERRORS` and `LATENCY`, with the label `NAME`.
+//!
+//! `record_client` counts connected clients in `CLIENTS` by the
+//! `handshake::ClientInfo` they sent, to watch how a rollout goes.
+//!
+//! This module needs the `metrics` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::metrics::{describe, MetricsLayer};
+//! use cubby_connect_server_core::middleware::timeout::{TimedOut, TimeoutLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(msg: String) -> Result<usize, TimedOut> {
+//!     Ok(msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), TimedOut> {
+//! describe();
+//! let handler = apply!(
+//!     MetricsLayer::new("pipeline"),
+//!     TimeoutLayer::new(Duration::from_secs(1)),
+//!     MetricsLayer::new("chat")
+//!     to chat
+//! );
+//! assert_eq!(handler.call(String::from("hello")).await?, 5);
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Counter, Histogram, Unit};
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::handshake::ClientInfo;
+use crate::layer::Layer;
+
+/// counter of calls
+pub const CALLS: &str = "cubby_calls_total";
+
+/// counter of calls that returned an error
+pub const ERRORS: &str = "cubby_errors_total";
+
+/// histogram of call durations in seconds
+pub const LATENCY: &str = "cubby_call_duration_seconds";
+
+/// counter of connected clients, labeled by their `ClientInfo`
+pub const CLIENTS: &str = "cubby_clients_total";
+
+/// label with the name of `MetricsLayer`
+pub const NAME: &str = "name";
+
+/// describes the metrics to the installed recorder.
+/// Exporters show the descriptions and units of described metrics.
+pub fn describe() {
+    describe_counter!(CALLS, "calls of handlers");
+    describe_counter!(ERRORS, "calls of handlers that returned an error");
+    describe_histogram!(LATENCY, Unit::Seconds, "durations of calls of handlers");
+    describe_counter!(CLIENTS, "connected clients by version, os and arch");
+}
+
+/// counts a client that connected with `info`.
+/// The app version is empty if the client did not tell it.
+pub fn record_client(info: &ClientInfo) {
+    counter!(
+        CLIENTS,
+        "crate_version" => info.crate_version.clone(),
+        "app_version" => info.app_version.clone().unwrap_or_default(),
+        "os" => info.os.clone(),
+        "arch" => info.arch.clone()
+    )
+    .increment(1);
+}
+
+/// `Layer` that builds `MetricsHandler`
+#[derive(Clone, Debug)]
+pub struct MetricsLayer {
+    name: String,
+}
+
+impl MetricsLayer {
+    /// records calls of the next handler with the label `name`
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl<T, H> Layer<T, H> for MetricsLayer
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = MetricsHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    /// metrics are registered to the recorder installed at this time
+    fn new_handler(&self, prev: H) -> Self::Future {
+        let name = self.name.clone();
+        ok(MetricsHandler {
+            calls: counter!(CALLS, NAME => name.clone()),
+            errors: counter!(ERRORS, NAME => name.clone()),
+            latency: histogram!(LATENCY, NAME => name),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that records calls of the previous handler
+pub struct MetricsHandler<H> {
+    calls: Counter,
+    errors: Counter,
+    latency: Histogram,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for MetricsHandler<H>
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    /// failed calls are recorded in the latency as well
+    fn call(&self, msg: T) -> Self::Future {
+        let errors = self.errors.clone();
+        let latency = self.latency.clone();
+        let start = Instant::now();
+        self.calls.increment(1);
+        let future = self.prev.call(msg);
+
+        Box::pin(async move {
+            let result = future.await;
+            latency.record(start.elapsed());
+            if result.is_err() {
+                errors.increment(1);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[test]
+    fn metrics_test() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+
+        metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                let work = fn_handler(|ms: u64| async move {
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    if ms > 0 {
+                        Ok(())
+                    } else {
+                        Err(())
+                    }
+                });
+                let handler = connect(MetricsLayer::new("work"), work).await.unwrap();
+                handler.call(10).await.unwrap();
+                handler.call(20).await.unwrap();
+                assert!(handler.call(0).await.is_err());
+            })
+        });
+
+        let mut found = 0;
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let key = key.key();
+            assert_eq!(key.labels().next().unwrap().value(), "work");
+            match (key.name(), value) {
+                (CALLS, DebugValue::Counter(calls)) => assert_eq!(calls, 3),
+                (ERRORS, DebugValue::Counter(errors)) => assert_eq!(errors, 1),
+                (LATENCY, DebugValue::Histogram(seconds)) => {
+                    let seconds: Vec<f64> = seconds.into_iter().map(|s| s.0).collect();
+                    assert_eq!(seconds, vec![0.01, 0.02, 0.0]);
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+            found += 1;
+        }
+        assert_eq!(found, 3);
+    }
+
+    #[test]
+    fn record_client_test() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let info = ClientInfo::current().app_version("2.3.1");
+        metrics::with_local_recorder(&recorder, || {
+            record_client(&info);
+            record_client(&info);
+        });
+
+        let snapshot = snapshotter.snapshot().into_vec();
+        assert_eq!(snapshot.len(), 1);
+        let (key, _, _, value) = &snapshot[0];
+        assert_eq!(key.key().name(), CLIENTS);
+        let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+        assert!(labels.contains(&("app_version", "2.3.1")));
+        assert!(labels.contains(&("os", std::env::consts::OS)));
+        assert_eq!(value, &DebugValue::Counter(2));
+    }
+}
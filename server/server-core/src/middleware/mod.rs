@@ -0,0 +1,59 @@
+//! General purpose middleware layers
+//!
+//! Layers in this module don't know anything about messages. They control
+//! how and when the next handler is called.
+//!
+//! - `aggregate`: reductions of messages over time windows
+//! - `and_then`: async steps after the next handler
+//! - `buffer`: bounded queue in front of a single worker
+//! - `concurrency_limit`: limit of calls that run at the same time
+//! - `fan_out`: fan-out of messages to several handlers
+//! - `filter`: dropping of messages by a predicate
+//! - `inspect`: observation of messages without changing them
+//! - `join`: join of two message streams by key
+//! - `map_err`: conversion of errors of the next handler
+//! - `metrics`: metrics of calls through the `metrics` facade
+//! - `rate_limit`: rate limit of messages with a token bucket
+//! - `sample`: forwarding of every Nth message or X messages per second
+//! - `spawn`: processing of messages on separate tasks, or by actors per key
+//! - `timeout`: deadline for each message
+//! - `trace`: spans of calls through `tracing`
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::timeout::{TimedOut, TimeoutLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn slow(_: ()) -> Result<(), TimedOut> {
+//!     tokio::time::sleep(Duration::from_secs(10)).await;
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), TimedOut> {
+//! let handler = apply!(TimeoutLayer::new(Duration::from_millis(10)) to slow);
+//! assert_eq!(handler.call(()).await, Err(TimedOut));
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod aggregate;
+pub mod and_then;
+pub mod buffer;
+pub mod concurrency_limit;
+pub mod fan_out;
+pub mod filter;
+pub mod inspect;
+pub mod join;
+pub mod map_err;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod rate_limit;
+pub mod sample;
+pub mod spawn;
+pub mod timeout;
+#[cfg(feature = "tracing")]
+pub mod trace;
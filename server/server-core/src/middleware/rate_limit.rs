@@ -0,0 +1,276 @@
+//! Rate limit with a token bucket
+//!
+//! `RateLimitLayer` caps how many messages per second go on to the next
+//! handler. The bucket holds up to `burst` tokens and is refilled by `rate`
+//! tokens per second, and each message takes a token.
+//!
+//! When the bucket is empty, messages fail with `RateLimited` by default.
+//! With `RateLimitLayer::wait`, they wait for their token instead, in the
+//! order they came. Messages that would wait longer than `max_wait` (5
+//! seconds by default) still fail, so a flood cannot queue up without end.
+//!
+//! Every handler that is built from the same layer shares the bucket.
+
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// default longest time that a message waits for its token
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// error of messages over the rate limit
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimited;
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate limit exceeded")
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// token bucket
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// takes a token if there is one
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// reserves a token and returns how long to wait for it.
+    /// Returns `None` without reserving if it is longer than `max`.
+    fn reserve(&mut self, now: Instant, max: Duration) -> Option<Duration> {
+        self.refill(now);
+        let tokens = self.tokens - 1.0;
+        let delay = match tokens >= 0.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64(-tokens / self.rate),
+        };
+        if delay > max {
+            return None;
+        }
+        self.tokens = tokens;
+        Some(delay)
+    }
+}
+
+/// `Layer` that builds `RateLimitHandler`
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    bucket: Arc<Mutex<Bucket>>,
+    wait: bool,
+    max_wait: Duration,
+}
+
+impl RateLimitLayer {
+    /// allows `rate` messages per second on average and up to `burst`
+    /// messages at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` or `burst` is zero.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        assert!(rate > 0, "rate should be positive");
+        assert!(burst > 0, "burst should be positive");
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: rate as f64,
+                burst: burst as f64,
+                tokens: burst as f64,
+                last: Instant::now(),
+            })),
+            wait: false,
+            max_wait: DEFAULT_MAX_WAIT,
+        }
+    }
+
+    /// makes messages wait until their token is available
+    /// instead of failing with `RateLimited`
+    pub fn wait(mut self) -> Self {
+        self.wait = true;
+        self
+    }
+
+    /// fails messages that would wait longer than `max_wait` with
+    /// `RateLimited` (5 seconds by default)
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+}
+
+impl<T, H> Layer<T, H> for RateLimitLayer
+where
+    T: 'static,
+    H: Handler<T> + 'static,
+    H::Error: From<RateLimited>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = RateLimitHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(RateLimitHandler {
+            bucket: self.bucket.clone(),
+            wait: self.wait,
+            max_wait: self.max_wait,
+            prev: Arc::new(prev),
+        })
+    }
+}
+
+/// `Handler` that limits the rate of messages to the previous handler
+pub struct RateLimitHandler<H> {
+    bucket: Arc<Mutex<Bucket>>,
+    wait: bool,
+    max_wait: Duration,
+    prev: Arc<H>,
+}
+
+impl<T, H> Handler<T> for RateLimitHandler<H>
+where
+    T: 'static,
+    H: Handler<T> + 'static,
+    H::Error: From<RateLimited>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, LocalBoxFuture<'static, Result<H::Output, H::Error>>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let now = Instant::now();
+        // the guard is dropped before calling the previous handler
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            match self.wait {
+                true => bucket.reserve(now, self.max_wait),
+                false => bucket.try_take(now).then_some(Duration::ZERO),
+            }
+        };
+
+        let delay = match delay {
+            Some(delay) => delay,
+            None => return Either::Right(Box::pin(async { Err(RateLimited.into()) })),
+        };
+        if delay.is_zero() {
+            return Either::Left(self.prev.call(msg));
+        }
+        let prev = self.prev.clone();
+        Either::Right(Box::pin(async move {
+            tokio::time::sleep_until(now + delay).await;
+            prev.call(msg).await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn id(i: u32) -> Result<u32, RateLimited> {
+        Ok(i)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reject_test() -> Result<(), RateLimited> {
+        let handler = connect(RateLimitLayer::new(2, 3), id).await?;
+
+        for i in 0..3 {
+            assert_eq!(handler.call(i).await, Ok(i));
+        }
+        assert_eq!(handler.call(3).await, Err(RateLimited));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(handler.call(4).await, Ok(4));
+        assert_eq!(handler.call(5).await, Err(RateLimited));
+
+        // refill doesn't go over the burst
+        tokio::time::advance(Duration::from_secs(10)).await;
+        for i in 0..3 {
+            assert_eq!(handler.call(i).await, Ok(i));
+        }
+        assert_eq!(handler.call(3).await, Err(RateLimited));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_test() -> Result<(), RateLimited> {
+        let handler = connect(RateLimitLayer::new(10, 1).wait(), id).await?;
+
+        let start = Instant::now();
+        let calls: Vec<_> = (0..5).map(|i| handler.call(i)).collect();
+        for (i, call) in calls.into_iter().enumerate() {
+            assert_eq!(call.await, Ok(i as u32));
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(400));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_wait_test() -> Result<(), RateLimited> {
+        let layer = RateLimitLayer::new(10, 1)
+            .wait()
+            .max_wait(Duration::from_millis(250));
+        let handler = connect(layer, id).await?;
+
+        let calls: Vec<_> = (0..5).map(|i| handler.call(i)).collect();
+        let results: Vec<_> = futures::future::join_all(calls).await;
+        assert_eq!(
+            results,
+            vec![Ok(0), Ok(1), Ok(2), Err(RateLimited), Err(RateLimited)]
+        );
+
+        // rejected calls did not take tokens
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(handler.call(5).await, Ok(5));
+        Ok(())
+    }
+
+    #[test]
+    fn guard_test() {
+        let layer = RateLimitLayer::new(10, 10);
+        let bucket = layer.bucket.clone();
+        let handler = RateLimitHandler {
+            bucket: layer.bucket.clone(),
+            wait: false,
+            max_wait: DEFAULT_MAX_WAIT,
+            prev: Arc::new(fn_handler(move |i: u32| {
+                // the bucket is not locked while the previous handler is called
+                assert!(bucket.try_lock().is_ok());
+                async move { Ok::<_, RateLimited>(i) }
+            })),
+        };
+        drop(handler.call(1));
+    }
+}
@@ -0,0 +1,258 @@
+//! Sampling of high-frequency messages
+//!
+//! Some streams, like positions or sensors, send messages much more often
+//! than handlers need them. `SampleLayer` forwards only some of them:
+//!
+//! - `SampleLayer::every(n)`: every `n`th message
+//! - `SampleLayer::per_second(x)`: at most `x` messages per second
+//!
+//! Messages that are not forwarded are dropped and resolve to `None`.
+//! With `SampleLayer::merge`, they are summarized instead: skipped messages
+//! are folded together and merged into the next forwarded message.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::sample::SampleLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn record(steps: u32) -> Result<u32, ()> {
+//!     Ok(steps)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! // forwards every third message with the sum of skipped ones
+//! let layer = SampleLayer::every(3).merge(|a: u32, b: u32| a + b);
+//! let handler = apply!(layer to record);
+//!
+//! assert_eq!(handler.call(1).await?, None);
+//! assert_eq!(handler.call(2).await?, None);
+//! assert_eq!(handler.call(3).await?, Some(6));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::{ok, Either, MapOk, Ready};
+use futures::TryFutureExt;
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// how to handle messages that are not forwarded
+pub trait Merge<T> {
+    /// called with a message that is not forwarded
+    fn skip(&self, pending: &mut Option<T>, msg: T);
+
+    /// returns the message to forward
+    fn take(&self, pending: &mut Option<T>, msg: T) -> T;
+}
+
+/// `Merge` that drops messages that are not forwarded
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Discard;
+
+impl<T> Merge<T> for Discard {
+    fn skip(&self, _: &mut Option<T>, _: T) {}
+
+    fn take(&self, _: &mut Option<T>, msg: T) -> T {
+        msg
+    }
+}
+
+impl<T, F> Merge<T> for F
+where
+    F: Fn(T, T) -> T,
+{
+    fn skip(&self, pending: &mut Option<T>, msg: T) {
+        *pending = Some(self.take(pending, msg));
+    }
+
+    fn take(&self, pending: &mut Option<T>, msg: T) -> T {
+        match pending.take() {
+            Some(prev) => self(prev, msg),
+            None => msg,
+        }
+    }
+}
+
+/// which messages are forwarded
+#[derive(Clone, Copy, Debug)]
+enum Sampler {
+    Every(u64),
+    Interval(Duration),
+}
+
+/// `Layer` that builds `SampleHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct SampleLayer<M = Discard> {
+    sampler: Sampler,
+    merge: M,
+}
+
+impl SampleLayer {
+    /// forwards every `n`th message (the `n`th, the `2n`th, ...)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn every(n: u64) -> Self {
+        assert!(n > 0, "n should be positive");
+        Self {
+            sampler: Sampler::Every(n),
+            merge: Discard,
+        }
+    }
+
+    /// forwards at most `x` messages per second.
+    /// A message is forwarded when `1 / x` seconds passed since the last
+    /// forwarded one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `x` is zero.
+    pub fn per_second(x: u32) -> Self {
+        assert!(x > 0, "x should be positive");
+        Self {
+            sampler: Sampler::Interval(Duration::from_secs(1) / x),
+            merge: Discard,
+        }
+    }
+
+    /// summarizes messages that are not forwarded with `merge`,
+    /// which merges an older message with a newer one
+    pub fn merge<M>(self, merge: M) -> SampleLayer<M> {
+        SampleLayer {
+            sampler: self.sampler,
+            merge,
+        }
+    }
+}
+
+impl<T, M, H> Layer<T, H> for SampleLayer<M>
+where
+    M: Merge<T> + Clone,
+    H: Handler<T>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = SampleHandler<T, M, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(SampleHandler {
+            sampler: self.sampler,
+            merge: self.merge.clone(),
+            state: Mutex::new(State {
+                count: 0,
+                last: None,
+                pending: None,
+            }),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+struct State<T> {
+    count: u64,
+    last: Option<Instant>,
+    pending: Option<T>,
+}
+
+/// `Handler` that forwards only samples of messages
+pub struct SampleHandler<T, M, H> {
+    sampler: Sampler,
+    merge: M,
+    state: Mutex<State<T>>,
+    prev: H,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, M, H> Handler<T> for SampleHandler<T, M, H>
+where
+    M: Merge<T>,
+    H: Handler<T>,
+{
+    type Output = Option<H::Output>;
+    type Error = H::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<
+        MapOk<H::Future, fn(H::Output) -> Option<H::Output>>,
+        Ready<Result<Option<H::Output>, H::Error>>,
+    >;
+
+    /// returns `None` if the message is not forwarded
+    fn call(&self, msg: T) -> Self::Future {
+        let mut state = self.state.lock().unwrap();
+
+        let forward = match self.sampler {
+            Sampler::Every(n) => {
+                state.count += 1;
+                state.count.is_multiple_of(n)
+            }
+            Sampler::Interval(interval) => {
+                let now = Instant::now();
+                let due = state.last.is_none_or(|last| now - last >= interval);
+                if due {
+                    state.last = Some(now);
+                }
+                due
+            }
+        };
+
+        if forward {
+            let msg = self.merge.take(&mut state.pending, msg);
+            drop(state);
+            Either::Left(self.prev.call(msg).map_ok(Some as fn(_) -> _))
+        } else {
+            self.merge.skip(&mut state.pending, msg);
+            Either::Right(ok(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn id(i: u32) -> Result<u32, ()> {
+        Ok(i)
+    }
+
+    #[tokio::test]
+    async fn every_test() -> Result<(), ()> {
+        let handler = connect(SampleLayer::every(2), id).await?;
+        let mut outputs = Vec::new();
+        for i in 1..=5 {
+            outputs.push(handler.call(i).await?);
+        }
+        assert_eq!(outputs, vec![None, Some(2), None, Some(4), None]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_second_test() -> Result<(), ()> {
+        let layer = SampleLayer::per_second(2).merge(u32::max);
+        let handler = connect(layer, id).await?;
+
+        assert_eq!(handler.call(1).await?, Some(1));
+        assert_eq!(handler.call(9).await?, None);
+        assert_eq!(handler.call(2).await?, None);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        // largest of skipped messages is merged
+        assert_eq!(handler.call(3).await?, Some(9));
+        assert_eq!(handler.call(4).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,665 @@
+//! Processing of messages on separate tasks
+//!
+//! A transport reads the next message only after the handler of the
+//! previous one returns. `SpawnLayer` keeps the read loop hot while heavy
+//! handlers run: each call of the next handler is spawned as a tokio task,
+//! and the call returns as soon as the message is accepted, with the
+//! `JoinHandle` of the task.
+//!
+//! At most `max_in_flight` tasks run at the same time. When the limit is
+//! reached, calls wait until a task finishes, which slows down the reader.
+//! Tasks are spawned on the current runtime, or on a dedicated one with
+//! `SpawnLayer::on`.
+//!
+//! Spawned calls run in any order. With `SpawnLayer::keyed`, messages with
+//! the same key (e.g. the id of a player) are processed one by one in the
+//! order they were accepted, while messages with different keys run in
+//! parallel. Each key has a queue, which is drained by one task at a time.
+//! Queued messages count toward `max_in_flight`, and the output is a
+//! `oneshot::Receiver` of the result instead of a `JoinHandle`.
+//!
+//! Stateful game logic (e.g. the state of a player) fits
+//! `KeyedSpawnLayer::actors`: an `Actor` is created for each key when its
+//! first message comes, handles the messages of the key in order with
+//! `&mut self`, and is dropped after it is idle for a while. Only the task
+//! of the key touches the actor, so its state needs no locking.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::layer::connect;
+//! use cubby_connect_server_core::middleware::spawn::SpawnLayer;
+//!
+//! async fn render(map: u32) -> Result<u32, ()> {
+//!     // something heavy
+//!     Ok(map * 2)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let handler = connect(SpawnLayer::new(64), render).await?;
+//! let task = handler.call(21).await?;
+//! // the result can be awaited later, or ignored
+//! assert_eq!(task.await.unwrap(), Ok(42));
+//!
+//! // maps of the same region are rendered in order
+//! let handler = connect(SpawnLayer::new(64).keyed(|map: &u32| map / 100), render).await?;
+//! let first = handler.call(101).await?;
+//! let second = handler.call(102).await?;
+//! assert_eq!(first.await.unwrap(), Ok(202));
+//! assert_eq!(second.await.unwrap(), Ok(204));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, BoxFuture, Ready};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `SpawnHandler`
+#[derive(Clone, Debug)]
+pub struct SpawnLayer {
+    max_in_flight: usize,
+    runtime: Option<Handle>,
+}
+
+impl SpawnLayer {
+    /// runs at most `max_in_flight` spawned calls at the same time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_in_flight` is zero.
+    pub fn new(max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "max_in_flight should be positive");
+        Self {
+            max_in_flight,
+            runtime: None,
+        }
+    }
+
+    /// spawns calls on `runtime` instead of the current runtime
+    pub fn on(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// processes messages with the same key from `key` one by one in order
+    pub fn keyed<T, K, F>(self, key: F) -> KeyedSpawnLayer<F>
+    where
+        K: Hash + Eq,
+        F: Fn(&T) -> K,
+    {
+        KeyedSpawnLayer {
+            max_in_flight: self.max_in_flight,
+            runtime: self.runtime,
+            key: Arc::new(key),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for SpawnLayer
+where
+    T: Send + 'static,
+    H: Handler<T> + Send + Sync + 'static,
+    H::Output: Send + 'static,
+    H::Error: Send + 'static,
+    H::Future: Send,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = SpawnHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(SpawnHandler {
+            semaphore: Arc::new(Semaphore::new(self.max_in_flight)),
+            runtime: self.runtime.clone(),
+            prev: Arc::new(prev),
+        })
+    }
+}
+
+/// `Handler` that calls the previous handler on a new task
+pub struct SpawnHandler<H> {
+    semaphore: Arc<Semaphore>,
+    runtime: Option<Handle>,
+    prev: Arc<H>,
+}
+
+impl<H> SpawnHandler<H> {
+    /// returns the number of calls that can be spawned now without waiting
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+impl<T, H> Handler<T> for SpawnHandler<H>
+where
+    T: Send + 'static,
+    H: Handler<T> + Send + Sync + 'static,
+    H::Output: Send + 'static,
+    H::Error: Send + 'static,
+    H::Future: Send,
+{
+    type Output = JoinHandle<Result<H::Output, H::Error>>;
+    type Error = H::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, H::Error>>;
+
+    /// returns when the call is spawned. The result of the previous handler
+    /// is the output of the returned task.
+    fn call(&self, msg: T) -> Self::Future {
+        let semaphore = self.semaphore.clone();
+        let runtime = self.runtime.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            // the semaphore is never closed
+            let permit = semaphore.acquire_owned().await.unwrap();
+            let task = async move {
+                let _permit = permit;
+                prev.call(msg).await
+            };
+            Ok(match runtime {
+                Some(runtime) => runtime.spawn(task),
+                None => tokio::spawn(task),
+            })
+        })
+    }
+}
+
+/// `Layer` that builds `KeyedSpawnHandler`
+#[derive(Debug)]
+pub struct KeyedSpawnLayer<F> {
+    max_in_flight: usize,
+    runtime: Option<Handle>,
+    key: Arc<F>,
+}
+
+impl<F> Clone for KeyedSpawnLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            max_in_flight: self.max_in_flight,
+            runtime: self.runtime.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<F> KeyedSpawnLayer<F> {
+    /// handles the messages of each key with an actor made by `new`,
+    /// instead of a handler shared by every key.
+    /// By default, actors are dropped after they are idle for 5 minutes.
+    pub fn actors<T, K, A, N>(self, new: N) -> ActorHandler<T, K, A, F, N>
+    where
+        K: Hash + Eq,
+        A: Actor<T>,
+        F: Fn(&T) -> K,
+        N: Fn(&K) -> A,
+    {
+        ActorHandler {
+            semaphore: Arc::new(Semaphore::new(self.max_in_flight)),
+            runtime: self.runtime,
+            key: self.key,
+            new: Arc::new(new),
+            idle_timeout: Duration::from_secs(300),
+            actors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T, H, K, F> Layer<T, H> for KeyedSpawnLayer<F>
+where
+    T: Send + 'static,
+    H: Handler<T> + Send + Sync + 'static,
+    H::Output: Send + 'static,
+    H::Error: Send + 'static,
+    H::Future: Send,
+    K: Hash + Eq + Clone + Send + 'static,
+    F: Fn(&T) -> K,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = KeyedSpawnHandler<T, H, K, F>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(KeyedSpawnHandler {
+            semaphore: Arc::new(Semaphore::new(self.max_in_flight)),
+            runtime: self.runtime.clone(),
+            key: self.key.clone(),
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            prev: Arc::new(prev),
+        })
+    }
+}
+
+/// message waiting in the queue of its key
+struct Job<T, O> {
+    msg: T,
+    result: oneshot::Sender<O>,
+    _permit: OwnedSemaphorePermit,
+}
+
+type Queues<K, T, O> = Arc<Mutex<HashMap<K, VecDeque<Job<T, O>>>>>;
+
+/// `Handler` that calls the previous handler on new tasks, one by one for
+/// messages with the same key
+pub struct KeyedSpawnHandler<T, H: Handler<T>, K, F> {
+    semaphore: Arc<Semaphore>,
+    runtime: Option<Handle>,
+    key: Arc<F>,
+
+    /// messages of keys that a task is working on.
+    /// A key is removed when its queue is drained.
+    queues: Queues<K, T, Result<H::Output, H::Error>>,
+    prev: Arc<H>,
+}
+
+impl<T, H: Handler<T>, K, F> KeyedSpawnHandler<T, H, K, F> {
+    /// returns the number of calls that can be accepted now without waiting
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+}
+
+impl<T, H, K, F> Handler<T> for KeyedSpawnHandler<T, H, K, F>
+where
+    T: Send + 'static,
+    H: Handler<T> + Send + Sync + 'static,
+    H::Output: Send + 'static,
+    H::Error: Send + 'static,
+    H::Future: Send,
+    K: Hash + Eq + Clone + Send + 'static,
+    F: Fn(&T) -> K,
+{
+    type Output = oneshot::Receiver<Result<H::Output, H::Error>>;
+    type Error = H::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, H::Error>>;
+
+    /// returns when the message is accepted. The result of the previous
+    /// handler is sent to the returned receiver.
+    fn call(&self, msg: T) -> Self::Future {
+        let key = (self.key)(&msg);
+        let semaphore = self.semaphore.clone();
+        let runtime = self.runtime.clone();
+        let queues = self.queues.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            // the semaphore is never closed
+            let permit = semaphore.acquire_owned().await.unwrap();
+            let (tx, rx) = oneshot::channel();
+            let job = Job {
+                msg,
+                result: tx,
+                _permit: permit,
+            };
+
+            let mut locked = queues.lock().unwrap();
+            if let Some(queue) = locked.get_mut(&key) {
+                queue.push_back(job);
+                return Ok(rx);
+            }
+            locked.insert(key.clone(), VecDeque::new());
+            drop(locked);
+
+            let task = drain(queues, key, job, prev);
+            match runtime {
+                Some(runtime) => runtime.spawn(task),
+                None => tokio::spawn(task),
+            };
+            Ok(rx)
+        })
+    }
+}
+
+/// calls `prev` with `job` and then with the queued messages of `key`,
+/// until the queue is empty
+async fn drain<T, H, K>(
+    queues: Queues<K, T, Result<H::Output, H::Error>>,
+    key: K,
+    mut job: Job<T, Result<H::Output, H::Error>>,
+    prev: Arc<H>,
+) where
+    H: Handler<T>,
+    K: Hash + Eq,
+{
+    let mut guard = Drained {
+        queues,
+        key,
+        done: false,
+    };
+    loop {
+        let Job { msg, result, .. } = job;
+        let _ = result.send(prev.call(msg).await);
+
+        let mut queues = guard.queues.lock().unwrap();
+        match queues.get_mut(&guard.key).and_then(VecDeque::pop_front) {
+            Some(next) => job = next,
+            None => {
+                queues.remove(&guard.key);
+                drop(queues);
+                guard.done = true;
+                return;
+            }
+        }
+    }
+}
+
+/// removes the queue of `key` if the handler panicked, so that later
+/// messages of the key are not stuck
+struct Drained<K: Hash + Eq, V> {
+    queues: Arc<Mutex<HashMap<K, V>>>,
+    key: K,
+    done: bool,
+}
+
+impl<K: Hash + Eq, V> Drop for Drained<K, V> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.remove(&self.key);
+        }
+    }
+}
+
+/// stateful handler of the messages of one key
+pub trait Actor<T> {
+    /// output of `handle`
+    type Output;
+
+    /// error of `handle`
+    type Error;
+
+    /// handles `msg`. It is not called again until the returned future
+    /// finishes.
+    fn handle(&mut self, msg: T) -> BoxFuture<'_, Result<Self::Output, Self::Error>>;
+}
+
+type Actors<K, T, O> = Arc<Mutex<HashMap<K, UnboundedSender<Job<T, O>>>>>;
+
+type ActorJob<T, A> = Job<T, Result<<A as Actor<T>>::Output, <A as Actor<T>>::Error>>;
+
+/// `Handler` that passes messages to the actor of their key, which runs
+/// on its own task
+pub struct ActorHandler<T, K, A: Actor<T>, F, N> {
+    semaphore: Arc<Semaphore>,
+    runtime: Option<Handle>,
+    key: Arc<F>,
+    new: Arc<N>,
+    idle_timeout: Duration,
+
+    /// senders to the tasks of live actors
+    actors: Actors<K, T, Result<A::Output, A::Error>>,
+}
+
+impl<T, K, A: Actor<T>, F, N> ActorHandler<T, K, A, F, N> {
+    /// drops actors that got no message for `idle_timeout`.
+    /// A message after that makes a new actor.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// returns the number of calls that can be accepted now without waiting
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    /// returns the number of live actors
+    pub fn len(&self) -> usize {
+        self.actors.lock().unwrap().len()
+    }
+
+    /// returns true if there is no live actor
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, K, A, F, N> Handler<T> for ActorHandler<T, K, A, F, N>
+where
+    T: Send + 'static,
+    K: Hash + Eq + Clone + Send + 'static,
+    A: Actor<T> + Send + 'static,
+    A::Output: Send + 'static,
+    A::Error: Send + 'static,
+    F: Fn(&T) -> K,
+    N: Fn(&K) -> A + Send + Sync + 'static,
+{
+    type Output = oneshot::Receiver<Result<A::Output, A::Error>>;
+    type Error = A::Error;
+    type Future = BoxFuture<'static, Result<Self::Output, A::Error>>;
+
+    /// returns when the message is accepted. The result of the actor is
+    /// sent to the returned receiver.
+    fn call(&self, msg: T) -> Self::Future {
+        let key = (self.key)(&msg);
+        let semaphore = self.semaphore.clone();
+        let runtime = self.runtime.clone();
+        let new = self.new.clone();
+        let idle_timeout = self.idle_timeout;
+        let actors = self.actors.clone();
+
+        Box::pin(async move {
+            // the semaphore is never closed
+            let permit = semaphore.acquire_owned().await.unwrap();
+            let (tx, rx) = oneshot::channel();
+            let mut job = Job {
+                msg,
+                result: tx,
+                _permit: permit,
+            };
+
+            let mut locked = actors.lock().unwrap();
+            if let Some(sender) = locked.get(&key) {
+                match sender.send(job) {
+                    Ok(()) => return Ok(rx),
+                    // the actor panicked
+                    Err(e) => job = e.0,
+                }
+            }
+            let (sender, jobs) = unbounded_channel();
+            let _ = sender.send(job);
+            locked.insert(key.clone(), sender);
+            drop(locked);
+
+            let task = act(new(&key), jobs, actors, key, idle_timeout);
+            match runtime {
+                Some(runtime) => runtime.spawn(task),
+                None => tokio::spawn(task),
+            };
+            Ok(rx)
+        })
+    }
+}
+
+/// passes `jobs` to `actor` until it is idle for `idle_timeout`
+async fn act<T, K, A>(
+    mut actor: A,
+    mut jobs: UnboundedReceiver<ActorJob<T, A>>,
+    actors: Actors<K, T, Result<A::Output, A::Error>>,
+    key: K,
+    idle_timeout: Duration,
+) where
+    K: Hash + Eq,
+    A: Actor<T>,
+{
+    loop {
+        let job = match timeout(idle_timeout, jobs.recv()).await {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(_) => {
+                // jobs are sent while the lock is held, so none is lost
+                let mut locked = actors.lock().unwrap();
+                match jobs.try_recv() {
+                    Ok(job) => job,
+                    Err(_) => {
+                        locked.remove(&key);
+                        return;
+                    }
+                }
+            }
+        };
+        let Job { msg, result, .. } = job;
+        let _ = result.send(actor.handle(msg).await);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn spawn_test() -> Result<(), ()> {
+        let work = fn_handler(|i: u32| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, ()>(i)
+        });
+        let handler = connect(SpawnLayer::new(2), work).await?;
+
+        // calls return before the work is done
+        let start = tokio::time::Instant::now();
+        let first = handler.call(1).await?;
+        let second = handler.call(2).await?;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(handler.available(), 0);
+
+        // waits for a free slot
+        let third = handler.call(3).await?;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        assert_eq!(first.await.unwrap(), Ok(1));
+        assert_eq!(second.await.unwrap(), Ok(2));
+        assert_eq!(third.await.unwrap(), Ok(3));
+        assert_eq!(handler.available(), 2);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keyed_test() -> Result<(), ()> {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let work = fn_handler({
+            let done = done.clone();
+            move |(player, secs): (u32, u64)| {
+                let done = done.clone();
+                async move {
+                    tokio::time::sleep(Duration::from_secs(secs)).await;
+                    done.lock().unwrap().push((player, secs));
+                    Ok::<_, ()>(player)
+                }
+            }
+        });
+        let layer = SpawnLayer::new(3).keyed(|&(player, _): &(u32, u64)| player);
+        let handler = connect(layer, work).await?;
+
+        let start = tokio::time::Instant::now();
+        let slow = handler.call((1, 2)).await?;
+        let fast = handler.call((1, 1)).await?;
+        let other = handler.call((2, 1)).await?;
+        assert_eq!(handler.available(), 0);
+
+        // the queued message of player 1 waits for the slow one
+        assert_eq!(other.await.unwrap(), Ok(2));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(slow.await.unwrap(), Ok(1));
+        assert_eq!(fast.await.unwrap(), Ok(1));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        assert_eq!(*done.lock().unwrap(), vec![(2, 1), (1, 2), (1, 1)]);
+
+        assert_eq!(handler.available(), 3);
+        assert!(handler.queues.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    /// position of a player
+    struct Player(i32);
+
+    impl Actor<(u32, i32)> for Player {
+        type Output = i32;
+        type Error = ();
+
+        fn handle(&mut self, (_, step): (u32, i32)) -> BoxFuture<'_, Result<i32, ()>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                self.0 += step;
+                Ok(self.0)
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn actor_test() -> Result<(), ()> {
+        let handler = SpawnLayer::new(8)
+            .keyed(|&(player, _): &(u32, i32)| player)
+            .actors(|&player: &u32| Player(player as i32 * 100))
+            .idle_timeout(Duration::from_secs(10));
+
+        let start = tokio::time::Instant::now();
+        let first = handler.call((1, 1)).await?;
+        let second = handler.call((1, 2)).await?;
+        let other = handler.call((2, 5)).await?;
+        assert_eq!(handler.len(), 2);
+
+        assert_eq!(first.await.unwrap(), Ok(101));
+        assert_eq!(other.await.unwrap(), Ok(205));
+        assert_eq!(second.await.unwrap(), Ok(103));
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(handler.call((1, 1)).await?.await.unwrap(), Ok(104));
+
+        // idle actors are dropped, and made again for the next message
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(handler.is_empty());
+        assert_eq!(handler.call((1, 1)).await?.await.unwrap(), Ok(101));
+        assert_eq!(handler.available(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn dedicated_runtime_test() {
+        let pool = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("pool")
+            .build()
+            .unwrap();
+        let layer = SpawnLayer::new(1).on(pool.handle().clone());
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (tx, rx) = oneshot::channel();
+            let tx = std::sync::Mutex::new(Some(tx));
+            let name = fn_handler(move |_: ()| {
+                let name = std::thread::current().name().map(String::from);
+                tx.lock().unwrap().take().unwrap().send(name).unwrap();
+                async { Ok::<_, ()>(()) }
+            });
+            let handler = connect(layer, name).await.unwrap();
+            handler.call(()).await.unwrap();
+            assert_eq!(rx.await.unwrap().as_deref(), Some("pool"));
+        });
+    }
+}
@@ -0,0 +1,133 @@
+//! Deadline for each message
+//!
+//! A slow handler stalls everything that waits for it. `TimeoutLayer` gives
+//! up on a message when the next handler does not finish in time, and
+//! returns `TimedOut` as the error of the chain instead.
+//!
+//! The next handler is dropped at the deadline, so it is cancelled at its
+//! current `.await`.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use futures::future::{ok, Map, Ready};
+use futures::FutureExt;
+use tokio::time::error::Elapsed;
+use tokio::time::Timeout;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// error of messages that are not processed in time
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimedOut;
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// `Layer` that builds `TimeoutHandler`
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutLayer {
+    duration: Duration,
+}
+
+impl TimeoutLayer {
+    /// fails each message that is not processed in `duration`
+    pub fn new(duration: Duration) -> Self {
+        Self { duration }
+    }
+}
+
+impl<T, H> Layer<T, H> for TimeoutLayer
+where
+    H: Handler<T>,
+    H::Error: From<TimedOut>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = TimeoutHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(TimeoutHandler {
+            duration: self.duration,
+            prev,
+        })
+    }
+}
+
+type Flatten<O, E> = fn(Result<Result<O, E>, Elapsed>) -> Result<O, E>;
+
+fn flatten<O, E: From<TimedOut>>(result: Result<Result<O, E>, Elapsed>) -> Result<O, E> {
+    result.unwrap_or_else(|_| Err(TimedOut.into()))
+}
+
+/// returns `handler` that fails messages not processed in `duration`
+pub fn timeout<H>(handler: H, duration: Duration) -> TimeoutHandler<H> {
+    TimeoutHandler {
+        duration,
+        prev: handler,
+    }
+}
+
+/// `Handler` that fails messages that are not processed in time
+pub struct TimeoutHandler<H> {
+    duration: Duration,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for TimeoutHandler<H>
+where
+    H: Handler<T>,
+    H::Error: From<TimedOut>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Map<Timeout<H::Future>, Flatten<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        tokio::time::timeout(self.duration, self.prev.call(msg)).map(flatten as Flatten<_, _>)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Error {
+        TimedOut,
+        Negative,
+    }
+
+    impl From<TimedOut> for Error {
+        fn from(_: TimedOut) -> Self {
+            Error::TimedOut
+        }
+    }
+
+    async fn sleep(secs: i64) -> Result<i64, Error> {
+        if secs < 0 {
+            return Err(Error::Negative);
+        }
+        tokio::time::sleep(Duration::from_secs(secs as u64)).await;
+        Ok(secs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_test() -> Result<(), Error> {
+        let handler = connect(TimeoutLayer::new(Duration::from_secs(5)), sleep).await?;
+        assert_eq!(handler.call(1).await, Ok(1));
+        assert_eq!(handler.call(-1).await, Err(Error::Negative));
+        assert_eq!(handler.call(10).await, Err(Error::TimedOut));
+        Ok(())
+    }
+}
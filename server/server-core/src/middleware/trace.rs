@@ -0,0 +1,220 @@
+//! Spans of calls through `tracing`
+//!
+//! `TraceLayer` opens a `tracing` span for every call of the next handler.
+//! The span has the name of the layer, the type of the message, and, when
+//! the call is done, its outcome (`ok` or `error`) and duration in
+//! microseconds. The next handler is called and polled inside the span, so
+//! spans of stacked `TraceLayer`s (and events of handlers) nest under the
+//! span of the outer layer.
+//!
+//! Spans go to the subscriber installed by the application. This module
+//! needs the `tracing` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::trace::TraceLayer;
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(msg: String) -> Result<usize, ()> {
+//!     tracing::info!("chat of {} bytes", msg.len());
+//!     Ok(msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let handler = apply!(TraceLayer::new("server"), TraceLayer::new("chat") to chat);
+//!
+//! // spans "server" > "chat" > the event of `chat`
+//! assert_eq!(handler.call(String::from("hello")).await?, 5);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::any::type_name;
+use std::sync::Arc;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// `Layer` that builds `TraceHandler`
+#[derive(Clone, Debug)]
+pub struct TraceLayer {
+    name: Arc<str>,
+}
+
+impl TraceLayer {
+    /// opens spans named `name` for calls of the next handler
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into().into(),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for TraceLayer
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = TraceHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(TraceHandler {
+            name: self.name.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that calls the previous handler in a span
+pub struct TraceHandler<H> {
+    name: Arc<str>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for TraceHandler<H>
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let span = info_span!(
+            "call",
+            name = &*self.name,
+            message_type = type_name::<T>(),
+            outcome = Empty,
+            duration_us = Empty,
+        );
+        let start = Instant::now();
+        let future = span.in_scope(|| self.prev.call(msg));
+
+        Box::pin(async move {
+            let result = future.instrument(span.clone()).await;
+            span.record("duration_us", start.elapsed().as_micros() as u64);
+            span.record("outcome", if result.is_ok() { "ok" } else { "error" });
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+    use std::sync::Mutex;
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    /// closed spans as "field=value, ... < name of the parent < ..."
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Vec<String>>>);
+
+    struct Fields(Vec<String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            if field.name() != "message_type" {
+                self.0.push(format!("{}={value:?}", field.name()));
+            }
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for Spans
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields(Vec::new());
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            values.record(span.extensions_mut().get_mut::<Fields>().unwrap());
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let parents: Vec<_> = span
+                .scope()
+                .skip(1)
+                .map(|parent| {
+                    let extensions = parent.extensions();
+                    extensions.get::<Fields>().unwrap().0[0].clone()
+                })
+                .collect();
+            let extensions = span.extensions();
+            let fields = &extensions.get::<Fields>().unwrap().0;
+            let mut line = parents.join(" < ");
+            if !line.is_empty() {
+                line = format!(" < {line}");
+            }
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}{line}", fields.join(", ")));
+        }
+    }
+
+    #[test]
+    fn trace_test() {
+        let spans = Spans::default();
+        let subscriber = tracing_subscriber::registry().with(spans.clone());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            runtime.block_on(async {
+                let check = fn_handler(|i: i32| async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(3)).await;
+                    if i < 0 {
+                        Err(())
+                    } else {
+                        Ok(i)
+                    }
+                });
+                let handler = connect(TraceLayer::new("check"), check).await.unwrap();
+                let handler = connect(TraceLayer::new("outer"), handler).await.unwrap();
+                assert_eq!(handler.call(1).await, Ok(1));
+                assert_eq!(handler.call(-1).await, Err(()));
+            })
+        });
+
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            vec![
+                "name=\"check\", duration_us=3000, outcome=\"ok\" < name=\"outer\"",
+                "name=\"outer\", duration_us=3000, outcome=\"ok\"",
+                "name=\"check\", duration_us=3000, outcome=\"error\" < name=\"outer\"",
+                "name=\"outer\", duration_us=3000, outcome=\"error\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,471 @@
+//! Versioned migrations of persisted state on startup
+//!
+//! Tables of `store`, `outbox`, `scheduled` and of applications change as
+//! the server evolves. Instead of failing to load data of older versions,
+//! the server upgrades it on startup with `Migrator`:
+//!
+//! - each migration has an increasing version, and is either SQL or a
+//!   handler that takes the transaction as `Tx<DB>` (e.g. to re-encode
+//!   stored payloads)
+//! - applied versions are recorded in a table (`cubby_migrations` by
+//!   default), so `run` only applies the pending ones
+//! - pending migrations are applied in one transaction, so a failing
+//!   migration leaves the stored state as it was
+//! - `dry_run` applies them and rolls back, to check that they succeed and
+//!   see what would be applied. Only the table of versions is created.
+//! - `backup` is called before pending migrations are applied
+//!
+//! `run` fails with `MigrateError::Unknown` when the database has a version
+//! that this build doesn't know, which means it was migrated by a newer
+//! version of the server.
+//!
+//! Dry run relies on transactional DDL, which SQLite and PostgreSQL have but
+//! MySQL doesn't.
+//!
+//! This module needs the `database` feature.
+//!
+//! # Examples
+//!
+//! The examples use SQLite, so they run with the `sqlite` feature.
+//!
+#![cfg_attr(feature = "sqlite", doc = "```")]
+#![cfg_attr(not(feature = "sqlite"), doc = "```ignore")]
+//! use cubby_connect_server_core::database::Tx;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::migrate::{Backup, Migrator};
+//! use sqlx::sqlite::{Sqlite, SqlitePoolOptions};
+//!
+//! async fn uppercase(tx: Tx<Sqlite>) -> Result<(), sqlx::Error> {
+//!     sqlx::query("UPDATE messages SET topic = upper(topic)")
+//!         .execute(&mut **tx.lock().await)
+//!         .await?;
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+//! let migrator = Migrator::new(pool.clone())
+//!     .sql(1, "create messages", "CREATE TABLE messages (seq INTEGER PRIMARY KEY, topic TEXT)")
+//!     .handler(2, "uppercase topics", fn_handler(uppercase))
+//!     .backup(fn_handler(|backup: Backup| async move {
+//!         println!("backup before migrating from {} to {}", backup.from, backup.to);
+//!         Ok::<_, sqlx::Error>(())
+//!     }));
+//!
+//! let migrated = migrator.clone().dry_run(true).run().await?;
+//! assert_eq!(migrated.applied, vec![1, 2]);
+//! assert_eq!(migrator.version().await?, 0);
+//!
+//! let migrated = migrator.run().await?;
+//! assert_eq!((migrated.from, migrated.to), (0, 2));
+//! assert!(migrator.run().await?.applied.is_empty());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use sqlx::{Database, Decode, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+
+use crate::database::Tx;
+use crate::handler::{Handler, IntoHandler};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+type Run<T> = Rc<dyn Fn(T) -> LocalBoxFuture<'static, Result<(), BoxError>>>;
+
+/// error of `Migrator::run`
+#[derive(Debug)]
+pub enum MigrateError {
+    /// the database has a version that no migration has
+    Unknown(i64),
+
+    /// the migration of the version failed
+    Failed(i64, BoxError),
+
+    /// the backup failed, and nothing was migrated
+    Backup(BoxError),
+
+    Database(sqlx::Error),
+}
+
+impl Display for MigrateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrateError::Unknown(version) => write!(f, "unknown migration version {version}"),
+            MigrateError::Failed(version, e) => write!(f, "migration {version} failed: {e}"),
+            MigrateError::Backup(e) => write!(f, "backup failed: {e}"),
+            MigrateError::Database(e) => write!(f, "database error: {e}"),
+        }
+    }
+}
+
+impl Error for MigrateError {}
+
+impl From<sqlx::Error> for MigrateError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrateError::Database(e)
+    }
+}
+
+/// versions that a backup is taken for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Backup {
+    /// current version of the database
+    pub from: i64,
+
+    /// version after pending migrations
+    pub to: i64,
+}
+
+/// result of `Migrator::run`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Migrated {
+    /// version before the run
+    pub from: i64,
+
+    /// version after the run, which is `from` in dry run
+    pub to: i64,
+
+    /// versions of applied migrations in order, which were rolled back in
+    /// dry run
+    pub applied: Vec<i64>,
+
+    pub dry_run: bool,
+}
+
+enum Step<DB: Database> {
+    Sql(String),
+    Handler(Run<Tx<DB>>),
+}
+
+impl<DB: Database> Clone for Step<DB> {
+    fn clone(&self) -> Self {
+        match self {
+            Step::Sql(sql) => Step::Sql(sql.clone()),
+            Step::Handler(run) => Step::Handler(run.clone()),
+        }
+    }
+}
+
+struct Migration<DB: Database> {
+    description: String,
+    step: Step<DB>,
+}
+
+impl<DB: Database> Clone for Migration<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            description: self.description.clone(),
+            step: self.step.clone(),
+        }
+    }
+}
+
+/// runs pending migrations on a database
+pub struct Migrator<DB: Database> {
+    pool: Pool<DB>,
+    table: String,
+    migrations: BTreeMap<i64, Migration<DB>>,
+    dry_run: bool,
+    backup: Option<Run<Backup>>,
+}
+
+impl<DB: Database> Clone for Migrator<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            table: self.table.clone(),
+            migrations: self.migrations.clone(),
+            dry_run: self.dry_run,
+            backup: self.backup.clone(),
+        }
+    }
+}
+
+impl<DB> Migrator<DB>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+{
+    /// migrates the database of `pool`, recording versions in the
+    /// `cubby_migrations` table
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self {
+            pool,
+            table: "cubby_migrations".to_owned(),
+            migrations: BTreeMap::new(),
+            dry_run: false,
+            backup: None,
+        }
+    }
+
+    /// records applied versions in `table`
+    pub fn table<S: Into<String>>(mut self, table: S) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// adds a migration of `version` that executes `sql`, which may have
+    /// many statements
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` is not positive, or if a migration of `version`
+    /// is already added.
+    pub fn sql<D, S>(self, version: i64, description: D, sql: S) -> Self
+    where
+        D: Into<String>,
+        S: Into<String>,
+    {
+        self.add(version, description.into(), Step::Sql(sql.into()))
+    }
+
+    /// adds a migration of `version` that calls `handler` with the
+    /// transaction
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` is not positive, or if a migration of `version`
+    /// is already added.
+    pub fn handler<D, H, I>(self, version: i64, description: D, handler: I) -> Self
+    where
+        D: Into<String>,
+        H: Handler<Tx<DB>> + 'static,
+        H::Error: Into<BoxError>,
+        I: IntoHandler<H, Tx<DB>>,
+    {
+        let handler = Rc::new(handler.into_handler());
+        let run: Run<Tx<DB>> = Rc::new(move |tx| {
+            let handler = handler.clone();
+            Box::pin(async move { handler.call(tx).await.map(|_| ()).map_err(Into::into) })
+        });
+        self.add(version, description.into(), Step::Handler(run))
+    }
+
+    fn add(mut self, version: i64, description: String, step: Step<DB>) -> Self {
+        assert!(version > 0, "version should be positive");
+        let prev = self
+            .migrations
+            .insert(version, Migration { description, step });
+        assert!(prev.is_none(), "migration {version} is added twice");
+        self
+    }
+
+    /// applies pending migrations and rolls them back instead of committing
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// calls `backup` before applying pending migrations, except in dry
+    /// run. Nothing is migrated when it fails.
+    pub fn backup<H, I>(mut self, backup: I) -> Self
+    where
+        H: Handler<Backup> + 'static,
+        H::Error: Into<BoxError>,
+        I: IntoHandler<H, Backup>,
+    {
+        let backup = Rc::new(backup.into_handler());
+        self.backup = Some(Rc::new(move |versions| {
+            let backup = backup.clone();
+            Box::pin(async move { backup.call(versions).await.map(|_| ()).map_err(Into::into) })
+        }));
+        self
+    }
+
+    /// returns the version of the database (0 if nothing is migrated)
+    pub async fn version(&self) -> Result<i64, sqlx::Error> {
+        Ok(self.versions().await?.last().copied().unwrap_or(0))
+    }
+
+    /// applies pending migrations in order
+    pub async fn run(&self) -> Result<Migrated, MigrateError> {
+        let versions = self.versions().await?;
+        if let Some(&version) = versions.iter().find(|v| !self.migrations.contains_key(v)) {
+            return Err(MigrateError::Unknown(version));
+        }
+        let from = versions.last().copied().unwrap_or(0);
+        let pending: Vec<_> = self.migrations.range(from + 1..).collect();
+        let to = pending.last().map_or(from, |(&version, _)| version);
+
+        if !self.dry_run && !pending.is_empty() {
+            if let Some(backup) = &self.backup {
+                backup(Backup { from, to })
+                    .await
+                    .map_err(MigrateError::Backup)?;
+            }
+        }
+
+        // versions are inserted in the transaction too, so a concurrent run
+        // of the same migrations fails on the primary key and rolls back
+        let tx = Tx::new(self.pool.begin().await?);
+        let mut applied = Vec::new();
+        for (&version, migration) in pending {
+            self.apply(&tx, version, migration)
+                .await
+                .map_err(|e| MigrateError::Failed(version, e))?;
+            applied.push(version);
+        }
+
+        // the transaction is rolled back when dropped
+        let tx = tx.take().await.expect("transaction is taken by migrations");
+        match self.dry_run {
+            true => tx.rollback().await?,
+            false => tx.commit().await?,
+        }
+        Ok(Migrated {
+            from,
+            to: if self.dry_run { from } else { to },
+            applied,
+            dry_run: self.dry_run,
+        })
+    }
+
+    async fn apply(
+        &self,
+        tx: &Tx<DB>,
+        version: i64,
+        migration: &Migration<DB>,
+    ) -> Result<(), BoxError> {
+        match &migration.step {
+            Step::Sql(sql) => {
+                sqlx::raw_sql(sql).execute(&mut **tx.lock().await).await?;
+            }
+            Step::Handler(run) => run(tx.clone()).await?,
+        }
+        let insert = format!(
+            "INSERT INTO {} (version, description) VALUES ($1, $2)",
+            self.table
+        );
+        sqlx::query(&insert)
+            .bind(version)
+            .bind(migration.description.clone())
+            .execute(&mut **tx.lock().await)
+            .await?;
+        Ok(())
+    }
+
+    /// returns applied versions in order, creating the table if it doesn't
+    /// exist
+    async fn versions(&self) -> Result<Vec<i64>, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let create = format!(
+            "CREATE TABLE IF NOT EXISTS {} (version BIGINT PRIMARY KEY, description TEXT NOT NULL)",
+            self.table
+        );
+        sqlx::raw_sql(&create).execute(&mut *conn).await?;
+
+        let select = format!("SELECT version FROM {} ORDER BY version", self.table);
+        let rows: Vec<(i64,)> = sqlx::query_as(&select).fetch_all(&mut *conn).await?;
+        Ok(rows.into_iter().map(|(version,)| version).collect())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use std::cell::RefCell;
+
+    use sqlx::sqlite::{Sqlite, SqlitePool, SqlitePoolOptions};
+
+    use crate::fn_handler::fn_handler;
+
+    use super::*;
+
+    async fn pool() -> Result<SqlitePool, sqlx::Error> {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+    }
+
+    async fn encode(tx: Tx<Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE messages SET payload = CAST(payload || '!' AS BLOB)")
+            .execute(&mut **tx.lock().await)
+            .await?;
+        Ok(())
+    }
+
+    async fn payloads(pool: &SqlitePool) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+        let rows: Vec<(Vec<u8>,)> = sqlx::query_as("SELECT payload FROM messages ORDER BY seq")
+            .fetch_all(pool)
+            .await?;
+        Ok(rows.into_iter().map(|(payload,)| payload).collect())
+    }
+
+    fn v1(pool: &SqlitePool) -> Migrator<Sqlite> {
+        Migrator::new(pool.clone()).sql(
+            1,
+            "create messages",
+            "CREATE TABLE messages (seq INTEGER PRIMARY KEY, payload BLOB); \
+             INSERT INTO messages (payload) VALUES (x'6869')",
+        )
+    }
+
+    #[tokio::test]
+    async fn migrate_test() -> Result<(), Box<dyn std::error::Error>> {
+        let pool = pool().await?;
+        assert_eq!(v1(&pool).run().await?.applied, vec![1]);
+
+        let backups = Rc::new(RefCell::new(Vec::new()));
+        let v2 = v1(&pool)
+            .handler(2, "encode payloads", fn_handler(encode))
+            .backup(fn_handler({
+                let backups = backups.clone();
+                move |backup: Backup| {
+                    backups.borrow_mut().push(backup);
+                    async { Ok::<_, sqlx::Error>(()) }
+                }
+            }));
+
+        // dry run doesn't write or back up
+        let migrated = v2.clone().dry_run(true).run().await?;
+        assert_eq!((migrated.from, migrated.to), (1, 1));
+        assert_eq!(migrated.applied, vec![2]);
+        assert_eq!(payloads(&pool).await?, vec![b"hi".to_vec()]);
+        assert!(backups.borrow().is_empty());
+
+        let migrated = v2.run().await?;
+        assert_eq!((migrated.from, migrated.to), (1, 2));
+        assert_eq!(payloads(&pool).await?, vec![b"hi!".to_vec()]);
+        assert_eq!(*backups.borrow(), vec![Backup { from: 1, to: 2 }]);
+
+        assert!(v2.run().await?.applied.is_empty());
+        assert_eq!(backups.borrow().len(), 1);
+
+        // migrated by a newer version
+        assert!(matches!(
+            v1(&pool).run().await,
+            Err(MigrateError::Unknown(2))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_test() -> Result<(), Box<dyn std::error::Error>> {
+        let pool = pool().await?;
+        let migrator = v1(&pool).sql(2, "broken", "UPDATE nothing SET x = 1");
+        assert!(matches!(
+            migrator.run().await,
+            Err(MigrateError::Failed(2, _))
+        ));
+        // the first migration is rolled back with the second
+        assert_eq!(migrator.version().await?, 0);
+
+        let migrator =
+            v1(&pool).backup(fn_handler(|_: Backup| async { Err::<(), _>("disk full") }));
+        assert!(matches!(migrator.run().await, Err(MigrateError::Backup(_))));
+        assert_eq!(migrator.version().await?, 0);
+        Ok(())
+    }
+}
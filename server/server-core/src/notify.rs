@@ -0,0 +1,697 @@
+//! Notifications of operational alerts
+//!
+//! Some messages in a pipeline mean that something is wrong and a human
+//! should look at it. `NotifyLayer` picks those messages with a closure that
+//! returns an `Alert`, and sends the alert to a `Notifier` before the message
+//! goes on.
+//!
+//! Backends of `Notifier`:
+//!
+//! - `Smtp`: email through an SMTP relay (plain SMTP without authentication)
+//! - `SlackWebhook`: Slack incoming webhook (needs the `slack` feature)
+//! - any `Fn(Alert) -> impl Future<Output = Result<(), E>>`
+//!
+//! The message waits for its alert, so `Smtp` and `SlackWebhook` give up
+//! after `DEFAULT_TIMEOUT` (see their `timeout`) instead of stalling the
+//! pipeline on a stuck relay. Closures should time out the same way.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::notify::{Alert, Level, NotifyLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! struct Trade {
+//!     gold: u64,
+//! }
+//!
+//! async fn page(alert: Alert) -> Result<(), ()> {
+//!     println!("{alert}");
+//!     Ok(())
+//! }
+//!
+//! async fn trade(_: Trade) -> Result<(), ()> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let layer = NotifyLayer::new(page, |t: &Trade| {
+//!     (t.gold > 1_000_000).then(|| Alert::new(Level::Warning, "huge trade", t.gold.to_string()))
+//! });
+//! let handler = apply!(layer to trade);
+//! // this would print "[WARNING] huge trade: 5000000"
+//! handler.call(Trade { gold: 5_000_000 }).await?;
+//! handler.call(Trade { gold: 10 }).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+#[cfg(feature = "slack")]
+pub use slack::SlackWebhook;
+pub use smtp::Smtp;
+
+/// time that `Smtp` and `SlackWebhook` wait for sending an alert
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how urgent an alert is
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Info => write!(f, "INFO"),
+            Level::Warning => write!(f, "WARNING"),
+            Level::Critical => write!(f, "CRITICAL"),
+        }
+    }
+}
+
+/// alert that is sent to humans
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Alert {
+    pub level: Level,
+    pub title: String,
+    pub body: String,
+}
+
+impl Alert {
+    pub fn new<S: Into<String>, B: Into<String>>(level: Level, title: S, body: B) -> Self {
+        Self {
+            level,
+            title: title.into(),
+            body: body.into(),
+        }
+    }
+}
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.level, self.title, self.body)
+    }
+}
+
+/// error of notifier backends
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NotifyError {
+    /// failed to communicate with the backend
+    Connection(String),
+
+    /// backend refused the notification
+    Rejected(String),
+
+    /// the alert cannot be sent as it is, like an address with a line break
+    Invalid(String),
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyError::Connection(e) => write!(f, "failed to connect to notifier: {e}"),
+            NotifyError::Rejected(e) => write!(f, "notification rejected: {e}"),
+            NotifyError::Invalid(e) => write!(f, "invalid notification: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+fn connection_error<E: ToString>(e: E) -> NotifyError {
+    NotifyError::Connection(e.to_string())
+}
+
+/// runs `notify` for `duration` at most
+async fn timeout<F>(duration: Duration, notify: F) -> Result<(), NotifyError>
+where
+    F: Future<Output = Result<(), NotifyError>>,
+{
+    tokio::time::timeout(duration, notify)
+        .await
+        .unwrap_or_else(|_| Err(connection_error("timed out")))
+}
+
+/// destination of alerts
+pub trait Notifier {
+    /// error when sending alerts
+    type Error;
+
+    /// future of `notify`
+    type Future: Future<Output = Result<(), Self::Error>>;
+
+    /// sends `alert`
+    fn notify(&self, alert: Alert) -> Self::Future;
+}
+
+impl<F, Fut, E> Notifier for F
+where
+    F: Fn(Alert) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    type Error = E;
+    type Future = Fut;
+
+    fn notify(&self, alert: Alert) -> Self::Future {
+        self(alert)
+    }
+}
+
+/// `Layer` that builds `NotifyHandler`
+pub struct NotifyLayer<N, F> {
+    notifier: Arc<N>,
+    classify: Arc<F>,
+}
+
+impl<N, F> NotifyLayer<N, F> {
+    /// `classify` returns an alert for messages that should be notified.
+    /// Other messages (`None`) just go on.
+    pub fn new(notifier: N, classify: F) -> Self {
+        Self {
+            notifier: Arc::new(notifier),
+            classify: Arc::new(classify),
+        }
+    }
+}
+
+impl<T, N, F, H> Layer<T, H> for NotifyLayer<N, F>
+where
+    T: 'static,
+    N: Notifier + 'static,
+    F: Fn(&T) -> Option<Alert>,
+    H: Handler<T> + 'static,
+    H::Error: From<N::Error> + 'static,
+    H::Output: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = NotifyHandler<T, N, F, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(NotifyHandler {
+            notifier: self.notifier.clone(),
+            classify: self.classify.clone(),
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that sends alerts of messages to `Notifier`
+pub struct NotifyHandler<T, N, F, H> {
+    notifier: Arc<N>,
+    classify: Arc<F>,
+    prev: Arc<H>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, N, F, H> Handler<T> for NotifyHandler<T, N, F, H>
+where
+    T: 'static,
+    N: Notifier + 'static,
+    F: Fn(&T) -> Option<Alert>,
+    H: Handler<T> + 'static,
+    H::Error: From<N::Error> + 'static,
+    H::Output: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// The alert is sent before calling the previous handler.
+    /// When sending fails, the message fails without being processed, so
+    /// that it can be retried.
+    fn call(&self, msg: T) -> Self::Future {
+        let notify = (self.classify)(&msg).map(|alert| self.notifier.notify(alert));
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            if let Some(notify) = notify {
+                notify.await?;
+            }
+            prev.call(msg).await
+        })
+    }
+}
+
+mod smtp {
+    use futures::future::BoxFuture;
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    use std::time::Duration;
+
+    use super::{connection_error, timeout, Alert, Notifier, NotifyError, DEFAULT_TIMEOUT};
+
+    /// `Notifier` that sends alerts as emails through an SMTP relay
+    #[derive(Clone, Debug)]
+    pub struct Smtp {
+        host: String,
+        port: u16,
+        from: String,
+        to: Vec<String>,
+        timeout: Duration,
+    }
+
+    impl Smtp {
+        /// relay at `host`:`port` that sends emails from `from` to `to`
+        pub fn new<S: Into<String>, A: Into<String>>(
+            host: S,
+            port: u16,
+            from: A,
+            to: Vec<String>,
+        ) -> Self {
+            Self {
+                host: host.into(),
+                port,
+                from: from.into(),
+                to,
+                timeout: DEFAULT_TIMEOUT,
+            }
+        }
+
+        /// gives up sending an alert after `timeout`
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    /// rejects `address` that would break the SMTP commands or headers it
+    /// is written in
+    fn check_address(address: &str) -> Result<(), NotifyError> {
+        if address.contains(['\r', '\n', '<', '>']) {
+            return Err(NotifyError::Invalid(format!("address {address:?}")));
+        }
+        Ok(())
+    }
+
+    /// reads a reply (which may have several lines) and checks its code
+    async fn expect<R>(reader: &mut R, code: &str) -> Result<(), NotifyError>
+    where
+        R: AsyncBufReadExt + Unpin,
+    {
+        loop {
+            let mut line = String::new();
+            if reader
+                .read_line(&mut line)
+                .await
+                .map_err(connection_error)?
+                == 0
+            {
+                return Err(connection_error("connection closed"));
+            }
+            if !line.starts_with(code) {
+                return Err(NotifyError::Rejected(line.trim_end().to_string()));
+            }
+            // `250-...` continues, `250 ...` is the last line
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn command<S>(
+        stream: &mut BufReader<S>,
+        line: &str,
+        code: &str,
+    ) -> Result<(), NotifyError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .map_err(connection_error)?;
+        expect(stream, code).await
+    }
+
+    pub(super) async fn send<S>(
+        stream: S,
+        from: &str,
+        to: &[String],
+        alert: &Alert,
+    ) -> Result<(), NotifyError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        check_address(from)?;
+        for rcpt in to {
+            check_address(rcpt)?;
+        }
+
+        let mut stream = BufReader::new(stream);
+        expect(&mut stream, "220").await?;
+        command(&mut stream, "HELO cubby-connect", "250").await?;
+        command(&mut stream, &format!("MAIL FROM:<{from}>"), "250").await?;
+        for rcpt in to {
+            command(&mut stream, &format!("RCPT TO:<{rcpt}>"), "250").await?;
+        }
+        command(&mut stream, "DATA", "354").await?;
+
+        // a line break in the title would start another header or the body
+        let title = alert.title.replace(['\r', '\n'], " ");
+        let mut data = format!(
+            "From: {from}\r\nTo: {}\r\nSubject: [{}] {title}\r\n\r\n",
+            to.join(", "),
+            alert.level,
+        );
+        for line in alert.body.lines() {
+            // lines starting with `.` are escaped by doubling it
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        command(&mut stream, &data, "250").await?;
+        command(&mut stream, "QUIT", "221").await
+    }
+
+    impl Notifier for Smtp {
+        type Error = NotifyError;
+        type Future = BoxFuture<'static, Result<(), NotifyError>>;
+
+        fn notify(&self, alert: Alert) -> Self::Future {
+            let smtp = self.clone();
+
+            Box::pin(timeout(smtp.timeout, async move {
+                let stream = TcpStream::connect((smtp.host.as_str(), smtp.port))
+                    .await
+                    .map_err(connection_error)?;
+                send(stream, &smtp.from, &smtp.to, &alert).await
+            }))
+        }
+    }
+}
+
+#[cfg(feature = "slack")]
+mod slack {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::BoxFuture;
+    use rustls::pki_types::ServerName;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    use super::{connection_error, timeout, Alert, Notifier, NotifyError, DEFAULT_TIMEOUT};
+
+    /// `Notifier` that posts alerts to a Slack incoming webhook
+    #[derive(Clone, Debug)]
+    pub struct SlackWebhook {
+        tls: bool,
+        host: String,
+        port: u16,
+        path: String,
+        timeout: Duration,
+    }
+
+    impl SlackWebhook {
+        /// webhook at `url` (e.g. `https://hooks.slack.com/services/...`).
+        /// Returns `None` when `url` is not a http(s) url.
+        pub fn new(url: &str) -> Option<Self> {
+            let (scheme, rest) = url.split_once("://")?;
+            let (tls, default_port) = match scheme {
+                "https" => (true, 443),
+                "http" => (false, 80),
+                _ => return None,
+            };
+            let (authority, path) = match rest.find('/') {
+                Some(i) => rest.split_at(i),
+                None => (rest, "/"),
+            };
+            let (host, port) = match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().ok()?),
+                None => (authority, default_port),
+            };
+
+            Some(Self {
+                tls,
+                host: host.to_string(),
+                port,
+                path: path.to_string(),
+                timeout: DEFAULT_TIMEOUT,
+            })
+        }
+
+        /// gives up posting an alert after `timeout`
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    async fn post<S>(mut stream: S, host: &str, path: &str, body: &str) -> Result<(), NotifyError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(connection_error)?;
+
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .await
+            .map_err(connection_error)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        if status.split(' ').nth(1) == Some("200") {
+            Ok(())
+        } else {
+            Err(NotifyError::Rejected(status.to_string()))
+        }
+    }
+
+    impl Notifier for SlackWebhook {
+        type Error = NotifyError;
+        type Future = BoxFuture<'static, Result<(), NotifyError>>;
+
+        fn notify(&self, alert: Alert) -> Self::Future {
+            let webhook = self.clone();
+
+            Box::pin(timeout(webhook.timeout, async move {
+                let body = serde_json::json!({ "text": alert.to_string() }).to_string();
+                let stream = TcpStream::connect((webhook.host.as_str(), webhook.port))
+                    .await
+                    .map_err(connection_error)?;
+
+                if !webhook.tls {
+                    return post(stream, &webhook.host, &webhook.path, &body).await;
+                }
+
+                let roots = rustls::RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                let provider = Arc::new(rustls::crypto::ring::default_provider());
+                let config = rustls::ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()
+                    .map_err(connection_error)?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let name = ServerName::try_from(webhook.host.clone()).map_err(connection_error)?;
+                let stream = TlsConnector::from(Arc::new(config))
+                    .connect(name, stream)
+                    .await
+                    .map_err(connection_error)?;
+                post(stream, &webhook.host, &webhook.path, &body).await
+            }))
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use tokio::net::TcpListener;
+
+        use super::*;
+        use crate::notify::Level;
+
+        #[tokio::test]
+        async fn webhook_test() -> Result<(), NotifyError> {
+            assert!(SlackWebhook::new("ftp://example.com").is_none());
+            let webhook = SlackWebhook::new("https://hooks.slack.com/services/T/B/X").unwrap();
+            assert_eq!(webhook.port, 443);
+            assert_eq!(webhook.path, "/services/T/B/X");
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let n = stream.read(&mut request).await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\n\r\nok")
+                    .await
+                    .unwrap();
+                String::from_utf8_lossy(&request[..n]).to_string()
+            });
+
+            let alert = Alert::new(Level::Critical, "down", "shard 3");
+            SlackWebhook::new(&url).unwrap().notify(alert).await?;
+            let request = server.await.unwrap();
+            assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+            assert!(request.ends_with(r#"{"text":"[CRITICAL] down: shard 3"}"#));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn layer_test() -> Result<(), NotifyError> {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_ = sent.clone();
+        let notifier = move |alert: Alert| {
+            let sent = sent_.clone();
+            async move {
+                if alert.level == Level::Critical {
+                    return Err(NotifyError::Rejected("down".to_string()));
+                }
+                sent.lock().unwrap().push(alert.title);
+                Ok(())
+            }
+        };
+        let classify = |n: &i32| match *n {
+            n if n > 100 => Some(Alert::new(Level::Critical, "too big", n.to_string())),
+            n if n > 10 => Some(Alert::new(Level::Warning, "big", n.to_string())),
+            _ => None,
+        };
+        let handler = connect(NotifyLayer::new(notifier, classify), |n: i32| async move {
+            Ok::<_, NotifyError>(n)
+        })
+        .await?;
+
+        assert_eq!(handler.call(1).await?, 1);
+        assert_eq!(handler.call(20).await?, 20);
+        assert!(handler.call(200).await.is_err());
+        assert_eq!(*sent.lock().unwrap(), vec!["big"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn smtp_test() -> Result<(), NotifyError> {
+        let (client, server) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut received = Vec::new();
+            server.write_all(b"220 relay\r\n").await.unwrap();
+            let mut line = String::new();
+            while server.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.as_str() {
+                    "DATA\r\n" => b"354 go ahead\r\n",
+                    ".\r\n" => b"250 queued\r\n",
+                    "QUIT\r\n" => b"221 bye\r\n",
+                    l if l.starts_with("HELO") => b"250-relay\r\n250 ok\r\n",
+                    l if l.contains(':') && l.ends_with(">\r\n") => b"250 ok\r\n",
+                    _ => b"",
+                };
+                server.write_all(reply).await.unwrap();
+                received.push(std::mem::take(&mut line));
+            }
+            received.concat()
+        });
+
+        let alert = Alert::new(Level::Warning, "lag", "tick is slow\n.\nreally");
+        let to = vec!["ops@cubby".to_string()];
+        smtp::send(client, "server@cubby", &to, &alert).await?;
+        let received = relay.await.unwrap();
+        assert!(received.contains("RCPT TO:<ops@cubby>\r\n"));
+        assert!(received.contains("Subject: [WARNING] lag\r\n"));
+        assert!(received.contains("tick is slow\r\n..\r\nreally\r\n.\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn smtp_injection_test() -> Result<(), NotifyError> {
+        let (client, server) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut received = String::new();
+            server.write_all(b"220 relay\r\n").await.unwrap();
+            let mut line = String::new();
+            while server.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.as_str() {
+                    "DATA\r\n" => b"354 go ahead\r\n",
+                    "QUIT\r\n" => b"221 bye\r\n",
+                    ".\r\n" | "HELO cubby-connect\r\n" => b"250 ok\r\n",
+                    l if l.contains(":<") => b"250 ok\r\n",
+                    _ => b"",
+                };
+                server.write_all(reply).await.unwrap();
+                received.push_str(&std::mem::take(&mut line));
+            }
+            received
+        });
+
+        let alert = Alert::new(Level::Info, "lag\r\nBcc: evil@example", "body");
+        let to = vec!["ops@cubby".to_string()];
+        smtp::send(client, "server@cubby", &to, &alert).await?;
+        let received = relay.await.unwrap();
+        assert!(received.contains("Subject: [INFO] lag  Bcc: evil@example\r\n"));
+        assert!(!received.contains("\r\nBcc:"));
+
+        // addresses with line breaks are not sent at all
+        let (client, _server) = tokio::io::duplex(4096);
+        let to = vec!["ops@cubby>\r\nRCPT TO:<evil@example".to_string()];
+        let result = smtp::send(client, "server@cubby", &to, &alert).await;
+        assert!(matches!(result, Err(NotifyError::Invalid(_))));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn smtp_timeout_test() {
+        // a relay that accepts the connection and never greets
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let relay = tokio::spawn(async move { listener.accept().await });
+
+        let smtp = Smtp::new(
+            "127.0.0.1",
+            port,
+            "server@cubby",
+            vec!["ops@cubby".to_string()],
+        )
+        .timeout(Duration::from_secs(1));
+        let result = smtp.notify(Alert::new(Level::Info, "lag", "")).await;
+        assert_eq!(
+            result,
+            Err(NotifyError::Connection("timed out".to_string()))
+        );
+        relay.abort();
+    }
+}
@@ -0,0 +1,432 @@
+//! Outbox pattern for reliable publishing of side effects
+//!
+//! A handler that publishes to an external system after writing to the
+//! database can crash in between, losing the side effect. With the outbox
+//! pattern, the handler only stages the messages in `Outbox`. `OutboxLayer`
+//! writes them into the `outbox` table in the transaction of the message
+//! (see `database::TransactionLayer`), so they are stored if and only if the
+//! message is committed. `OutboxPublisher` delivers the stored messages in
+//! the background and deletes them after delivery.
+//!
+//! Delivery is at-least-once: a message may be published again when the
+//! publisher crashes right after publishing it.
+//!
+//! The table should look like this (in SQLite):
+//!
+//! ```sql
+//! CREATE TABLE outbox (
+//!     id INTEGER PRIMARY KEY AUTOINCREMENT,
+//!     topic TEXT NOT NULL,
+//!     payload BLOB NOT NULL
+//! )
+//! ```
+//!
+//! This module needs the `database` feature.
+//!
+//! # Examples
+//!
+//! The examples use SQLite, so they run with the `sqlite` feature.
+//!
+#![cfg_attr(feature = "sqlite", doc = "```")]
+#![cfg_attr(not(feature = "sqlite"), doc = "```ignore")]
+//! use cubby_connect_server_core::database::TransactionLayer;
+//! use cubby_connect_server_core::context::{Missing, Request};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::outbox::{Outbox, OutboxLayer, OutboxMessage, OutboxPublisher};
+//! use cubby_connect_server_core::apply;
+//! use sqlx::sqlite::{Sqlite, SqlitePoolOptions};
+//!
+//! #[derive(Debug)]
+//! struct Error;
+//!
+//! impl From<Missing> for Error {
+//!     fn from(_: Missing) -> Self {
+//!         Error
+//!     }
+//! }
+//!
+//! impl From<sqlx::Error> for Error {
+//!     fn from(_: sqlx::Error) -> Self {
+//!         Error
+//!     }
+//! }
+//!
+//! async fn level_up(player: &'static str, outbox: Outbox) -> Result<(), Error> {
+//!     outbox.stage("achievements", player.as_bytes().to_vec());
+//!     Ok(())
+//! }
+//!
+//! async fn publish(msg: OutboxMessage) -> Result<(), ()> {
+//!     println!("publish {} to {}", msg.payload.len(), msg.topic);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Error> {
+//! let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+//! sqlx::query(
+//!     "CREATE TABLE outbox (id INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT, payload BLOB)",
+//! )
+//! .execute(&pool)
+//! .await?;
+//!
+//! let handler = apply!(
+//!     TransactionLayer::new(pool.clone()),
+//!     OutboxLayer::<Sqlite>::new()
+//!     to level_up
+//! );
+//! handler.call(Request::new("cubby")).await?;
+//!
+//! // usually `run` is spawned instead
+//! let publisher = OutboxPublisher::new(pool, publish);
+//! assert_eq!(publisher.publish_pending().await?, 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use sqlx::{Database, Decode, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+
+use crate::context::{Context, FromContext, Request};
+use crate::database::Tx;
+use crate::handler::{Handler, IntoHandler};
+use crate::layer::Layer;
+
+/// message that is published by `OutboxPublisher`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutboxMessage {
+    /// id in the outbox table (0 before stored)
+    pub id: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// messages staged by a handler.
+/// `OutboxLayer` puts a new one in the context of each message.
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    staged: Arc<Mutex<Vec<OutboxMessage>>>,
+}
+
+impl Outbox {
+    /// stages `payload` to be published to `topic` after the transaction is
+    /// committed
+    pub fn stage<S: Into<String>>(&self, topic: S, payload: Vec<u8>) {
+        self.staged.lock().unwrap().push(OutboxMessage {
+            id: 0,
+            topic: topic.into(),
+            payload,
+        });
+    }
+
+    /// takes the staged messages out
+    fn take(&self) -> Vec<OutboxMessage> {
+        mem::take(&mut *self.staged.lock().unwrap())
+    }
+}
+
+impl FromContext for Outbox {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Outbox>().cloned()
+    }
+}
+
+/// `Layer` that builds `OutboxHandler`.
+/// It should be inside of `TransactionLayer`, which provides `Tx`.
+pub struct OutboxLayer<DB> {
+    _marker: PhantomData<fn(DB)>,
+}
+
+impl<DB> Default for OutboxLayer<DB> {
+    fn default() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<DB> OutboxLayer<DB> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T, DB, H> Layer<Request<T>, H> for OutboxLayer<DB>
+where
+    T: 'static,
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> Vec<u8>: Encode<'q, DB> + Type<DB>,
+    H: Handler<Request<T>> + 'static,
+    H::Error: From<sqlx::Error> + 'static,
+    H::Output: 'static,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = OutboxHandler<T, DB, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(OutboxHandler {
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that stores staged messages in the transaction of the message
+pub struct OutboxHandler<T, DB, H> {
+    prev: Arc<H>,
+    _marker: PhantomData<fn(T, DB)>,
+}
+
+impl<T, DB, H> Handler<Request<T>> for OutboxHandler<T, DB, H>
+where
+    T: 'static,
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> Vec<u8>: Encode<'q, DB> + Type<DB>,
+    H: Handler<Request<T>> + 'static,
+    H::Error: From<sqlx::Error> + 'static,
+    H::Output: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// Staged messages are dropped when the handler fails.
+    fn call(&self, mut req: Request<T>) -> Self::Future {
+        let tx = Tx::<DB>::from_context(&req.context);
+        let outbox = Outbox::default();
+        req.context.insert(outbox.clone());
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            let tx = tx.ok_or_else(|| {
+                sqlx::Error::Configuration("`OutboxLayer` needs `TransactionLayer`".into())
+            })?;
+            let output = prev.call(req).await?;
+
+            let mut tx = tx.lock().await;
+            for msg in outbox.take() {
+                sqlx::query::<DB>("INSERT INTO outbox (topic, payload) VALUES ($1, $2)")
+                    .bind(msg.topic)
+                    .bind(msg.payload)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+            Ok(output)
+        })
+    }
+}
+
+/// background publisher of the messages in the outbox table
+pub struct OutboxPublisher<DB: Database, H> {
+    pool: Pool<DB>,
+    publish: H,
+    batch: i64,
+    interval: Duration,
+}
+
+impl<DB, H> OutboxPublisher<DB, H>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> (i64, String, Vec<u8>): FromRow<'r, DB::Row>,
+    H: Handler<OutboxMessage>,
+{
+    /// publishes messages in `pool` with `publish`.
+    /// By default, up to 100 messages are published every second.
+    pub fn new<P: IntoHandler<H, OutboxMessage>>(pool: Pool<DB>, publish: P) -> Self {
+        Self {
+            pool,
+            publish: publish.into_handler(),
+            batch: 100,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    /// sets the largest number of messages that are published at once
+    pub fn batch(mut self, batch: i64) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// sets the time between checking the outbox table
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// publishes stored messages in order, deleting each after it is
+    /// published. It stops at the first message that fails, which is
+    /// retried later. Returns the number of published messages.
+    pub async fn publish_pending(&self) -> Result<usize, sqlx::Error> {
+        let rows: Vec<(i64, String, Vec<u8>)> =
+            sqlx::query_as("SELECT id, topic, payload FROM outbox ORDER BY id LIMIT $1")
+                .bind(self.batch)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut published = 0;
+        for (id, topic, payload) in rows {
+            let msg = OutboxMessage { id, topic, payload };
+            if self.publish.call(msg).await.is_err() {
+                break;
+            }
+            sqlx::query("DELETE FROM outbox WHERE id = $1")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            published += 1;
+        }
+        Ok(published)
+    }
+
+    /// publishes messages every interval forever.
+    /// Errors of the database are retried in the next interval.
+    pub async fn run(&self) {
+        loop {
+            let _ = self.publish_pending().await;
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::future::{err, Ready};
+    use sqlx::sqlite::{Sqlite, SqlitePool, SqlitePoolOptions};
+
+    use crate::context::{extract, Missing};
+    use crate::database::TransactionLayer;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[derive(Debug)]
+    enum Error {
+        Missing,
+        Db,
+        Rejected,
+    }
+
+    impl From<Missing> for Error {
+        fn from(_: Missing) -> Self {
+            Error::Missing
+        }
+    }
+
+    impl From<sqlx::Error> for Error {
+        fn from(_: sqlx::Error) -> Self {
+            Error::Db
+        }
+    }
+
+    async fn pool() -> Result<SqlitePool, Error> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::query(
+            "CREATE TABLE outbox (id INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT, payload BLOB)",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(pool)
+    }
+
+    async fn stage(n: u8, outbox: Outbox) -> Result<(), Error> {
+        outbox.stage("a", vec![n]);
+        outbox.stage("b", vec![n]);
+        if n == 0 {
+            Err(Error::Rejected)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// publisher that fails when `fail` is set
+    struct Publish(Arc<Mutex<Vec<OutboxMessage>>>, AtomicBool);
+
+    impl Handler<OutboxMessage> for Publish {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, msg: OutboxMessage) -> Self::Future {
+            if self.1.load(Ordering::SeqCst) {
+                return err(());
+            }
+            self.0.lock().unwrap().push(msg);
+            ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn outbox_test() -> Result<(), Error> {
+        let pool = pool().await?;
+        let handler = connect(
+            TransactionLayer::new(pool.clone()),
+            connect(OutboxLayer::<Sqlite>::new(), extract(stage)).await?,
+        )
+        .await?;
+        handler.call(Request::new(1)).await?;
+        assert!(handler.call(Request::new(0)).await.is_err());
+        handler.call(Request::new(2)).await?;
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let publisher = OutboxPublisher::new(
+            pool.clone(),
+            Publish(published.clone(), AtomicBool::new(true)),
+        )
+        .batch(3);
+        assert_eq!(publisher.publish_pending().await?, 0);
+        publisher.publish.1.store(false, Ordering::SeqCst);
+        assert_eq!(publisher.publish_pending().await?, 3);
+        assert_eq!(publisher.publish_pending().await?, 1);
+        assert_eq!(publisher.publish_pending().await?, 0);
+
+        let published: Vec<_> = published
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| (m.topic.clone(), m.payload[0]))
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                ("a".to_string(), 1),
+                ("b".to_string(), 1),
+                ("a".to_string(), 2),
+                ("b".to_string(), 2)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_transaction_test() -> Result<(), Error> {
+        let handler = connect(OutboxLayer::<Sqlite>::new(), extract(stage)).await?;
+        assert!(matches!(
+            handler.call(Request::new(1)).await,
+            Err(Error::Db)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! Tracing of single peers
+//!
+//! Raising the log level for everyone floods the logs when only one player
+//! has a problem. `PeerTracing` holds the set of peers (connections or
+//! identities, as `PeerId` in the context of requests) that are traced.
+//! `PeerTraceLayer` reports the full message flow of traced peers to a sink:
+//! every message, and the output or error of the next handler with how long
+//! it took. Messages of other peers pass through untouched.
+//!
+//! Peers can be traced and untraced at runtime through `PeerTracing::admin`,
+//! a handler of admin commands like `LogLevels::admin`.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use cubby_connect_server_core::context::{Context, ContextLayer, Request};
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::peer_trace::{PeerId, PeerTraceLayer, PeerTracing};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn echo(req: Request<String>) -> Result<String, ()> {
+//!     Ok(req.msg)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let traces = Arc::new(Mutex::new(Vec::new()));
+//! let traces_ = traces.clone();
+//! let tracing = PeerTracing::new(move |event| traces_.lock().unwrap().push(event.to_string()));
+//!
+//! let mut context = Context::new();
+//! context.insert(PeerId::new("player-42"));
+//! let handler = apply!(ContextLayer::new(context), PeerTraceLayer::new(&tracing) to echo);
+//!
+//! handler.call(String::from("hello")).await?;
+//! assert!(traces.lock().unwrap().is_empty());
+//!
+//! // usually sent through the admin route
+//! tracing.admin().call(String::from("on player-42")).await.unwrap();
+//! handler.call(String::from("hello")).await?;
+//! assert_eq!(traces.lock().unwrap()[0], "[player-42] <- \"hello\"");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::future::{ok, ready, Either, LocalBoxFuture, Ready};
+use tokio::time::Instant;
+
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// identity of the peer of a request (e.g. address or user name)
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PeerId(pub String);
+
+impl PeerId {
+    pub fn new<S: Into<String>>(id: S) -> Self {
+        Self(id.into())
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromContext for PeerId {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<PeerId>().cloned()
+    }
+}
+
+/// step of the message flow of a traced peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// message from the peer, formatted with `Debug`
+    Received { peer: PeerId, msg: String },
+
+    /// output of the handler
+    Replied {
+        peer: PeerId,
+        output: String,
+        elapsed: Duration,
+    },
+
+    /// error of the handler
+    Failed {
+        peer: PeerId,
+        error: String,
+        elapsed: Duration,
+    },
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceEvent::Received { peer, msg } => write!(f, "[{peer}] <- {msg}"),
+            TraceEvent::Replied {
+                peer,
+                output,
+                elapsed,
+            } => write!(f, "[{peer}] -> {output} ({elapsed:?})"),
+            TraceEvent::Failed {
+                peer,
+                error,
+                elapsed,
+            } => write!(f, "[{peer}] !! {error} ({elapsed:?})"),
+        }
+    }
+}
+
+type Sink = Arc<dyn Fn(&TraceEvent) + Send + Sync>;
+
+/// set of traced peers and the sink of their events.
+/// Clones share the same set.
+#[derive(Clone)]
+pub struct PeerTracing {
+    peers: Arc<RwLock<BTreeSet<PeerId>>>,
+    sink: Sink,
+}
+
+impl PeerTracing {
+    /// reports events of traced peers to `sink`
+    pub fn new<F>(sink: F) -> Self
+    where
+        F: Fn(&TraceEvent) + Send + Sync + 'static,
+    {
+        Self {
+            peers: Arc::new(RwLock::new(BTreeSet::new())),
+            sink: Arc::new(sink),
+        }
+    }
+
+    /// starts tracing `peer`
+    pub fn enable(&self, peer: PeerId) {
+        self.peers.write().unwrap().insert(peer);
+    }
+
+    /// stops tracing `peer`, and returns false if it wasn't traced
+    pub fn disable(&self, peer: &PeerId) -> bool {
+        self.peers.write().unwrap().remove(peer)
+    }
+
+    /// returns true if `peer` is traced
+    pub fn is_traced(&self, peer: &PeerId) -> bool {
+        self.peers.read().unwrap().contains(peer)
+    }
+
+    /// returns traced peers in order
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.peers.read().unwrap().iter().cloned().collect()
+    }
+
+    /// returns a handler of admin commands
+    pub fn admin(&self) -> TraceAdmin {
+        TraceAdmin {
+            tracing: self.clone(),
+        }
+    }
+}
+
+/// error of admin commands that can't be parsed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidCommand(pub String);
+
+impl Display for InvalidCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid trace command: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCommand {}
+
+/// `Handler` of admin commands that change `PeerTracing`.
+///
+/// A command is one of:
+///
+/// - an empty string, which only returns traced peers
+/// - `on <peer>`, which starts tracing the peer
+/// - `off <peer>`, which stops tracing the peer
+/// - `clear`, which stops tracing every peer
+///
+/// Every command returns traced peers after the command, separated by `,`.
+pub struct TraceAdmin {
+    tracing: PeerTracing,
+}
+
+impl TraceAdmin {
+    fn run(&self, command: &str) -> Result<String, InvalidCommand> {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, _, _) => {}
+            (Some("clear"), None, _) => self.tracing.peers.write().unwrap().clear(),
+            (Some("on"), Some(peer), None) => self.tracing.enable(PeerId::new(peer)),
+            (Some("off"), Some(peer), None) => {
+                self.tracing.disable(&PeerId::new(peer));
+            }
+            _ => return Err(InvalidCommand(command.to_string())),
+        }
+        let peers: Vec<_> = self.tracing.peers().iter().map(|p| p.0.clone()).collect();
+        Ok(peers.join(","))
+    }
+}
+
+impl Handler<String> for TraceAdmin {
+    type Output = String;
+    type Error = InvalidCommand;
+    type Future = Ready<Result<String, InvalidCommand>>;
+
+    fn call(&self, command: String) -> Self::Future {
+        ready(self.run(&command))
+    }
+}
+
+/// `Layer` that builds `PeerTraceHandler`
+pub struct PeerTraceLayer {
+    tracing: PeerTracing,
+}
+
+impl PeerTraceLayer {
+    /// traces peers of `tracing`
+    pub fn new(tracing: &PeerTracing) -> Self {
+        Self {
+            tracing: tracing.clone(),
+        }
+    }
+}
+
+impl<T, H> Layer<Request<T>, H> for PeerTraceLayer
+where
+    T: Debug,
+    H: Handler<Request<T>>,
+    H::Output: Debug,
+    H::Error: Debug,
+    H::Future: 'static,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = PeerTraceHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(PeerTraceHandler {
+            tracing: self.tracing.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that reports the message flow of traced peers
+pub struct PeerTraceHandler<H> {
+    tracing: PeerTracing,
+    prev: H,
+}
+
+impl<T, H> Handler<Request<T>> for PeerTraceHandler<H>
+where
+    T: Debug,
+    H: Handler<Request<T>>,
+    H::Output: Debug,
+    H::Error: Debug,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, LocalBoxFuture<'static, Result<H::Output, H::Error>>>;
+
+    fn call(&self, req: Request<T>) -> Self::Future {
+        let peer = match req.context.get::<PeerId>() {
+            Some(peer) if self.tracing.is_traced(peer) => peer.clone(),
+            _ => return Either::Left(self.prev.call(req)),
+        };
+
+        let sink = self.tracing.sink.clone();
+        sink(&TraceEvent::Received {
+            peer: peer.clone(),
+            msg: format!("{:?}", req.msg),
+        });
+        let start = Instant::now();
+        let future = self.prev.call(req);
+
+        Either::Right(Box::pin(async move {
+            let result = future.await;
+            let elapsed = start.elapsed();
+            sink(&match &result {
+                Ok(output) => TraceEvent::Replied {
+                    peer,
+                    output: format!("{output:?}"),
+                    elapsed,
+                },
+                Err(error) => TraceEvent::Failed {
+                    peer,
+                    error: format!("{error:?}"),
+                    elapsed,
+                },
+            });
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn trace_test() -> Result<(), &'static str> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ = events.clone();
+        let tracing = PeerTracing::new(move |e| events_.lock().unwrap().push(e.clone()));
+        tracing.enable(PeerId::new("a"));
+
+        let work = fn_handler(|req: Request<u32>| async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if req.msg > 0 {
+                Ok(req.msg)
+            } else {
+                Err("zero")
+            }
+        });
+        let handler = connect(PeerTraceLayer::new(&tracing), work).await?;
+
+        let request = |peer: Option<&str>, msg| {
+            let mut req = Request::new(msg);
+            if let Some(peer) = peer {
+                req.context.insert(PeerId::new(peer));
+            }
+            req
+        };
+        handler.call(request(Some("b"), 1)).await?;
+        handler.call(request(None, 1)).await?;
+        handler.call(request(Some("a"), 2)).await?;
+        assert!(handler.call(request(Some("a"), 0)).await.is_err());
+
+        let peer = PeerId::new("a");
+        let elapsed = Duration::from_millis(5);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                TraceEvent::Received {
+                    peer: peer.clone(),
+                    msg: String::from("2"),
+                },
+                TraceEvent::Replied {
+                    peer: peer.clone(),
+                    output: String::from("2"),
+                    elapsed,
+                },
+                TraceEvent::Received {
+                    peer: peer.clone(),
+                    msg: String::from("0"),
+                },
+                TraceEvent::Failed {
+                    peer,
+                    error: String::from("\"zero\""),
+                    elapsed,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_test() -> Result<(), InvalidCommand> {
+        let tracing = PeerTracing::new(|_| {});
+        let admin = tracing.admin();
+        assert_eq!(admin.call(String::from("on b")).await?, "b");
+        assert_eq!(admin.call(String::from(" on  a ")).await?, "a,b");
+        assert_eq!(admin.call(String::from("off b")).await?, "a");
+        assert!(admin.call(String::from("on")).await.is_err());
+        assert!(admin.call(String::from("on a b")).await.is_err());
+        assert_eq!(admin.call(String::from("clear")).await?, "");
+        assert!(!tracing.is_traced(&PeerId::new("a")));
+        Ok(())
+    }
+}
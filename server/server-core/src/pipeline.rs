@@ -0,0 +1,216 @@
+//! Middleware pipelines assembled at startup
+//!
+//! `apply!` fixes the middleware stack at compile time. `PipelineBuilder`
+//! assembles it at startup instead: it has a registry of layer constructors
+//! by name, and stacks the layers that are named in `Config::layers`
+//! (outermost first, like `apply!`) on a handler. Deployments can then
+//! change the middleware in the configuration file without recompiling:
+//!
+//! ```json
+//! { "layers": ["trace", "ratelimit", "auth"] }
+//! ```
+//!
+//! Built handlers are `BoxHandler`s, since their type is only known at
+//! runtime. Unknown names fail the build before any layer is built.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::middleware::concurrency_limit::ConcurrencyLimitLayer;
+//! use cubby_connect_server_core::middleware::timeout::{TimedOut, TimeoutLayer};
+//! use cubby_connect_server_core::pipeline::{PipelineBuilder, PipelineError};
+//!
+//! async fn echo(msg: String) -> Result<String, TimedOut> {
+//!     Ok(msg)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), PipelineError<TimedOut>> {
+//! let pipeline = PipelineBuilder::new()
+//!     .register("timeout", || TimeoutLayer::new(Duration::from_secs(5)))
+//!     .register("concurrency", || ConcurrencyLimitLayer::new(64));
+//!
+//! let config = Config::builder()
+//!     .layers(vec![String::from("timeout"), String::from("concurrency")])
+//!     .build()
+//!     .unwrap();
+//! let handler = pipeline.build_from(&config, fn_handler(echo)).await?;
+//! assert_eq!(handler.call(String::from("hello")).await, Ok(String::from("hello")));
+//!
+//! let unknown = pipeline.build(&["auth"], fn_handler(echo)).await;
+//! assert_eq!(unknown.err(), Some(PipelineError::UnknownLayer(String::from("auth"))));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::config::Config;
+use crate::handler::{BoxHandler, Handler};
+use crate::layer::{connect_all, BoxLayer, Layer};
+
+/// error when building a pipeline
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PipelineError<E> {
+    /// no layer is registered with the name
+    UnknownLayer(String),
+
+    /// a layer failed to build its handler
+    Init(E),
+}
+
+impl<E: Display> Display for PipelineError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::UnknownLayer(name) => write!(f, "unknown layer `{name}`"),
+            PipelineError::Init(e) => write!(f, "failed to build a layer: {e}"),
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for PipelineError<E> {}
+
+/// makes a layer for a build
+type Constructor<T, O, E> = Box<dyn Fn() -> BoxLayer<T, O, E>>;
+
+/// registry of layers by name that builds pipelines of `BoxHandler`s
+pub struct PipelineBuilder<T, O, E> {
+    layers: HashMap<String, Constructor<T, O, E>>,
+}
+
+impl<T, O, E> Default for PipelineBuilder<T, O, E> {
+    fn default() -> Self {
+        Self {
+            layers: HashMap::new(),
+        }
+    }
+}
+
+impl<T, O, E> PipelineBuilder<T, O, E>
+where
+    T: 'static,
+    O: 'static,
+    E: 'static,
+{
+    /// returns a builder without any layer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers layers made by `constructor` as `name`.
+    /// A layer is made for every build.
+    pub fn register<S, F, L>(mut self, name: S, constructor: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn() -> L + 'static,
+        L: Layer<T, BoxHandler<T, O, E>, Next = T, Error = E> + 'static,
+        L::Handler: Handler<T, Output = O> + 'static,
+        <L::Handler as Handler<T>>::Future: 'static,
+        L::InitError: Into<E>,
+        L::Future: 'static,
+    {
+        let constructor: Constructor<T, O, E> = Box::new(move || BoxLayer::new(constructor()));
+        self.layers.insert(name.into(), constructor);
+        self
+    }
+
+    /// returns true if a layer is registered as `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.layers.contains_key(name)
+    }
+
+    /// stacks the layers of `names` on `handler`, the first is the outermost
+    pub async fn build<S, H>(
+        &self,
+        names: &[S],
+        handler: H,
+    ) -> Result<BoxHandler<T, O, E>, PipelineError<E>>
+    where
+        S: AsRef<str>,
+        H: Handler<T, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        let constructors = names
+            .iter()
+            .map(|name| {
+                self.layers
+                    .get(name.as_ref())
+                    .ok_or_else(|| PipelineError::UnknownLayer(name.as_ref().to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let layers = constructors.into_iter().map(|c| c()).collect();
+        connect_all(layers, handler)
+            .await
+            .map_err(PipelineError::Init)
+    }
+
+    /// stacks the layers of `Config::layers` on `handler`
+    pub async fn build_from<H>(
+        &self,
+        config: &Config,
+        handler: H,
+    ) -> Result<BoxHandler<T, O, E>, PipelineError<E>>
+    where
+        H: Handler<T, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        self.build(&config.layers, handler).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::fn_handler::fn_handler;
+    use crate::fn_layer::fn_layer;
+
+    use super::*;
+
+    async fn record(msg: Vec<&'static str>) -> Result<Vec<&'static str>, ()> {
+        Ok(msg)
+    }
+
+    #[tokio::test]
+    async fn build_test() -> Result<(), PipelineError<()>> {
+        let built = Arc::new(Mutex::new(0));
+        let built_ = built.clone();
+        let pipeline = PipelineBuilder::new()
+            .register("a", move || {
+                *built_.lock().unwrap() += 1;
+                fn_layer(|mut msg: Vec<&'static str>| async move {
+                    msg.push("a");
+                    Ok(msg)
+                })
+            })
+            .register("b", || {
+                fn_layer(|mut msg: Vec<&'static str>| async move {
+                    msg.push("b");
+                    Ok(msg)
+                })
+            });
+        assert!(pipeline.contains("a"));
+
+        // outermost first
+        let handler = pipeline.build(&["b", "a", "b"], fn_handler(record)).await?;
+        assert_eq!(handler.call(Vec::new()).await, Ok(vec!["b", "a", "b"]));
+        let handler = pipeline.build::<&str, _>(&[], fn_handler(record)).await?;
+        assert_eq!(handler.call(Vec::new()).await, Ok(vec![]));
+        assert_eq!(*built.lock().unwrap(), 1);
+
+        // nothing is built with an unknown name
+        let result = pipeline.build(&["a", "c"], fn_handler(record)).await;
+        assert_eq!(
+            result.err(),
+            Some(PipelineError::UnknownLayer(String::from("c")))
+        );
+        assert_eq!(*built.lock().unwrap(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! Publish/subscribe of messages by topic
+//!
+//! Messages published to a topic are delivered to every subscriber of the
+//! topic. Each delivery is `Traced`, and keeps the `TraceId` of the published
+//! message, so one publish can be traced across all resulting deliveries.
+//!
+//! `PubSub` is a `Handler` of `(K, Traced<T>)` where `K` is the topic, so it
+//! can be put at the end of a chain.
+//!
+//! `send_at` and `send_after` publish later through a `timer::Timer`, to the
+//! subscribers at that time. They only live in memory; `scheduled` keeps
+//! them in a database across restarts.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::pubsub::PubSub;
+//! use cubby_connect_server_core::trace_id::Traced;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let pubsub = PubSub::new();
+//! let mut alice = pubsub.subscribe("chat");
+//! let mut bob = pubsub.subscribe("chat");
+//!
+//! let msg = Traced::new("hello");
+//! let trace_id = msg.trace_id;
+//! assert_eq!(pubsub.call(("chat", msg)).await, Ok(2));
+//!
+//! assert_eq!(alice.recv().await.unwrap().trace_id, trace_id);
+//! assert_eq!(bob.recv().await.unwrap().trace_id, trace_id);
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use futures::future::{ok, Ready};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::timer::{Timer, TimerId};
+use crate::trace_id::Traced;
+
+/// subscribers of each topic
+pub struct PubSub<K, T> {
+    topics: Mutex<HashMap<K, Vec<UnboundedSender<Traced<T>>>>>,
+
+    /// timer of scheduled messages, spawned at the first one
+    timer: OnceLock<Timer<(K, Traced<T>)>>,
+}
+
+impl<K, T> Default for PubSub<K, T> {
+    fn default() -> Self {
+        Self {
+            topics: Mutex::new(HashMap::new()),
+            timer: OnceLock::new(),
+        }
+    }
+}
+
+impl<K, T> PubSub<K, T>
+where
+    K: Hash + Eq,
+    T: Clone,
+{
+    /// returns a pub/sub without any topic
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// subscribes to `topic`.
+    /// Drop the receiver to unsubscribe.
+    pub fn subscribe(&self, topic: K) -> UnboundedReceiver<Traced<T>> {
+        let (tx, rx) = unbounded_channel();
+        self.topics
+            .lock()
+            .unwrap()
+            .entry(topic)
+            .or_default()
+            .push(tx);
+        rx
+    }
+
+    /// delivers `msg` to every subscriber of `topic` with its trace id.
+    /// Returns the number of subscribers that received it.
+    pub fn publish(&self, topic: &K, msg: Traced<T>) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = match topics.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+
+        subscribers.retain(|tx| tx.send(msg.clone()).is_ok());
+        let count = subscribers.len();
+        if count == 0 {
+            topics.remove(topic);
+        }
+        count
+    }
+
+    /// returns the number of subscribers of `topic`
+    pub fn subscribers(&self, topic: &K) -> usize {
+        let topics = self.topics.lock().unwrap();
+        topics
+            .get(topic)
+            .map_or(0, |s| s.iter().filter(|tx| !tx.is_closed()).count())
+    }
+}
+
+impl<K, T> PubSub<K, T>
+where
+    K: Hash + Eq + Send + 'static,
+    T: Clone + Send + 'static,
+{
+    /// publishes `msg` to `topic` at `at`
+    pub fn send_at(self: &Arc<Self>, at: Instant, topic: K, msg: Traced<T>) -> TimerId {
+        let timer = self.timer.get_or_init(|| {
+            // the timer must not keep the pub/sub alive
+            let weak = Arc::downgrade(self);
+            Timer::spawn(move |(topic, msg)| {
+                if let Some(pubsub) = weak.upgrade() {
+                    pubsub.publish(&topic, msg);
+                }
+            })
+        });
+        timer.schedule(at, (topic, msg))
+    }
+
+    /// publishes `msg` to `topic` after `after`
+    pub fn send_after(self: &Arc<Self>, after: Duration, topic: K, msg: Traced<T>) -> TimerId {
+        self.send_at(Instant::now() + after, topic, msg)
+    }
+
+    /// cancels a message of `send_at` or `send_after` that is not published
+    /// yet
+    pub fn cancel_send(&self, id: TimerId) {
+        if let Some(timer) = self.timer.get() {
+            timer.cancel(id);
+        }
+    }
+}
+
+/// publishes messages, the output is the number of subscribers received it
+impl<K, T> Handler<(K, Traced<T>)> for PubSub<K, T>
+where
+    K: Hash + Eq,
+    T: Clone,
+{
+    type Output = usize;
+    type Error = ();
+    type Future = Ready<Result<usize, ()>>;
+
+    fn call(&self, (topic, msg): (K, Traced<T>)) -> Self::Future {
+        ok(self.publish(&topic, msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_test() {
+        let pubsub = PubSub::new();
+        let mut a = pubsub.subscribe(1);
+        let b = pubsub.subscribe(1);
+        let mut c = pubsub.subscribe(2);
+
+        let msg = Traced::new(10);
+        assert_eq!(pubsub.publish(&1, msg.clone()), 2);
+        assert_eq!(a.recv().await, Some(msg.clone()));
+        assert!(c.try_recv().is_err());
+
+        // closed subscriber is removed
+        drop(b);
+        assert_eq!(pubsub.subscribers(&1), 1);
+        assert_eq!(pubsub.publish(&1, msg.child(20)), 1);
+        assert_eq!(a.recv().await.unwrap().trace_id, msg.trace_id);
+
+        drop(a);
+        assert_eq!(pubsub.publish(&1, msg), 0);
+        assert_eq!(pubsub.publish(&3, Traced::new(0)), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_after_test() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut rx = pubsub.subscribe("event");
+
+        let id = pubsub.send_after(Duration::from_secs(1), "event", Traced::new("cancelled"));
+        pubsub.send_after(Duration::from_secs(2), "event", Traced::new("starts"));
+        pubsub.cancel_send(id);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.recv().await.unwrap().msg, "starts");
+    }
+}
@@ -0,0 +1,352 @@
+//! Relay of messages between two connections
+//!
+//! When a peer-to-peer link cannot be established, the server can forward
+//! messages between two clients instead (like TURN). `Relay` binds two
+//! connections into a pair. Messages from one side are sent to the
+//! outgoing handler of the other side, and the traffic is accounted per
+//! direction. When the pair uses more than `RelayLimits` allows, it is
+//! unbound.
+//!
+//! `Relay` is a `Handler` of `(K, T)` where `K` is the key of the connection
+//! that sent the message, so it can be put at the end of a chain.
+//!
+//! Messages are forwarded as they are, so `Envelope`s keep their headers
+//! and `Traced` messages their trace id (see `trace_id`). Traffic counts
+//! the bytes of the payload.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::relay::{Relay, RelayLimits};
+//! use futures::future::{ok, Ready};
+//!
+//! // sends messages to a client
+//! struct Outgoing(&'static str);
+//!
+//! impl Handler<Vec<u8>> for Outgoing {
+//!     type Output = ();
+//!     type Error = ();
+//!     type Future = Ready<Result<(), ()>>;
+//!
+//!     fn call(&self, msg: Vec<u8>) -> Self::Future {
+//!         println!("{} got {msg:?}", self.0);
+//!         ok(())
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let relay = Relay::new(RelayLimits::default().max_messages(100));
+//! relay
+//!     .bind("alice", Outgoing("alice"), "bob", Outgoing("bob"))
+//!     .unwrap();
+//!
+//! // this would print "bob got [1, 2, 3]"
+//! relay.call(("alice", vec![1, 2, 3])).await.unwrap();
+//! assert_eq!(relay.stats(&"alice").unwrap().sent.bytes, 3);
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{err, LocalBoxFuture};
+use futures::{FutureExt, TryFutureExt};
+
+use crate::handler::{Handler, IntoHandler};
+
+/// limits of traffic of a pair (sum of both directions)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RelayLimits {
+    /// maximum bytes that can be relayed
+    pub max_bytes: Option<u64>,
+
+    /// maximum messages that can be relayed
+    pub max_messages: Option<u64>,
+}
+
+impl RelayLimits {
+    /// sets maximum bytes
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// sets maximum messages
+    pub fn max_messages(mut self, max_messages: u64) -> Self {
+        self.max_messages = Some(max_messages);
+        self
+    }
+}
+
+/// traffic of one direction
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// traffic of a connection in a pair
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RelayStats {
+    /// relayed from this connection to the other
+    pub sent: Traffic,
+
+    /// relayed from the other to this connection
+    pub received: Traffic,
+}
+
+/// error when relaying a message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RelayError<E> {
+    /// the connection is not bound to a pair
+    NotBound,
+
+    /// the connection is already bound to a pair
+    AlreadyBound,
+
+    /// the pair exceeded `RelayLimits` and is unbound
+    LimitExceeded(RelayStats),
+
+    /// outgoing handler failed
+    Send(E),
+}
+
+impl<E: Display> Display for RelayError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayError::NotBound => write!(f, "connection is not bound to a pair"),
+            RelayError::AlreadyBound => write!(f, "connection is already bound to a pair"),
+            RelayError::LimitExceeded(_) => write!(f, "relay limit exceeded"),
+            RelayError::Send(e) => write!(f, "failed to relay: {e}"),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + Display> std::error::Error for RelayError<E> {}
+
+struct Pair<K, H> {
+    keys: [K; 2],
+    outs: [Arc<H>; 2],
+    traffic: [Traffic; 2],
+}
+
+impl<K: Eq, H> Pair<K, H> {
+    fn side(&self, key: &K) -> usize {
+        if self.keys[0] == *key {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn stats(&self, key: &K) -> RelayStats {
+        let side = self.side(key);
+        RelayStats {
+            sent: self.traffic[side],
+            received: self.traffic[1 - side],
+        }
+    }
+}
+
+type Pairs<K, H> = HashMap<K, Arc<Mutex<Pair<K, H>>>>;
+
+/// pairs of connections that relay messages to each other
+pub struct Relay<K, H> {
+    limits: RelayLimits,
+    pairs: Mutex<Pairs<K, H>>,
+}
+
+impl<K, H> Relay<K, H>
+where
+    K: Hash + Eq + Clone,
+{
+    /// returns a relay without any pair
+    pub fn new(limits: RelayLimits) -> Self {
+        Self {
+            limits,
+            pairs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// binds connection `a` and `b`.
+    /// `a_out` and `b_out` are the handlers that send messages to each of them.
+    pub fn bind<T, IA, IB>(
+        &self,
+        a: K,
+        a_out: IA,
+        b: K,
+        b_out: IB,
+    ) -> Result<(), RelayError<H::Error>>
+    where
+        H: Handler<T>,
+        IA: IntoHandler<H, T>,
+        IB: IntoHandler<H, T>,
+    {
+        let mut pairs = self.pairs.lock().unwrap();
+        if a == b || pairs.contains_key(&a) || pairs.contains_key(&b) {
+            return Err(RelayError::AlreadyBound);
+        }
+
+        let pair = Arc::new(Mutex::new(Pair {
+            keys: [a.clone(), b.clone()],
+            outs: [
+                Arc::new(a_out.into_handler()),
+                Arc::new(b_out.into_handler()),
+            ],
+            traffic: [Traffic::default(); 2],
+        }));
+        pairs.insert(a, pair.clone());
+        pairs.insert(b, pair);
+        Ok(())
+    }
+
+    /// unbinds the pair of `key` and returns the final stats of `key`
+    pub fn unbind(&self, key: &K) -> Option<RelayStats> {
+        let mut pairs = self.pairs.lock().unwrap();
+        let pair = pairs.remove(key)?;
+        let pair = pair.lock().unwrap();
+        pairs.remove(&pair.keys[1 - pair.side(key)]);
+        Some(pair.stats(key))
+    }
+
+    /// returns the stats of `key` if it is bound
+    pub fn stats(&self, key: &K) -> Option<RelayStats> {
+        let pairs = self.pairs.lock().unwrap();
+        let stats = pairs.get(key)?.lock().unwrap().stats(key);
+        Some(stats)
+    }
+
+    /// returns the other side of `key` if it is bound
+    pub fn peer(&self, key: &K) -> Option<K> {
+        let pairs = self.pairs.lock().unwrap();
+        let pair = pairs.get(key)?.lock().unwrap();
+        Some(pair.keys[1 - pair.side(key)].clone())
+    }
+}
+
+/// `Relay` relays `(from, msg)` to the other side of `from`
+impl<K, H, T> Handler<(K, T)> for Relay<K, H>
+where
+    K: Hash + Eq + Clone,
+    H: Handler<T>,
+    H::Output: 'static,
+    H::Error: 'static,
+    H::Future: 'static,
+    T: AsRef<[u8]>,
+{
+    type Output = H::Output;
+    type Error = RelayError<H::Error>;
+    type Future = LocalBoxFuture<'static, Result<H::Output, Self::Error>>;
+
+    fn call(&self, (from, msg): (K, T)) -> Self::Future {
+        let pair = match self.pairs.lock().unwrap().get(&from) {
+            Some(pair) => pair.clone(),
+            None => return err(RelayError::NotBound).boxed_local(),
+        };
+
+        let mut locked = pair.lock().unwrap();
+        let side = locked.side(&from);
+        let traffic = locked.traffic[side];
+        let total = Traffic {
+            messages: locked.traffic[0].messages + locked.traffic[1].messages + 1,
+            bytes: locked.traffic[0].bytes + locked.traffic[1].bytes + msg.as_ref().len() as u64,
+        };
+
+        let over = |max: Option<u64>, v: u64| max.is_some_and(|max| v > max);
+        if over(self.limits.max_messages, total.messages)
+            || over(self.limits.max_bytes, total.bytes)
+        {
+            let stats = locked.stats(&from);
+            drop(locked);
+            self.unbind(&from);
+            return err(RelayError::LimitExceeded(stats)).boxed_local();
+        }
+
+        locked.traffic[side] = Traffic {
+            messages: traffic.messages + 1,
+            bytes: traffic.bytes + msg.as_ref().len() as u64,
+        };
+        let out = locked.outs[1 - side].clone();
+        drop(locked);
+
+        out.call(msg).map_err(RelayError::Send).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::future::{ok, Ready};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Inbox(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Handler<Vec<u8>> for Inbox {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, msg: Vec<u8>) -> Self::Future {
+            self.0.lock().unwrap().push(msg);
+            ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_test() {
+        let relay = Relay::new(RelayLimits::default());
+        let (a, b) = (Inbox::default(), Inbox::default());
+        relay.bind(1, a.clone(), 2, b.clone()).unwrap();
+        assert_eq!(
+            relay.bind(2, Inbox::default(), 3, Inbox::default()),
+            Err(RelayError::AlreadyBound)
+        );
+
+        relay.call((1, vec![1, 2])).await.unwrap();
+        relay.call((2, vec![3])).await.unwrap();
+        relay.call((2, vec![4])).await.unwrap();
+        assert_eq!(*a.0.lock().unwrap(), vec![vec![3], vec![4]]);
+        assert_eq!(*b.0.lock().unwrap(), vec![vec![1, 2]]);
+        assert_eq!(relay.peer(&1), Some(2));
+
+        let stats = relay.unbind(&2).unwrap();
+        assert_eq!(
+            stats.sent,
+            Traffic {
+                messages: 2,
+                bytes: 2
+            }
+        );
+        assert_eq!(
+            stats.received,
+            Traffic {
+                messages: 1,
+                bytes: 2
+            }
+        );
+        assert_eq!(relay.stats(&1), None);
+        assert_eq!(relay.call((1, vec![])).await, Err(RelayError::NotBound));
+    }
+
+    #[tokio::test]
+    async fn limit_test() {
+        let relay = Relay::new(RelayLimits::default().max_bytes(4));
+        relay
+            .bind(1, Inbox::default(), 2, Inbox::default())
+            .unwrap();
+
+        relay.call((1, vec![0; 3])).await.unwrap();
+        assert!(matches!(
+            relay.call((2, vec![0; 2])).await,
+            Err(RelayError::LimitExceeded(_))
+        ));
+        assert_eq!(relay.call((1, vec![])).await, Err(RelayError::NotBound));
+    }
+}
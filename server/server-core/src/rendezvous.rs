@@ -0,0 +1,274 @@
+//! Rendezvous service for UDP hole punching
+//!
+//! When a deployment wants peer-to-peer data paths, two clients behind NAT
+//! cannot connect to each other directly because they don't know the public
+//! address of each other. `RendezvousServer` runs in the server and only
+//! brokers: clients register themselves with the same key, and the server
+//! tells each of them the address of the other as it sees them.
+//! Then `hole_punch` sends packets to each other so that both NATs open
+//! a mapping, and the socket can be used as a direct link.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::rendezvous::{hole_punch, RendezvousServer};
+//! use std::time::Duration;
+//! use tokio::net::UdpSocket;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> std::io::Result<()> {
+//! // in server
+//! let server = RendezvousServer::bind("0.0.0.0:20205").await?;
+//! tokio::spawn(async move { server.run().await });
+//!
+//! // in each client
+//! let socket = UdpSocket::bind("0.0.0.0:0").await?;
+//! let server = "203.0.113.1:20205".parse().unwrap();
+//! let peer = hole_punch(&socket, server, "match-42", Duration::from_secs(10)).await?;
+//! socket.send_to(b"hello", peer).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use prost::Message;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::{sleep_until, timeout_at, Instant};
+
+use crate::protobuf::rendezvous::packet::Kind;
+use crate::protobuf::rendezvous::{Packet, PeerAddress, Punch, Register};
+use crate::transport::accept_retry;
+
+/// interval of resending packets while punching
+const RESEND: Duration = Duration::from_millis(200);
+
+/// number of punches that are sent back when a punch is received
+const CONFIRM: usize = 3;
+
+fn encode(kind: Kind) -> Vec<u8> {
+    Packet { kind: Some(kind) }.encode_to_vec()
+}
+
+fn decode(packet: &[u8]) -> Option<Kind> {
+    Packet::decode(packet).ok()?.kind
+}
+
+/// service that tells clients the address of each other
+pub struct RendezvousServer {
+    socket: UdpSocket,
+    ttl: Duration,
+}
+
+impl RendezvousServer {
+    /// binds the service to `addr`
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            ttl: Duration::from_secs(30),
+        })
+    }
+
+    /// sets how long a registration is kept (default is 30 seconds)
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// address that the service is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// runs the service forever.
+    ///
+    /// This only returns when the socket fails. Errors of a single peer
+    /// (e.g. a peer that is gone) are logged and skipped.
+    pub async fn run(&self) -> io::Result<()> {
+        let mut registered: HashMap<String, Vec<(SocketAddr, Instant)>> = HashMap::new();
+        let mut buf = [0u8; 512];
+
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    let Some(delay) = accept_retry(&e) else {
+                        return Err(e);
+                    };
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %e, "failed to receive a rendezvous packet");
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+            let key = match decode(&buf[..len]) {
+                Some(Kind::Register(Register { key })) => key,
+                _ => continue,
+            };
+
+            let now = Instant::now();
+            registered.retain(|_, peers| {
+                peers.retain(|(_, at)| now.duration_since(*at) < self.ttl);
+                !peers.is_empty()
+            });
+
+            let peers = registered.entry(key).or_default();
+            if let Some(peer) = peers.iter_mut().find(|(addr, _)| *addr == from) {
+                peer.1 = now;
+            } else if peers.len() < 2 {
+                peers.push((from, now));
+            } else {
+                // only two clients can be paired with one key
+                continue;
+            }
+
+            if let [(a, _), (b, _)] = peers[..] {
+                // each one is told even if the other cannot be
+                for (to, peer) in [(a, b), (b, a)] {
+                    if let Err(e) = self.send_peer(to, peer).await {
+                        if accept_retry(&e).is_none() {
+                            return Err(e);
+                        }
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(error = %e, %to, "failed to send a rendezvous packet");
+                    }
+                }
+            }
+        }
+    }
+
+    /// tells `to` that its peer is `peer`
+    async fn send_peer(&self, to: SocketAddr, peer: SocketAddr) -> io::Result<()> {
+        let packet = encode(Kind::Peer(PeerAddress {
+            address: peer.to_string(),
+        }));
+        self.socket.send_to(&packet, to).await?;
+        Ok(())
+    }
+}
+
+/// finds the peer registered with the same `key` in rendezvous `server`,
+/// and punches a hole in NAT for it.
+///
+/// Returns the address of the peer that `socket` can send to directly.
+/// Returns `ErrorKind::TimedOut` if the peer could not be reached in `timeout`.
+pub async fn hole_punch(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    key: &str,
+    timeout: Duration,
+) -> io::Result<SocketAddr> {
+    let deadline = Instant::now() + timeout;
+    let register = encode(Kind::Register(Register {
+        key: key.to_string(),
+    }));
+    let punch = encode(Kind::Punch(Punch {
+        key: key.to_string(),
+    }));
+
+    let mut peer: Option<SocketAddr> = None;
+    let mut buf = [0u8; 512];
+
+    while Instant::now() < deadline {
+        match peer {
+            None => socket.send_to(&register, server).await?,
+            Some(peer) => socket.send_to(&punch, peer).await?,
+        };
+
+        let resend = Instant::now() + RESEND;
+        while let Ok(received) = timeout_at(resend.min(deadline), socket.recv_from(&mut buf)).await
+        {
+            let (len, from) = received?;
+            match decode(&buf[..len]) {
+                Some(Kind::Peer(PeerAddress { address })) if from == server => {
+                    peer = address.parse().ok();
+                }
+                Some(Kind::Punch(Punch { key: k })) if k == key => {
+                    // the peer may not have received our punch yet
+                    for _ in 0..CONFIRM {
+                        socket.send_to(&punch, from).await?;
+                    }
+                    return Ok(from);
+                }
+                _ => {}
+            }
+        }
+
+        sleep_until(resend.min(deadline)).await;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "peer could not be reached",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn hole_punch_test() -> io::Result<()> {
+        let server = RendezvousServer::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        tokio::spawn(async move { server.run().await });
+
+        let a = UdpSocket::bind("127.0.0.1:0").await?;
+        let b = UdpSocket::bind("127.0.0.1:0").await?;
+        let timeout = Duration::from_secs(5);
+
+        let (peer_of_a, peer_of_b) = tokio::join!(
+            hole_punch(&a, server_addr, "key", timeout),
+            hole_punch(&b, server_addr, "key", timeout),
+        );
+        assert_eq!(peer_of_a?, b.local_addr()?);
+        assert_eq!(peer_of_b?, a.local_addr()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gone_peer_test() -> io::Result<()> {
+        let server = RendezvousServer::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        let task = tokio::spawn(async move { server.run().await });
+
+        // told about a peer that is gone
+        let gone = UdpSocket::bind("127.0.0.1:0").await?;
+        let packet = encode(Kind::Register(Register {
+            key: String::from("gone"),
+        }));
+        gone.send_to(&packet, server_addr).await?;
+        drop(gone);
+        let a = UdpSocket::bind("127.0.0.1:0").await?;
+        let result = hole_punch(&a, server_addr, "gone", Duration::from_millis(500)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        // still serves others
+        let b = UdpSocket::bind("127.0.0.1:0").await?;
+        let c = UdpSocket::bind("127.0.0.1:0").await?;
+        let timeout = Duration::from_secs(5);
+        let (peer_of_b, peer_of_c) = tokio::join!(
+            hole_punch(&b, server_addr, "key", timeout),
+            hole_punch(&c, server_addr, "key", timeout),
+        );
+        assert_eq!(peer_of_b?, c.local_addr()?);
+        assert_eq!(peer_of_c?, b.local_addr()?);
+        assert!(!task.is_finished());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_peer_test() -> io::Result<()> {
+        let server = RendezvousServer::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        tokio::spawn(async move { server.run().await });
+
+        let a = UdpSocket::bind("127.0.0.1:0").await?;
+        let result = hole_punch(&a, server_addr, "alone", Duration::from_millis(500)).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+//! Typed responses sent from handlers
+//!
+//! A handler does not have to respond exactly once. By taking `Reply<R>` as
+//! an argument, it can send zero, one or many responses of type `R` back to
+//! the caller. Every response is tagged with the `CorrelationId` of the
+//! request so that the caller can match them.
+//!
+//! `Reply<R>` is extracted from the context: the connection puts its
+//! `Outbox<R>` into the context, and the `CorrelationId` is put for each
+//! request.
+//!
+//! Handlers that respond exactly once can simply return the response as
+//! their output instead, and put `ReplyLayer` in front of them. It sends the
+//! output through `Reply` of the request.
+//!
+//! A handler can also return immediately and respond later with
+//! `Reply::defer` or `Reply::spawn`. If the deferred response is not sent
+//! before the timeout, the caller receives `Expired` (converted into `R`)
+//! instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::context::{Context, Missing, Request};
+//! use cubby_connect_server_core::handler::{Handler, IntoHandler};
+//! use cubby_connect_server_core::reply::{CorrelationId, Outbox, Reply, ReplyError};
+//!
+//! #[derive(Debug)]
+//! enum Error {
+//!     Missing,
+//!     Closed,
+//! }
+//!
+//! impl From<Missing> for Error {
+//!     fn from(_: Missing) -> Self {
+//!         Error::Missing
+//!     }
+//! }
+//!
+//! impl From<ReplyError> for Error {
+//!     fn from(_: ReplyError) -> Self {
+//!         Error::Closed
+//!     }
+//! }
+//!
+//! // answers with every word of the message
+//! async fn split(msg: &'static str, reply: Reply<String>) -> Result<(), Error> {
+//!     for word in msg.split(' ') {
+//!         reply.send(word.to_string())?;
+//!     }
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Error> {
+//! let (outbox, mut responses) = Outbox::<String>::channel();
+//! let mut context = Context::new();
+//! context.insert(outbox);
+//! context.insert(CorrelationId(7));
+//!
+//! let handler = split.into_handler();
+//! handler.call(Request::with_context("hello world", context)).await?;
+//!
+//! let response = responses.recv().await.unwrap();
+//! assert_eq!(response.correlation_id, Some(7));
+//! assert_eq!(response.body, "hello");
+//! assert_eq!(responses.recv().await.unwrap().body, "world");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// id of a request that responses refer to
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CorrelationId(pub u64);
+
+/// response to a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response<R> {
+    /// `None` when the request did not have `CorrelationId`
+    pub correlation_id: Option<u64>,
+    pub body: R,
+}
+
+/// error when sending response
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplyError {
+    /// the connection is closed
+    Closed,
+
+    /// deferred response is not sent before the timeout
+    Expired,
+}
+
+impl Display for ReplyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyError::Closed => write!(f, "connection is closed"),
+            ReplyError::Expired => write!(f, "deferred response is expired"),
+        }
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+/// response that is sent instead when a deferred response is expired.
+/// Response type should implement `From<Expired>` to be deferred.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Expired {
+    pub correlation_id: Option<u64>,
+}
+
+impl Display for Expired {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.correlation_id {
+            Some(id) => write!(f, "response of request {id} is expired"),
+            None => write!(f, "response is expired"),
+        }
+    }
+}
+
+impl std::error::Error for Expired {}
+
+/// responses of type `R` that are waiting to be written to the connection
+pub struct Outbox<R> {
+    tx: UnboundedSender<Response<R>>,
+}
+
+impl<R> Outbox<R> {
+    /// makes an outbox and the receiver of responses.
+    /// The connection should write what it receives to the peer.
+    pub fn channel() -> (Self, UnboundedReceiver<Response<R>>) {
+        let (tx, rx) = unbounded_channel();
+        (Self { tx }, rx)
+    }
+}
+
+impl<R> Clone for Outbox<R> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+/// sender of responses to the current request
+pub struct Reply<R> {
+    correlation_id: Option<u64>,
+    outbox: Outbox<R>,
+}
+
+impl<R> Reply<R> {
+    /// makes a reply of the request `correlation_id`
+    pub fn new(correlation_id: Option<CorrelationId>, outbox: Outbox<R>) -> Self {
+        Self {
+            correlation_id: correlation_id.map(|id| id.0),
+            outbox,
+        }
+    }
+
+    /// correlation id of the request
+    pub fn correlation_id(&self) -> Option<CorrelationId> {
+        self.correlation_id.map(CorrelationId)
+    }
+
+    /// sends a response.
+    /// This can be called any number of times.
+    pub fn send(&self, body: R) -> Result<(), ReplyError> {
+        self.outbox
+            .tx
+            .send(Response {
+                correlation_id: self.correlation_id,
+                body,
+            })
+            .map_err(|_| ReplyError::Closed)
+    }
+
+    /// returns true if the connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.outbox.tx.is_closed()
+    }
+}
+
+impl<R> Reply<R>
+where
+    R: From<Expired> + Send + 'static,
+{
+    /// keeps this reply to respond later.
+    /// If nothing is sent with `Deferred` in `timeout`, `Expired` is sent.
+    ///
+    /// This should be called in tokio runtime.
+    pub fn defer(self, timeout: Duration) -> Deferred<R> {
+        let done = Arc::new(AtomicBool::new(false));
+        let deferred = Deferred {
+            done: done.clone(),
+            reply: self.clone(),
+        };
+
+        tokio::spawn(async move {
+            sleep(timeout).await;
+            if !done.swap(true, Ordering::SeqCst) {
+                let _ = self.send(R::from(Expired {
+                    correlation_id: self.correlation_id,
+                }));
+            }
+        });
+
+        deferred
+    }
+
+    /// responds with the output of `f` in background.
+    /// If `f` does not complete in `timeout`, `Expired` is sent.
+    pub fn spawn<F>(self, timeout: Duration, f: F) -> JoinHandle<Result<(), ReplyError>>
+    where
+        F: Future<Output = R> + Send + 'static,
+    {
+        let deferred = self.defer(timeout);
+        tokio::spawn(async move {
+            let body = f.await;
+            deferred.send(body)
+        })
+    }
+}
+
+/// response that would be sent later
+pub struct Deferred<R> {
+    done: Arc<AtomicBool>,
+    reply: Reply<R>,
+}
+
+impl<R> Deferred<R> {
+    /// correlation id of the request
+    pub fn correlation_id(&self) -> Option<CorrelationId> {
+        self.reply.correlation_id()
+    }
+
+    /// returns true if the response is already expired
+    pub fn is_expired(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// sends the deferred response.
+    /// Returns `ReplyError::Expired` if the timeout is already passed.
+    pub fn send(self, body: R) -> Result<(), ReplyError> {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return Err(ReplyError::Expired);
+        }
+        self.reply.send(body)
+    }
+}
+
+impl<R> Clone for Reply<R> {
+    fn clone(&self) -> Self {
+        Self {
+            correlation_id: self.correlation_id,
+            outbox: self.outbox.clone(),
+        }
+    }
+}
+
+impl<R: Send + 'static> FromContext for Reply<R> {
+    fn from_context(context: &Context) -> Option<Self> {
+        let outbox = context.get::<Outbox<R>>()?.clone();
+        Some(Reply::new(context.get::<CorrelationId>().copied(), outbox))
+    }
+}
+
+/// `Layer` that sends the output of previous handler as the response
+pub struct ReplyLayer;
+
+impl<T, H> Layer<Request<T>, H> for ReplyLayer
+where
+    H: Handler<Request<T>>,
+    H::Output: Send + 'static,
+    H::Future: 'static,
+{
+    type Next = Request<T>;
+    type Error = H::Error;
+    type Handler = ReplyHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ReplyHandler { prev })
+    }
+}
+
+/// `Handler` that sends the output of previous handler to `Outbox` in the
+/// context. The output is dropped if there is no `Outbox` or the connection
+/// is already closed.
+pub struct ReplyHandler<H> {
+    prev: H,
+}
+
+impl<T, H> Handler<Request<T>> for ReplyHandler<H>
+where
+    H: Handler<Request<T>>,
+    H::Output: Send + 'static,
+    H::Future: 'static,
+{
+    type Output = ();
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<(), Self::Error>>;
+
+    fn call(&self, req: Request<T>) -> Self::Future {
+        let reply = Reply::<H::Output>::from_context(&req.context);
+        let prev = self.prev.call(req);
+
+        Box::pin(async move {
+            let output = prev.await?;
+            if let Some(reply) = reply {
+                let _ = reply.send(output);
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn reply_test() {
+        let (outbox, mut rx) = Outbox::<u32>::channel();
+        let mut context = Context::new();
+        assert!(Reply::<u32>::from_context(&context).is_none());
+
+        context.insert(outbox);
+        let reply = Reply::<u32>::from_context(&context).unwrap();
+        assert_eq!(reply.correlation_id(), None);
+        reply.send(1).unwrap();
+
+        context.insert(CorrelationId(3));
+        let reply = Reply::<u32>::from_context(&context).unwrap();
+        reply.send(2).unwrap();
+        reply.clone().send(3).unwrap();
+
+        assert_eq!(
+            rx.recv().await,
+            Some(Response {
+                correlation_id: None,
+                body: 1
+            })
+        );
+        assert_eq!(rx.recv().await.unwrap().correlation_id, Some(3));
+        assert_eq!(rx.recv().await.unwrap().body, 3);
+
+        drop(rx);
+        assert!(reply.is_closed());
+        assert_eq!(reply.send(4), Err(ReplyError::Closed));
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Answer {
+        Value(u32),
+        Expired(Option<u64>),
+    }
+
+    impl From<Expired> for Answer {
+        fn from(e: Expired) -> Self {
+            Answer::Expired(e.correlation_id)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deferred_test() {
+        let (outbox, mut rx) = Outbox::<Answer>::channel();
+
+        let deferred =
+            Reply::new(Some(CorrelationId(1)), outbox.clone()).defer(Duration::from_secs(10));
+        assert_eq!(deferred.correlation_id(), Some(CorrelationId(1)));
+        sleep(Duration::from_secs(5)).await;
+        deferred.send(Answer::Value(1)).unwrap();
+        assert_eq!(rx.recv().await.unwrap().body, Answer::Value(1));
+
+        let deferred =
+            Reply::new(Some(CorrelationId(2)), outbox.clone()).defer(Duration::from_secs(10));
+        sleep(Duration::from_secs(11)).await;
+        assert!(deferred.is_expired());
+        assert_eq!(deferred.send(Answer::Value(2)), Err(ReplyError::Expired));
+        assert_eq!(rx.recv().await.unwrap().body, Answer::Expired(Some(2)));
+
+        // nothing is sent twice
+        sleep(Duration::from_secs(20)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spawn_test() {
+        let (outbox, mut rx) = Outbox::<Answer>::channel();
+        let reply = Reply::new(Some(CorrelationId(1)), outbox);
+
+        let fast = reply.clone().spawn(Duration::from_secs(10), async {
+            sleep(Duration::from_secs(1)).await;
+            Answer::Value(1)
+        });
+        let slow = reply.spawn(Duration::from_secs(10), async {
+            sleep(Duration::from_secs(60)).await;
+            Answer::Value(2)
+        });
+
+        assert_eq!(rx.recv().await.unwrap().body, Answer::Value(1));
+        assert_eq!(rx.recv().await.unwrap().body, Answer::Expired(Some(1)));
+        assert_eq!(fast.await.unwrap(), Ok(()));
+        assert_eq!(slow.await.unwrap(), Err(ReplyError::Expired));
+    }
+
+    #[tokio::test]
+    async fn reply_layer_test() -> Result<(), ()> {
+        async fn double(req: Request<u32>) -> Result<u32, ()> {
+            Ok(req.msg * 2)
+        }
+
+        let (outbox, mut rx) = Outbox::<u32>::channel();
+        let mut context = Context::new();
+        context.insert(outbox);
+        context.insert(CorrelationId(5));
+
+        let handler =
+            crate::layer::connect(ReplyLayer, crate::fn_handler::fn_handler(double)).await?;
+        handler.call(Request::with_context(21, context)).await?;
+        assert_eq!(
+            rx.recv().await,
+            Some(Response {
+                correlation_id: Some(5),
+                body: 42
+            })
+        );
+
+        // without outbox, output is dropped
+        handler.call(Request::new(1)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! Routers that dispatch messages to different handler chains
+//!
+//! `ContentRouter` dispatches raw payloads by their `ContentType`, so that
+//! one server can accept protobuf game messages and JSON admin commands on
+//! different chains without separate listeners. The content type of each
+//! message is found in its context. When there is no `ContentType`, the
+//! codec `Negotiated` for the connection is used instead.
+//!
+//! `RouterLayer` dispatches envelopes by the type of the message
+//! (`Headers::message_type`). Each route of `Router` decodes the payload
+//! into its protobuf message type and calls its own chain with it. Messages
+//! of other types go on to the next handler of the layer.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::context::Request;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::router::{ContentRouter, ContentType, NoRoute};
+//!
+//! async fn game(_: Request<Vec<u8>>) -> Result<&'static str, NoRoute> {
+//!     Ok("game")
+//! }
+//!
+//! async fn admin(_: Request<Vec<u8>>) -> Result<&'static str, NoRoute> {
+//!     Ok("admin")
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), NoRoute> {
+//! let router = ContentRouter::new()
+//!     .route("protobuf", fn_handler(game))
+//!     .route("json", fn_handler(admin));
+//!
+//! let mut req = Request::new(b"{}".to_vec());
+//! req.context.insert(ContentType::new("json"));
+//! assert_eq!(router.call(req).await?, "admin");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ```
+//! use cubby_connect_server_core::context::Request;
+//! use cubby_connect_server_core::envelope::Envelope;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::router::{MessageType, Router, RouterLayer};
+//! use cubby_connect_server_core::apply;
+//! use prost::{DecodeError, Message};
+//!
+//! #[derive(Clone, PartialEq, Message)]
+//! struct Login {
+//!     #[prost(string, tag = "1")]
+//!     name: String,
+//! }
+//!
+//! impl MessageType for Login {
+//!     const MESSAGE_TYPE: &'static str = "login";
+//! }
+//!
+//! async fn login(req: Request<Login>) -> Result<String, DecodeError> {
+//!     Ok(format!("welcome {}", req.msg.name))
+//! }
+//!
+//! async fn unknown(_: Envelope<Vec<u8>>) -> Result<String, DecodeError> {
+//!     Ok("unknown".to_string())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), DecodeError> {
+//! let router = Router::new().route::<Login, _>(fn_handler(login));
+//! let handler = apply!(RouterLayer::new(router) to unknown);
+//!
+//! let env = Envelope::typed(&Login { name: "cubby".to_string() });
+//! assert_eq!(handler.call(env).await?, "welcome cubby");
+//! assert_eq!(handler.call(Envelope::new(Vec::new())).await?, "unknown");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{err, ok, Either, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use prost::{DecodeError, Message};
+
+use crate::codec::Negotiated;
+use crate::context::{Context, FromContext, Request};
+use crate::envelope::Envelope;
+use crate::handler::{BoxHandler, Handler};
+use crate::layer::Layer;
+
+/// content type (format) of a payload.
+/// It has the same names as codecs, like `protobuf` or `json`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ContentType(pub String);
+
+impl ContentType {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self(name.into())
+    }
+}
+
+/// content type of the message, or the negotiated codec of the connection
+impl FromContext for ContentType {
+    fn from_context(context: &Context) -> Option<Self> {
+        context
+            .get::<ContentType>()
+            .cloned()
+            .or_else(|| Negotiated::from_context(context).map(|n| ContentType::new(n.0)))
+    }
+}
+
+/// error when there is no route for a message.
+/// It has the content type of the message if there is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NoRoute(pub Option<String>);
+
+impl Display for NoRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(content_type) => write!(f, "no route for content type `{content_type}`"),
+            None => write!(f, "no route for message without content type"),
+        }
+    }
+}
+
+impl std::error::Error for NoRoute {}
+
+/// `Handler` that dispatches payloads by their content type
+pub struct ContentRouter<O, E> {
+    routes: HashMap<String, BoxHandler<Request<Vec<u8>>, O, E>>,
+    fallback: Option<BoxHandler<Request<Vec<u8>>, O, E>>,
+}
+
+impl<O, E> Default for ContentRouter<O, E> {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl<O, E> ContentRouter<O, E> {
+    /// returns a router without any route
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// routes payloads of `content_type` to `handler`
+    pub fn route<S, H>(mut self, content_type: S, handler: H) -> Self
+    where
+        S: Into<String>,
+        H: Handler<Request<Vec<u8>>, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        self.routes
+            .insert(content_type.into(), BoxHandler::new(handler));
+        self
+    }
+
+    /// routes payloads that don't match any route to `handler`
+    pub fn fallback<H>(mut self, handler: H) -> Self
+    where
+        H: Handler<Request<Vec<u8>>, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+    {
+        self.fallback = Some(BoxHandler::new(handler));
+        self
+    }
+}
+
+impl<O, E> Handler<Request<Vec<u8>>> for ContentRouter<O, E>
+where
+    O: 'static,
+    E: From<NoRoute> + 'static,
+{
+    type Output = O;
+    type Error = E;
+    type Future = LocalBoxFuture<'static, Result<O, E>>;
+
+    fn call(&self, req: Request<Vec<u8>>) -> Self::Future {
+        let content_type = ContentType::from_context(&req.context);
+        let route = content_type
+            .as_ref()
+            .and_then(|c| self.routes.get(&c.0))
+            .or(self.fallback.as_ref());
+
+        match route {
+            Some(handler) => handler.call(req),
+            None => err(NoRoute(content_type.map(|c| c.0)).into()).boxed_local(),
+        }
+    }
+}
+
+/// protobuf message that has a type name for routing
+pub trait MessageType {
+    /// name that is written in `Headers::message_type`
+    const MESSAGE_TYPE: &'static str;
+}
+
+impl Envelope<Vec<u8>> {
+    /// encodes `msg` into an envelope with its type name
+    pub fn typed<M: Message + MessageType>(msg: &M) -> Self {
+        let mut env = Envelope::new(msg.encode_to_vec());
+        env.headers.message_type = Some(M::MESSAGE_TYPE.to_string());
+        env
+    }
+}
+
+/// `Handler` that decodes payloads into `M` before `H`
+struct DecodeRoute<M, H> {
+    handler: H,
+    _marker: PhantomData<fn(M)>,
+}
+
+impl<M, H> Handler<Envelope<Vec<u8>>> for DecodeRoute<M, H>
+where
+    M: Message + Default,
+    H: Handler<Request<M>>,
+    H::Error: From<DecodeError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, env: Envelope<Vec<u8>>) -> Self::Future {
+        match M::decode(env.msg.as_slice()) {
+            Ok(msg) => Either::Left(self.handler.call(env.map(|_| msg).into_request())),
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
+}
+
+/// routes of message types for `RouterLayer`
+pub struct Router<O, E> {
+    routes: HashMap<&'static str, BoxHandler<Envelope<Vec<u8>>, O, E>>,
+}
+
+impl<O, E> Default for Router<O, E> {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl<O, E> Router<O, E> {
+    /// returns a router without any route
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// routes messages of type `M` to `handler`.
+    /// The payload is decoded into `M`, and the headers are in the context
+    /// of the request.
+    pub fn route<M, H>(mut self, handler: H) -> Self
+    where
+        M: Message + Default + MessageType + 'static,
+        H: Handler<Request<M>, Output = O, Error = E> + 'static,
+        H::Future: 'static,
+        O: 'static,
+        E: From<DecodeError> + 'static,
+    {
+        let route = DecodeRoute {
+            handler,
+            _marker: PhantomData::<fn(M)>,
+        };
+        self.routes.insert(M::MESSAGE_TYPE, BoxHandler::new(route));
+        self
+    }
+}
+
+/// `Layer` that builds `RouterHandler`
+pub struct RouterLayer<O, E> {
+    router: Arc<Router<O, E>>,
+}
+
+impl<O, E> RouterLayer<O, E> {
+    pub fn new(router: Router<O, E>) -> Self {
+        Self {
+            router: Arc::new(router),
+        }
+    }
+}
+
+impl<O, E, H> Layer<Envelope<Vec<u8>>, H> for RouterLayer<O, E>
+where
+    O: 'static,
+    E: 'static,
+    H: Handler<Envelope<Vec<u8>>, Output = O, Error = E>,
+    H::Future: 'static,
+{
+    type Next = Envelope<Vec<u8>>;
+    type Error = E;
+    type Handler = RouterHandler<O, E, H>;
+    type InitError = E;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(RouterHandler {
+            router: self.router.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that dispatches envelopes by the type of the message.
+/// Messages without a route go to the previous handler.
+pub struct RouterHandler<O, E, H> {
+    router: Arc<Router<O, E>>,
+    prev: H,
+}
+
+impl<O, E, H> Handler<Envelope<Vec<u8>>> for RouterHandler<O, E, H>
+where
+    O: 'static,
+    E: 'static,
+    H: Handler<Envelope<Vec<u8>>, Output = O, Error = E>,
+    H::Future: 'static,
+{
+    type Output = O;
+    type Error = E;
+    type Future = LocalBoxFuture<'static, Result<O, E>>;
+
+    fn call(&self, env: Envelope<Vec<u8>>) -> Self::Future {
+        let route = env
+            .headers
+            .message_type
+            .as_deref()
+            .and_then(|t| self.router.routes.get(t));
+
+        match route {
+            Some(handler) => handler.call(env),
+            None => self.prev.call(env).boxed_local(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::envelope::Headers;
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    async fn len(req: Request<Vec<u8>>) -> Result<usize, NoRoute> {
+        Ok(req.msg.len())
+    }
+
+    async fn zero(_: Request<Vec<u8>>) -> Result<usize, NoRoute> {
+        Ok(0)
+    }
+
+    fn request(content_type: Option<&str>, negotiated: Option<&'static str>) -> Request<Vec<u8>> {
+        let mut req = Request::new(vec![1, 2, 3]);
+        if let Some(c) = content_type {
+            req.context.insert(ContentType::new(c));
+        }
+        if let Some(n) = negotiated {
+            req.context.insert(Negotiated(n));
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn route_test() -> Result<(), NoRoute> {
+        let router = ContentRouter::new().route("protobuf", fn_handler(len));
+
+        assert_eq!(router.call(request(Some("protobuf"), None)).await?, 3);
+        // negotiated codec is used without content type
+        assert_eq!(router.call(request(None, Some("protobuf"))).await?, 3);
+        assert_eq!(
+            router.call(request(Some("json"), Some("protobuf"))).await,
+            Err(NoRoute(Some("json".to_string())))
+        );
+        assert_eq!(router.call(request(None, None)).await, Err(NoRoute(None)));
+
+        let router = router.fallback(fn_handler(zero));
+        assert_eq!(router.call(request(Some("json"), None)).await?, 0);
+        Ok(())
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Chat {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    impl MessageType for Chat {
+        const MESSAGE_TYPE: &'static str = "chat";
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Move {
+        #[prost(int32, tag = "1")]
+        x: i32,
+    }
+
+    impl MessageType for Move {
+        const MESSAGE_TYPE: &'static str = "move";
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Decode,
+        Unknown(Option<String>),
+    }
+
+    impl From<DecodeError> for Error {
+        fn from(_: DecodeError) -> Self {
+            Error::Decode
+        }
+    }
+
+    async fn chat(req: Request<Chat>) -> Result<String, Error> {
+        Ok(req.msg.text)
+    }
+
+    async fn move_to(req: Request<Move>) -> Result<String, Error> {
+        let headers = Headers::from_context(&req.context).unwrap();
+        Ok(format!(
+            "{} to {}",
+            headers.get("player").unwrap(),
+            req.msg.x
+        ))
+    }
+
+    async fn unknown(env: Envelope<Vec<u8>>) -> Result<String, Error> {
+        Err(Error::Unknown(env.headers.message_type))
+    }
+
+    #[tokio::test]
+    async fn router_layer_test() -> Result<(), Error> {
+        let router = Router::new()
+            .route::<Chat, _>(fn_handler(chat))
+            .route::<Move, _>(fn_handler(move_to));
+        let handler = connect(RouterLayer::new(router), unknown).await?;
+
+        let env = Envelope::typed(&Chat {
+            text: "hi".to_string(),
+        });
+        assert_eq!(handler.call(env).await?, "hi");
+
+        let mut env = Envelope::typed(&Move { x: 3 });
+        env.headers.insert("player", "cubby");
+        assert_eq!(handler.call(env).await?, "cubby to 3");
+
+        let mut env = Envelope::new(vec![0xff]);
+        env.headers.message_type = Some("move".to_string());
+        assert_eq!(handler.call(env).await, Err(Error::Decode));
+
+        let mut env = Envelope::new(Vec::new());
+        env.headers.message_type = Some("trade".to_string());
+        assert_eq!(
+            handler.call(env).await,
+            Err(Error::Unknown(Some("trade".to_string())))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! Requests from the server to a client (reverse RPC)
+//!
+//! Usually clients send requests and the server responds, but sometimes the
+//! server needs to ask a client (e.g. "report your local state").
+//! `Caller<Q, R>` sends a request of type `Q` to one client with a new
+//! `CorrelationId` and waits for the response of type `R` with a timeout.
+//!
+//! The connection writes `Call<Q>`s that are sent through the `Caller`, and
+//! gives responses from the client back to the `Caller`. `Caller` is also a
+//! `Handler<Response<R>>` for this purpose.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::reply::Response;
+//! use cubby_connect_server_core::rpc::Caller;
+//! use std::time::Duration;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::rpc::CallError> {
+//! let (caller, mut calls) = Caller::<&str, u32>::channel();
+//!
+//! // client side that answers the number of items
+//! let responder = caller.clone();
+//! tokio::spawn(async move {
+//!     while let Some(call) = calls.recv().await {
+//!         assert_eq!(call.body, "count items");
+//!         let response = Response {
+//!             correlation_id: Some(call.correlation_id),
+//!             body: 3,
+//!         };
+//!         responder.call(response).await.unwrap();
+//!     }
+//! });
+//!
+//! let count = caller.request("count items", Duration::from_secs(1)).await?;
+//! assert_eq!(count, 3);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{err, ok, Ready};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::context::{Context, FromContext};
+use crate::handler::Handler;
+use crate::reply::Response;
+
+/// request from the server to a client
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Call<Q> {
+    /// the client should respond with this id
+    pub correlation_id: u64,
+    pub body: Q,
+}
+
+/// error when calling a client
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CallError {
+    /// the connection is closed
+    Closed,
+
+    /// the client did not respond before the timeout
+    Timeout,
+
+    /// response does not match any request that is waiting
+    Unexpected(Option<u64>),
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::Closed => write!(f, "connection is closed"),
+            CallError::Timeout => write!(f, "client did not respond in time"),
+            CallError::Unexpected(Some(id)) => write!(f, "unexpected response to {id}"),
+            CallError::Unexpected(None) => write!(f, "response without correlation id"),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+struct Inner<Q, R> {
+    tx: UnboundedSender<Call<Q>>,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<R>>>,
+}
+
+/// removes a request from `pending` when it stops waiting, whether it is
+/// done or dropped
+struct Waiting<'a, R> {
+    pending: &'a Mutex<HashMap<u64, oneshot::Sender<R>>>,
+    id: u64,
+}
+
+impl<R> Drop for Waiting<'_, R> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// sends requests to one client and waits for responses
+pub struct Caller<Q, R> {
+    inner: Arc<Inner<Q, R>>,
+}
+
+impl<Q, R> Clone for Caller<Q, R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<Q, R> Caller<Q, R> {
+    /// makes a caller and the receiver of calls.
+    /// The connection should write the received calls to the client.
+    pub fn channel() -> (Self, UnboundedReceiver<Call<Q>>) {
+        let (tx, rx) = unbounded_channel();
+        let caller = Self {
+            inner: Arc::new(Inner {
+                tx,
+                next_id: AtomicU64::new(0),
+                pending: Mutex::new(HashMap::new()),
+            }),
+        };
+        (caller, rx)
+    }
+
+    /// sends `body` to the client and waits for its response
+    /// at most `duration`.
+    /// The request stops waiting if the returned future is dropped.
+    pub async fn request(&self, body: Q, duration: Duration) -> Result<R, CallError> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(id, tx);
+        let _waiting = Waiting {
+            pending: &self.inner.pending,
+            id,
+        };
+
+        let call = Call {
+            correlation_id: id,
+            body,
+        };
+        if self.inner.tx.send(call).is_err() {
+            return Err(CallError::Closed);
+        }
+
+        match timeout(duration, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(CallError::Closed),
+            Err(_) => Err(CallError::Timeout),
+        }
+    }
+
+    /// returns the number of requests that are waiting for responses
+    pub fn pending(&self) -> usize {
+        self.inner.pending.lock().unwrap().len()
+    }
+
+    /// gives `response` to the request that is waiting for it.
+    /// Late responses (after the timeout) are `CallError::Unexpected`.
+    pub fn complete(&self, response: Response<R>) -> Result<(), CallError> {
+        let id = response.correlation_id;
+        let tx = id
+            .and_then(|id| self.inner.pending.lock().unwrap().remove(&id))
+            .ok_or(CallError::Unexpected(id))?;
+        tx.send(response.body)
+            .map_err(|_| CallError::Unexpected(id))
+    }
+}
+
+/// responses from the client
+impl<Q, R> Handler<Response<R>> for Caller<Q, R> {
+    type Output = ();
+    type Error = CallError;
+    type Future = Ready<Result<(), CallError>>;
+
+    fn call(&self, response: Response<R>) -> Self::Future {
+        match self.complete(response) {
+            Ok(()) => ok(()),
+            Err(e) => err(e),
+        }
+    }
+}
+
+/// caller of the client that sent the request.
+/// The connection puts its `Caller` into the context.
+impl<Q: Send + 'static, R: Send + 'static> FromContext for Caller<Q, R> {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Caller<Q, R>>().cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn request_test() {
+        let (caller, mut calls) = Caller::<u32, u32>::channel();
+
+        let responder = caller.clone();
+        tokio::spawn(async move {
+            while let Some(call) = calls.recv().await {
+                // does not answer 0
+                if call.body != 0 {
+                    let response = Response {
+                        correlation_id: Some(call.correlation_id),
+                        body: call.body * 2,
+                    };
+                    responder.call(response).await.unwrap();
+                }
+            }
+        });
+
+        let (a, b) = tokio::join!(
+            caller.request(1, Duration::from_secs(1)),
+            caller.request(2, Duration::from_secs(1))
+        );
+        assert_eq!((a, b), (Ok(2), Ok(4)));
+        assert_eq!(
+            caller.request(0, Duration::from_millis(10)).await,
+            Err(CallError::Timeout)
+        );
+        assert_eq!(caller.pending(), 0);
+
+        // late response
+        let late = Response {
+            correlation_id: Some(2),
+            body: 0,
+        };
+        assert_eq!(caller.call(late).await, Err(CallError::Unexpected(Some(2))));
+    }
+
+    #[tokio::test]
+    async fn closed_test() {
+        let (caller, calls) = Caller::<u32, u32>::channel();
+        drop(calls);
+        assert_eq!(
+            caller.request(1, Duration::from_secs(1)).await,
+            Err(CallError::Closed)
+        );
+        assert_eq!(caller.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_test() {
+        let (caller, mut calls) = Caller::<u32, u32>::channel();
+
+        // given up before the client responds
+        let request = caller.request(1, Duration::from_secs(1));
+        let dropped = tokio::time::timeout(Duration::from_millis(10), request);
+        assert!(dropped.await.is_err());
+        assert_eq!(caller.pending(), 0);
+
+        let call = calls.recv().await.unwrap();
+        let response = Response {
+            correlation_id: Some(call.correlation_id),
+            body: 2,
+        };
+        assert_eq!(
+            caller.call(response).await,
+            Err(CallError::Unexpected(Some(call.correlation_id)))
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Scheduled messages that survive restarts
+//!
+//! `Connections::send_at` and `PubSub::send_at` keep scheduled messages in
+//! memory, so they are lost when the server restarts. `ScheduledStore`
+//! writes them into the `scheduled` table instead, and
+//! `ScheduledPublisher` publishes each to its topic when it is due and
+//! deletes it. Messages that became due while the server was down are
+//! published right after it starts.
+//!
+//! Connections don't survive restarts, so durable messages go to topics.
+//! The publisher sleeps until the next message is due, or until the store
+//! schedules an earlier one. Delivery is at-least-once like the outbox.
+//!
+//! The table should look like this (in SQLite), with `due` in milliseconds
+//! since the unix epoch:
+//!
+//! ```sql
+//! CREATE TABLE scheduled (
+//!     id INTEGER PRIMARY KEY AUTOINCREMENT,
+//!     due INTEGER NOT NULL,
+//!     topic TEXT NOT NULL,
+//!     payload BLOB NOT NULL
+//! )
+//! ```
+//!
+//! This module needs the `database` feature.
+//!
+//! # Examples
+//!
+//! The examples use SQLite, so they run with the `sqlite` feature.
+//!
+#![cfg_attr(feature = "sqlite", doc = "```")]
+#![cfg_attr(not(feature = "sqlite"), doc = "```ignore")]
+//! use std::time::{Duration, SystemTime};
+//! use cubby_connect_server_core::scheduled::{ScheduledMessage, ScheduledPublisher, ScheduledStore};
+//! use sqlx::sqlite::SqlitePoolOptions;
+//!
+//! async fn publish(msg: ScheduledMessage) -> Result<(), ()> {
+//!     println!("publish {} to {}", msg.payload.len(), msg.topic);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), sqlx::Error> {
+//! let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+//! sqlx::query("CREATE TABLE scheduled (id INTEGER PRIMARY KEY AUTOINCREMENT, due INTEGER, topic TEXT, payload BLOB)")
+//!     .execute(&pool)
+//!     .await?;
+//!
+//! let store = ScheduledStore::new(pool);
+//! store.send_at(SystemTime::now(), "event", b"starts".to_vec()).await?;
+//! store.send_after(Duration::from_secs(3600), "event", b"ends".to_vec()).await?;
+//!
+//! // usually `run` is spawned instead
+//! let publisher = ScheduledPublisher::new(store, publish);
+//! assert_eq!(publisher.publish_due().await?, 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sqlx::{Database, Decode, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+use tokio::sync::Notify;
+
+use crate::handler::{Handler, IntoHandler};
+
+/// message that is published by `ScheduledPublisher`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScheduledMessage {
+    /// id in the scheduled table
+    pub id: i64,
+    pub due: SystemTime,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// store of messages to publish later.
+/// Clones share the same table.
+pub struct ScheduledStore<DB: Database> {
+    pool: Pool<DB>,
+
+    /// wakes the publisher when a message is scheduled
+    scheduled: Arc<Notify>,
+}
+
+impl<DB: Database> Clone for ScheduledStore<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            scheduled: self.scheduled.clone(),
+        }
+    }
+}
+
+impl<DB> ScheduledStore<DB>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> Vec<u8>: Encode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> i64: Decode<'r, DB> + Type<DB>,
+{
+    /// stores messages in the `scheduled` table of `pool`
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self {
+            pool,
+            scheduled: Arc::new(Notify::new()),
+        }
+    }
+
+    /// publishes `payload` to `topic` at `at`, and returns its id
+    pub async fn send_at<S: Into<String>>(
+        &self,
+        at: SystemTime,
+        topic: S,
+        payload: Vec<u8>,
+    ) -> Result<i64, sqlx::Error> {
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO scheduled (due, topic, payload) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(to_millis(at))
+        .bind(topic.into())
+        .bind(payload)
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.scheduled.notify_waiters();
+        Ok(id)
+    }
+
+    /// publishes `payload` to `topic` after `after`, and returns its id
+    pub async fn send_after<S: Into<String>>(
+        &self,
+        after: Duration,
+        topic: S,
+        payload: Vec<u8>,
+    ) -> Result<i64, sqlx::Error> {
+        self.send_at(SystemTime::now() + after, topic, payload)
+            .await
+    }
+
+    /// cancels the message of `id`.
+    /// Returns false if it is already published or cancelled.
+    pub async fn cancel(&self, id: i64) -> Result<bool, sqlx::Error> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("DELETE FROM scheduled WHERE id = $1 RETURNING id")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.is_some())
+    }
+
+    /// returns when the next message is due
+    pub async fn next_due(&self) -> Result<Option<SystemTime>, sqlx::Error> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT due FROM scheduled ORDER BY due LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(due,)| from_millis(due)))
+    }
+}
+
+/// publisher of messages in `ScheduledStore` when they are due
+pub struct ScheduledPublisher<DB: Database, H> {
+    store: ScheduledStore<DB>,
+    publish: H,
+    batch: i64,
+    interval: Duration,
+}
+
+impl<DB, H> ScheduledPublisher<DB, H>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> Vec<u8>: Encode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> i64: Decode<'r, DB> + Type<DB>,
+    for<'r> (i64, i64, String, Vec<u8>): FromRow<'r, DB::Row>,
+    H: Handler<ScheduledMessage>,
+{
+    /// publishes messages in `store` with `publish`.
+    /// By default, up to 100 messages are published at once, and the table
+    /// is checked at least every minute for messages scheduled by other
+    /// servers.
+    pub fn new<P: IntoHandler<H, ScheduledMessage>>(store: ScheduledStore<DB>, publish: P) -> Self {
+        Self {
+            store,
+            publish: publish.into_handler(),
+            batch: 100,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    /// sets the largest number of messages that are published at once
+    pub fn batch(mut self, batch: i64) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// sets the longest time between checking the scheduled table
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// publishes due messages in order, deleting each after it is
+    /// published. It stops at the first message that fails, which is
+    /// retried later. Returns the number of published messages.
+    pub async fn publish_due(&self) -> Result<usize, sqlx::Error> {
+        let rows: Vec<(i64, i64, String, Vec<u8>)> = sqlx::query_as(
+            "SELECT id, due, topic, payload FROM scheduled WHERE due <= $1 ORDER BY due, id LIMIT $2",
+        )
+        .bind(to_millis(SystemTime::now()))
+        .bind(self.batch)
+        .fetch_all(&self.store.pool)
+        .await?;
+
+        let mut published = 0;
+        for (id, due, topic, payload) in rows {
+            let msg = ScheduledMessage {
+                id,
+                due: from_millis(due),
+                topic,
+                payload,
+            };
+            if self.publish.call(msg).await.is_err() {
+                break;
+            }
+            self.store.cancel(id).await?;
+            published += 1;
+        }
+        Ok(published)
+    }
+
+    /// publishes messages when they are due forever.
+    /// Errors of the database are retried in the next interval.
+    pub async fn run(&self) {
+        loop {
+            // registered before checking, not to miss messages scheduled
+            // in between
+            let scheduled = self.store.scheduled.notified();
+            tokio::pin!(scheduled);
+            scheduled.as_mut().enable();
+
+            let mut wait = self.interval;
+            if let Ok(published) = self.publish_due().await {
+                if published as i64 == self.batch {
+                    continue;
+                }
+                if let Ok(Some(due)) = self.store.next_due().await {
+                    let until = due.duration_since(SystemTime::now()).unwrap_or_default();
+                    wait = wait.min(until);
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = scheduled => {}
+            }
+        }
+    }
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    if millis >= 0 {
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(millis.unsigned_abs())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::future::{ok, Ready};
+    use sqlx::sqlite::{Sqlite, SqlitePool, SqlitePoolOptions};
+
+    use super::*;
+
+    async fn store() -> Result<ScheduledStore<Sqlite>, sqlx::Error> {
+        let pool: SqlitePool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::query(
+            "CREATE TABLE scheduled (id INTEGER PRIMARY KEY AUTOINCREMENT, due INTEGER, topic TEXT, payload BLOB)",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(ScheduledStore::new(pool))
+    }
+
+    /// handler that keeps published payloads
+    #[derive(Clone, Default)]
+    struct Published(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Handler<ScheduledMessage> for Published {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, msg: ScheduledMessage) -> Self::Future {
+            self.0.lock().unwrap().push(msg.payload);
+            ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_test() -> Result<(), sqlx::Error> {
+        let store = store().await?;
+        let now = SystemTime::now();
+        store
+            .send_at(now - Duration::from_secs(1), "a", vec![2])
+            .await?;
+        store
+            .send_at(now - Duration::from_secs(2), "a", vec![1])
+            .await?;
+        let later = store
+            .send_after(Duration::from_secs(3600), "a", vec![3])
+            .await?;
+        let cancelled = store.send_at(now, "a", vec![0]).await?;
+        assert!(store.cancel(cancelled).await?);
+        assert!(!store.cancel(cancelled).await?);
+
+        let published = Published::default();
+        let publisher = ScheduledPublisher::new(store.clone(), published.clone());
+        assert_eq!(publisher.publish_due().await?, 2);
+        assert_eq!(publisher.publish_due().await?, 0);
+        assert_eq!(*published.0.lock().unwrap(), vec![vec![1], vec![2]]);
+
+        let due = store.next_due().await?.unwrap();
+        assert!(due > now + Duration::from_secs(3500));
+        assert!(store.cancel(later).await?);
+        assert_eq!(store.next_due().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_test() -> Result<(), sqlx::Error> {
+        let store = store().await?;
+        let published = Published::default();
+        let publisher = ScheduledPublisher::new(store.clone(), published.clone());
+        let task = tokio::spawn(async move { publisher.run().await });
+
+        // the publisher is woken by the store, before the interval
+        store
+            .send_after(Duration::from_millis(50), "a", vec![1])
+            .await?;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(*published.0.lock().unwrap(), vec![vec![1]]);
+
+        task.abort();
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Schema ids of payloads and schema registry client
+//!
+//! Organizations with a central schema registry tag every payload with the
+//! id of its schema. A tagged frame is a magic byte (`0`), the schema id in
+//! 4 bytes (big endian), and the payload, which is the same wire format as
+//! Confluent Schema Registry.
+//!
+//! `SchemaRegistry` fetches schemas from a `SchemaSource` and caches them.
+//! `SchemaLayer` validates the schema id of every frame with the registry
+//! before passing it to the next handler as `Tagged`.
+//!
+//! With the `schema-registry` feature, `HttpSource` fetches schemas from the
+//! REST API of a registry (`GET /schemas/ids/{id}`).
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::schema::{tag, Schema, SchemaError, SchemaLayer, SchemaRegistry, Tagged};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn fetch(id: u32) -> Result<Schema, SchemaError> {
+//!     match id {
+//!         1 => Ok(Schema {
+//!             id,
+//!             definition: "message Person { required string name = 1; }".to_string(),
+//!         }),
+//!         _ => Err(SchemaError::Unknown(id)),
+//!     }
+//! }
+//!
+//! async fn handle(t: Tagged) -> Result<(), SchemaError> {
+//!     println!("{} bytes of schema {}", t.payload.len(), t.schema.id);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), SchemaError> {
+//! let registry = SchemaRegistry::new(fetch);
+//! let handler = apply!(SchemaLayer::new(registry) to handle);
+//!
+//! handler.call(tag(1, b"payload")).await?;
+//! assert_eq!(handler.call(tag(2, b"payload")).await, Err(SchemaError::Unknown(2)));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// first byte of a tagged frame
+pub const MAGIC: u8 = 0;
+
+/// error of schemas
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SchemaError {
+    /// frame does not start with magic byte and schema id
+    Untagged,
+
+    /// registry does not know the schema id
+    Unknown(u32),
+
+    /// failed to communicate with registry
+    Registry(String),
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::Untagged => write!(f, "payload is not tagged with schema id"),
+            SchemaError::Unknown(id) => write!(f, "unknown schema id {id}"),
+            SchemaError::Registry(e) => write!(f, "schema registry error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// schema in registry
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Schema {
+    pub id: u32,
+
+    /// schema itself (e.g. `.proto` definition)
+    pub definition: String,
+}
+
+/// tags `payload` with `schema_id`
+pub fn tag(schema_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(MAGIC);
+    frame.extend_from_slice(&schema_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// splits tagged `frame` into schema id and payload
+pub fn untag(frame: &[u8]) -> Result<(u32, &[u8]), SchemaError> {
+    match frame {
+        [MAGIC, a, b, c, d, payload @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), payload)),
+        _ => Err(SchemaError::Untagged),
+    }
+}
+
+/// where schemas are fetched from.
+/// Functions as `async fn(u32) -> Result<Schema, SchemaError>` are also sources.
+pub trait SchemaSource {
+    /// future of `fetch`
+    type Future: Future<Output = Result<Schema, SchemaError>>;
+
+    /// fetches schema of `id`
+    fn fetch(&self, id: u32) -> Self::Future;
+}
+
+impl<F, Fut> SchemaSource for F
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<Schema, SchemaError>>,
+{
+    type Future = Fut;
+
+    fn fetch(&self, id: u32) -> Self::Future {
+        self(id)
+    }
+}
+
+/// schemas fetched from a source with local cache.
+/// Schemas are immutable in registries, so they are cached forever.
+pub struct SchemaRegistry<S> {
+    source: S,
+    cache: Mutex<HashMap<u32, Arc<Schema>>>,
+}
+
+impl<S: SchemaSource> SchemaRegistry<S> {
+    /// returns a registry with empty cache
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// returns schema of `id` from the cache or the source
+    pub async fn schema(&self, id: u32) -> Result<Arc<Schema>, SchemaError> {
+        if let Some(schema) = self.cache.lock().unwrap().get(&id) {
+            return Ok(schema.clone());
+        }
+
+        let schema = Arc::new(self.source.fetch(id).await?);
+        if schema.id != id {
+            return Err(SchemaError::Registry(format!(
+                "requested schema {id}, got {}",
+                schema.id
+            )));
+        }
+        self.cache.lock().unwrap().insert(id, schema.clone());
+        Ok(schema)
+    }
+
+    /// checks that the schema of tagged `frame` is in the registry,
+    /// and returns the schema and the payload
+    pub async fn validate<'f>(
+        &self,
+        frame: &'f [u8],
+    ) -> Result<(Arc<Schema>, &'f [u8]), SchemaError> {
+        let (id, payload) = untag(frame)?;
+        Ok((self.schema(id).await?, payload))
+    }
+
+    /// returns the number of cached schemas
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+}
+
+/// payload that is validated with its schema
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tagged {
+    pub schema: Arc<Schema>,
+    pub payload: Vec<u8>,
+}
+
+/// `Layer` that builds `SchemaHandler`
+pub struct SchemaLayer<S> {
+    registry: Arc<SchemaRegistry<S>>,
+}
+
+impl<S> SchemaLayer<S> {
+    /// validates frames with `registry`
+    pub fn new(registry: SchemaRegistry<S>) -> Self {
+        Self::shared(Arc::new(registry))
+    }
+
+    /// validates frames with `registry` shared with others
+    pub fn shared(registry: Arc<SchemaRegistry<S>>) -> Self {
+        Self { registry }
+    }
+}
+
+impl<S, H> Layer<Vec<u8>, H> for SchemaLayer<S>
+where
+    S: SchemaSource + 'static,
+    H: Handler<Tagged> + 'static,
+    H::Error: From<SchemaError>,
+{
+    type Next = Tagged;
+    type Error = H::Error;
+    type Handler = SchemaHandler<S, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(SchemaHandler {
+            registry: self.registry.clone(),
+            prev: Arc::new(prev),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that validates schema ids of tagged frames
+pub struct SchemaHandler<S, H> {
+    registry: Arc<SchemaRegistry<S>>,
+    prev: Arc<H>,
+    _marker: PhantomData<fn(Vec<u8>)>,
+}
+
+impl<S, H> Handler<Vec<u8>> for SchemaHandler<S, H>
+where
+    S: SchemaSource + 'static,
+    H: Handler<Tagged> + 'static,
+    H::Error: From<SchemaError>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, frame: Vec<u8>) -> Self::Future {
+        let registry = self.registry.clone();
+        let prev = self.prev.clone();
+
+        Box::pin(async move {
+            let (schema, payload) = registry.validate(&frame).await?;
+            let tagged = Tagged {
+                schema,
+                payload: payload.to_vec(),
+            };
+            prev.call(tagged).await
+        })
+    }
+}
+
+#[cfg(feature = "schema-registry")]
+pub use http::HttpSource;
+
+#[cfg(feature = "schema-registry")]
+mod http {
+    use futures::future::BoxFuture;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::{Schema, SchemaError, SchemaSource};
+
+    /// `SchemaSource` of REST API of schema registry (plain HTTP)
+    #[derive(Clone, Debug)]
+    pub struct HttpSource {
+        host: String,
+        port: u16,
+    }
+
+    impl HttpSource {
+        /// registry at `host`:`port`
+        pub fn new<S: Into<String>>(host: S, port: u16) -> Self {
+            Self {
+                host: host.into(),
+                port,
+            }
+        }
+    }
+
+    fn registry_error<E: ToString>(e: E) -> SchemaError {
+        SchemaError::Registry(e.to_string())
+    }
+
+    impl SchemaSource for HttpSource {
+        type Future = BoxFuture<'static, Result<Schema, SchemaError>>;
+
+        fn fetch(&self, id: u32) -> Self::Future {
+            let host = self.host.clone();
+            let port = self.port;
+
+            Box::pin(async move {
+                let mut stream = TcpStream::connect((host.as_str(), port))
+                    .await
+                    .map_err(registry_error)?;
+                let request = format!(
+                    "GET /schemas/ids/{id} HTTP/1.1\r\nHost: {host}\r\n\
+                     Accept: application/json\r\nConnection: close\r\n\r\n"
+                );
+                stream
+                    .write_all(request.as_bytes())
+                    .await
+                    .map_err(registry_error)?;
+
+                let mut response = Vec::new();
+                stream
+                    .read_to_end(&mut response)
+                    .await
+                    .map_err(registry_error)?;
+                let response = String::from_utf8(response).map_err(registry_error)?;
+                let (head, body) = response
+                    .split_once("\r\n\r\n")
+                    .ok_or_else(|| registry_error("malformed response"))?;
+
+                match head.split(' ').nth(1) {
+                    Some("200") => {}
+                    Some("404") => return Err(SchemaError::Unknown(id)),
+                    _ => return Err(registry_error(head.lines().next().unwrap_or(head))),
+                }
+
+                let json: serde_json::Value = serde_json::from_str(body).map_err(registry_error)?;
+                let definition = json["schema"]
+                    .as_str()
+                    .ok_or_else(|| registry_error("no schema in response"))?;
+                Ok(Schema {
+                    id,
+                    definition: definition.to_string(),
+                })
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use tokio::net::TcpListener;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn http_test() -> Result<(), Box<dyn std::error::Error>> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let port = listener.local_addr()?.port();
+            tokio::spawn(async move {
+                for _ in 0..2 {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    let len = stream.read(&mut buf).await.unwrap();
+                    let response = if buf[..len].starts_with(b"GET /schemas/ids/1 ") {
+                        "HTTP/1.1 200 OK\r\n\r\n{\"schema\": \"message A {}\"}"
+                    } else {
+                        "HTTP/1.1 404 Not Found\r\n\r\n{}"
+                    };
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+
+            let source = HttpSource::new("127.0.0.1", port);
+            assert_eq!(source.fetch(1).await?.definition, "message A {}");
+            assert_eq!(source.fetch(2).await, Err(SchemaError::Unknown(2)));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::{err, Ready};
+
+    use super::*;
+
+    #[test]
+    fn tag_test() {
+        let frame = tag(0x01020304, b"abc");
+        assert_eq!(frame, [0, 1, 2, 3, 4, b'a', b'b', b'c']);
+        assert_eq!(untag(&frame), Ok((0x01020304, &b"abc"[..])));
+        assert_eq!(untag(&[1, 0, 0, 0, 0]), Err(SchemaError::Untagged));
+        assert_eq!(untag(&[0, 0]), Err(SchemaError::Untagged));
+    }
+
+    struct Counting(AtomicUsize);
+
+    impl SchemaSource for Counting {
+        type Future = Ready<Result<Schema, SchemaError>>;
+
+        fn fetch(&self, id: u32) -> Self::Future {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            if id < 10 {
+                ok(Schema {
+                    id,
+                    definition: String::new(),
+                })
+            } else {
+                err(SchemaError::Unknown(id))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_test() {
+        let registry = SchemaRegistry::new(Counting(AtomicUsize::new(0)));
+        let frame = tag(1, b"x");
+        let (schema, payload) = registry.validate(&frame).await.unwrap();
+        assert_eq!((schema.id, payload), (1, &b"x"[..]));
+        registry.validate(&frame).await.unwrap();
+        assert_eq!(registry.source.0.load(Ordering::SeqCst), 1);
+
+        // unknown schemas are not cached
+        assert_eq!(
+            registry.validate(&tag(10, b"")).await,
+            Err(SchemaError::Unknown(10))
+        );
+        registry.validate(&tag(10, b"")).await.unwrap_err();
+        assert_eq!(registry.source.0.load(Ordering::SeqCst), 3);
+        assert_eq!(registry.cached(), 1);
+    }
+}
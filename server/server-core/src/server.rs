@@ -0,0 +1,1133 @@
+//! Server that binds the configured transports and runs a handler
+//!
+//! `Server` is the entry point of an application. It binds TCP on
+//! `Config::tcp_port` and QUIC on `Config::quic_port` (only when TLS is
+//! configured, because QUIC always needs it, and with the `quic` feature,
+//! which is on by default), spawns a task per
+//! connection, decodes frames and passes them to the handler as
+//! `Request`s. The output of the handler is written back like the
+//! listeners in `transport` do.
+//!
+//! Every connection starts with the handshake (see `handshake`), unless
+//! `Config::handshake` is off. The codec is negotiated from
+//! `Config::codecs`, the compression of frames from `Config::compressions`,
+//! and clients are checked with `VersionPolicy::from_config`. What is
+//! negotiated is in the context of every request, with the `ClientInfo` of
+//! the client. QUIC clients do the handshake on their first bidirectional
+//! stream.
+//!
+//! Each connection is registered in `Connections`, and its `Connection` is
+//! in the context of every request, so handlers can tag it. So is the
+//! `ClientCert` of a client that presented one (see `transport::mtls`). Messages pushed
+//! with `ServerHandle::broadcast_to` are written to the stream of a TCP
+//! connection, or to a unidirectional stream of a QUIC connection.
+//!
+//! With `Server::flags`, the feature flags of a `FlagStore` are pushed to
+//! every connection after the handshake and whenever they change (see
+//! `feature_flags`).
+//!
+//! With `Server::heartbeat`, every connection is pinged, and connections
+//! of dead peers are closed.
+//!
+//! With `Server::sessions`, `Sessions` is in the context too, and the
+//! session of a closed connection is detached, so that the client resumes
+//! it later (see `session`).
+//!
+//! `run` returns a `ServerHandle` as soon as the listeners are bound, and
+//! `ServerHandle::shutdown` closes every connection.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use bytes::Bytes;
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::connections::Connection;
+//! use cubby_connect_server_core::context::{extract, Missing};
+//! use cubby_connect_server_core::server::Server;
+//!
+//! async fn join(room: String, connection: Connection<Bytes>) -> Result<String, Missing> {
+//!     connection.tag(room.clone());
+//!     Ok(format!("joined {room}"))
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder().build().unwrap();
+//! let server = Server::from_config(config).handler(extract(join)).run().await?;
+//!
+//! // e.g. from an admin task
+//! server.broadcast_to("lobby", &String::from("welcome"));
+//!
+//! // when the application stops
+//! server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::pending;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use prost::Message;
+#[cfg(feature = "quic")]
+use quinn::Connection as QuicConnection;
+#[cfg(feature = "quic")]
+use tokio::io::{join, AsyncWriteExt};
+use tokio::io::{split, AsyncRead, AsyncWrite};
+#[cfg(feature = "quic")]
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+use crate::client_version::VersionPolicy;
+use crate::codec::compress::Compression;
+#[cfg(feature = "quic")]
+use crate::codec::compress::DeflateContext;
+use crate::codec::{Codecs, Protobuf};
+use crate::config::Config;
+use crate::connections::{Connection, Connections};
+use crate::context::{Context, Request};
+use crate::feature_flags::FlagStore;
+use crate::fn_handler::fn_handler;
+use crate::handler::Handler;
+use crate::handshake::{accept_versioned, HandshakeError};
+#[cfg(feature = "quic")]
+use crate::heartbeat;
+use crate::heartbeat::{Heartbeat, Pinger};
+use crate::session::Sessions;
+#[cfg(feature = "quic")]
+use crate::transport::mtls::ClientCert;
+#[cfg(feature = "quic")]
+use crate::transport::quic::{finish_bi, QuicListener};
+use crate::transport::tcp::TcpListener;
+#[cfg(feature = "quic")]
+use crate::transport::write_frame;
+use crate::transport::{serve_requests, FrameCompression, TransportError};
+
+/// builder of a server from `Config` and a handler of `Request<M>`
+pub struct Server<H = ()> {
+    config: Config,
+    handler: H,
+    connections: Connections<Bytes>,
+    heartbeat: Option<Heartbeat>,
+    sessions: Option<Sessions>,
+    flags: Option<FlagStore>,
+}
+
+impl Server {
+    /// returns a server of `config` without a handler
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            handler: (),
+            connections: Connections::new(),
+            heartbeat: None,
+            sessions: None,
+            flags: None,
+        }
+    }
+}
+
+impl<H> Server<H> {
+    /// sets the handler of every message
+    pub fn handler<H2>(self, handler: H2) -> Server<H2> {
+        Server {
+            config: self.config,
+            handler,
+            connections: self.connections,
+            heartbeat: self.heartbeat,
+            sessions: self.sessions,
+            flags: self.flags,
+        }
+    }
+
+    /// uses `connections` as the registry of connections, e.g. to share it
+    /// with other servers
+    pub fn connections(mut self, connections: Connections<Bytes>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// pings every connection with `heartbeat` (usually
+    /// `Heartbeat::from_config`), and closes connections of dead peers
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// puts `sessions` into the context of every request, and detaches
+    /// the session of a connection when it closes
+    pub fn sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// pushes the flags of `store` to every connection after the handshake,
+    /// and again whenever they change
+    pub fn flags(mut self, store: FlagStore) -> Self {
+        self.flags = Some(store);
+        self
+    }
+
+    /// binds the listeners and serves messages of type `M` in the
+    /// background. Returns after the listeners are bound.
+    pub async fn run<M>(self) -> Result<ServerHandle, TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<Request<M>> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(self.handler);
+        let handshake = match self.config.handshake {
+            true => Some(Arc::new(Handshake::<M>::from_config(&self.config)?)),
+            false => None,
+        };
+        let threshold = self.config.compression_threshold;
+        let takeover = self.config.context_takeover;
+        let tcp = Arc::new(TcpListener::bind(&self.config).await?);
+        #[cfg(feature = "quic")]
+        let quic = match QuicListener::bind(&self.config) {
+            Ok(quic) => Some(Arc::new(quic)),
+            Err(TransportError::NoTls) => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut tasks = Vec::new();
+        tasks.push(tokio::spawn({
+            let tcp = tcp.clone();
+            let handler = handler.clone();
+            let handshake = handshake.clone();
+            let connections = self.connections.clone();
+            let heartbeat = self.heartbeat.clone();
+            let sessions = self.sessions.clone();
+            let flags = self.flags.clone();
+            async move {
+                tcp.run_with(move |mut stream, peer, cert| {
+                    let handler = handler.clone();
+                    let handshake = handshake.clone();
+                    let sessions = sessions.clone();
+                    let flags = flags.clone();
+                    let (registration, mut pushed) = connections.register();
+                    let pinger = ping(heartbeat.as_ref(), &connections, registration.connection());
+                    async move {
+                        let mut context = Context::new();
+                        context.insert(registration.connection());
+                        if let Some(cert) = cert {
+                            context.insert(cert);
+                        }
+                        if let Some(sessions) = &sessions {
+                            context.insert(sessions.clone());
+                        }
+                        if let Some(handshake) = &handshake {
+                            handshake.accept(&mut stream, peer, &mut context).await?;
+                        }
+
+                        let handler = handler.as_ref();
+                        let compression =
+                            FrameCompression::from_context(&context, threshold, takeover);
+                        let (mut reader, mut writer) = split(stream);
+                        let (pushed, pinger) = (Some(&mut pushed), pinger.as_ref());
+                        let serve = serve_requests(
+                            handler,
+                            &context,
+                            &mut reader,
+                            &mut writer,
+                            &compression,
+                            pushed,
+                            pinger,
+                        );
+                        let flags = push_flags(flags.as_ref(), registration.connection());
+                        let served = tokio::select! {
+                            served = serve => served,
+                            _ = flags => unreachable!(),
+                        };
+                        detach(sessions.as_ref(), &registration.connection()).await;
+                        served
+                    }
+                })
+                .await
+            }
+        }));
+
+        #[cfg(feature = "quic")]
+        if let Some(quic) = &quic {
+            tasks.push(tokio::spawn({
+                let quic = quic.clone();
+                let connections = self.connections.clone();
+                let heartbeat = self.heartbeat.clone();
+                let sessions = self.sessions.clone();
+                let flags = self.flags.clone();
+                async move {
+                    quic.run_with(move |connection| {
+                        let handler = handler.clone();
+                        let handshake = handshake.clone();
+                        let sessions = sessions.clone();
+                        let flags = flags.clone();
+                        let (registration, pushed) = connections.register();
+                        let pinger =
+                            ping(heartbeat.as_ref(), &connections, registration.connection());
+                        async move {
+                            let mut context = Context::new();
+                            context.insert(registration.connection());
+                            if let Some(cert) = ClientCert::from_quic(&connection) {
+                                context.insert(cert);
+                            }
+                            if let Some(sessions) = &sessions {
+                                context.insert(sessions.clone());
+                            }
+                            if let Some(handshake) = &handshake {
+                                if accept_quic(handshake, &connection, &mut context)
+                                    .await
+                                    .is_err()
+                                {
+                                    // the peer closes after reading why
+                                    connection.closed().await;
+                                    return;
+                                }
+                            }
+
+                            // pushes end when the connection is closed
+                            let compression =
+                                FrameCompression::from_context(&context, threshold, takeover);
+                            let serve = serve_quic(
+                                handler,
+                                context,
+                                &connection,
+                                threshold,
+                                takeover,
+                                pinger.clone(),
+                            );
+                            tokio::select! {
+                                _ = serve => {}
+                                _ = push_quic(&connection, &compression, pushed) => {}
+                                _ = push_flags(flags.as_ref(), registration.connection()) => {}
+                                _ = heartbeat::dead(pinger.as_ref()) => {
+                                    connection.close(0u32.into(), b"peer missed heartbeats");
+                                }
+                            }
+                            detach(sessions.as_ref(), &registration.connection()).await;
+                        }
+                    })
+                    .await
+                }
+            }));
+        }
+
+        Ok(ServerHandle {
+            tcp_addr: tcp.local_addr()?,
+            #[cfg(feature = "quic")]
+            quic_addr: quic.as_ref().map(|quic| quic.local_addr()).transpose()?,
+            #[cfg(not(feature = "quic"))]
+            quic_addr: None,
+            tcp,
+            #[cfg(feature = "quic")]
+            quic,
+            connections: self.connections,
+            tasks,
+        })
+    }
+}
+
+/// starts pinging `connection` through pushes, if `heartbeat` is set
+fn ping(
+    heartbeat: Option<&Heartbeat>,
+    connections: &Connections<Bytes>,
+    connection: Connection<Bytes>,
+) -> Option<Pinger> {
+    let connections = connections.clone();
+    let id = connection.id();
+    let push = fn_handler(move |frame: Bytes| {
+        let pushed = connections.send_to(id, frame);
+        async move { pushed.then_some(()).ok_or(()) }
+    });
+    heartbeat.map(|heartbeat| heartbeat.spawn(id, push))
+}
+
+/// pushes the flags of `store` to `connection` right away and whenever
+/// they change, if `store` is set. It never returns, so that it can be
+/// selected with serving the connection.
+async fn push_flags(store: Option<&FlagStore>, connection: Connection<Bytes>) {
+    if let Some(store) = store {
+        let mut watch = store.watch();
+        while let Some(flags) = watch.changed().await {
+            connection.send(Bytes::from(flags.to_envelope().encode()));
+        }
+    }
+    pending().await
+}
+
+/// detaches the session of a closed connection, if `sessions` is set.
+/// The session is lost if it cannot be saved.
+async fn detach(sessions: Option<&Sessions>, connection: &Connection<Bytes>) {
+    if let Some(sessions) = sessions {
+        let _ = sessions.detach(connection).await;
+    }
+}
+
+/// what the handshake of every connection negotiates and checks, from
+/// `Config`
+struct Handshake<M> {
+    codecs: Codecs<M>,
+    compressions: Vec<Compression>,
+    policy: VersionPolicy,
+    timeout: Duration,
+}
+
+impl<M: Message + Default + 'static> Handshake<M> {
+    fn from_config(config: &Config) -> io::Result<Self> {
+        let policy = VersionPolicy::from_config(config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            codecs: Codecs::new().with(Protobuf).enabled(&config.codecs),
+            compressions: config
+                .compressions
+                .iter()
+                .filter_map(|name| Compression::from_name(name))
+                .collect(),
+            policy,
+            timeout: config.handshake_timeout,
+        })
+    }
+
+    /// does the handshake with the client at `peer`, and puts what is
+    /// negotiated into `context`. Clients are rolled out by their IP address.
+    /// Fails if the client takes longer than `Config::handshake_timeout`
+    async fn accept<S>(
+        &self,
+        stream: &mut S,
+        peer: SocketAddr,
+        context: &mut Context,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (codecs, compressions) = (&self.codecs, &self.compressions);
+        let ip = peer.ip();
+        let accepted = accept_versioned(stream, codecs, compressions, &self.policy, &ip);
+        let accepted = tokio::time::timeout(self.timeout, accepted)
+            .await
+            .map_err(|_| handshake_timed_out())?
+            .map_err(|e| match e {
+                HandshakeError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e),
+            })?;
+        accepted.insert_into(context);
+        Ok(())
+    }
+}
+
+fn handshake_timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")
+}
+
+/// does the handshake of `connection` on its first bidirectional stream,
+/// which is finished after it
+#[cfg(feature = "quic")]
+async fn accept_quic<M>(
+    handshake: &Handshake<M>,
+    connection: &QuicConnection,
+    context: &mut Context,
+) -> io::Result<()>
+where
+    M: Message + Default + 'static,
+{
+    let (send, recv) = tokio::time::timeout(handshake.timeout, connection.accept_bi())
+        .await
+        .map_err(|_| handshake_timed_out())??;
+    let mut stream = join(recv, send);
+    let accepted = handshake
+        .accept(&mut stream, connection.remote_address(), context)
+        .await;
+    let (recv, send) = stream.into_inner();
+    finish_bi(send, recv, Ok(()));
+    accepted
+}
+
+/// serves every bidirectional stream of `connection` with `handler`
+#[cfg(feature = "quic")]
+async fn serve_quic<M, H>(
+    handler: Arc<H>,
+    context: Context,
+    connection: &QuicConnection,
+    threshold: usize,
+    takeover: bool,
+    pinger: Option<Pinger>,
+) where
+    M: Message + Default + 'static,
+    H: Handler<Request<M>> + Send + Sync + 'static,
+    H::Output: Message,
+    H::Future: Send,
+{
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        let handler = handler.clone();
+        let context = stream_context(&context);
+        let pinger = pinger.clone();
+        tokio::spawn(async move {
+            let handler = handler.as_ref();
+            let pinger = pinger.as_ref();
+            let compression = FrameCompression::from_context(&context, threshold, takeover);
+            let (reader, writer) = (&mut recv, &mut send);
+            let served = serve_requests(
+                handler,
+                &context,
+                reader,
+                writer,
+                &compression,
+                None,
+                pinger,
+            )
+            .await;
+            finish_bi(send, recv, served);
+        });
+    }
+}
+
+/// returns the context of a stream of a QUIC connection. Streams are not
+/// ordered with each other, so each one gets its own `DeflateContext` when
+/// the connection negotiated deflate.
+#[cfg(feature = "quic")]
+fn stream_context(context: &Context) -> Context {
+    let mut context = context.clone();
+    if context.contains::<DeflateContext>() {
+        context.insert(DeflateContext::new());
+    }
+    context
+}
+
+/// writes pushed messages to a unidirectional stream of `connection`,
+/// which is opened at the first message
+#[cfg(feature = "quic")]
+async fn push_quic(
+    connection: &QuicConnection,
+    compression: &FrameCompression,
+    mut pushed: UnboundedReceiver<Bytes>,
+) -> io::Result<()> {
+    let mut stream = None;
+    while let Some(push) = pushed.recv().await {
+        let send = match &mut stream {
+            Some(send) => send,
+            None => stream.insert(connection.open_uni().await?),
+        };
+        write_frame(send, &compression.compress(&push)?).await?;
+        send.flush().await?;
+    }
+    Ok(())
+}
+
+/// handle of a running server
+pub struct ServerHandle {
+    tcp_addr: SocketAddr,
+    quic_addr: Option<SocketAddr>,
+    tcp: Arc<TcpListener>,
+    #[cfg(feature = "quic")]
+    quic: Option<Arc<QuicListener>>,
+    connections: Connections<Bytes>,
+    tasks: Vec<JoinHandle<Result<(), TransportError>>>,
+}
+
+impl ServerHandle {
+    /// returns the address of the TCP listener
+    pub fn tcp_addr(&self) -> SocketAddr {
+        self.tcp_addr
+    }
+
+    /// returns the address of the QUIC listener.
+    /// Returns `None` if QUIC is not served because TLS is not configured,
+    /// or the `quic` feature is off.
+    pub fn quic_addr(&self) -> Option<SocketAddr> {
+        self.quic_addr
+    }
+
+    /// returns the connections of the server
+    pub fn connections(&self) -> &Connections<Bytes> {
+        &self.connections
+    }
+
+    /// pushes `msg` to every connection with `tag`, and returns to how many
+    pub fn broadcast_to<M: Message>(&self, tag: &str, msg: &M) -> usize {
+        self.connections
+            .broadcast_to(tag, Bytes::from(msg.encode_to_vec()))
+    }
+
+    /// pushes `msg` to every connection, and returns to how many
+    pub fn broadcast<M: Message>(&self, msg: &M) -> usize {
+        self.connections.broadcast(Bytes::from(msg.encode_to_vec()))
+    }
+
+    /// closes the listeners with every connection, and waits until they
+    /// stop. Returns the first error of the listeners.
+    pub async fn shutdown(self) -> Result<(), TransportError> {
+        self.tcp.close();
+        #[cfg(feature = "quic")]
+        if let Some(quic) = &self.quic {
+            quic.close();
+        }
+
+        let mut result = Ok(());
+        for task in self.tasks {
+            let stopped = match task.await {
+                Ok(stopped) => stopped,
+                Err(e) => Err(TransportError::Io(io::Error::other(e))),
+            };
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "quic")]
+    use quinn::crypto::rustls::QuicClientConfig;
+    #[cfg(feature = "quic")]
+    use quinn::Endpoint;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::auth::Identity;
+    use crate::client_version::Blocked;
+    use crate::codec::compress::{DeflateContext, FLAG_DEFLATE_TAKEOVER};
+    use crate::config::ClientAuth;
+    use crate::context::{extract, Missing};
+    use crate::envelope::Envelope;
+    use crate::error::CubbyError;
+    use crate::feature_flags::{Flags, FLAGS_TYPE};
+    use crate::fn_handler::fn_handler;
+    use crate::handshake::{self, connect_as, ClientInfo};
+    use crate::heartbeat::{self, Liveness};
+    use crate::session::{MemorySessionStore, SessionToken};
+    use crate::transport::mtls::ClientCert;
+    #[cfg(feature = "quic")]
+    use crate::transport::ALPN;
+    use crate::transport::{read_frame, write_frame};
+
+    use super::*;
+
+    async fn join(room: String, connection: Connection<Bytes>) -> Result<String, Missing> {
+        connection.tag(room.clone());
+        Ok(format!("joined {room}"))
+    }
+
+    /// connects to `addr` and does the handshake
+    async fn open(addr: SocketAddr) -> Result<TcpStream, HandshakeError> {
+        let mut stream = TcpStream::connect(addr).await?;
+        handshake::connect(&mut stream, &["protobuf"]).await?;
+        Ok(stream)
+    }
+
+    async fn read_string(stream: &mut TcpStream) -> io::Result<Option<String>> {
+        let frame = read_frame(stream).await?;
+        Ok(frame.map(|frame| String::decode(frame.as_slice()).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn server_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+        assert_eq!(server.quic_addr(), None);
+
+        let mut lobby = open(server.tcp_addr()).await?;
+        let mut game = open(server.tcp_addr()).await?;
+        for (stream, room) in [(&mut lobby, "lobby"), (&mut game, "game")] {
+            write_frame(stream, &String::from(room).encode_to_vec()).await?;
+            stream.flush().await?;
+            assert_eq!(read_string(stream).await?, Some(format!("joined {room}")));
+        }
+        assert_eq!(server.connections().len(), 2);
+
+        assert_eq!(server.broadcast_to("lobby", &String::from("hello")), 1);
+        assert_eq!(server.broadcast(&String::from("bye")), 2);
+        assert_eq!(read_string(&mut lobby).await?, Some(String::from("hello")));
+        assert_eq!(read_string(&mut lobby).await?, Some(String::from("bye")));
+        assert_eq!(read_string(&mut game).await?, Some(String::from("bye")));
+
+        server.shutdown().await?;
+        assert_eq!(read_string(&mut lobby).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn heartbeat_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .heartbeat_interval(Duration::from_millis(20))
+            .heartbeat_timeout(Duration::from_millis(20))
+            .heartbeat_misses(2)
+            .build()?;
+        let dead = Arc::new(Mutex::new(Vec::new()));
+        let heartbeat = Heartbeat::from_config(&config).on_change({
+            let dead = dead.clone();
+            move |id, liveness| {
+                if liveness == Liveness::Dead {
+                    dead.lock().unwrap().push(id);
+                }
+            }
+        });
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .heartbeat(heartbeat)
+            .run()
+            .await?;
+
+        let mut stream = open(server.tcp_addr()).await?;
+        for _ in 0..3 {
+            let ping = read_frame(&mut stream).await?.unwrap();
+            write_frame(&mut stream, &heartbeat::answer(&ping).unwrap()).await?;
+            stream.flush().await?;
+        }
+        write_frame(&mut stream, &String::from("lobby").encode_to_vec()).await?;
+        stream.flush().await?;
+        loop {
+            // pings may come before the answer
+            let frame = read_frame(&mut stream).await?.unwrap();
+            if heartbeat::answer(&frame).is_none() {
+                assert_eq!(String::decode(frame.as_slice())?, "joined lobby");
+                break;
+            }
+        }
+        assert!(dead.lock().unwrap().is_empty());
+
+        // closed after missing pongs
+        while read_frame(&mut stream).await?.is_some() {}
+        assert_eq!(dead.lock().unwrap().len(), 1);
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_test() -> Result<(), Box<dyn std::error::Error>> {
+        async fn login(
+            token: String,
+            sessions: Sessions,
+            connection: Connection<Bytes>,
+        ) -> Result<String, CubbyError> {
+            if !token.is_empty() {
+                sessions
+                    .resume(&SessionToken::from(token.clone()), &connection)
+                    .await?;
+                return Ok(token);
+            }
+            connection.tag("lobby");
+            let identity = Identity {
+                subject: String::from("alice"),
+                roles: Vec::new(),
+            };
+            Ok(sessions.start(identity, &connection).await?.to_string())
+        }
+
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let sessions = Sessions::from_config(MemorySessionStore::new(), &config);
+        let server = Server::from_config(config)
+            .sessions(sessions.clone())
+            .handler(extract(login))
+            .run()
+            .await?;
+
+        let mut stream = open(server.tcp_addr()).await?;
+        write_frame(&mut stream, &String::new().encode_to_vec()).await?;
+        stream.flush().await?;
+        let token = read_string(&mut stream).await?.unwrap();
+
+        // detached before the connection is unregistered
+        drop(stream);
+        while !server.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let token = SessionToken::from(token);
+        sessions
+            .send(&token, Bytes::from(String::from("missed").encode_to_vec()))
+            .await?;
+
+        let mut stream = open(server.tcp_addr()).await?;
+        write_frame(&mut stream, &token.to_string().encode_to_vec()).await?;
+        stream.flush().await?;
+        // the pending message may come before the answer
+        let mut frames = vec![
+            read_string(&mut stream).await?.unwrap(),
+            read_string(&mut stream).await?.unwrap(),
+        ];
+        frames.sort();
+        assert_eq!(frames, [token.to_string(), String::from("missed")]);
+        assert_eq!(server.connections().tagged("lobby"), 1);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_test() -> Result<(), Box<dyn std::error::Error>> {
+        async fn hello(
+            _: String,
+            info: ClientInfo,
+            compression: Compression,
+        ) -> Result<String, Missing> {
+            Ok(format!(
+                "{} {}",
+                info.app_version.unwrap_or_default(),
+                compression.name()
+            ))
+        }
+
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .compressions(vec![String::from("lz4")])
+            .compression_threshold(0)
+            .min_client_version("0.1")
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(hello))
+            .run()
+            .await?;
+
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        let info = ClientInfo::current().app_version("2.3.1");
+        let compressions = [Compression::Zstd, Compression::Lz4];
+        let negotiated =
+            connect_as(&mut stream, &["json", "protobuf"], &compressions, &info).await?;
+        assert_eq!(negotiated, (String::from("protobuf"), Compression::Lz4));
+
+        // frames are compressed both ways
+        let frame = String::from("hello").encode_to_vec();
+        write_frame(&mut stream, &Compression::Lz4.compress(&frame, 0)?).await?;
+        stream.flush().await?;
+        let frame = Compression::decompress(&read_frame(&mut stream).await?.unwrap())?;
+        assert_eq!(String::decode(frame.as_slice())?, "2.3.1 lz4");
+
+        // codecs that are not enabled are not negotiated
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        assert!(matches!(
+            connect_as(&mut stream, &["json"], &[], &info).await,
+            Err(HandshakeError::NoCommonCodec(_))
+        ));
+        assert_eq!(read_frame(&mut stream).await?, None);
+        server.shutdown().await?;
+
+        // clients below the minimum version are blocked
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .min_client_version("99.0")
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        assert!(matches!(
+            connect_as(&mut stream, &["protobuf"], &[], &ClientInfo::current()).await,
+            Err(HandshakeError::Blocked(Blocked::TooOld))
+        ));
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn context_takeover_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .compressions(vec![String::from("deflate")])
+            .compression_threshold(0)
+            .context_takeover(true)
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        let info = ClientInfo::current();
+        connect_as(&mut stream, &["protobuf"], &[Compression::Deflate], &info).await?;
+
+        // responses and pushes continue the deflate stream of the connection
+        let deflate = DeflateContext::new();
+        let frame = String::from("lobby").encode_to_vec();
+        write_frame(&mut stream, &deflate.compress(&frame, 0, true)?).await?;
+        stream.flush().await?;
+        let frame = read_frame(&mut stream).await?.unwrap();
+        assert_eq!(frame[0], FLAG_DEFLATE_TAKEOVER);
+        let frame = deflate.decompress(&frame)?;
+        assert_eq!(String::decode(frame.as_slice())?, "joined lobby");
+
+        server.broadcast_to("lobby", &"x".repeat(100));
+        let frame = read_frame(&mut stream).await?.unwrap();
+        assert_eq!(frame[0], FLAG_DEFLATE_TAKEOVER);
+        let frame = deflate.decompress(&frame)?;
+        assert_eq!(String::decode(frame.as_slice())?, "x".repeat(100));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .handshake_timeout(Duration::from_millis(100))
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+
+        // closed without a ClientHello
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        let closed = tokio::time::timeout(Duration::from_secs(5), read_frame(&mut stream));
+        assert_eq!(closed.await??, None);
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_handler_test() -> Result<(), Box<dyn std::error::Error>> {
+        // "wait" is handled after "go" arrives
+        let go = Arc::new(tokio::sync::Notify::new());
+        let handler = fn_handler({
+            let go = go.clone();
+            move |req: Request<String>| {
+                let go = go.clone();
+                async move {
+                    match req.msg.as_str() {
+                        "wait" => go.notified().await,
+                        _ => go.notify_one(),
+                    }
+                    Ok::<_, Missing>(format!("done {}", req.msg))
+                }
+            }
+        });
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let server = Server::from_config(config).handler(handler).run().await?;
+
+        // pushed while a handler is running
+        let mut stream = open(server.tcp_addr()).await?;
+        write_frame(&mut stream, &String::from("wait").encode_to_vec()).await?;
+        stream.flush().await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.broadcast(&String::from("pushed")), 1);
+        assert_eq!(
+            read_string(&mut stream).await?,
+            Some(String::from("pushed"))
+        );
+
+        // outputs are in the order of requests
+        write_frame(&mut stream, &String::from("go").encode_to_vec()).await?;
+        stream.flush().await?;
+        assert_eq!(
+            read_string(&mut stream).await?,
+            Some(String::from("done wait"))
+        );
+        assert_eq!(
+            read_string(&mut stream).await?,
+            Some(String::from("done go"))
+        );
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flags_test() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config::builder().host((127, 0, 0, 1)).tcp_port(0).build()?;
+        let store = FlagStore::with_flags([("new_inventory", "true")].into_iter().collect());
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .flags(store.clone())
+            .run()
+            .await?;
+
+        async fn read_flags(stream: &mut TcpStream) -> Result<Flags, Box<dyn std::error::Error>> {
+            let frame = read_frame(stream).await?.ok_or("closed")?;
+            let env = Envelope::decode(&frame)?;
+            if env.headers.message_type.as_deref() != Some(FLAGS_TYPE) {
+                return Err("not flags".into());
+            }
+            let msg = crate::protobuf::feature_flags::Flags::decode(env.msg.as_slice())?;
+            Ok(msg.flags.into_iter().collect())
+        }
+
+        // the flags come right after the handshake, and again on changes
+        let mut stream = open(server.tcp_addr()).await?;
+        assert!(read_flags(&mut stream).await?.is_enabled("new_inventory"));
+        store.set("max_party", "8");
+        let flags = read_flags(&mut stream).await?;
+        assert_eq!(flags.get("max_party"), Some("8"));
+        assert_eq!(flags.len(), 2);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "quic")]
+    #[test]
+    fn stream_context_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut context = Context::new();
+        assert!(!stream_context(&context).contains::<DeflateContext>());
+
+        context.insert(DeflateContext::new());
+        let (first, second) = (stream_context(&context), stream_context(&context));
+        let data = b"position of player 7 ".repeat(10);
+        let deflate = |context: &Context| context.get::<DeflateContext>().unwrap().clone();
+        let frame = deflate(&first).compress(&data, 16, true)?;
+
+        // the second stream does not continue the deflate stream of the first
+        assert_eq!(deflate(&second).compress(&data, 16, true)?, frame);
+        assert!(deflate(&first).compress(&data, 16, true)?.len() < frame.len());
+        Ok(())
+    }
+
+    #[cfg(feature = "quic")]
+    #[tokio::test]
+    async fn quic_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("cubby-server-cert-{}.pem", std::process::id()));
+        let key_path = dir.join(format!("cubby-server-key-{}.pem", std::process::id()));
+        std::fs::write(&cert_path, cert.cert.pem())?;
+        std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .quic_port(0)
+            .cert_path(&cert_path)
+            .key_path(&key_path)
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(join))
+            .run()
+            .await?;
+        std::fs::remove_file(cert_path)?;
+        std::fs::remove_file(key_path)?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(cert.cert))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse()?)?;
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls)?,
+        )));
+        let connection = client
+            .connect(server.quic_addr().unwrap(), "localhost")?
+            .await?;
+
+        // the handshake is done on the first stream
+        let (send, recv) = connection.open_bi().await?;
+        handshake::connect(&mut tokio::io::join(recv, send), &["protobuf"]).await?;
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        write_frame(&mut send, &String::from("lobby").encode_to_vec()).await?;
+        send.finish()?;
+        let frame = read_frame(&mut recv).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice())?, "joined lobby");
+
+        // pushes come in a unidirectional stream
+        assert_eq!(server.broadcast_to("lobby", &String::from("hello")), 1);
+        let mut pushed = connection.accept_uni().await?;
+        let frame = read_frame(&mut pushed).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice())?, "hello");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_cert_test() -> Result<(), Box<dyn std::error::Error>> {
+        async fn whoami(_: String, cert: ClientCert) -> Result<String, Missing> {
+            Ok(cert.subject)
+        }
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let ca_key = KeyPair::generate()?;
+        let mut ca = CertificateParams::new(Vec::new())?;
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca.self_signed(&ca_key)?;
+        let client_key = KeyPair::generate()?;
+        let mut client = CertificateParams::new(Vec::new())?;
+        client.distinguished_name.push(DnType::CommonName, "alice");
+        client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = client.signed_by(&client_key, &ca, &ca_key)?;
+
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("cubby-mtls-cert-{}.pem", std::process::id()));
+        let key_path = dir.join(format!("cubby-mtls-key-{}.pem", std::process::id()));
+        let ca_path = dir.join(format!("cubby-mtls-ca-{}.pem", std::process::id()));
+        std::fs::write(&cert_path, cert.cert.pem())?;
+        std::fs::write(&key_path, cert.key_pair.serialize_pem())?;
+        std::fs::write(&ca_path, ca.pem())?;
+
+        let config = Config::builder()
+            .host((127, 0, 0, 1))
+            .tcp_port(0)
+            .quic_port(0)
+            .cert_path(&cert_path)
+            .key_path(&key_path)
+            .client_ca_path(&ca_path)
+            .client_auth(ClientAuth::Optional)
+            .build()?;
+        let server = Server::from_config(config)
+            .handler(extract(whoami))
+            .run()
+            .await?;
+        for path in [cert_path, key_path, ca_path] {
+            std::fs::remove_file(path)?;
+        }
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(cert.cert))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots);
+        let key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+        let configs = [
+            builder
+                .clone()
+                .with_client_auth_cert(vec![client.der().clone()], key.into())?,
+            builder.with_no_client_auth(),
+        ];
+
+        let mut answers = Vec::new();
+        for config in configs {
+            let mut stream = TlsConnector::from(Arc::new(config))
+                .connect(
+                    ServerName::try_from("localhost")?,
+                    TcpStream::connect(server.tcp_addr()).await?,
+                )
+                .await?;
+            handshake::connect(&mut stream, &["protobuf"]).await?;
+            write_frame(&mut stream, &String::from("who").encode_to_vec()).await?;
+            stream.flush().await?;
+            let frame = read_frame(&mut stream).await.ok().flatten();
+            answers.push(frame.map(|frame| String::decode(frame.as_slice()).unwrap()));
+        }
+        // without a certificate, `whoami` fails and the connection is closed
+        assert_eq!(answers, [Some("CN=alice".to_string()), None]);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! Integration with service managers (needs the `service` feature)
+//!
+//! Init systems expect a long running server to tell its state and to leave
+//! some traces on the system:
+//!
+//! - `PidFile`: file with the process id, removed when the server stops
+//! - `systemd`: `sd_notify` of readiness and watchdog keep-alives (Unix)
+//! - `windows`: handling of service control requests (Windows)
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::service::{systemd, PidFile};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let _pid = PidFile::create("/run/cubby-connect.pid")?;
+//!
+//! // ... bind listeners ...
+//!
+//! systemd::ready()?;
+//! tokio::spawn(systemd::run_watchdog());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(windows)]
+pub mod windows;
+
+/// file that contains the process id while the server runs.
+/// The file is removed when this is dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// writes the id of this process to `path`.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` when the file belongs to a
+    /// process that is still running. A file left by a dead process is
+    /// replaced.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if let Ok(old) = std::fs::read_to_string(&path) {
+            match old.trim().parse::<u32>() {
+                Ok(pid) if is_running(pid) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} belongs to running process {pid}", path.display()),
+                    ));
+                }
+                _ => std::fs::remove_file(&path)?,
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path })
+    }
+
+    /// returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// returns true if the process of `pid` is running.
+/// Any process is assumed to be running where it cannot be known.
+fn is_running(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    if cfg!(target_os = "linux") {
+        Path::new("/proc").join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pid_file_test() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("cubby-pid-{}", std::process::id()));
+
+        let pid = PidFile::create(&path)?;
+        let content = std::fs::read_to_string(pid.path())?;
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        let e = PidFile::create(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+
+        drop(pid);
+        assert!(!path.exists());
+
+        // stale file is replaced
+        std::fs::write(&path, "not a pid")?;
+        let pid = PidFile::create(&path)?;
+        assert!(pid.path().exists());
+        Ok(())
+    }
+}
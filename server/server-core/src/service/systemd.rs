@@ -0,0 +1,101 @@
+//! `sd_notify` protocol of systemd
+//!
+//! systemd passes the path of a datagram socket as `NOTIFY_SOCKET` to
+//! services of `Type=notify`. Every function here does nothing and returns
+//! `Ok(false)` when the server is not started by systemd.
+//!
+//! With `WatchdogSec=` in the unit, systemd restarts the server when the
+//! keep-alives stop. `run_watchdog` sends them at half of the interval.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// sends `state` to the socket of `NOTIFY_SOCKET`.
+/// Returns false if there is no socket.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => send(&socket.to_string_lossy(), state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// tells that the server is ready to accept connections
+pub fn ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+/// tells that the server began to shut down
+pub fn stopping() -> io::Result<bool> {
+    notify("STOPPING=1")
+}
+
+/// tells a free-form status shown by `systemctl status`
+pub fn status(status: &str) -> io::Result<bool> {
+    notify(&format!("STATUS={status}"))
+}
+
+/// sends a keep-alive to the watchdog of systemd
+pub fn watchdog() -> io::Result<bool> {
+    notify("WATCHDOG=1")
+}
+
+/// returns the interval of keep-alives, which is half of `WATCHDOG_USEC`.
+/// Returns `None` if the watchdog is not enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID") {
+        if pid.to_string_lossy() != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// sends keep-alives forever while the runtime is responsive.
+/// Returns immediately if the watchdog is not enabled.
+pub async fn run_watchdog() -> io::Result<()> {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return Ok(()),
+    };
+    loop {
+        watchdog()?;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    let sock = UnixDatagram::unbound()?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        sock.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    sock.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn send_test() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("cubby-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path)?;
+
+        send(&path.to_string_lossy(), "READY=1")?;
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf)?;
+        assert_eq!(&buf[..len], b"READY=1");
+
+        std::fs::remove_file(&path)
+    }
+}
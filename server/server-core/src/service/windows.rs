@@ -0,0 +1,74 @@
+//! Windows service control
+//!
+//! A Windows service is started by the service control manager, which
+//! calls back into the process on its own thread. `run` connects the
+//! manager to the main function of the server: the server is reported
+//! as running while `main` runs, and a stop or shutdown request resolves
+//! the `oneshot::Receiver` given to `main`.
+
+use std::ffi::OsString;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// main function of the server, which returns when the server stopped
+pub type ServiceMain = fn(oneshot::Receiver<()>);
+
+static SERVICE: OnceLock<(&'static str, ServiceMain)> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// runs `main` as the Windows service of `name`.
+/// This blocks until the service stops, and fails when the process is not
+/// started by the service control manager.
+pub fn run(name: &'static str, main: ServiceMain) -> windows_service::Result<()> {
+    let _ = SERVICE.set((name, main));
+    service_dispatcher::start(name, ffi_service_main)
+}
+
+fn service_main(_: Vec<OsString>) {
+    if let Some(&(name, main)) = SERVICE.get() {
+        let _ = run_service(name, main);
+    }
+}
+
+fn status(state: ServiceState, controls: ServiceControlAccept) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: controls,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+fn run_service(name: &str, main: ServiceMain) -> windows_service::Result<()> {
+    let (tx, rx) = oneshot::channel();
+    let tx = Mutex::new(Some(tx));
+
+    let handle = service_control_handler::register(name, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+
+    handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    ))?;
+    main(rx);
+    handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))
+}
@@ -0,0 +1,731 @@
+//! Sessions that clients resume on a new connection
+//!
+//! Connections of mobile clients are lost whenever they change networks,
+//! and authenticating and subscribing again on every reconnection is slow.
+//! `Sessions` keeps what a client had beyond its connection:
+//!
+//! - the `Identity` verified at the first auth
+//! - subscriptions, which are the tags of the connection (see `connections`)
+//! - messages sent with `Sessions::send` while the client is away
+//!
+//! After the first auth, `Sessions::start` issues a `SessionToken`, which
+//! the application hands to the client. A client that reconnects (e.g. on a
+//! new QUIC connection) presents the token, and `Sessions::resume` puts the
+//! session onto the new connection: it is tagged again, and pending
+//! messages are pushed to it. A session does not expire while its client is
+//! connected. When a connection closes, `Server` detaches its session (see
+//! `Server::sessions`), which is kept for `Config::session_ttl` from then.
+//!
+//! Sessions are kept in a `SessionStore`. `MemorySessionStore` keeps them in
+//! memory; other stores (e.g. a database shared by servers, so that a client
+//! can resume on another server) implement the trait. Expired sessions are
+//! evicted from the store whenever a session starts.
+//!
+//! Resuming, detaching, ending and sending to a session load it from the
+//! store, change it and save it back. `Sessions` locks the token meanwhile,
+//! so changes to the same session (e.g. messages sent at once) are made one
+//! after another instead of overwriting each other. The lock is held by
+//! `Sessions` and its clones, so servers sharing a store are not locked
+//! with each other.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use bytes::Bytes;
+//! use cubby_connect_server_core::auth::Identity;
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::connections::Connection;
+//! use cubby_connect_server_core::context::extract;
+//! use cubby_connect_server_core::error::CubbyError;
+//! use cubby_connect_server_core::server::Server;
+//! use cubby_connect_server_core::session::{MemorySessionStore, SessionToken, Sessions};
+//!
+//! // the message is the token of the session, or empty at the first login
+//! async fn login(
+//!     token: String,
+//!     sessions: Sessions,
+//!     connection: Connection<Bytes>,
+//! ) -> Result<String, CubbyError> {
+//!     if !token.is_empty() {
+//!         sessions.resume(&SessionToken::from(token.clone()), &connection).await?;
+//!         return Ok(token);
+//!     }
+//!
+//!     let identity = Identity { subject: String::from("alice"), roles: Vec::new() };
+//!     let token = sessions.start(identity, &connection).await?;
+//!     Ok(token.to_string())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder().build().unwrap();
+//! let sessions = Sessions::from_config(MemorySessionStore::new(), &config);
+//! let server = Server::from_config(config)
+//!     .sessions(sessions)
+//!     .handler(extract(login))
+//!     .run()
+//!     .await?;
+//! # server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::future::{ready, BoxFuture};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::auth::Identity;
+use crate::config::Config;
+use crate::connections::{Connection, ConnectionId};
+use crate::context::{Context, FromContext};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// length of a token in random bytes
+const TOKEN_BYTES: usize = 32;
+
+/// error of sessions
+#[derive(Debug)]
+pub enum SessionError {
+    /// the token is not issued, or its session is expired or ended
+    Unknown,
+
+    /// no random source to make a token
+    Random,
+
+    /// error of the `SessionStore`
+    Store(BoxError),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Unknown => write!(f, "unknown or expired session"),
+            SessionError::Random => write!(f, "no random source for session tokens"),
+            SessionError::Store(e) => write!(f, "session store error: {e}"),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+/// secret that a client presents to resume its session
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SessionToken(String);
+
+impl SessionToken {
+    /// returns a random token.
+    /// Fails if the random source of the OS is not available.
+    pub fn generate() -> Result<Self, SessionError> {
+        let mut bytes = [0; TOKEN_BYTES];
+        rustls::crypto::ring::default_provider()
+            .secure_random
+            .fill(&mut bytes)
+            .map_err(|_| SessionError::Random)?;
+        Ok(Self(bytes.iter().map(|b| format!("{b:02x}")).collect()))
+    }
+
+    /// returns the token as it is handed to the client
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SessionToken {
+    fn from(token: String) -> Self {
+        Self(token)
+    }
+}
+
+impl Display for SessionToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// state of a session in a `SessionStore`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionState {
+    pub identity: Identity,
+
+    /// tags of the connection, put back when the session is resumed
+    pub subscriptions: BTreeSet<String>,
+
+    /// messages sent while the client is away, oldest first
+    pub pending: Vec<Bytes>,
+
+    /// when the session expires, which is `None` while the client is
+    /// connected
+    pub expires_at: Option<SystemTime>,
+}
+
+impl SessionState {
+    /// returns true if the session is expired at `now`
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// storage of sessions
+pub trait SessionStore: Send + Sync {
+    /// returns the session of `token`
+    fn load(
+        &self,
+        token: &SessionToken,
+    ) -> BoxFuture<'static, Result<Option<SessionState>, BoxError>>;
+
+    /// saves `state` as the session of `token`, replacing the old one
+    fn save(
+        &self,
+        token: &SessionToken,
+        state: SessionState,
+    ) -> BoxFuture<'static, Result<(), BoxError>>;
+
+    /// removes the session of `token`
+    fn remove(&self, token: &SessionToken) -> BoxFuture<'static, Result<(), BoxError>>;
+
+    /// removes sessions expired at `now`, and returns how many. Stores that
+    /// expire sessions by themselves keep the default, which does nothing.
+    fn evict_expired(&self, now: SystemTime) -> BoxFuture<'static, Result<usize, BoxError>> {
+        let _ = now;
+        Box::pin(ready(Ok(0)))
+    }
+}
+
+/// `SessionStore` in memory.
+/// Clones share the same sessions.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: Arc<Mutex<HashMap<SessionToken, SessionState>>>,
+}
+
+impl MemorySessionStore {
+    /// returns a store without sessions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns the number of sessions, including expired ones
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// returns true if there is no session
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(
+        &self,
+        token: &SessionToken,
+    ) -> BoxFuture<'static, Result<Option<SessionState>, BoxError>> {
+        let state = self.sessions.lock().unwrap().get(token).cloned();
+        Box::pin(ready(Ok(state)))
+    }
+
+    fn save(
+        &self,
+        token: &SessionToken,
+        state: SessionState,
+    ) -> BoxFuture<'static, Result<(), BoxError>> {
+        self.sessions.lock().unwrap().insert(token.clone(), state);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn remove(&self, token: &SessionToken) -> BoxFuture<'static, Result<(), BoxError>> {
+        self.sessions.lock().unwrap().remove(token);
+        Box::pin(ready(Ok(())))
+    }
+
+    fn evict_expired(&self, now: SystemTime) -> BoxFuture<'static, Result<usize, BoxError>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let len = sessions.len();
+        sessions.retain(|_, state| !state.is_expired(now));
+        Box::pin(ready(Ok(len - sessions.len())))
+    }
+}
+
+/// session attached to a connection
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CurrentSession {
+    pub token: SessionToken,
+    pub identity: Identity,
+}
+
+/// sessions attached to open connections
+#[derive(Default)]
+struct Attached {
+    by_connection: HashMap<ConnectionId, CurrentSession>,
+    by_token: HashMap<SessionToken, Connection<Bytes>>,
+}
+
+/// locks of tokens whose sessions are being changed
+type Locks = Arc<Mutex<HashMap<SessionToken, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// lock of a token, which is removed from `Locks` when no one else holds or
+/// waits for it
+struct TokenLock {
+    // released before the lock is removed
+    _guard: OwnedMutexGuard<()>,
+    _remove: RemoveLock,
+}
+
+struct RemoveLock {
+    token: SessionToken,
+    locks: Locks,
+}
+
+impl Drop for RemoveLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks
+            .get(&self.token)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.token);
+        }
+    }
+}
+
+/// sessions of clients in a `SessionStore`.
+/// Clones share the same store, attached sessions and locks.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    ttl: Duration,
+    max_pending: usize,
+    attached: Arc<Mutex<Attached>>,
+    locks: Locks,
+}
+
+impl Sessions {
+    /// returns sessions in `store`, which are kept for 10 minutes after
+    /// their connection closes
+    pub fn new<S: SessionStore + 'static>(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            ttl: Duration::from_secs(10 * 60),
+            max_pending: 256,
+            attached: Arc::new(Mutex::new(Attached::default())),
+            locks: Arc::default(),
+        }
+    }
+
+    /// returns sessions in `store` with `session_ttl` and
+    /// `session_max_pending` of `config`
+    pub fn from_config<S: SessionStore + 'static>(store: S, config: &Config) -> Self {
+        Self::new(store)
+            .ttl(config.session_ttl)
+            .max_pending(config.session_max_pending)
+    }
+
+    /// sets how long a session is kept after its connection closes
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// sets how many messages are kept while the client is away
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// starts a session of `identity` on `connection`, and returns its
+    /// token. Expired sessions are evicted from the store first.
+    pub async fn start(
+        &self,
+        identity: Identity,
+        connection: &Connection<Bytes>,
+    ) -> Result<SessionToken, SessionError> {
+        self.store
+            .evict_expired(SystemTime::now())
+            .await
+            .map_err(SessionError::Store)?;
+
+        let token = SessionToken::generate()?;
+        let state = SessionState {
+            identity: identity.clone(),
+            subscriptions: connection.tags().into_iter().collect(),
+            pending: Vec::new(),
+            expires_at: None,
+        };
+        self.store
+            .save(&token, state)
+            .await
+            .map_err(SessionError::Store)?;
+
+        self.attach(token.clone(), identity, connection);
+        Ok(token)
+    }
+
+    /// resumes the session of `token` on `connection`: the connection is
+    /// tagged with its subscriptions, and pending messages are pushed to
+    /// it. A connection that still has the session loses it.
+    pub async fn resume(
+        &self,
+        token: &SessionToken,
+        connection: &Connection<Bytes>,
+    ) -> Result<Identity, SessionError> {
+        let _lock = self.lock(token).await;
+        let mut state = self.load(token).await?;
+        for tag in &state.subscriptions {
+            connection.tag(tag.clone());
+        }
+        for msg in state.pending.drain(..) {
+            connection.send(msg);
+        }
+        state.expires_at = None;
+        let identity = state.identity.clone();
+        self.store
+            .save(token, state)
+            .await
+            .map_err(SessionError::Store)?;
+
+        self.attach(token.clone(), identity.clone(), connection);
+        Ok(identity)
+    }
+
+    /// detaches the session of `connection`, which is about to close, and
+    /// saves its subscriptions. The session expires after the ttl from now
+    /// unless it is resumed.
+    pub async fn detach(&self, connection: &Connection<Bytes>) -> Result<(), SessionError> {
+        let Some(token) = self.detach_connection(connection.id()) else {
+            return Ok(());
+        };
+
+        let _lock = self.lock(&token).await;
+        let state = self.store.load(&token).await.map_err(SessionError::Store)?;
+        let Some(mut state) = state else {
+            // ended meanwhile
+            return Ok(());
+        };
+        state.subscriptions = connection.tags().into_iter().collect();
+        state.expires_at = Some(SystemTime::now() + self.ttl);
+        self.store
+            .save(&token, state)
+            .await
+            .map_err(SessionError::Store)
+    }
+
+    /// ends the session of `token`, e.g. when the client logs out
+    pub async fn end(&self, token: &SessionToken) -> Result<(), SessionError> {
+        let _lock = self.lock(token).await;
+        {
+            let mut attached = self.attached.lock().unwrap();
+            if let Some(connection) = attached.by_token.remove(token) {
+                attached.by_connection.remove(&connection.id());
+            }
+        }
+        self.store.remove(token).await.map_err(SessionError::Store)
+    }
+
+    /// pushes `msg` to the client of the session of `token`. While the
+    /// client is away, it is kept until the session is resumed.
+    pub async fn send(&self, token: &SessionToken, msg: Bytes) -> Result<(), SessionError> {
+        // a session resumed meanwhile gets `msg` directly
+        let _lock = self.lock(token).await;
+        let connection = self.attached.lock().unwrap().by_token.get(token).cloned();
+        if let Some(connection) = connection {
+            if connection.send(msg.clone()) {
+                return Ok(());
+            }
+        }
+
+        let mut state = self.load(token).await?;
+        state.pending.push(msg);
+        if state.pending.len() > self.max_pending {
+            let dropped = state.pending.len() - self.max_pending;
+            state.pending.drain(..dropped);
+        }
+        self.store
+            .save(token, state)
+            .await
+            .map_err(SessionError::Store)
+    }
+
+    /// returns the session attached to the connection of `id`
+    pub fn current(&self, id: ConnectionId) -> Option<CurrentSession> {
+        let attached = self.attached.lock().unwrap();
+        attached.by_connection.get(&id).cloned()
+    }
+
+    /// waits until no one else changes the session of `token`, and locks it
+    /// until the returned lock is dropped
+    async fn lock(&self, token: &SessionToken) -> TokenLock {
+        // removes the lock even if this is dropped while waiting
+        let remove = RemoveLock {
+            token: token.clone(),
+            locks: self.locks.clone(),
+        };
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(token.clone())
+            .or_default()
+            .clone();
+        TokenLock {
+            _guard: lock.lock_owned().await,
+            _remove: remove,
+        }
+    }
+
+    /// returns the session of `token` that is not expired.
+    /// An expired one is removed.
+    async fn load(&self, token: &SessionToken) -> Result<SessionState, SessionError> {
+        let state = self.store.load(token).await.map_err(SessionError::Store)?;
+        match state {
+            Some(state) if !state.is_expired(SystemTime::now()) => Ok(state),
+            Some(_) => {
+                self.store
+                    .remove(token)
+                    .await
+                    .map_err(SessionError::Store)?;
+                Err(SessionError::Unknown)
+            }
+            None => Err(SessionError::Unknown),
+        }
+    }
+
+    fn attach(&self, token: SessionToken, identity: Identity, connection: &Connection<Bytes>) {
+        let mut attached = self.attached.lock().unwrap();
+        if let Some(old) = attached.by_token.insert(token.clone(), connection.clone()) {
+            attached.by_connection.remove(&old.id());
+        }
+        let session = CurrentSession { token, identity };
+        if let Some(old) = attached.by_connection.insert(connection.id(), session) {
+            attached.by_token.remove(&old.token);
+        }
+    }
+
+    /// removes the session of the connection of `id`, and returns its token
+    fn detach_connection(&self, id: ConnectionId) -> Option<SessionToken> {
+        let mut attached = self.attached.lock().unwrap();
+        let session = attached.by_connection.remove(&id)?;
+        attached.by_token.remove(&session.token);
+        Some(session.token)
+    }
+}
+
+impl FromContext for Sessions {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<Sessions>().cloned()
+    }
+}
+
+/// the session of the connection, if it is started or resumed
+impl FromContext for CurrentSession {
+    fn from_context(context: &Context) -> Option<Self> {
+        let sessions = context.get::<Sessions>()?;
+        let connection = context.get::<Connection<Bytes>>()?;
+        sessions.current(connection.id())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::connections::Connections;
+
+    use super::*;
+
+    fn alice() -> Identity {
+        Identity {
+            subject: String::from("alice"),
+            roles: vec![String::from("player")],
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_test() -> Result<(), SessionError> {
+        let connections = Connections::new();
+        let store = MemorySessionStore::new();
+        let sessions = Sessions::new(store.clone()).max_pending(2);
+
+        let (first, _) = connections.register();
+        first.connection().tag("lobby");
+        let token = sessions.start(alice(), &first.connection()).await?;
+        assert_eq!(token.as_str().len(), TOKEN_BYTES * 2);
+        assert_eq!(
+            sessions.current(first.connection().id()).unwrap().token,
+            token
+        );
+
+        // subscribed after the start, and pushed while away
+        first.connection().tag("chat");
+        sessions.detach(&first.connection()).await?;
+        drop(first);
+        for msg in ["1", "2", "3"] {
+            sessions.send(&token, Bytes::from(msg)).await?;
+        }
+
+        let (second, mut pushed) = connections.register();
+        assert_eq!(
+            sessions.resume(&token, &second.connection()).await?,
+            alice()
+        );
+        assert_eq!(second.connection().tags(), ["chat", "lobby"]);
+        assert_eq!(pushed.recv().await, Some(Bytes::from("2")));
+        assert_eq!(pushed.recv().await, Some(Bytes::from("3")));
+
+        // pushed directly while attached
+        sessions.send(&token, Bytes::from("4")).await?;
+        assert_eq!(pushed.recv().await, Some(Bytes::from("4")));
+        assert!(store
+            .load(&token)
+            .await
+            .unwrap()
+            .unwrap()
+            .pending
+            .is_empty());
+
+        sessions.end(&token).await?;
+        assert!(store.is_empty());
+        assert!(sessions.current(second.connection().id()).is_none());
+        assert!(matches!(
+            sessions.resume(&token, &second.connection()).await,
+            Err(SessionError::Unknown)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expire_test() -> Result<(), SessionError> {
+        let connections = Connections::new();
+        let store = MemorySessionStore::new();
+        let sessions = Sessions::new(store.clone()).ttl(Duration::ZERO);
+
+        // expires as soon as it is detached
+        let (registration, _) = connections.register();
+        let token = sessions.start(alice(), &registration.connection()).await?;
+        sessions.detach(&registration.connection()).await?;
+        assert!(matches!(
+            sessions.send(&token, Bytes::new()).await,
+            Err(SessionError::Unknown)
+        ));
+        assert!(store.is_empty());
+
+        // evicted when another session starts
+        let (first, _) = connections.register();
+        sessions.start(alice(), &first.connection()).await?;
+        sessions.detach(&first.connection()).await?;
+        let (second, _) = connections.register();
+        sessions.start(alice(), &second.connection()).await?;
+        assert_eq!(store.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outlive_ttl_test() -> Result<(), SessionError> {
+        let connections = Connections::new();
+        let store = MemorySessionStore::new();
+        let sessions = Sessions::new(store.clone()).ttl(Duration::from_millis(50));
+
+        // connected longer than the ttl
+        let (first, _) = connections.register();
+        let token = sessions.start(alice(), &first.connection()).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.evict_expired(SystemTime::now()).await.unwrap(), 0);
+        sessions.detach(&first.connection()).await?;
+
+        let (second, _) = connections.register();
+        assert_eq!(
+            sessions.resume(&token, &second.connection()).await?,
+            alice()
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sessions.detach(&second.connection()).await?;
+
+        let (third, _) = connections.register();
+        assert_eq!(sessions.resume(&token, &third.connection()).await?, alice());
+        Ok(())
+    }
+
+    /// `MemorySessionStore` that takes a while to load
+    struct SlowStore(MemorySessionStore);
+
+    impl SessionStore for SlowStore {
+        fn load(
+            &self,
+            token: &SessionToken,
+        ) -> BoxFuture<'static, Result<Option<SessionState>, BoxError>> {
+            let load = self.0.load(token);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                load.await
+            })
+        }
+
+        fn save(
+            &self,
+            token: &SessionToken,
+            state: SessionState,
+        ) -> BoxFuture<'static, Result<(), BoxError>> {
+            self.0.save(token, state)
+        }
+
+        fn remove(&self, token: &SessionToken) -> BoxFuture<'static, Result<(), BoxError>> {
+            self.0.remove(token)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_test() -> Result<(), SessionError> {
+        let connections = Connections::new();
+        let store = MemorySessionStore::new();
+        let sessions = Sessions::new(SlowStore(store.clone()));
+
+        let (registration, _) = connections.register();
+        let token = sessions.start(alice(), &registration.connection()).await?;
+        sessions.detach(&registration.connection()).await?;
+
+        // sent at once while away, and none of them is lost
+        let sends = (0..10).map(|i| sessions.send(&token, Bytes::from(vec![i])));
+        for sent in futures::future::join_all(sends).await {
+            sent?;
+        }
+        let state = store.load(&token).await.unwrap().unwrap();
+        assert_eq!(state.pending.len(), 10);
+        assert!(sessions.locks.lock().unwrap().is_empty());
+
+        // a lock given up while waiting is removed as well
+        let lock = sessions.lock(&token).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(10), sessions.lock(&token));
+        assert!(waiting.await.is_err());
+        drop(lock);
+        assert!(sessions.locks.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takeover_test() -> Result<(), SessionError> {
+        let connections = Connections::new();
+        let sessions = Sessions::new(MemorySessionStore::new());
+
+        let (old, _) = connections.register();
+        let token = sessions.start(alice(), &old.connection()).await?;
+
+        // resumed before the old connection is found dead
+        let (new, mut pushed) = connections.register();
+        sessions.resume(&token, &new.connection()).await?;
+        assert!(sessions.current(old.connection().id()).is_none());
+
+        // closing the old connection does not detach the new one
+        sessions.detach(&old.connection()).await?;
+        assert_eq!(
+            sessions.current(new.connection().id()).unwrap().token,
+            token
+        );
+        sessions.send(&token, Bytes::from("hi")).await?;
+        assert_eq!(pushed.recv().await, Some(Bytes::from("hi")));
+        Ok(())
+    }
+}
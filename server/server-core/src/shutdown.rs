@@ -0,0 +1,238 @@
+//! Graceful shutdown with hooks of applications
+//!
+//! Applications often have something to do before the process exits:
+//! flushing caches, persisting sessions or saying goodbye on topics.
+//! They register these as hooks to `Shutdown`, which runs them in the order
+//! of registration when the server shuts down.
+//!
+//! Each hook has its own timeout, so that one stuck hook doesn't keep the
+//! server from stopping. `Shutdown::run` reports how each hook went, and
+//! `Report` shows which hook overran or failed.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::shutdown::{Outcome, Shutdown};
+//!
+//! async fn flush_cache() -> Result<(), String> {
+//!     println!("cache is flushed");
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let shutdown = Shutdown::new();
+//! shutdown.hook("flush cache", Duration::from_secs(5), flush_cache);
+//!
+//! // listeners stop when the shutdown is triggered
+//! let signal = shutdown.signal();
+//! tokio::spawn(async move {
+//!     signal.wait().await;
+//!     println!("stop accepting");
+//! });
+//!
+//! shutdown.trigger();
+//! for report in shutdown.run().await {
+//!     // this would print "flush cache: done in 0ms"
+//!     println!("{report}");
+//!     assert_eq!(report.outcome, Outcome::Done);
+//! }
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use tokio::sync::watch;
+
+type Hook = Box<dyn FnOnce() -> LocalBoxFuture<'static, Result<(), String>> + Send>;
+
+/// how a hook ended
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// finished successfully
+    Done,
+
+    /// returned an error
+    Failed(String),
+
+    /// didn't finish in its timeout, so it was cancelled
+    TimedOut,
+}
+
+/// result of a hook
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Report {
+    pub name: String,
+    pub elapsed: Duration,
+    pub outcome: Outcome,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = self.elapsed.as_millis();
+        match &self.outcome {
+            Outcome::Done => write!(f, "{}: done in {ms}ms", self.name),
+            Outcome::Failed(e) => write!(f, "{}: failed in {ms}ms: {e}", self.name),
+            Outcome::TimedOut => write!(f, "{}: overran its timeout of {ms}ms", self.name),
+        }
+    }
+}
+
+/// receiver of the shutdown signal
+#[derive(Clone, Debug)]
+pub struct Signal(watch::Receiver<bool>);
+
+impl Signal {
+    /// returns true if the shutdown is triggered
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// waits until the shutdown is triggered
+    pub async fn wait(mut self) {
+        // an error means that `Shutdown` is dropped, which is also a shutdown
+        let _ = self.0.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// registry of shutdown hooks.
+/// Clones share the same hooks and signal.
+#[derive(Clone)]
+pub struct Shutdown {
+    hooks: Arc<Mutex<Vec<(String, Duration, Hook)>>>,
+    signal: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    /// returns a registry without hooks
+    pub fn new() -> Self {
+        Self {
+            hooks: Arc::new(Mutex::new(Vec::new())),
+            signal: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// registers `hook` that is called once on shutdown.
+    /// It is cancelled when it doesn't finish in `timeout`.
+    pub fn hook<S, F, Fut, E>(&self, name: S, timeout: Duration, hook: F)
+    where
+        S: Into<String>,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + 'static,
+        E: Display,
+    {
+        let hook: Hook = Box::new(move || {
+            hook()
+                .map(|result| result.map_err(|e| e.to_string()))
+                .boxed_local()
+        });
+        self.hooks
+            .lock()
+            .unwrap()
+            .push((name.into(), timeout, hook));
+    }
+
+    /// returns a receiver of the shutdown signal
+    pub fn signal(&self) -> Signal {
+        Signal(self.signal.subscribe())
+    }
+
+    /// tells every `Signal` that the server is shutting down
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    /// triggers the shutdown and runs every hook in the order of
+    /// registration. Hooks run only once even if this is called again.
+    pub async fn run(&self) -> Vec<Report> {
+        self.trigger();
+
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        let mut reports = Vec::with_capacity(hooks.len());
+        for (name, timeout, hook) in hooks {
+            let start = Instant::now();
+            let outcome = match tokio::time::timeout(timeout, hook()).await {
+                Ok(Ok(())) => Outcome::Done,
+                Ok(Err(e)) => Outcome::Failed(e),
+                Err(_) => Outcome::TimedOut,
+            };
+            let elapsed = match outcome {
+                Outcome::TimedOut => timeout,
+                _ => start.elapsed(),
+            };
+            reports.push(Report {
+                name,
+                elapsed,
+                outcome,
+            });
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn run_test() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let shutdown = Shutdown::new();
+
+        let order_ = order.clone();
+        shutdown.hook("first", Duration::from_secs(1), move || async move {
+            order_.lock().unwrap().push("first");
+            Ok::<_, String>(())
+        });
+        shutdown.hook("stuck", Duration::from_secs(1), || async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, String>(())
+        });
+        shutdown.hook("failing", Duration::from_secs(1), || async {
+            Err("disk is full")
+        });
+        let order_ = order.clone();
+        shutdown.hook("last", Duration::from_secs(1), move || async move {
+            order_.lock().unwrap().push("last");
+            Ok::<_, String>(())
+        });
+
+        let signal = shutdown.signal();
+        assert!(!signal.is_triggered());
+
+        let reports = shutdown.clone().run().await;
+        assert!(signal.is_triggered());
+        signal.wait().await;
+
+        let outcomes: Vec<_> = reports.iter().map(|r| r.outcome.clone()).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Done,
+                Outcome::TimedOut,
+                Outcome::Failed("disk is full".to_string()),
+                Outcome::Done,
+            ]
+        );
+        assert_eq!(*order.lock().unwrap(), vec!["first", "last"]);
+        assert_eq!(
+            reports[1].to_string(),
+            "stuck: overran its timeout of 1000ms"
+        );
+
+        // hooks run only once
+        assert!(shutdown.run().await.is_empty());
+    }
+}
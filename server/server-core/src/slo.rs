@@ -0,0 +1,416 @@
+//! Error budgets of routes
+//!
+//! An SLO (service level objective) of a route is the rate of calls that
+//! should succeed in a rolling window, like 99.9% in 30 minutes. The other
+//! 0.1% is the error budget of the route. `SloTracker` counts successful and
+//! failed calls of each route (recorded by `SloLayer`) and computes the
+//! burn rate: how fast the budget is used, where 1.0 uses up exactly the
+//! budget in the window and 10.0 uses it up ten times faster.
+//!
+//! `SloTracker::watch_every` checks the burn rates periodically and calls
+//! an alerting function with `BurnEvent`s when a route starts or stops
+//! burning its budget faster than a threshold. `SloTracker::snapshot`
+//! returns the status of every route, to be exported as metrics.
+//!
+//! This module needs the `slo` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::slo::{Slo, SloLayer, SloTracker};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn login(name: &'static str) -> Result<(), ()> {
+//!     if name.is_empty() {
+//!         Err(())
+//!     } else {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let tracker = Arc::new(SloTracker::new());
+//! tracker.target("login", Slo::new(0.99, Duration::from_secs(1800)));
+//!
+//! let handler = apply!(SloLayer::new(tracker.clone(), "login") to login);
+//! for _ in 0..9 {
+//!     handler.call("cubby").await?;
+//! }
+//! assert!(handler.call("").await.is_err());
+//!
+//! // 10% of calls failed, which burns the budget of 1% ten times faster
+//! let status = tracker.status("login").unwrap();
+//! assert_eq!(status.total, 10);
+//! assert!((status.burn_rate - 10.0).abs() < 1e-6);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{ok, LocalBoxFuture, Ready};
+use tokio::time::{interval, Instant};
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// number of buckets of a window
+const BUCKETS: u32 = 60;
+
+/// objective of a route
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slo {
+    /// rate of calls that should succeed (e.g. `0.999`)
+    pub target: f64,
+
+    /// rolling window of the rate
+    pub window: Duration,
+}
+
+impl Slo {
+    /// `target` of successful calls in `window`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `target` is not in `0.0..1.0` or `window` is zero.
+    pub fn new(target: f64, window: Duration) -> Self {
+        assert!((0.0..1.0).contains(&target), "target should be in 0.0..1.0");
+        assert!(!window.is_zero(), "window should be positive");
+        Self { target, window }
+    }
+}
+
+/// calls of a route in a window, as buckets of (start, total, failures)
+#[derive(Debug)]
+struct Window {
+    slo: Slo,
+    buckets: VecDeque<(Instant, u64, u64)>,
+}
+
+impl Window {
+    fn new(slo: Slo) -> Self {
+        Self {
+            slo,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    /// removes buckets that are out of the window
+    fn purge(&mut self, now: Instant) {
+        while let Some((start, _, _)) = self.buckets.front() {
+            if now.duration_since(*start) < self.slo.window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    fn record(&mut self, now: Instant, success: bool) {
+        self.purge(now);
+        let width = self.slo.window / BUCKETS;
+        match self.buckets.back_mut() {
+            Some((start, total, failures)) if now.duration_since(*start) < width => {
+                *total += 1;
+                *failures += u64::from(!success);
+            }
+            _ => self.buckets.push_back((now, 1, u64::from(!success))),
+        }
+    }
+
+    fn status(&mut self, route: &str, now: Instant) -> SloStatus {
+        self.purge(now);
+        let (total, failures) = self
+            .buckets
+            .iter()
+            .fold((0, 0), |(t, f), (_, total, failures)| {
+                (t + total, f + failures)
+            });
+        let (success_rate, burn_rate) = if total == 0 {
+            (1.0, 0.0)
+        } else {
+            let error_rate = failures as f64 / total as f64;
+            (1.0 - error_rate, error_rate / (1.0 - self.slo.target))
+        };
+        SloStatus {
+            route: route.to_string(),
+            slo: self.slo,
+            total,
+            failures,
+            success_rate,
+            burn_rate,
+        }
+    }
+}
+
+/// status of a route in its window
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloStatus {
+    pub route: String,
+    pub slo: Slo,
+    pub total: u64,
+    pub failures: u64,
+
+    /// rate of successful calls (1.0 without calls)
+    pub success_rate: f64,
+
+    /// rate of failed calls over the error budget (0.0 without calls)
+    pub burn_rate: f64,
+}
+
+impl Display for SloStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: success={:.3}% target={:.3}% burn_rate={:.2} ({}/{} failed)",
+            self.route,
+            self.success_rate * 100.0,
+            self.slo.target * 100.0,
+            self.burn_rate,
+            self.failures,
+            self.total
+        )
+    }
+}
+
+/// change of a route against the burn rate threshold
+#[derive(Clone, Debug, PartialEq)]
+pub enum BurnEvent {
+    /// the route started burning its budget faster than the threshold
+    Alert(SloStatus),
+
+    /// the route went back under the threshold
+    Resolved(SloStatus),
+}
+
+impl Display for BurnEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BurnEvent::Alert(status) => write!(f, "[ALERT] {status}"),
+            BurnEvent::Resolved(status) => write!(f, "[RESOLVED] {status}"),
+        }
+    }
+}
+
+/// windows of routes with objectives
+#[derive(Debug, Default)]
+pub struct SloTracker {
+    windows: Mutex<BTreeMap<String, Window>>,
+}
+
+impl SloTracker {
+    /// returns a tracker without any route
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets the objective of `route`, which resets its window.
+    /// Calls of routes without an objective are not tracked.
+    pub fn target<S: Into<String>>(&self, route: S, slo: Slo) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.insert(route.into(), Window::new(slo));
+    }
+
+    /// adds a call of `route`
+    pub fn record(&self, route: &str, success: bool) {
+        if let Some(window) = self.windows.lock().unwrap().get_mut(route) {
+            window.record(Instant::now(), success);
+        }
+    }
+
+    /// returns the status of `route`, or `None` without an objective
+    pub fn status(&self, route: &str) -> Option<SloStatus> {
+        let mut windows = self.windows.lock().unwrap();
+        let now = Instant::now();
+        windows.get_mut(route).map(|w| w.status(route, now))
+    }
+
+    /// returns statuses of every route in order of routes
+    pub fn snapshot(&self) -> Vec<SloStatus> {
+        let mut windows = self.windows.lock().unwrap();
+        let now = Instant::now();
+        windows
+            .iter_mut()
+            .map(|(route, window)| window.status(route, now))
+            .collect()
+    }
+
+    /// checks burn rates every `period` forever, and calls `alert` with
+    /// routes that crossed `threshold` since the last check
+    pub async fn watch_every<F>(&self, period: Duration, threshold: f64, mut alert: F)
+    where
+        F: FnMut(Vec<BurnEvent>),
+    {
+        let mut burning = BTreeSet::new();
+        let mut ticker = interval(period);
+        loop {
+            ticker.tick().await;
+            let events: Vec<_> = self
+                .snapshot()
+                .into_iter()
+                .filter_map(|status| {
+                    let over = status.burn_rate > threshold;
+                    if over && burning.insert(status.route.clone()) {
+                        Some(BurnEvent::Alert(status))
+                    } else if !over && burning.remove(&status.route) {
+                        Some(BurnEvent::Resolved(status))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            if !events.is_empty() {
+                alert(events);
+            }
+        }
+    }
+}
+
+/// `Layer` that builds `SloHandler`
+pub struct SloLayer {
+    tracker: Arc<SloTracker>,
+    route: Arc<str>,
+}
+
+impl SloLayer {
+    /// records results of the next handler as `route` in `tracker`
+    pub fn new<S: Into<String>>(tracker: Arc<SloTracker>, route: S) -> Self {
+        Self {
+            tracker,
+            route: route.into().into(),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for SloLayer
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = SloHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(SloHandler {
+            tracker: self.tracker.clone(),
+            route: self.route.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that records whether the previous handler succeeds
+pub struct SloHandler<H> {
+    tracker: Arc<SloTracker>,
+    route: Arc<str>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for SloHandler<H>
+where
+    H: Handler<T>,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let tracker = self.tracker.clone();
+        let route = self.route.clone();
+        let future = self.prev.call(msg);
+
+        Box::pin(async move {
+            let result = future.await;
+            tracker.record(&route, result.is_ok());
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fn_handler::fn_handler;
+    use crate::layer::connect;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn window_test() {
+        let tracker = SloTracker::new();
+        tracker.target("chat", Slo::new(0.9, Duration::from_secs(60)));
+
+        tracker.record("chat", false);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        for _ in 0..3 {
+            tracker.record("chat", true);
+        }
+        // untracked routes are ignored
+        tracker.record("move", false);
+        assert_eq!(tracker.status("move"), None);
+
+        let status = tracker.status("chat").unwrap();
+        assert_eq!((status.total, status.failures), (4, 1));
+        assert!((status.burn_rate - 2.5).abs() < 1e-6);
+
+        // the failure goes out of the window
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let status = tracker.status("chat").unwrap();
+        assert_eq!((status.total, status.failures), (3, 0));
+        assert_eq!(status.burn_rate, 0.0);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(tracker.snapshot()[0].success_rate, 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alert_test() -> Result<(), ()> {
+        let tracker = Arc::new(SloTracker::new());
+        tracker.target("chat", Slo::new(0.5, Duration::from_secs(10)));
+        let chat = fn_handler(|ok: bool| async move {
+            if ok {
+                Ok(())
+            } else {
+                Err(())
+            }
+        });
+        let handler = connect(SloLayer::new(tracker.clone(), "chat"), chat).await?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ = events.clone();
+        let watcher = tracker.clone();
+        let task = tokio::spawn(async move {
+            watcher
+                .watch_every(Duration::from_secs(1), 1.0, move |e| {
+                    events_.lock().unwrap().extend(e)
+                })
+                .await
+        });
+
+        handler.call(true).await?;
+        assert!(handler.call(false).await.is_err());
+        assert!(handler.call(false).await.is_err());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(matches!(events.lock().unwrap()[..], [BurnEvent::Alert(_)]));
+
+        // alerted only once while burning
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(events.lock().unwrap().len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        task.abort();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[1], BurnEvent::Resolved(s) if s.total == 0));
+        Ok(())
+    }
+}
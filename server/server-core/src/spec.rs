@@ -0,0 +1,366 @@
+//! Machine-readable description of the protocol
+//!
+//! Clients of other languages implement the protocol from a description,
+//! which should not drift from this crate. `generate` describes the frame
+//! layout, the handshake sequence, the messages and the errors as JSON from
+//! the definitions in code:
+//!
+//! - messages come from the descriptors of the `.proto` files that this
+//!   crate is built with, including their comments
+//! - frame lengths, compression flags and errors are matched exhaustively
+//!   on their Rust types, so a new variant doesn't build until it is
+//!   described here
+//!
+//! `protobuf/protocol.json` in the repository is the generated description,
+//! and a test fails when it is out of date. The `protocol-spec` binary of
+//! the server crate prints it.
+//!
+//! This module needs the `spec` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::spec::generate;
+//!
+//! let spec = generate();
+//! assert_eq!(spec["frame"]["max_frame"], 16 * 1024 * 1024);
+//! assert_eq!(spec["messages"]["handshake.ClientHello"]["fields"][0]["name"], "codecs");
+//! println!("{}", serde_json::to_string_pretty(&spec).unwrap());
+//! ```
+
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use serde_json::{json, Map, Value};
+
+use crate::client_version::Blocked;
+use crate::codec::compress::{
+    CompressError, Compression, FLAG_DEFLATE, FLAG_DEFLATE_TAKEOVER, FLAG_LZ4, FLAG_NONE, FLAG_ZSTD,
+};
+use crate::codec::framed::{FrameError, Length, MAX_FRAME};
+use crate::handshake::{HandshakeError, IncompatibleVersion};
+use crate::unsupported::Unsupported;
+
+/// descriptors of the `.proto` files, written by the build script
+const DESCRIPTORS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/protocol.bin"));
+
+/// `.proto` files that are part of the protocol (`sample.proto` is not)
+const PROTOCOL_FILES: [&str; 8] = [
+    "auth.proto",
+    "discovery.proto",
+    "envelope.proto",
+    "feature_flags.proto",
+    "handshake.proto",
+    "heartbeat.proto",
+    "rendezvous.proto",
+    "unsupported.proto",
+];
+
+/// path of a message in `FileDescriptorProto` (`message_type`)
+const MESSAGE_PATH: i32 = 4;
+
+/// path of a field in `DescriptorProto` (`field`)
+const FIELD_PATH: i32 = 2;
+
+/// returns the description of the protocol
+pub fn generate() -> Value {
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "frame": frame(),
+        "handshake": handshake(),
+        "messages": messages(),
+        "errors": errors(),
+    })
+}
+
+fn length(length: Length) -> Value {
+    match length {
+        Length::U32 => json!({
+            "name": "u32",
+            "description": "4-byte big-endian unsigned integer",
+        }),
+        Length::Varint => json!({
+            "name": "varint",
+            "description": "unsigned varint of protobuf, at most 10 bytes",
+        }),
+    }
+}
+
+fn compression(compression: Compression) -> Value {
+    let flag = match compression {
+        Compression::None => FLAG_NONE,
+        Compression::Zstd => FLAG_ZSTD,
+        Compression::Lz4 => FLAG_LZ4,
+        Compression::Deflate => {
+            return json!({
+                "name": compression.name(),
+                "flag": FLAG_DEFLATE,
+                "context_takeover_flag": FLAG_DEFLATE_TAKEOVER,
+                "context_takeover": "raw deflate flushed with sync flush, without the final \
+                                     00 00 ff ff, continuing the stream of the previous frames \
+                                     with this flag in the same direction",
+            });
+        }
+    };
+    json!({ "name": compression.name(), "flag": flag })
+}
+
+fn frame() -> Value {
+    json!({
+        "layout": "length of the payload followed by the payload",
+        "lengths": [length(Length::U32), length(Length::Varint)],
+        "default_length": "u32",
+        "max_frame": MAX_FRAME,
+        "compression": {
+            "layout": "when a compression is negotiated, the payload starts with a flag byte \
+                       telling how the rest is compressed; otherwise there is no flag",
+            "algorithms": [
+                compression(Compression::None),
+                compression(Compression::Zstd),
+                compression(Compression::Lz4),
+                compression(Compression::Deflate),
+            ],
+        },
+        "payload": "envelope.Envelope after the handshake",
+    })
+}
+
+fn handshake() -> Value {
+    json!({
+        "sequence": [
+            { "from": "client", "message": "handshake.ClientHello" },
+            { "from": "server", "message": "handshake.ServerHello" },
+        ],
+        "codec": "the server chooses the first of its codecs, in its order, that the client \
+                  offered; without one, `codec` is empty and the connection is closed",
+        "compression": "the server chooses the first compression in its order of preference \
+                        that the client offered; without one, frames are not compressed",
+        "version": "the connection speaks the older protocol version of the peers; when it is \
+                    below the oldest one that a peer speaks, or the client version doesn't \
+                    match the semver requirement of the server, `incompatible` is set and \
+                    the connection is closed",
+        "protocol_version": crate::PROTOCOL_VERSION,
+        "min_protocol_version": crate::MIN_PROTOCOL_VERSION,
+    })
+}
+
+fn messages() -> Value {
+    let set = FileDescriptorSet::decode(DESCRIPTORS).expect("descriptors are built by build.rs");
+    let mut messages = Map::new();
+    for file in set
+        .file
+        .iter()
+        .filter(|file| PROTOCOL_FILES.contains(&file.name()))
+    {
+        for (i, message) in file.message_type.iter().enumerate() {
+            let name = format!("{}.{}", file.package(), message.name());
+            messages.insert(name, describe_message(file, i as i32, message));
+        }
+    }
+    Value::Object(messages)
+}
+
+fn describe_message(file: &FileDescriptorProto, index: i32, message: &DescriptorProto) -> Value {
+    let fields: Vec<_> = message
+        .field
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let path = [MESSAGE_PATH, index, FIELD_PATH, i as i32];
+            let mut value = json!({
+                "name": field.name(),
+                "number": field.number(),
+                "type": field_type(message, field),
+                "label": match field.label() {
+                    Label::Optional => "optional",
+                    Label::Required => "required",
+                    Label::Repeated if is_map(message, field) => "map",
+                    Label::Repeated => "repeated",
+                },
+            });
+            if let Some(oneof) = field.oneof_index {
+                value["oneof"] = json!(message.oneof_decl[oneof as usize].name());
+            }
+            if let Some(comment) = comment(file, &path) {
+                value["comment"] = json!(comment);
+            }
+            value
+        })
+        .collect();
+
+    let mut value = json!({ "fields": fields });
+    if let Some(comment) = comment(file, &[MESSAGE_PATH, index]) {
+        value["comment"] = json!(comment);
+    }
+    value
+}
+
+/// returns the entry of a map field, which is a nested message
+fn map_entry<'a>(
+    message: &'a DescriptorProto,
+    field: &FieldDescriptorProto,
+) -> Option<&'a DescriptorProto> {
+    let name = field.type_name().rsplit('.').next()?;
+    message.nested_type.iter().find(|nested| {
+        nested.name() == name && nested.options.as_ref().and_then(|o| o.map_entry) == Some(true)
+    })
+}
+
+fn is_map(message: &DescriptorProto, field: &FieldDescriptorProto) -> bool {
+    field.r#type() == Type::Message && map_entry(message, field).is_some()
+}
+
+fn field_type(message: &DescriptorProto, field: &FieldDescriptorProto) -> String {
+    match field.r#type() {
+        Type::Double => String::from("double"),
+        Type::Float => String::from("float"),
+        Type::Int64 => String::from("int64"),
+        Type::Uint64 => String::from("uint64"),
+        Type::Int32 => String::from("int32"),
+        Type::Fixed64 => String::from("fixed64"),
+        Type::Fixed32 => String::from("fixed32"),
+        Type::Bool => String::from("bool"),
+        Type::String => String::from("string"),
+        Type::Bytes => String::from("bytes"),
+        Type::Uint32 => String::from("uint32"),
+        Type::Sfixed32 => String::from("sfixed32"),
+        Type::Sfixed64 => String::from("sfixed64"),
+        Type::Sint32 => String::from("sint32"),
+        Type::Sint64 => String::from("sint64"),
+        Type::Group | Type::Enum | Type::Message => match map_entry(message, field) {
+            Some(entry) => format!(
+                "map<{}, {}>",
+                field_type(entry, &entry.field[0]),
+                field_type(entry, &entry.field[1])
+            ),
+            None => field.type_name().trim_start_matches('.').to_string(),
+        },
+    }
+}
+
+/// returns the comment before the definition at `path`
+fn comment(file: &FileDescriptorProto, path: &[i32]) -> Option<String> {
+    let location = file
+        .source_code_info
+        .as_ref()?
+        .location
+        .iter()
+        .find(|location| location.path == path)?;
+    let comment = location.leading_comments.as_deref()?.trim();
+    (!comment.is_empty()).then(|| comment.to_string())
+}
+
+fn handshake_error(e: HandshakeError) -> Value {
+    let code = match &e {
+        HandshakeError::Io(_) => return Value::Null,
+        HandshakeError::Malformed => "malformed_handshake",
+        HandshakeError::NoCommonCodec(_) => "no_common_codec",
+        HandshakeError::Blocked(blocked) => blocked.name(),
+        HandshakeError::IncompatibleVersion(_) => "incompatible_version",
+    };
+    json!({
+        "code": code,
+        "stage": "handshake",
+        "example": e.to_string(),
+        "effect": "the connection is closed after ServerHello, if any",
+    })
+}
+
+fn frame_error(e: FrameError) -> Value {
+    let code = match &e {
+        FrameError::TooLarge { .. } => "frame_too_large",
+        FrameError::MalformedLength => "malformed_frame_length",
+    };
+    json!({
+        "code": code,
+        "stage": "frame",
+        "example": e.to_string(),
+        "effect": "the connection is closed without reading the frame",
+    })
+}
+
+fn compress_error(e: CompressError) -> Value {
+    let code = match &e {
+        CompressError::Compress(_) => return Value::Null,
+        CompressError::Decompress(_) => "decompress_failed",
+        CompressError::UnknownFlag(_) => "unknown_compression_flag",
+    };
+    json!({
+        "code": code,
+        "stage": "compression",
+        "example": e.to_string(),
+        "effect": "the frame is rejected",
+    })
+}
+
+fn errors() -> Value {
+    let errors = [
+        handshake_error(HandshakeError::Malformed),
+        handshake_error(HandshakeError::NoCommonCodec(vec![String::from(
+            "protobuf",
+        )])),
+        handshake_error(HandshakeError::Blocked(Blocked::TooOld)),
+        handshake_error(HandshakeError::Blocked(Blocked::Deprecated)),
+        handshake_error(HandshakeError::IncompatibleVersion(IncompatibleVersion {
+            client_version: String::from("0.1.0"),
+            client_protocol: 1,
+            server_version: String::from("0.2.0"),
+            server_protocol: 1,
+            requirement: Some(String::from("^0.2")),
+        })),
+        frame_error(FrameError::TooLarge {
+            len: MAX_FRAME as u64 + 1,
+            max: MAX_FRAME,
+        }),
+        frame_error(FrameError::MalformedLength),
+        compress_error(CompressError::Decompress(String::from("corrupted data"))),
+        compress_error(CompressError::UnknownFlag(9)),
+        json!({
+            "code": "unsupported",
+            "stage": "message",
+            "example": Unsupported {
+                name: String::from("move"),
+                version: 3,
+                supported_versions: vec![1, 2],
+            }
+            .to_string(),
+            "effect": "unsupported.Unsupported is sent back as a frame",
+        }),
+    ];
+    Value::Array(errors.into_iter().filter(|e| !e.is_null()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_test() {
+        let spec = generate();
+        let envelope = &spec["messages"]["envelope.Envelope"];
+        let headers = &envelope["fields"][3];
+        assert_eq!(headers["name"], "headers");
+        assert_eq!(headers["type"], "map<string, string>");
+        assert_eq!(headers["label"], "map");
+        assert_eq!(headers["comment"], "custom headers");
+
+        let packet = &spec["messages"]["rendezvous.Packet"]["fields"][0];
+        assert_eq!(packet["type"], "rendezvous.Register");
+        assert_eq!(packet["oneof"], "kind");
+        assert!(spec["messages"]["sample.Person"].is_null());
+    }
+
+    #[test]
+    fn errors_test() {
+        let spec = generate();
+        let codes: Vec<_> = spec["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["code"].as_str().unwrap())
+            .collect();
+        assert!(codes.contains(&"no_common_codec"));
+        assert!(codes.contains(&"frame_too_large"));
+        assert_eq!(spec["frame"]["compression"]["algorithms"][2]["flag"], 2);
+    }
+}
@@ -0,0 +1,506 @@
+//! Replication of named state documents to subscribers
+//!
+//! The server keeps documents in `StateSync`. Each document is a map of
+//! fields with a version number. Whenever a document is updated, only the
+//! changed fields are pushed to subscribers as a `Update::Diff`.
+//! A subscriber keeps its copy in `Replica`. When a replica finds that it
+//! missed some diffs (the version does not match), it asks for a
+//! `Update::Snapshot` again with `StateSync::snapshot`.
+//!
+//! Each document also has an epoch, which is new whenever the document is
+//! created, so a document that is removed and created again is not taken
+//! for the old one whose version restarts. A replica of another epoch
+//! needs a snapshot of the new one (`SyncError::Recreated`), and updates
+//! of an older epoch or version than the replica are ignored.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::state_sync::{Replica, StateSync};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let sync = StateSync::new();
+//! sync.update("lobby", |doc| {
+//!     doc.insert("players".to_string(), 1);
+//! });
+//!
+//! // the first update is always a snapshot
+//! let mut rx = sync.subscribe("lobby");
+//! let mut replica = Replica::new("lobby");
+//! replica.apply(rx.recv().await.unwrap()).unwrap();
+//!
+//! sync.update("lobby", |doc| {
+//!     doc.insert("players".to_string(), 2);
+//! });
+//! replica.apply(rx.recv().await.unwrap()).unwrap();
+//! assert_eq!(replica.fields()["players"], 2);
+//! assert_eq!(replica.version(), 2);
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// fields of a document
+pub type Fields<V> = BTreeMap<String, V>;
+
+/// update of a document that is sent to subscribers
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Update<V> {
+    /// whole document
+    Snapshot {
+        name: String,
+        epoch: u64,
+        version: u64,
+        fields: Fields<V>,
+    },
+
+    /// changes from version `base` to version `version`
+    Diff {
+        name: String,
+        epoch: u64,
+        base: u64,
+        version: u64,
+        set: Fields<V>,
+        removed: Vec<String>,
+    },
+}
+
+impl<V> Update<V> {
+    /// name of the document
+    pub fn name(&self) -> &str {
+        match self {
+            Update::Snapshot { name, .. } => name,
+            Update::Diff { name, .. } => name,
+        }
+    }
+
+    /// epoch of the document, which changes when it is created again
+    pub fn epoch(&self) -> u64 {
+        match self {
+            Update::Snapshot { epoch, .. } => *epoch,
+            Update::Diff { epoch, .. } => *epoch,
+        }
+    }
+
+    /// version of the document after applying this update
+    pub fn version(&self) -> u64 {
+        match self {
+            Update::Snapshot { version, .. } => *version,
+            Update::Diff { version, .. } => *version,
+        }
+    }
+}
+
+/// error when applying `Update` to `Replica`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SyncError {
+    /// update of other document
+    WrongDocument(String),
+
+    /// diff does not start from the version of replica.
+    /// replica should be resynced with snapshot.
+    Gap { expected: u64, got: u64 },
+
+    /// diff of a newer epoch, which means the document was removed and
+    /// created again. replica should be resynced with snapshot.
+    Recreated { expected: u64, got: u64 },
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::WrongDocument(name) => write!(f, "update of other document `{name}`"),
+            SyncError::Gap { expected, got } => {
+                write!(f, "expected diff from version {expected}, got {got}")
+            }
+            SyncError::Recreated { expected, got } => {
+                write!(f, "expected diff of epoch {expected}, got {got}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+struct Document<V> {
+    epoch: u64,
+    version: u64,
+    fields: Fields<V>,
+    subscribers: Vec<UnboundedSender<Update<V>>>,
+}
+
+impl<V: Clone> Document<V> {
+    fn new(epoch: u64) -> Self {
+        Self {
+            epoch,
+            version: 0,
+            fields: Fields::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn snapshot(&self, name: &str) -> Update<V> {
+        Update::Snapshot {
+            name: name.to_string(),
+            epoch: self.epoch,
+            version: self.version,
+            fields: self.fields.clone(),
+        }
+    }
+}
+
+/// documents of the server
+pub struct StateSync<V> {
+    documents: Mutex<HashMap<String, Document<V>>>,
+
+    /// epoch of the last created document
+    epoch: AtomicU64,
+}
+
+impl<V> Default for StateSync<V> {
+    fn default() -> Self {
+        Self {
+            documents: Mutex::new(HashMap::new()),
+            epoch: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<V> StateSync<V>
+where
+    V: Clone + PartialEq,
+{
+    /// returns an empty `StateSync`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns the document `name`, creating it with a new epoch when it
+    /// does not exist
+    fn document<'a>(
+        &self,
+        documents: &'a mut HashMap<String, Document<V>>,
+        name: &str,
+    ) -> &'a mut Document<V> {
+        documents
+            .entry(name.to_string())
+            .or_insert_with(|| Document::new(self.epoch.fetch_add(1, Ordering::Relaxed) + 1))
+    }
+
+    /// modifies the document `name` with `f`.
+    /// Document is created when it does not exist.
+    ///
+    /// If anything changed, version is increased and diff is pushed to
+    /// subscribers. Returns the version after the update.
+    pub fn update<F>(&self, name: &str, f: F) -> u64
+    where
+        F: FnOnce(&mut Fields<V>),
+    {
+        let mut documents = self.documents.lock().unwrap();
+        let doc = self.document(&mut documents, name);
+
+        let mut fields = doc.fields.clone();
+        f(&mut fields);
+
+        let set: Fields<V> = fields
+            .iter()
+            .filter(|(k, v)| doc.fields.get(*k) != Some(*v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let removed: Vec<String> = doc
+            .fields
+            .keys()
+            .filter(|k| !fields.contains_key(*k))
+            .cloned()
+            .collect();
+
+        if set.is_empty() && removed.is_empty() {
+            return doc.version;
+        }
+
+        let diff = Update::Diff {
+            name: name.to_string(),
+            epoch: doc.epoch,
+            base: doc.version,
+            version: doc.version + 1,
+            set,
+            removed,
+        };
+        doc.version += 1;
+        doc.fields = fields;
+        doc.subscribers.retain(|tx| tx.send(diff.clone()).is_ok());
+
+        doc.version
+    }
+
+    /// subscribes to the document `name`.
+    /// The first update received is the snapshot of current document.
+    pub fn subscribe(&self, name: &str) -> UnboundedReceiver<Update<V>> {
+        let mut documents = self.documents.lock().unwrap();
+        let doc = self.document(&mut documents, name);
+
+        let (tx, rx) = unbounded_channel();
+        // receiver is alive, so it cannot fail
+        let _ = tx.send(doc.snapshot(name));
+        doc.subscribers.push(tx);
+        rx
+    }
+
+    /// returns the whole document `name` for resync
+    pub fn snapshot(&self, name: &str) -> Option<Update<V>> {
+        let documents = self.documents.lock().unwrap();
+        documents.get(name).map(|doc| doc.snapshot(name))
+    }
+
+    /// removes the document `name`.
+    /// Subscribers would get `None` when they receive. A document created
+    /// with the name later has a new epoch.
+    pub fn remove(&self, name: &str) -> bool {
+        self.documents.lock().unwrap().remove(name).is_some()
+    }
+}
+
+/// copy of a document in subscriber
+#[derive(Clone, Debug)]
+pub struct Replica<V> {
+    name: String,
+    epoch: u64,
+    version: u64,
+    fields: Fields<V>,
+}
+
+impl<V> Replica<V> {
+    /// returns an empty replica of `name`.
+    /// It needs a snapshot to be in sync.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            epoch: 0,
+            version: 0,
+            fields: Fields::new(),
+        }
+    }
+
+    /// name of the document
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// epoch of the document that this replica is a copy of
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// version of this replica
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// fields of this replica
+    pub fn fields(&self) -> &Fields<V> {
+        &self.fields
+    }
+
+    /// applies an update from `StateSync`.
+    ///
+    /// Updates that are older than the replica (already applied, or of an
+    /// older epoch) are ignored. If there is a gap between replica and
+    /// diff, `SyncError::Gap` is returned, and if the diff is of a newer
+    /// epoch, `SyncError::Recreated`. Nothing changes on errors.
+    pub fn apply(&mut self, update: Update<V>) -> Result<(), SyncError> {
+        if update.name() != self.name {
+            return Err(SyncError::WrongDocument(update.name().to_string()));
+        }
+        match update {
+            Update::Snapshot { epoch, version, .. }
+                if (epoch, version) < (self.epoch, self.version) => {}
+            Update::Snapshot {
+                epoch,
+                version,
+                fields,
+                ..
+            } => {
+                self.epoch = epoch;
+                self.version = version;
+                self.fields = fields;
+            }
+            Update::Diff { epoch, version, .. }
+                if (epoch, version) <= (self.epoch, self.version) => {}
+            Update::Diff { epoch, .. } if epoch != self.epoch => {
+                return Err(SyncError::Recreated {
+                    expected: self.epoch,
+                    got: epoch,
+                });
+            }
+            Update::Diff {
+                base,
+                version,
+                set,
+                removed,
+                ..
+            } => {
+                if base != self.version {
+                    return Err(SyncError::Gap {
+                        expected: self.version,
+                        got: base,
+                    });
+                }
+                for k in removed {
+                    self.fields.remove(&k);
+                }
+                self.fields.extend(set);
+                self.version = version;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn diff_test() {
+        let sync = StateSync::new();
+        let mut rx = sync.subscribe("doc");
+        let mut replica = Replica::new("doc");
+        replica.apply(rx.recv().await.unwrap()).unwrap();
+
+        sync.update("doc", |d| {
+            d.insert("a".to_string(), 1);
+            d.insert("b".to_string(), 2);
+        });
+        sync.update("doc", |d| {
+            d.remove("a");
+            d.insert("b".to_string(), 2);
+            d.insert("c".to_string(), 3);
+        });
+        // nothing changed, so nothing is sent
+        assert_eq!(sync.update("doc", |_| {}), 2);
+
+        replica.apply(rx.recv().await.unwrap()).unwrap();
+        let diff = rx.recv().await.unwrap();
+        assert_eq!(
+            diff,
+            Update::Diff {
+                name: "doc".to_string(),
+                epoch: 1,
+                base: 1,
+                version: 2,
+                set: Fields::from([("c".to_string(), 3)]),
+                removed: vec!["a".to_string()],
+            }
+        );
+        replica.apply(diff).unwrap();
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(replica.version(), 2);
+        assert_eq!(
+            replica.fields(),
+            &Fields::from([("b".to_string(), 2), ("c".to_string(), 3)])
+        );
+    }
+
+    #[tokio::test]
+    async fn gap_test() {
+        let sync = StateSync::new();
+        let mut rx = sync.subscribe("doc");
+        let mut replica = Replica::new("doc");
+        replica.apply(rx.recv().await.unwrap()).unwrap();
+
+        sync.update("doc", |d| {
+            d.insert("a".to_string(), 1);
+        });
+        sync.update("doc", |d| {
+            d.insert("a".to_string(), 2);
+        });
+
+        // first diff is lost
+        rx.recv().await.unwrap();
+        assert_eq!(
+            replica.apply(rx.recv().await.unwrap()),
+            Err(SyncError::Gap {
+                expected: 0,
+                got: 1
+            })
+        );
+
+        replica.apply(sync.snapshot("doc").unwrap()).unwrap();
+        assert_eq!(replica.version(), 2);
+        assert_eq!(replica.fields()["a"], 2);
+        assert_eq!(replica.apply(sync.snapshot("doc").unwrap()), Ok(()));
+        assert!(Replica::<i32>::new("other")
+            .apply(sync.snapshot("doc").unwrap())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn recreate_test() {
+        let sync = StateSync::new();
+        let mut rx = sync.subscribe("doc");
+        let mut replica = Replica::new("doc");
+        replica.apply(rx.recv().await.unwrap()).unwrap();
+        for n in 1..=3 {
+            sync.update("doc", |d| {
+                d.insert("a".to_string(), n);
+            });
+            replica.apply(rx.recv().await.unwrap()).unwrap();
+        }
+        let stale = sync.snapshot("doc").unwrap();
+        assert_eq!((replica.epoch(), replica.version()), (1, 3));
+
+        // the version of the new document restarts
+        assert!(sync.remove("doc"));
+        assert_eq!(rx.recv().await, None);
+        let mut rx = sync.subscribe("doc");
+        let snapshot = rx.recv().await.unwrap();
+        sync.update("doc", |d| {
+            d.insert("b".to_string(), 1);
+        });
+        let diff = rx.recv().await.unwrap();
+        assert_eq!((diff.epoch(), diff.version()), (2, 1));
+
+        // which is not taken for an old diff of the replica
+        assert_eq!(
+            replica.apply(diff.clone()),
+            Err(SyncError::Recreated {
+                expected: 1,
+                got: 2
+            })
+        );
+        replica.apply(snapshot).unwrap();
+        replica.apply(diff).unwrap();
+        assert_eq!(replica.fields(), &Fields::from([("b".to_string(), 1)]));
+
+        // a snapshot of the old document doesn't overwrite the new one
+        replica.apply(stale).unwrap();
+        assert_eq!((replica.epoch(), replica.version()), (2, 1));
+        assert_eq!(replica.fields(), &Fields::from([("b".to_string(), 1)]));
+    }
+
+    #[tokio::test]
+    async fn stale_snapshot_test() {
+        let sync = StateSync::new();
+        sync.update("doc", |d| {
+            d.insert("a".to_string(), 1);
+        });
+        let stale = sync.snapshot("doc").unwrap();
+        sync.update("doc", |d| {
+            d.insert("a".to_string(), 2);
+        });
+
+        let mut replica = Replica::new("doc");
+        replica.apply(sync.snapshot("doc").unwrap()).unwrap();
+        replica.apply(stale).unwrap();
+        assert_eq!(replica.version(), 2);
+        assert_eq!(replica.fields()["a"], 2);
+    }
+}
@@ -0,0 +1,349 @@
+//! Durable message store with consumer cursors
+//!
+//! `MessageStore` appends messages of topics to a table, each with an
+//! increasing sequence number. Named consumers read a topic with `Consumer`,
+//! which keeps a cursor (the last acknowledged sequence number) in another
+//! table, so a consumer continues where it left off after a restart:
+//!
+//! - `Consumer::poll` reads messages after the cursor
+//! - `Consumer::ack` moves the cursor forward after they are processed
+//! - `Consumer::reset` moves the cursor anywhere, to skip or replay messages
+//!
+//! Consumers with different names read the same topic independently, so the
+//! store works as a lightweight durable queue. Delivery is at-least-once:
+//! messages that are processed but not acknowledged are read again.
+//!
+//! The tables should look like this (in SQLite):
+//!
+//! ```sql
+//! CREATE TABLE messages (
+//!     seq INTEGER PRIMARY KEY AUTOINCREMENT,
+//!     topic TEXT NOT NULL,
+//!     payload BLOB NOT NULL
+//! );
+//! CREATE TABLE cursors (
+//!     consumer TEXT NOT NULL,
+//!     topic TEXT NOT NULL,
+//!     seq INTEGER NOT NULL,
+//!     PRIMARY KEY (consumer, topic)
+//! );
+//! ```
+//!
+//! This module needs the `database` feature.
+//!
+//! # Examples
+//!
+//! The examples use SQLite, so they run with the `sqlite` feature.
+//!
+#![cfg_attr(feature = "sqlite", doc = "```")]
+#![cfg_attr(not(feature = "sqlite"), doc = "```ignore")]
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::store::{MessageStore, StoredMessage};
+//! use sqlx::sqlite::SqlitePoolOptions;
+//!
+//! async fn print(msg: StoredMessage) -> Result<(), ()> {
+//!     println!("{}: {:?}", msg.seq, msg.payload);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), sqlx::Error> {
+//! let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+//! sqlx::query("CREATE TABLE messages (seq INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT, payload BLOB)")
+//!     .execute(&pool)
+//!     .await?;
+//! sqlx::query("CREATE TABLE cursors (consumer TEXT, topic TEXT, seq INTEGER, PRIMARY KEY (consumer, topic))")
+//!     .execute(&pool)
+//!     .await?;
+//!
+//! let store = MessageStore::new(pool);
+//! store.append("chat", b"hello".to_vec()).await?;
+//!
+//! let print = fn_handler(print);
+//! let consumer = store.consumer("archiver", "chat");
+//! assert_eq!(consumer.consume_pending(&print).await?, 1);
+//! assert_eq!(consumer.consume_pending(&print).await?, 0);
+//!
+//! // replay from the beginning
+//! consumer.reset(0).await?;
+//! assert_eq!(consumer.consume_pending(&print).await?, 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use sqlx::{Database, Decode, Encode, Executor, FromRow, IntoArguments, Pool, Type};
+
+use crate::handler::Handler;
+
+/// message read from `MessageStore`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredMessage {
+    /// sequence number, which increases in the order of append
+    pub seq: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// append-only store of messages
+pub struct MessageStore<DB: Database> {
+    pool: Pool<DB>,
+}
+
+impl<DB: Database> Clone for MessageStore<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<DB> MessageStore<DB>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> Vec<u8>: Encode<'q, DB> + Type<DB>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> i64: Decode<'r, DB> + Type<DB>,
+{
+    /// stores messages in the `messages` table of `pool`
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self { pool }
+    }
+
+    /// appends `payload` to `topic`, and returns its sequence number
+    pub async fn append<S: Into<String>>(
+        &self,
+        topic: S,
+        payload: Vec<u8>,
+    ) -> Result<i64, sqlx::Error> {
+        let (seq,): (i64,) =
+            sqlx::query_as("INSERT INTO messages (topic, payload) VALUES ($1, $2) RETURNING seq")
+                .bind(topic.into())
+                .bind(payload)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(seq)
+    }
+
+    /// returns the consumer of `topic` named `name`
+    pub fn consumer<N, S>(&self, name: N, topic: S) -> Consumer<DB>
+    where
+        N: Into<String>,
+        S: Into<String>,
+    {
+        Consumer {
+            pool: self.pool.clone(),
+            name: name.into(),
+            topic: topic.into(),
+            batch: 100,
+        }
+    }
+}
+
+/// named reader of a topic with a persisted cursor
+pub struct Consumer<DB: Database> {
+    pool: Pool<DB>,
+    name: String,
+    topic: String,
+    batch: i64,
+}
+
+impl<DB> Consumer<DB>
+where
+    DB: Database,
+    for<'q> DB::Arguments<'q>: IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> String: Encode<'q, DB> + Type<DB>,
+    for<'q> i64: Encode<'q, DB> + Type<DB>,
+    for<'r> i64: Decode<'r, DB>,
+    for<'r> (i64,): FromRow<'r, DB::Row>,
+    for<'r> (i64, String, Vec<u8>): FromRow<'r, DB::Row>,
+{
+    /// sets the largest number of messages that `consume_pending` reads at
+    /// once (100 by default)
+    pub fn batch(mut self, batch: i64) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    /// returns the name of the consumer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// returns the last acknowledged sequence number (0 if none)
+    pub async fn cursor(&self) -> Result<i64, sqlx::Error> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT seq FROM cursors WHERE consumer = $1 AND topic = $2")
+                .bind(self.name.clone())
+                .bind(self.topic.clone())
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map_or(0, |(seq,)| seq))
+    }
+
+    /// reads up to `limit` messages after the cursor in order.
+    /// The cursor doesn't move until they are acknowledged.
+    pub async fn poll(&self, limit: i64) -> Result<Vec<StoredMessage>, sqlx::Error> {
+        let cursor = self.cursor().await?;
+        let rows: Vec<(i64, String, Vec<u8>)> = sqlx::query_as(
+            "SELECT seq, topic, payload FROM messages WHERE topic = $1 AND seq > $2 ORDER BY seq LIMIT $3",
+        )
+        .bind(self.topic.clone())
+        .bind(cursor)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(seq, topic, payload)| StoredMessage {
+                seq,
+                topic,
+                payload,
+            })
+            .collect())
+    }
+
+    /// acknowledges every message up to `seq`.
+    /// The cursor never moves backward by this (see `reset`).
+    pub async fn ack(&self, seq: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO cursors (consumer, topic, seq) VALUES ($1, $2, $3) \
+             ON CONFLICT (consumer, topic) DO UPDATE SET seq = excluded.seq \
+             WHERE excluded.seq > cursors.seq",
+        )
+        .bind(self.name.clone())
+        .bind(self.topic.clone())
+        .bind(seq)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// moves the cursor to `seq`, so that messages after `seq` are read
+    /// next. `reset(0)` replays the topic from the beginning.
+    pub async fn reset(&self, seq: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO cursors (consumer, topic, seq) VALUES ($1, $2, $3) \
+             ON CONFLICT (consumer, topic) DO UPDATE SET seq = excluded.seq",
+        )
+        .bind(self.name.clone())
+        .bind(self.topic.clone())
+        .bind(seq)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// calls `handler` with pending messages in order, acknowledging each
+    /// after the handler succeeds. It stops at the first message that
+    /// fails, which is read again later. Returns the number of consumed
+    /// messages.
+    pub async fn consume_pending<H>(&self, handler: &H) -> Result<usize, sqlx::Error>
+    where
+        H: Handler<StoredMessage>,
+    {
+        let mut consumed = 0;
+        for msg in self.poll(self.batch).await? {
+            let seq = msg.seq;
+            if handler.call(msg).await.is_err() {
+                break;
+            }
+            self.ack(seq).await?;
+            consumed += 1;
+        }
+        Ok(consumed)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use futures::future::{err, ok, Ready};
+    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+
+    use super::*;
+
+    async fn store() -> Result<MessageStore<sqlx::Sqlite>, sqlx::Error> {
+        let pool: SqlitePool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::query(
+            "CREATE TABLE messages (seq INTEGER PRIMARY KEY AUTOINCREMENT, topic TEXT, payload BLOB)",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE cursors (consumer TEXT, topic TEXT, seq INTEGER, PRIMARY KEY (consumer, topic))",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(MessageStore::new(pool))
+    }
+
+    /// handler that fails at payload 0
+    struct NonZero;
+
+    impl Handler<StoredMessage> for NonZero {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, msg: StoredMessage) -> Self::Future {
+            if msg.payload[0] == 0 {
+                err(())
+            } else {
+                ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_test() -> Result<(), sqlx::Error> {
+        let store = store().await?;
+        for (topic, n) in [("a", 1), ("b", 1), ("a", 2), ("a", 3)] {
+            store.append(topic, vec![n]).await?;
+        }
+
+        let first = store.consumer("first", "a");
+        let second = store.consumer("second", "a");
+        assert_eq!(first.cursor().await?, 0);
+
+        let polled = first.poll(2).await?;
+        let seqs: Vec<_> = polled.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![1, 3]);
+
+        first.ack(3).await?;
+        assert_eq!(first.cursor().await?, 3);
+        first.ack(1).await?;
+        assert_eq!(first.cursor().await?, 3);
+        assert_eq!(first.poll(10).await?.len(), 1);
+
+        // consumers are independent
+        assert_eq!(second.poll(10).await?.len(), 3);
+
+        first.reset(1).await?;
+        assert_eq!(first.poll(10).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consume_test() -> Result<(), sqlx::Error> {
+        let store = store().await?;
+        for n in [1, 2, 0, 3] {
+            store.append("a", vec![n]).await?;
+        }
+
+        let consumer = store.consumer("c", "a").batch(10);
+        assert_eq!(consumer.consume_pending(&NonZero).await?, 2);
+        assert_eq!(consumer.cursor().await?, 2);
+        assert_eq!(consumer.consume_pending(&NonZero).await?, 0);
+
+        consumer.ack(3).await?;
+        assert_eq!(consumer.consume_pending(&NonZero).await?, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! Bridges between handlers and `futures` streams/sinks
+//!
+//! - `HandlerSink` turns a handler into a `Sink`. It keeps at most
+//!   `max_in_flight` calls running, and is not ready for more messages until
+//!   one of them finishes, so producers are slowed down by the handler.
+//! - `drive` passes every item of a `Stream` to a handler one by one.
+//!   The next item is pulled only after the previous call finished.
+//! - `call_all` turns a `Stream` of messages into a `Stream` of outputs.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::stream::{call_all, drive, HandlerSink};
+//! use futures::{stream, SinkExt, StreamExt};
+//!
+//! async fn double(i: i32) -> Result<i32, ()> {
+//!     Ok(i * 2)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let handler = fn_handler(double);
+//!
+//! let outputs: Vec<_> = call_all(&handler, stream::iter(1..=3)).collect().await;
+//! assert_eq!(outputs, vec![Ok(2), Ok(4), Ok(6)]);
+//!
+//! drive(stream::iter(1..=3), &handler).await?;
+//!
+//! let mut sink = HandlerSink::new(handler, 2);
+//! sink.send_all(&mut stream::iter(1..=3).map(Ok)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::FuturesUnordered;
+use futures::{Sink, Stream, StreamExt};
+
+use crate::handler::Handler;
+
+/// `Sink` that calls a handler with every item.
+/// Outputs are discarded, and the first error is returned from the sink.
+pub struct HandlerSink<H, T>
+where
+    H: Handler<T>,
+{
+    handler: H,
+    in_flight: FuturesUnordered<H::Future>,
+    max_in_flight: usize,
+}
+
+// fields are never pinned structurally
+impl<H, T> Unpin for HandlerSink<H, T> where H: Handler<T> {}
+
+impl<H, T> HandlerSink<H, T>
+where
+    H: Handler<T>,
+{
+    /// makes a sink that runs at most `max_in_flight` calls at the same time.
+    /// `max_in_flight` is at least 1.
+    pub fn new(handler: H, max_in_flight: usize) -> Self {
+        Self {
+            handler,
+            in_flight: FuturesUnordered::new(),
+            max_in_flight: max_in_flight.max(1),
+        }
+    }
+
+    /// returns the number of calls that are running
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// polls running calls until none of them is ready
+    fn poll_in_flight(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), H::Error>> {
+        loop {
+            match self.in_flight.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<H, T> Sink<T> for HandlerSink<H, T>
+where
+    H: Handler<T>,
+{
+    type Error = H::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(e)) = this.poll_in_flight(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if this.in_flight.len() < this.max_in_flight {
+            Poll::Ready(Ok(()))
+        } else {
+            // waker is registered by `poll_in_flight`
+            Poll::Pending
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let future = this.handler.call(item);
+        this.in_flight.push(future);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_in_flight(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// calls `handler` with every item of `stream` in order.
+/// Stops at the first error.
+pub async fn drive<S, H>(stream: S, handler: &H) -> Result<(), H::Error>
+where
+    S: Stream,
+    H: Handler<S::Item>,
+{
+    futures::pin_mut!(stream);
+    while let Some(msg) = stream.next().await {
+        handler.call(msg).await?;
+    }
+    Ok(())
+}
+
+/// returns a stream of results of calling `handler` with every item of
+/// `stream`, in order
+pub fn call_all<'a, S, H>(
+    handler: &'a H,
+    stream: S,
+) -> impl Stream<Item = Result<H::Output, H::Error>> + 'a
+where
+    S: Stream + 'a,
+    H: Handler<S::Item>,
+{
+    stream.then(move |msg| handler.call(msg))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::LocalBoxFuture;
+    use futures::{stream, SinkExt};
+
+    use super::*;
+
+    /// counts running calls and remembers the maximum
+    struct Slow {
+        running: Arc<AtomicUsize>,
+        max: Arc<AtomicUsize>,
+    }
+
+    impl Handler<u32> for Slow {
+        type Output = ();
+        type Error = u32;
+        type Future = LocalBoxFuture<'static, Result<(), u32>>;
+
+        fn call(&self, msg: u32) -> Self::Future {
+            let running = self.running.clone();
+            let max = self.max.clone();
+
+            Box::pin(async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                if msg == 0 {
+                    Err(msg)
+                } else {
+                    Ok(())
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_test() {
+        let max = Arc::new(AtomicUsize::new(0));
+        let handler = Slow {
+            running: Arc::new(AtomicUsize::new(0)),
+            max: max.clone(),
+        };
+
+        let mut sink = HandlerSink::new(handler, 2);
+        sink.send_all(&mut stream::iter(1..=10).map(Ok))
+            .await
+            .unwrap();
+        assert_eq!(sink.in_flight(), 0);
+        assert_eq!(max.load(Ordering::SeqCst), 2);
+
+        // error is returned from the sink
+        sink.feed(0).await.unwrap();
+        assert_eq!(sink.flush().await, Err(0));
+    }
+
+    #[tokio::test]
+    async fn drive_test() {
+        let max = Arc::new(AtomicUsize::new(0));
+        let handler = Slow {
+            running: Arc::new(AtomicUsize::new(0)),
+            max: max.clone(),
+        };
+
+        drive(stream::iter(1..=3), &handler).await.unwrap();
+        assert_eq!(max.load(Ordering::SeqCst), 1);
+        assert_eq!(drive(stream::iter([1, 0, 2]), &handler).await, Err(0));
+    }
+}
@@ -0,0 +1,175 @@
+//! Timer that fires items at their time
+//!
+//! `Timer` keeps items with the instant to fire them, and a background task
+//! calls a function with each item when its time comes. Items of the same
+//! instant are fired in the order they were scheduled. Scheduled items can
+//! be cancelled by the `TimerId` returned from `schedule`.
+//!
+//! The task stops when every clone of the timer is dropped, and items that
+//! are not fired yet are dropped with it. Items only live in memory; see
+//! `scheduled` for items that survive restarts.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use cubby_connect_server_core::timer::Timer;
+//! use tokio::sync::mpsc::unbounded_channel;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let (tx, mut rx) = unbounded_channel();
+//! let timer = Timer::spawn(move |msg| {
+//!     let _ = tx.send(msg);
+//! });
+//!
+//! timer.schedule_after(Duration::from_millis(20), "later");
+//! let id = timer.schedule_after(Duration::from_millis(10), "never");
+//! timer.schedule_after(Duration::from_millis(0), "now");
+//! timer.cancel(id);
+//!
+//! assert_eq!(rx.recv().await, Some("now"));
+//! assert_eq!(rx.recv().await, Some("later"));
+//! # }
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep_until, Instant};
+
+/// id of a scheduled item, unique in its `Timer`
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TimerId(pub u64);
+
+enum Command<T> {
+    Schedule(TimerId, Instant, T),
+    Cancel(TimerId),
+}
+
+/// handle of a timer task.
+/// Clones share the same task.
+pub struct Timer<T> {
+    commands: UnboundedSender<Command<T>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<T> Clone for Timer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Timer<T> {
+    /// spawns the task of a timer, which calls `fire` with each item at its
+    /// time
+    pub fn spawn<F>(fire: F) -> Self
+    where
+        F: FnMut(T) + Send + 'static,
+    {
+        let (commands, rx) = unbounded_channel();
+        tokio::spawn(run(rx, fire));
+        Self {
+            commands,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+}
+
+impl<T> Timer<T> {
+    /// fires `item` at `at`, or as soon as possible if `at` has passed
+    pub fn schedule(&self, at: Instant, item: T) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let _ = self.commands.send(Command::Schedule(id, at, item));
+        id
+    }
+
+    /// fires `item` after `after`
+    pub fn schedule_after(&self, after: Duration, item: T) -> TimerId {
+        self.schedule(Instant::now() + after, item)
+    }
+
+    /// drops the item of `id` if it is not fired yet
+    pub fn cancel(&self, id: TimerId) {
+        let _ = self.commands.send(Command::Cancel(id));
+    }
+}
+
+async fn run<T, F>(mut commands: UnboundedReceiver<Command<T>>, mut fire: F)
+where
+    F: FnMut(T),
+{
+    let mut queue = BinaryHeap::new();
+    let mut items = HashMap::new();
+
+    loop {
+        let now = Instant::now();
+        while let Some(&Reverse((at, id))) = queue.peek() {
+            if at > now {
+                break;
+            }
+            queue.pop();
+            // cancelled items are only removed from `items`
+            if let Some(item) = items.remove(&id) {
+                fire(item);
+            }
+        }
+
+        let next = queue.peek().map(|&Reverse((at, _))| at);
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Schedule(id, at, item)) => {
+                    queue.push(Reverse((at, id)));
+                    items.insert(id, item);
+                }
+                Some(Command::Cancel(id)) => {
+                    items.remove(&id);
+                }
+                None => return,
+            },
+            _ = sleep_until(next.unwrap_or(now)), if next.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn timer_test() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let timer = Timer::spawn({
+            let fired = fired.clone();
+            move |n| fired.lock().unwrap().push((n, Instant::now()))
+        });
+
+        let start = Instant::now();
+        timer.schedule(start + Duration::from_secs(2), 2);
+        timer.schedule(start + Duration::from_secs(1), 1);
+        let cancelled = timer.schedule(start + Duration::from_secs(1), 0);
+        timer.schedule(start + Duration::from_secs(1), 3);
+        timer.cancel(cancelled);
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let fired = fired.lock().unwrap().clone();
+        let fired: Vec<_> = fired.into_iter().map(|(n, at)| (n, at - start)).collect();
+        assert_eq!(
+            fired,
+            vec![
+                (1, Duration::from_secs(1)),
+                (3, Duration::from_secs(1)),
+                (2, Duration::from_secs(2)),
+            ]
+        );
+    }
+}
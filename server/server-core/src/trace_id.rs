@@ -0,0 +1,215 @@
+//! Trace ids that follow a message through every delivery
+//!
+//! A single user action can cause many deliveries: a published message is
+//! fanned out to every subscriber, and a message can be relayed to another
+//! connection. `Traced<T>` carries the `TraceId` of the originating request
+//! with the message, so that every resulting delivery can be traced back.
+//!
+//! The id of the request that is being handled is usually in the context,
+//! and messages made while handling it should keep the same id with
+//! `Traced::with`.
+//!
+//! On the wire, the id is `Headers::trace_id` of the envelope, so it goes
+//! to other connections and nodes with the message. `Traced<T>` turns into
+//! `Envelope<T>` with the id in its headers, and back with
+//! `Traced::from_envelope`. Relays forward envelopes with their headers as
+//! they are, so the id is kept on every hop.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::trace_id::{TraceId, Traced};
+//!
+//! let request = Traced::new("buy sword");
+//! // messages made from the request keep the same id
+//! let event = request.child("sword sold");
+//! assert_eq!(request.trace_id, event.trace_id);
+//! assert_ne!(request.trace_id, TraceId::new());
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::context::{Context, FromContext};
+use crate::envelope::Envelope;
+
+/// id that is shared by every message caused by one request
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TraceId(pub u128);
+
+impl TraceId {
+    /// returns a new id that is unique across processes (in practice).
+    ///
+    /// Upper 64 bits are random per process and lower 64 bits are a counter.
+    pub fn new() -> Self {
+        static PROCESS: OnceLock<u64> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let process = *PROCESS.get_or_init(|| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.finish()
+        });
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self((u128::from(process) << 64) | u128::from(count))
+    }
+}
+
+impl Default for TraceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for TraceId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// trace id of the request that is being handled
+impl FromContext for TraceId {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<TraceId>().copied()
+    }
+}
+
+/// message with the trace id of its origin
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Traced<T> {
+    pub trace_id: TraceId,
+    pub msg: T,
+}
+
+impl<T> Traced<T> {
+    /// starts a new trace with `msg`
+    pub fn new(msg: T) -> Self {
+        Self::with(TraceId::new(), msg)
+    }
+
+    /// `msg` in the trace of `trace_id`
+    pub fn with(trace_id: TraceId, msg: T) -> Self {
+        Self { trace_id, msg }
+    }
+
+    /// another message in the same trace
+    pub fn child<U>(&self, msg: U) -> Traced<U> {
+        Traced::with(self.trace_id, msg)
+    }
+
+    /// changes the message keeping the trace id
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Traced<U> {
+        Traced::with(self.trace_id, f(self.msg))
+    }
+
+    /// message of `env` in the trace of its headers, or in a new trace
+    /// when it has none
+    pub fn from_envelope(env: Envelope<T>) -> Self {
+        Self::with(env.headers.trace_id.unwrap_or_default(), env.msg)
+    }
+}
+
+/// envelope with the trace id in its headers, so that the id goes over the
+/// wire with the message
+impl<T> From<Traced<T>> for Envelope<T> {
+    fn from(traced: Traced<T>) -> Self {
+        let mut env = Envelope::new(traced.msg);
+        env.headers.trace_id = Some(traced.trace_id);
+        env
+    }
+}
+
+/// bytes of the message, so that it can be relayed as is
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Traced<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.msg.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use futures::future::{ok, Ready};
+
+    use crate::handler::Handler;
+    use crate::relay::{Relay, RelayLimits};
+
+    use super::*;
+
+    #[test]
+    fn trace_id_test() {
+        let a = TraceId::new();
+        let b = TraceId::new();
+        assert_ne!(a, b);
+        assert_eq!(a.0 >> 64, b.0 >> 64);
+        assert_eq!(a.to_string().len(), 32);
+
+        let mut context = Context::new();
+        assert_eq!(TraceId::from_context(&context), None);
+        context.insert(a);
+        assert_eq!(TraceId::from_context(&context), Some(a));
+    }
+
+    struct Outgoing(Mutex<Vec<TraceId>>);
+
+    impl Handler<Traced<Vec<u8>>> for &Outgoing {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, msg: Traced<Vec<u8>>) -> Self::Future {
+            self.0.lock().unwrap().push(msg.trace_id);
+            ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_test() {
+        let a = Outgoing(Mutex::new(Vec::new()));
+        let b = Outgoing(Mutex::new(Vec::new()));
+        let relay = Relay::new(RelayLimits::default());
+        relay.bind(1, &a, 2, &b).unwrap();
+
+        let msg = Traced::new(vec![1, 2, 3]);
+        let trace_id = msg.trace_id;
+        relay.call((1, msg)).await.unwrap();
+        assert_eq!(*b.0.lock().unwrap(), vec![trace_id]);
+        assert_eq!(relay.stats(&1).unwrap().sent.bytes, 3);
+    }
+
+    /// sends encoded envelopes to another node
+    struct Node(Mutex<Vec<Vec<u8>>>);
+
+    impl Handler<Envelope<Vec<u8>>> for &Node {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, env: Envelope<Vec<u8>>) -> Self::Future {
+            self.0.lock().unwrap().push(env.encode());
+            ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn envelope_relay_test() {
+        let a = Node(Mutex::new(Vec::new()));
+        let b = Node(Mutex::new(Vec::new()));
+        let relay = Relay::new(RelayLimits::default());
+        relay.bind(1, &a, 2, &b).unwrap();
+
+        let msg = Traced::new(vec![1, 2, 3]);
+        let trace_id = msg.trace_id;
+        relay.call((1, Envelope::from(msg))).await.unwrap();
+        assert_eq!(relay.stats(&1).unwrap().sent.bytes, 3);
+
+        // the other node reads the same trace id from the wire
+        let frame = b.0.lock().unwrap().pop().unwrap();
+        let traced = Traced::from_envelope(Envelope::decode(&frame).unwrap());
+        assert_eq!(traced, Traced::with(trace_id, vec![1, 2, 3]));
+    }
+}
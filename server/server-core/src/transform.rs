@@ -0,0 +1,138 @@
+//! Layers made of a single transform
+//!
+//! Many layers only change the message before the next handler gets it,
+//! but a `Layer` impl still needs a handler type and several associated
+//! types. `#[layer::layer]` on an impl block with
+//!
+//! ```ignore
+//! async fn transform(&self, input: T) -> Result<U, E>
+//! ```
+//!
+//! implements `Transform<T>` and `Layer<T, H>` for the type instead. Its
+//! handlers are `TransformHandler`s, which call `transform` and pass the
+//! output to the next handler. An error of `transform` is converted into
+//! the error of the next handler with `Into`.
+//!
+//! The type must be `Clone` (each handler owns a clone) and `'static`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::layer::layer;
+//! use cubby_connect_server_core::apply;
+//!
+//! #[derive(Clone)]
+//! struct Prefix(&'static str);
+//!
+//! #[layer]
+//! impl Prefix {
+//!     async fn transform(&self, msg: String) -> Result<String, ()> {
+//!         Ok(format!("{}{msg}", self.0))
+//!     }
+//! }
+//!
+//! async fn len(msg: String) -> Result<usize, ()> {
+//!     Ok(msg.len())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), ()> {
+//! let handler = apply!(Prefix("> ") to len);
+//! assert_eq!(handler.call(String::from("hello")).await?, 7);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use futures::future::LocalBoxFuture;
+
+use crate::handler::Handler;
+
+/// transform of a message before the next handler.
+/// It is usually implemented by `#[layer::layer]`.
+pub trait Transform<T> {
+    /// message to the next handler
+    type Output: 'static;
+
+    /// error when the message cannot be transformed
+    type Error: 'static;
+
+    /// transforms `input`
+    fn run(self: Arc<Self>, input: T)
+        -> LocalBoxFuture<'static, Result<Self::Output, Self::Error>>;
+}
+
+/// `Handler` that transforms messages with `L` and calls the previous
+/// handler with them
+pub struct TransformHandler<L, H> {
+    transform: Arc<L>,
+    prev: Arc<H>,
+}
+
+impl<L, H> TransformHandler<L, H> {
+    /// returns a handler that calls `prev` with messages transformed by
+    /// `transform`
+    pub fn new(transform: L, prev: H) -> Self {
+        Self {
+            transform: Arc::new(transform),
+            prev: Arc::new(prev),
+        }
+    }
+}
+
+impl<T, L, H> Handler<T> for TransformHandler<L, H>
+where
+    L: Transform<T>,
+    L::Error: Into<H::Error>,
+    H: Handler<L::Output> + 'static,
+    H::Future: 'static,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = LocalBoxFuture<'static, Result<H::Output, H::Error>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        let transformed = self.transform.clone().run(msg);
+        let prev = self.prev.clone();
+        Box::pin(async move {
+            let msg = transformed.await.map_err(Into::into)?;
+            prev.call(msg).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::{ready, Ready};
+
+    use crate::fn_handler::fn_handler;
+
+    use super::*;
+
+    struct Parse;
+
+    impl Transform<&'static str> for Parse {
+        type Output = i32;
+        type Error = &'static str;
+
+        fn run(
+            self: Arc<Self>,
+            input: &'static str,
+        ) -> LocalBoxFuture<'static, Result<i32, Self::Error>> {
+            Box::pin(async move { input.parse().map_err(|_| "not a number") })
+        }
+    }
+
+    fn double(n: i32) -> Ready<Result<i32, String>> {
+        ready(Ok(n * 2))
+    }
+
+    #[tokio::test]
+    async fn transform_test() {
+        let handler = TransformHandler::new(Parse, fn_handler(double));
+        assert_eq!(handler.call("21").await, Ok(42));
+        assert_eq!(handler.call("x").await, Err(String::from("not a number")));
+    }
+}
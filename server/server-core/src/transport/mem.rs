@@ -0,0 +1,169 @@
+//! In-memory transport for tests
+//!
+//! Testing a whole pipeline with real sockets needs ports and certificates.
+//! `MemListener` serves in-memory connections (like `tokio::io::duplex`)
+//! with the same frames and handlers as other transports. Clients connect
+//! with `MemConnector` in the same process.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::transport::mem::MemListener;
+//! use cubby_connect_server_core::transport::{read_frame, write_frame};
+//! use prost::Message;
+//! use std::sync::Arc;
+//!
+//! async fn upper(s: String) -> Result<String, ()> {
+//!     Ok(s.to_uppercase())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let listener = Arc::new(MemListener::new());
+//! let connector = listener.connector();
+//! let server = listener.clone();
+//! tokio::spawn(async move { server.run(fn_handler(upper)).await });
+//!
+//! let mut stream = connector.connect()?;
+//! write_frame(&mut stream, &"hello".to_string().encode_to_vec()).await?;
+//! let frame = read_frame(&mut stream).await?.unwrap();
+//! assert_eq!(String::decode(frame.as_slice())?, "HELLO");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::sync::Arc;
+
+use prost::Message;
+use tokio::io::{duplex, DuplexStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex};
+
+use crate::handler::Handler;
+use crate::transport::{serve_stream, TransportError};
+
+/// buffer size of each direction of a connection
+const BUFFER: usize = 64 * 1024;
+
+/// connects to `MemListener`
+#[derive(Clone, Debug)]
+pub struct MemConnector {
+    tx: UnboundedSender<DuplexStream>,
+}
+
+impl MemConnector {
+    /// opens a connection and returns the client side of it
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = duplex(BUFFER);
+        self.tx
+            .send(server)
+            .map_err(|_| io::Error::new(io::ErrorKind::ConnectionRefused, "listener is dropped"))?;
+        Ok(client)
+    }
+}
+
+/// listener of in-memory connections
+pub struct MemListener {
+    tx: UnboundedSender<DuplexStream>,
+    rx: Mutex<UnboundedReceiver<DuplexStream>>,
+    closed: watch::Sender<bool>,
+}
+
+impl Default for MemListener {
+    fn default() -> Self {
+        let (tx, rx) = unbounded_channel();
+        Self {
+            tx,
+            rx: Mutex::new(rx),
+            closed: watch::channel(false).0,
+        }
+    }
+}
+
+impl MemListener {
+    /// returns a listener without any connection
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// returns a connector to this listener
+    pub fn connector(&self) -> MemConnector {
+        MemConnector {
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// closes every connection and stops `run`
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// serves connections with `handler` until the listener is closed.
+    /// Connections made before `run` are served as well.
+    pub async fn run<M, H>(&self, handler: H) -> Result<(), TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<M> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(handler);
+        let mut closed = self.closed.subscribe();
+        let mut rx = self.rx.lock().await;
+
+        loop {
+            let stream = tokio::select! {
+                // the listener holds a sender, so this never ends
+                Some(stream) = rx.recv() => stream,
+                _ = closed.wait_for(|closed| *closed) => return Ok(()),
+            };
+
+            let handler = handler.clone();
+            let mut closed = self.closed.subscribe();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = serve_stream(handler.as_ref(), stream) => {}
+                    _ = closed.wait_for(|closed| *closed) => {}
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fn_handler::fn_handler;
+    use crate::transport::{read_frame, write_frame};
+
+    use super::*;
+
+    async fn len(s: String) -> Result<u32, ()> {
+        Ok(s.len() as u32)
+    }
+
+    #[tokio::test]
+    async fn mem_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = Arc::new(MemListener::new());
+        let connector = listener.connector();
+        // connected before run
+        let mut a = connector.connect()?;
+
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(len)).await });
+        let mut b = connector.connect()?;
+
+        for (stream, s) in [(&mut a, "abc"), (&mut b, "hello")] {
+            write_frame(stream, &s.to_string().encode_to_vec()).await?;
+            let frame = read_frame(stream).await?.unwrap();
+            assert_eq!(u32::decode(frame.as_slice())? as usize, s.len());
+        }
+
+        listener.close();
+        task.await??;
+        // connection is closed by the server
+        assert_eq!(read_frame(&mut a).await?, None);
+        Ok(())
+    }
+}
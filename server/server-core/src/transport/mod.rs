@@ -0,0 +1,450 @@
+//! Transports that feed messages from the network into handlers
+//!
+//! Every transport carries the same frames (see `codec::framed`): a 4-byte
+//! big-endian length followed by a protobuf message. Each frame read from a stream is decoded
+//! into the message type of the handler and passed to the handler. The
+//! output of the handler is encoded and written back to the same stream as
+//! a frame, unless it is empty (e.g. `()`).
+//!
+//! - `quic`: QUIC connections, each bidirectional stream is served separately
+//!   (with the `quic` feature)
+//! - `mem`: in-memory connections for tests, without any socket
+//! - `tcp`: TCP connections with optional TLS, for networks that block UDP
+//! - `uds`: Unix domain sockets (named pipes on Windows) for processes on the
+//!   same host
+//!
+//! `udp` is different from others: each datagram is a message without
+//! length, and messages can be lost, duplicated or reordered.
+//!
+//! Sockets of `quic`, `tcp` and `udp` are bound with
+//! `Config::socket_options` (`SO_REUSEPORT`, backlog, `TCP_NODELAY`, buffer
+//! sizes and type of service).
+//!
+//! TLS of `quic` and `tcp` can verify certificates of clients too (see
+//! `mtls`).
+
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::future::pending;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::stream::{FuturesOrdered, StreamExt};
+use futures::FutureExt;
+use prost::Message;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::codec::compress::{Compression, DeflateContext};
+use crate::codec::framed::{FrameCodec, FramedRead};
+use crate::config::Config;
+use crate::context::{Context, FromContext, Request};
+use crate::handler::Handler;
+use crate::heartbeat::{self, Pinger};
+
+pub mod mem;
+pub mod mtls;
+#[cfg(feature = "quic")]
+pub mod quic;
+mod socket;
+pub mod tcp;
+pub mod udp;
+pub mod uds;
+
+pub use crate::codec::framed::{read_frame, write_frame, MAX_FRAME};
+
+/// ALPN protocol name of connections
+pub const ALPN: &[u8] = b"cubby-connect";
+
+/// number of requests of a connection that are handled at once, beyond
+/// which no more frames are read until one is done
+const MAX_IN_FLIGHT: usize = 32;
+
+/// error when running transport
+#[derive(Debug)]
+pub enum TransportError {
+    /// error from socket or files
+    Io(io::Error),
+
+    /// invalid certificate or key
+    Tls(rustls::Error),
+
+    /// `key_path` or `cert_path` is not set in `Config`
+    NoTls,
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "io error: {e}"),
+            TransportError::Tls(e) => write!(f, "tls error: {e}"),
+            TransportError::NoTls => write!(f, "key or certificate is not configured"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<rustls::Error> for TransportError {
+    fn from(e: rustls::Error) -> Self {
+        TransportError::Tls(e)
+    }
+}
+
+/// time to wait after running out of file descriptors or memory
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// returns how long to wait before accepting again after `accept` failed
+/// with `e`, or `None` if the listener cannot accept any more.
+/// Receiving from UDP sockets is retried the same way.
+///
+/// A connection that is lost before it is accepted fails only itself, so
+/// the next one is accepted at once. So does a datagram, or an ICMP error
+/// of an earlier datagram (`ConnectionReset` on Windows). Running out of file descriptors
+/// (`EMFILE`, `ENFILE`) or memory lasts until some connections close, so
+/// accepting again waits a little instead of spinning.
+pub(crate) fn accept_retry(e: &io::Error) -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    if matches!(e.raw_os_error(), Some(libc::EBADF | libc::ENOTSOCK)) {
+        return None;
+    }
+
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::WouldBlock => Some(Duration::ZERO),
+        io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported => None,
+        _ => Some(ACCEPT_BACKOFF),
+    }
+}
+
+/// passes every frame of `reader` to `handler` until the stream is finished,
+/// and writes outputs to `writer`. Each output is flushed, so that it is not
+/// left in the buffer of TLS or a `BufWriter` while the peer waits.
+///
+/// Stream is closed with an error when a frame cannot be decoded or the
+/// handler fails.
+pub(crate) async fn serve<M, H, R, W>(handler: &H, reader: &mut R, writer: &mut W) -> io::Result<()>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Output: Message,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut frames = FramedRead::new(reader, FrameCodec::default());
+    while let Some(frame) = frames.next().await? {
+        let msg = M::decode(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let output = handler
+            .call(msg)
+            .await
+            .map_err(|_| io::Error::other("handler failed"))?
+            .encode_to_vec();
+        if !output.is_empty() {
+            write_frame(writer, &output).await?;
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await
+}
+
+/// compression of the frames of a connection, negotiated in the handshake
+/// (see `handshake::Accepted::insert_into`). Heartbeat frames are not
+/// compressed, since peers answer them before anything else.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FrameCompression {
+    compression: Compression,
+    deflate: Option<DeflateContext>,
+    threshold: usize,
+    context_takeover: bool,
+}
+
+impl FrameCompression {
+    /// returns the compression in `context`, which compresses frames that
+    /// are not smaller than `threshold` bytes, and retains the deflate
+    /// context with `context_takeover`
+    pub(crate) fn from_context(
+        context: &Context,
+        threshold: usize,
+        context_takeover: bool,
+    ) -> Self {
+        Self {
+            compression: Compression::from_context(context).unwrap_or_default(),
+            deflate: DeflateContext::from_context(context),
+            threshold,
+            context_takeover,
+        }
+    }
+
+    /// decompresses a frame from the peer
+    fn decompress(&self, frame: Bytes) -> io::Result<Bytes> {
+        let frame = match (self.compression, &self.deflate) {
+            (Compression::None, _) => return Ok(frame),
+            (_, Some(deflate)) => deflate.decompress(&frame),
+            (_, None) => Compression::decompress(&frame),
+        };
+        frame
+            .map(Bytes::from)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// compresses a frame to the peer, unless it is a ping
+    pub(crate) fn compress<'a>(&self, frame: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        if self.compression == Compression::None || heartbeat::is_ping(frame) {
+            return Ok(Cow::Borrowed(frame));
+        }
+        let frame = match (self.compression, &self.deflate) {
+            (Compression::Deflate, Some(deflate)) => {
+                deflate.compress(frame, self.threshold, self.context_takeover)
+            }
+            (compression, _) => compression.compress(frame, self.threshold),
+        };
+        frame.map(Cow::Owned).map_err(io::Error::other)
+    }
+}
+
+/// passes every frame of `reader` to `handler` as a `Request` with
+/// `context`, and writes outputs to `writer` like `serve`.
+/// Frames are decompressed and compressed with `compression`.
+/// Frames from `pushed` are written to `writer` in between.
+/// With `pinger`, pongs go to it, pings are answered, and the connection is
+/// closed when the peer is dead.
+///
+/// Up to `MAX_IN_FLIGHT` handlers run at once, while frames are still read
+/// and pushed, so a slow handler does not hold up pushes nor heartbeats.
+/// Outputs are written in the order of their requests.
+pub(crate) async fn serve_requests<M, H, R, W>(
+    handler: &H,
+    context: &Context,
+    reader: &mut R,
+    writer: &mut W,
+    compression: &FrameCompression,
+    mut pushed: Option<&mut UnboundedReceiver<Bytes>>,
+    pinger: Option<&Pinger>,
+) -> io::Result<()>
+where
+    M: Message + Default,
+    H: Handler<Request<M>>,
+    H::Output: Message,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut frames = FramedRead::new(reader, FrameCodec::default());
+    let dead = heartbeat::dead(pinger);
+    tokio::pin!(dead);
+    let mut calls = FuturesOrdered::new();
+    let mut finished = false;
+    while !finished || !calls.is_empty() {
+        // reading frames is cancel safe
+        tokio::select! {
+            frame = frames.next(), if !finished && calls.len() < MAX_IN_FLIGHT => {
+                let Some(frame) = frame? else {
+                    finished = true;
+                    continue;
+                };
+                if let Some(pinger) = pinger {
+                    if pinger.receive(&frame) {
+                        continue;
+                    }
+                    if let Some(pong) = heartbeat::answer(&frame) {
+                        write_frame(writer, &pong).await?;
+                        writer.flush().await?;
+                        continue;
+                    }
+                }
+                let frame = compression.decompress(frame)?;
+                let msg =
+                    M::decode(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                // only encoded outputs are kept, which are `Send`
+                let call = handler.call(Request::with_context(msg, context.clone()));
+                calls.push_back(call.map(|output| match output {
+                    Ok(output) => Ok(output.encode_to_vec()),
+                    Err(_) => Err(io::Error::other("handler failed")),
+                }));
+            }
+            Some(output) = calls.next() => {
+                let output = output?;
+                if !output.is_empty() {
+                    write_frame(writer, &compression.compress(&output)?).await?;
+                    writer.flush().await?;
+                }
+            }
+            Some(push) = next_push(&mut pushed) => {
+                write_frame(writer, &compression.compress(&push)?).await?;
+                writer.flush().await?;
+            }
+            _ = &mut dead => return Err(dead_peer()),
+        }
+    }
+
+    writer.flush().await
+}
+
+/// receives the next frame from `pushed`, or never without it
+async fn next_push(pushed: &mut Option<&mut UnboundedReceiver<Bytes>>) -> Option<Bytes> {
+    match pushed {
+        Some(pushed) => pushed.recv().await,
+        None => pending().await,
+    }
+}
+
+/// error when the peer missed too many heartbeats
+pub(crate) fn dead_peer() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "peer missed heartbeats")
+}
+
+/// stream of a connection, which can be encrypted or not
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// serves a connection that is a single stream
+pub(crate) async fn serve_stream<M, H, S>(handler: &H, stream: S) -> io::Result<()>
+where
+    M: Message + Default,
+    H: Handler<M>,
+    H::Output: Message,
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut reader, mut writer) = split(stream);
+    serve(handler, &mut reader, &mut writer).await
+}
+
+/// reads certificate chain in PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TransportError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader).collect::<Result<_, _>>()?)
+}
+
+/// reads the first private key in PEM file
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TransportError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        TransportError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no private key in file",
+        ))
+    })
+}
+
+/// address of `host` of `config` with `port`
+pub(crate) fn host_addr(config: &Config, port: u16) -> SocketAddr {
+    let (a, b, c, d) = config.host;
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port)
+}
+
+/// reads `key_path` and `cert_path` of `config`
+pub(crate) fn load_tls(
+    config: &Config,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), TransportError> {
+    match (&config.cert_path, &config.key_path) {
+        (Some(cert), Some(key)) => Ok((load_certs(cert)?, load_key(key)?)),
+        _ => Err(TransportError::NoTls),
+    }
+}
+
+/// makes TLS configuration of server with `certs` and `key`.
+/// Clients are asked for certificates only with `client_auth`.
+pub(crate) fn server_tls(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_auth: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<rustls::ServerConfig, TransportError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let builder = match client_auth {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder.with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(tls)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_test() -> io::Result<()> {
+        async fn upper(s: String) -> Result<String, ()> {
+            Ok(s.to_uppercase())
+        }
+
+        let handler = crate::fn_handler::fn_handler(upper);
+        let (mut client, server) = tokio::io::duplex(64);
+        let (mut reader, mut writer) = tokio::io::split(server);
+        let task = tokio::spawn(async move { serve(&handler, &mut reader, &mut writer).await });
+
+        write_frame(&mut client, &"hi".to_string().encode_to_vec()).await?;
+        let frame = read_frame(&mut client).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice()).unwrap(), "HI");
+
+        // broken message closes the stream
+        write_frame(&mut client, &[0xff]).await?;
+        assert!(task.await.unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn accept_retry_test() {
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(accept_retry(&aborted), Some(Duration::ZERO));
+        #[cfg(target_os = "linux")]
+        {
+            let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+            assert_eq!(accept_retry(&emfile), Some(ACCEPT_BACKOFF));
+            let ebadf = io::Error::from_raw_os_error(libc::EBADF);
+            assert_eq!(accept_retry(&ebadf), None);
+        }
+        assert_eq!(
+            accept_retry(&io::Error::from(io::ErrorKind::InvalidInput)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_buffered_test() -> io::Result<()> {
+        async fn upper(s: String) -> Result<String, ()> {
+            Ok(s.to_uppercase())
+        }
+
+        // outputs come out of the buffer while the stream is still open
+        let handler = crate::fn_handler::fn_handler(upper);
+        let (mut client, server) = tokio::io::duplex(64);
+        let (mut reader, writer) = tokio::io::split(server);
+        tokio::spawn(async move {
+            let mut writer = tokio::io::BufWriter::new(writer);
+            serve(&handler, &mut reader, &mut writer).await
+        });
+
+        for msg in ["hi", "bye"] {
+            write_frame(&mut client, &msg.to_string().encode_to_vec()).await?;
+            let frame = read_frame(&mut client).await?.unwrap();
+            assert_eq!(
+                String::decode(frame.as_slice()).unwrap(),
+                msg.to_uppercase()
+            );
+        }
+        Ok(())
+    }
+}
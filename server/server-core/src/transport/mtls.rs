@@ -0,0 +1,189 @@
+//! Mutual TLS: certificates of clients
+//!
+//! When `Config::client_ca_path` is set, TLS connections of `tcp` and `quic`
+//! ask clients for a certificate and verify it with the CA bundle of that
+//! file. `Config::client_auth` chooses whether clients without a
+//! certificate are still accepted (`ClientAuth::Optional`) or rejected
+//! (`ClientAuth::Required`).
+//!
+//! `Server` puts the verified certificate of a connection into the context
+//! of its requests as `ClientCert`, so handlers take it as an argument.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::config::{ClientAuth, Config};
+//! use cubby_connect_server_core::context::{extract, Missing};
+//! use cubby_connect_server_core::server::Server;
+//! use cubby_connect_server_core::transport::mtls::ClientCert;
+//!
+//! async fn whoami(_: String, cert: ClientCert) -> Result<String, Missing> {
+//!     Ok(cert.subject)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder()
+//!     .key_path("key.pem")
+//!     .cert_path("cert.pem")
+//!     .client_ca_path("clients-ca.pem")
+//!     .client_auth(ClientAuth::Required)
+//!     .build()
+//!     .unwrap();
+//!
+//! let server = Server::from_config(config).handler(extract(whoami)).run().await?;
+//! # server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rustls::pki_types::CertificateDer;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{ClientAuth, Config};
+use crate::context::{Context, FromContext};
+use crate::transport::{load_certs, TransportError};
+
+/// verified certificate of a client
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClientCert {
+    /// distinguished name of the subject, like `CN=alice, O=cubby`
+    pub subject: String,
+
+    /// DNS names, emails, URIs and IP addresses of the subject alternative
+    /// name extension
+    pub sans: Vec<String>,
+
+    /// the certificate itself
+    pub der: CertificateDer<'static>,
+}
+
+impl ClientCert {
+    /// parses a DER encoded certificate.
+    /// Returns `None` if it is not a valid X.509 certificate.
+    pub fn from_der(der: CertificateDer<'static>) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).ok()?;
+        let sans = match cert.subject_alternative_name().ok()? {
+            Some(san) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(san_name)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let subject = cert.subject().to_string();
+        Some(Self { subject, sans, der })
+    }
+
+    /// returns the certificate of the peer from the chain it presented,
+    /// which is the first one
+    pub(crate) fn from_chain(chain: Option<&[CertificateDer<'_>]>) -> Option<Self> {
+        Self::from_der(chain?.first()?.clone().into_owned())
+    }
+
+    /// returns the certificate of the peer of a QUIC connection
+    #[cfg(feature = "quic")]
+    pub(crate) fn from_quic(connection: &quinn::Connection) -> Option<Self> {
+        let identity = connection.peer_identity()?;
+        let chain = identity.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+        Self::from_chain(Some(&chain))
+    }
+}
+
+impl FromContext for ClientCert {
+    fn from_context(context: &Context) -> Option<Self> {
+        context.get::<ClientCert>().cloned()
+    }
+}
+
+/// returns a name of the subject alternative name as a string
+fn san_name(name: &GeneralName<'_>) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+            Some(name.to_string())
+        }
+        GeneralName::IPAddress(ip) => match *ip {
+            [a, b, c, d] => Some(IpAddr::from([*a, *b, *c, *d]).to_string()),
+            ip => <[u8; 16]>::try_from(ip)
+                .ok()
+                .map(|ip| IpAddr::from(ip).to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// makes a verifier of client certificates signed by `roots`.
+/// Clients without a certificate are accepted only with
+/// `ClientAuth::Optional`.
+pub fn client_verifier(
+    roots: Vec<CertificateDer<'static>>,
+    client_auth: ClientAuth,
+) -> Result<Arc<dyn ClientCertVerifier>, TransportError> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root)?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(store), provider);
+    let builder = match client_auth {
+        ClientAuth::Optional => builder.allow_unauthenticated(),
+        ClientAuth::Required => builder,
+    };
+    builder
+        .build()
+        .map_err(|e| TransportError::Tls(rustls::Error::General(e.to_string())))
+}
+
+/// reads `client_ca_path` of `config` into a verifier of client
+/// certificates. Returns `None` if it is not set.
+pub(crate) fn load_client_auth(
+    config: &Config,
+) -> Result<Option<Arc<dyn ClientCertVerifier>>, TransportError> {
+    match &config.client_ca_path {
+        Some(path) => Ok(Some(client_verifier(
+            load_certs(path)?,
+            config.client_auth,
+        )?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+
+    use super::*;
+
+    #[test]
+    fn from_der_test() -> Result<(), Box<dyn std::error::Error>> {
+        let mut params = CertificateParams::new(vec!["client.example".to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params
+            .subject_alt_names
+            .push(SanType::IpAddress("10.0.0.1".parse()?));
+        let cert = params.self_signed(&KeyPair::generate()?)?;
+
+        let client = ClientCert::from_der(cert.der().clone()).unwrap();
+        assert_eq!(client.subject, "CN=alice");
+        assert_eq!(client.sans, ["client.example", "10.0.0.1"]);
+        assert_eq!(&client.der, cert.der());
+
+        assert_eq!(ClientCert::from_der(CertificateDer::from(vec![1, 2])), None);
+        Ok(())
+    }
+
+    #[test]
+    fn client_verifier_test() {
+        // a verifier needs at least one root
+        assert!(client_verifier(Vec::new(), ClientAuth::Required).is_err());
+    }
+}
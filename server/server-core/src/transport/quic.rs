@@ -0,0 +1,251 @@
+//! QUIC transport
+//!
+//! `QuicListener` accepts QUIC connections on `Config::quic_port`. Every
+//! bidirectional stream that a client opens is served concurrently: frames
+//! are decoded and passed to the handler, and outputs are written back to the
+//! stream.
+//!
+//! The handler is shared by every connection, so it should be `Send + Sync`
+//! (see `fn_layer::send_fn_layer`).
+//!
+//! Clients are asked for certificates when `client_ca_path` is set in
+//! `Config` (see `mtls`).
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::transport::quic::QuicListener;
+//!
+//! async fn echo(s: String) -> Result<String, ()> {
+//!     Ok(s)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder()
+//!     .key_path("key.pem")
+//!     .cert_path("cert.pem")
+//!     .build()
+//!     .unwrap();
+//!
+//! let listener = QuicListener::bind(&config)?;
+//! listener.run(fn_handler(echo)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use prost::Message;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{
+    default_runtime, Connection, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+
+use crate::config::{Config, SocketOptions};
+use crate::handler::Handler;
+use crate::transport::mtls::load_client_auth;
+use crate::transport::{host_addr, load_tls, serve, server_tls, socket, TransportError};
+
+/// listener of QUIC connections
+pub struct QuicListener {
+    endpoint: Endpoint,
+}
+
+impl QuicListener {
+    /// binds to `host` and `quic_port` of `config` with its key,
+    /// certificate and socket options. QUIC always needs TLS, so `TransportError::NoTls` is
+    /// returned when they are not set.
+    pub fn bind(config: &Config) -> Result<Self, TransportError> {
+        let (certs, key) = load_tls(config)?;
+        let tls = server_tls(certs, key, load_client_auth(config)?)?;
+        let addr = host_addr(config, config.quic_port);
+        Self::bind_tls(addr, tls, &config.socket_options)
+    }
+
+    /// binds to `addr` with certificate chain and key in memory
+    pub fn bind_with(
+        addr: SocketAddr,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, TransportError> {
+        Self::bind_with_options(addr, certs, key, &SocketOptions::default())
+    }
+
+    /// binds to `addr` like `bind_with`, with `options` of the socket.
+    /// quinn may overwrite the type of service with ECN marks.
+    pub fn bind_with_options(
+        addr: SocketAddr,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        Self::bind_tls(addr, server_tls(certs, key, None)?, options)
+    }
+
+    /// binds to `addr` like `bind_with_options`, and verifies certificates
+    /// of clients with `client_auth` (see `mtls::client_verifier`)
+    pub fn bind_with_client_auth(
+        addr: SocketAddr,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        client_auth: Arc<dyn ClientCertVerifier>,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        Self::bind_tls(addr, server_tls(certs, key, Some(client_auth))?, options)
+    }
+
+    /// binds to `addr` with TLS configuration of server `tls`
+    fn bind_tls(
+        addr: SocketAddr,
+        tls: rustls::ServerConfig,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        let tls = QuicServerConfig::try_from(tls)
+            .map_err(|e| TransportError::Tls(rustls::Error::General(e.to_string())))?;
+        let runtime =
+            default_runtime().ok_or_else(|| io::Error::other("no async runtime found"))?;
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(ServerConfig::with_crypto(Arc::new(tls))),
+            socket::bind_udp(addr, options)?,
+            runtime,
+        )?;
+        Ok(Self { endpoint })
+    }
+
+    /// returns the address that is bound
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// closes every connection and stops `run`
+    pub fn close(&self) {
+        self.endpoint.close(0u32.into(), b"closed");
+    }
+
+    /// accepts connections and serves messages of type `M` with `handler`
+    /// until the listener is closed
+    pub async fn run<M, H>(&self, handler: H) -> Result<(), TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<M> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(handler);
+        self.run_with(move |connection| {
+            let handler = handler.clone();
+            async move {
+                while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        let served = serve(handler.as_ref(), &mut recv, &mut send).await;
+                        finish_bi(send, recv, served);
+                    });
+                }
+            }
+        })
+        .await
+    }
+
+    /// accepts connections and passes each to `serve` until the listener is
+    /// closed
+    pub(crate) async fn run_with<F, Fut>(&self, serve: F) -> Result<(), TransportError>
+    where
+        F: Fn(Connection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let serve = Arc::new(serve);
+        while let Some(incoming) = self.endpoint.accept().await {
+            let serve = serve.clone();
+            tokio::spawn(async move {
+                // failed handshakes are just dropped
+                if let Ok(connection) = incoming.await {
+                    serve(connection).await;
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// finishes a bidirectional stream that is served successfully, or resets
+/// it otherwise
+pub(crate) fn finish_bi(mut send: SendStream, mut recv: RecvStream, served: io::Result<()>) {
+    if served.is_ok() {
+        let _ = send.finish();
+    } else {
+        let _ = recv.stop(1u32.into());
+        let _ = send.reset(1u32.into());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quinn::crypto::rustls::QuicClientConfig;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use crate::fn_handler::fn_handler;
+    use crate::transport::{read_frame, write_frame, ALPN};
+
+    use super::*;
+
+    async fn upper(s: String) -> Result<String, ()> {
+        Ok(s.to_uppercase())
+    }
+
+    #[tokio::test]
+    async fn quic_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_der = CertificateDer::from(cert.cert);
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let listener = Arc::new(QuicListener::bind_with(
+            "127.0.0.1:0".parse()?,
+            vec![cert_der.clone()],
+            key.into(),
+        )?);
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse()?)?;
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls)?,
+        )));
+        let connection = client.connect(addr, "localhost")?.await?;
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        write_frame(&mut send, &"hello".to_string().encode_to_vec()).await?;
+        write_frame(&mut send, &"world".to_string().encode_to_vec()).await?;
+        send.finish()?;
+
+        let frame = read_frame(&mut recv).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice())?, "HELLO");
+        let frame = read_frame(&mut recv).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice())?, "WORLD");
+        assert_eq!(read_frame(&mut recv).await?, None);
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+//! Listening sockets with `SocketOptions`
+//!
+//! Sockets are made with `socket2`, so that options that must be set
+//! before `bind` or `listen` (`SO_REUSEPORT`, the backlog) can be set.
+//! Accepted TCP connections inherit the buffer sizes and the type of
+//! service of their listener.
+//!
+//! `bind_tcp_all` and `bind_udp_all` bind `SocketOptions::acceptors`
+//! sockets to the same port with `SO_REUSEPORT`, and attach the program of
+//! `SocketOptions::steering` to them on Linux.
+
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config::{SocketOptions, Steering};
+
+/// makes a socket of `ty` for `addr` with `options`, and binds it
+fn bind(
+    addr: SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    options: &SocketOptions,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if ty == Type::STREAM {
+        // like `std`, so that a restarted server binds the port at once
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    if options.reuse_port {
+        reuse_port(&socket)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(tos) = options.tos {
+        set_tos(&socket, addr, tos)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+fn set_tos(socket: &Socket, addr: SocketAddr, tos: u32) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_tos_v4(tos),
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        SocketAddr::V6(_) => socket.set_tclass_v6(tos),
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+        SocketAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "IPV6_TCLASS is not supported on this platform",
+        )),
+    }
+}
+
+/// binds a TCP listener to `addr` with `options`
+pub(crate) fn bind_tcp(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<tokio::net::TcpListener> {
+    let socket = bind(addr, Type::STREAM, Protocol::TCP, options)?;
+    let backlog = options.backlog.min(i32::MAX as u32) as i32;
+    socket.listen(backlog)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// binds a non-blocking UDP socket to `addr` with `options`, which is
+/// given to tokio or quinn
+pub(crate) fn bind_udp(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<std::net::UdpSocket> {
+    Ok(bind(addr, Type::DGRAM, Protocol::UDP, options)?.into())
+}
+
+/// returns the number of sockets to bind for `options`
+fn acceptors(options: &SocketOptions) -> usize {
+    match options.acceptors {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        n => n,
+    }
+}
+
+/// binds sockets of `options` to `addr` with `bind`. With more than one,
+/// they share the port of the first one, so `addr` may have port 0.
+fn bind_all<S, B, L>(
+    addr: SocketAddr,
+    options: &SocketOptions,
+    bind: B,
+    local_addr: L,
+) -> io::Result<Vec<S>>
+where
+    B: Fn(SocketAddr, &SocketOptions) -> io::Result<S>,
+    L: Fn(&S) -> io::Result<SocketAddr>,
+{
+    let n = acceptors(options);
+    if n == 1 {
+        return Ok(vec![bind(addr, options)?]);
+    }
+
+    let options = SocketOptions {
+        reuse_port: true,
+        ..options.clone()
+    };
+    let first = bind(addr, &options)?;
+    let addr = local_addr(&first)?;
+    let mut sockets = vec![first];
+    for _ in 1..n {
+        sockets.push(bind(addr, &options)?);
+    }
+    Ok(sockets)
+}
+
+/// binds TCP listeners to `addr` for every acceptor of `options`
+pub(crate) fn bind_tcp_all(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<Vec<tokio::net::TcpListener>> {
+    let listeners = bind_all(addr, options, bind_tcp, |listener| listener.local_addr())?;
+    steer(&listeners, options.steering)?;
+    Ok(listeners)
+}
+
+/// binds UDP sockets to `addr` for every acceptor of `options`
+pub(crate) fn bind_udp_all(
+    addr: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<Vec<std::net::UdpSocket>> {
+    let sockets = bind_all(addr, options, bind_udp, |socket| socket.local_addr())?;
+    steer(&sockets, options.steering)?;
+    Ok(sockets)
+}
+
+/// attaches the program of `steering` to the group of `sockets`. A program
+/// is shared by the group, so it is attached to the first socket.
+#[cfg(target_os = "linux")]
+fn steer<S: AsFd>(sockets: &[S], steering: Steering) -> io::Result<()> {
+    use libc::{BPF_A, BPF_ABS, BPF_ALU, BPF_K, BPF_LD, BPF_MOD, BPF_RET, BPF_W};
+
+    if sockets.len() < 2 {
+        return Ok(());
+    }
+    match steering {
+        Steering::Hash => Ok(()),
+        Steering::Cpu => {
+            // index of the socket is the CPU modulo the number of sockets
+            let cpu = (libc::SKF_AD_OFF + libc::SKF_AD_CPU) as u32;
+            let program = [
+                filter(BPF_LD | BPF_W | BPF_ABS, cpu),
+                filter(BPF_ALU | BPF_MOD | BPF_K, sockets.len() as u32),
+                filter(BPF_RET | BPF_A, 0),
+            ];
+            attach_cbpf(sockets[0].as_fd(), &program)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn steer<S>(_: &[S], steering: Steering) -> io::Result<()> {
+    match steering {
+        Steering::Hash => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "steering of SO_REUSEPORT is only supported on Linux",
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn filter(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// attaches a classic BPF program that returns the index of the socket of
+/// a packet to the group of `socket`
+#[cfg(target_os = "linux")]
+fn attach_cbpf(socket: BorrowedFd<'_>, program: &[libc::sock_filter]) -> io::Result<()> {
+    let program = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr().cast_mut(),
+    };
+    set_socket_option(socket, libc::SO_ATTACH_REUSEPORT_CBPF, &program)
+}
+
+/// attaches a loaded eBPF program to the group of `socket`
+#[cfg(target_os = "linux")]
+pub(crate) fn attach_ebpf(socket: BorrowedFd<'_>, program: BorrowedFd<'_>) -> io::Result<()> {
+    set_socket_option(socket, libc::SO_ATTACH_REUSEPORT_EBPF, &program.as_raw_fd())
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_option<T>(socket: BorrowedFd<'_>, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` points to a `T` of the given length, which the kernel
+    // only reads, and `socket` is open while it is borrowed
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            (value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn options_test() -> io::Result<()> {
+        let options = SocketOptions::builder()
+            .reuse_port(cfg!(unix))
+            .recv_buffer_size(64 * 1024)
+            .tos(0xb8)
+            .build()
+            .unwrap();
+
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), &options)?;
+        let socket = socket2::SockRef::from(&listener);
+        assert!(socket.recv_buffer_size()? >= 64 * 1024);
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tos_v4()?, 0xb8);
+
+        // another socket binds the same port with SO_REUSEPORT
+        #[cfg(unix)]
+        bind_tcp(listener.local_addr()?, &options)?;
+
+        let udp = bind_udp("127.0.0.1:0".parse().unwrap(), &options)?;
+        #[cfg(unix)]
+        bind_udp(udp.local_addr()?, &options)?;
+        assert!(bind_udp(udp.local_addr()?, &SocketOptions::default()).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn acceptors_test() -> io::Result<()> {
+        let options = SocketOptions::builder().acceptors(3).build().unwrap();
+        let listeners = bind_tcp_all("127.0.0.1:0".parse().unwrap(), &options)?;
+        assert_eq!(listeners.len(), 3);
+        let addr = listeners[0].local_addr()?;
+        assert_ne!(addr.port(), 0);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr()?, addr);
+        }
+
+        let options = SocketOptions::builder().acceptors(0).build().unwrap();
+        let sockets = bind_udp_all("127.0.0.1:0".parse().unwrap(), &options)?;
+        assert_eq!(sockets.len(), acceptors(&options));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn steering_test() -> io::Result<()> {
+        let options = SocketOptions::builder()
+            .acceptors(2)
+            .steering(Steering::Cpu)
+            .build()
+            .unwrap();
+        bind_udp_all("127.0.0.1:0".parse().unwrap(), &options)?;
+
+        // every datagram goes to the socket that the program returns
+        let options = SocketOptions::builder().acceptors(2).build().unwrap();
+        let sockets = bind_udp_all("127.0.0.1:0".parse().unwrap(), &options)?;
+        attach_cbpf(
+            sockets[0].as_fd(),
+            &[filter(libc::BPF_RET | libc::BPF_K, 1)],
+        )?;
+        let sockets = sockets
+            .into_iter()
+            .map(tokio::net::UdpSocket::from_std)
+            .collect::<io::Result<Vec<_>>>()?;
+        for _ in 0..8 {
+            let sender = std::net::UdpSocket::bind("127.0.0.1:0")?;
+            sender.send_to(b"ping", sockets[0].local_addr()?)?;
+            sockets[1].recv_from(&mut [0; 4]).await?;
+        }
+        assert!(sockets[0].try_recv_from(&mut [0; 4]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+//! TCP transport with optional TLS
+//!
+//! Many networks block UDP, so QUIC cannot be used everywhere.
+//! `TcpListener` accepts TCP connections on `Config::tcp_port` and serves them
+//! with the same frames and handlers as `QuicListener`, so one pipeline can
+//! serve both transports. Each connection is a single stream.
+//!
+//! TLS is used when `key_path` and `cert_path` are set in `Config`, and
+//! clients are asked for certificates when `client_ca_path` is set too.
+//! A connection that does not finish the TLS handshake within
+//! `Config::handshake_timeout` is closed.
+//!
+//! With `SocketOptions::acceptors` above one, the port is bound by that many
+//! sockets with `SO_REUSEPORT`, and each is accepted by its own task.
+//! A connection that fails to be accepted (e.g. aborted by the client, or
+//! no file descriptor left) is skipped, so only errors of the listener
+//! itself stop the acceptors.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::transport::tcp::TcpListener;
+//!
+//! async fn echo(s: String) -> Result<String, ()> {
+//!     Ok(s)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder()
+//!     .key_path("key.pem")
+//!     .cert_path("cert.pem")
+//!     .build()
+//!     .unwrap();
+//!
+//! let listener = TcpListener::bind(&config).await?;
+//! listener.run(fn_handler(echo)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use prost::Message;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{Config, SocketOptions};
+use crate::handler::Handler;
+use crate::transport::mtls::{load_client_auth, ClientCert};
+use crate::transport::{
+    accept_retry, host_addr, load_tls, serve_stream, server_tls, socket, Stream, TransportError,
+};
+
+/// time that a connection has for the TLS handshake unless it is set
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// listener of TCP connections
+pub struct TcpListener {
+    /// sockets bound to the same port, each accepted by its own task
+    listeners: Vec<Arc<tokio::net::TcpListener>>,
+    tls: Option<TlsAcceptor>,
+    nodelay: bool,
+    handshake_timeout: Duration,
+    closed: watch::Sender<bool>,
+    paused: watch::Sender<bool>,
+}
+
+impl TcpListener {
+    /// binds to `host` and `tcp_port` of `config` with its socket options.
+    /// TLS is used only when both `key_path` and `cert_path` are set.
+    pub async fn bind(config: &Config) -> Result<Self, TransportError> {
+        let tls = match load_tls(config) {
+            Ok((certs, key)) => Some(server_tls(certs, key, load_client_auth(config)?)?),
+            Err(TransportError::NoTls) => None,
+            Err(e) => return Err(e),
+        };
+        let addr = host_addr(config, config.tcp_port);
+        Ok(Self::bind_tls(addr, tls, &config.socket_options)?
+            .handshake_timeout(config.handshake_timeout))
+    }
+
+    /// binds to `addr` with certificate chain and key in memory.
+    /// If `tls` is `None`, connections are not encrypted.
+    pub async fn bind_with(
+        addr: SocketAddr,
+        tls: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    ) -> Result<Self, TransportError> {
+        Self::bind_with_options(addr, tls, &SocketOptions::default()).await
+    }
+
+    /// binds to `addr` like `bind_with`, with `options` of the socket
+    pub async fn bind_with_options(
+        addr: SocketAddr,
+        tls: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        let tls = match tls {
+            Some((certs, key)) => Some(server_tls(certs, key, None)?),
+            None => None,
+        };
+        Self::bind_tls(addr, tls, options)
+    }
+
+    /// binds to `addr` like `bind_with_options`, and verifies certificates
+    /// of clients with `client_auth` (see `mtls::client_verifier`)
+    pub async fn bind_with_client_auth(
+        addr: SocketAddr,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        client_auth: Arc<dyn ClientCertVerifier>,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        let tls = server_tls(certs, key, Some(client_auth))?;
+        Self::bind_tls(addr, Some(tls), options)
+    }
+
+    /// binds to `addr` with TLS configuration of server `tls`
+    fn bind_tls(
+        addr: SocketAddr,
+        tls: Option<rustls::ServerConfig>,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        let tls = tls.map(|tls| TlsAcceptor::from(Arc::new(tls)));
+        Ok(Self {
+            listeners: socket::bind_tcp_all(addr, options)?
+                .into_iter()
+                .map(Arc::new)
+                .collect(),
+            tls,
+            nodelay: options.nodelay,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            closed: watch::channel(false).0,
+            paused: watch::channel(false).0,
+        })
+    }
+
+    /// sets the time that a connection has for the TLS handshake (default:
+    /// 10 seconds)
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// returns the address that is bound
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// returns the number of sockets that accept connections
+    pub fn acceptors(&self) -> usize {
+        self.listeners.len()
+    }
+
+    /// attaches a loaded eBPF program that picks the socket of each connection
+    /// (`SO_ATTACH_REUSEPORT_EBPF`), instead of `SocketOptions::steering`.
+    /// A socket filter program returns the index of the socket, in the
+    /// order they are bound.
+    #[cfg(target_os = "linux")]
+    pub fn attach_steering_program(&self, program: BorrowedFd<'_>) -> io::Result<()> {
+        socket::attach_ebpf(self.listeners[0].as_fd(), program)
+    }
+
+    /// returns true if connections are encrypted
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// closes every connection and stops `run`
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// stops accepting new connections until `resume`.
+    /// Connections that are already accepted are still served.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// accepts new connections again after `pause`
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// accepts connections and serves messages of type `M` with `handler`
+    /// until the listener is closed
+    pub async fn run<M, H>(&self, handler: H) -> Result<(), TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<M> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(handler);
+        self.run_with(move |stream, _, _| {
+            let handler = handler.clone();
+            async move { serve_stream(handler.as_ref(), stream).await }
+        })
+        .await
+    }
+
+    /// accepts connections and serves each with `serve` (after TLS) until
+    /// the listener is closed. `serve` gets the address of the peer and the
+    /// certificate of the client too, if it presented one. An error of any acceptor stops the others.
+    pub(crate) async fn run_with<F, Fut>(&self, serve: F) -> Result<(), TransportError>
+    where
+        F: Fn(Box<dyn Stream>, SocketAddr, Option<ClientCert>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let serve = Arc::new(serve);
+        let mut acceptors = JoinSet::new();
+        for listener in &self.listeners {
+            acceptors.spawn(accept(
+                listener.clone(),
+                self.tls.clone(),
+                self.nodelay,
+                self.handshake_timeout,
+                serve.clone(),
+                self.closed.subscribe(),
+                self.paused.subscribe(),
+            ));
+        }
+
+        // the rest are aborted when `acceptors` is dropped
+        while let Some(accepted) = acceptors.join_next().await {
+            accepted.map_err(io::Error::other)??;
+        }
+        Ok(())
+    }
+}
+
+/// accepts connections of `listener` until it is closed
+async fn accept<F, Fut>(
+    listener: Arc<tokio::net::TcpListener>,
+    tls: Option<TlsAcceptor>,
+    nodelay: bool,
+    handshake_timeout: Duration,
+    serve: Arc<F>,
+    mut closed: watch::Receiver<bool>,
+    mut paused: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    F: Fn(Box<dyn Stream>, SocketAddr, Option<ClientCert>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    loop {
+        tokio::select! {
+            _ = paused.wait_for(|paused| !*paused) => {}
+            _ = closed.wait_for(|closed| *closed) => return Ok(()),
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = closed.wait_for(|closed| *closed) => return Ok(()),
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // only errors of the listener itself stop accepting
+                let Some(delay) = accept_retry(&e) else {
+                    return Err(e);
+                };
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %e, "failed to accept a tcp connection");
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = closed.wait_for(|closed| *closed) => return Ok(()),
+                }
+            }
+        };
+        let _ = stream.set_nodelay(nodelay);
+
+        let serve = serve.clone();
+        let tls = tls.clone();
+        let mut closed = closed.clone();
+        tokio::spawn(async move {
+            let connection = async {
+                let (stream, cert): (Box<dyn Stream>, _) = match tls {
+                    Some(tls) => {
+                        let stream = tokio::time::timeout(handshake_timeout, tls.accept(stream))
+                            .await
+                            .map_err(|_| {
+                                io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out")
+                            })??;
+                        let cert = ClientCert::from_chain(stream.get_ref().1.peer_certificates());
+                        (Box::new(stream), cert)
+                    }
+                    None => (Box::new(stream), None),
+                };
+                serve(stream, peer, cert).await
+            };
+
+            // errors of a connection only close that connection
+            tokio::select! {
+                _ = connection => {}
+                _ = closed.wait_for(|closed| *closed) => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    use crate::config::ClientAuth;
+    use crate::fn_handler::fn_handler;
+    use crate::transport::mtls::client_verifier;
+    use crate::transport::{read_frame, write_frame, ALPN};
+
+    use super::*;
+
+    async fn upper(s: String) -> Result<String, ()> {
+        Ok(s.to_uppercase())
+    }
+
+    async fn roundtrip<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> io::Result<String> {
+        write_frame(&mut stream, &"hello".to_string().encode_to_vec()).await?;
+        stream.flush().await?;
+        let frame = read_frame(&mut stream).await?.unwrap();
+        Ok(String::decode(frame.as_slice()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn plain_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = Arc::new(TcpListener::bind_with("127.0.0.1:0".parse()?, None).await?);
+        assert!(!listener.is_tls());
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        assert_eq!(roundtrip(TcpStream::connect(addr).await?).await?, "HELLO");
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn acceptors_test() -> Result<(), Box<dyn std::error::Error>> {
+        let options = SocketOptions::builder().acceptors(4).build()?;
+        let listener = TcpListener::bind_with_options("127.0.0.1:0".parse()?, None, &options);
+        let listener = Arc::new(listener.await?);
+        assert_eq!(listener.acceptors(), 4);
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        // connections are spread over the acceptors, and all of them serve
+        let clients =
+            (0..32).map(|_| async move { roundtrip(TcpStream::connect(addr).await?).await });
+        for result in futures::future::join_all(clients).await {
+            assert_eq!(result?, "HELLO");
+        }
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pause_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = Arc::new(TcpListener::bind_with("127.0.0.1:0".parse()?, None).await?);
+        let addr = listener.local_addr()?;
+        listener.pause();
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        // connection waits in the backlog until resumed
+        let pending = tokio::spawn(async move { roundtrip(TcpStream::connect(addr).await?).await });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+        listener.resume();
+        assert_eq!(pending.await??, "HELLO");
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_timeout_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let tls = Some((vec![CertificateDer::from(cert.cert)], key.into()));
+        let listener = TcpListener::bind_with("127.0.0.1:0".parse()?, tls).await?;
+        let listener = Arc::new(listener.handshake_timeout(Duration::from_millis(100)));
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        // closed without a ClientHello of TLS
+        let mut stream = TcpStream::connect(addr).await?;
+        let closed = tokio::time::timeout(Duration::from_secs(5), read_frame(&mut stream));
+        assert_eq!(closed.await??, None);
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_der = CertificateDer::from(cert.cert);
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let tls = Some((vec![cert_der.clone()], key.into()));
+        let listener = Arc::new(TcpListener::bind_with("127.0.0.1:0".parse()?, tls).await?);
+        assert!(listener.is_tls());
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN.to_vec()];
+
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(
+                ServerName::try_from("localhost")?,
+                TcpStream::connect(addr).await?,
+            )
+            .await?;
+        assert_eq!(roundtrip(stream).await?, "HELLO");
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mtls_test() -> Result<(), Box<dyn std::error::Error>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_der = CertificateDer::from(cert.cert);
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let ca_key = KeyPair::generate()?;
+        let mut ca = CertificateParams::new(Vec::new())?;
+        ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca.self_signed(&ca_key)?;
+        let client_key = KeyPair::generate()?;
+        let mut client = CertificateParams::new(vec!["client.example".to_string()])?;
+        client.distinguished_name.push(DnType::CommonName, "alice");
+        client.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = client.signed_by(&client_key, &ca, &ca_key)?;
+
+        let verifier = client_verifier(vec![ca.der().clone()], ClientAuth::Required)?;
+        let listener = TcpListener::bind_with_client_auth(
+            "127.0.0.1:0".parse()?,
+            vec![cert_der.clone()],
+            key.into(),
+            verifier,
+            &SocketOptions::default(),
+        )
+        .await?;
+        let listener = Arc::new(listener);
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move {
+            // answers the subject of the client certificate
+            server
+                .run_with(|mut stream, _, cert| async move {
+                    let subject = cert.map(|cert| cert.subject).unwrap_or_default();
+                    write_frame(&mut stream, &subject.encode_to_vec()).await?;
+                    stream.flush().await
+                })
+                .await
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots);
+        let key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+        let configs = [
+            builder
+                .clone()
+                .with_client_auth_cert(vec![client.der().clone()], key.into())?,
+            builder.with_no_client_auth(),
+        ];
+
+        let mut subjects = Vec::new();
+        for config in configs {
+            let subject = async {
+                let mut stream = TlsConnector::from(Arc::new(config))
+                    .connect(
+                        ServerName::try_from("localhost")?,
+                        TcpStream::connect(addr).await?,
+                    )
+                    .await?;
+                let frame = read_frame(&mut stream).await?;
+                Ok::<_, Box<dyn std::error::Error>>(String::decode(frame.unwrap().as_slice())?)
+            };
+            subjects.push(subject.await.ok());
+        }
+        // a client without a certificate is rejected
+        assert_eq!(subjects, [Some("CN=alice".to_string()), None]);
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+}
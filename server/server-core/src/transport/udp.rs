@@ -0,0 +1,323 @@
+//! Raw UDP transport for unreliable messages
+//!
+//! Some messages (e.g. positions in a game) are useless when they are late,
+//! so retransmission only adds latency. `UdpListener` receives each datagram
+//! on `Config::udp_port` as one protobuf message and passes it to the handler
+//! as `Datagram<M>`.
+//!
+//! **Messages are unreliable and unordered**: a datagram can be lost,
+//! duplicated, or arrive after a later one. Handlers of datagrams should be
+//! written with that in mind (e.g. by ignoring messages older than the last
+//! one). Use other transports for anything that has to arrive.
+//!
+//! Datagrams are handled concurrently, up to `UdpListener::max_in_flight` at
+//! once; datagrams beyond that are dropped, as if the socket buffer were
+//! full. The output of the handler is sent back to the sender as a datagram
+//! unless it is empty, also without any guarantee. Datagrams that cannot be
+//! decoded or fail in the handler are dropped, and so are errors of a
+//! single datagram (e.g. an ICMP error of an earlier answer).
+//!
+//! With `SocketOptions::acceptors` above one, the port is bound by that many
+//! sockets with `SO_REUSEPORT`, and each is read by its own task. The OS
+//! keeps datagrams of the same sender on the same socket, and answers are
+//! sent from the socket that received the datagram. On Linux, the socket of
+//! a datagram can be picked by `SocketOptions::steering` or by an eBPF
+//! program of `attach_steering_program` instead.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::transport::udp::{Datagram, UdpListener};
+//!
+//! async fn position(d: Datagram<String>) -> Result<(), ()> {
+//!     println!("{} is at {}", d.from, d.msg);
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! let config = Config::builder().udp_port(30000).build().unwrap();
+//! let listener = UdpListener::bind(&config).await?;
+//! listener.run(fn_handler(position)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsFd, BorrowedFd};
+use std::sync::Arc;
+
+use prost::Message;
+use tokio::net::UdpSocket;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::config::{Config, SocketOptions};
+use crate::handler::Handler;
+use crate::transport::{accept_retry, host_addr, socket, TransportError};
+
+/// largest payload of a UDP datagram
+const MAX_DATAGRAM: usize = 65_507;
+
+/// number of datagrams that are handled at once unless it is set
+const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+/// message received in a datagram.
+/// It may be lost, duplicated, or reordered with other datagrams.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Datagram<M> {
+    /// address of the sender
+    pub from: SocketAddr,
+    pub msg: M,
+}
+
+/// listener of UDP datagrams
+pub struct UdpListener {
+    /// sockets bound to the same port, each read by its own task
+    sockets: Vec<Arc<UdpSocket>>,
+    max_in_flight: usize,
+    closed: watch::Sender<bool>,
+}
+
+impl UdpListener {
+    /// binds to `host` and `udp_port` of `config` with its socket options
+    pub async fn bind(config: &Config) -> Result<Self, TransportError> {
+        let addr = host_addr(config, config.udp_port);
+        Self::bind_with_options(addr, &config.socket_options).await
+    }
+
+    /// binds to `addr`
+    pub async fn bind_with(addr: SocketAddr) -> Result<Self, TransportError> {
+        Self::bind_with_options(addr, &SocketOptions::default()).await
+    }
+
+    /// binds to `addr` with `options` of the socket
+    pub async fn bind_with_options(
+        addr: SocketAddr,
+        options: &SocketOptions,
+    ) -> Result<Self, TransportError> {
+        let sockets = socket::bind_udp_all(addr, options)?
+            .into_iter()
+            .map(|socket| Ok(Arc::new(UdpSocket::from_std(socket)?)))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            sockets,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            closed: watch::channel(false).0,
+        })
+    }
+
+    /// sets how many datagrams are handled at once by all sockets (default:
+    /// 1024). Datagrams received beyond that are dropped.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// returns the address that is bound
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
+    }
+
+    /// returns the number of sockets that receive datagrams
+    pub fn acceptors(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// attaches a loaded eBPF program that picks the socket of each datagram
+    /// (`SO_ATTACH_REUSEPORT_EBPF`), instead of `SocketOptions::steering`.
+    /// A socket filter program returns the index of the socket, in the
+    /// order they are bound.
+    #[cfg(target_os = "linux")]
+    pub fn attach_steering_program(&self, program: BorrowedFd<'_>) -> io::Result<()> {
+        socket::attach_ebpf(self.sockets[0].as_fd(), program)
+    }
+
+    /// stops `run`
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// receives datagrams and passes messages of type `M` to `handler`
+    /// until the listener is closed. An error of any socket stops the
+    /// others.
+    pub async fn run<M, H>(&self, handler: H) -> Result<(), TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<Datagram<M>> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(handler);
+        let permits = Arc::new(Semaphore::new(self.max_in_flight));
+        let mut receivers = JoinSet::new();
+        for socket in &self.sockets {
+            receivers.spawn(receive(
+                socket.clone(),
+                handler.clone(),
+                permits.clone(),
+                self.closed.subscribe(),
+            ));
+        }
+
+        // the rest are aborted when `receivers` is dropped
+        while let Some(received) = receivers.join_next().await {
+            received.map_err(io::Error::other)??;
+        }
+        Ok(())
+    }
+}
+
+/// receives datagrams of `socket` until it is closed
+async fn receive<M, H>(
+    socket: Arc<UdpSocket>,
+    handler: Arc<H>,
+    permits: Arc<Semaphore>,
+    mut closed: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    M: Message + Default + 'static,
+    H: Handler<Datagram<M>> + Send + Sync + 'static,
+    H::Output: Message,
+    H::Future: Send,
+{
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = closed.wait_for(|closed| *closed) => return Ok(()),
+        };
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                // only errors of the socket itself stop receiving
+                let Some(delay) = accept_retry(&e) else {
+                    return Err(e);
+                };
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %e, "failed to receive a datagram");
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = closed.wait_for(|closed| *closed) => return Ok(()),
+                }
+            }
+        };
+
+        let msg = match M::decode(&buf[..len]) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            continue;
+        };
+
+        let handler = handler.clone();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let output = match handler.call(Datagram { from, msg }).await {
+                Ok(output) => output.encode_to_vec(),
+                Err(_) => return,
+            };
+            if !output.is_empty() {
+                let _ = socket.send_to(&output, from).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::sync::Notify;
+    use tokio::time::timeout;
+
+    use crate::fn_handler::fn_handler;
+
+    use super::*;
+
+    async fn upper(d: Datagram<String>) -> Result<String, ()> {
+        Ok(d.msg.to_uppercase())
+    }
+
+    #[tokio::test]
+    async fn datagram_test() -> Result<(), Box<dyn std::error::Error>> {
+        let listener = Arc::new(UdpListener::bind_with("127.0.0.1:0".parse()?).await?);
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client
+            .send_to(&"hello".to_string().encode_to_vec(), addr)
+            .await?;
+
+        let mut buf = [0u8; 64];
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await??;
+        assert_eq!(String::decode(&buf[..len])?, "HELLO");
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_in_flight_test() -> Result<(), Box<dyn std::error::Error>> {
+        // datagrams are handled after "go" is notified
+        let calls = Arc::new(AtomicUsize::new(0));
+        let go = Arc::new(Notify::new());
+        let handler = fn_handler({
+            let (calls, go) = (calls.clone(), go.clone());
+            move |d: Datagram<String>| {
+                let (calls, go) = (calls.clone(), go.clone());
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    go.notified().await;
+                    Ok::<_, ()>(d.msg.to_uppercase())
+                }
+            }
+        });
+        let listener = UdpListener::bind_with("127.0.0.1:0".parse()?).await?;
+        let listener = Arc::new(listener.max_in_flight(1));
+        let addr = listener.local_addr()?;
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(handler).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut buf = [0u8; 64];
+        client
+            .send_to(&"a".to_string().encode_to_vec(), addr)
+            .await?;
+        while calls.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // dropped while "a" is handled
+        client
+            .send_to(&"b".to_string().encode_to_vec(), addr)
+            .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        go.notify_one();
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await??;
+        assert_eq!(String::decode(&buf[..len])?, "A");
+
+        go.notify_one();
+        client
+            .send_to(&"c".to_string().encode_to_vec(), addr)
+            .await?;
+        let (len, _) = timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await??;
+        assert_eq!(String::decode(&buf[..len])?, "C");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        listener.close();
+        task.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! Local transport for processes on the same host
+//!
+//! `UdsListener` accepts connections on a Unix domain socket (on Linux and
+//! macOS) or a named pipe (on Windows) at `Config::local_path`, without
+//! touching the network stack. Connections are served with the same frames
+//! and handlers as `TcpListener`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use cubby_connect_server_core::config::Config;
+//! use cubby_connect_server_core::fn_handler::fn_handler;
+//! use cubby_connect_server_core::transport::uds::UdsListener;
+//!
+//! async fn echo(s: String) -> Result<String, ()> {
+//!     Ok(s)
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), cubby_connect_server_core::transport::TransportError> {
+//! // use a name like `\\.\pipe\cubby` on Windows
+//! let config = Config::builder().local_path("/tmp/cubby.sock").build().unwrap();
+//!
+//! let listener = UdsListener::bind(&config)?;
+//! listener.run(fn_handler(echo)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use prost::Message;
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::handler::Handler;
+#[cfg(unix)]
+use crate::transport::accept_retry;
+use crate::transport::{serve_stream, TransportError};
+
+/// listener of local connections
+pub struct UdsListener {
+    path: PathBuf,
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+    closed: watch::Sender<bool>,
+}
+
+impl UdsListener {
+    /// binds to `local_path` of `config`
+    pub fn bind(config: &Config) -> Result<Self, TransportError> {
+        match &config.local_path {
+            Some(path) => Self::bind_with(path),
+            None => Err(TransportError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local path is not configured",
+            ))),
+        }
+    }
+
+    /// binds to `path`.
+    /// On unix, a stale socket file at `path` is removed first.
+    pub fn bind_with<P: AsRef<Path>>(path: P) -> Result<Self, TransportError> {
+        let path = path.as_ref().to_path_buf();
+
+        #[cfg(unix)]
+        let listener = {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            tokio::net::UnixListener::bind(&path)?
+        };
+
+        Ok(Self {
+            path,
+            #[cfg(unix)]
+            listener,
+            closed: watch::channel(false).0,
+        })
+    }
+
+    /// returns the path that is bound
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// closes every connection and stops `run`
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// accepts connections and serves messages of type `M` with `handler`
+    /// until the listener is closed
+    pub async fn run<M, H>(&self, handler: H) -> Result<(), TransportError>
+    where
+        M: Message + Default + 'static,
+        H: Handler<M> + Send + Sync + 'static,
+        H::Output: Message,
+        H::Future: Send,
+    {
+        let handler = Arc::new(handler);
+        let mut closed = self.closed.subscribe();
+
+        #[cfg(windows)]
+        let mut server = tokio::net::windows::named_pipe::ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&self.path)?;
+
+        loop {
+            #[cfg(unix)]
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = closed.wait_for(|closed| *closed) => return Ok(()),
+            };
+            #[cfg(unix)]
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // only errors of the listener itself stop accepting
+                    let Some(delay) = accept_retry(&e) else {
+                        return Err(e.into());
+                    };
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %e, "failed to accept a local connection");
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => continue,
+                        _ = closed.wait_for(|closed| *closed) => return Ok(()),
+                    }
+                }
+            };
+
+            #[cfg(windows)]
+            let stream = {
+                tokio::select! {
+                    connected = server.connect() => connected?,
+                    _ = closed.wait_for(|closed| *closed) => return Ok(()),
+                }
+                // next client connects to a new instance
+                let next =
+                    tokio::net::windows::named_pipe::ServerOptions::new().create(&self.path)?;
+                std::mem::replace(&mut server, next)
+            };
+
+            let handler = handler.clone();
+            let mut closed = self.closed.subscribe();
+            tokio::spawn(async move {
+                // errors of a connection only close that connection
+                tokio::select! {
+                    _ = serve_stream(handler.as_ref(), stream) => {}
+                    _ = closed.wait_for(|closed| *closed) => {}
+                }
+            });
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UdsListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use tokio::net::UnixStream;
+
+    use crate::fn_handler::fn_handler;
+    use crate::transport::{read_frame, write_frame};
+
+    use super::*;
+
+    async fn upper(s: String) -> Result<String, ()> {
+        Ok(s.to_uppercase())
+    }
+
+    #[tokio::test]
+    async fn uds_test() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("cubby-uds-test-{}.sock", std::process::id()));
+        let listener = Arc::new(UdsListener::bind_with(&path)?);
+        let server = listener.clone();
+        let task = tokio::spawn(async move { server.run(fn_handler(upper)).await });
+
+        let mut stream = UnixStream::connect(&path).await?;
+        write_frame(&mut stream, &"hello".to_string().encode_to_vec()).await?;
+        let frame = read_frame(&mut stream).await?.unwrap();
+        assert_eq!(String::decode(frame.as_slice())?, "HELLO");
+
+        listener.close();
+        task.await??;
+        drop(listener);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
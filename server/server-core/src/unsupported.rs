@@ -0,0 +1,277 @@
+//! Feedback for message types that the server does not know
+//!
+//! Clients and servers of different versions can send messages that the
+//! other side does not know. Instead of dropping such messages silently,
+//! `SupportedLayer` checks the type and version of every message against
+//! `SupportedTypes`, and fails with `Unsupported` for unknown ones.
+//! `Unsupported` tells which versions of the type are supported, and it is
+//! sent back to the client as a frame (`Unsupported::encode`). The client
+//! decodes the frame into the same typed error with `Unsupported::decode`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::unsupported::{SupportedLayer, SupportedTypes, Typed, Unsupported};
+//! use cubby_connect_server_core::apply;
+//!
+//! struct Move {
+//!     version: u32,
+//! }
+//!
+//! impl Typed for Move {
+//!     fn type_name(&self) -> &str {
+//!         "move"
+//!     }
+//!
+//!     fn type_version(&self) -> u32 {
+//!         self.version
+//!     }
+//! }
+//!
+//! async fn handle(_: Move) -> Result<(), Unsupported> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Unsupported> {
+//! let mut types = SupportedTypes::new();
+//! types.add("move", 1).add("move", 2);
+//!
+//! let handler = apply!(SupportedLayer::new(types) to handle);
+//! handler.call(Move { version: 2 }).await?;
+//!
+//! let e = handler.call(Move { version: 3 }).await.unwrap_err();
+//! // this is sent to the client
+//! let frame = e.encode();
+//! assert_eq!(Unsupported::decode(&frame).unwrap().supported_versions, vec![1, 2]);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use futures::future::{err, ok, Either, Ready};
+use prost::Message;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+use crate::protobuf::unsupported as proto;
+
+/// message that has a type name and a version
+pub trait Typed {
+    /// name of the message type
+    fn type_name(&self) -> &str;
+
+    /// version of the message type
+    fn type_version(&self) -> u32;
+}
+
+/// error when the type (or the version) of a message is not supported
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Unsupported {
+    pub name: String,
+    pub version: u32,
+
+    /// versions of the type that are supported.
+    /// It is empty when the type itself is unknown.
+    pub supported_versions: Vec<u32>,
+}
+
+impl Unsupported {
+    /// encodes into a frame that is sent to the client
+    pub fn encode(&self) -> Vec<u8> {
+        proto::Unsupported {
+            name: self.name.clone(),
+            version: self.version,
+            supported_versions: self.supported_versions.clone(),
+        }
+        .encode_to_vec()
+    }
+
+    /// decodes a frame sent from the server.
+    /// Returns `None` if the frame is not `Unsupported`.
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        let msg = proto::Unsupported::decode(frame).ok()?;
+
+        Some(Self {
+            name: msg.name,
+            version: msg.version,
+            supported_versions: msg.supported_versions,
+        })
+    }
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.supported_versions.is_empty() {
+            write!(f, "unknown message type `{}`", self.name)
+        } else {
+            write!(
+                f,
+                "unsupported version {} of `{}` (supported: {:?})",
+                self.version, self.name, self.supported_versions
+            )
+        }
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// message types and versions that the server supports
+#[derive(Clone, Debug, Default)]
+pub struct SupportedTypes {
+    types: HashMap<String, BTreeSet<u32>>,
+}
+
+impl SupportedTypes {
+    /// returns an empty set of types
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds `version` of type `name`
+    pub fn add<S: Into<String>>(&mut self, name: S, version: u32) -> &mut Self {
+        self.types.entry(name.into()).or_default().insert(version);
+        self
+    }
+
+    /// returns supported versions of `name` in ascending order
+    pub fn versions(&self, name: &str) -> Vec<u32> {
+        self.types
+            .get(name)
+            .map(|v| v.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// returns `Unsupported` when `version` of `name` is not supported
+    pub fn check(&self, name: &str, version: u32) -> Result<(), Unsupported> {
+        match self.types.get(name) {
+            Some(versions) if versions.contains(&version) => Ok(()),
+            _ => Err(Unsupported {
+                name: name.to_string(),
+                version,
+                supported_versions: self.versions(name),
+            }),
+        }
+    }
+}
+
+/// `Layer` that builds `SupportedHandler`
+pub struct SupportedLayer {
+    types: Arc<SupportedTypes>,
+}
+
+impl SupportedLayer {
+    /// makes a layer that only passes messages of `types`
+    pub fn new(types: SupportedTypes) -> Self {
+        Self {
+            types: Arc::new(types),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for SupportedLayer
+where
+    T: Typed,
+    H: Handler<T>,
+    H::Error: From<Unsupported>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = SupportedHandler<T, H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(SupportedHandler {
+            types: self.types.clone(),
+            prev,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// `Handler` that fails with `Unsupported` for unknown message types
+pub struct SupportedHandler<T, H> {
+    types: Arc<SupportedTypes>,
+    prev: H,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, H> Handler<T> for SupportedHandler<T, H>
+where
+    T: Typed,
+    H: Handler<T>,
+    H::Error: From<Unsupported>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        match self.types.check(msg.type_name(), msg.type_version()) {
+            Ok(()) => Either::Left(self.prev.call(msg)),
+            Err(e) => Either::Right(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::connect;
+
+    use super::*;
+
+    struct Msg(&'static str, u32);
+
+    impl Typed for Msg {
+        fn type_name(&self) -> &str {
+            self.0
+        }
+
+        fn type_version(&self) -> u32 {
+            self.1
+        }
+    }
+
+    async fn handle(msg: Msg) -> Result<u32, Unsupported> {
+        Ok(msg.1)
+    }
+
+    #[tokio::test]
+    async fn supported_test() -> Result<(), Unsupported> {
+        let mut types = SupportedTypes::new();
+        types.add("a", 2).add("a", 1).add("b", 1);
+        let handler = connect(SupportedLayer::new(types), handle).await?;
+
+        assert_eq!(handler.call(Msg("a", 2)).await?, 2);
+        assert_eq!(
+            handler.call(Msg("a", 3)).await,
+            Err(Unsupported {
+                name: "a".to_string(),
+                version: 3,
+                supported_versions: vec![1, 2],
+            })
+        );
+
+        let e = handler.call(Msg("c", 1)).await.unwrap_err();
+        assert!(e.supported_versions.is_empty());
+        assert_eq!(e.to_string(), "unknown message type `c`");
+        Ok(())
+    }
+
+    #[test]
+    fn frame_test() {
+        let e = Unsupported {
+            name: "a".to_string(),
+            version: 3,
+            supported_versions: vec![1, 2],
+        };
+        assert_eq!(Unsupported::decode(&e.encode()), Some(e));
+        assert_eq!(Unsupported::decode(b"garbage"), None);
+    }
+}
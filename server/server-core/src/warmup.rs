@@ -0,0 +1,248 @@
+//! Warm-up before accepting traffic
+//!
+//! A server that accepts traffic right after start answers the first
+//! messages slowly or with errors: caches are empty and connections to the
+//! auth server are not made yet. Layers register such work as warm-up tasks
+//! to `WarmUp` when they are created, and the server runs them all before it
+//! reports ready (e.g. `service::systemd::ready`) and resumes its listeners.
+//!
+//! `ReadyLayer` gates a pipeline itself: it rejects messages with `NotReady`
+//! until the warm-up is finished.
+//!
+//! # Examples
+//!
+//! ```
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::warmup::{NotReady, ReadyLayer, WarmUp};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn load_cache() -> Result<(), String> {
+//!     println!("cache is loaded");
+//!     Ok(())
+//! }
+//!
+//! async fn chat(_: &'static str) -> Result<(), NotReady> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), NotReady> {
+//! let warm_up = WarmUp::new();
+//! warm_up.task("cache", load_cache());
+//!
+//! let handler = apply!(ReadyLayer::new(&warm_up) to chat);
+//! assert_eq!(handler.call("hello").await, Err(NotReady));
+//!
+//! warm_up.run().await.unwrap();
+//! assert!(warm_up.is_ready());
+//! handler.call("hello").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{err, join_all, ok, BoxFuture, Either, Ready};
+use futures::FutureExt;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+type Task = (String, BoxFuture<'static, Result<(), String>>);
+
+/// error of messages that come before the warm-up is finished
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NotReady;
+
+impl Display for NotReady {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server is not ready")
+    }
+}
+
+impl std::error::Error for NotReady {}
+
+/// error of a warm-up task
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WarmUpError {
+    pub name: String,
+    pub error: String,
+}
+
+impl Display for WarmUpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "warm-up `{}` failed: {}", self.name, self.error)
+    }
+}
+
+impl std::error::Error for WarmUpError {}
+
+/// registry of warm-up tasks and the readiness of the server.
+/// Clones share the same tasks and readiness.
+#[derive(Clone)]
+pub struct WarmUp {
+    tasks: Arc<Mutex<Vec<Task>>>,
+    ready: Arc<watch::Sender<bool>>,
+}
+
+impl Default for WarmUp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WarmUp {
+    /// returns a registry that is not ready yet
+    pub fn new() -> Self {
+        Self {
+            tasks: Arc::new(Mutex::new(Vec::new())),
+            ready: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// registers `task` that should finish before the server is ready
+    pub fn task<S, F, E>(&self, name: S, task: F)
+    where
+        S: Into<String>,
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display,
+    {
+        let task = task.map(|result| result.map_err(|e| e.to_string()));
+        self.tasks.lock().unwrap().push((name.into(), task.boxed()));
+    }
+
+    /// runs every registered task concurrently, and marks the server ready
+    /// when all of them succeeded. Returns how long each task took.
+    ///
+    /// The server stays not ready when any task fails.
+    pub async fn run(&self) -> Result<Vec<(String, Duration)>, WarmUpError> {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        let start = Instant::now();
+        let results = join_all(tasks.into_iter().map(|(name, task)| async move {
+            let result = task.await;
+            (name, start.elapsed(), result)
+        }))
+        .await;
+
+        let mut elapsed = Vec::with_capacity(results.len());
+        for (name, took, result) in results {
+            match result {
+                Ok(()) => elapsed.push((name, took)),
+                Err(error) => return Err(WarmUpError { name, error }),
+            }
+        }
+        self.ready.send_replace(true);
+        Ok(elapsed)
+    }
+
+    /// returns true if the warm-up is finished
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// waits until the warm-up is finished
+    pub async fn wait_ready(&self) {
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
+}
+
+/// `Layer` that builds `ReadyHandler`
+pub struct ReadyLayer {
+    ready: watch::Receiver<bool>,
+}
+
+impl ReadyLayer {
+    /// rejects messages until `warm_up` is finished
+    pub fn new(warm_up: &WarmUp) -> Self {
+        Self {
+            ready: warm_up.ready.subscribe(),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for ReadyLayer
+where
+    H: Handler<T>,
+    H::Error: From<NotReady>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = ReadyHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ReadyHandler {
+            ready: self.ready.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that rejects messages before the warm-up is finished
+pub struct ReadyHandler<H> {
+    ready: watch::Receiver<bool>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for ReadyHandler<H>
+where
+    H: Handler<T>,
+    H::Error: From<NotReady>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        if *self.ready.borrow() {
+            Either::Left(self.prev.call(msg))
+        } else {
+            Either::Right(err(NotReady.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn run_test() {
+        let warm_up = WarmUp::new();
+        warm_up.task("auth", async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok::<_, String>(())
+        });
+        warm_up.task("cache", async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, String>(())
+        });
+
+        let waiter = warm_up.clone();
+        let waiting = tokio::spawn(async move { waiter.wait_ready().await });
+
+        // tasks run concurrently
+        let elapsed = warm_up.run().await.unwrap();
+        assert_eq!(elapsed[0], ("auth".to_string(), Duration::from_secs(2)));
+        assert_eq!(elapsed[1], ("cache".to_string(), Duration::from_secs(1)));
+        assert!(warm_up.is_ready());
+        waiting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_test() {
+        let warm_up = WarmUp::new();
+        warm_up.task("ok", async { Ok::<_, String>(()) });
+        warm_up.task("auth", async { Err("connection refused") });
+
+        let e = warm_up.run().await.unwrap_err();
+        assert_eq!(e.to_string(), "warm-up `auth` failed: connection refused");
+        assert!(!warm_up.is_ready());
+    }
+}
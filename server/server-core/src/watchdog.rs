@@ -0,0 +1,316 @@
+//! Memory watchdog for graceful handling of memory pressure
+//!
+//! When the process uses too much memory, it is better to refuse some work
+//! than to be killed by the OOM killer. `MemoryWatchdog` measures the memory
+//! usage (RSS of the process by default) and tells the `Pressure`:
+//!
+//! - `Pressure::High` past the soft limit: `ShedLayer` rejects new messages
+//! - `Pressure::Critical` past the hard limit: listeners should also stop
+//!   accepting connections (e.g. `TcpListener::pause`)
+//!
+//! Consumers like queues and sessions can be tracked by name, so that the
+//! largest ones are reported when the pressure changes.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use cubby_connect_server_core::handler::Handler;
+//! use cubby_connect_server_core::watchdog::{MemoryWatchdog, Overloaded, Pressure, ShedLayer};
+//! use cubby_connect_server_core::apply;
+//!
+//! async fn chat(_: &'static str) -> Result<(), Overloaded> {
+//!     Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Overloaded> {
+//! let usage = Arc::new(AtomicUsize::new(0));
+//! let usage_ = usage.clone();
+//! let watchdog = MemoryWatchdog::new(100, 200).usage(move || Some(usage_.load(Ordering::SeqCst)));
+//!
+//! let handler = apply!(ShedLayer::new(&watchdog) to chat);
+//! handler.call("hello").await?;
+//!
+//! usage.store(150, Ordering::SeqCst);
+//! assert_eq!(watchdog.check(), Pressure::High);
+//! assert_eq!(handler.call("hello").await, Err(Overloaded));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::{err, ok, Either, Ready};
+use tokio::sync::watch;
+
+use crate::handler::Handler;
+use crate::layer::Layer;
+
+/// number of consumers that are reported
+const TOP: usize = 5;
+
+/// memory pressure of the process
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Pressure {
+    /// below the soft limit
+    #[default]
+    Normal,
+
+    /// past the soft limit: new messages are shed
+    High,
+
+    /// past the hard limit: new connections are not accepted either
+    Critical,
+}
+
+/// error of messages that are shed under memory pressure
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Overloaded;
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server is overloaded")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// returns resident set size of this process in bytes.
+/// Returns `None` when it is not known (e.g. not on Linux).
+pub fn rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+type Usage = Box<dyn Fn() -> Option<usize> + Send + Sync>;
+type Consumer = (String, Box<dyn Fn() -> usize + Send + Sync>);
+
+/// watchdog of memory usage
+pub struct MemoryWatchdog {
+    soft: usize,
+    hard: usize,
+    usage: Usage,
+    consumers: Mutex<Vec<Consumer>>,
+    pressure: watch::Sender<Pressure>,
+}
+
+impl MemoryWatchdog {
+    /// watches RSS of the process with `soft` and `hard` limits in bytes
+    pub fn new(soft: usize, hard: usize) -> Self {
+        Self {
+            soft,
+            hard,
+            usage: Box::new(rss),
+            consumers: Mutex::new(Vec::new()),
+            pressure: watch::channel(Pressure::Normal).0,
+        }
+    }
+
+    /// measures memory usage with `usage` instead of RSS
+    /// (e.g. usage of a buffer pool)
+    pub fn usage<F>(mut self, usage: F) -> Self
+    where
+        F: Fn() -> Option<usize> + Send + Sync + 'static,
+    {
+        self.usage = Box::new(usage);
+        self
+    }
+
+    /// tracks a consumer of memory, like a queue or a session.
+    /// `size` returns its current size.
+    pub fn track<S, F>(&self, name: S, size: F)
+    where
+        S: Into<String>,
+        F: Fn() -> usize + Send + Sync + 'static,
+    {
+        self.consumers
+            .lock()
+            .unwrap()
+            .push((name.into(), Box::new(size)));
+    }
+
+    /// stops tracking consumers of `name`
+    pub fn untrack(&self, name: &str) {
+        self.consumers.lock().unwrap().retain(|(n, _)| n != name);
+    }
+
+    /// returns up to `n` largest consumers with their sizes
+    pub fn top(&self, n: usize) -> Vec<(String, usize)> {
+        let mut sizes: Vec<_> = self
+            .consumers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, size)| (name.clone(), size()))
+            .collect();
+        sizes.sort_by_key(|(_, size)| Reverse(*size));
+        sizes.truncate(n);
+        sizes
+    }
+
+    /// measures memory usage now and updates the pressure.
+    /// Pressure stays the same when usage cannot be measured.
+    pub fn check(&self) -> Pressure {
+        let pressure = match (self.usage)() {
+            Some(used) if used >= self.hard => Pressure::Critical,
+            Some(used) if used >= self.soft => Pressure::High,
+            Some(_) => Pressure::Normal,
+            None => return self.pressure(),
+        };
+        self.pressure.send_if_modified(|p| {
+            let changed = *p != pressure;
+            *p = pressure;
+            changed
+        });
+        pressure
+    }
+
+    /// returns the pressure of the last check
+    pub fn pressure(&self) -> Pressure {
+        *self.pressure.borrow()
+    }
+
+    /// returns a receiver that is notified when the pressure changes
+    pub fn subscribe(&self) -> watch::Receiver<Pressure> {
+        self.pressure.subscribe()
+    }
+
+    /// checks memory every `interval` forever.
+    /// When the pressure changes, `on_change` is called with the new
+    /// pressure and the largest consumers. Use it to pause listeners and to
+    /// log the consumers.
+    pub async fn run<F>(&self, interval: Duration, on_change: F)
+    where
+        F: Fn(Pressure, Vec<(String, usize)>),
+    {
+        let mut last = self.pressure();
+        loop {
+            let pressure = self.check();
+            if pressure != last {
+                on_change(pressure, self.top(TOP));
+                last = pressure;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// `Layer` that builds `ShedHandler`
+pub struct ShedLayer {
+    pressure: watch::Receiver<Pressure>,
+}
+
+impl ShedLayer {
+    /// sheds messages while `watchdog` is under `Pressure::High` or more
+    pub fn new(watchdog: &MemoryWatchdog) -> Self {
+        Self {
+            pressure: watchdog.subscribe(),
+        }
+    }
+}
+
+impl<T, H> Layer<T, H> for ShedLayer
+where
+    H: Handler<T>,
+    H::Error: From<Overloaded>,
+{
+    type Next = T;
+    type Error = H::Error;
+    type Handler = ShedHandler<H>;
+    type InitError = H::Error;
+    type Future = Ready<Result<Self::Handler, Self::InitError>>;
+
+    fn new_handler(&self, prev: H) -> Self::Future {
+        ok(ShedHandler {
+            pressure: self.pressure.clone(),
+            prev,
+        })
+    }
+}
+
+/// `Handler` that rejects messages under memory pressure
+pub struct ShedHandler<H> {
+    pressure: watch::Receiver<Pressure>,
+    prev: H,
+}
+
+impl<T, H> Handler<T> for ShedHandler<H>
+where
+    H: Handler<T>,
+    H::Error: From<Overloaded>,
+{
+    type Output = H::Output;
+    type Error = H::Error;
+    type Future = Either<H::Future, Ready<Result<H::Output, H::Error>>>;
+
+    fn call(&self, msg: T) -> Self::Future {
+        if *self.pressure.borrow() >= Pressure::High {
+            Either::Right(err(Overloaded.into()))
+        } else {
+            Either::Left(self.prev.call(msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn rss_test() {
+        if cfg!(target_os = "linux") {
+            assert!(rss().unwrap() > 0);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_test() {
+        let usage = Arc::new(AtomicUsize::new(0));
+        let usage_ = usage.clone();
+        let watchdog = Arc::new(
+            MemoryWatchdog::new(100, 200).usage(move || Some(usage_.load(Ordering::SeqCst))),
+        );
+        watchdog.track("small", || 1);
+        watchdog.track("queue", || 30);
+        watchdog.track("session", || 20);
+        watchdog.untrack("small");
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_ = changes.clone();
+        let runner = watchdog.clone();
+        let task = tokio::spawn(async move {
+            runner
+                .run(Duration::from_secs(1), move |p, top| {
+                    changes_.lock().unwrap().push((p, top))
+                })
+                .await
+        });
+
+        let mut rx = watchdog.subscribe();
+        usage.store(250, Ordering::SeqCst);
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow(), Pressure::Critical);
+        usage.store(10, Ordering::SeqCst);
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow(), Pressure::Normal);
+        task.abort();
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            changes[0].1,
+            vec![("queue".to_string(), 30), ("session".to_string(), 20)]
+        );
+        assert_eq!(changes[1].0, Pressure::Normal);
+    }
+}
@@ -0,0 +1,259 @@
+//! Allocation audit of the per-message hot path
+//!
+//! The global allocator of this test binary counts allocations of each
+//! thread, so that tests can assert how many allocations are done while
+//! a message goes through a pipeline. If a change makes the hot path
+//! allocate more, these tests would fail.
+//!
+//! Frames are decoded and passed through static layers without
+//! allocations. Parsing the envelope and routing it by message type do
+//! allocate a fixed number of times per message, which is asserted
+//! exactly, so that the numbers are updated when they change.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|c| c.set(c.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|c| c.set(c.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// returns the number of allocations done in this thread while running `f`
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[cfg(test)]
+mod allocation_test {
+    use std::marker::PhantomData;
+
+    use futures::executor::block_on;
+    use futures::future::{ok, Ready};
+
+    use cubby_connect_server_core::handler::Handler;
+    use cubby_connect_server_core::layer::{connect, Layer};
+
+    use super::allocations;
+
+    /// layer that passes message as is without boxing
+    struct EchoFactory;
+
+    struct Echo<T, H> {
+        prev: H,
+        _marker: PhantomData<T>,
+    }
+
+    impl<T, H> Layer<T, H> for EchoFactory
+    where
+        H: Handler<T>,
+    {
+        type Next = T;
+        type Error = H::Error;
+        type Handler = Echo<T, H>;
+        type InitError = ();
+        type Future = Ready<Result<Self::Handler, ()>>;
+
+        fn new_handler(&self, prev: H) -> Self::Future {
+            ok(Echo {
+                prev,
+                _marker: PhantomData,
+            })
+        }
+    }
+
+    impl<T, H> Handler<T> for Echo<T, H>
+    where
+        H: Handler<T>,
+    {
+        type Output = H::Output;
+        type Error = H::Error;
+        type Future = H::Future;
+
+        fn call(&self, msg: T) -> Self::Future {
+            self.prev.call(msg)
+        }
+    }
+
+    struct Sink;
+
+    impl Handler<u64> for Sink {
+        type Output = ();
+        type Error = ();
+        type Future = Ready<Result<(), ()>>;
+
+        fn call(&self, _: u64) -> Self::Future {
+            ok(())
+        }
+    }
+
+    async fn plus_one(i: u64) -> Result<u64, ()> {
+        Ok(i + 1)
+    }
+
+    async fn sink(_: u64) -> Result<(), ()> {
+        Ok(())
+    }
+
+    #[test]
+    fn static_pipeline_test() {
+        let handler = block_on(async {
+            connect(
+                EchoFactory,
+                connect(EchoFactory, connect(EchoFactory, Sink).await?).await?,
+            )
+            .await
+        })
+        .unwrap();
+
+        // warm up
+        block_on(handler.call(0)).unwrap();
+
+        let n = allocations(|| {
+            for i in 0..100 {
+                block_on(handler.call(i)).unwrap();
+            }
+        });
+        assert_eq!(n, 0, "static pipeline should not allocate");
+    }
+
+    #[test]
+    fn fn_layer_pipeline_test() {
+        const LAYERS: usize = 3;
+
+        let handler = block_on(async {
+            connect(
+                plus_one,
+                connect(plus_one, connect(plus_one, sink).await?).await?,
+            )
+            .await
+        })
+        .unwrap();
+
+        block_on(handler.call(0)).unwrap();
+
+        let n = allocations(|| {
+            for i in 0..100 {
+                block_on(handler.call(i)).unwrap();
+            }
+        });
+        // each `FnLayer` boxes one future per message
+        assert!(n <= 100 * LAYERS, "{n} allocations for 100 messages");
+    }
+
+    /// stages of a message after the pipeline: frame decode, envelope
+    /// parse and routing by message type
+    mod hot_path {
+        use bytes::BytesMut;
+        use futures::executor::block_on;
+        use prost::{DecodeError, Message};
+
+        use cubby_connect_server_core::codec::framed::{FrameCodec, FramedRead};
+        use cubby_connect_server_core::context::Request;
+        use cubby_connect_server_core::envelope::Envelope;
+        use cubby_connect_server_core::fn_handler::fn_handler;
+        use cubby_connect_server_core::handler::Handler;
+        use cubby_connect_server_core::layer::connect;
+        use cubby_connect_server_core::router::{MessageType, Router, RouterLayer};
+
+        use super::super::allocations;
+
+        #[derive(Clone, PartialEq, Message)]
+        struct Move {
+            #[prost(int32, tag = "1")]
+            x: i32,
+        }
+
+        impl MessageType for Move {
+            const MESSAGE_TYPE: &'static str = "move";
+        }
+
+        fn frame() -> Vec<u8> {
+            Envelope::typed(&Move { x: 1 }).encode()
+        }
+
+        #[test]
+        fn frame_decode_test() {
+            let codec = FrameCodec::default();
+            let mut buf = BytesMut::new();
+            for _ in 0..101 {
+                codec.encode(&frame(), &mut buf).unwrap();
+            }
+            let buf = buf.freeze();
+            let mut frames = FramedRead::new(buf.as_ref(), FrameCodec::default());
+            block_on(frames.next()).unwrap();
+
+            // frames are split from the read buffer without copying
+            let n = allocations(|| {
+                for _ in 0..100 {
+                    block_on(frames.next()).unwrap().unwrap();
+                }
+            });
+            assert_eq!(n, 0, "frame decode should not allocate");
+        }
+
+        #[test]
+        fn envelope_decode_test() {
+            let frame = frame();
+            let n = allocations(|| {
+                for _ in 0..100 {
+                    Envelope::decode(&frame).unwrap();
+                }
+            });
+            // prost copies the payload and the message type out of the
+            // frame into `Bytes`, and then into the `Vec` and the `String`
+            assert_eq!(n, 100 * 4, "{n} allocations for 100 envelopes");
+
+            let empty = Envelope::new(Vec::new()).encode();
+            let n = allocations(|| {
+                for _ in 0..100 {
+                    Envelope::decode(&empty).unwrap();
+                }
+            });
+            assert_eq!(n, 0, "envelope without fields should not allocate");
+        }
+
+        #[test]
+        fn route_test() {
+            let router =
+                Router::new().route::<Move, _>(fn_handler(|req: Request<Move>| async move {
+                    Ok::<_, DecodeError>(req.msg.x)
+                }));
+            let unrouted = fn_handler(|_: Envelope<Vec<u8>>| async { Ok(0) });
+            let handler = block_on(connect(RouterLayer::new(router), unrouted)).unwrap();
+
+            let env = Envelope::decode(&frame()).unwrap();
+            block_on(handler.call(env.clone())).unwrap();
+            let envs: Vec<_> = (0..100).map(|_| env.clone()).collect();
+            let n = allocations(|| {
+                for env in envs {
+                    assert_eq!(block_on(handler.call(env)), Ok(1));
+                }
+            });
+            // the future of the route is boxed, and the request gets a new
+            // context with the headers (the map, its table and the value)
+            assert_eq!(n, 100 * 4, "{n} allocations for 100 routed messages");
+        }
+    }
+}
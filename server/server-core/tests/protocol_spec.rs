@@ -0,0 +1,30 @@
+//! Generated protocol description in the repository
+//!
+//! `protobuf/protocol.json` is read by authors of clients in other
+//! languages, so it must match what `spec::generate` describes. When the
+//! protocol changes, update it with
+//! `UPDATE_GOLDEN=1 cargo test --features spec --test protocol_spec`.
+
+#![cfg(feature = "spec")]
+
+use std::fs;
+use std::path::Path;
+
+use cubby_connect_server_core::spec::generate;
+
+#[test]
+fn protocol_json_test() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../protobuf/protocol.json");
+    let generated = serde_json::to_string_pretty(&generate()).unwrap() + "\n";
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, generated).unwrap();
+        return;
+    }
+
+    let written = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    assert!(
+        written == generated,
+        "{} is out of date, run `UPDATE_GOLDEN=1 cargo test --features spec --test protocol_spec`",
+        path.display()
+    );
+}
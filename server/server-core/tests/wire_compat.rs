@@ -0,0 +1,265 @@
+//! Wire compatibility with released versions
+//!
+//! `tests/golden/<version>` has bytes of handshakes, envelopes and frames as
+//! they were sent by each released version. Every version must still be
+//! readable, and the current version must write exactly the same bytes as
+//! its fixtures, so changes of the wire format are caught before a release.
+//!
+//! Hellos that tell the versions of the peers are `*_hello_versioned.bin`.
+//! `client_hello.bin` and `server_hello.bin` of `0.1.0` are hellos of peers
+//! that don't tell, which are still accepted as speaking the first protocol
+//! version.
+//!
+//! When the wire format changes on purpose for a new version, add its
+//! fixtures with `UPDATE_GOLDEN=1 cargo test --test wire_compat`. Fixtures of
+//! older versions should never be changed.
+
+use std::convert::Infallible;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use cubby_connect_server_core::client_version::VersionPolicy;
+use cubby_connect_server_core::codec::compress::Compression;
+use cubby_connect_server_core::codec::framed::{FrameCodec, Length};
+use cubby_connect_server_core::codec::{Codecs, Negotiated, Protobuf};
+use cubby_connect_server_core::config::Config;
+use cubby_connect_server_core::context::Request;
+use cubby_connect_server_core::envelope::{Envelope, Headers};
+use cubby_connect_server_core::fn_handler::fn_handler;
+use cubby_connect_server_core::handshake::{
+    accept_versioned, accept_with, connect_with, HandshakeError,
+};
+use cubby_connect_server_core::server::Server;
+use cubby_connect_server_core::transport::{read_frame, write_frame};
+use prost::Message;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const CURRENT: &str = env!("CARGO_PKG_VERSION");
+
+/// payload of frames, compressible enough for both algorithms
+const PAYLOAD: &[u8] = b"cubby cubby cubby cubby cubby cubby cubby cubby cubby cubby";
+
+fn golden() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// returns directories of released versions
+fn versions() -> Vec<PathBuf> {
+    let mut versions: Vec<_> = fs::read_dir(golden())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    versions.sort();
+    assert!(!versions.is_empty(), "no golden fixtures");
+    versions
+}
+
+fn read(version: &Path, name: &str) -> Vec<u8> {
+    let path = version.join(name);
+    fs::read(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+}
+
+/// checks that the current version writes `bytes` as fixture `name`,
+/// or updates the fixture with `UPDATE_GOLDEN`
+fn check_written(name: &str, bytes: &[u8]) {
+    let dir = golden().join(CURRENT);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), bytes).unwrap();
+        return;
+    }
+    assert_eq!(bytes, read(&dir, name), "{name} of {CURRENT} changed");
+}
+
+fn envelope() -> Envelope<Vec<u8>> {
+    let mut headers = Headers::new();
+    headers.correlation_id = Some(42);
+    headers.timestamp = Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_000));
+    headers.content_type = Some(String::from("protobuf"));
+    headers.message_type = Some(String::from("login"));
+    headers.insert("trace-id", "0af7651916cd43dd");
+    Envelope::with_headers(headers, PAYLOAD.to_vec())
+}
+
+#[test]
+fn envelope_test() {
+    check_written("envelope.bin", &envelope().encode());
+
+    for version in versions() {
+        let decoded = Envelope::decode(&read(&version, "envelope.bin")).unwrap();
+        assert_eq!(decoded, envelope(), "{}", version.display());
+    }
+}
+
+#[test]
+fn frame_test() {
+    for (name, length) in [
+        ("frame_u32.bin", Length::U32),
+        ("frame_varint.bin", Length::Varint),
+    ] {
+        let codec = FrameCodec::new().length(length);
+        let mut buf = BytesMut::new();
+        codec.encode(PAYLOAD, &mut buf).unwrap();
+        check_written(name, &buf);
+
+        for version in versions() {
+            let mut buf = BytesMut::from(&read(&version, name)[..]);
+            let frame = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(&frame[..], PAYLOAD, "{name} of {}", version.display());
+            assert!(buf.is_empty());
+        }
+    }
+}
+
+#[test]
+fn compressed_frame_test() {
+    // compressed bytes may change with versions of the libraries, so only
+    // decompression is checked
+    for (name, compression) in [
+        ("frame_zstd.bin", Compression::Zstd),
+        ("frame_lz4.bin", Compression::Lz4),
+    ] {
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            check_written(name, &compression.compress(PAYLOAD, 0).unwrap());
+        }
+        for version in versions() {
+            let frame = read(&version, name);
+            assert_eq!(
+                Compression::decompress(&frame).unwrap(),
+                PAYLOAD,
+                "{name} of {}",
+                version.display()
+            );
+        }
+    }
+}
+
+/// runs a handshake of the current version, and returns the frames of
+/// the client and the server
+async fn record_handshake() -> (Vec<u8>, Vec<u8>) {
+    let (mut client, mut client_relay) = tokio::io::duplex(1024);
+    let (mut server, mut server_relay) = tokio::io::duplex(1024);
+    let codecs = Codecs::<String>::new().with(Protobuf);
+
+    let relay = async {
+        let hello = read_frame(&mut client_relay).await.unwrap().unwrap();
+        write_frame(&mut server_relay, &hello).await.unwrap();
+        let reply = read_frame(&mut server_relay).await.unwrap().unwrap();
+        write_frame(&mut client_relay, &reply).await.unwrap();
+        (hello, reply)
+    };
+    let (client, server, (hello, reply)) = tokio::join!(
+        connect_with(
+            &mut client,
+            &["json", "protobuf"],
+            &[Compression::Zstd, Compression::Lz4]
+        ),
+        accept_with(&mut server, &codecs, &[Compression::Lz4]),
+        relay
+    );
+    client.unwrap();
+    server.unwrap();
+
+    let frame = |bytes: &[u8]| {
+        let mut buf = BytesMut::new();
+        FrameCodec::new().encode(bytes, &mut buf).unwrap();
+        buf.to_vec()
+    };
+    (frame(&hello), frame(&reply))
+}
+
+/// returns hello fixtures of `version` with and without versions, named
+/// `<name>.bin` and `<name>_versioned.bin`
+fn hellos(version: &Path, name: &str) -> Vec<Vec<u8>> {
+    [format!("{name}.bin"), format!("{name}_versioned.bin")]
+        .into_iter()
+        .filter(|name| version.join(name).exists())
+        .map(|name| read(version, &name))
+        .collect()
+}
+
+#[tokio::test]
+async fn handshake_test() {
+    let (hello, reply) = record_handshake().await;
+    check_written("client_hello_versioned.bin", &hello);
+    check_written("server_hello_versioned.bin", &reply);
+
+    let codecs = Codecs::<String>::new().with(Protobuf);
+    for version in versions() {
+        // server of the current version with a client of `version`
+        for hello in hellos(&version, "client_hello") {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            client.write_all(&hello).await.unwrap();
+            let negotiated = accept_with(&mut server, &codecs, &[Compression::Lz4])
+                .await
+                .unwrap();
+            assert_eq!(negotiated, (Negotiated("protobuf"), Compression::Lz4));
+        }
+
+        // client of the current version with a server of `version`
+        for reply in hellos(&version, "server_hello") {
+            let (mut client, mut server) = tokio::io::duplex(1024);
+            server.write_all(&reply).await.unwrap();
+            let compressions = [Compression::Zstd, Compression::Lz4];
+            let chosen = connect_with(&mut client, &["json", "protobuf"], &compressions)
+                .await
+                .unwrap();
+            assert_eq!(chosen, (String::from("protobuf"), Compression::Lz4));
+        }
+    }
+}
+
+#[tokio::test]
+async fn unversioned_hello_test() -> Result<(), Box<dyn std::error::Error>> {
+    let hello = read(&golden().join("0.1.0"), "client_hello.bin");
+    let codecs = Codecs::<String>::new().with(Protobuf);
+
+    // speaks the first protocol version, and tells no client info
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_all(&hello).await?;
+    let accepted = accept_versioned(&mut server, &codecs, &[], &VersionPolicy::new(), "").await?;
+    assert_eq!(accepted.protocol_version, 1);
+    assert_eq!(accepted.client_info, None);
+
+    // is treated as 0.0.0 by a semver requirement
+    let policy = VersionPolicy::new().require(">=0.1".parse()?);
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    client.write_all(&hello).await?;
+    match accept_versioned(&mut server, &codecs, &[], &policy, "").await {
+        Err(HandshakeError::IncompatibleVersion(incompatible)) => {
+            assert_eq!(incompatible.client_version, "0.0.0");
+            assert_eq!(incompatible.client_protocol, 1);
+            assert_eq!(incompatible.requirement.as_deref(), Some(">=0.1"));
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    // and by `Server`, which serves it or closes the connection
+    for (requirement, served) in [(None, true), (Some(">=0.1"), false)] {
+        let mut config = Config::builder();
+        config.host((127, 0, 0, 1)).tcp_port(0);
+        if let Some(requirement) = requirement {
+            config.client_version_req(requirement);
+        }
+        let server = Server::from_config(config.build()?)
+            .handler(fn_handler(|req: Request<String>| async move {
+                Ok::<_, Infallible>(req.msg)
+            }))
+            .run()
+            .await?;
+
+        let mut stream = TcpStream::connect(server.tcp_addr()).await?;
+        stream.write_all(&hello).await?;
+        assert!(read_frame(&mut stream).await?.is_some());
+        write_frame(&mut stream, &String::from("hello").encode_to_vec()).await?;
+        stream.flush().await?;
+        let echo = read_frame(&mut stream).await.ok().flatten();
+        assert_eq!(echo.is_some(), served);
+        server.shutdown().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Expansion of `#[handler]`
+//!
+//! The function is turned into a unit struct of the same name, so it can be
+//! used wherever a handler is expected. The struct implements
+//! `Handler<Request<M>>` where `M` is the type of the message argument, and
+//! each other argument is taken out of the context with `FromContext`.
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{Error, FnArg, GenericArgument, ItemFn, PathArguments, ReturnType, Type, TypePath};
+
+/// name of the attribute that marks the message argument
+const MSG: &str = "msg";
+
+struct Arg {
+    ident: syn::Ident,
+    ty: Type,
+}
+
+/// returns `O` and `E` of `Result<O, E>`
+pub(crate) fn result_types(output: &ReturnType) -> syn::Result<(Type, Type)> {
+    let error = || Error::new(output.span(), "handler must return `Result<O, E>`");
+    let ty = match output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => return Err(error()),
+    };
+    let segment = match ty.as_ref() {
+        Type::Path(TypePath { qself: None, path }) => path.segments.last().ok_or_else(error)?,
+        _ => return Err(error()),
+    };
+    if segment.ident != "Result" {
+        return Err(error());
+    }
+
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => &args.args,
+        _ => return Err(error()),
+    };
+    let mut types = args.iter().filter_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    });
+    match (types.next(), types.next(), types.next()) {
+        (Some(output), Some(error), None) => Ok((output, error)),
+        _ => Err(error()),
+    }
+}
+
+pub(crate) fn expand(mut item: ItemFn) -> syn::Result<TokenStream> {
+    let sig = &mut item.sig;
+    if sig.asyncness.is_none() {
+        return Err(Error::new(
+            sig.fn_token.span(),
+            "handler must be an `async fn`",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(
+            sig.generics.span(),
+            "handler cannot have generic parameters",
+        ));
+    }
+    let (output, error) = result_types(&sig.output)?;
+
+    // arguments are passed by position, so patterns like `(a, b): (u8, u8)`
+    // work as well
+    let mut args = Vec::new();
+    let mut msg = None;
+    for (i, input) in sig.inputs.iter_mut().enumerate() {
+        let pat = match input {
+            FnArg::Typed(pat) => pat,
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new(receiver.span(), "handler cannot take `self`"))
+            }
+        };
+        let len = pat.attrs.len();
+        pat.attrs.retain(|attr| !attr.path.is_ident(MSG));
+        if pat.attrs.len() != len {
+            if msg.is_some() {
+                return Err(Error::new(pat.span(), "only one argument can be `#[msg]`"));
+            }
+            msg = Some(i);
+        }
+        args.push(Arg {
+            ident: format_ident!("__arg{}", i),
+            ty: (*pat.ty).clone(),
+        });
+    }
+    // the message is the last argument unless marked
+    let msg = match msg.or_else(|| args.len().checked_sub(1)) {
+        Some(msg) => msg,
+        None => {
+            return Err(Error::new(
+                sig.paren_token.span,
+                "handler must take the message as an argument",
+            ))
+        }
+    };
+
+    let core = quote!(cubby_connect_server_core);
+    let vis = &item.vis;
+    let name = &item.sig.ident;
+    let attrs = &item.attrs;
+    let msg_ty = &args[msg].ty;
+    let msg_ident = &args[msg].ident;
+    let idents: Vec<_> = args.iter().map(|arg| &arg.ident).collect();
+    let extractors = args
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != msg)
+        .map(|(_, Arg { ident, ty })| {
+            quote! {
+                let #ident = match <#ty as #core::context::FromContext>::from_context(&req.context) {
+                    ::std::option::Option::Some(v) => v,
+                    ::std::option::Option::None => {
+                        let missing = #core::context::Missing(::std::any::type_name::<#ty>());
+                        return ::std::boxed::Box::pin(::std::future::ready(
+                            ::std::result::Result::Err(::std::convert::From::from(missing)),
+                        ));
+                    }
+                };
+            }
+        });
+
+    let mut inner = item.clone();
+    inner.attrs.clear();
+    inner.vis = syn::Visibility::Inherited;
+    inner.sig.ident = format_ident!("__handle");
+
+    Ok(quote! {
+        #(#attrs)*
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy, Debug, Default)]
+        #vis struct #name;
+
+        impl #core::handler::Handler<#core::context::Request<#msg_ty>> for #name {
+            type Output = #output;
+            type Error = #error;
+            type Future = ::std::pin::Pin<::std::boxed::Box<
+                dyn ::std::future::Future<Output = ::std::result::Result<#output, #error>>,
+            >>;
+
+            fn call(&self, req: #core::context::Request<#msg_ty>) -> Self::Future {
+                #[allow(clippy::too_many_arguments)]
+                #inner
+
+                #(#extractors)*
+                let #msg_ident = req.msg;
+                ::std::boxed::Box::pin(__handle(#(#idents),*))
+            }
+        }
+    })
+}
@@ -0,0 +1,105 @@
+//! Expansion of `#[layer]`
+//!
+//! The impl block is kept as is. Its `async fn transform` is wrapped in an
+//! impl of `Transform<I>`, and the type gets an impl of `Layer<I, H>` that
+//! builds `TransformHandler`s with a clone of itself.
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{Error, FnArg, ImplItem, ItemImpl};
+
+use crate::handler::result_types;
+
+/// name of the method that transforms messages
+const TRANSFORM: &str = "transform";
+
+pub(crate) fn expand(item: ItemImpl) -> syn::Result<TokenStream> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(Error::new(
+            path.span(),
+            "layer must be on an impl block without a trait",
+        ));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(
+            item.generics.span(),
+            "layer cannot have generic parameters",
+        ));
+    }
+    let sig = item
+        .items
+        .iter()
+        .find_map(|item| match item {
+            ImplItem::Method(method) if method.sig.ident == TRANSFORM => Some(&method.sig),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::new(
+                item.impl_token.span(),
+                "layer must have `async fn transform(&self, input)`",
+            )
+        })?;
+    if sig.asyncness.is_none() {
+        return Err(Error::new(
+            sig.fn_token.span(),
+            "transform must be an `async fn`",
+        ));
+    }
+
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver))
+            if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => return Err(Error::new(sig.inputs.span(), "transform must take `&self`")),
+    }
+    let input = match (inputs.next(), inputs.next()) {
+        (Some(FnArg::Typed(pat)), None) => &pat.ty,
+        _ => {
+            return Err(Error::new(
+                sig.inputs.span(),
+                "transform must take one input besides `&self`",
+            ))
+        }
+    };
+    let (output, error) = result_types(&sig.output)?;
+
+    let core = quote!(cubby_connect_server_core);
+    let self_ty = &item.self_ty;
+    Ok(quote! {
+        #item
+
+        impl #core::transform::Transform<#input> for #self_ty {
+            type Output = #output;
+            type Error = #error;
+
+            fn run(
+                self: ::std::sync::Arc<Self>,
+                input: #input,
+            ) -> ::std::pin::Pin<::std::boxed::Box<
+                dyn ::std::future::Future<Output = ::std::result::Result<#output, #error>>,
+            >> {
+                ::std::boxed::Box::pin(async move { <#self_ty>::transform(&self, input).await })
+            }
+        }
+
+        impl<__H> #core::layer::Layer<#input, __H> for #self_ty
+        where
+            __H: #core::handler::Handler<#output> + 'static,
+            __H::Future: 'static,
+            #error: ::std::convert::Into<__H::Error>,
+        {
+            type Next = #output;
+            type Error = __H::Error;
+            type Handler = #core::transform::TransformHandler<Self, __H>;
+            type InitError = __H::Error;
+            type Future = ::std::future::Ready<::std::result::Result<Self::Handler, Self::InitError>>;
+
+            fn new_handler(&self, prev: __H) -> Self::Future {
+                ::std::future::ready(::std::result::Result::Ok(
+                    #core::transform::TransformHandler::new(::std::clone::Clone::clone(self), prev),
+                ))
+            }
+        }
+    })
+}
@@ -1,23 +1,36 @@
 //! This is a collection of macros that is used in server
 //!
-//! - apply: this would
+//! - apply: this would connect layers and a handler into one handler
+//! - apply_build: this would do the same as apply, but evaluates to a future
+//!   of `Result` instead of using `?`
+//! - handler: this would turn an async function with extractor arguments
+//!   into a handler
+//! - layer: this would turn a type with an async `transform` method into a
+//!   layer
 
 use proc_macro::TokenStream;
 
 use quote::{quote, ToTokens};
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
-use syn::{parse_macro_input, Expr, Token};
+use syn::{parse_macro_input, Expr, ExprRange, ItemFn, ItemImpl, RangeLimits, Token, Type};
 
-mod to {
+mod handler;
+mod layer;
+
+mod kw {
     use syn::custom_keyword;
 
     custom_keyword!(to);
+    custom_keyword!(into);
 }
 
 struct Args {
     layers: Punctuated<Expr, Token![,]>,
     handler: Expr,
+
+    /// error type of the whole chain after `into`
+    error: Option<Type>,
 }
 
 impl Parse for Args {
@@ -30,29 +43,112 @@ impl Parse for Args {
             if let Ok(punct) = input.parse() {
                 layers.push_punct(punct);
             } else {
-                input.parse::<to::to>()?;
+                input.parse::<kw::to>()?;
                 break;
             }
         }
 
         let handler = input.parse()?;
+        let error = if input.peek(kw::into) {
+            input.parse::<kw::into>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
 
-        Ok(Args { layers, handler })
+        Ok(Args {
+            layers,
+            handler,
+            error,
+        })
     }
 }
 
-impl ToTokens for Args {
-    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        let last_layer = self.layers.last().unwrap();
+/// returns `layers` of a `..layers` spread
+fn spread(expr: &Expr) -> Option<&Expr> {
+    match expr {
+        Expr::Range(ExprRange {
+            from: None,
+            limits: RangeLimits::HalfOpen(_),
+            to: Some(to),
+            ..
+        }) => Some(to),
+        _ => None,
+    }
+}
+
+impl Args {
+    /// expands into the handler, or into a future of `Result` of the
+    /// handler if `build` is true
+    fn expand(&self, build: bool) -> proc_macro2::TokenStream {
         let handler = &self.handler;
-        let mut ret =
-            quote!( cubby_connect_server_core::layer::connect( #last_layer, #handler ).await? );
+        // with an error type, every layer is connected by `connect_into`,
+        // which converts errors into it
+        let connect = match &self.error {
+            Some(error) => {
+                quote!( cubby_connect_server_core::layer::connect_into::<#error, _, _, _, _, _> )
+            }
+            None => quote!(cubby_connect_server_core::layer::connect),
+        };
+        // `apply!` propagates errors with `?`, and `apply_build!` returns
+        // them from the async block
+        let wait = |future: proc_macro2::TokenStream| {
+            if build {
+                quote!(match #future.await {
+                    ::std::result::Result::Ok(handler) => handler,
+                    ::std::result::Result::Err(e) => return ::std::result::Result::Err(e),
+                })
+            } else {
+                quote!( #future.await? )
+            }
+        };
+
+        // with a spread, every layer is boxed and connected by `connect_all`,
+        // so the error type is only given to the handler
+        let ret = if self.layers.iter().any(|layer| spread(layer).is_some()) {
+            let handler = match &self.error {
+                Some(error) => {
+                    quote!( cubby_connect_server_core::middleware::map_err::err_into::<#error, _>(
+                        cubby_connect_server_core::handler::IntoHandler::into_handler(#handler)
+                    ) )
+                }
+                None => quote!( #handler ),
+            };
+            let layers = self.layers.iter().map(|layer| match spread(layer) {
+                Some(layers) => quote!( __layers.extend(#layers); ),
+                None => quote!( __layers.push(cubby_connect_server_core::layer::BoxLayer::new(#layer)); ),
+            });
+            wait(quote!(
+                cubby_connect_server_core::layer::connect_all(
+                    {
+                        let mut __layers = ::std::vec::Vec::new();
+                        #(#layers)*
+                        __layers
+                    },
+                    #handler,
+                )
+            ))
+        } else {
+            let last_layer = self.layers.last().unwrap();
+            let mut ret = wait(quote!( #connect( #last_layer, #handler ) ));
+
+            for i in self.layers.iter().rev().skip(1) {
+                ret = wait(quote!( #connect( #i, #ret ) ));
+            }
+            ret
+        };
 
-        for i in self.layers.iter().rev().skip(1) {
-            ret = quote!( cubby_connect_server_core::layer::connect( #i, #ret ).await? );
+        if build {
+            quote!( async move { ::std::result::Result::Ok(#ret) } )
+        } else {
+            ret
         }
+    }
+}
 
-        ret.to_tokens(tokens);
+impl ToTokens for Args {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.expand(false).to_tokens(tokens);
     }
 }
 
@@ -66,6 +162,66 @@ impl ToTokens for Args {
 /// let handler = apply!(some_layer_1, some_layer_2, ..., some_layer_n to some_handler);
 /// ```
 ///
+/// Layers and handlers usually share one error type. With `into E` after
+/// the handler, they are connected by `layer::connect_into` instead, which
+/// converts errors on both sides of every layer into `E` with `Into`, so
+/// layers and handlers of different error types form a chain of `E`.
+/// Function layers still fail with `E` itself.
+///
+/// ```
+/// use cubby_connect_server_core::apply;
+/// use cubby_connect_server_core::error::CubbyError;
+/// use cubby_connect_server_core::handler::Handler;
+/// use cubby_connect_server_core::middleware::filter::FilterLayer;
+/// use cubby_connect_server_core::middleware::timeout::TimeoutLayer;
+/// use std::time::Duration;
+///
+/// // fails with `io::Error`, which is not `From<TimedOut>` nor `From<Filtered>`
+/// async fn load(path: String) -> Result<String, std::io::Error> {
+///     std::fs::read_to_string(path)
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), CubbyError> {
+/// let handler = apply!(
+///     FilterLayer::new(|path: &String| !path.is_empty()).reject(),
+///     TimeoutLayer::new(Duration::from_secs(1))
+///     to load into CubbyError
+/// );
+/// assert!(matches!(handler.call(String::new()).await, Err(CubbyError::Filtered)));
+/// assert!(matches!(handler.call(String::from("/nothing")).await, Err(CubbyError::Io(_))));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Layers only known at runtime can be spread from a `Vec<BoxLayer<T, O, E>>`
+/// with `..layers`. Then every layer is boxed, and they are connected by
+/// `layer::connect_all` into a `BoxHandler`.
+///
+/// ```
+/// use cubby_connect_server_core::apply;
+/// use cubby_connect_server_core::fn_layer::fn_layer;
+/// use cubby_connect_server_core::handler::Handler;
+/// use cubby_connect_server_core::layer::BoxLayer;
+///
+/// async fn double(n: i32) -> Result<i32, ()> {
+///     Ok(n * 2)
+/// }
+///
+/// async fn show(n: i32) -> Result<String, ()> {
+///     Ok(n.to_string())
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), ()> {
+/// // e.g. chosen by plugins
+/// let plugins = vec![BoxLayer::new(fn_layer(double)), BoxLayer::new(fn_layer(double))];
+/// let handler = apply!(fn_layer(|n: i32| async move { Ok(n + 1) }), ..plugins to show);
+/// assert_eq!(handler.call(1).await?, "8");
+/// # Ok(())
+/// # }
+/// ```
+///
 /// ```
 /// use cubby_connect_server_core::apply;
 /// use cubby_connect_server_core::handler::Handler;
@@ -94,6 +250,189 @@ pub fn apply(input: TokenStream) -> TokenStream {
     quote!( #args ).into()
 }
 
+/// Macro to connect layers and handler like `apply`, without `?`
+///
+/// `apply!` awaits and propagates errors with `?`, so it can only be used in
+/// an async function with a compatible error. `apply_build!` takes the same
+/// arguments, and evaluates to a future of `Result<handler, error>` that the
+/// caller handles explicitly, e.g. in `main` or in a lazy static. Nothing is
+/// built until the future is awaited.
+///
+/// Every layer must fail with the same `InitError` (usually the error of
+/// the handler), since errors are returned as is, unless the chain has an
+/// error type with `into E`, which they are converted into.
+///
+/// # Examples
+///
+/// ```
+/// use cubby_connect_server_core::apply_build;
+/// use cubby_connect_server_core::handler::Handler;
+///
+/// async fn echo<T>(t: T) -> Result<T, String> {
+///     Ok(t)
+/// }
+///
+/// async fn len(s: &'static str) -> Result<usize, String> {
+///     Ok(s.len())
+/// }
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let handler = match apply_build!(echo, echo to len).await {
+///         Ok(handler) => handler,
+///         Err(e) => panic!("failed to build the pipeline: {e}"),
+///     };
+///     assert_eq!(handler.call("hello").await, Ok(5));
+/// }
+/// ```
+#[proc_macro]
+pub fn apply_build(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as Args);
+    args.expand(true).into()
+}
+
+/// Attribute to turn an async function into a handler of `Request<M>`
+///
+/// The function becomes a unit struct of the same name, which implements
+/// `Handler<Request<M>>`. `M` is the type of the message argument: the one
+/// marked with `#[msg]`, or the last one. Every other argument is taken
+/// out of the context of the request with `FromContext`, in any order, like
+/// extractors of axum. When the context does not have one, the handler
+/// fails with `context::Missing`, so the error type must implement
+/// `From<Missing>`.
+///
+/// The function must return `Result<O, E>`, and cannot be generic.
+///
+/// # Examples
+///
+/// ```
+/// use cubby_connect_server_core::context::{Context, ContextLayer, Data, Missing, Request};
+/// use cubby_connect_server_core::handler::{self, Handler};
+/// use cubby_connect_server_core::peer_trace::PeerId;
+/// use cubby_connect_server_core::apply;
+///
+/// struct AppState {
+///     motd: &'static str,
+/// }
+///
+/// #[derive(Debug)]
+/// struct Error;
+///
+/// impl From<Missing> for Error {
+///     fn from(_: Missing) -> Self {
+///         Error
+///     }
+/// }
+///
+/// /// greets the peer
+/// #[handler::handler]
+/// async fn on_chat(peer: PeerId, state: Data<AppState>, msg: String) -> Result<String, Error> {
+///     Ok(format!("{}: {msg} ({})", peer, state.motd))
+/// }
+///
+/// #[handler::handler]
+/// async fn on_move(#[msg] x: i32, peer: Option<PeerId>) -> Result<i32, Error> {
+///     Ok(if peer.is_some() { x } else { -x })
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Error> {
+/// let mut context = Context::new();
+/// context.insert(Data::new(AppState { motd: "welcome" }));
+/// context.insert(PeerId::new("player-42"));
+///
+/// let handler = apply!(ContextLayer::new(context) to on_chat);
+/// assert_eq!(handler.call(String::from("hi")).await?, "player-42: hi (welcome)");
+///
+/// // nothing in the context
+/// assert_eq!(on_move.call(Request::new(3)).await?, -3);
+/// assert!(on_chat.call(Request::new(String::from("hi"))).await.is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro_attribute]
+pub fn handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return syn::Error::new_spanned(attr, "handler takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemFn);
+    handler::expand(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Attribute to turn a type with an async `transform` method into a layer
+///
+/// It is put on an impl block that has
+/// `async fn transform(&self, input: I) -> Result<O, E>`, and implements
+/// `Layer<I, H>` for the type: handlers built by it call `transform` with
+/// each message, and the next handler with the output. `E` is converted
+/// into the error of the next handler with `Into`. See
+/// `cubby_connect_server_core::transform` for the generated impls.
+///
+/// It is an attribute rather than a derive because a derive cannot see
+/// the methods of the type. The type must be `Clone` and `'static`, and
+/// cannot be generic.
+///
+/// # Examples
+///
+/// ```
+/// use cubby_connect_server_core::handler::Handler;
+/// use cubby_connect_server_core::layer::layer;
+/// use cubby_connect_server_core::apply;
+///
+/// #[derive(Debug, PartialEq)]
+/// enum Error {
+///     TooLong,
+///     Empty,
+/// }
+///
+/// #[derive(Clone)]
+/// struct MaxLen(usize);
+///
+/// #[layer]
+/// impl MaxLen {
+///     async fn transform(&self, msg: String) -> Result<String, Error> {
+///         if msg.len() > self.0 {
+///             return Err(Error::TooLong);
+///         }
+///         Ok(msg.trim().to_string())
+///     }
+/// }
+///
+/// async fn chat(msg: String) -> Result<String, Error> {
+///     if msg.is_empty() {
+///         return Err(Error::Empty);
+///     }
+///     Ok(msg)
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Error> {
+/// let handler = apply!(MaxLen(8) to chat);
+/// assert_eq!(handler.call(String::from(" hi ")).await?, "hi");
+/// assert_eq!(handler.call(String::from("too long!")).await, Err(Error::TooLong));
+/// assert_eq!(handler.call(String::from("  ")).await, Err(Error::Empty));
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro_attribute]
+pub fn layer(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = proc_macro2::TokenStream::from(attr);
+        return syn::Error::new_spanned(attr, "layer takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemImpl);
+    layer::expand(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
 #[allow(dead_code)]
 mod compile_fail_test {
     /// apply cannot be empty
@@ -117,4 +456,68 @@ mod compile_fail_test {
     /// apply!(hello, world)
     /// ```
     fn no_to() {}
+
+    /// handler should be async
+    ///
+    /// error: handler must be an `async fn`
+    ///
+    /// ```compile_fail
+    /// use cubby_connect_server_macro::handler;
+    ///
+    /// #[handler]
+    /// fn hello(msg: String) -> Result<(), ()> {
+    ///     Ok(())
+    /// }
+    /// ```
+    fn not_async() {}
+
+    /// handler should return a result
+    ///
+    /// error: handler must return `Result<O, E>`
+    ///
+    /// ```compile_fail
+    /// use cubby_connect_server_macro::handler;
+    ///
+    /// #[handler]
+    /// async fn hello(msg: String) {}
+    /// ```
+    fn no_result() {}
+
+    /// layer should have a transform
+    ///
+    /// error: layer must have `async fn transform(&self, input)`
+    ///
+    /// ```compile_fail
+    /// use cubby_connect_server_macro::layer;
+    ///
+    /// #[derive(Clone)]
+    /// struct Upper;
+    ///
+    /// #[layer]
+    /// impl Upper {
+    ///     async fn upper(&self, msg: String) -> Result<String, ()> {
+    ///         Ok(msg.to_uppercase())
+    ///     }
+    /// }
+    /// ```
+    fn no_transform() {}
+
+    /// transform should take one input
+    ///
+    /// error: transform must take one input besides `&self`
+    ///
+    /// ```compile_fail
+    /// use cubby_connect_server_macro::layer;
+    ///
+    /// #[derive(Clone)]
+    /// struct Join;
+    ///
+    /// #[layer]
+    /// impl Join {
+    ///     async fn transform(&self, a: String, b: String) -> Result<String, ()> {
+    ///         Ok(a + &b)
+    ///     }
+    /// }
+    /// ```
+    fn two_inputs() {}
 }
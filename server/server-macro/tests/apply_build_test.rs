@@ -0,0 +1,151 @@
+#[cfg(test)]
+mod apply_build_test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::{err, Ready};
+
+    use cubby_connect_server_core::fn_layer::fn_layer;
+    use cubby_connect_server_core::handler::Handler;
+    use cubby_connect_server_core::layer::{BoxLayer, Layer};
+    use cubby_connect_server_core::middleware::filter::{FilterLayer, Filtered};
+    use cubby_connect_server_macro::apply_build;
+
+    async fn echo<T>(t: T) -> Result<T, &'static str> {
+        Ok(t)
+    }
+
+    async fn double(n: i32) -> Result<i32, &'static str> {
+        Ok(n * 2)
+    }
+
+    /// layer that fails to build
+    struct Broken;
+
+    impl<H: Handler<i32, Error = &'static str>> Layer<i32, H> for Broken {
+        type Next = i32;
+        type Error = &'static str;
+        type Handler = H;
+        type InitError = &'static str;
+        type Future = Ready<Result<H, &'static str>>;
+
+        fn new_handler(&self, _: H) -> Self::Future {
+            err("broken")
+        }
+    }
+
+    static BUILT: AtomicUsize = AtomicUsize::new(0);
+
+    async fn counted(n: i32) -> Result<i32, &'static str> {
+        BUILT.fetch_add(1, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    #[test]
+    fn build_test() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // built outside of an async fn
+        let handler = runtime
+            .block_on(apply_build!(echo, echo to double))
+            .unwrap();
+        assert_eq!(runtime.block_on(handler.call(2)), Ok(4));
+
+        let result = runtime.block_on(apply_build!(echo, Broken to double));
+        assert_eq!(result.err(), Some("broken"));
+
+        let layers = vec![BoxLayer::new(Broken)];
+        let result = runtime.block_on(apply_build!(fn_layer(echo), ..layers to double));
+        assert_eq!(result.err(), Some("broken"));
+
+        // nothing is built until the future is awaited
+        let future = apply_build!(echo to counted);
+        assert_eq!(BUILT.load(Ordering::Relaxed), 0);
+        let handler = runtime.block_on(future).unwrap();
+        assert_eq!(runtime.block_on(handler.call(7)), Ok(7));
+        assert_eq!(BUILT.load(Ordering::Relaxed), 1);
+    }
+
+    /// error of a chain with layers and handlers of other error types
+    #[derive(Debug, Eq, PartialEq)]
+    enum AppError {
+        Filtered,
+        Handler(&'static str),
+        Build(u8),
+    }
+
+    impl From<Filtered> for AppError {
+        fn from(_: Filtered) -> Self {
+            AppError::Filtered
+        }
+    }
+
+    impl From<&'static str> for AppError {
+        fn from(e: &'static str) -> Self {
+            AppError::Handler(e)
+        }
+    }
+
+    impl From<u8> for AppError {
+        fn from(e: u8) -> Self {
+            AppError::Build(e)
+        }
+    }
+
+    async fn odd(n: i32) -> Result<i32, &'static str> {
+        if n % 2 == 0 {
+            Err("even")
+        } else {
+            Ok(n)
+        }
+    }
+
+    /// layer that fails to build with another error
+    struct BrokenCode;
+
+    impl<H: Handler<i32>> Layer<i32, H> for BrokenCode {
+        type Next = i32;
+        type Error = H::Error;
+        type Handler = H;
+        type InitError = u8;
+        type Future = Ready<Result<H, u8>>;
+
+        fn new_handler(&self, _: H) -> Self::Future {
+            err(3)
+        }
+    }
+
+    #[test]
+    fn into_test() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let handler = runtime
+            .block_on(
+                apply_build!(FilterLayer::new(|n: &i32| *n > 0).reject() to odd into AppError),
+            )
+            .unwrap();
+        assert_eq!(runtime.block_on(handler.call(1)), Ok(1));
+        assert_eq!(runtime.block_on(handler.call(-1)), Err(AppError::Filtered));
+        assert_eq!(
+            runtime.block_on(handler.call(2)),
+            Err(AppError::Handler("even"))
+        );
+
+        let result = runtime.block_on(apply_build!(BrokenCode to odd into AppError));
+        assert_eq!(result.err(), Some(AppError::Build(3)));
+
+        let layers = vec![BoxLayer::new(fn_layer(|n: i32| async move {
+            Ok::<_, AppError>(n + 1)
+        }))];
+        let handler = runtime
+            .block_on(apply_build!(..layers to odd into AppError))
+            .unwrap();
+        assert_eq!(
+            runtime.block_on(handler.call(1)),
+            Err(AppError::Handler("even"))
+        );
+    }
+}
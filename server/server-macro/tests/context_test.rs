@@ -0,0 +1,35 @@
+#[cfg(test)]
+mod context_test {
+    use cubby_connect_server_core::context::{Context, ContextLayer, Data, Missing};
+    use cubby_connect_server_core::handler::Handler;
+    use cubby_connect_server_macro::apply;
+    use num_traits::PrimInt;
+
+    #[derive(Debug)]
+    struct Error;
+
+    impl From<Missing> for Error {
+        fn from(_: Missing) -> Self {
+            Error
+        }
+    }
+
+    async fn plus_one<I: PrimInt>(i: I) -> Result<I, Error> {
+        Ok(i.add(I::one()))
+    }
+
+    async fn check(i: i32, expected: Data<i32>) -> Result<(), Error> {
+        assert_eq!(i, *expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extractor_test() -> Result<(), Error> {
+        let mut context = Context::new();
+        context.insert(Data::new(3));
+
+        let handler = apply!(plus_one, plus_one, ContextLayer::new(context) to check);
+        handler.call(1).await?;
+        Ok(())
+    }
+}
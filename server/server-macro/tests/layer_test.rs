@@ -47,6 +47,7 @@ mod layer_test {
         H: Handler<T>,
         H::Future: 'static,
     {
+        type Output = ();
         type Error = H::Error;
         type Future = LocalBoxFuture<'static, Result<(), Self::Error>>;
 
@@ -73,6 +74,7 @@ mod layer_test {
     }
 
     impl<T: Display> Handler<T> for Check {
+        type Output = ();
         type Error = ();
         type Future = Ready<Result<(), ()>>;
 
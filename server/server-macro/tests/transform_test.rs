@@ -0,0 +1,57 @@
+#[cfg(test)]
+mod transform_test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use cubby_connect_server_core::handler::Handler;
+    use cubby_connect_server_core::layer::layer;
+    use cubby_connect_server_macro::apply;
+
+    #[derive(Debug, PartialEq)]
+    struct Error(String);
+
+    impl From<std::num::ParseIntError> for Error {
+        fn from(e: std::num::ParseIntError) -> Self {
+            Error(e.to_string())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Parse;
+
+    #[layer]
+    impl Parse {
+        async fn transform(&self, msg: &'static str) -> Result<i64, std::num::ParseIntError> {
+            msg.parse()
+        }
+    }
+
+    /// counts messages, and is shared by the handlers it builds
+    #[derive(Clone, Default)]
+    struct Count(Arc<AtomicUsize>);
+
+    #[layer]
+    impl Count {
+        async fn transform(&self, n: i64) -> Result<i64, Error> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(n)
+        }
+    }
+
+    async fn negate(n: i64) -> Result<i64, Error> {
+        Ok(-n)
+    }
+
+    #[tokio::test]
+    async fn transform_test() -> Result<(), Error> {
+        let count = Count::default();
+        let handler = apply!(Parse, count.clone() to negate);
+
+        assert_eq!(handler.call("42").await?, -42);
+        assert_eq!(handler.call("-1").await?, 1);
+        // the error of `transform` is converted into the error of `negate`
+        assert!(handler.call("x").await.is_err());
+        assert_eq!(count.0.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+}
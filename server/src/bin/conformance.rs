@@ -0,0 +1,63 @@
+use std::net::ToSocketAddrs;
+use std::process::ExitCode;
+
+use cubby_connect_server_core::conformance;
+
+const USAGE: &str = "usage: conformance <host:port> [--codec <name>]...
+
+runs protocol conformance checks against a server
+
+options:
+    --codec <name>  offer a codec in the handshake, in order of preference (default: protobuf)";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut address = None;
+    let mut codecs = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--codec" => match args.next() {
+                Some(codec) => codecs.push(codec),
+                None => {
+                    eprintln!("{USAGE}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if address.is_none() && !arg.starts_with('-') => address = Some(arg),
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let Some(address) = address else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    if codecs.is_empty() {
+        codecs.push(String::from("protobuf"));
+    }
+
+    let addr = match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            println!("[FAIL] address\n       {address}: no address");
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            println!("[FAIL] address\n       {address}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let report = conformance::run(addr, &codecs).await;
+    print!("{report}");
+    if report.is_ok() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
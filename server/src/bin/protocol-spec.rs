@@ -0,0 +1,7 @@
+use cubby_connect_server_core::spec;
+
+/// prints the description of the protocol as JSON
+fn main() {
+    let spec = serde_json::to_string_pretty(&spec::generate()).expect("spec is valid JSON");
+    println!("{spec}");
+}
@@ -0,0 +1,96 @@
+use std::convert::Infallible;
+use std::process::ExitCode;
+
+use cubby_connect_server_core::check;
+use cubby_connect_server_core::config::{Config, ConfigBuilder};
+use cubby_connect_server_core::context::Request;
+use cubby_connect_server_core::fn_handler::fn_handler;
+use cubby_connect_server_core::heartbeat::Heartbeat;
+use cubby_connect_server_core::server::Server;
+
+const USAGE: &str = "usage: cubby-connect-server [--config <config.json>] [--check]
+
+options:
+    --config <path>  load configuration from a json file
+    --check          validate configuration, tls, protobuf and auth server, then exit";
+
+/// loads `Config` from a json file, with defaults for missing fields
+fn load_config(path: Option<&str>) -> Result<Config, String> {
+    let builder = match path {
+        Some(path) => {
+            let json = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            serde_json::from_str::<ConfigBuilder>(&json).map_err(|e| format!("{path}: {e}"))?
+        }
+        None => Config::builder(),
+    };
+    builder.build().map_err(|e| e.to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut config_path = None;
+    let mut check = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--config" => match args.next() {
+                Some(path) => config_path = Some(path),
+                None => {
+                    eprintln!("{USAGE}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let config = match load_config(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[FAIL] config\n       {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if check {
+        let report = check::run(&config).await;
+        print!("{report}");
+        return match report.is_ok() {
+            true => ExitCode::SUCCESS,
+            false => ExitCode::FAILURE,
+        };
+    }
+
+    match serve(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// serves with `config` until ctrl-c, echoing every message back
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let heartbeat = Heartbeat::from_config(&config);
+    let server = Server::from_config(config)
+        .heartbeat(heartbeat)
+        .handler(fn_handler(|req: Request<Vec<u8>>| async move {
+            Ok::<_, Infallible>(req.msg)
+        }))
+        .run()
+        .await?;
+    match server.quic_addr() {
+        Some(quic) => println!("listening on tcp {} and quic {quic}", server.tcp_addr()),
+        None => println!("listening on tcp {}", server.tcp_addr()),
+    }
+
+    tokio::signal::ctrl_c().await?;
+    server.shutdown().await?;
+    Ok(())
+}